@@ -0,0 +1,209 @@
+//! N-API bindings of the capture engine
+//! Lets the Node agent run a capture session inside its own process instead of spawning
+//! `win-audio-capture` and parsing its stdout:
+//!
+//! ```js
+//! const capture = startCapture(["--session", id, "--out", path], onFrame, onEvent);
+//! capture.pause(); capture.resume(); capture.stop();
+//! await capture.finished();
+//! ```
+//!
+//! The options are the sidecar's command line. `onFrame` gets every frame of the stream
+//! (PCM samples, energy levels, the handshake) and `onEvent` every event line, both on the
+//! JS thread. `listDevices()` returns the endpoints `list-devices` prints, and
+//! `capabilities(licenseToken?)` the report of `capabilities --json`.
+//!
+//! Errors thrown or rejected with carry the error's kind as `code` (`device`, `format`,
+//! `io`, `protocol`, `policy`, `network`, `config` or `internal`), the `kind` of the
+//! sidecar's `error` event.
+
+use napi::bindgen_prelude::{AsyncTask, Buffer, Int16Array};
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::{Env, Error, JsFunction, Result, Task};
+use napi_derive::napi;
+use selly_audio_capture::frame::{Frame, FrameKind};
+use selly_audio_capture::in_process::CaptureSession;
+use selly_audio_capture::{capabilities, devices, error, Args};
+
+/// Frames and events that can wait for the JS thread before the calls start failing
+const CALLBACK_QUEUE: usize = 1_024;
+
+/// JS error for `e`, with its kind as `code`
+fn to_napi(env: &Env, e: anyhow::Error) -> Error {
+    let reason = format!("{:#}", e);
+    let thrown = env
+        .create_error(Error::from_reason(reason.clone()))
+        .and_then(|mut object| {
+            object.set_named_property("code", error::kind(&e).as_str())?;
+            Ok(Error::from(object.into_unknown()))
+        });
+    thrown.unwrap_or_else(|_| Error::from_reason(reason))
+}
+
+/// One frame of the stream
+#[napi(object)]
+pub struct AudioFrame {
+    /// `handshake`, `pcm`, `energy`, `shared_pcm`, `marker`, `stats` or `end_of_stream`
+    pub kind: String,
+    pub sequence: u32,
+    pub sample_offset: i64,
+    /// Nanoseconds since the stream started, for typed frames (`--protocol v3`)
+    pub monotonic_ns: Option<i64>,
+    /// Microseconds since the Unix epoch, for typed frames
+    pub utc_us: Option<i64>,
+    /// Interleaved samples of a PCM frame, empty for the others
+    pub samples: Int16Array,
+    /// Payload of any other kind of frame
+    pub data: Buffer,
+}
+
+impl From<Frame> for AudioFrame {
+    fn from(frame: Frame) -> Self {
+        let kind = match frame.header.kind {
+            FrameKind::Handshake => "handshake",
+            FrameKind::Pcm => "pcm",
+            FrameKind::Energy => "energy",
+            FrameKind::SharedPcm => "shared_pcm",
+            FrameKind::Marker => "marker",
+            FrameKind::Stats => "stats",
+            FrameKind::EndOfStream => "end_of_stream",
+        };
+        Self {
+            kind: kind.to_string(),
+            sequence: frame.header.sequence,
+            sample_offset: frame.header.sample_offset as i64,
+            monotonic_ns: frame.timestamps.map(|t| t.monotonic_ns as i64),
+            utc_us: frame.timestamps.map(|t| t.utc_us),
+            samples: Int16Array::new(frame.samples),
+            data: frame.data.into(),
+        }
+    }
+}
+
+/// A running capture session
+#[napi]
+pub struct Capture {
+    session: Option<CaptureSession>,
+}
+
+#[napi]
+impl Capture {
+    #[napi]
+    pub fn pause(&self, env: Env) -> Result<()> {
+        self.session()?.pause().map_err(|e| to_napi(&env, e))
+    }
+
+    #[napi]
+    pub fn resume(&self, env: Env) -> Result<()> {
+        self.session()?.resume().map_err(|e| to_napi(&env, e))
+    }
+
+    /// Stop the way a signal does; `finished()` resolves once the recording is finalized
+    #[napi]
+    pub fn stop(&self, env: Env) -> Result<()> {
+        self.session()?.stop().map_err(|e| to_napi(&env, e))
+    }
+
+    /// Send any control command line, e.g. `{"cmd":"marker","label":"Pricing"}`
+    #[napi]
+    pub fn command(&self, env: Env, line: String) -> Result<()> {
+        self.session()?.command(&line).map_err(|e| to_napi(&env, e))
+    }
+
+    #[napi(getter)]
+    pub fn is_finished(&self) -> bool {
+        self.session
+            .as_ref()
+            .is_none_or(CaptureSession::is_finished)
+    }
+
+    /// Resolves when the session has ended, rejecting with its error if it failed
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn finished(&mut self) -> AsyncTask<Finish> {
+        AsyncTask::new(Finish {
+            session: self.session.take(),
+            failure: None,
+        })
+    }
+
+    fn session(&self) -> Result<&CaptureSession> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| Error::from_reason("finished() was already called"))
+    }
+}
+
+pub struct Finish {
+    session: Option<CaptureSession>,
+    /// How the session failed, turned into the JS error on the JS thread
+    failure: Option<anyhow::Error>,
+}
+
+impl Task for Finish {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        match self.session.take().map(CaptureSession::wait) {
+            Some(Err(e)) => {
+                let reason = format!("{:#}", e);
+                self.failure = Some(e);
+                Err(Error::from_reason(reason))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<()> {
+        match self.failure.take() {
+            Some(e) => Err(to_napi(&env, e)),
+            None => Err(err),
+        }
+    }
+}
+
+/// Start capturing with the sidecar's options
+#[napi]
+pub fn start_capture(
+    env: Env,
+    options: Vec<String>,
+    #[napi(ts_arg_type = "(frame: AudioFrame) => void")] on_frame: JsFunction,
+    #[napi(ts_arg_type = "(event: string) => void")] on_event: JsFunction,
+) -> Result<Capture> {
+    let args = Args::from_options(options).map_err(|e| to_napi(&env, e))?;
+    let frames: ThreadsafeFunction<AudioFrame, ErrorStrategy::Fatal> =
+        on_frame.create_threadsafe_function(CALLBACK_QUEUE, |ctx| Ok(vec![ctx.value]))?;
+    let events: ThreadsafeFunction<String, ErrorStrategy::Fatal> =
+        on_event.create_threadsafe_function(CALLBACK_QUEUE, |ctx| Ok(vec![ctx.value]))?;
+    let session = CaptureSession::start(
+        args,
+        move |frame: Frame| {
+            frames.call(frame.into(), ThreadsafeFunctionCallMode::NonBlocking);
+        },
+        move |line: String| {
+            events.call(line, ThreadsafeFunctionCallMode::NonBlocking);
+        },
+    )
+    .map_err(|e| to_napi(&env, e))?;
+    Ok(Capture {
+        session: Some(session),
+    })
+}
+
+/// The active capture and render endpoints, as `list-devices` prints them
+#[napi(ts_return_type = "Array<Record<string, unknown>>")]
+pub fn list_devices(env: Env) -> Result<serde_json::Value> {
+    let endpoints = devices::list().map_err(|e| to_napi(&env, e))?;
+    serde_json::to_value(endpoints).map_err(|e| Error::from_reason(e.to_string()))
+}
+
+/// What this build can do on this machine, as `capabilities --json` reports it
+#[napi(ts_return_type = "Record<string, unknown>")]
+pub fn capabilities(license_token: Option<String>) -> Result<serde_json::Value> {
+    let report = capabilities::detect(license_token.as_deref());
+    serde_json::to_value(report).map_err(|e| Error::from_reason(e.to_string()))
+}
@@ -0,0 +1,38 @@
+//! With the `static-crt` feature an MSVC build links the Visual C++ runtime statically, so
+//! the binary needs no redistributable. The UCRT is still loaded from Windows, which has
+//! shipped it since Windows 10; `-C target-feature=+crt-static` links that in as well.
+//! MinGW builds already link their runtime statically.
+//!
+//! The `opus` feature links libopus statically as well, from `OPUS_LIB_DIR` when set.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=OPUS_LIB_DIR");
+    if std::env::var_os("CARGO_FEATURE_OPUS").is_some() {
+        if let Some(dir) = std::env::var_os("OPUS_LIB_DIR") {
+            println!("cargo:rustc-link-search=native={}", dir.to_string_lossy());
+        }
+        println!("cargo:rustc-link-lib=static=opus");
+    }
+    let msvc = std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc");
+    let crt_static = std::env::var("CARGO_CFG_TARGET_FEATURE")
+        .is_ok_and(|features| features.split(',').any(|f| f == "crt-static"));
+    if std::env::var_os("CARGO_FEATURE_STATIC_CRT").is_none() || !msvc || crt_static {
+        return;
+    }
+    for lib in [
+        "msvcrt.lib",
+        "msvcrtd.lib",
+        "vcruntime.lib",
+        "vcruntimed.lib",
+        "libvcruntimed.lib",
+        "libcmtd.lib",
+        "libucrt.lib",
+        "libucrtd.lib",
+    ] {
+        println!("cargo:rustc-link-arg=/NODEFAULTLIB:{}", lib);
+    }
+    for lib in ["libcmt.lib", "libvcruntime.lib", "ucrt.lib"] {
+        println!("cargo:rustc-link-arg=/DEFAULTLIB:{}", lib);
+    }
+}
@@ -0,0 +1,42 @@
+//! Feeds arbitrary bytes through the SELL frame decoder in arbitrary chunk sizes.
+//! Run with `cargo +nightly fuzz run frame_decoder`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use selly_audio_capture::frame::{self, FrameDecoder, HEADER_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    // First byte picks the chunk size so the fuzzer also explores split reads
+    let Some((&chunk, stream)) = data.split_first() else {
+        return;
+    };
+    let chunk = chunk.max(1) as usize;
+
+    let mut decoder = FrameDecoder::new();
+    for piece in stream.chunks(chunk) {
+        decoder.push(piece);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(decoded)) if decoded.header.kind != frame::FrameKind::Pcm => {
+                    assert_eq!(decoded.header.payload_len as usize, decoded.data.len());
+                }
+                Ok(Some(decoded)) => {
+                    // Whatever decodes must re-encode to the same header and payload size
+                    assert_eq!(decoded.header.payload_len as usize, decoded.samples.len() * 2);
+                    let mut bytes = Vec::new();
+                    frame::write_frame(
+                        &mut bytes,
+                        &decoded.samples,
+                        decoded.header.sequence,
+                        decoded.header.sample_offset,
+                    )
+                    .unwrap();
+                    assert_eq!(bytes[..HEADER_LEN], frame::encode_header(&decoded.header));
+                }
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+    }
+});
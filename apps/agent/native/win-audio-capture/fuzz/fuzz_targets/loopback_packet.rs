@@ -0,0 +1,27 @@
+//! Feeds arbitrary wave formats and packets through the loopback packet conversion.
+//! Run with `cargo +nightly fuzz run loopback_packet`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use selly_audio_capture::packet_format::{self, PacketFormat, SampleType};
+
+fuzz_target!(|data: &[u8]| {
+    // First byte says how many of the following bytes are the wave format
+    let Some((&format_len, rest)) = data.split_first() else {
+        return;
+    };
+    let (format, packet) = rest.split_at((format_len as usize).min(rest.len()));
+    let Ok(format) = PacketFormat::parse(format) else {
+        return;
+    };
+
+    let mut mono = Vec::with_capacity(format.frames_in(packet.len()));
+    let frames = packet_format::to_mono(&format, packet, &mut mono);
+    assert_eq!(frames, packet.len() / format.block_align as usize);
+    assert_eq!(mono.len(), frames);
+    assert!(mono.iter().all(|sample| sample.is_finite()));
+    if format.sample_type == SampleType::Int {
+        assert!(mono.iter().all(|sample| (-1.0..1.0).contains(sample)));
+    }
+});
@@ -0,0 +1,118 @@
+//! Prospect voice anonymization
+//! With `--anonymize` the loopback channel (and a separate extra loopback channel) goes
+//! through a delay-line pitch shifter before quantization, for recordings that feed
+//! training datasets where the prospect must not be identifiable. The shifter resamples
+//! short overlapping grains, so pitch and formants move together, which is what disguises
+//! a voice (pitch alone keeps the timbre recognisable). It runs regardless of the power
+//! profile: it is a privacy requirement, not an enhancement.
+
+/// Grain length; long enough for speech pitch periods, short enough not to smear syllables
+const GRAIN_MS: f64 = 40.0;
+
+/// Largest shift accepted by `--anonymize-semitones`
+pub const MAX_SEMITONES: f64 = 12.0;
+
+/// Streaming pitch shifter for one channel
+pub struct PitchShifter {
+    buffer: Vec<f32>,
+    write: usize,
+    /// Delay of the first read head in samples; the second trails it by half a grain
+    delay: f64,
+    /// How much the delay changes per sample: `1 - ratio`
+    step: f64,
+}
+
+impl PitchShifter {
+    pub fn new(semitones: f64, sample_rate: u32) -> Self {
+        let grain = ((sample_rate as f64 * GRAIN_MS / 1000.0) as usize).max(16);
+        let ratio = 2f64.powf(semitones / 12.0);
+        Self {
+            buffer: vec![0.0; grain + 2],
+            write: 0,
+            delay: 0.0,
+            step: 1.0 - ratio,
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let len = self.buffer.len();
+        let grain = (len - 2) as f64;
+        self.buffer[self.write] = sample;
+
+        let mut out = 0.0;
+        for offset in [0.0, grain / 2.0] {
+            let delay = (self.delay + offset).rem_euclid(grain);
+            // sin² windows half a grain apart sum to one, so the crossfade is level
+            let gain = (std::f64::consts::PI * delay / grain).sin().powi(2);
+            out += gain * self.read(delay);
+        }
+
+        self.delay = (self.delay + self.step).rem_euclid(grain);
+        self.write = (self.write + 1) % len;
+        out as f32
+    }
+
+    /// Linear interpolation `delay` samples behind the newest one
+    fn read(&self, delay: f64) -> f64 {
+        let len = self.buffer.len();
+        let whole = delay.floor();
+        let frac = delay - whole;
+        let newer = (self.write + len - whole as usize) % len;
+        let older = (newer + len - 1) % len;
+        self.buffer[newer] as f64 * (1.0 - frac) + self.buffer[older] as f64 * frac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rising zero crossings per second
+    fn frequency(samples: &[f32], sample_rate: u32) -> f64 {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        crossings as f64 * sample_rate as f64 / samples.len() as f64
+    }
+
+    fn shifted_tone(semitones: f64, hz: f64) -> Vec<f32> {
+        let rate = 48_000;
+        let mut shifter = PitchShifter::new(semitones, rate);
+        (0..rate as usize)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                shifter.process((2.0 * std::f64::consts::PI * hz * t).sin() as f32 * 0.5)
+            })
+            // Skip the first grain while the delay line fills
+            .skip(rate as usize / 10)
+            .collect()
+    }
+
+    #[test]
+    fn an_octave_up_doubles_the_pitch() {
+        let out = shifted_tone(12.0, 200.0);
+        let hz = frequency(&out, 48_000);
+        assert!((hz - 400.0).abs() < 25.0, "{}", hz);
+    }
+
+    #[test]
+    fn shifting_down_lowers_the_pitch_and_keeps_the_level() {
+        let out = shifted_tone(-5.0, 300.0);
+        let hz = frequency(&out, 48_000);
+        assert!((hz - 300.0 * 2f64.powf(-5.0 / 12.0)).abs() < 20.0, "{}", hz);
+        let peak = out.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak < 0.75, "{}", peak);
+    }
+
+    #[test]
+    fn zero_shift_is_a_delay() {
+        // Half a 40 ms grain at 8 kHz
+        let mut shifter = PitchShifter::new(0.0, 8_000);
+        let out: Vec<f32> = (0..400).map(|i| shifter.process(i as f32)).collect();
+        assert!(out[200..]
+            .iter()
+            .enumerate()
+            .all(|(i, &s)| s == (i + 40) as f32));
+    }
+}
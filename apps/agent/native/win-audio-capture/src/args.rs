@@ -0,0 +1,707 @@
+//! Capture options
+//! The options of a capture session, parsed from the command line by the CLI and from the
+//! same strings by an embedding host, so both get the defaults and checks.
+
+use crate::clock_sync::ClockSource;
+use crate::device_select::DeviceStrategy;
+use crate::ducking::DuckingMode;
+use crate::error::fail;
+use crate::headset::HeadsetControls;
+#[cfg(feature = "otlp")]
+use crate::http::ProxySetting;
+use crate::i18n::Lang;
+use crate::latency::LatencyCompensation;
+use crate::license::{self, Feature, Policy};
+use crate::limiter::LimiterMode;
+use crate::loopback_role::{LoopbackLayout, LoopbackRole};
+use crate::mute::MuteSync;
+use crate::notes::NotesMode;
+#[cfg(feature = "otlp")]
+use crate::otlp;
+use crate::pipeline::PipelineTag;
+use crate::polarity::PolarityMode;
+use crate::power::BatteryMode;
+use crate::quantize::Dither;
+use crate::rate_negotiation::RatePolicy;
+use crate::resample::ResampleQuality;
+use crate::sink::OutputFormat;
+use crate::soak::{Fault, SoakSpec};
+use crate::stream::{Protocol, StdoutFormat};
+use crate::synth::SyntheticSource;
+use crate::tracks::OutputMode;
+use crate::{
+    anonymize, health, highlights, jobs, latency, paths, preview, resample, rolling, sink, spool,
+};
+use anyhow::Result;
+use clap::Parser;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Session identifier
+    #[arg(long)]
+    pub session: String,
+
+    /// Output WAV file path; `{session}` is replaced with a file-name-safe session ID
+    #[arg(long)]
+    pub out: PathBuf,
+
+    /// Output container
+    #[arg(long, value_enum, default_value_t = OutputFormat::Wav)]
+    pub format: OutputFormat,
+
+    /// Bitrate of `--format opus` in bits per second
+    #[arg(long, default_value_t = sink::DEFAULT_OPUS_BITRATE)]
+    pub opus_bitrate: u32,
+
+    /// Sample rate of the recording and the stream in Hz; devices running at another rate
+    /// are resampled to it
+    #[arg(long, default_value = "48000")]
+    pub sample_rate: u32,
+
+    /// Generate a test signal instead of capturing: `sine[:HZ]` or `noise`, optionally
+    /// prefixed with `mic=` or `loopback=` to replace only that source (repeatable)
+    #[arg(long)]
+    pub source: Vec<SyntheticSource>,
+
+    /// Soak test: run against synthetic sources for `hours=N` or `minutes=N`, then stop and
+    /// report whether the memory and timeline invariants held
+    #[arg(long)]
+    pub soak: Option<SoakSpec>,
+
+    /// Faults to inject at random during a soak test
+    #[arg(long, value_enum, value_delimiter = ',', requires = "soak")]
+    pub inject: Vec<Fault>,
+
+    /// Number of channels (must be 2 for stereo)
+    #[arg(long, default_value = "2")]
+    pub channels: u16,
+
+    /// Seconds to keep capturing after a stop request before finalizing
+    #[arg(long, default_value = "0")]
+    pub trailing_secs: f64,
+
+    /// Refuse to record to network, cloud-synced or slow storage instead of staging locally
+    #[arg(long)]
+    pub require_local: bool,
+
+    /// Bytes per second used to deliver spooled recordings once their share is back
+    #[arg(long, default_value_t = spool::DEFAULT_DRAIN_RATE)]
+    pub spool_drain_rate: u64,
+
+    /// Low power profile: larger buffers, no optional processing, lower thread priority
+    #[arg(long, value_enum, default_value_t = BatteryMode::Off)]
+    pub battery_mode: BatteryMode,
+
+    /// Emit a `stats` heartbeat (levels, drops, xruns, queue depths) on stderr
+    #[arg(long)]
+    pub json_logs: bool,
+
+    /// How often the `--json-logs` heartbeat is emitted
+    #[arg(long, default_value_t = health::DEFAULT_INTERVAL_MS)]
+    pub stats_interval_ms: u64,
+
+    /// Show live meters, drops, sinks and recent events on the console instead of the
+    /// event lines, refreshed by the heartbeat
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Reopen the MIC stream when it delivers nothing for this long (0 = never)
+    #[arg(long, default_value = "1000")]
+    pub mic_stall_ms: u64,
+
+    /// How the MIC is chosen: scored across all inputs, or a fixed Windows default
+    #[arg(long, value_enum, default_value_t = DeviceStrategy::Ranked)]
+    pub device_strategy: DeviceStrategy,
+
+    /// Endpoint ID (from `list-devices`) of the MIC to record, instead of --device-strategy
+    #[arg(long)]
+    pub mic_device: Option<String>,
+
+    /// Endpoint ID (from `list-devices`) of the render endpoint to loop back, instead of
+    /// --loopback-role
+    #[arg(long)]
+    pub loopback_device: Option<String>,
+
+    /// Record only what this process and its children play (Windows 10 20H1 and later;
+    /// older systems record the endpoint)
+    #[arg(long, conflicts_with = "loopback_process_name")]
+    pub loopback_pid: Option<u32>,
+
+    /// As --loopback-pid, for the running executable of this name, e.g. `zoom.exe`
+    #[arg(long)]
+    pub loopback_process_name: Option<String>,
+
+    /// As --loopback-pid, for the conferencing app that is in a meeting when capture starts
+    #[arg(long, conflicts_with_all = ["loopback_pid", "loopback_process_name"])]
+    pub loopback_meeting_app: bool,
+
+    /// Report the meeting Zoom or Teams is in (app, meeting ID, participants) as it changes
+    #[arg(long)]
+    pub meeting_bridge: bool,
+
+    /// Default render endpoint to record; `auto` follows the one a conferencing app is playing on
+    #[arg(long, value_enum, default_value_t = LoopbackRole::Auto)]
+    pub loopback_role: LoopbackRole,
+
+    /// Second render endpoint to record at the same time (console, communications or multimedia)
+    #[arg(long, value_enum)]
+    pub loopback_extra_role: Option<LoopbackRole>,
+
+    /// Whether the second endpoint is mixed into the loopback channel or kept as a third channel
+    #[arg(long, value_enum, default_value_t = LoopbackLayout::Mix)]
+    pub loopback_layout: LoopbackLayout,
+
+    /// Language of the `message` of events shown to the rep
+    #[arg(long, value_enum, default_value_t = Lang::En)]
+    pub lang: Lang,
+
+    /// Where `audio_note` snippets go: copied next to the recording, or played into an
+    /// extra channel of it
+    #[arg(long, value_enum, default_value_t = NotesMode::Attachments)]
+    pub notes: NotesMode,
+
+    /// Resampler used when the loopback device runs at a different rate than the MIC
+    #[arg(long, value_enum, default_value_t = ResampleQuality::Balanced)]
+    pub resample_quality: ResampleQuality,
+
+    /// What to do when the MIC or the loopback endpoint runs slower than --sample-rate
+    /// (a Bluetooth headset in hands-free mode): upsample it, record at its rate, or fail
+    #[arg(long, value_enum, default_value_t = RatePolicy::Resample)]
+    pub rate_policy: RatePolicy,
+
+    /// Line the MIC and loopback paths up by their measured latency difference
+    #[arg(long, value_enum, default_value_t = LatencyCompensation::Auto)]
+    pub latency_compensation: LatencyCompensation,
+
+    /// What the mixer's timeline follows: the local clock, or the one the agent reports with
+    /// `{"cmd":"clock",...}`
+    #[arg(long, value_enum, default_value_t = ClockSource::System)]
+    pub clock: ClockSource,
+
+    /// Known latency of the loopback path relative to the MIC's, in ms (negative when the
+    /// MIC is the slower one); applied instead of measuring
+    #[arg(long, allow_negative_numbers = true)]
+    pub latency_offset_ms: Option<f64>,
+
+    /// High-pass cutoff for the MIC in Hz (0 = off); adjustable live with the `dsp` command
+    #[arg(long, default_value = "0")]
+    pub hpf_hz: f32,
+
+    /// Automatic gain control on the MIC
+    #[arg(long)]
+    pub agc: bool,
+
+    /// Speech level --agc steers towards, in dBFS RMS
+    #[arg(long, default_value = "-20", allow_negative_numbers = true)]
+    pub agc_target_db: f32,
+
+    /// MIC noise suppression depth, 0 (off) to 1
+    #[arg(long, default_value = "0")]
+    pub denoise: f32,
+
+    /// Cancel the loopback's echo in the MIC (speaker bleed), using the loopback as reference
+    #[arg(long)]
+    pub aec: bool,
+
+    /// Diagnostics: also write the unprocessed MIC, the processed MIC and the loopback
+    /// channel(s) side by side into this file, to judge what the MIC processing does
+    #[arg(long)]
+    pub dsp_compare: Option<PathBuf>,
+
+    /// Container of the `--dsp-compare` file, `--format` if not given
+    #[arg(long, value_enum, requires = "dsp_compare")]
+    pub dsp_compare_format: Option<OutputFormat>,
+
+    /// Tenant public key to seal the `--dsp-compare` file for; `--wrap-key` only seals the
+    /// recording
+    #[arg(long, requires = "dsp_compare")]
+    pub dsp_compare_wrap_key: Option<PathBuf>,
+
+    /// Peak control applied before converting the mix to 16-bit
+    #[arg(long, value_enum, default_value_t = LimiterMode::Off)]
+    pub limiter: LimiterMode,
+
+    /// Dither applied when converting the mix to 16-bit
+    #[arg(long, value_enum, default_value_t = Dither::Off)]
+    pub dither: Dither,
+
+    /// What headset call-control buttons (hook switch, mute) do to the recording
+    #[arg(long, value_enum, default_value_t = HeadsetControls::Off)]
+    pub headset_controls: HeadsetControls,
+
+    /// Follow the Windows MIC mute state: emit events, or also record silence while muted
+    #[arg(long, value_enum, default_value_t = MuteSync::Events)]
+    pub mute_sync: MuteSync,
+
+    /// Flip MIC input channels that arrive with inverted polarity before averaging them
+    #[arg(long, value_enum, default_value_t = PolarityMode::Auto)]
+    pub polarity_correction: PolarityMode,
+
+    /// Cut leading and trailing silence (all channels below the threshold) when finalizing
+    #[arg(long)]
+    pub trim_silence: bool,
+
+    /// Leave pauses longer than this many seconds out of the file, logging the cut spans in
+    /// the timeline
+    #[arg(long)]
+    pub compact_silence: Option<f64>,
+
+    /// Close the file every N seconds and carry on in the next part, as `rotate` does
+    #[arg(long)]
+    pub segment_seconds: Option<u64>,
+
+    /// Hold only the last N minutes in memory; nothing is recorded unless `commit` is sent
+    #[arg(long)]
+    pub rolling_minutes: Option<u32>,
+
+    /// Most audio held in memory across the queues and the rolling buffer, in MB; full
+    /// buffers push back, drop new blocks or evict old audio instead of growing
+    #[arg(long)]
+    pub max_buffer_mb: Option<u64>,
+
+    /// Level below which audio counts as silence for --trim-silence and --compact-silence,
+    /// in dBFS
+    #[arg(long, default_value = "-50", allow_negative_numbers = true)]
+    pub trim_threshold_db: f64,
+
+    /// Mark chapters at long pauses and changes in turn-taking, in the timeline and as cues
+    #[arg(long)]
+    pub chapters: bool,
+
+    /// Silence card numbers and social security numbers read out, as found in the words of
+    /// `transcript` commands, once the recording is finalized
+    #[arg(long)]
+    pub auto_redact: bool,
+
+    /// Lowest ASR confidence (0 to 1) of the words of a number --auto-redact silences
+    #[arg(long, default_value = "0.8")]
+    pub auto_redact_confidence: f32,
+
+    /// Audio silenced before a number --auto-redact found, in milliseconds
+    #[arg(long, default_value = "250")]
+    pub auto_redact_pre_ms: u64,
+
+    /// Audio silenced after a number --auto-redact found, in milliseconds
+    #[arg(long, default_value = "500")]
+    pub auto_redact_post_ms: u64,
+
+    /// What kind of call this is, announced in the handshake and the recording's metadata
+    /// so downstream consumers can route it
+    #[arg(long, value_enum)]
+    pub pipeline_tag: Option<PipelineTag>,
+
+    /// Token an agent must present to attach (frames and control); attaching is open to
+    /// the session's user without one
+    #[arg(long)]
+    pub admin_token: Option<String>,
+
+    /// Serve the read-only observe pipes, to clients presenting this token (or the admin one)
+    #[arg(long)]
+    pub observer_token: Option<String>,
+
+    /// Tenant public key (PEM or DER, RSA or P-256) to seal the finished recording for;
+    /// only the backend holding the private key can open it
+    #[arg(long)]
+    pub wrap_key: Option<PathBuf>,
+
+    /// Signed feature policy of the tenant, enabling the premium modules it lists
+    #[arg(long)]
+    pub license_token: Option<String>,
+
+    /// The verified `--license-token`, filled in by `prepare`
+    #[arg(skip)]
+    pub license: Option<Policy>,
+
+    /// Emit a `sync` event (sample position + UTC) every this many seconds and mark the
+    /// same pulses as cue points, for aligning external video
+    #[arg(long)]
+    pub sync_pulse_secs: Option<f64>,
+
+    /// Communications ducking: keep the capture from ducking other audio, only mark
+    /// ducked stretches in the timeline, or ignore it
+    #[arg(long, value_enum, default_value_t = DuckingMode::OptOut)]
+    pub ducking: DuckingMode,
+
+    /// Frame stream protocol: `auto` sends the handshake once the consumer negotiates,
+    /// `v3` sends timestamped, checksummed typed frames
+    #[arg(long, alias = "stream-protocol", value_enum, default_value_t = Protocol::Auto)]
+    pub protocol: Protocol,
+
+    /// What goes to stdout: SELL `frames`, or a `wav` stream for ffmpeg/sox while debugging
+    #[arg(long, value_enum, default_value_t = StdoutFormat::Frames, conflicts_with = "soak")]
+    pub stdout_format: StdoutFormat,
+
+    /// Send the handshake again every this many PCM frames (100ms each), for consumers that
+    /// join the stream while it runs
+    #[arg(long)]
+    pub handshake_every: Option<u32>,
+
+    /// Record pipeline spans and write them here as a Chrome/Perfetto trace at the end
+    #[arg(long)]
+    pub trace_out: Option<PathBuf>,
+
+    /// Count allocations, locks, logging and IO on the audio threads (debug builds assert)
+    #[arg(long)]
+    pub rt_checks: bool,
+
+    /// Report the session to this OTLP/HTTP collector when it ends (`http://host:4318`)
+    #[cfg(feature = "otlp")]
+    #[arg(long)]
+    pub otlp_endpoint: Option<otlp::Endpoint>,
+
+    /// Extra header for OTLP requests, `name=value` (repeatable)
+    #[cfg(feature = "otlp")]
+    #[arg(long = "otlp-header", requires = "otlp_endpoint")]
+    pub otlp_headers: Vec<otlp::Header>,
+
+    /// Proxy for network requests: `system` settings (WinHTTP, PAC), `direct` or `http://host:port`
+    #[cfg(feature = "otlp")]
+    #[arg(long, default_value = "system")]
+    pub proxy: ProxySetting,
+
+    /// User for a proxy that asks for credentials; the password is read from SELLY_PROXY_PASSWORD
+    #[cfg(feature = "otlp")]
+    #[arg(long)]
+    pub proxy_user: Option<String>,
+
+    /// Shell command run once recording has started, with the session in SELLY_* variables
+    #[arg(long)]
+    pub on_start_cmd: Option<String>,
+
+    /// Shell command run once the recording is finalized, with the session and the file in
+    /// SELLY_* variables
+    #[arg(long)]
+    pub on_finalize_cmd: Option<String>,
+
+    /// Seconds a hook command may run before it is killed
+    #[arg(long, default_value = "30")]
+    pub hook_timeout_secs: u64,
+
+    /// How many finalize jobs (preview, highlights, tracks) run at once
+    #[arg(long, default_value_t = jobs::default_jobs())]
+    pub finalize_jobs: usize,
+
+    /// Seconds after the stop after which optional finalize stages (trim, cues, preview,
+    /// highlights) are skipped or cut short; the recording is still sealed and published
+    #[arg(long)]
+    pub finalize_timeout: Option<u64>,
+
+    /// Also write a sped-up copy (e.g. 1.5 or 2) of the finished recording, pitch preserved
+    #[arg(long)]
+    pub preview_speed: Option<f64>,
+
+    /// Cut this many highlight clips (the best-scored moments by speech, markers and
+    /// keyword hits) out of the finished recording
+    #[arg(long)]
+    pub highlights: Option<usize>,
+
+    /// Length of each highlight clip
+    #[arg(long, default_value_t = 45)]
+    pub highlight_secs: u64,
+
+    /// Deliver the recording as it is, as one mono file per channel (`split`), or as both
+    #[arg(long, value_enum, default_value_t = OutputMode::Stereo)]
+    pub output_mode: OutputMode,
+
+    /// With split tracks, also write a mono mix of all channels
+    #[arg(long)]
+    pub mixdown: bool,
+
+    /// Pitch/formant-shift the prospect (loopback) channels so the speaker can't be identified
+    #[arg(long)]
+    pub anonymize: bool,
+
+    /// Shift used by --anonymize, in semitones (negative lowers the voice)
+    #[arg(long, default_value = "-4", allow_negative_numbers = true)]
+    pub anonymize_semitones: f64,
+
+    /// JSON config with per-device overrides (default: config.json in the state directory)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+/// `Args` on their own, for parsing without the CLI's subcommands
+#[derive(Parser, Debug)]
+#[command(name = "win-audio-capture", no_binary_name = true)]
+struct Options {
+    #[command(flatten)]
+    args: Args,
+}
+
+impl Args {
+    /// Parse capture options given as on the command line, e.g. `["--session", "s1", ...]`
+    pub fn from_options<I, T>(options: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Ok(Options::try_parse_from(options)?.args)
+    }
+
+    /// Check the options against each other and fill in what they imply, before capture
+    pub fn prepare(&mut self) -> Result<()> {
+        self.license = self
+            .license_token
+            .as_deref()
+            .map(license::verify)
+            .transpose()?;
+        license::check(
+            self.license.as_ref(),
+            &[
+                (self.aec, "--aec", Feature::Aec),
+                (self.wrap_key.is_some(), "--wrap-key", Feature::Encryption),
+                (
+                    self.dsp_compare_wrap_key.is_some(),
+                    "--dsp-compare-wrap-key",
+                    Feature::Encryption,
+                ),
+            ],
+        )?;
+
+        // A soak test never touches the devices
+        if self.soak.is_some() && self.source.is_empty() {
+            self.source.push(
+                "noise"
+                    .parse::<SyntheticSource>()
+                    .map_err(|e| fail!(Config, "{}", e))?,
+            );
+        }
+
+        // Validate channels
+        if self.channels != 2 {
+            return Err(fail!(Config, "Only stereo (2 channels) is supported"));
+        }
+
+        if !(resample::MIN_RATE..=resample::MAX_RATE).contains(&self.sample_rate) {
+            return Err(fail!(
+                Config,
+                "--sample-rate must be between {} and {} Hz",
+                resample::MIN_RATE,
+                resample::MAX_RATE
+            ));
+        }
+
+        if !(sink::MIN_OPUS_BITRATE..=sink::MAX_OPUS_BITRATE).contains(&self.opus_bitrate) {
+            return Err(fail!(
+                Config,
+                "--opus-bitrate must be between {} and {} bit/s",
+                sink::MIN_OPUS_BITRATE,
+                sink::MAX_OPUS_BITRATE
+            ));
+        }
+        if !self.format.is_riff() {
+            // Matroska takes chapters and sync pulses as its own chapters
+            let compressed = self.format.is_compressed();
+            let rewriting = [
+                (self.trim_silence, "--trim-silence"),
+                (self.auto_redact, "--auto-redact"),
+                (self.chapters && compressed, "--chapters"),
+                (
+                    self.sync_pulse_secs.is_some() && compressed,
+                    "--sync-pulse-secs",
+                ),
+            ];
+            if let Some((_, option)) = rewriting.iter().find(|(set, _)| *set) {
+                return Err(fail!(
+                    Config,
+                    "{} rewrites the finished recording, which --format {:?} can't be",
+                    option,
+                    self.format
+                ));
+            }
+        }
+
+        if self.output_mode != OutputMode::Stereo {
+            if !self.format.is_riff() {
+                return Err(fail!(
+                    Config,
+                    "--output-mode {:?} cuts the tracks from the finished recording, which --format {:?} can't be read back from",
+                    self.output_mode,
+                    self.format
+                ));
+            }
+            if self.wrap_key.is_some() {
+                return Err(fail!(
+                    Config,
+                    "--output-mode can't be combined with --wrap-key, the tracks would be left unsealed"
+                ));
+            }
+        } else if self.mixdown {
+            return Err(fail!(Config, "--mixdown needs --output-mode split or both"));
+        }
+
+        if let Some(every) = self.handshake_every {
+            if every == 0 {
+                return Err(fail!(Config, "--handshake-every must be at least 1"));
+            }
+            if self.protocol == Protocol::V1 {
+                return Err(fail!(
+                    Config,
+                    "--handshake-every needs a protocol with a handshake, not --protocol v1"
+                ));
+            }
+        }
+
+        if self.stats_interval_ms < 100 {
+            return Err(fail!(Config, "--stats-interval-ms must be at least 100"));
+        }
+
+        if !self.trailing_secs.is_finite() || self.trailing_secs < 0.0 {
+            return Err(fail!(
+                Config,
+                "--trailing-secs must be a non-negative number"
+            ));
+        }
+
+        if !self.trim_threshold_db.is_finite() || self.trim_threshold_db >= 0.0 {
+            return Err(fail!(
+                Config,
+                "--trim-threshold-db must be a negative number"
+            ));
+        }
+
+        if let Some(secs) = self.compact_silence {
+            if !secs.is_finite() || secs <= 0.0 {
+                return Err(fail!(
+                    Config,
+                    "--compact-silence must be a positive number of seconds"
+                ));
+            }
+        }
+
+        if self.segment_seconds == Some(0) {
+            return Err(fail!(Config, "--segment-seconds must be at least 1"));
+        }
+
+        if self.max_buffer_mb == Some(0) {
+            return Err(fail!(Config, "--max-buffer-mb must be at least 1"));
+        }
+
+        if let Some(minutes) = self.rolling_minutes {
+            if !(1..=rolling::MAX_MINUTES).contains(&minutes) {
+                return Err(fail!(
+                    Config,
+                    "--rolling-minutes must be between 1 and {}",
+                    rolling::MAX_MINUTES
+                ));
+            }
+            let conflicting = [
+                (self.compact_silence.is_some(), "--compact-silence"),
+                (self.dsp_compare.is_some(), "--dsp-compare"),
+            ];
+            if let Some((_, option)) = conflicting.iter().find(|(set, _)| *set) {
+                return Err(fail!(
+                    Config,
+                    "--rolling-minutes can't be combined with {}",
+                    option
+                ));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.auto_redact_confidence) {
+            return Err(fail!(
+                Config,
+                "--auto-redact-confidence must be between 0 and 1"
+            ));
+        }
+
+        if let Some(secs) = self.sync_pulse_secs {
+            if !secs.is_finite() || secs <= 0.0 {
+                return Err(fail!(
+                    Config,
+                    "--sync-pulse-secs must be a positive number of seconds"
+                ));
+            }
+        }
+
+        if let Some(offset) = self.latency_offset_ms {
+            if !offset.is_finite() || offset.abs() > latency::MAX_OFFSET_MS {
+                return Err(fail!(
+                    Config,
+                    "--latency-offset-ms must be within ±{}",
+                    latency::MAX_OFFSET_MS
+                ));
+            }
+        }
+
+        if !(highlights::MIN_CLIP_SECS..=highlights::MAX_CLIP_SECS).contains(&self.highlight_secs) {
+            return Err(fail!(
+                Config,
+                "--highlight-secs must be between {} and {}",
+                highlights::MIN_CLIP_SECS,
+                highlights::MAX_CLIP_SECS
+            ));
+        }
+        if let Some(speed) = self.preview_speed {
+            if !(preview::MIN_SPEED..=preview::MAX_SPEED).contains(&speed) {
+                return Err(fail!(
+                    Config,
+                    "--preview-speed must be between {} and {}",
+                    preview::MIN_SPEED,
+                    preview::MAX_SPEED
+                ));
+            }
+        }
+
+        if !self.anonymize_semitones.is_finite()
+            || self.anonymize_semitones == 0.0
+            || self.anonymize_semitones.abs() > anonymize::MAX_SEMITONES
+        {
+            return Err(fail!(
+                Config,
+                "--anonymize-semitones must be non-zero and at most {} either way",
+                anonymize::MAX_SEMITONES
+            ));
+        }
+
+        match self.loopback_extra_role {
+            Some(LoopbackRole::Auto) => {
+                return Err(fail!(
+                    Config,
+                    "--loopback-extra-role needs an explicit role"
+                ));
+            }
+            Some(role) if role == self.loopback_role => {
+                return Err(fail!(
+                    Config,
+                    "--loopback-extra-role must differ from --loopback-role"
+                ));
+            }
+            _ => {}
+        }
+
+        // The audio would be drawn over the view
+        if self.tui && std::io::stdout().is_terminal() {
+            return Err(fail!(
+                Config,
+                "--tui draws on the console; send stdout (the audio stream) elsewhere, e.g. > NUL"
+            ));
+        }
+
+        // Session-derived names are sanitized here rather than trusted from the caller, then
+        // long output paths get the extended-length form on Windows
+        self.out = paths::expand_template(&self.out, &self.session);
+        self.out = paths::prepare_output(&self.out)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options_like_the_command_line() {
+        let args = Args::from_options(["--session", "s1", "--out", "call.wav", "--agc"]).unwrap();
+        assert_eq!((args.session.as_str(), args.agc), ("s1", true));
+        assert_eq!((args.sample_rate, args.channels), (48_000, 2));
+        assert!(Args::from_options(["--session", "s1"]).is_err());
+
+        let mut args =
+            Args::from_options(["--session", "s1", "--out", "a.wav", "--channels", "1"]).unwrap();
+        assert!(args.prepare().is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! Instance attach pipes
+//! A running capture serves two named pipes: `data` carries SELL frames out and control
+//! commands in, `events` mirrors the NDJSON event lines. `attach` connects to them and
+//! bridges them onto its own stdio, so an agent that lost its original capture process
+//! (restart, crash) reattaches by spawning `attach` instead of a duplicate capture.
+//!
+//! With `--observer-token` there is also a read-only surface for any number of observers:
+//! `observe` carries a copy of the frame stream and `observe.events` the event lines, each
+//! narrowed to what the observer subscribed to (see `subscription`). Nothing is ever read
+//! from an observer beyond its opening line (see `ipc_role`).
+
+use crate::control::{self, Request};
+use crate::error::fail;
+use crate::events;
+use crate::frame;
+use crate::ipc_role::{self, Role, Surface, Tokens};
+use crate::registry::{self, InstanceEntry};
+use crate::stream::{FrameOutput, ObserverFeed};
+use crate::subscription::Subscription;
+use anyhow::{Context, Result};
+use serde_json::json;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+};
+use tokio::sync::mpsc;
+
+/// Frame copies queued per observer (5 seconds) before it starts losing frames
+const OBSERVER_FRAMES: usize = 50;
+
+/// Serve the attach pipes for `entry` on the runtime. One agent can be attached at a time.
+/// The observe pipes are served too when `tokens` has an observer token.
+pub fn spawn_server(
+    entry: &InstanceEntry,
+    tokens: Tokens,
+    commands: mpsc::Sender<Request>,
+    outputs: mpsc::Sender<FrameOutput>,
+    observers: mpsc::UnboundedSender<ObserverFeed>,
+) -> Result<()> {
+    // Created up front, so a name clash shows up at session start rather than on attach
+    let (data, events_pipe) = create_pipes(entry, true)?;
+    if tokens.observer.is_some() {
+        let frames = create_pipe(&entry.observe_pipe, true)?;
+        let events_pipe = create_pipe(&entry.observe_events_pipe, true)?;
+        tokio::spawn(serve_observers(
+            entry.clone(),
+            tokens.clone(),
+            frames,
+            Feed::Frames(observers),
+        ));
+        tokio::spawn(serve_observers(
+            entry.clone(),
+            tokens.clone(),
+            events_pipe,
+            Feed::Events,
+        ));
+    }
+    tokio::spawn(serve(
+        entry.clone(),
+        tokens,
+        data,
+        events_pipe,
+        commands,
+        outputs,
+    ));
+    Ok(())
+}
+
+fn create_pipe(name: &str, first: bool) -> Result<NamedPipeServer> {
+    ServerOptions::new()
+        .first_pipe_instance(first)
+        .create(name)
+        .with_context(|| format!("Failed to create attach pipe {}", name))
+}
+
+fn create_pipes(entry: &InstanceEntry, first: bool) -> Result<(NamedPipeServer, NamedPipeServer)> {
+    Ok((
+        create_pipe(&entry.data_pipe, first)?,
+        create_pipe(&entry.events_pipe, first)?,
+    ))
+}
+
+async fn serve(
+    entry: InstanceEntry,
+    tokens: Tokens,
+    mut data: NamedPipeServer,
+    mut events_pipe: NamedPipeServer,
+    commands: mpsc::Sender<Request>,
+    outputs: mpsc::Sender<FrameOutput>,
+) {
+    loop {
+        let connected = async {
+            data.connect().await?;
+            events_pipe.connect().await
+        };
+        if let Err(e) = connected.await {
+            eprintln!("[win-audio-capture] Warning: Attach pipe failed: {}", e);
+            return;
+        }
+
+        let (reader, writer) = tokio::io::split(data);
+        let mut reader = BufReader::new(reader);
+        let admitted = authenticate(&mut reader, &tokens, Surface::Admin).await;
+        if admitted.is_none() {
+            // Dropping both ends disconnects the client
+            drop((reader, writer, events_pipe));
+            events::emit(
+                "ipc_rejected",
+                json!({ "session": entry.session, "surface": Surface::Admin }),
+            );
+            match create_pipes(&entry, false) {
+                Ok(pipes) => (data, events_pipe) = pipes,
+                Err(e) => {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                    return;
+                }
+            }
+            continue;
+        }
+
+        // Mirror events first so the agent sees its own `attached` event
+        let (mirror_tx, mut mirror_rx) = mpsc::unbounded_channel::<String>();
+        let mirror = tokio::spawn(async move {
+            while let Some(mut line) = mirror_rx.recv().await {
+                line.push('\n');
+                if events_pipe.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        events::set_mirror(Some(mirror_tx));
+        events::emit("attached", json!({ "session": entry.session }));
+
+        if outputs.send(Box::new(writer)).await.is_err() {
+            return;
+        }
+
+        // Commands flow until the agent closes its end
+        control::read_commands(reader, commands.clone()).await;
+
+        events::set_mirror(None);
+        mirror.abort();
+        // Drop frames quietly until the next agent attaches
+        if outputs.send(Box::new(tokio::io::sink())).await.is_err() {
+            return;
+        }
+        events::emit("detached", json!({ "session": entry.session }));
+
+        match create_pipes(&entry, false) {
+            Ok(pipes) => (data, events_pipe) = pipes,
+            Err(e) => {
+                eprintln!("[win-audio-capture] Warning: {:#}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Read the client's token line (when `surface` needs one) and decide its role, along with
+/// what the line subscribes to
+async fn authenticate<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    tokens: &Tokens,
+    surface: Surface,
+) -> Option<(Role, Subscription)> {
+    if !tokens.required(surface) {
+        let role = tokens.authorize(surface, None)?;
+        return Some((role, Subscription::default()));
+    }
+    let mut line = String::new();
+    let mut limited = reader.take(ipc_role::MAX_AUTH_LINE as u64);
+    let read = limited.read_line(&mut line);
+    match tokio::time::timeout(ipc_role::AUTH_TIMEOUT, read).await {
+        Ok(Ok(n)) if n > 0 => {
+            let role = tokens.authorize(surface, Some(&line))?;
+            Some((role, Subscription::from_line(&line)))
+        }
+        _ => None,
+    }
+}
+
+/// What an observe pipe carries
+#[derive(Clone)]
+enum Feed {
+    /// Copies of the frame stream, registered with the streamer
+    Frames(mpsc::UnboundedSender<ObserverFeed>),
+    /// Event lines
+    Events,
+}
+
+impl Feed {
+    fn name(&self) -> &'static str {
+        match self {
+            Feed::Frames(_) => "frames",
+            Feed::Events => "events",
+        }
+    }
+}
+
+/// Accept observers on `pipe`, a fresh pipe instance for each so any number can connect
+async fn serve_observers(
+    entry: InstanceEntry,
+    tokens: Tokens,
+    mut pipe: NamedPipeServer,
+    feed: Feed,
+) {
+    let name = match feed {
+        Feed::Frames(_) => &entry.observe_pipe,
+        Feed::Events => &entry.observe_events_pipe,
+    };
+    loop {
+        if let Err(e) = pipe.connect().await {
+            eprintln!("[win-audio-capture] Warning: Observe pipe failed: {}", e);
+            return;
+        }
+        let next = match create_pipe(name, false) {
+            Ok(next) => next,
+            Err(e) => {
+                eprintln!("[win-audio-capture] Warning: {:#}", e);
+                return;
+            }
+        };
+        let connected = std::mem::replace(&mut pipe, next);
+        tokio::spawn(observe(
+            entry.session.clone(),
+            tokens.clone(),
+            connected,
+            feed.clone(),
+        ));
+    }
+}
+
+/// Serve one connected observer until either end goes away
+async fn observe(session: String, tokens: Tokens, pipe: NamedPipeServer, feed: Feed) {
+    let (reader, mut writer) = tokio::io::split(pipe);
+    let mut reader = BufReader::new(reader);
+    let Some((role, subscription)) = authenticate(&mut reader, &tokens, Surface::Observer).await
+    else {
+        events::emit(
+            "ipc_rejected",
+            json!({ "session": session, "surface": Surface::Observer, "feed": feed.name() }),
+        );
+        return;
+    };
+
+    // Registered before announcing, so an events observer sees its own `observer_attached`
+    enum Incoming {
+        Frames(mpsc::Receiver<Vec<u8>>),
+        Events(mpsc::UnboundedReceiver<String>),
+    }
+    let mut incoming = match &feed {
+        Feed::Frames(observers) => {
+            let (tx, rx) = mpsc::channel(OBSERVER_FRAMES);
+            if observers.send(tx).is_err() {
+                return;
+            }
+            Incoming::Frames(rx)
+        }
+        Feed::Events => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            events::add_observer(tx, subscription.clone());
+            Incoming::Events(rx)
+        }
+    };
+    events::emit(
+        "observer_attached",
+        json!({
+            "session": session,
+            "role": role,
+            "feed": feed.name(),
+            "subscription": subscription,
+        }),
+    );
+
+    let forward = async {
+        loop {
+            let bytes = match &mut incoming {
+                Incoming::Frames(rx) => match rx.recv().await {
+                    Some(bytes) if !wants_frame(&subscription, &bytes) => continue,
+                    bytes => bytes,
+                },
+                Incoming::Events(rx) => rx.recv().await.map(|mut line| {
+                    line.push('\n');
+                    line.into_bytes()
+                }),
+            };
+            let Some(bytes) = bytes else { break };
+            if writer.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    };
+    let mut ignored = tokio::io::sink();
+    tokio::select! {
+        _ = forward => {}
+        // Anything an observer sends is ignored; EOF means it left
+        _ = tokio::io::copy(&mut reader, &mut ignored) => {}
+    }
+    events::emit(
+        "observer_detached",
+        json!({ "session": session, "feed": feed.name() }),
+    );
+}
+
+/// Whether the encoded frame `bytes` is one `subscription` takes
+fn wants_frame(subscription: &Subscription, bytes: &[u8]) -> bool {
+    frame::decode_header(bytes).is_ok_and(|header| subscription.wants_frame(header.kind))
+}
+
+/// Attach to the running capture for `session` and bridge it onto this process's stdio
+/// until the capture ends. `token` is the admin token, if the capture requires one.
+pub fn run_client(session: &str, token: Option<&str>) -> Result<()> {
+    let entry = find(session)?;
+    block_on(bridge(entry, token.map(token_line)))
+}
+
+/// Follow the running capture for `session` read-only: the frames and events of
+/// `subscription`, on stdout and stderr
+pub fn run_observer(session: &str, token: &str, subscription: Subscription) -> Result<()> {
+    let entry = find(session)?;
+    if entry.observe_pipe.is_empty() {
+        return Err(fail!(
+            Config,
+            "The capture for session {:?} has no observe pipes",
+            session
+        ));
+    }
+    let frames = subscription
+        .frames
+        .as_ref()
+        .is_none_or(|frames| !frames.is_empty());
+    let mut line = serde_json::to_value(&subscription)?;
+    line["token"] = json!(token);
+    block_on(watch(entry, format!("{}\n", line), frames))
+}
+
+fn find(session: &str) -> Result<InstanceEntry> {
+    registry::find(&registry::registry_dir(), session)?
+        .ok_or_else(|| fail!(Config, "No running capture for session {:?}", session))
+}
+
+fn token_line(token: &str) -> String {
+    format!("{}\n", json!({ "token": token }))
+}
+
+fn block_on(client: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")?;
+    let result = runtime.block_on(client);
+
+    // The stdin copy sits in a blocking read that can't be cancelled, don't wait for it
+    runtime.shutdown_background();
+    result
+}
+
+fn open(name: &str) -> Result<NamedPipeClient> {
+    ClientOptions::new().open(name).with_context(|| {
+        format!(
+            "Failed to open attach pipe {} (is another agent already attached?)",
+            name
+        )
+    })
+}
+
+async fn bridge(entry: InstanceEntry, token: Option<String>) -> Result<()> {
+    let mut data = open(&entry.data_pipe)?;
+    let mut events_pipe = open(&entry.events_pipe)?;
+    if let Some(token) = token {
+        data.write_all(token.as_bytes())
+            .await
+            .context("Failed to send attach token")?;
+    }
+    events::emit(
+        "attaching",
+        json!({ "session": entry.session, "pid": entry.pid, "out": entry.out }),
+    );
+
+    let (mut data_read, mut data_write) = tokio::io::split(data);
+    let commands = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut tokio::io::stdin(), &mut data_write).await;
+    });
+    let events = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut events_pipe, &mut tokio::io::stderr()).await;
+    });
+
+    let mut stdout = tokio::io::stdout();
+    tokio::select! {
+        // The instance closes the pipes when capture ends
+        frames = tokio::io::copy(&mut data_read, &mut stdout) => {
+            frames.context("Attach data pipe failed")?;
+            let _ = events.await;
+        }
+        // Our agent went away; exiting closes the pipes and detaches without stopping
+        // the capture
+        _ = commands => {}
+    }
+    Ok(())
+}
+
+/// Follow the observe pipes, opening with `line`; the frame pipe only if `frames`
+async fn watch(entry: InstanceEntry, line: String, frames: bool) -> Result<()> {
+    let mut events_pipe = open(&entry.observe_events_pipe)?;
+    events_pipe
+        .write_all(line.as_bytes())
+        .await
+        .context("Failed to send observer token")?;
+    if !frames {
+        // The pipe closes when capture ends, or right away if the token was refused
+        tokio::io::copy(&mut events_pipe, &mut tokio::io::stderr())
+            .await
+            .context("Observe pipe failed")?;
+        return Ok(());
+    }
+    let mut frames = open(&entry.observe_pipe)?;
+    frames
+        .write_all(line.as_bytes())
+        .await
+        .context("Failed to send observer token")?;
+    let events = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut events_pipe, &mut tokio::io::stderr()).await;
+    });
+    // Both pipes close when capture ends, or right away if the token was refused
+    tokio::io::copy(&mut frames, &mut tokio::io::stdout())
+        .await
+        .context("Observe pipe failed")?;
+    let _ = events.await;
+    Ok(())
+}
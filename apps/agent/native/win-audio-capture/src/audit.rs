@@ -0,0 +1,258 @@
+//! Session audit log
+//! Every action that changes what ends up in a recording (start, stop, mute, suspend,
+//! device changes, MIC processing changes, hook commands, later redactions) is appended to
+//! `<stem>.audit.jsonl` next to it, with its wall time, sample position and actor: who or
+//! what asked for it (`cli`, `control`, `os`, `headset`, `signal`).
+//!
+//! Each line carries the previous entry's hash and the SHA-256 of its own content, so an
+//! edited, reordered or removed entry breaks the chain from that point on;
+//! `audit-verify <file>` walks it. The head hash goes out in the `stopped` event as well,
+//! so a log regenerated from scratch won't match what the agent was told at the time.
+
+use crate::error::fail;
+use crate::events;
+use crate::sha256;
+use crate::timeline;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// `prev_hash` of the first entry
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Suffix of the hash spliced onto every line after its hashed content
+const HASH_KEY: &str = ",\"hash\":\"";
+
+/// One entry, as hashed (the line adds `hash` after these fields)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    pub wall_time_ms: u64,
+    pub action: String,
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_position: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    pub prev_hash: String,
+}
+
+/// Appends to a recording's audit log, continuing its chain if one exists
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    next_seq: u64,
+    head: String,
+}
+
+/// Path of the audit log of `recording`
+pub fn path(recording: &Path) -> PathBuf {
+    timeline::sidecar_path(recording, "audit.jsonl")
+}
+
+/// Name of the signed-in user, for entries made from the command line
+pub fn local_user() -> String {
+    std::env::var("USERNAME")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+impl AuditLog {
+    pub fn open(recording: &Path) -> Result<Self> {
+        let path = path(recording);
+        let (next_seq, head) = match std::fs::read_to_string(&path) {
+            Ok(text) => match text.lines().rfind(|line| !line.trim().is_empty()) {
+                Some(line) => {
+                    let (entry, hash) = split_line(line)
+                        .with_context(|| format!("Unreadable audit log {}", path.display()))?;
+                    (entry.seq + 1, hash.to_string())
+                }
+                None => (0, GENESIS.to_string()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, GENESIS.to_string()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self {
+            path,
+            file,
+            next_seq,
+            head,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hash of the last entry
+    pub fn head(&self) -> &str {
+        &self.head
+    }
+
+    /// Append an entry and flush it to disk before returning
+    pub fn append(
+        &mut self,
+        action: &str,
+        actor: &str,
+        sample_position: Option<u64>,
+        data: Option<Value>,
+    ) -> Result<()> {
+        let entry = Entry {
+            seq: self.next_seq,
+            wall_time_ms: events::unix_millis(),
+            action: action.to_string(),
+            actor: actor.to_string(),
+            sample_position,
+            data,
+            prev_hash: self.head.clone(),
+        };
+        let body = serde_json::to_string(&entry)?;
+        let hash = sha256::hex_digest(body.as_bytes());
+        let line = format!("{}{}{}\"}}\n", &body[..body.len() - 1], HASH_KEY, hash);
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("Failed to append to {}", self.path.display()))?;
+        self.next_seq += 1;
+        self.head = hash;
+        Ok(())
+    }
+}
+
+/// Split a line into its entry and the hash it claims, checking that the hash matches
+fn split_line(line: &str) -> Result<(Entry, &str)> {
+    let at = line
+        .rfind(HASH_KEY)
+        .ok_or_else(|| fail!(Format, "Entry has no hash"))?;
+    let hash = line[at + HASH_KEY.len()..]
+        .strip_suffix("\"}")
+        .ok_or_else(|| fail!(Format, "Entry has no hash"))?;
+    let body = format!("{}}}", &line[..at]);
+    if sha256::hex_digest(body.as_bytes()) != hash {
+        return Err(fail!(Format, "Entry content doesn't match its hash"));
+    }
+    let entry = serde_json::from_str(&body).context("Malformed entry")?;
+    Ok((entry, hash))
+}
+
+/// Outcome of `verify`
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    pub path: PathBuf,
+    /// Entries that check out, from the start
+    pub entries: u64,
+    pub head: String,
+    pub intact: bool,
+    /// Whether the capture recorded its `stopped` entry
+    pub sealed: bool,
+    /// Sequence number where the chain breaks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+/// Walk the audit log of `recording` (or the log itself) and check the chain
+pub fn verify(recording: &Path) -> Result<Verification> {
+    let path = if recording.to_string_lossy().ends_with(".audit.jsonl") {
+        recording.to_path_buf()
+    } else {
+        path(recording)
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read audit log {}", path.display()))?;
+
+    let mut result = Verification {
+        path,
+        entries: 0,
+        head: GENESIS.to_string(),
+        intact: true,
+        sealed: false,
+        broken_at: None,
+        problem: None,
+    };
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let seq = result.entries;
+        let problem = match split_line(line) {
+            Ok((entry, _)) if entry.seq != seq => Some(format!("Expected entry {}", seq)),
+            Ok((entry, _)) if entry.prev_hash != result.head => {
+                Some("Entry doesn't follow the previous one".to_string())
+            }
+            Ok((entry, hash)) => {
+                result.sealed |= entry.action == "stopped";
+                result.head = hash.to_string();
+                None
+            }
+            Err(e) => Some(format!("{:#}", e)),
+        };
+        if let Some(problem) = problem {
+            result.intact = false;
+            result.broken_at = Some(seq);
+            result.problem = Some(problem);
+            break;
+        }
+        result.entries += 1;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write_log(recording: &Path) {
+        let mut log = AuditLog::open(recording).unwrap();
+        log.append("start", "cli", Some(0), Some(json!({ "session": "s1" })))
+            .unwrap();
+        log.append("mute", "os", Some(4_800), None).unwrap();
+        log.append("stopped", "cli", Some(9_600), None).unwrap();
+    }
+
+    #[test]
+    fn chains_entries_and_continues_an_existing_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("call.wav");
+        write_log(&recording);
+
+        let mut log = AuditLog::open(&recording).unwrap();
+        let head = log.head().to_string();
+        log.append("redact", "cli", None, Some(json!({ "user": "legal" })))
+            .unwrap();
+
+        let verification = verify(&recording).unwrap();
+        assert!(verification.intact && verification.sealed);
+        assert_eq!(verification.entries, 4);
+        assert_eq!(verification.head, log.head());
+        let text = std::fs::read_to_string(path(&recording)).unwrap();
+        let (first, _) = split_line(text.lines().next().unwrap()).unwrap();
+        assert_eq!(first.prev_hash, GENESIS);
+        let (last, _) = split_line(text.lines().last().unwrap()).unwrap();
+        assert_eq!((last.seq, last.prev_hash), (3, head));
+    }
+
+    #[test]
+    fn detects_edited_and_removed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("call.wav");
+        write_log(&recording);
+        let original = std::fs::read_to_string(path(&recording)).unwrap();
+
+        std::fs::write(path(&recording), original.replace("\"os\"", "\"cli\"")).unwrap();
+        let edited = verify(&recording).unwrap();
+        assert_eq!((edited.intact, edited.broken_at), (false, Some(1)));
+
+        let lines: Vec<&str> = original.lines().collect();
+        std::fs::write(path(&recording), format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let removed = verify(&recording).unwrap();
+        assert_eq!((removed.entries, removed.broken_at), (1, Some(1)));
+    }
+}
@@ -0,0 +1,256 @@
+//! Auto-redaction of spoken numbers
+//! With `--auto-redact` the words of `transcript` commands are searched for card numbers
+//! (13 to 19 digits passing the Luhn check) and US social security numbers read out, digit
+//! by digit or in groups. A match whose words all reach `--auto-redact-confidence` is
+//! masked in the `transcript` event, reported as `auto_redacted` and silenced in the
+//! recording once it is finalized, padded by `--auto-redact-pre-ms`/`--auto-redact-post-ms`.
+//! The silenced spans go into the `redactions` log of the timeline like those of `redact`.
+//! A backend that reports no confidences passes the gate.
+//!
+//! The digits themselves are never logged.
+
+use crate::transcript::AlignedWord;
+use serde::Serialize;
+
+/// What a masked word's text is replaced with
+pub const MASK: &str = "[redacted]";
+
+/// What was read out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    CardNumber,
+    Ssn,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::CardNumber => "card_number",
+            Kind::Ssn => "ssn",
+        }
+    }
+}
+
+/// A span of the session to silence, padding included
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Span {
+    pub kind: Kind,
+    /// Absolute sample positions `[sample_position, end_position)`
+    pub sample_position: u64,
+    pub end_position: u64,
+    /// Lowest confidence among the matched words
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+}
+
+/// Finds sensitive numbers in transcripts and collects the spans to silence
+#[derive(Debug)]
+pub struct AutoRedactor {
+    min_confidence: f32,
+    pre_frames: u64,
+    post_frames: u64,
+    spans: Vec<Span>,
+}
+
+impl AutoRedactor {
+    pub fn new(min_confidence: f32, pre_ms: u64, post_ms: u64, sample_rate: u32) -> Self {
+        Self {
+            min_confidence,
+            pre_frames: pre_ms * sample_rate as u64 / 1000,
+            post_frames: post_ms * sample_rate as u64 / 1000,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Mask the sensitive numbers in `words`; returns the spans found. A span overlapping
+    /// one found before (the final result of an interim one) is merged into it.
+    pub fn scan(&mut self, words: &mut [AlignedWord]) -> Vec<Span> {
+        let mut found = Vec::new();
+        for (kind, range) in detect(words, self.min_confidence) {
+            let matched = &words[range.clone()];
+            let span = Span {
+                kind,
+                sample_position: matched[0].sample_position.saturating_sub(self.pre_frames),
+                end_position: matched[matched.len() - 1].end_position + self.post_frames,
+                confidence: matched
+                    .iter()
+                    .filter_map(|word| word.confidence)
+                    .reduce(f32::min),
+            };
+            for word in &mut words[range] {
+                word.text = MASK.to_string();
+            }
+            self.insert(span);
+            found.push(span);
+        }
+        found
+    }
+
+    /// Every span found so far, in order and not overlapping
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    fn insert(&mut self, span: Span) {
+        let overlapping = |s: &Span| {
+            s.sample_position < span.end_position && span.sample_position < s.end_position
+        };
+        let mut merged = span;
+        self.spans.retain(|s| {
+            if !overlapping(s) {
+                return true;
+            }
+            merged.sample_position = merged.sample_position.min(s.sample_position);
+            merged.end_position = merged.end_position.max(s.end_position);
+            false
+        });
+        let index = self
+            .spans
+            .partition_point(|s| s.sample_position < merged.sample_position);
+        self.spans.insert(index, merged);
+    }
+}
+
+/// Digits a word reads out: `4111`, `41-11`, `four`, `oh`; `None` for other words
+fn digits_of(text: &str) -> Option<String> {
+    let word = text
+        .trim_matches(|c: char| c.is_ascii_punctuation())
+        .to_ascii_lowercase();
+    let spelled = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+    ];
+    if let Some(digit) = spelled.iter().position(|&name| name == word) {
+        return Some(digit.to_string());
+    }
+    if word == "oh" {
+        return Some("0".to_string());
+    }
+    let digits: String = word.chars().filter(|c| c.is_ascii_digit()).collect();
+    let rest_separators = word.chars().all(|c| c.is_ascii_digit() || c == '-');
+    (!digits.is_empty() && rest_separators).then_some(digits)
+}
+
+fn luhn(digits: &str) -> bool {
+    let sum: u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(i, b)| {
+            let d = (b - b'0') as u32;
+            match i % 2 {
+                0 => d,
+                _ if d > 4 => d * 2 - 9,
+                _ => d * 2,
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn is_ssn(digits: &str) -> bool {
+    digits.len() == 9
+        && !digits.starts_with("000")
+        && !digits.starts_with("666")
+        && !digits.starts_with('9')
+        && &digits[3..5] != "00"
+        && &digits[5..] != "0000"
+}
+
+fn classify(digits: &str) -> Option<Kind> {
+    if (13..=19).contains(&digits.len()) && luhn(digits) {
+        Some(Kind::CardNumber)
+    } else if is_ssn(digits) {
+        Some(Kind::Ssn)
+    } else {
+        None
+    }
+}
+
+/// Runs of words reading out a card number or SSN, longest first from each start
+fn detect(words: &[AlignedWord], min_confidence: f32) -> Vec<(Kind, std::ops::Range<usize>)> {
+    let digits: Vec<Option<String>> = words.iter().map(|word| digits_of(&word.text)).collect();
+    let confident = |word: &AlignedWord| word.confidence.is_none_or(|c| c >= min_confidence);
+    let mut found = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let run_end = (start..words.len())
+            .find(|&i| digits[i].is_none())
+            .unwrap_or(words.len());
+        let matched = (start + 1..=run_end).rev().find_map(|end| {
+            let number: String = digits[start..end].iter().flatten().cloned().collect();
+            let kind = classify(&number)?;
+            words[start..end]
+                .iter()
+                .all(confident)
+                .then_some((kind, start..end))
+        });
+        match matched {
+            Some((kind, range)) => {
+                start = range.end;
+                found.push((kind, range));
+            }
+            None => start += 1,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str, confidence: f32) -> Vec<AlignedWord> {
+        text.split(' ')
+            .enumerate()
+            .map(|(i, text)| AlignedWord {
+                text: text.to_string(),
+                sample_position: i as u64 * 1_000,
+                end_position: i as u64 * 1_000 + 800,
+                confidence: Some(confidence),
+                speaker: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn masks_card_numbers_and_ssns_read_out() {
+        let mut redactor = AutoRedactor::new(0.8, 100, 200, 1_000);
+        let mut said = words(
+            "it's 4111 1111 1111 1111 and my social is 123 45 6789.",
+            0.9,
+        );
+        let spans = redactor.scan(&mut said);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].kind, Kind::CardNumber);
+        assert_eq!(
+            (spans[0].sample_position, spans[0].end_position),
+            (900, 5_000)
+        );
+        assert_eq!(spans[1].kind, Kind::Ssn);
+        assert_eq!(said[1].text, MASK);
+        assert_eq!(said[9].text, MASK);
+        assert_eq!(said[7].text, "social");
+
+        // Spelled out, and a Luhn failure isn't a card
+        let mut spelled = words(
+            "four one one one one one one one one one one one one one one one",
+            0.9,
+        );
+        assert_eq!(redactor.scan(&mut spelled)[0].kind, Kind::CardNumber);
+        assert!(redactor
+            .scan(&mut words("4111 1111 1111 1112", 0.9))
+            .is_empty());
+    }
+
+    #[test]
+    fn gates_on_confidence_and_merges_repeats() {
+        let mut redactor = AutoRedactor::new(0.8, 0, 0, 1_000);
+        assert!(redactor.scan(&mut words("123 45 6789", 0.5)).is_empty());
+        // An interim result and its final one silence one span
+        redactor.scan(&mut words("123 45 6789", 0.85));
+        redactor.scan(&mut words("123 45 6789", 0.95));
+        assert_eq!(redactor.spans().len(), 1);
+        assert_eq!(redactor.spans()[0].end_position, 2_800);
+    }
+}
@@ -0,0 +1,213 @@
+//! Memory budget
+//! `--max-buffer-mb N` bounds the audio the capture holds in memory at any time, so a
+//! stalled disk or consumer can't grow the sidecar until it is OOM-killed next to the
+//! conferencing app. The budget is split by weight over the buffers in use (the mixer
+//! queue, the sink queue that also absorbs slow or staged storage, the live stream queue,
+//! the DSP comparison queue and the rolling buffer) and each share becomes that buffer's
+//! depth. The budget only ever lowers the built-in depths. A buffer at its share follows
+//! its policy: the mixer and sink queues push back (the mixer then skips ahead, a gap the
+//! timeline documents), the stream and comparison queues drop the new block, and the
+//! rolling buffer lets its oldest audio go early. Every time that happens counts as a
+//! budget-pressure event; the first one of each buffer is reported as `budget_pressure`.
+//! Depths are counted in mixer blocks of one tick, so a block mixed after a stall can
+//! take more than its share for as long as it is queued.
+
+use crate::error::fail;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Fewest blocks a queue is given, whatever the budget
+pub const MIN_DEPTH: usize = 16;
+/// Allocation overhead counted per queued block
+const BLOCK_OVERHEAD: usize = 32;
+
+/// A buffer the budget is split over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pool {
+    Mixer,
+    Sink,
+    Stream,
+    Compare,
+    Rolling,
+}
+
+impl Pool {
+    const ALL: [Pool; 5] = [
+        Pool::Mixer,
+        Pool::Sink,
+        Pool::Stream,
+        Pool::Compare,
+        Pool::Rolling,
+    ];
+
+    /// Share of the budget relative to the other buffers in use
+    fn weight(self) -> usize {
+        match self {
+            Pool::Mixer | Pool::Stream | Pool::Compare => 1,
+            Pool::Sink => 4,
+            Pool::Rolling => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pool::Mixer => "mixer",
+            Pool::Sink => "sink",
+            Pool::Stream => "stream",
+            Pool::Compare => "compare",
+            Pool::Rolling => "rolling",
+        }
+    }
+
+    /// What happens to a block once the buffer is at its share
+    pub fn policy(self) -> &'static str {
+        match self {
+            Pool::Mixer | Pool::Sink => "backpressure",
+            Pool::Stream | Pool::Compare => "drop_newest",
+            Pool::Rolling => "evict_oldest",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// `--max-buffer-mb`, split over the buffers of a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    shares: BTreeMap<&'static str, usize>,
+}
+
+impl Budget {
+    pub fn new(max_mb: u64, pools: &[Pool]) -> Self {
+        let total = max_mb as usize * 1024 * 1024;
+        let weights: usize = pools.iter().map(|pool| pool.weight()).sum();
+        let shares = pools
+            .iter()
+            .map(|&pool| (pool.name(), total * pool.weight() / weights.max(1)))
+            .collect();
+        Self { shares }
+    }
+
+    /// Bytes `pool` may hold, if it is in use
+    pub fn bytes(&self, pool: Pool) -> Option<usize> {
+        self.shares.get(pool.name()).copied()
+    }
+
+    /// Depth of the queue for `pool` with blocks of `block_bytes`, at most `default`
+    pub fn depth(&self, pool: Pool, block_bytes: usize, default: usize) -> Result<usize> {
+        let Some(bytes) = self.bytes(pool) else {
+            return Ok(default);
+        };
+        let depth = bytes / (block_bytes + BLOCK_OVERHEAD);
+        if depth < MIN_DEPTH {
+            return Err(fail!(
+                Config,
+                "--max-buffer-mb leaves the {} queue {} blocks, it needs at least {}",
+                pool.name(),
+                depth,
+                MIN_DEPTH
+            ));
+        }
+        Ok(depth.min(default))
+    }
+}
+
+/// Bytes of one mixer block of `channels` at `sample_rate` with a tick of `tick`
+pub fn block_bytes(sample_rate: u32, channels: usize, tick: Duration) -> usize {
+    let frames = (sample_rate as u128 * tick.as_millis() / 1_000) as usize;
+    frames.max(1) * channels * std::mem::size_of::<i16>()
+}
+
+/// Budget-pressure events per buffer, shared with the mixer and sink threads
+#[derive(Debug, Default)]
+pub struct Pressure {
+    events: [AtomicU64; 5],
+    reported: [AtomicBool; 5],
+}
+
+impl Pressure {
+    pub fn hit(&self, pool: Pool) {
+        self.events[pool.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Buffers under pressure for the first time since the last call
+    pub fn newly_hit(&self) -> Vec<Pool> {
+        Pool::ALL
+            .into_iter()
+            .filter(|pool| {
+                self.events[pool.index()].load(Ordering::Relaxed) > 0
+                    && !self.reported[pool.index()].swap(true, Ordering::Relaxed)
+            })
+            .collect()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.events
+            .iter()
+            .map(|events| events.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Events per buffer, for the `stats` and `stopped` events
+    pub fn counts(&self) -> BTreeMap<&'static str, u64> {
+        Pool::ALL
+            .iter()
+            .map(|&pool| {
+                (
+                    pool.name(),
+                    self.events[pool.index()].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_budget_over_the_buffers_in_use() {
+        let tick = Duration::from_millis(10);
+        let stereo = block_bytes(48_000, 2, tick);
+        assert_eq!(stereo, 1_920);
+
+        // 8 + 4 + 1 + 1 of 14 shares
+        let budget = Budget::new(14, &[Pool::Mixer, Pool::Sink, Pool::Stream, Pool::Rolling]);
+        let mb = 1024 * 1024;
+        assert_eq!(budget.bytes(Pool::Rolling), Some(8 * mb));
+        assert_eq!(budget.bytes(Pool::Compare), None);
+        assert_eq!(
+            budget.depth(Pool::Sink, stereo, 4_096).unwrap(),
+            4 * mb / 1_952
+        );
+        // Never deeper than built in, and untouched when not budgeted
+        assert_eq!(budget.depth(Pool::Mixer, stereo, 256).unwrap(), 256);
+        assert_eq!(budget.depth(Pool::Compare, stereo, 256).unwrap(), 256);
+
+        let tight = Budget::new(1, &[Pool::Mixer, Pool::Sink, Pool::Stream]);
+        assert!(tight.depth(Pool::Mixer, stereo, 256).is_ok());
+        let wide = block_bytes(96_000, 8, tick);
+        assert!(tight.depth(Pool::Mixer, wide, 256).is_err());
+    }
+
+    #[test]
+    fn reports_each_buffer_once() {
+        let pressure = Pressure::default();
+        assert!(pressure.newly_hit().is_empty());
+        pressure.hit(Pool::Stream);
+        pressure.hit(Pool::Stream);
+        pressure.hit(Pool::Rolling);
+        assert_eq!(pressure.newly_hit(), [Pool::Stream, Pool::Rolling]);
+        pressure.hit(Pool::Stream);
+        assert!(pressure.newly_hit().is_empty());
+        assert_eq!(pressure.total(), 4);
+        assert_eq!(pressure.counts()["stream"], 3);
+    }
+}
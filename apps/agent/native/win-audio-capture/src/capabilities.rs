@@ -0,0 +1,223 @@
+//! Capability report
+//! `capabilities --json` tells the agent up front what this sidecar can do on this machine,
+//! so it can hide or grey out options instead of starting a session and reading why it
+//! failed: the optional parts compiled into the build (Cargo features), the premium modules
+//! the `--license-token` permits, what the OS offers (the process loopback needs Windows 10
+//! 20H1, so it is probed by activating it on this process) and the hardware it found (cores,
+//! architecture, power source and audio endpoints). Nothing in it is fatal; what couldn't be
+//! found out is `null` or comes with its error.
+
+use crate::devices::{self, Endpoint};
+use crate::license::{self, Feature, Policy};
+use crate::power::{self, PowerStatus};
+use crate::self_test;
+use serde::Serialize;
+
+/// Everything `capabilities` reports
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    /// Cargo features and whether this build has them
+    pub compiled: Vec<Compiled>,
+    pub license: LicenseReport,
+    pub os: OsReport,
+    pub hardware: HardwareReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Compiled {
+    pub feature: &'static str,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LicenseReport {
+    /// The verified policy, `None` without a token or with one that didn't verify
+    pub policy: Option<Policy>,
+    /// Why the token was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Every premium module and whether the policy permits it
+    pub features: Vec<Permitted>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Permitted {
+    pub feature: &'static str,
+    pub permitted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OsReport {
+    pub family: &'static str,
+    /// Windows build number, e.g. 19045
+    pub build: Option<u32>,
+    pub process_loopback: Ability,
+}
+
+/// Whether the OS can do something, and why not
+#[derive(Debug, Clone, Serialize)]
+pub struct Ability {
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HardwareReport {
+    pub cores: usize,
+    /// Architecture the binary was built for
+    pub arch: &'static str,
+    /// The machine's own, which differs under emulation
+    pub os_arch: Option<&'static str>,
+    pub power: PowerStatus,
+    /// Active endpoints, `None` when they couldn't be listed
+    pub endpoints: Option<Vec<Endpoint>>,
+}
+
+/// Detect what this build can do here, with the policy of `license_token` if given
+pub fn detect(license_token: Option<&str>) -> Capabilities {
+    let (policy, error) = match license_token.map(license::verify) {
+        Some(Ok(policy)) => (Some(policy), None),
+        Some(Err(e)) => (None, Some(format!("{:#}", e))),
+        None => (None, None),
+    };
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        compiled: compiled(),
+        license: LicenseReport {
+            features: permitted(policy.as_ref()),
+            policy,
+            error,
+        },
+        os: OsReport {
+            family: std::env::consts::OS,
+            build: os::build(),
+            process_loopback: os::process_loopback(),
+        },
+        hardware: HardwareReport {
+            cores: std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            arch: std::env::consts::ARCH,
+            os_arch: self_test::os_arch(),
+            power: power::power_status(),
+            endpoints: devices::list().ok(),
+        },
+    }
+}
+
+fn compiled() -> Vec<Compiled> {
+    [
+        ("otlp", cfg!(feature = "otlp")),
+        ("self_update", cfg!(feature = "self-update")),
+        ("codecs", cfg!(feature = "codecs")),
+        ("opus", cfg!(feature = "opus")),
+        ("static_crt", cfg!(feature = "static-crt")),
+    ]
+    .into_iter()
+    .map(|(feature, enabled)| Compiled { feature, enabled })
+    .collect()
+}
+
+fn permitted(policy: Option<&Policy>) -> Vec<Permitted> {
+    Feature::ALL
+        .into_iter()
+        .map(|feature| Permitted {
+            feature: feature.name(),
+            permitted: policy.is_some_and(|policy| policy.allows(feature)),
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+mod os {
+    use super::Ability;
+    use crate::process_loopback;
+    use windows::core::w;
+    use windows::Win32::Foundation::ERROR_SUCCESS;
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use windows::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+
+    /// From the registry, as version APIs report 8.1 to unmanifested binaries
+    pub fn build() -> Option<u32> {
+        let mut buffer = [0u16; 16];
+        let mut size = std::mem::size_of_val(&buffer) as u32;
+        let status = unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                w!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"),
+                w!("CurrentBuildNumber"),
+                RRF_RT_REG_SZ,
+                None,
+                Some(buffer.as_mut_ptr().cast()),
+                Some(&mut size),
+            )
+        };
+        if status != ERROR_SUCCESS {
+            return None;
+        }
+        let len = (size as usize / 2).saturating_sub(1).min(buffer.len());
+        String::from_utf16(&buffer[..len]).ok()?.parse().ok()
+    }
+
+    pub fn process_loopback() -> Ability {
+        let activated = unsafe {
+            let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+            process_loopback::activate(std::process::id())
+        };
+        match activated {
+            Ok(_) => Ability {
+                available: true,
+                reason: None,
+            },
+            Err(e) => Ability {
+                available: false,
+                reason: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod os {
+    use super::Ability;
+
+    pub fn build() -> Option<u32> {
+        None
+    }
+
+    pub fn process_loopback() -> Ability {
+        Ability {
+            available: false,
+            reason: Some("Process loopback needs Windows 10 20H1 or later".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_premium_module_against_the_policy() {
+        let policy = Policy {
+            tenant: "acme".to_string(),
+            features: vec!["encryption".to_string(), "transcribe_v2".to_string()],
+            exp: None,
+        };
+        let report = |policy| {
+            permitted(policy)
+                .into_iter()
+                .map(|p| (p.feature, p.permitted))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            report(Some(&policy)),
+            [("aec", false), ("local_asr", false), ("encryption", true)]
+        );
+        assert!(report(None).iter().all(|&(_, permitted)| !permitted));
+
+        let refused = detect(Some("not-a-token")).license;
+        assert!(refused.policy.is_none() && refused.error.is_some());
+        assert!(compiled().iter().any(|c| c.feature == "opus"));
+    }
+}
@@ -0,0 +1,4460 @@
+//! MIC + loopback capture pipeline
+//! Opens the devices and wires the session together: device callbacks feed the mixer
+//! engine thread, and the async runtime hosts everything else (control channel, signals,
+//! the recording sink on a blocking worker, the stdout frame stream).
+
+use crate::attach;
+use crate::audit::{self, AuditLog};
+use crate::auto_redact::{self, AutoRedactor};
+use crate::budget::{self, Budget, Pool, Pressure};
+use crate::chapters::{Chapter, ChapterDetector};
+use crate::clock_sync::{ClockSource, ClockSync, ClockTracker, Correction, ExternalClock};
+use crate::conferencing::{self, Meeting, MeetingTracker};
+use crate::control::{self, ControlCommand, Outcome, Outcomes, Request};
+use crate::device_cache::{CachedConfig, DeviceCache};
+use crate::device_config::{CaptureConfig, DeviceOverride};
+use crate::device_select::{self, DeviceStrategy};
+use crate::device_watch::{self, DeviceChange, Reaction};
+use crate::devices::{self, Flow};
+use crate::dsp::{self, DspParams, DspUpdate};
+use crate::ducking::{self, DuckEvent, DuckTracker, DuckWatcher, DuckingMode};
+use crate::durations::{self, Durations, SessionFrames};
+use crate::endpoint_mute::{self, MuteWatcher};
+use crate::engine::{self, AudioBlock, EngineConfig, EngineSources, Gap};
+use crate::envelope::{self, Envelope, SealedSink};
+use crate::error::{fail, ErrorKind};
+use crate::etw;
+use crate::events;
+use crate::footprint::{self, FootprintTracker};
+use crate::gain_staging::GainStaging;
+use crate::headset::{HeadsetButton, HeadsetControls};
+use crate::health::{Heartbeat, LiveCounters};
+use crate::hid_telephony;
+use crate::highlights;
+use crate::hooks::{self, HookOutcome};
+use crate::instance_lock::{self, InstanceLock, Scope};
+use crate::ipc_role::Tokens;
+use crate::jobs;
+use crate::journal::{self, Journal};
+use crate::latency::{self, Alignment, LatencyCompensation};
+use crate::mic_lock;
+use crate::mixer::{self, SystemClock};
+use crate::mute::{MuteState, MuteSync};
+use crate::notes::{self, NotePlayer, NoteSamples, NotesMode};
+use crate::output_location;
+use crate::paths;
+use crate::pipeline::PipelineTag;
+use crate::polarity::{PolarityDetector, PolarityFlip, PolarityMode};
+use crate::power::{self, PowerProfile};
+use crate::preview;
+use crate::process_loopback;
+use crate::progress::{Finalize, Stage};
+use crate::rate_negotiation::{self, Negotiated, SourceRate};
+use crate::reconfigure::{self, AddedSink, SinkSetup};
+use crate::recovery;
+use crate::redact::{self, Redaction};
+use crate::registry::{self, InstanceEntry};
+use crate::resample::{ResampleQuality, Resampler};
+use crate::riff;
+use crate::rolling::{Committed, RollingBuffer};
+use crate::rt;
+#[cfg(feature = "self-update")]
+use crate::self_update;
+use crate::session::SourceCounter;
+use crate::shared_pcm;
+use crate::shm;
+use crate::silence::{self, Elision, SilenceCompactor};
+use crate::sink::{self, mka, OutputFormat, RecordingSink, SinkSpec, SinkSummary};
+use crate::soak::{self, BlockablePipe, Faults, Invariants, Probe, Scheduler, StallingSink};
+use crate::spool;
+use crate::stream::{self, FrameOutput, Notice, Protocol, StdoutFormat};
+use crate::suspend::{self, PowerEvent};
+use crate::sync_pulse::SyncPulse;
+use crate::synth;
+use crate::timeline::{self, ChainLink, Timeline, TimelineEntry};
+use crate::trace;
+use crate::tracks::{self, OutputMode};
+use crate::transcript;
+use crate::trim::{self, SilenceTracker, Trim};
+use crate::tui;
+use crate::upload_manifest::{self, UploadManifest};
+use crate::warnings;
+use crate::wasapi_loopback::{self, WasapiLoopbackCapture};
+use crate::wasapi_mic::{self, MicBackend, WasapiMicStream};
+use crate::watchdog::{self, StallStats, StallWatchdog};
+use crate::wipe;
+use crate::workspace::Workspace;
+use crate::Args;
+#[cfg(feature = "otlp")]
+use crate::{http, otlp};
+use anyhow::{anyhow, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::StreamConfig;
+use rtrb::{Consumer, RingBuffer};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Mixed blocks buffered between the engine and each consumer (~2.5s at 10ms blocks)
+const BLOCK_QUEUE_DEPTH: usize = 256;
+
+/// Sink queue depth when the output is staged off slow storage (~40s at 10ms blocks)
+const STAGED_SINK_QUEUE_DEPTH: usize = 4096;
+
+/// Polarity flips a MIC callback can queue (they come seconds apart at most)
+const POLARITY_FLIP_QUEUE: usize = 8;
+
+/// How often queued polarity flips are picked up
+const POLARITY_FLIP_POLL: Duration = Duration::from_millis(100);
+
+/// MIC stream errors a callback can queue between two pickups (a flapping device fails
+/// every callback)
+const STREAM_ERROR_QUEUE: usize = 256;
+
+/// Launch to first mixed block the default build is held to
+const STARTUP_BUDGET: Duration = Duration::from_millis(300);
+
+/// What drives a session run inside another process, in place of its stdio
+pub struct Host {
+    /// Control commands, as they would come in on stdin
+    pub commands: mpsc::Receiver<Request>,
+    /// Where the frame stream goes instead of stdout
+    pub frames: FrameOutput,
+    /// When the host asked for the session, which startup is timed from instead of launch
+    pub started: Instant,
+}
+
+/// Run a capture session until stopped
+pub fn run(args: Args) -> Result<()> {
+    run_hosted(args, None)
+}
+
+/// Run a capture session until stopped, taking commands from and handing frames to `host`
+/// when it runs in-process. A hosted session leaves Ctrl+C to the host.
+pub fn run_hosted(args: Args, host: Option<Host>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")?;
+
+    let _etw = etw::register();
+    #[cfg(feature = "self-update")]
+    self_update::apply_pending_at_start();
+    #[cfg(feature = "otlp")]
+    let exporter = match args.otlp_endpoint.clone() {
+        Some(endpoint) => {
+            let proxy = http::ProxyConfig::new(args.proxy.clone(), args.proxy_user.clone())?;
+            Some(otlp::Exporter::start(
+                endpoint,
+                args.otlp_headers.clone(),
+                proxy,
+                &args.session,
+            ))
+        }
+        None => None,
+    };
+    let result = runtime.block_on(run_session(args, host));
+    #[cfg(feature = "otlp")]
+    if let Some(exporter) = exporter {
+        exporter.finish(result.as_ref().err());
+    }
+
+    // The stdin reader sits in a blocking read that can't be cancelled, don't wait for it
+    runtime.shutdown_background();
+    result
+}
+
+async fn run_session(args: Args, host: Option<Host>) -> Result<()> {
+    let trailing_window = Duration::from_secs_f64(args.trailing_secs);
+    let pipeline_tag = args.pipeline_tag.map(PipelineTag::as_str);
+    if args.trace_out.is_some() {
+        trace::start();
+    }
+    if args.rt_checks {
+        rt::enable_checks();
+    }
+    let mut footprint_tracker = footprint::usage().map(FootprintTracker::new);
+
+    if args.stdout_format == StdoutFormat::Wav && host.is_some() {
+        return Err(fail!(
+            Config,
+            "--stdout-format wav needs the CLI's stdout; in-process hosts get frames"
+        ));
+    }
+    // Before anything is printed, so the status lines go to stderr and not into the audio
+    let wav_stdout = match args.stdout_format {
+        StdoutFormat::Wav => {
+            Some(stream::wav_stdout().context("Failed to open stdout for the WAV stream")?)
+        }
+        StdoutFormat::Frames => None,
+    };
+
+    println!(
+        "[win-audio-capture] Starting capture for session: {}",
+        args.session
+    );
+    println!("[win-audio-capture] Output: {:?}", args.out);
+    println!("[win-audio-capture] Sample rate: {} Hz", args.sample_rate);
+
+    // One instance per session and per output file; held until capture returns
+    let _session_lock = instance_lock::acquire(&args.session, Scope::Session, &args.session)?;
+    let _output_lock = instance_lock::acquire(
+        &args.session,
+        Scope::Output,
+        &instance_lock::output_key(&args.out),
+    )?;
+
+    // Under the output lock, so no other capture appends to the same journal
+    match Journal::open(&args.out) {
+        Ok(journal) => events::set_journal(Some(journal)),
+        Err(e) => eprintln!("[win-audio-capture] Warning: {:#}", e),
+    }
+    let tui = if args.tui { Some(tui::start()?) } else { None };
+
+    // Wrapped up front so a bad tenant key fails before anything is recorded
+    let envelope = match &args.wrap_key {
+        Some(path) => {
+            let envelope = Envelope::new(&args.session, &envelope::read_public_key(path)?)?;
+            println!(
+                "[win-audio-capture] Recording will be sealed for key {} ({:?})",
+                envelope.key_id(),
+                envelope.wrap()
+            );
+            Some(envelope)
+        }
+        None => None,
+    };
+    let compare_key = match &args.dsp_compare_wrap_key {
+        Some(path) => {
+            let key = envelope::read_public_key(path)?;
+            let envelope = Envelope::new(&args.session, &key)?;
+            println!(
+                "[win-audio-capture] DSP comparison file will be sealed for key {}",
+                envelope.key_id()
+            );
+            Some(key)
+        }
+        None => None,
+    };
+
+    // Repair what crashed sessions left behind while this one records
+    let wrap_key = args
+        .wrap_key
+        .as_deref()
+        .and_then(|path| envelope::read_public_key(path).ok());
+    let current = args.session.clone();
+    let recovery = std::thread::spawn(move || {
+        recovery::scan(
+            &upload_manifest::manifest_dir(),
+            Some(&current),
+            wrap_key.as_deref(),
+        )
+        .map_err(|e| {
+            eprintln!(
+                "[win-audio-capture] Warning: Crash recovery failed: {:#}",
+                e
+            )
+        })
+    });
+
+    // Discoverable by `list-sessions`/`attach` while it runs
+    let instance = InstanceEntry::for_session(&args.session, &args.out, events::unix_millis());
+    let _registration = registry::register(&registry::registry_dir(), &instance)
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok();
+
+    // Check the output location before any device is opened so --require-local fails fast
+    let location = output_location::check(&args.out, args.require_local)?;
+    // Kept with what is in it if the session fails
+    let workspace = Workspace::create(&args.session)?;
+    let staging = location
+        .staged
+        .then(|| output_location::staging_path(&args.out, &args.session));
+    if location.location.is_network() || location.staged {
+        events::emit(
+            "output_location",
+            json!({
+                "session": args.session,
+                "location": location.location,
+                "probe_ms": location.probe_ms,
+                "reachable": location.reachable,
+                "staged": staging,
+            }),
+        );
+    }
+
+    if !location.reachable {
+        eprintln!(
+            "[win-audio-capture] Output location is unreachable, the recording will be spooled until it is back"
+        );
+    }
+    // Earlier recordings still waiting for their share are delivered in the background
+    spawn_spool_drainer(args.spool_drain_rate);
+
+    let power_status = power::power_status();
+    let profile = PowerProfile::resolve(args.battery_mode, power_status);
+    events::emit(
+        "power_mode",
+        json!({
+            "session": args.session,
+            "requested": args.battery_mode,
+            "status": power_status,
+            "profile": profile,
+        }),
+    );
+
+    // Cleared to stop the mixer engine and the loopback thread
+    let running = Arc::new(AtomicBool::new(true));
+
+    // Set up graceful shutdown. Signals are only counted here; the session loop decides
+    // when capture actually ends so the trailing window can keep recording. Each request
+    // carries its actor for the audit log.
+    let (stop_tx, mut stop_rx) = mpsc::unbounded_channel::<&'static str>();
+    let hang_up_tx = stop_tx.clone();
+    if host.is_none() {
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if stop_tx.send("signal").is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Wait-free rings for audio samples from the device callbacks
+    let (loopback_tx, loopback_rx) = engine::source_queue();
+
+    // Control commands from the parent process
+    let (control_tx, mut control_rx) = mpsc::channel::<Request>(64);
+    let launched = host
+        .as_ref()
+        .map(|host| host.started)
+        .or_else(|| crate::LAUNCHED.get().copied());
+    let host_frames = match host {
+        Some(Host {
+            mut commands,
+            frames,
+            started: _,
+        }) => {
+            let control_tx = control_tx.clone();
+            tokio::spawn(async move {
+                while let Some(request) = commands.recv().await {
+                    if control_tx.send(request).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Some(frames)
+        }
+        None => {
+            control::spawn_stdin_reader(control_tx.clone());
+            None
+        }
+    };
+    warnings::spawn_flusher();
+
+    // An attaching agent takes over the frame stream and sends commands over the pipe
+    let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+    // Faults a `--soak` run injects; the frames go through a pipe that can be made to block
+    let soak_faults = args.soak.map(|_| Arc::new(Faults::new()));
+    let first_output: Option<FrameOutput> = match (&soak_faults, host_frames) {
+        (Some(faults), frames) => {
+            let frames = frames.unwrap_or_else(|| Box::new(tokio::io::stdout()));
+            Some(Box::new(BlockablePipe::new(frames, faults.clone())))
+        }
+        (None, frames) => frames,
+    };
+    if let Some(output) = first_output {
+        // Taken before the first block, so nothing goes to stdout
+        let _ = output_tx.try_send(output);
+    }
+    // Observers get their own copy of the frames, but never the control channel
+    let (observer_tx, observer_rx) = mpsc::unbounded_channel();
+    let tokens = Tokens {
+        admin: args.admin_token.clone(),
+        observer: args.observer_token.clone(),
+    };
+    if let Err(e) = attach::spawn_server(&instance, tokens, control_tx, output_tx, observer_tx) {
+        eprintln!("[win-audio-capture] Warning: Attach unavailable: {:#}", e);
+    }
+
+    // Loaded before any device is opened so a broken config fails fast
+    let capture_config = match &args.config {
+        Some(path) => CaptureConfig::load(path, true)?,
+        None => CaptureConfig::load(&CaptureConfig::default_path(), false)?,
+    };
+    // What `setup` settled on, unless the command line says otherwise
+    let mut args = args;
+    if args.mic_device.is_none() {
+        args.mic_device = capture_config.mic_device.clone();
+    }
+    if args.loopback_device.is_none() {
+        args.loopback_device = capture_config.loopback_device.clone();
+    }
+    if args.latency_offset_ms.is_none() {
+        args.latency_offset_ms = capture_config.latency_offset_ms;
+    }
+
+    // Get audio host
+    let host = cpal::default_host();
+
+    let device_cache_path = DeviceCache::default_path();
+    let mut device_cache = DeviceCache::load(&device_cache_path);
+
+    // Per-source delivery counters for drift estimates
+    let mic_counter = Arc::new(SourceCounter::default());
+    let loopback_counter = Arc::new(SourceCounter::default());
+
+    // Inverted MIC channels found by the input callback, for the session to log
+    let (polarity_tx, mut polarity_rx) = mpsc::unbounded_channel::<PolarityFlip>();
+    let (duck_tx, mut duck_rx) = mpsc::unbounded_channel::<DuckEvent>();
+
+    // `--source` generators stand in for the devices they replace
+    let mut synthetic_handles = Vec::new();
+    let opened_mic = match synth::signal_for(&args.source, synth::Target::Mic) {
+        Some(signal) => {
+            println!("[win-audio-capture] MIC: synthetic {:?}", signal);
+            let (ring_tx, ring) = engine::source_queue();
+            synthetic_handles.push(synth::spawn(
+                signal,
+                args.sample_rate,
+                ring_tx,
+                mic_counter.clone(),
+                running.clone(),
+                soak_faults.clone(),
+            )?);
+            OpenedMic::synthetic(ring)
+        }
+        None => open_mic(
+            &args,
+            &host,
+            &capture_config,
+            &mut device_cache,
+            MicInputSettings {
+                counter: mic_counter.clone(),
+                polarity_tx: polarity_tx.clone(),
+            },
+            &duck_tx,
+        )?,
+    };
+    let OpenedMic {
+        name: mut input_device_name,
+        endpoint_id: mut input_endpoint_id,
+        _lock: mut _device_lock,
+        _ducking: mut _duck_watcher,
+        input: mut mic,
+        stream: input_stream,
+        ring: mic_rx,
+    } = opened_mic;
+    let overrides = mic
+        .as_ref()
+        .map_or_else(DeviceOverride::default, |mic| mic.overrides.clone());
+
+    let dsp_params = DspParams::default()
+        .apply(DspUpdate {
+            hpf_hz: Some(args.hpf_hz),
+            agc: Some(overrides.agc.unwrap_or(args.agc)),
+            agc_target_db: Some(args.agc_target_db),
+            denoise: Some(match overrides.denoise {
+                Some(false) => 0.0,
+                Some(true) if args.denoise == 0.0 => dsp::DEFAULT_DENOISE,
+                _ => args.denoise,
+            }),
+            aec: Some(args.aec),
+        })
+        .context("Invalid MIC processing options")?;
+
+    let mic_locked_at_start = mic.is_some() && input_stream.is_none();
+
+    // Both sources are brought to the negotiated rate before they are mixed
+    let loopback_signal = synth::signal_for(&args.source, synth::Target::Loopback);
+    let negotiated = negotiate_rate(&args, mic.as_ref())?;
+    let actual_sample_rate = negotiated.rate;
+    // The MIC was opened for --sample-rate; it isn't playing yet, so reopening is cheap
+    let (input_stream, mic_rx) = match (&mut mic, input_stream) {
+        (Some(mic), Some(stream)) if actual_sample_rate != mic.target_rate => {
+            mic.target_rate = actual_sample_rate;
+            drop(stream);
+            let (stream, ring) = mic.open()?;
+            (Some(stream), ring)
+        }
+        (Some(mic), stream) => {
+            mic.target_rate = actual_sample_rate;
+            (stream, mic_rx)
+        }
+        (None, stream) => (stream, mic_rx),
+    };
+
+    // Set when a default render endpoint moves, for the loopbacks following one to reopen
+    let loopback_moved = Arc::new(AtomicBool::new(false));
+    let extra_loopback_moved = Arc::new(AtomicBool::new(false));
+
+    // Start WASAPI loopback capture in background thread
+    let loopback_handle = if let Some(signal) = loopback_signal {
+        println!("[win-audio-capture] Loopback: synthetic {:?}", signal);
+        synthetic_handles.push(synth::spawn(
+            signal,
+            actual_sample_rate,
+            loopback_tx,
+            loopback_counter.clone(),
+            running.clone(),
+            soak_faults.clone(),
+        )?);
+        None
+    } else {
+        // Resolved up front so a pinned endpoint that isn't there fails the session at once
+        let loopback_device = match &args.loopback_device {
+            Some(id) => {
+                let endpoints = devices::list()?;
+                let endpoint = devices::find(&endpoints, Flow::Render, id)?;
+                println!("[win-audio-capture] Pinned loopback \"{}\"", endpoint.name);
+                Some(endpoint.id.clone())
+            }
+            None => None,
+        };
+        let loopback_capture = WasapiLoopbackCapture::new(
+            loopback_tx,
+            running.clone(),
+            loopback_counter.clone(),
+            profile,
+            args.loopback_role,
+            actual_sample_rate,
+            args.resample_quality,
+        )
+        .device(loopback_device)
+        .process(loopback_process(&args))
+        .follow_default(loopback_moved.clone());
+        match loopback_capture.start() {
+            Ok(handle) => {
+                println!("[win-audio-capture] WASAPI loopback capture started");
+                Some(handle)
+            }
+            Err(e) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: Could not start WASAPI loopback: {}",
+                    e
+                );
+                eprintln!(
+                    "[win-audio-capture] Recording MIC only, loopback channel will be silent"
+                );
+                None
+            }
+        }
+    };
+
+    // Optional second endpoint, e.g. speakers next to a headset carrying the call
+    let (extra_loopback_handle, extra_loopback_rx) = match args.loopback_extra_role {
+        Some(role) => {
+            let (extra_tx, extra_rx) = engine::source_queue();
+            let extra_capture = WasapiLoopbackCapture::new(
+                extra_tx,
+                running.clone(),
+                Arc::new(SourceCounter::default()),
+                profile,
+                role,
+                actual_sample_rate,
+                args.resample_quality,
+            )
+            .alongside(args.loopback_role)
+            .follow_default(extra_loopback_moved.clone());
+            match extra_capture.start() {
+                Ok(handle) => {
+                    println!(
+                        "[win-audio-capture] Extra loopback ({:?}, {:?}) started",
+                        role, args.loopback_layout
+                    );
+                    (Some(handle), Some(extra_rx))
+                }
+                Err(e) => {
+                    eprintln!(
+                        "[win-audio-capture] Warning: Could not start extra loopback: {}",
+                        e
+                    );
+                    (None, None)
+                }
+            }
+        }
+        None => (None, None),
+    };
+
+    if !profile.optional_dsp && dsp_params != DspParams::default() {
+        println!("[win-audio-capture] Low power profile, MIC processing is off");
+    }
+
+    let engine_config = EngineConfig {
+        sample_rate: actual_sample_rate,
+        profile,
+        limiter: args.limiter,
+        dither: args.dither,
+        extra_layout: args.loopback_layout,
+        anonymize: args.anonymize.then_some(args.anonymize_semitones),
+        dsp: dsp_params,
+        notes_channel: args.notes == NotesMode::Channel,
+    };
+    let channels = engine_config.channels(extra_loopback_rx.is_some());
+
+    // `--max-buffer-mb` split over the buffers this session has
+    let pools: Vec<Pool> = [
+        (true, Pool::Mixer),
+        (true, Pool::Sink),
+        (true, Pool::Stream),
+        (args.dsp_compare.is_some(), Pool::Compare),
+        (args.rolling_minutes.is_some(), Pool::Rolling),
+    ]
+    .into_iter()
+    .filter_map(|(used, pool)| used.then_some(pool))
+    .collect();
+    let budget = args.max_buffer_mb.map(|mb| Budget::new(mb, &pools));
+    let pressure = Arc::new(Pressure::default());
+    let block_bytes =
+        |channels| budget::block_bytes(actual_sample_rate, channels, profile.mixer_tick);
+    let depth = |pool, channels, default| match &budget {
+        Some(budget) => budget.depth(pool, block_bytes(channels), default),
+        None => Ok(default),
+    };
+    let mixer_queue_depth = depth(Pool::Mixer, channels, BLOCK_QUEUE_DEPTH)?;
+    let stream_queue_depth = depth(Pool::Stream, 2, BLOCK_QUEUE_DEPTH)?;
+    let compare_queue_depth = depth(Pool::Compare, channels + 1, BLOCK_QUEUE_DEPTH)?;
+
+    // Set up the recording sink using device's actual sample rate
+    let spec = SinkSpec {
+        channels: channels as u16,
+        sample_rate: actual_sample_rate,
+    };
+
+    // Slow or network storage gets a local staging file and a deeper queue in front of it
+    let recording_path = match &staging {
+        Some(path) => {
+            eprintln!(
+                "[win-audio-capture] Output is on slow or network storage, staging at {:?}",
+                path
+            );
+            path.clone()
+        }
+        None => args.out.clone(),
+    };
+    let sink_queue_depth = depth(
+        Pool::Sink,
+        channels,
+        if staging.is_some() {
+            STAGED_SINK_QUEUE_DEPTH
+        } else {
+            BLOCK_QUEUE_DEPTH
+        },
+    )?;
+    if let Some(budget) = &budget {
+        println!(
+            "[win-audio-capture] Memory budget {} MB: mixer {}, sink {}, stream {}{} blocks",
+            args.max_buffer_mb.unwrap_or_default(),
+            mixer_queue_depth,
+            sink_queue_depth,
+            stream_queue_depth,
+            if budget.bytes(Pool::Compare).is_some() {
+                format!(", compare {}", compare_queue_depth)
+            } else {
+                String::new()
+            }
+        );
+    }
+
+    let encoding = sink::Encoding {
+        opus_bitrate: args.opus_bitrate,
+    };
+    let recording = sink::create_with(args.format, &recording_path, spec, encoding)?;
+    let recording = match &soak_faults {
+        Some(faults) => Box::new(StallingSink::new(recording, faults.clone())) as _,
+        None => recording,
+    };
+    update_upload_manifest(&args.session, |manifest| {
+        manifest.pipeline_tag = pipeline_tag.map(str::to_string);
+        manifest.sealed = args.wrap_key.is_some();
+        manifest.add_segment(&args.out);
+        Ok(())
+    });
+    let (sink_tx, sink_rx) = mpsc::channel::<AudioBlock>(sink_queue_depth);
+    let discarded = Arc::new(AtomicBool::new(false));
+    let finalize = Finalize::new(
+        &args.session,
+        args.finalize_timeout.map(Duration::from_secs),
+    );
+    let trim_job = args.trim_silence.then(|| TrimJob {
+        format: args.format,
+        path: recording_path.clone(),
+        spec,
+        tracker: SilenceTracker::new(channels, args.trim_threshold_db),
+        discarded: discarded.clone(),
+        finalize: finalize.clone(),
+    });
+    let compactor = args.compact_silence.map(|secs| {
+        SilenceCompactor::new(channels, actual_sample_rate, secs, args.trim_threshold_db)
+    });
+    let (rotation_tx, rotation_rx) = std::sync::mpsc::channel();
+    let (rotated_tx, mut rotated_rx) = mpsc::unbounded_channel();
+    let rotation_blocker = rotation_blocker(&args, staging.is_some());
+    if let (Some(_), Some(option)) = (args.segment_seconds, rotation_blocker) {
+        return Err(fail!(
+            Config,
+            "--segment-seconds can't be combined with {}",
+            option
+        ));
+    }
+    let rotations = rotation_blocker.is_none().then(|| Rotations {
+        format: args.format,
+        encoding,
+        spec,
+        requests: rotation_rx,
+        done: rotated_tx,
+    });
+    let (commit_tx, commit_rx) = std::sync::mpsc::channel();
+    let (committed_tx, mut committed_rx) = mpsc::unbounded_channel();
+    let rolling = args.rolling_minutes.map(|minutes| {
+        let max_bytes = budget.as_ref().and_then(|budget| budget.bytes(Pool::Rolling));
+        let buffer = RollingBuffer::new(channels, actual_sample_rate, minutes, max_bytes);
+        if buffer.capped() {
+            eprintln!(
+                "[win-audio-capture] Warning: --max-buffer-mb leaves the rolling buffer {}s instead of {} minutes",
+                buffer.held_secs(actual_sample_rate),
+                minutes
+            );
+        }
+        Rolling {
+            buffer,
+            requests: commit_rx,
+            done: committed_tx,
+            pressure: pressure.clone(),
+        }
+    });
+    let sink_worker =
+        spawn_sink_writer(recording, sink_rx, compactor, trim_job, rotations, rolling);
+
+    // Raw/processed MIC comparison file; the recording goes ahead without it
+    let (compare_tx, compare_worker) = match &args.dsp_compare {
+        Some(path) => {
+            if !profile.optional_dsp {
+                eprintln!(
+                    "[win-audio-capture] Warning: Low power profile, the DSP comparison file will show no processing"
+                );
+            }
+            let compare_spec = SinkSpec {
+                channels: spec.channels + 1,
+                ..spec
+            };
+            let format = args.dsp_compare_format.unwrap_or(args.format);
+            let compare = sink::create_with(format, path, compare_spec, encoding).map(|compare| {
+                match compare_key {
+                    Some(key) => Box::new(SealedSink::new(compare, &args.session, key)) as _,
+                    None => compare,
+                }
+            });
+            match compare {
+                Ok(compare) => {
+                    let (tx, rx) = mpsc::channel::<AudioBlock>(compare_queue_depth);
+                    (
+                        Some(tx),
+                        Some(spawn_sink_writer(compare, rx, None, None, None, None)),
+                    )
+                }
+                Err(e) => {
+                    eprintln!(
+                        "[win-audio-capture] Warning: DSP comparison file disabled: {:#}",
+                        e
+                    );
+                    (None, None)
+                }
+            }
+        }
+        None => (None, None),
+    };
+
+    // stdout PCM frame stream
+    let (stream_tx, stream_rx) = mpsc::channel::<AudioBlock>(stream_queue_depth);
+    let handshake = stream::handshake(
+        actual_sample_rate,
+        session_features(&args),
+        pipeline_tag,
+        Some(&args.session),
+        args.handshake_every,
+    );
+    let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+    // Zero-copy delivery for consumers that negotiate it; frames stay inline without it
+    let layout = shm::Layout::DEFAULT;
+    let shared_ring = shared_pcm::create(&instance.pcm_mapping, layout)
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok();
+    let shared_mapping = shared_ring.is_some().then(|| {
+        json!({
+            "mapping": instance.pcm_mapping,
+            "slots": layout.slots,
+            "slot_bytes": layout.slot_bytes,
+            "slot_stride": layout.slot_stride(),
+        })
+    });
+    let streamer = match wav_stdout {
+        None => {
+            eprintln!("[win-audio-capture] Dual-mode output enabled: WAV file + stdout PCM frames");
+            tokio::spawn(stream::stream_frames(
+                stream_rx,
+                output_rx,
+                notice_rx,
+                args.protocol,
+                handshake,
+                shared_ring,
+                observer_rx,
+            ))
+        }
+        Some(stdout) => {
+            eprintln!(
+                "[win-audio-capture] Dual-mode output enabled: recording + stdout WAV stream"
+            );
+            tokio::spawn(stream::stream_wav(stream_rx, stdout, actual_sample_rate))
+        }
+    };
+
+    // Start MIC stream (loopback is already running in background thread)
+    let mut input_stream = input_stream;
+    if let (Some(stream), Some(mic)) = (&input_stream, &mic) {
+        stream.play().context("Failed to start MIC stream")?;
+        device_cache.record_input(
+            &input_device_name,
+            CachedConfig {
+                sample_rate: actual_sample_rate,
+                channels: mic.config.channels,
+            },
+            events::unix_millis(),
+        );
+        if let Err(e) = device_cache.save(&device_cache_path) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    let (block_tx, mut block_rx) = mpsc::channel::<AudioBlock>(mixer_queue_depth);
+    let (gap_tx, mut gap_rx) = mpsc::unbounded_channel::<Gap>();
+    let (mic_replacement_tx, mic_replacements) = std::sync::mpsc::channel();
+    let (dsp_tx, dsp_updates) = std::sync::mpsc::channel();
+    let (delay_tx, delays) = std::sync::mpsc::channel();
+    let mic_muted = Arc::new(AtomicBool::new(false));
+    let (note_tx, note_rx) = std::sync::mpsc::channel::<NoteSamples>();
+    let mixer_counters = Arc::new(LiveCounters::default());
+    let clock_sync = (args.clock == ClockSource::External).then(|| Arc::new(ClockSync::new()));
+    let clock: Box<dyn mixer::Clock + Send> = match &clock_sync {
+        Some(sync) => Box::new(ExternalClock(sync.clone())),
+        None => Box::new(SystemClock::new()),
+    };
+    let engine = engine::spawn(
+        clock,
+        engine_config,
+        EngineSources {
+            mic: mic_rx,
+            loopback: loopback_rx,
+            extra_loopback: extra_loopback_rx,
+            mic_replacements,
+            mic_muted: mic_muted.clone(),
+            dsp_updates,
+            delays,
+            notes: engine_config
+                .notes_channel
+                .then(|| NotePlayer::new(note_rx)),
+            counters: mixer_counters.clone(),
+            pressure: pressure.clone(),
+        },
+        running.clone(),
+        block_tx,
+        gap_tx,
+        compare_tx,
+    )
+    .context("Failed to start mixer thread")?;
+
+    // Suspend/resume from the OS; capture carries on without them if registration fails
+    let (power_tx, mut power_rx) = mpsc::unbounded_channel::<PowerEvent>();
+    let _suspend_watcher = suspend::watch(power_tx)
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok();
+
+    // Default-device changes and hotplug; without them devices only reopen after errors
+    let (device_tx, mut device_rx) = mpsc::unbounded_channel::<DeviceChange>();
+    let _device_watcher = device_watch::watch(device_tx)
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok();
+    let mut device_reaction = Reaction::default();
+    let mut device_settle = None;
+
+    // Headset call buttons, listened for from the first block on; capture carries on without
+    // them if the listener can't start
+    let (headset_tx, mut headset_rx) = mpsc::unbounded_channel::<HeadsetButton>();
+    let mut headset_tx = (args.headset_controls != HeadsetControls::Off).then_some(headset_tx);
+    let mut _headset_watcher = None;
+
+    // OS-level MIC mute; like the headset, optional if the endpoint can't be watched
+    let (mute_tx, mut mute_rx) = mpsc::unbounded_channel::<bool>();
+    let mute_sync = if mic.is_some() {
+        args.mute_sync
+    } else {
+        MuteSync::Off
+    };
+    let mut mute_watcher = watch_endpoint_mute(mute_sync, &mute_tx, &input_endpoint_id);
+
+    println!("[win-audio-capture] Recording started...");
+
+    let mut session = Session {
+        id: args.session.clone(),
+        sample_rate: actual_sample_rate,
+        sample_position: 0,
+        capture_started: Instant::now(),
+        timeline: Timeline::new(&args.out, &args.session, actual_sample_rate, pipeline_tag)
+            .with_start(events::unix_millis()),
+        mic_counter,
+        loopback_counter,
+        power: profile,
+        suspended_at_ms: None,
+        gain_staging: GainStaging::new(actual_sample_rate),
+        headset_controls: args.headset_controls,
+        mute: MuteState::new(args.mute_sync),
+        mic_muted,
+        chapters: args
+            .chapters
+            .then(|| ChapterDetector::new(actual_sample_rate)),
+        sync: args
+            .sync_pulse_secs
+            .map(|secs| SyncPulse::new(secs, actual_sample_rate)),
+        auto_redact: args.auto_redact.then(|| {
+            AutoRedactor::new(
+                args.auto_redact_confidence,
+                args.auto_redact_pre_ms,
+                args.auto_redact_post_ms,
+                actual_sample_rate,
+            )
+        }),
+        protocol: args.protocol,
+        stream_notices: notice_tx,
+        shared_mapping,
+        audit: AuditLog::open(&args.out)?,
+        dsp: dsp_params,
+        dsp_updates: dsp_tx,
+        mic_endpoint: input_endpoint_id.clone(),
+        mic_locked_at: None,
+        mic_locked_ms: 0,
+        alignment: None,
+        delays: delay_tx,
+        ducking: DuckTracker::default(),
+        panic_delete: None,
+        outcomes: Outcomes::default(),
+        out: args.out.clone(),
+        note_channel: engine_config.notes_channel.then(|| channels - 1),
+        note_queue: note_tx,
+        note_files: Vec::new(),
+        paused_at: None,
+        paused_frames: 0,
+        stop_requested: false,
+        rotations: match rotation_blocker {
+            Some(option) => Err(option),
+            None => Ok(rotation_tx),
+        },
+        rotating: None,
+        parts: Vec::new(),
+        part_start: 0,
+        segment_frames: args
+            .segment_seconds
+            .map(|secs| secs.saturating_mul(actual_sample_rate as u64)),
+        pipeline_tag,
+        commits: args.rolling_minutes.map(|_| commit_tx),
+        clock: clock_sync.map(ClockTracker::new),
+        commit_requested: false,
+        meetings: args.meeting_bridge.then(MeetingTracker::default),
+        added_sinks: Vec::new(),
+        sink_setup: SinkSetup {
+            format: args.format,
+            spec,
+            encoding,
+            queue_depth: sink_queue_depth,
+        },
+        add_sink_blocker: add_sink_blocker(&args),
+        framed_stream: args.stdout_format == StdoutFormat::Frames,
+    };
+    // Written right away so even a part without any events can be stitched
+    if let Err(e) = session.timeline.save() {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+    }
+    session.audit(
+        "start",
+        "cli",
+        json!({
+            "session": args.session,
+            "user": audit::local_user(),
+            "mic": input_device_name,
+            "mic_backend": mic.as_ref().map(|mic| mic.backend),
+            "path": args.out,
+            "sample_rate": actual_sample_rate,
+            "pipeline_tag": pipeline_tag,
+            "synthetic": args.source,
+        }),
+    );
+    events::emit(
+        "started",
+        json!({
+            "session": args.session,
+            "path": args.out,
+            "format": args.format,
+            "sample_rate": actual_sample_rate,
+            "channels": channels,
+            "mic": {
+                "name": input_device_name,
+                "endpoint_id": input_endpoint_id,
+                "sample_rate": mic.as_ref().map(|mic| mic.config.sample_rate.0),
+                "channels": mic.as_ref().map(|mic| mic.config.channels),
+                "backend": mic.as_ref().map(|mic| mic.backend),
+            },
+            "loopback": {
+                "role": args.loopback_role,
+                "extra_role": args.loopback_extra_role,
+                "running": loopback_handle.is_some() || loopback_signal.is_some(),
+            },
+            "synthetic": args.source,
+            "pipeline_tag": pipeline_tag,
+            "license": args.license,
+            "rate_negotiation": negotiated.is_limited().then_some(&negotiated),
+        }),
+    );
+
+    // The start hook runs alongside the capture; its outcome is audited when it ends
+    let (hook_tx, mut hook_rx) = mpsc::unbounded_channel();
+    if let Some(command) = args.on_start_cmd.clone() {
+        let env = hook_env(&args, "start", &args.out, actual_sample_rate, channels);
+        let timeout = Duration::from_secs(args.hook_timeout_secs);
+        tokio::task::spawn_blocking(move || {
+            let _ = hook_tx.send(hooks::run("on_start", &command, &env, timeout));
+        });
+    }
+
+    // Session loop: fan mixed blocks out to the consumers and react to commands/signals
+    let mut stop_requests = 0;
+    let mut stop_deadline: Option<tokio::time::Instant> = None;
+    let mut stream_drops: u64 = 0;
+    let mut mic_watchdog = (args.mic_stall_ms > 0 && mic.is_some())
+        .then(|| StallWatchdog::new(Duration::from_millis(args.mic_stall_ms), Instant::now()));
+    let mut watchdog_tick = tokio::time::interval(watchdog::CHECK_INTERVAL);
+    watchdog_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut lock_retry = tokio::time::interval(mic_lock::RETRY_INTERVAL);
+    lock_retry.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    if mic_locked_at_start {
+        session.mic_locked();
+    }
+    // A given offset is applied right away, a measured one once both paths have reported
+    let mut latency_pending = false;
+    match args.latency_offset_ms {
+        Some(offset_ms) => session.align(Alignment::fixed(offset_ms, actual_sample_rate), "cli"),
+        None => latency_pending = args.latency_compensation == LatencyCompensation::Auto,
+    }
+    let mut latency_tick = tokio::time::interval(Duration::from_secs(1));
+    latency_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut first_block = true;
+    let mut heartbeat = (args.json_logs || args.tui).then(|| Heartbeat::new(actual_sample_rate));
+    let mut stats_tick = tokio::time::interval(Duration::from_millis(args.stats_interval_ms));
+    stats_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut soak_run = args.soak.zip(soak_faults.clone()).map(|(spec, faults)| {
+        println!(
+            "[win-audio-capture] Soak test for {}s, injecting {:?}",
+            spec.duration.as_secs(),
+            args.inject
+        );
+        (
+            spec,
+            Scheduler::new(faults, &args.inject),
+            Invariants::new(spec),
+        )
+    });
+    let mut fault_tick = tokio::time::interval(Duration::from_secs(1));
+    fault_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut soak_check = tokio::time::interval(soak::CHECK_INTERVAL);
+    soak_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut soak_ended = false;
+    let mut budget_tick = tokio::time::interval(Duration::from_secs(1));
+    budget_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut meeting_tick = tokio::time::interval(conferencing::POLL_INTERVAL);
+    meeting_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let deadline = stop_deadline.unwrap_or_else(tokio::time::Instant::now);
+        let device_deadline = device_settle.unwrap_or_else(tokio::time::Instant::now);
+        tokio::select! {
+            block = block_rx.recv() => {
+                // The engine closes the channel once it has mixed its final block
+                let Some(block) = block else { break };
+                // Paused stretches are recorded as silence, so positions stay continuous
+                let block: AudioBlock = if session.paused_at.is_some() {
+                    vec![0; block.len()].into()
+                } else {
+                    block
+                };
+                if first_block {
+                    first_block = false;
+                    report_startup(&args.session, launched);
+                    // Started only now, to keep them off the path to the first sample
+                    _headset_watcher = headset_tx.take().and_then(|tx| {
+                        hid_telephony::watch(tx)
+                            .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+                            .ok()
+                    });
+                }
+                // The live stream and the analysis only ever see the stereo mix
+                let stereo = if channels == 2 {
+                    block.clone()
+                } else {
+                    stereo_of(&block, channels)
+                };
+                session.observe(&stereo);
+                if let Some(heartbeat) = &mut heartbeat {
+                    heartbeat.observe(&stereo);
+                }
+
+                // The live stream is best effort, the recording must get every block
+                if stream_tx.try_send(stereo).is_err() {
+                    stream_drops += 1;
+                    pressure.hit(Pool::Stream);
+                    etw::write(
+                        etw::LEVEL_WARNING,
+                        etw::KEYWORD_DROP,
+                        "frame_dropped",
+                        &json!({ "sample_position": session.sample_position, "drops": stream_drops }),
+                    );
+                }
+                for added in &mut session.added_sinks {
+                    added.write(&block).await;
+                }
+                if sink_tx.capacity() == 0 {
+                    pressure.hit(Pool::Sink);
+                }
+                if sink_tx.send(block).await.is_err() {
+                    // Writer failed; its error is reported below
+                    running.store(false, Ordering::SeqCst);
+                    break;
+                }
+            }
+            Some(request) = control_rx.recv() => {
+                session.handle_request(request);
+                if std::mem::take(&mut session.stop_requested) {
+                    let _ = hang_up_tx.send("control");
+                }
+                if session.panic_delete.is_some() {
+                    // No trailing window, nothing of this session is kept
+                    discarded.store(true, Ordering::SeqCst);
+                    stop_deadline = None;
+                    running.store(false, Ordering::SeqCst);
+                }
+            }
+            Some(outcome) = hook_rx.recv() => session.hook_finished(outcome),
+            Some(change) = device_rx.recv() => {
+                device_reaction.add(&change, input_endpoint_id.as_deref());
+                device_settle.get_or_insert_with(|| tokio::time::Instant::now() + device_watch::SETTLE);
+            }
+            _ = tokio::time::sleep_until(device_deadline), if device_settle.is_some() => {
+                device_settle = None;
+                let reaction = std::mem::take(&mut device_reaction);
+                if reaction.is_empty() {
+                    continue;
+                }
+                if reaction.reopen_loopback {
+                    loopback_moved.store(true, Ordering::SeqCst);
+                    extra_loopback_moved.store(true, Ordering::SeqCst);
+                }
+                // A pinned or synthetic MIC stays as it is
+                let follows = args.mic_device.is_none() && mic.is_some();
+                let mut switched_from = None;
+                if reaction.reselect_mic && follows {
+                    let settings = MicInputSettings {
+                        counter: session.mic_counter.clone(),
+                        polarity_tx: polarity_tx.clone(),
+                    };
+                    let current = (!reaction.mic_removed).then_some(input_device_name.as_str());
+                    match reselect_mic(&args, &host, &capture_config, &mut device_cache, current, settings, &duck_tx) {
+                        Ok(Some(opened)) => {
+                            let _ = mic_replacement_tx.send(opened.ring);
+                            drop(input_stream);
+                            drop(mute_watcher);
+                            input_stream = opened.stream;
+                            mic = opened.input;
+                            _device_lock = opened._lock;
+                            _duck_watcher = opened._ducking;
+                            input_endpoint_id = opened.endpoint_id;
+                            mute_watcher = watch_endpoint_mute(mute_sync, &mute_tx, &input_endpoint_id);
+                            session.mic_endpoint = input_endpoint_id.clone();
+                            switched_from = Some(std::mem::replace(&mut input_device_name, opened.name));
+                            if let Some(watchdog) = &mut mic_watchdog {
+                                watchdog.reset(Instant::now());
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("[win-audio-capture] Warning: MIC switch failed: {:#}", e),
+                    }
+                } else if reaction.mic_removed {
+                    eprintln!(
+                        "[win-audio-capture] Warning: MIC \"{}\" was removed",
+                        input_device_name
+                    );
+                }
+                if let Some(from) = &switched_from {
+                    println!(
+                        "[win-audio-capture] MIC switched from \"{}\" to \"{}\"",
+                        from, input_device_name
+                    );
+                    events::emit(
+                        "device_switched",
+                        json!({
+                            "session": args.session,
+                            "source": "mic",
+                            "from": from,
+                            "to": input_device_name,
+                            "sample_position": session.sample_position,
+                        }),
+                    );
+                }
+                session.audit(
+                    "device_change",
+                    "os",
+                    json!({
+                        "reason": "hotplug",
+                        "reaction": reaction,
+                        "mic": input_device_name,
+                        "switched_from": switched_from,
+                    }),
+                );
+            }
+            Some(closed) = rotated_rx.recv() => session.rotated(closed),
+            _ = budget_tick.tick() => {
+                if let (Some(tracker), Some(usage)) = (&mut footprint_tracker, footprint::usage()) {
+                    tracker.observe(usage);
+                }
+                for pool in pressure.newly_hit() {
+                    eprintln!(
+                        "[win-audio-capture] Warning: The {} buffer reached its memory limit ({})",
+                        pool.name(),
+                        pool.policy()
+                    );
+                    events::emit(
+                        "budget_pressure",
+                        json!({
+                            "session": session.id,
+                            "pool": pool,
+                            "policy": pool.policy(),
+                            "sample_position": session.sample_position,
+                        }),
+                    );
+                }
+            }
+            _ = meeting_tick.tick(), if session.meetings.is_some() && !first_block => {
+                session.meeting(conferencing::current());
+            }
+            _ = fault_tick.tick(), if soak_run.is_some() => {
+                let Some((spec, scheduler, _)) = &mut soak_run else { continue };
+                for (fault, length) in scheduler.tick() {
+                    println!(
+                        "[win-audio-capture] Soak: injecting {} for {}ms",
+                        fault.name(),
+                        length.as_millis()
+                    );
+                    events::emit(
+                        "fault_injected",
+                        json!({
+                            "session": session.id,
+                            "fault": fault,
+                            "length_ms": length.as_millis() as u64,
+                        }),
+                    );
+                }
+                if !soak_ended && session.capture_started.elapsed() >= spec.duration {
+                    soak_ended = true;
+                    println!("[win-audio-capture] Soak test finished, stopping");
+                    let _ = hang_up_tx.send("soak");
+                }
+            }
+            _ = soak_check.tick(), if soak_run.is_some() => {
+                let (Some((_, _, invariants)), Some(faults)) = (&mut soak_run, &soak_faults) else {
+                    continue;
+                };
+                let probe = Probe {
+                    elapsed: session.capture_started.elapsed(),
+                    resident_bytes: soak::resident_bytes(),
+                    accounted_frames: session.sample_position + mixer_counters.load().skipped_frames,
+                    sample_rate: actual_sample_rate,
+                };
+                let broken = invariants.check(probe);
+                for violation in &broken {
+                    eprintln!(
+                        "[win-audio-capture] Warning: Soak invariant {} broken at {}s: {}",
+                        violation.invariant, violation.at_secs, violation.detail
+                    );
+                }
+                events::emit(
+                    "soak",
+                    json!({
+                        "session": session.id,
+                        "elapsed_secs": probe.elapsed.as_secs(),
+                        "resident_bytes": probe.resident_bytes,
+                        "accounted_frames": probe.accounted_frames,
+                        "injected": faults.counts(),
+                        "violations": broken,
+                    }),
+                );
+            }
+            Some(committed) = committed_rx.recv() => session.committed(committed),
+            Some(gap) = gap_rx.recv() => session.record_gap(gap),
+            Some(muted) = mute_rx.recv() => session.endpoint_muted(muted),
+            Some(event) = duck_rx.recv() => session.ducking(event),
+            Some(flip) = polarity_rx.recv() => session.polarity_corrected(flip),
+            Some(button) = headset_rx.recv() => {
+                if session.headset_button(button) {
+                    // Same as a stop request, so the trailing window still applies
+                    let _ = hang_up_tx.send("headset");
+                }
+            }
+            Some(event) = power_rx.recv() => match event {
+                PowerEvent::Suspend => session.suspending(),
+                PowerEvent::Resume => {
+                    session.resumed();
+                    // The MIC endpoint usually comes back invalidated; the loopback thread
+                    // reopens its own device
+                    drop(input_stream);
+                    drop(mute_watcher);
+                    mute_watcher =
+                        watch_endpoint_mute(mute_sync, &mute_tx, &input_endpoint_id);
+                    input_stream = mic
+                        .as_ref()
+                        .and_then(|mic| session.reopen_mic(mic, &mic_replacement_tx, "resume"));
+                    if let Some(watchdog) = &mut mic_watchdog {
+                        watchdog.reset(Instant::now());
+                    }
+                    session.audit(
+                        "device_change",
+                        "os",
+                        json!({
+                            "reason": "resume",
+                            "mic": input_device_name,
+                            "reopened": input_stream.is_some(),
+                        }),
+                    );
+                }
+            },
+            _ = watchdog_tick.tick(), if mic_watchdog.is_some() => {
+                let Some(watchdog) = &mut mic_watchdog else { continue };
+                let now = Instant::now();
+                if session.suspended_at_ms.is_some() || session.mic_locked_at.is_some() {
+                    // Nothing is delivered while the system sleeps or another app holds it
+                    watchdog.reset(now);
+                } else if let Some(stalled) = watchdog.check(session.mic_counter.samples(), now) {
+                    drop(input_stream);
+                    input_stream = mic
+                        .as_ref()
+                        .and_then(|mic| session.reopen_mic(mic, &mic_replacement_tx, "stall"));
+                    if input_stream.is_some() {
+                        watchdog.restarted();
+                    }
+                    session.mic_stalled(stalled, input_stream.is_some(), watchdog.stats());
+                    watchdog.reset(Instant::now());
+                }
+            }
+            _ = stats_tick.tick(), if heartbeat.is_some() => {
+                let Some(heartbeat) = &mut heartbeat else { continue };
+                let [mic_level, loopback_level] = heartbeat.levels();
+                let mixer = mixer_counters.load();
+                let stats = json!({
+                        "session": args.session,
+                        "sample_position": session.sample_position,
+                        "levels": { "mic": mic_level, "loopback": loopback_level },
+                        "stream_drops": stream_drops,
+                        "budget_pressure": pressure.counts(),
+                        "xruns": {
+                            "mic_underruns": mixer.mic_underruns,
+                            "loopback_underruns": mixer.loopback_underruns,
+                            "mic_overruns": mixer.mic_overruns,
+                            "loopback_overruns": mixer.loopback_overruns,
+                            "gaps": mixer.gaps,
+                        },
+                        "queues": {
+                            "mixer": mixer_queue_depth - block_rx.capacity(),
+                            "sink": sink_queue_depth - sink_tx.capacity(),
+                            "stream": stream_queue_depth - stream_tx.capacity(),
+                        },
+                        "paused": session.paused_at.is_some(),
+                    });
+                if session.protocol == Protocol::V3 {
+                    let _ = session.stream_notices.send(Notice::Stats(stats.clone()));
+                }
+                events::emit("stats", stats);
+            }
+            _ = latency_tick.tick(), if latency_pending => {
+                latency_pending = !session.measure_latency();
+            }
+            _ = lock_retry.tick(), if session.mic_locked_at.is_some() => {
+                input_stream = mic
+                    .as_ref()
+                    .and_then(|mic| session.reopen_mic(mic, &mic_replacement_tx, "lock"));
+                if let (Some(_), Some(watchdog)) = (&input_stream, &mut mic_watchdog) {
+                    watchdog.reset(Instant::now());
+                }
+            }
+            Some(actor) = stop_rx.recv() => {
+                // Two-phase stop: the first request opens the trailing window, the window
+                // elapsing (or another request) ends capture
+                stop_requests += 1;
+                session.audit(
+                    if stop_requests == 1 { "stop" } else { "stop_now" },
+                    actor,
+                    json!({ "trailing_secs": args.trailing_secs }),
+                );
+                if stop_requests == 1 {
+                    println!("\n[win-audio-capture] Received shutdown signal, stopping...");
+                    events::emit(
+                        "stopping",
+                        json!({ "session": args.session, "trailing_secs": args.trailing_secs }),
+                    );
+                    if !trailing_window.is_zero() {
+                        println!(
+                            "[win-audio-capture] Capturing {:.1}s trailing window before finalizing",
+                            args.trailing_secs
+                        );
+                    }
+                    stop_deadline = Some(tokio::time::Instant::now() + trailing_window);
+                } else {
+                    println!("\n[win-audio-capture] Received second shutdown signal, stopping now");
+                    stop_deadline = None;
+                    running.store(false, Ordering::SeqCst);
+                }
+            }
+            _ = tokio::time::sleep_until(deadline), if stop_deadline.is_some() => {
+                stop_deadline = None;
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Let the consumers drain and finish
+    finalize.begin();
+    let wall = session.capture_started.elapsed();
+    drop(block_rx);
+    drop(sink_tx);
+    drop(stream_tx);
+    let stats = join_thread(engine, "Mixer")?;
+    let Written {
+        mut summary,
+        elisions,
+        trim,
+        committed,
+    } = sink_worker.await.context("Recording writer panicked")??;
+    let dsp_compare = match compare_worker {
+        Some(worker) => match worker.await {
+            Ok(Ok(written)) => {
+                println!(
+                    "[win-audio-capture] DSP comparison file: {:?}",
+                    written.summary.path
+                );
+                Some(json!({
+                    "path": written.summary.path,
+                    "frames": written.summary.frames,
+                    "bytes": written.summary.bytes,
+                }))
+            }
+            Ok(Err(e)) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: DSP comparison file failed: {:#}",
+                    e
+                );
+                None
+            }
+            Err(e) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: DSP comparison writer panicked: {:?}",
+                    e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let mut added_sinks = Vec::new();
+    for added in &mut session.added_sinks {
+        match added.finish().await {
+            Ok(written) => added_sinks.push(written),
+            Err(e) => eprintln!(
+                "[win-audio-capture] Warning: Added sink {:?} failed: {:#}",
+                added.path(),
+                e
+            ),
+        }
+    }
+    if let Some(request) = session.panic_delete.take() {
+        let _ = streamer.await;
+        return wipe_session(&args, &mut session, &recording_path, request);
+    }
+    if !committed {
+        let _ = streamer.await;
+        return discard_uncommitted(&args, &mut session, &recording_path);
+    }
+    let compacted = args.compact_silence.map(|_| {
+        let frames: u64 = elisions.iter().map(|e| e.frames).sum();
+        json!({ "spans": elisions.len(), "frames": frames })
+    });
+    if !elisions.is_empty() {
+        if args.compact_silence.is_some() {
+            println!(
+                "[win-audio-capture] Left {} span(s) of silence out of the recording",
+                elisions.len()
+            );
+        }
+        if let Err(e) = session.timeline.set_silence(elisions.clone()) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+    if let Some(trim) = trim {
+        println!(
+            "[win-audio-capture] Trimmed {} leading and {} trailing frames of silence",
+            trim.leading_frames, trim.trailing_frames
+        );
+        if let Err(e) = session.timeline.set_trim(trim) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+    // Chapters and sync pulses share the recording's one cue list
+    let mut cues = Vec::new();
+    if let Some(detector) = session.chapters.take() {
+        match session.mark_chapters(detector.chapters(), &elisions, trim) {
+            Ok(chapter_cues) => cues.extend(chapter_cues),
+            Err(e) => eprintln!("[win-audio-capture] Warning: Chapter markers: {:#}", e),
+        }
+    }
+    if let Some(sync) = &session.sync {
+        cues.extend(sync.pulses().iter().filter_map(|pulse| {
+            Some((
+                cue_frame(pulse.sample_position, &elisions, trim)?,
+                pulse.label(),
+            ))
+        }));
+    }
+    // Stored before auto-redaction, which brings the redacted duration up to date
+    let paused = session.paused_frames
+        + session
+            .paused_at
+            .map_or(0, |at| session.sample_position - at);
+    let frames = SessionFrames {
+        mixed: stats.frames,
+        paused,
+        skipped: stats.skipped_frames,
+        removed: elisions.iter().map(|e| e.frames).sum::<u64>()
+            + trim.map_or(0, |trim| trim.leading_frames + trim.trailing_frames),
+        redacted: 0,
+        file: summary.frames,
+    };
+    let mut durations = Durations::new(wall, frames, session.sample_rate);
+    if let Err(e) = session.timeline.set_durations(durations) {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+    }
+    // Before anything is sealed or published; a recording that couldn't be redacted isn't
+    if let Some(redactor) = &session.auto_redact {
+        let redacted = apply_auto_redactions(
+            &recording_path,
+            redactor.spans(),
+            &elisions,
+            trim,
+            session.sample_rate,
+        )
+        .context("Auto-redaction failed")?;
+        durations = Durations::new(
+            wall,
+            SessionFrames { redacted, ..frames },
+            session.sample_rate,
+        );
+    }
+    // Matroska takes them as chapters, next to the session's tags
+    let matroska = cfg!(feature = "codecs") && args.format == OutputFormat::Mka;
+    let cue_stage = (!cues.is_empty() || matroska).then(|| finalize.stage(Stage::Cues));
+    if let Some(stage) = cue_stage.flatten() {
+        cues.sort_by_key(|&(frame, _)| frame);
+        let appended = if matroska {
+            mka::append(
+                &recording_path,
+                &cues,
+                &matroska_tags(&session),
+                session.sample_rate,
+            )
+        } else {
+            riff::append_cues(&recording_path, &cues)
+        };
+        match appended {
+            Ok(bytes) => summary.bytes = bytes,
+            Err(e) => eprintln!("[win-audio-capture] Warning: Cue points: {:#}", e),
+        }
+        stage.finish();
+    }
+    // Sealed last, once nothing rewrites the file any more
+    let sealed = match &envelope {
+        Some(envelope) => {
+            let mut stage = finalize.enter(Stage::Seal);
+            let sealed = envelope.seal_file(&recording_path, &mut |done, total| {
+                stage.report(done, total)
+            })?;
+            stage.finish();
+            summary.bytes = sealed.bytes;
+            session.audit("sealed", "cli", json!(sealed));
+            Some(sealed)
+        }
+        None => None,
+    };
+    let mut spooled = false;
+    if let Some(staged) = &staging {
+        let mut stage = finalize.enter(Stage::Publish);
+        match output_location::publish_staged(staged, &args.out, &mut |done, total| {
+            stage.report(done, total)
+        }) {
+            Ok(()) => stage.finish(),
+            Err(e) if location.location.is_network() => {
+                eprintln!(
+                    "[win-audio-capture] Warning: {:#}; spooling for later delivery",
+                    e
+                );
+                let dir = spool::spool_dir();
+                let job = spool::enqueue(&dir, &args.session, staged, &args.out)?;
+                events::emit(
+                    "spooled",
+                    json!({
+                        "session": args.session,
+                        "destination": job.destination,
+                        "bytes": job.bytes,
+                        "queue": spool::depth(&dir)?,
+                    }),
+                );
+                spooled = true;
+            }
+            Err(e) => return Err(e),
+        }
+        summary.path = args.out.clone();
+    }
+    update_upload_manifest(&session.id, |manifest| {
+        manifest.finalize_segment(&summary.path, summary.bytes)
+    });
+
+    // Neither can be cut from a file that isn't there or doesn't read as PCM
+    let unreadable = if spooled {
+        Some("spooled")
+    } else if sealed.is_some() {
+        Some("sealed")
+    } else if args.format.is_compressed() {
+        Some("compressed")
+    } else if !args.format.is_riff() {
+        Some("not WAV")
+    } else {
+        None
+    };
+    let slots = jobs::Slots::new(args.finalize_jobs);
+    let (preview_path, highlights, tracks) = tokio::join!(
+        write_preview(&args, &summary.path, unreadable, &finalize, &slots),
+        write_highlights(&args, &summary.path, unreadable, &finalize, &slots),
+        write_tracks(&args, &summary.path, unreadable, &finalize, &slots),
+    );
+    let (preview_path, highlights, tracks) = (preview_path?, highlights?, tracks?);
+    if !tracks.is_empty() {
+        update_upload_manifest(&session.id, |manifest| {
+            for track in &tracks {
+                manifest.add_segment(&track.path);
+                manifest.finalize_segment(&track.path, track.bytes)?;
+            }
+            Ok(())
+        });
+    }
+    let counters = streamer.await.context("Frame stream task panicked")?;
+
+    // Clean up streams
+    drop(input_stream);
+
+    if let Some(handle) = extra_loopback_handle {
+        match handle.join() {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: Extra loopback failed: {:#}",
+                    e
+                )
+            }
+            Err(e) => eprintln!(
+                "[win-audio-capture] Warning: Extra loopback thread panicked: {:?}",
+                e
+            ),
+        }
+    }
+
+    // Wait for loopback thread to finish
+    for handle in synthetic_handles {
+        let _ = handle.join();
+    }
+    let resample_stats = match loopback_handle.map(|handle| handle.join()) {
+        Some(Ok(Ok(stats))) => stats,
+        Some(Ok(Err(e))) => {
+            eprintln!(
+                "[win-audio-capture] Warning: Loopback capture failed: {:#}",
+                e
+            );
+            None
+        }
+        Some(Err(e)) => {
+            eprintln!(
+                "[win-audio-capture] Warning: Loopback thread panicked: {:?}",
+                e
+            );
+            None
+        }
+        None => None,
+    };
+
+    let trace_summary = args.trace_out.as_deref().and_then(|path| {
+        trace::finish(path)
+            .map_err(|e| eprintln!("[win-audio-capture] Warning: Trace export failed: {:#}", e))
+            .ok()
+    });
+
+    let rt_violations = args.rt_checks.then(rt::violations);
+    if let Some(violations) = rt_violations.filter(|v| v.total() > 0) {
+        eprintln!(
+            "[win-audio-capture] Warning: {} real-time violations on the audio threads: {:?}",
+            violations.total(),
+            violations
+        );
+    }
+
+    let samples_written = summary.frames * channels as u64;
+    // A start hook still running gets its timeout to finish
+    while let Some(outcome) = hook_rx.recv().await {
+        session.hook_finished(outcome);
+    }
+    if let Some(command) = args.on_finalize_cmd.clone() {
+        let mut env = hook_env(
+            &args,
+            "finalize",
+            &summary.path,
+            actual_sample_rate,
+            channels,
+        );
+        env.extend([
+            ("SELLY_SAMPLES", samples_written.to_string()),
+            ("SELLY_BYTES", summary.bytes.to_string()),
+            ("SELLY_SPOOLED", spooled.to_string()),
+            ("SELLY_AUDIT_HEAD", session.audit.head().to_string()),
+        ]);
+        let timeout = Duration::from_secs(args.hook_timeout_secs);
+        let outcome =
+            tokio::task::spawn_blocking(move || hooks::run("on_finalize", &command, &env, timeout))
+                .await
+                .context("Hook runner panicked")
+                .and_then(|outcome| outcome);
+        session.hook_finished(outcome);
+    }
+    let _ = recovery.join();
+    // Split tracks replace the recording, once they are all there and the jobs reading it are done
+    let mut recording_path = Some(summary.path.clone());
+    if args.output_mode == OutputMode::Split && !tracks.is_empty() {
+        match std::fs::remove_file(&summary.path) {
+            Ok(()) => {
+                update_upload_manifest(&session.id, |manifest| {
+                    manifest.remove_segment(&summary.path);
+                    Ok(())
+                });
+                recording_path = None;
+            }
+            Err(e) => eprintln!(
+                "[win-audio-capture] Warning: Failed to remove the recording after splitting it: {}",
+                e
+            ),
+        }
+    }
+    session.audit(
+        "stopped",
+        "cli",
+        json!({
+            "samples": samples_written,
+            "bytes": summary.bytes,
+            "trim": trim,
+            "durations": durations,
+        }),
+    );
+    session.segment_completed(&summary, session.part_start);
+    let footprint = footprint_tracker
+        .zip(footprint::usage())
+        .map(|(tracker, usage)| tracker.finish(usage));
+    println!(
+        "[win-audio-capture] Recording stopped. Samples: {}, Bytes: {}",
+        samples_written, summary.bytes
+    );
+    // In parts: as one `json!` literal this payload is past the macro's recursion limit
+    let recording = json!({
+        "session": session.id,
+        "path": recording_path,
+        "samples": samples_written,
+        "sample_position": counters.sample_position(),
+        "bytes": summary.bytes,
+        "channels": channels,
+        "pipeline_tag": pipeline_tag,
+        "trim": trim,
+        "compacted_silence": compacted,
+        "parts": (!session.parts.is_empty()).then_some(&session.parts),
+        "paused_frames": session.paused_frames,
+        "durations": durations,
+        "dsp_compare": dsp_compare,
+        "added_sinks": (!added_sinks.is_empty()).then_some(&added_sinks),
+        "anonymized": args.anonymize,
+        "spooled": spooled,
+        "sealed": sealed,
+        "preview": preview_path,
+        "highlights": highlights,
+        "tracks": (!tracks.is_empty()).then_some(&tracks),
+    });
+    let pipeline = json!({
+        "finalize": finalize.summary(),
+        "trace": trace_summary,
+        "rt": rt_violations,
+        "footprint": footprint,
+        "stream_drops": stream_drops,
+        "budget": {
+            "max_mb": args.max_buffer_mb,
+            "pressure_events": pressure.total(),
+            "pressure": pressure.counts(),
+        },
+        "watchdog": mic_watchdog.map(|watchdog| json!({ "mic": watchdog.stats() })),
+        "clock": session.clock.as_ref().map(ClockTracker::stats),
+        "meeting": session.meetings.as_ref().and_then(MeetingTracker::last),
+    });
+    let devices = json!({
+        "alignment": session.alignment,
+        "mic_locked_ms": session.mic_locked_ms
+            + session.mic_locked_at.map_or(0, |since| since.elapsed().as_millis() as u64),
+        "ducking": session.ducking.stats(Instant::now()),
+        "audit": { "path": session.audit.path(), "head": session.audit.head() },
+        "power": profile,
+        "resampler": resample_stats,
+        "mixer": {
+            "mic_underruns": stats.mic_underruns,
+            "loopback_underruns": stats.loopback_underruns,
+            "mic_overruns": stats.mic_overruns,
+            "loopback_overruns": stats.loopback_overruns,
+            "loopback_idle_frames": stats.loopback_idle_frames,
+            "mic_correction_ppm": stats.mic_correction_ppm,
+            "loopback_correction_ppm": stats.loopback_correction_ppm,
+        },
+    });
+    events::emit("stopped", merged([recording, pipeline, devices]));
+
+    // Everything written on the way is in place or spooled by now
+    if let Err(e) = workspace.finish() {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+    }
+    if let Some(tui) = tui {
+        tui.finish().await;
+    }
+
+    if let (Some((spec, _, invariants)), Some(faults)) = (soak_run, soak_faults) {
+        events::emit(
+            "soak_report",
+            json!({
+                "session": session.id,
+                "duration_secs": spec.duration.as_secs(),
+                "injected": faults.counts(),
+                "violations": invariants.violations,
+                "peak_resident_bytes": invariants.peak_bytes,
+            }),
+        );
+        if !invariants.violations.is_empty() {
+            return Err(anyhow!(
+                "Soak test failed: {} invariant violations",
+                invariants.violations.len()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// One object with the fields of every object in `parts`
+fn merged(parts: impl IntoIterator<Item = Value>) -> Value {
+    let mut fields = serde_json::Map::new();
+    for part in parts {
+        if let Value::Object(part) = part {
+            fields.extend(part);
+        }
+    }
+    Value::Object(fields)
+}
+
+/// Tags of a Matroska recording
+fn matroska_tags(session: &Session) -> Vec<(&'static str, String)> {
+    let mut tags = vec![
+        ("SESSION", session.id.clone()),
+        (
+            "ENCODER",
+            concat!("win-audio-capture ", env!("CARGO_PKG_VERSION")).to_string(),
+        ),
+    ];
+    if let Some(tag) = session.pipeline_tag {
+        tags.push(("PIPELINE_TAG", tag.to_string()));
+    }
+    if let Some(meeting) = session.meetings.as_ref().and_then(MeetingTracker::last) {
+        tags.push(("MEETING_APP", format!("{:?}", meeting.app).to_lowercase()));
+        if let Some(id) = &meeting.meeting_id {
+            tags.push(("MEETING_ID", id.clone()));
+        }
+    }
+    tags
+}
+
+/// File frame a cue at session position `position` goes on (cue frames are file positions,
+/// so they skip compacted silence and any trimmed lead-in); `None` if it was trimmed off
+fn cue_frame(position: u64, elisions: &[Elision], trim: Option<Trim>) -> Option<u64> {
+    let frame = silence::file_frame(elisions, position);
+    let frame = match trim {
+        Some(trim) => frame.saturating_sub(trim.leading_frames),
+        None => frame,
+    };
+    trim.is_none_or(|trim| frame < trim.kept_frames())
+        .then_some(frame)
+}
+
+/// Silence `spans` (session positions) of the finished recording. Returns the frames
+/// silenced.
+fn apply_auto_redactions(
+    recording_path: &Path,
+    spans: &[auto_redact::Span],
+    elisions: &[Elision],
+    trim: Option<Trim>,
+    sample_rate: u32,
+) -> Result<u64> {
+    let audio = riff::RiffAudio::open(recording_path)?;
+    let total = audio.frames();
+    let file_frame = |position: u64| {
+        let frame = silence::file_frame(elisions, position);
+        let frame = match trim {
+            Some(trim) => frame.saturating_sub(trim.leading_frames),
+            None => frame,
+        };
+        frame.min(total)
+    };
+    let session_time = |position: u64| {
+        let ms = position * 1000 / sample_rate as u64;
+        format!("{}.{:03}", ms / 1000, ms % 1000)
+    };
+    let redacted_ms = events::unix_millis();
+    let redactions: Vec<Redaction> = spans
+        .iter()
+        .map(|span| Redaction {
+            from: session_time(span.sample_position),
+            to: session_time(span.end_position),
+            start_frame: file_frame(span.sample_position),
+            end_frame: file_frame(span.end_position),
+            reason: Some(span.kind.name().to_string()),
+            redacted_ms,
+        })
+        .filter(|redaction| redaction.start_frame < redaction.end_frame)
+        .collect();
+    if redactions.is_empty() {
+        return Ok(0);
+    }
+    redact::apply(recording_path, &audio, &redactions, "asr")?;
+    println!(
+        "[win-audio-capture] Silenced {} number(s) read out in the call",
+        redactions.len()
+    );
+    Ok(durations::covered_frames(
+        redactions.iter().map(|r| (r.start_frame, r.end_frame)),
+    ))
+}
+
+/// What every hook sees of the session, as `SELLY_*` environment variables
+fn hook_env(
+    args: &Args,
+    event: &str,
+    output: &Path,
+    sample_rate: u32,
+    channels: usize,
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("SELLY_SESSION", args.session.clone()),
+        ("SELLY_EVENT", event.to_string()),
+        ("SELLY_OUTPUT", output.display().to_string()),
+        ("SELLY_FORMAT", format!("{:?}", args.format).to_lowercase()),
+        ("SELLY_SAMPLE_RATE", sample_rate.to_string()),
+        ("SELLY_CHANNELS", channels.to_string()),
+        (
+            "SELLY_TIMELINE",
+            timeline::sidecar_path(&args.out, "timeline.json")
+                .display()
+                .to_string(),
+        ),
+        (
+            "SELLY_AUDIT_LOG",
+            audit::path(&args.out).display().to_string(),
+        ),
+        (
+            "SELLY_PIPELINE_TAG",
+            args.pipeline_tag
+                .map_or("", PipelineTag::as_str)
+                .to_string(),
+        ),
+    ]
+}
+
+/// Session state the control commands read and update
+struct Session {
+    id: String,
+    sample_rate: u32,
+    /// Absolute stereo pairs mixed so far
+    sample_position: u64,
+    capture_started: Instant,
+    timeline: Timeline,
+    mic_counter: Arc<SourceCounter>,
+    loopback_counter: Arc<SourceCounter>,
+    power: PowerProfile,
+    /// Wall time of the last suspend notification, until the gap it caused is recorded
+    suspended_at_ms: Option<u64>,
+    gain_staging: GainStaging,
+    headset_controls: HeadsetControls,
+    mute: MuteState,
+    /// Shared with the engine, which records silence on the MIC channel while set
+    mic_muted: Arc<AtomicBool>,
+    chapters: Option<ChapterDetector>,
+    /// `--sync-pulse-secs`
+    sync: Option<SyncPulse>,
+    auto_redact: Option<AutoRedactor>,
+    protocol: Protocol,
+    /// Negotiated frame types, markers and stats, for the frame stream
+    stream_notices: mpsc::UnboundedSender<Notice>,
+    /// Where `shared_pcm` consumers find the ring, if it could be created
+    shared_mapping: Option<Value>,
+    audit: AuditLog,
+    /// MIC processing parameters, as last sent to the mixer
+    dsp: DspParams,
+    dsp_updates: std::sync::mpsc::Sender<DspParams>,
+    /// Endpoint ID of the MIC, when known
+    mic_endpoint: Option<String>,
+    /// Since when another application holds the MIC exclusively
+    mic_locked_at: Option<Instant>,
+    /// Time spent locked out before the current lock
+    mic_locked_ms: u64,
+    /// Latency compensation, once applied
+    alignment: Option<Alignment>,
+    delays: std::sync::mpsc::Sender<(mixer::Source, usize)>,
+    ducking: DuckTracker,
+    /// Set by `panic_delete`; the loop ends capture and the files are wiped
+    panic_delete: Option<PanicDelete>,
+    /// How the last control commands with an `id` ended, for answering retries
+    outcomes: Outcomes,
+    /// `--out`, which attachments are stored next to
+    out: PathBuf,
+    /// Channel audio notes are played into, with `--notes channel`
+    note_channel: Option<usize>,
+    note_queue: std::sync::mpsc::Sender<NoteSamples>,
+    /// Audio notes stored as attachments
+    note_files: Vec<PathBuf>,
+    /// Position `pause` was sent at, while paused
+    paused_at: Option<u64>,
+    /// Frames earlier pauses recorded as silence
+    paused_frames: u64,
+    /// Set by `stop`; the loop passes it on like a signal
+    stop_requested: bool,
+    /// Where `rotate` asks the sink writer for a new file, or the option that rules it out
+    rotations: Result<std::sync::mpsc::Sender<Rotation>, &'static str>,
+    /// A rotation the sink writer hasn't reported yet
+    rotating: Option<Rotating>,
+    /// Files after `--out` the recording went on in, the current one last
+    parts: Vec<PathBuf>,
+    /// Session position the current file starts at
+    part_start: u64,
+    /// `--segment-seconds` in frames
+    segment_frames: Option<u64>,
+    pipeline_tag: Option<&'static str>,
+    /// Where `commit` asks the sink writer to keep its rolling buffer, with `--rolling-minutes`
+    commits: Option<std::sync::mpsc::Sender<()>>,
+    commit_requested: bool,
+    /// Follows the agent's clock reports, with `--clock external`
+    clock: Option<ClockTracker>,
+    /// The conferencing app's meeting, with `--meeting-bridge`
+    meetings: Option<MeetingTracker>,
+    /// Files added with `reconfigure`, written alongside the recording
+    added_sinks: Vec<AddedSink>,
+    sink_setup: SinkSetup,
+    /// The option `add_sink` can't be combined with, if any
+    add_sink_blocker: Option<&'static str>,
+    /// The stream carries SELL frames, whose length `frame_ms` sets
+    framed_stream: bool,
+}
+
+/// A requested rotation
+struct Rotating {
+    path: PathBuf,
+    /// Session position the new file starts at
+    at: u64,
+    started_ms: u64,
+    /// Session the new file belongs to, for a `new_session` hand-over
+    session: Option<String>,
+}
+
+/// An accepted `panic_delete`
+struct PanicDelete {
+    reason: Option<String>,
+}
+
+impl Session {
+    /// Append to the audit log at the current position; a failed write doesn't stop capture
+    fn audit(&mut self, action: &str, actor: &str, data: Value) {
+        if let Err(e) = self
+            .audit
+            .append(action, actor, Some(self.sample_position), Some(data))
+        {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Report how a hook command went, in the audit log and as a `hook` event
+    fn hook_finished(&mut self, outcome: Result<HookOutcome>) {
+        match outcome {
+            Ok(outcome) => {
+                if outcome.timed_out {
+                    eprintln!(
+                        "[win-audio-capture] Warning: {} hook killed after {}ms",
+                        outcome.hook, outcome.duration_ms
+                    );
+                } else if !outcome.succeeded() {
+                    eprintln!(
+                        "[win-audio-capture] Warning: {} hook exited with {:?}",
+                        outcome.hook, outcome.exit_code
+                    );
+                }
+                events::emit(
+                    "hook",
+                    json!({
+                        "session": self.id,
+                        "hook": outcome.hook,
+                        "exit_code": outcome.exit_code,
+                        "timed_out": outcome.timed_out,
+                        "duration_ms": outcome.duration_ms,
+                    }),
+                );
+                self.audit("hook", "cli", json!(outcome));
+            }
+            Err(e) => {
+                eprintln!("[win-audio-capture] Warning: {:#}", e);
+                events::emit(
+                    "hook",
+                    json!({ "session": self.id, "error": format!("{:#}", e) }),
+                );
+                self.audit("hook", "cli", json!({ "error": format!("{:#}", e) }));
+            }
+        }
+    }
+
+    /// Account for a mixed block and, once enough audio has been seen, report gain staging
+    fn observe(&mut self, block: &[i16]) {
+        let frames = (block.len() / 2) as u64;
+        if let Some(sync) = &mut self.sync {
+            for pulse in sync.observe(self.sample_position, frames, events::unix_millis()) {
+                events::emit(
+                    "sync",
+                    json!({
+                        "session": self.id,
+                        "index": pulse.index,
+                        "sample_position": pulse.sample_position,
+                        "sample_rate": self.sample_rate,
+                        "utc_ms": pulse.utc_ms,
+                        "utc": pulse.utc,
+                    }),
+                );
+            }
+        }
+        self.sample_position += frames;
+        if let Some(chapters) = &mut self.chapters {
+            chapters.observe(block);
+        }
+        let segment_due = self
+            .segment_frames
+            .is_some_and(|frames| self.sample_position - self.part_start >= frames);
+        if segment_due && self.rotating.is_none() {
+            if let Err(e) = self.rotate("segment", None) {
+                eprintln!("[win-audio-capture] Warning: Segment rotation: {:#}", e);
+                self.part_start = self.sample_position;
+            }
+        }
+        if let Some(report) = self.gain_staging.observe(block) {
+            println!(
+                "[win-audio-capture] Gain staging: MIC {:?}, loopback {:?}",
+                report.mic.recommendation, report.loopback.recommendation
+            );
+            events::emit(
+                "gain_staging",
+                json!({ "session": self.id, "report": report }),
+            );
+        }
+    }
+
+    fn suspending(&mut self) {
+        println!("[win-audio-capture] System is suspending");
+        self.suspended_at_ms = Some(events::unix_millis());
+        events::emit(
+            "suspend",
+            json!({ "session": self.id, "sample_position": self.sample_position }),
+        );
+        self.audit("suspend", "os", json!({}));
+    }
+
+    fn resumed(&mut self) {
+        println!("[win-audio-capture] System resumed, reopening devices");
+        events::emit(
+            "resume",
+            json!({ "session": self.id, "sample_position": self.sample_position }),
+        );
+        self.audit("resume", "os", json!({}));
+    }
+
+    /// The MIC stream delivered nothing for `stalled` and was reopened (or not)
+    fn mic_stalled(&mut self, stalled: Duration, reopened: bool, stats: StallStats) {
+        let stalled_ms = stalled.as_millis() as u64;
+        eprintln!(
+            "[win-audio-capture] Warning: MIC delivered nothing for {}ms, {}",
+            stalled_ms,
+            if reopened {
+                "restarted its stream"
+            } else {
+                "could not restart it"
+            }
+        );
+        events::emit(
+            "stream_stalled",
+            json!({
+                "session": self.id,
+                "source": "mic",
+                "sample_position": self.sample_position,
+                "stalled_ms": stalled_ms,
+                "reopened": reopened,
+                "stalls": stats.stalls,
+            }),
+        );
+        self.audit(
+            "stream_restart",
+            "os",
+            json!({ "source": "mic", "stalled_ms": stalled_ms, "reopened": reopened }),
+        );
+    }
+
+    /// Reopen the MIC stream and hand its ring to the mixer; `None` if the device doesn't
+    /// come back, or is held by another application (see `mic_locked`)
+    fn reopen_mic(
+        &mut self,
+        mic: &MicInput,
+        replacements: &std::sync::mpsc::Sender<Consumer<f32>>,
+        reason: &str,
+    ) -> Option<MicStream> {
+        let opened = mic.open().and_then(|(stream, ring)| {
+            stream.play().context("Failed to start MIC stream")?;
+            Ok((stream, ring))
+        });
+        match opened {
+            Ok((stream, ring)) => {
+                let _ = replacements.send(ring);
+                self.mic_unlocked();
+                Some(stream)
+            }
+            Err(e) if mic_lock::is_exclusive_lock(&e) => {
+                self.mic_locked();
+                None
+            }
+            Err(e) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: MIC reopen after {} failed: {:#}",
+                    reason, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Derive the latency offset once both paths have delivered for long enough; returns
+    /// whether the measurement is done (applied, or given up on as implausible)
+    fn measure_latency(&mut self) -> bool {
+        if self.capture_started.elapsed() < latency::CALIBRATION {
+            return false;
+        }
+        let (Some(mic), Some(loopback)) = (
+            self.mic_counter.mean_latency(latency::MIN_PACKETS),
+            self.loopback_counter.mean_latency(latency::MIN_PACKETS),
+        ) else {
+            // The loopback delivers nothing while nothing plays
+            return false;
+        };
+        match Alignment::measured(mic, loopback, self.sample_rate) {
+            Some(alignment) => self.align(alignment, "os"),
+            None => eprintln!(
+                "[win-audio-capture] Warning: MIC/loopback latencies of {:?}/{:?} are too far apart, not compensating",
+                mic, loopback
+            ),
+        }
+        true
+    }
+
+    /// Hold the faster path back by the alignment's offset
+    fn align(&mut self, alignment: Alignment, actor: &str) {
+        if alignment.frames > 0 {
+            let _ = self
+                .delays
+                .send((alignment.delayed, alignment.frames as usize));
+        }
+        println!(
+            "[win-audio-capture] Latency compensation: loopback {:+.1}ms against the MIC, delaying the {:?} by {} frames",
+            alignment.offset_ms, alignment.delayed, alignment.frames
+        );
+        events::emit(
+            "latency_aligned",
+            json!({
+                "session": self.id,
+                "sample_position": self.sample_position,
+                "alignment": alignment,
+            }),
+        );
+        self.audit("latency_alignment", actor, json!(alignment));
+        self.alignment = Some(alignment);
+    }
+
+    /// Another application holds the MIC exclusively: report who, and keep recording the
+    /// loopback until it is free
+    fn mic_locked(&mut self) {
+        if self.mic_locked_at.is_some() {
+            return;
+        }
+        self.mic_locked_at = Some(Instant::now());
+        let holders = mic_lock::holders(self.mic_endpoint.as_deref())
+            .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+            .unwrap_or_default();
+        eprintln!(
+            "[win-audio-capture] Warning: MIC is held exclusively by another application ({}), recording the loopback only",
+            holders
+                .iter()
+                .map(|holder| holder.app.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        events::emit(
+            "mic_locked",
+            json!({
+                "session": self.id,
+                "sample_position": self.sample_position,
+                "reason": "exclusive",
+                "holders": holders,
+                "fallback": "loopback_only",
+            }),
+        );
+        self.audit("mic_locked", "os", json!({ "holders": holders }));
+    }
+
+    /// The MIC opened again after a lock
+    fn mic_unlocked(&mut self) {
+        let Some(since) = self.mic_locked_at.take() else {
+            return;
+        };
+        let locked_ms = since.elapsed().as_millis() as u64;
+        self.mic_locked_ms += locked_ms;
+        println!(
+            "[win-audio-capture] MIC is free again after {}ms, recording it",
+            locked_ms
+        );
+        events::emit(
+            "mic_unlocked",
+            json!({
+                "session": self.id,
+                "sample_position": self.sample_position,
+                "locked_ms": locked_ms,
+            }),
+        );
+        self.audit("mic_unlocked", "os", json!({ "locked_ms": locked_ms }));
+    }
+
+    /// Mark skipped clock time in the timeline. The recording itself stays continuous, so
+    /// the entry is the only record of how long the gap was.
+    fn record_gap(&mut self, gap: Gap) {
+        let skipped_ms = gap.skipped_frames * 1000 / self.sample_rate as u64;
+        let cause = if self.suspended_at_ms.take().is_some() {
+            "suspend"
+        } else {
+            "stall"
+        };
+        let entry = TimelineEntry {
+            kind: "gap".to_string(),
+            label: Some(cause.to_string()),
+            sample_position: gap.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "system".to_string(),
+            data: Some(json!({
+                "skipped_frames": gap.skipped_frames,
+                "skipped_ms": skipped_ms,
+            })),
+        };
+        eprintln!(
+            "[win-audio-capture] Skipped {}ms of clock time ({})",
+            skipped_ms, cause
+        );
+        events::emit("gap", json!(entry));
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Mark a headset button press and apply it. Returns true when the press should stop
+    /// the capture.
+    fn headset_button(&mut self, button: HeadsetButton) -> bool {
+        let full = self.headset_controls == HeadsetControls::Full;
+        if full && button == HeadsetButton::Mute {
+            self.mute.headset = !self.mute.headset;
+            self.apply_mute();
+            let action = if self.mute.headset { "mute" } else { "unmute" };
+            let silenced = self.mute.silenced();
+            self.audit(action, "headset", json!({ "silenced": silenced }));
+        }
+        let muted = self.mute.silenced();
+        println!("[win-audio-capture] Headset button: {:?}", button);
+
+        let entry = TimelineEntry {
+            kind: "headset".to_string(),
+            label: serde_json::to_value(button)
+                .ok()
+                .and_then(|label| label.as_str().map(str::to_string)),
+            sample_position: self.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "headset".to_string(),
+            data: Some(json!({ "mic_muted": muted })),
+        };
+        events::emit(
+            "headset",
+            json!({ "session": self.id, "button": button, "entry": entry }),
+        );
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+
+        full && button == HeadsetButton::OnHook
+    }
+
+    /// Play `path` into the notes channel or store it as an attachment, and say where it went
+    fn audio_note(&mut self, path: &Path) -> Result<Value> {
+        let mut samples = notes::load(path, self.sample_rate)?;
+        let duration_ms = samples.len() as u64 * 1000 / self.sample_rate as u64;
+        if let Some(channel) = self.note_channel {
+            notes::normalize(&mut samples);
+            let _ = self.note_queue.send(samples.into());
+            return Ok(json!({ "channel": channel, "duration_ms": duration_ms }));
+        }
+        let file = timeline::sidecar_path(
+            &self.out,
+            &format!("note-{}.wav", self.note_files.len() + 1),
+        );
+        std::fs::copy(path, &file).with_context(|| format!("Failed to store {:?}", path))?;
+        self.note_files.push(file.clone());
+        Ok(json!({ "file": file, "duration_ms": duration_ms }))
+    }
+
+    /// Follow the OS mute state of the MIC endpoint; repeats (volume changes) are ignored
+    fn endpoint_muted(&mut self, muted: bool) {
+        if muted == self.mute.endpoint {
+            return;
+        }
+        self.mute.endpoint = muted;
+        self.apply_mute();
+
+        let kind = if muted { "mute" } else { "unmute" };
+        println!("[win-audio-capture] MIC endpoint {}d", kind);
+        let silenced = self.mute.silenced();
+        self.audit(kind, "os", json!({ "silenced": silenced }));
+        let entry = TimelineEntry {
+            kind: kind.to_string(),
+            label: None,
+            sample_position: self.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "os".to_string(),
+            data: Some(json!({ "silenced": self.mute.silenced() })),
+        };
+        events::emit(kind, json!({ "session": self.id, "entry": entry }));
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Follow the conferencing app's meeting; only changes are reported
+    fn meeting(&mut self, seen: Option<Meeting>) {
+        let Some(tracker) = &mut self.meetings else {
+            return;
+        };
+        let Some(change) = tracker.observe(seen) else {
+            return;
+        };
+        let meeting = tracker.current().or(tracker.last()).cloned();
+        println!("[win-audio-capture] {}: {:?}", change.name(), meeting);
+        let entry = TimelineEntry {
+            kind: change.name().to_string(),
+            label: meeting
+                .as_ref()
+                .and_then(|meeting| meeting.meeting_id.clone()),
+            sample_position: self.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "conferencing".to_string(),
+            data: serde_json::to_value(&meeting).ok(),
+        };
+        events::emit(
+            change.name(),
+            json!({ "session": self.id, "meeting": meeting, "entry": entry }),
+        );
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Mark the start or end of a stretch in which other audio is ducked
+    fn ducking(&mut self, event: DuckEvent) {
+        let now = Instant::now();
+        let ducked = match self.ducking.observe(event, now) {
+            Some(ducked) => ducked,
+            None => return,
+        };
+        let data = match event {
+            DuckEvent::Ducked {
+                communication_sessions,
+            } => json!({ "ducked": true, "communication_sessions": communication_sessions }),
+            DuckEvent::Unducked => {
+                json!({ "ducked": false, "stats": self.ducking.stats(now) })
+            }
+        };
+        println!(
+            "[win-audio-capture] System audio {}",
+            if ducked { "ducked" } else { "no longer ducked" }
+        );
+        self.audit("ducking", "os", data.clone());
+        let entry = TimelineEntry {
+            kind: "ducking".to_string(),
+            label: Some(if ducked { "start" } else { "end" }.to_string()),
+            sample_position: self.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "os".to_string(),
+            data: Some(data),
+        };
+        events::emit("ducking", json!({ "session": self.id, "entry": entry }));
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Log a MIC channel the input callback started flipping
+    fn polarity_corrected(&mut self, flip: PolarityFlip) {
+        println!(
+            "[win-audio-capture] MIC channel {} is inverted (correlation {:.2}), correcting",
+            flip.channel, flip.correlation
+        );
+        let entry = TimelineEntry {
+            kind: "polarity_corrected".to_string(),
+            label: None,
+            sample_position: self.sample_position,
+            wall_time_ms: events::unix_millis(),
+            source: "sidecar".to_string(),
+            data: Some(json!(flip)),
+        };
+        events::emit(
+            "polarity_corrected",
+            json!({ "session": self.id, "entry": entry }),
+        );
+        if let Err(e) = self.timeline.push(entry) {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+    }
+
+    /// Put the chapters on the timeline, returning them as labelled cue points for the
+    /// finished recording
+    fn mark_chapters(
+        &mut self,
+        chapters: &[Chapter],
+        elisions: &[Elision],
+        trim: Option<Trim>,
+    ) -> Result<Vec<(u64, String)>> {
+        let mut cues = Vec::with_capacity(chapters.len());
+        for (i, chapter) in chapters.iter().enumerate() {
+            let label = format!("Chapter {}", i + 1);
+            if let Some(frame) = cue_frame(chapter.sample_position, elisions, trim) {
+                cues.push((frame, label.clone()));
+            }
+            self.timeline.push(TimelineEntry {
+                kind: "chapter".to_string(),
+                label: Some(label),
+                sample_position: chapter.sample_position,
+                wall_time_ms: events::unix_millis(),
+                source: "sidecar".to_string(),
+                data: Some(json!({ "reason": chapter.reason, "mode": chapter.mode })),
+            })?;
+        }
+        println!("[win-audio-capture] Marked {} chapter(s)", chapters.len());
+        events::emit(
+            "chapters",
+            json!({ "session": self.id, "chapters": chapters }),
+        );
+        Ok(cues)
+    }
+
+    fn apply_mute(&self) {
+        self.mic_muted.store(self.mute.silenced(), Ordering::SeqCst);
+    }
+
+    /// `commit` took effect: the rolling buffer is being written out and recording goes on
+    fn committed(&mut self, committed: Committed) {
+        println!(
+            "[win-audio-capture] Keeping the recording, {} frames of it from the rolling buffer",
+            committed.kept_frames
+        );
+        events::emit(
+            "committed",
+            json!({
+                "session": self.id,
+                "path": self.out,
+                "sample_position": self.sample_position,
+                "kept_frames": committed.kept_frames,
+                "dropped_frames": committed.dropped_frames,
+            }),
+        );
+    }
+
+    /// Ask the sink writer to carry on in the next part from the current position, which
+    /// belongs to `session` if given
+    fn rotate(&mut self, actor: &str, session: Option<String>) -> Result<()> {
+        let requests = self
+            .rotations
+            .as_ref()
+            .map_err(|option| fail!(Config, "rotate is not available with {}", option))?;
+        if self.rotating.is_some() {
+            return Err(fail!(Protocol, "a rotation is already in progress"));
+        }
+        let path = paths::part_path(&self.out, self.parts.len() + 2);
+        requests
+            .send(Rotation {
+                at: self.sample_position,
+                path: path.clone(),
+            })
+            .map_err(|_| anyhow!("the recording writer has stopped"))?;
+        println!("[win-audio-capture] Rotating to {:?}", path);
+        self.audit("rotate", actor, json!({ "path": path, "session": session }));
+        self.rotating = Some(Rotating {
+            path,
+            at: self.sample_position,
+            started_ms: events::unix_millis(),
+            session,
+        });
+        Ok(())
+    }
+
+    /// With `--segment-seconds`, report a finished part as a segment
+    fn segment_completed(&self, closed: &SinkSummary, start_position: u64) {
+        if self.segment_frames.is_none() {
+            return;
+        }
+        events::emit(
+            "segment_completed",
+            json!({
+                "session": self.id,
+                "index": self.parts.len() + 1,
+                "path": closed.path,
+                "frames": closed.frames,
+                "duration_ms": closed.frames * 1_000 / self.sample_rate as u64,
+                "bytes": closed.bytes,
+                "sample_position": start_position,
+            }),
+        );
+    }
+
+    /// The sink writer closed the previous file of a rotation (or couldn't): the next one
+    /// takes over the timeline from the rotation on and joins the upload manifest
+    fn rotated(&mut self, closed: Result<SinkSummary>) {
+        let Some(rotating) = self.rotating.take() else {
+            return;
+        };
+        let closed = match closed {
+            Ok(closed) => closed,
+            Err(e) => {
+                eprintln!("[win-audio-capture] Warning: Rotation failed: {:#}", e);
+                events::emit(
+                    "rotate_error",
+                    json!({
+                        "session": self.id,
+                        "path": rotating.path,
+                        "error": format!("{:#}", e),
+                    }),
+                );
+                // The current file goes on; the next segment is tried a segment later
+                self.part_start = self.sample_position;
+                return;
+            }
+        };
+        let session = rotating.session.clone().unwrap_or_else(|| self.id.clone());
+        let mut next = Timeline::new(
+            &rotating.path,
+            &session,
+            self.sample_rate,
+            self.pipeline_tag,
+        )
+        .with_start(rotating.started_ms)
+        .starting_at(rotating.at);
+        if rotating.session.is_some() {
+            next = next.with_predecessor(ChainLink {
+                session: self.id.clone(),
+                path: closed.path.clone(),
+                sample_position: rotating.at,
+            });
+        }
+        let mut previous = std::mem::replace(&mut self.timeline, next);
+        if rotating.session.is_some() {
+            let link = ChainLink {
+                session: session.clone(),
+                path: rotating.path.clone(),
+                sample_position: rotating.at,
+            };
+            if let Err(e) = previous.set_successor(link) {
+                eprintln!("[win-audio-capture] Warning: {:#}", e);
+            }
+        }
+        let moved = previous.split_off(rotating.at).unwrap_or_else(|e| {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+            Vec::new()
+        });
+        for entry in moved {
+            if let Err(e) = self.timeline.push(entry) {
+                eprintln!("[win-audio-capture] Warning: {:#}", e);
+            }
+        }
+        // Written right away, like the first file's, so the part can be stitched
+        if let Err(e) = self.timeline.save() {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+        }
+        if rotating.session.is_some() {
+            update_upload_manifest(&self.id, |manifest| {
+                manifest.finalize_segment(&closed.path, closed.bytes)
+            });
+            update_upload_manifest(&session, |manifest| {
+                manifest.pipeline_tag = self.pipeline_tag.map(str::to_string);
+                manifest.add_segment(&rotating.path);
+                Ok(())
+            });
+        } else {
+            update_upload_manifest(&self.id, |manifest| {
+                manifest.finalize_segment(&closed.path, closed.bytes)?;
+                manifest.add_segment(&rotating.path);
+                Ok(())
+            });
+        }
+        self.segment_completed(&closed, self.part_start);
+        self.part_start = rotating.at;
+        self.parts.push(rotating.path.clone());
+
+        println!(
+            "[win-audio-capture] Rotated, {:?} is complete ({} frames)",
+            closed.path, closed.frames
+        );
+        self.audit(
+            "rotated",
+            "control",
+            json!({ "closed": closed.path, "bytes": closed.bytes, "next": rotating.path }),
+        );
+        events::emit(
+            "rotated",
+            json!({
+                "session": self.id,
+                "path": closed.path,
+                "frames": closed.frames,
+                "bytes": closed.bytes,
+                "next": rotating.path,
+                "sample_position": rotating.at,
+            }),
+        );
+        if session != self.id {
+            println!(
+                "[win-audio-capture] Session {} continues as {}",
+                self.id, session
+            );
+            self.audit("new_session", "control", json!({ "successor": session }));
+            events::emit(
+                "session_chained",
+                json!({
+                    "session": session,
+                    "predecessor": self.id,
+                    "path": rotating.path,
+                    "predecessor_path": closed.path,
+                    "sample_position": rotating.at,
+                }),
+            );
+            self.id = session;
+        }
+    }
+
+    /// Carry out a control command and answer it
+    fn handle_request(&mut self, request: Request) {
+        let cmd = request.command.name();
+        let id = request.id.as_deref();
+        if let Some(outcome) = id.and_then(|id| self.outcomes.get(id)).cloned() {
+            // A retry of a command already carried out
+            control::answer(id, cmd, &outcome, json!({ "duplicate": true }));
+            return;
+        }
+        if request.expired() {
+            // Not remembered, so a retry with the same id still runs
+            let outcome = Outcome::Failed(
+                ErrorKind::Protocol,
+                "Timed out before it was carried out".to_string(),
+            );
+            control::answer(id, cmd, &outcome, json!({ "expired": true }));
+            return;
+        }
+        let outcome = match self.handle_command(request.command) {
+            Ok(()) => Outcome::Done,
+            Err(e) => Outcome::failed(&e),
+        };
+        control::answer(request.id.as_deref(), cmd, &outcome, json!({}));
+        if let Some(id) = request.id {
+            self.outcomes.insert(id, outcome);
+        }
+    }
+
+    fn handle_command(&mut self, command: ControlCommand) -> Result<()> {
+        let _span = trace::span("control");
+        match command {
+            ControlCommand::Position => {
+                events::emit(
+                    "position",
+                    json!({
+                        "session": self.id,
+                        "sample_position": self.sample_position,
+                        "sample_rate": self.sample_rate,
+                        "wall_time_ms": events::unix_millis(),
+                        "elapsed_ms": self.capture_started.elapsed().as_millis() as u64,
+                        "sources": {
+                            "mic": self.mic_counter.drift(),
+                            "loopback": self.loopback_counter.drift(),
+                        },
+                        "power": self.power,
+                    }),
+                );
+            }
+            ControlCommand::TimelineEvent {
+                kind,
+                label,
+                at_ms,
+                data,
+            } => {
+                // Shift the anchor back by however long ago the event happened
+                let now_ms = events::unix_millis();
+                let wall_time_ms = at_ms.unwrap_or(now_ms);
+                let lag_samples =
+                    now_ms.saturating_sub(wall_time_ms) * self.sample_rate as u64 / 1000;
+                let entry = TimelineEntry {
+                    kind,
+                    label,
+                    sample_position: self.sample_position.saturating_sub(lag_samples),
+                    wall_time_ms,
+                    source: "external".to_string(),
+                    data,
+                };
+                events::emit("timeline_event", json!(entry));
+                if let Err(e) = self.timeline.push(entry) {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                }
+            }
+            ControlCommand::Negotiate { frames } => {
+                // A v1 stream has nothing to negotiate
+                let v1 = self.protocol == Protocol::V1;
+                let shared = self.shared_mapping.is_some();
+                let (accepted, rejected): (Vec<String>, Vec<String>) =
+                    frames.into_iter().partition(|name| {
+                        !v1 && stream::OPTIONAL_FRAMES.contains(&name.as_str())
+                            && (shared || name != "shared_pcm")
+                    });
+                let shared_mapping = accepted
+                    .iter()
+                    .any(|name| name == "shared_pcm")
+                    .then(|| self.shared_mapping.clone());
+                events::emit(
+                    "negotiated",
+                    json!({
+                        "session": self.id,
+                        "protocol": self.protocol.version(),
+                        "frames": accepted,
+                        "rejected": rejected,
+                        "shared_pcm": shared_mapping,
+                    }),
+                );
+                let _ = self.stream_notices.send(Notice::Negotiated(accepted));
+            }
+            ControlCommand::AudioNote { path, label } => {
+                let data = self.audio_note(&path)?;
+                let entry = TimelineEntry {
+                    kind: "audio_note".to_string(),
+                    label,
+                    sample_position: self.sample_position,
+                    wall_time_ms: events::unix_millis(),
+                    source: "external".to_string(),
+                    data: Some(data),
+                };
+                self.audit(
+                    "audio_note",
+                    "control",
+                    json!({ "path": path, "note": entry }),
+                );
+                events::emit("audio_note", json!(entry));
+                if let Err(e) = self.timeline.push(entry) {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                }
+            }
+            ControlCommand::PanicDelete { session, reason } => {
+                if session != self.id {
+                    return Err(fail!(Protocol, "session {:?} is not this capture", session));
+                }
+                println!("[win-audio-capture] Panic delete requested, discarding the session");
+                self.audit("panic_delete", "control", json!({ "reason": reason }));
+                events::emit(
+                    "panic_deleting",
+                    json!({ "session": self.id, "reason": reason }),
+                );
+                self.panic_delete = Some(PanicDelete { reason });
+            }
+            ControlCommand::Pause => {
+                if self.paused_at.is_some() {
+                    return Err(fail!(Protocol, "capture is already paused"));
+                }
+                println!("[win-audio-capture] Paused, recording silence");
+                self.paused_at = Some(self.sample_position);
+                self.audit("pause", "control", json!({}));
+                let entry = TimelineEntry {
+                    kind: "pause".to_string(),
+                    label: None,
+                    sample_position: self.sample_position,
+                    wall_time_ms: events::unix_millis(),
+                    source: "sidecar".to_string(),
+                    data: None,
+                };
+                events::emit("paused", json!({ "session": self.id, "entry": entry }));
+                if let Err(e) = self.timeline.push(entry) {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                }
+            }
+            ControlCommand::Resume => {
+                let Some(paused_at) = self.paused_at.take() else {
+                    return Err(fail!(Protocol, "capture is not paused"));
+                };
+                let frames = self.sample_position - paused_at;
+                self.paused_frames += frames;
+                println!(
+                    "[win-audio-capture] Resumed after {:.1}s paused",
+                    frames as f64 / self.sample_rate as f64
+                );
+                self.audit("resume", "control", json!({ "paused_frames": frames }));
+                let entry = TimelineEntry {
+                    kind: "resume".to_string(),
+                    label: None,
+                    sample_position: self.sample_position,
+                    wall_time_ms: events::unix_millis(),
+                    source: "sidecar".to_string(),
+                    data: Some(json!({ "paused_frames": frames })),
+                };
+                events::emit("resumed", json!({ "session": self.id, "entry": entry }));
+                if let Err(e) = self.timeline.push(entry) {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                }
+            }
+            ControlCommand::Marker { label, data } => {
+                let entry = TimelineEntry {
+                    kind: "marker".to_string(),
+                    label: Some(label),
+                    sample_position: self.sample_position,
+                    wall_time_ms: events::unix_millis(),
+                    source: "external".to_string(),
+                    data,
+                };
+                events::emit("marker", json!({ "session": self.id, "entry": entry }));
+                if self.protocol == Protocol::V3 {
+                    let _ = self.stream_notices.send(Notice::Marker(json!(entry)));
+                }
+                if let Err(e) = self.timeline.push(entry) {
+                    eprintln!("[win-audio-capture] Warning: {:#}", e);
+                }
+            }
+            ControlCommand::Rotate => self.rotate("control", None)?,
+            ControlCommand::NewSession { session } => {
+                if session.is_empty() || session == self.id {
+                    return Err(fail!(
+                        Protocol,
+                        "new_session needs a session other than this one"
+                    ));
+                }
+                self.rotate("control", Some(session))?;
+            }
+            ControlCommand::Commit => {
+                let commits = self
+                    .commits
+                    .as_ref()
+                    .ok_or_else(|| fail!(Config, "commit needs --rolling-minutes"))?;
+                // Committing again is harmless: the recording is already being kept
+                if !self.commit_requested {
+                    commits
+                        .send(())
+                        .map_err(|_| anyhow!("the recording writer has stopped"))?;
+                    self.commit_requested = true;
+                    self.audit("commit", "control", json!({ "at": self.sample_position }));
+                }
+            }
+            ControlCommand::Clock {
+                media_time_ms,
+                at_ms,
+            } => {
+                let tracker = self
+                    .clock
+                    .as_mut()
+                    .ok_or_else(|| fail!(Config, "clock needs --clock external"))?;
+                if !media_time_ms.is_finite() {
+                    return Err(fail!(Protocol, "media_time_ms must be a number"));
+                }
+                let in_transit =
+                    at_ms.map_or(0, |at_ms| events::unix_millis().saturating_sub(at_ms));
+                let local = tracker
+                    .local_elapsed()
+                    .saturating_sub(Duration::from_millis(in_transit));
+                match tracker.correct(media_time_ms, local) {
+                    Correction::Locked => {
+                        println!("[win-audio-capture] Following the external clock");
+                        events::emit(
+                            "clock_locked",
+                            json!({ "session": self.id, "sample_position": self.sample_position }),
+                        );
+                    }
+                    Correction::Reset { error_ms } => {
+                        eprintln!(
+                            "[win-audio-capture] Warning: External clock was {:.0} ms off, re-anchored",
+                            error_ms
+                        );
+                        events::emit(
+                            "clock_reset",
+                            json!({
+                                "session": self.id,
+                                "sample_position": self.sample_position,
+                                "error_ms": error_ms,
+                            }),
+                        );
+                    }
+                    Correction::Tracking { .. } => {}
+                }
+            }
+            ControlCommand::Stop => self.stop_requested = true,
+            ControlCommand::Transcript {
+                words,
+                fed,
+                is_final,
+            } => {
+                let mut words = transcript::align(words, &fed, self.sample_rate);
+                let found = match &mut self.auto_redact {
+                    Some(redactor) => redactor.scan(&mut words),
+                    None => Vec::new(),
+                };
+                for span in found {
+                    self.audit("auto_redact", "asr", json!(span));
+                    events::emit("auto_redacted", json!({ "session": self.id, "span": span }));
+                }
+                events::emit(
+                    "transcript",
+                    json!({
+                        "session": self.id,
+                        "final": is_final,
+                        "sample_rate": self.sample_rate,
+                        "words": words,
+                    }),
+                );
+            }
+            ControlCommand::Reconfigure(change) => {
+                let stages = change.stages();
+                if stages.is_empty() {
+                    return Err(fail!(
+                        Protocol,
+                        "reconfigure needs denoise, frame_ms or add_sink"
+                    ));
+                }
+                // Everything is checked first, so a bad field restarts nothing
+                let dsp = match change.denoise {
+                    Some(denoise) => Some(self.dsp.apply(DspUpdate {
+                        denoise: Some(denoise),
+                        ..DspUpdate::default()
+                    })?),
+                    None => None,
+                };
+                let frame_samples = match change.frame_ms {
+                    Some(_) if !self.framed_stream => {
+                        return Err(fail!(Config, "frame_ms needs --stdout-format frames"));
+                    }
+                    Some(frame_ms) => {
+                        Some(reconfigure::samples_per_frame(frame_ms, self.sample_rate)?)
+                    }
+                    None => None,
+                };
+                if let Some(request) = &change.add_sink {
+                    if let Some(option) = self.add_sink_blocker {
+                        return Err(fail!(Config, "add_sink can't be combined with {}", option));
+                    }
+                    let taken = request.path == self.out
+                        || self.parts.contains(&request.path)
+                        || self
+                            .added_sinks
+                            .iter()
+                            .any(|added| added.path() == request.path);
+                    if taken || request.path.exists() {
+                        return Err(fail!(Config, "{:?} already exists", request.path));
+                    }
+                }
+
+                if let Some(params) = dsp {
+                    self.dsp = params;
+                    let _ = self.dsp_updates.send(params);
+                }
+                if let Some(samples) = frame_samples {
+                    let _ = self.stream_notices.send(Notice::FrameSize(samples));
+                }
+                let added = change.add_sink.map(|request| {
+                    let added = AddedSink::open(request, &self.sink_setup, self.sample_position);
+                    let path = added.path().to_path_buf();
+                    self.added_sinks.push(added);
+                    path
+                });
+                self.audit(
+                    "reconfigure",
+                    "control",
+                    json!({ "stages": stages, "frame_ms": change.frame_ms, "added_sink": added }),
+                );
+                events::emit(
+                    "reconfigured",
+                    json!({
+                        "session": self.id,
+                        "stages": stages,
+                        "dsp": dsp,
+                        "frame_ms": change.frame_ms,
+                        "added_sink": added,
+                        "sample_position": self.sample_position,
+                    }),
+                );
+            }
+            ControlCommand::Dsp(update) => {
+                let params = self.dsp.apply(update)?;
+                self.dsp = params;
+                let _ = self.dsp_updates.send(params);
+                self.audit("dsp", "control", json!(params));
+                events::emit(
+                    "dsp",
+                    json!({
+                        "session": self.id,
+                        "params": params,
+                        "active": self.power.optional_dsp,
+                    }),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Overwrite and remove everything the session has written except its audit log, the
+/// record of the deletion; later events go out on stderr only
+fn wipe_session(
+    args: &Args,
+    session: &mut Session,
+    recording_path: &Path,
+    request: PanicDelete,
+) -> Result<()> {
+    let timeline = timeline::sidecar_path(&args.out, "timeline.json");
+    let mut paths = vec![
+        recording_path.to_path_buf(),
+        timeline.with_extension("json.tmp"),
+        timeline,
+    ];
+    paths.extend(args.dsp_compare.clone());
+    paths.extend(
+        session
+            .added_sinks
+            .iter()
+            .map(|added| added.path().to_path_buf()),
+    );
+    paths.extend(session.note_files.iter().cloned());
+    for part in &session.parts {
+        let timeline = timeline::sidecar_path(part, "timeline.json");
+        paths.extend([part.clone(), timeline.with_extension("json.tmp"), timeline]);
+    }
+    // Closed first: an open file can't be removed on Windows
+    events::set_journal(None);
+    paths.push(journal::path(&args.out));
+
+    let mut wiped = Vec::new();
+    let mut failed = Vec::new();
+    for path in paths {
+        match wipe::wipe(&path) {
+            Ok(Some(file)) => wiped.push(file),
+            Ok(None) => {}
+            Err(e) => failed.push(json!({ "path": path, "error": format!("{:#}", e) })),
+        }
+    }
+    update_upload_manifest(&args.session, |manifest| {
+        manifest.remove_segment(&args.out);
+        for part in &session.parts {
+            manifest.remove_segment(part);
+        }
+        Ok(())
+    });
+
+    session.audit(
+        "wiped",
+        "control",
+        json!({ "reason": request.reason, "files": wiped, "failed": failed }),
+    );
+    events::emit(
+        "panic_deleted",
+        json!({
+            "session": args.session,
+            "reason": request.reason,
+            "files": wiped,
+            "failed": failed,
+            "audit": { "path": session.audit.path(), "head": session.audit.head() },
+        }),
+    );
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Failed to wipe {} file(s) of session {}",
+            failed.len(),
+            args.session
+        ));
+    }
+    println!(
+        "[win-audio-capture] Session discarded, {} file(s) wiped",
+        wiped.len()
+    );
+    Ok(())
+}
+
+/// End a `--rolling-minutes` session that was never committed: the recording only holds a
+/// header, and it goes with its timeline
+fn discard_uncommitted(args: &Args, session: &mut Session, recording_path: &Path) -> Result<()> {
+    let timeline = timeline::sidecar_path(&args.out, "timeline.json");
+    for path in [recording_path.to_path_buf(), timeline] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove {:?}", path)),
+        }
+    }
+    update_upload_manifest(&args.session, |manifest| {
+        manifest.remove_segment(&args.out);
+        Ok(())
+    });
+    session.audit("stopped", "cli", json!({ "committed": false }));
+    println!("[win-audio-capture] Recording stopped without a commit, nothing was kept");
+    events::emit(
+        "stopped",
+        json!({
+            "session": args.session,
+            "path": null,
+            "samples": 0,
+            "sample_position": session.sample_position,
+            "committed": false,
+        }),
+    );
+    Ok(())
+}
+
+/// `--preview-speed`, run as a finalize job
+async fn write_preview(
+    args: &Args,
+    recording: &Path,
+    unreadable: Option<&str>,
+    finalize: &Arc<Finalize>,
+    slots: &jobs::Slots,
+) -> Result<Option<PathBuf>> {
+    let Some(speed) = args.preview_speed else {
+        return Ok(None);
+    };
+    if let Some(state) = unreadable {
+        eprintln!(
+            "[win-audio-capture] Warning: Recording is {}, skipping the preview",
+            state
+        );
+        return Ok(None);
+    }
+    let Some(mut stage) = finalize.stage(Stage::Preview) else {
+        return Ok(None);
+    };
+    let (path, format) = (recording.to_path_buf(), args.format);
+    let written = slots
+        .run(move || {
+            let preview = preview::write(&path, format, speed, &mut |done, total| {
+                stage.report(done, total)
+            });
+            if preview.is_ok() {
+                stage.finish();
+            }
+            preview
+        })
+        .await
+        .context("Preview writer panicked")?;
+    match written {
+        Ok(preview) => {
+            println!(
+                "[win-audio-capture] Wrote {}x preview: {}",
+                speed,
+                preview.path.display()
+            );
+            Ok(Some(preview.path))
+        }
+        Err(e) => {
+            eprintln!("[win-audio-capture] Warning: Preview failed: {:#}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// `--highlights`, run as a finalize job
+async fn write_highlights(
+    args: &Args,
+    recording: &Path,
+    unreadable: Option<&str>,
+    finalize: &Arc<Finalize>,
+    slots: &jobs::Slots,
+) -> Result<Vec<highlights::Highlight>> {
+    let Some(count) = args.highlights.filter(|&count| count > 0) else {
+        return Ok(Vec::new());
+    };
+    if let Some(state) = unreadable {
+        eprintln!(
+            "[win-audio-capture] Warning: Recording is {}, skipping highlights",
+            state
+        );
+        return Ok(Vec::new());
+    }
+    let Some(mut stage) = finalize.stage(Stage::Highlights) else {
+        return Ok(Vec::new());
+    };
+    let (path, format, secs) = (recording.to_path_buf(), args.format, args.highlight_secs);
+    let written = slots
+        .run(move || {
+            let clips = highlights::write(&path, format, count, secs, &mut |done, total| {
+                stage.report(done, total)
+            });
+            if clips.is_ok() {
+                stage.finish();
+            }
+            clips
+        })
+        .await
+        .context("Highlight writer panicked")?;
+    match written {
+        Ok(clips) => {
+            println!(
+                "[win-audio-capture] Wrote {} highlight clip(s)",
+                clips.len()
+            );
+            Ok(clips)
+        }
+        Err(e) => {
+            eprintln!("[win-audio-capture] Warning: Highlights failed: {:#}", e);
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// `--output-mode split|both`, run as a finalize job
+async fn write_tracks(
+    args: &Args,
+    recording: &Path,
+    unreadable: Option<&str>,
+    finalize: &Arc<Finalize>,
+    slots: &jobs::Slots,
+) -> Result<Vec<tracks::Track>> {
+    if args.output_mode == OutputMode::Stereo {
+        return Ok(Vec::new());
+    }
+    if let Some(state) = unreadable {
+        eprintln!(
+            "[win-audio-capture] Warning: Recording is {}, keeping it instead of split tracks",
+            state
+        );
+        return Ok(Vec::new());
+    }
+    let Some(mut stage) = finalize.stage(Stage::Tracks) else {
+        return Ok(Vec::new());
+    };
+    let (path, format) = (recording.to_path_buf(), args.format);
+    let (notes_channel, mixdown) = (args.notes == NotesMode::Channel, args.mixdown);
+    let written = slots
+        .run(move || {
+            let tracks =
+                tracks::write(&path, format, notes_channel, mixdown, &mut |done, total| {
+                    stage.report(done, total)
+                });
+            if tracks.is_ok() {
+                stage.finish();
+            }
+            tracks
+        })
+        .await
+        .context("Track writer panicked")?;
+    match written {
+        Ok(tracks) => {
+            println!("[win-audio-capture] Wrote {} track(s)", tracks.len());
+            Ok(tracks)
+        }
+        Err(e) => {
+            eprintln!(
+                "[win-audio-capture] Warning: Splitting tracks failed, keeping the recording: {:#}",
+                e
+            );
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Session options announced in the handshake
+fn session_features(args: &Args) -> Vec<String> {
+    [
+        (args.anonymize, "anonymize"),
+        (args.chapters, "chapters"),
+        (args.trim_silence, "trim_silence"),
+        (args.compact_silence.is_some(), "compact_silence"),
+        (args.rolling_minutes.is_some(), "rolling"),
+        (args.dsp_compare.is_some(), "dsp_compare"),
+        (args.preview_speed.is_some(), "preview"),
+        (args.highlights.is_some(), "highlights"),
+        (args.output_mode != OutputMode::Stereo, "split_tracks"),
+        (args.sync_pulse_secs.is_some(), "sync_pulse"),
+        (args.mute_sync == MuteSync::Silence, "mute_silence"),
+        (
+            args.headset_controls == HeadsetControls::Full,
+            "headset_controls",
+        ),
+        (
+            args.polarity_correction == PolarityMode::Auto,
+            "polarity_correction",
+        ),
+        (args.loopback_extra_role.is_some(), "extra_loopback"),
+        (!args.source.is_empty(), "synthetic_source"),
+        (args.wrap_key.is_some(), "sealed"),
+        (args.observer_token.is_some(), "observers"),
+        (args.notes == NotesMode::Channel, "notes_channel"),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, name)| name.to_string())
+    .collect()
+}
+
+/// Get the device's default/supported config instead of forcing 48kHz.
+/// This prevents "configuration not supported" errors on different hardware.
+fn probe_input_config(device: &cpal::Device) -> Result<StreamConfig> {
+    let supported = device
+        .default_input_config()
+        .context("Failed to get default input config from MIC device")?;
+
+    println!(
+        "[win-audio-capture] MIC native config: {:?} @ {} Hz, {} channel(s)",
+        supported.sample_format(),
+        supported.sample_rate().0,
+        supported.channels()
+    );
+
+    Ok(StreamConfig {
+        channels: supported.channels(), // Use native channel count
+        sample_rate: supported.sample_rate(),
+        buffer_size: cpal::BufferSize::Default,
+    })
+}
+
+fn with_overrides(config: StreamConfig, overrides: &DeviceOverride) -> StreamConfig {
+    StreamConfig {
+        sample_rate: overrides
+            .sample_rate
+            .map(cpal::SampleRate)
+            .unwrap_or(config.sample_rate),
+        ..config
+    }
+}
+
+/// The MIC side of a session: a capture device, or a `--source` generator feeding `ring`
+struct OpenedMic {
+    name: String,
+    endpoint_id: Option<String>,
+    _lock: Option<InstanceLock>,
+    _ducking: Option<DuckWatcher>,
+    /// `None` for a synthetic MIC
+    input: Option<MicInput>,
+    /// `None` while another application holds the MIC, and for a synthetic one
+    stream: Option<MicStream>,
+    ring: Consumer<f32>,
+}
+
+impl OpenedMic {
+    fn synthetic(ring: Consumer<f32>) -> Self {
+        Self {
+            name: "synthetic".to_string(),
+            endpoint_id: None,
+            _lock: None,
+            _ducking: None,
+            input: None,
+            stream: None,
+            ring,
+        }
+    }
+}
+
+/// What the MIC callback reports to besides the ring
+struct MicInputSettings {
+    counter: Arc<SourceCounter>,
+    polarity_tx: mpsc::UnboundedSender<PolarityFlip>,
+}
+
+/// Select the MIC device and open its stream (paused)
+fn open_mic(
+    args: &Args,
+    host: &cpal::Host,
+    capture_config: &CaptureConfig,
+    device_cache: &mut DeviceCache,
+    settings: MicInputSettings,
+    duck_tx: &mpsc::UnboundedSender<DuckEvent>,
+) -> Result<OpenedMic> {
+    let selected = match &args.mic_device {
+        Some(id) => pinned_input_device(&args.session, id, host)?,
+        None => select_input_device(&args.session, args.device_strategy, host, device_cache)?,
+    };
+    open_mic_device(
+        args,
+        selected,
+        capture_config,
+        device_cache,
+        settings,
+        duck_tx,
+    )
+}
+
+/// Select the MIC again after a device change and open (and start) it if the pick is no
+/// longer `current`; `None` when it stays
+fn reselect_mic(
+    args: &Args,
+    host: &cpal::Host,
+    capture_config: &CaptureConfig,
+    device_cache: &mut DeviceCache,
+    current: Option<&str>,
+    settings: MicInputSettings,
+    duck_tx: &mpsc::UnboundedSender<DuckEvent>,
+) -> Result<Option<OpenedMic>> {
+    let selected = select_input_device(&args.session, args.device_strategy, host, device_cache)?;
+    if selected.0.name().ok().as_deref() == current {
+        return Ok(None);
+    }
+    let opened = open_mic_device(
+        args,
+        selected,
+        capture_config,
+        device_cache,
+        settings,
+        duck_tx,
+    )?;
+    let (Some(stream), Some(mic)) = (&opened.stream, &opened.input) else {
+        return Err(fail!(
+            Device,
+            "\"{}\" is held by another application",
+            opened.name
+        ));
+    };
+    stream.play().context("Failed to start MIC stream")?;
+    device_cache.record_input(
+        &opened.name,
+        CachedConfig {
+            sample_rate: mic.config.sample_rate.0,
+            channels: mic.config.channels,
+        },
+        events::unix_millis(),
+    );
+    if let Err(e) = device_cache.save(&DeviceCache::default_path()) {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+    }
+    Ok(Some(opened))
+}
+
+/// Open the stream (paused) of the MIC `input_device`, falling back to probing when the
+/// cached config fails
+fn open_mic_device(
+    args: &Args,
+    (input_device, input_endpoint_id): (cpal::Device, Option<String>),
+    capture_config: &CaptureConfig,
+    device_cache: &mut DeviceCache,
+    settings: MicInputSettings,
+    duck_tx: &mpsc::UnboundedSender<DuckEvent>,
+) -> Result<OpenedMic> {
+    let input_device_name = input_device
+        .name()
+        .unwrap_or_else(|_| "Unknown".to_string());
+    println!("[win-audio-capture] MIC device: {}", input_device_name);
+    let device_lock = instance_lock::acquire(&args.session, Scope::Device, &input_device_name)?;
+
+    let overrides = capture_config.for_device(&input_device_name);
+    if overrides != DeviceOverride::default() {
+        println!("[win-audio-capture] MIC overrides: {:?}", overrides);
+        events::emit(
+            "device_config",
+            json!({ "session": args.session, "device": input_device_name, "overrides": overrides }),
+        );
+    }
+
+    // Listing the inputs is slow and can only end in a warning, so it doesn't hold up capture
+    if let Some(usual) = device_cache.usual_input().map(|device| device.name.clone()) {
+        let (session, current) = (args.session.clone(), input_device_name.clone());
+        std::thread::spawn(move || warn_if_usual_input_missing(&session, &usual, &current));
+    }
+
+    // Start from the cached last-good config and only probe the driver when there is none
+    // or it stopped working
+    let cached_config = device_cache
+        .input(&input_device_name)
+        .map(|device| device.last_good);
+    let input_config = match cached_config {
+        Some(cached) => {
+            println!(
+                "[win-audio-capture] MIC cached config: {} Hz, {} channel(s)",
+                cached.sample_rate, cached.channels
+            );
+            StreamConfig {
+                channels: cached.channels,
+                sample_rate: cpal::SampleRate(cached.sample_rate),
+                buffer_size: cpal::BufferSize::Default,
+            }
+        }
+        None => probe_input_config(&input_device)?,
+    };
+    let mut mic = MicInput {
+        device: input_device,
+        endpoint_id: input_endpoint_id.clone(),
+        backend: MicBackend::Cpal,
+        config: with_overrides(input_config, &overrides),
+        overrides,
+        counter: settings.counter,
+        polarity: args.polarity_correction,
+        polarity_tx: settings.polarity_tx,
+        target_rate: args.sample_rate,
+        quality: args.resample_quality,
+    };
+
+    // The ducking opt-out has to be in place before the MIC's audio session starts
+    let duck_watcher = watch_ducking(args.ducking, duck_tx, &input_endpoint_id);
+
+    // Build input stream (MIC) - use f32 callback but handle format conversion
+    let opened = match mic.open() {
+        Err(e) if cached_config.is_some() && !mic_lock::is_exclusive_lock(&e) => {
+            eprintln!(
+                "[win-audio-capture] Cached MIC config failed ({:#}), probing device",
+                e
+            );
+            device_cache.forget_input(&input_device_name);
+            mic.config = with_overrides(probe_input_config(&mic.device)?, &mic.overrides);
+            mic.open()
+        }
+        opened => opened,
+    };
+    let opened = match opened {
+        Err(e) if !mic_lock::is_exclusive_lock(&e) => mic.fall_back(&args.session, e),
+        opened => opened,
+    };
+    // A MIC held exclusively by another application leaves the session loopback-only
+    // until it is free, with silence on the MIC channel
+    let (input_stream, mic_rx) = match opened {
+        Ok((stream, ring)) => (Some(stream), ring),
+        Err(e) if mic_lock::is_exclusive_lock(&e) => {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+            (None, engine::source_queue().1)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(OpenedMic {
+        name: input_device_name,
+        endpoint_id: input_endpoint_id,
+        _lock: Some(device_lock),
+        _ducking: duck_watcher,
+        input: Some(mic),
+        stream: input_stream,
+        ring: mic_rx,
+    })
+}
+
+/// Settle the rate the session records at against the rates the MIC and the loopback
+/// endpoint run at (`--rate-policy`), and report it when a source is slower than the
+/// request. Generated `--source` signals run at the request, so it stands with them, and a
+/// process loopback delivers whatever rate it is asked for.
+fn negotiate_rate(args: &Args, mic: Option<&MicInput>) -> Result<Negotiated> {
+    let synthetic = [synth::Target::Mic, synth::Target::Loopback]
+        .into_iter()
+        .any(|target| synth::signal_for(&args.source, target).is_some());
+    let mut sources = Vec::new();
+    if !synthetic {
+        if let Some(mic) = mic {
+            sources.push(SourceRate {
+                source: "mic",
+                rate: mic.config.sample_rate.0,
+            });
+        }
+        let process = args.loopback_pid.is_some()
+            || args.loopback_process_name.is_some()
+            || args.loopback_meeting_app;
+        let loopback_rate = match &args.loopback_device {
+            _ if process => Ok(None),
+            Some(id) => devices::list().and_then(|endpoints| {
+                Ok(devices::find(&endpoints, Flow::Render, id)?
+                    .format
+                    .map(|format| format.sample_rate))
+            }),
+            None => wasapi_loopback::endpoint_rate(args.loopback_role).map(Some),
+        };
+        match loopback_rate {
+            Ok(Some(rate)) => sources.push(SourceRate {
+                source: "loopback",
+                rate,
+            }),
+            Ok(None) => {}
+            Err(e) => eprintln!(
+                "[win-audio-capture] Warning: Could not read the loopback endpoint's rate: {:#}",
+                e
+            ),
+        }
+    }
+
+    let negotiated = rate_negotiation::negotiate(
+        args.sample_rate,
+        sources,
+        args.rate_policy,
+        args.format.sample_rates(),
+    )?;
+    let Some(slowest) = negotiated.limited_by else {
+        return Ok(negotiated);
+    };
+    if negotiated.rate == negotiated.requested {
+        eprintln!(
+            "[win-audio-capture] Warning: The {} runs at {} Hz; upsampled to {} Hz it carries nothing above {} Hz (--rate-policy downgrade records at its rate)",
+            slowest.source,
+            slowest.rate,
+            negotiated.rate,
+            slowest.rate / 2
+        );
+    } else {
+        println!(
+            "[win-audio-capture] Recording at {} Hz instead of {} Hz, the {} runs at {} Hz",
+            negotiated.rate, negotiated.requested, slowest.source, slowest.rate
+        );
+    }
+    events::emit(
+        "rate_negotiated",
+        json!({ "session": args.session, "negotiation": negotiated }),
+    );
+    Ok(negotiated)
+}
+
+/// PID of `--loopback-pid`, `--loopback-process-name` or `--loopback-meeting-app`; a name
+/// that isn't running or no meeting in progress falls back to the endpoint loopback
+fn loopback_process(args: &Args) -> Option<u32> {
+    if args.loopback_pid.is_some() {
+        return args.loopback_pid;
+    }
+    if args.loopback_meeting_app {
+        let meeting = conferencing::current();
+        match &meeting {
+            Some(meeting) => println!(
+                "[win-audio-capture] Recording what {:?} plays in the meeting (PID {})",
+                meeting.app, meeting.pid
+            ),
+            None => {
+                eprintln!(
+                    "[win-audio-capture] Warning: No conferencing app is in a meeting, recording the whole system mix"
+                );
+                events::emit(
+                    "loopback_fallback",
+                    json!({ "meeting_app": true, "error": "no meeting in progress" }),
+                );
+            }
+        }
+        return meeting.map(|meeting| meeting.pid);
+    }
+    let name = args.loopback_process_name.as_deref()?;
+    let pid = process_loopback::processes()
+        .map(|processes| process_loopback::tree_root(&processes, name))
+        .unwrap_or_else(|e| {
+            eprintln!("[win-audio-capture] Warning: {:#}", e);
+            None
+        });
+    if pid.is_none() {
+        eprintln!(
+            "[win-audio-capture] Warning: {} is not running, recording the whole system mix",
+            name
+        );
+        events::emit(
+            "loopback_fallback",
+            json!({ "process_name": name, "error": "process not running" }),
+        );
+    }
+    pid
+}
+
+/// The `--mic-device` endpoint
+fn pinned_input_device(
+    session: &str,
+    id: &str,
+    host: &cpal::Host,
+) -> Result<(cpal::Device, Option<String>)> {
+    let endpoints = devices::list()?;
+    let endpoint = devices::find(&endpoints, Flow::Capture, id)?;
+    let device = host
+        .input_devices()?
+        .find(|device| device.name().ok().as_deref() == Some(&endpoint.name))
+        .ok_or_else(|| {
+            fail!(
+                Device,
+                "Pinned MIC \"{}\" is not an input device",
+                endpoint.name
+            )
+        })?;
+    println!("[win-audio-capture] Pinned MIC \"{}\"", endpoint.name);
+    events::emit(
+        "device_selected",
+        json!({ "session": session, "pinned": endpoint }),
+    );
+    Ok((device, Some(endpoint.id.clone())))
+}
+
+/// Pick the MIC with `strategy` and report the decision; the Console default (with no
+/// endpoint ID) when the endpoints can't be listed or the pick isn't an input cpal knows
+fn select_input_device(
+    session: &str,
+    strategy: DeviceStrategy,
+    host: &cpal::Host,
+    cache: &DeviceCache,
+) -> Result<(cpal::Device, Option<String>)> {
+    let last_used = cache.last_input().map(|device| device.name.as_str());
+    let candidates = device_select::candidates(last_used).unwrap_or_else(|e| {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+        Vec::new()
+    });
+    if let Some(decision) = device_select::decide(strategy, &candidates) {
+        let device = host.input_devices().ok().and_then(|mut devices| {
+            devices.find(|device| device.name().ok().as_deref() == Some(&decision.name))
+        });
+        match device {
+            Some(device) => {
+                println!(
+                    "[win-audio-capture] Selected MIC \"{}\" ({:?}, score {}, confidence {:.2})",
+                    decision.name, strategy, decision.score, decision.confidence
+                );
+                events::emit(
+                    "device_selected",
+                    json!({ "session": session, "decision": decision }),
+                );
+                return Ok((device, decision.id));
+            }
+            None => eprintln!(
+                "[win-audio-capture] Warning: Selected MIC \"{}\" is not an input device, using the default",
+                decision.name
+            ),
+        }
+    }
+    let device = host
+        .default_input_device()
+        .ok_or_else(|| fail!(Device, "No default input device found"))?;
+    Ok((device, None))
+}
+
+/// Warn when `current` isn't the MIC usually recorded with (`usual`, its friendly name in
+/// the cache) and that one isn't connected either
+fn warn_if_usual_input_missing(session: &str, usual: &str, current: &str) {
+    if usual == current {
+        return;
+    }
+
+    let connected = cpal::default_host()
+        .input_devices()
+        .map(|mut devices| devices.any(|device| device.name().ok().as_deref() == Some(usual)))
+        .unwrap_or(false);
+    if !connected {
+        println!(
+            "[win-audio-capture] Warning: Your usual MIC \"{}\" is missing, recording from \"{}\"",
+            usual, current
+        );
+        events::emit(
+            "usual_device_missing",
+            json!({ "session": session, "usual": usual, "using": current }),
+        );
+    }
+}
+
+/// Per-packet MIC processing, shared by the cpal and WASAPI backends
+type MicProcessor = Box<dyn FnMut(&[f32], Option<Duration>) + Send>;
+
+/// MIC device and the settings needed to (re)open its stream
+struct MicInput {
+    device: cpal::Device,
+    /// Endpoint the WASAPI fallback opens; the default capture endpoint when unknown
+    endpoint_id: Option<String>,
+    backend: MicBackend,
+    config: StreamConfig,
+    overrides: DeviceOverride,
+    counter: Arc<SourceCounter>,
+    polarity: PolarityMode,
+    polarity_tx: mpsc::UnboundedSender<PolarityFlip>,
+    /// Session rate; the MIC is resampled to it when the device runs at another
+    target_rate: u32,
+    quality: ResampleQuality,
+}
+
+impl MicInput {
+    /// Build a paused input stream feeding a fresh ring, through the backend in use
+    fn open(&self) -> Result<(MicStream, Consumer<f32>)> {
+        match self.backend {
+            MicBackend::Cpal => self.open_cpal(),
+            MicBackend::Wasapi => self.open_wasapi(),
+        }
+    }
+
+    fn open_cpal(&self) -> Result<(MicStream, Consumer<f32>)> {
+        let (mut process, mic_rx) =
+            self.processor(self.config.channels, self.config.sample_rate.0)?;
+        // Errors leave the callback on a ring, to be rate-limited on the runtime
+        let (mut error_tx, error_rx) = RingBuffer::new(STREAM_ERROR_QUEUE);
+        forward_stream_errors(error_rx);
+        // Some virtual drivers panic inside cpal; that fails the open like any other error
+        let built = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.device.build_input_stream(
+                &self.config,
+                move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                    let timestamp = info.timestamp();
+                    process(data, timestamp.callback.duration_since(&timestamp.capture));
+                },
+                move |err| {
+                    let _ = error_tx.push(err);
+                },
+                None,
+            )
+        }));
+        let stream = match built {
+            Ok(stream) => stream.context("Failed to build MIC input stream")?,
+            Err(_) => return Err(fail!(Device, "cpal panicked building the MIC input stream")),
+        };
+        self.opened(self.config.channels, self.config.sample_rate.0);
+        Ok((MicStream::Cpal(stream), mic_rx))
+    }
+
+    fn open_wasapi(&self) -> Result<(MicStream, Consumer<f32>)> {
+        let format = wasapi_mic::mix_format(self.endpoint_id.as_deref())?;
+        let (process, mic_rx) = self.processor(format.channels, format.sample_rate)?;
+        let stream = WasapiMicStream::open(self.endpoint_id.clone(), format, process)?;
+        self.opened(format.channels, format.sample_rate);
+        Ok((MicStream::Wasapi(stream), mic_rx))
+    }
+
+    /// Switch to the raw WASAPI backend after cpal failed with `error`, and open it
+    fn fall_back(
+        &mut self,
+        session: &str,
+        error: anyhow::Error,
+    ) -> Result<(MicStream, Consumer<f32>)> {
+        eprintln!(
+            "[win-audio-capture] Warning: cpal couldn't open the MIC ({:#}), using WASAPI directly",
+            error
+        );
+        self.backend = MicBackend::Wasapi;
+        let fallback = || format!("WASAPI fallback after cpal failed ({:#})", error);
+        let format = wasapi_mic::mix_format(self.endpoint_id.as_deref()).with_context(fallback)?;
+        self.config.channels = format.channels;
+        self.config.sample_rate = cpal::SampleRate(format.sample_rate);
+        let opened = self.open_wasapi().with_context(fallback)?;
+        events::emit(
+            "mic_backend",
+            json!({
+                "session": session,
+                "backend": self.backend,
+                "error": format!("{:#}", error),
+            }),
+        );
+        Ok(opened)
+    }
+
+    fn opened(&self, channels: u16, sample_rate: u32) {
+        etw::write(
+            etw::LEVEL_INFO,
+            etw::KEYWORD_DEVICE,
+            "device_open",
+            &json!({
+                "source": "mic",
+                "backend": self.backend,
+                "channels": channels,
+                "sample_rate": sample_rate,
+            }),
+        );
+    }
+
+    /// The per-packet MIC processing both backends run: interleaved frames of `channels`
+    /// at `native_rate` (and their age, when known) in, mono at the session rate into the
+    /// returned ring
+    fn processor(&self, channels: u16, native_rate: u32) -> Result<(MicProcessor, Consumer<f32>)> {
+        let (mut mic_tx, mic_rx) = engine::source_queue();
+        let counter = self.counter.clone();
+        let num_channels = channels as usize;
+        let mixed_channels = self.overrides.channels_to_mix(channels)?;
+        let scale = self.overrides.gain() / mixed_channels.len() as f32;
+        self.counter.set_sample_rate(native_rate);
+        // Room for callbacks of up to a second, so resampling doesn't allocate in them
+        let mut mono = Vec::with_capacity(native_rate as usize);
+        let mut resampled = Vec::new();
+        let mut resampler = (native_rate != self.target_rate).then(|| {
+            println!(
+                "[win-audio-capture] Resampling MIC {} -> {} Hz ({:?})",
+                native_rate, self.target_rate, self.quality
+            );
+            let mut resampler = Resampler::new(native_rate, self.target_rate, self.quality);
+            resampled.reserve(resampler.reserve(mono.capacity()));
+            resampler
+        });
+        // Detection starts over with every stream, so a different device is judged afresh
+        let mut polarity = (self.polarity == PolarityMode::Auto)
+            .then(|| PolarityDetector::new(&mixed_channels, native_rate));
+        // Flips leave the callback on a wait-free ring and are forwarded from the runtime
+        let (mut flip_tx, flip_rx) = RingBuffer::new(POLARITY_FLIP_QUEUE);
+        if polarity.is_some() {
+            forward_polarity_flips(flip_rx, self.polarity_tx.clone());
+        }
+        let mut frame = vec![0.0f32; mixed_channels.len()];
+        let process: MicProcessor = Box::new(move |data: &[f32], age: Option<Duration>| {
+            // Only allocates on the first callback, before the section starts
+            trace::register_thread();
+            let _section = rt::enter();
+            let _span = trace::span("mic_callback");
+            if let Some(age) = age {
+                counter.record_latency(age);
+            }
+            // Average the mapped channels to mono
+            for chunk in data.chunks_exact(num_channels) {
+                for (value, &channel) in frame.iter_mut().zip(&mixed_channels) {
+                    *value = chunk[channel];
+                }
+                let sum: f32 = match &mut polarity {
+                    Some(detector) => {
+                        detector.observe(&frame, |flip| {
+                            let _ = flip_tx.push(flip);
+                        });
+                        frame.iter().zip(detector.signs()).map(|(v, s)| v * s).sum()
+                    }
+                    None => frame.iter().sum(),
+                };
+                if resampler.is_some() {
+                    mono.push(sum * scale);
+                } else {
+                    let _ = mic_tx.push(sum * scale);
+                }
+            }
+            if let Some(resampler) = &mut resampler {
+                resampled.clear();
+                resampler.process(&mono, &mut resampled);
+                mono.clear();
+                for &sample in &resampled {
+                    let _ = mic_tx.push(sample);
+                }
+            }
+            counter.add((data.len() / num_channels) as u64);
+        });
+        Ok((process, mic_rx))
+    }
+}
+
+/// The MIC's stream, through cpal or the raw WASAPI fallback
+enum MicStream {
+    Cpal(cpal::Stream),
+    Wasapi(WasapiMicStream),
+}
+
+impl MicStream {
+    fn play(&self) -> Result<()> {
+        match self {
+            MicStream::Cpal(stream) => Ok(stream.play()?),
+            MicStream::Wasapi(stream) => stream.play(),
+        }
+    }
+}
+
+/// Hand polarity flips from a MIC callback's ring to the session until the stream is gone
+fn forward_polarity_flips(
+    mut flips: Consumer<PolarityFlip>,
+    tx: mpsc::UnboundedSender<PolarityFlip>,
+) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(POLARITY_FLIP_POLL);
+        loop {
+            tick.tick().await;
+            while let Ok(flip) = flips.pop() {
+                let _ = tx.send(flip);
+            }
+            if flips.is_abandoned() {
+                break;
+            }
+        }
+    });
+}
+
+/// Hand MIC stream errors from a callback's ring to the warning rate limiter until the
+/// stream is gone
+fn forward_stream_errors(mut errors: Consumer<cpal::StreamError>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(POLARITY_FLIP_POLL);
+        loop {
+            tick.tick().await;
+            while let Ok(err) = errors.pop() {
+                warnings::warn("mic_stream", &format!("MIC stream error: {}", err));
+            }
+            if errors.is_abandoned() {
+                break;
+            }
+        }
+    });
+}
+
+/// Apply `change` to the session's upload manifest. Failures only cost resumability, so
+/// they are logged rather than ending the capture.
+fn update_upload_manifest(session: &str, change: impl FnOnce(&mut UploadManifest) -> Result<()>) {
+    let dir = upload_manifest::manifest_dir();
+    let result = UploadManifest::load(&dir, session).and_then(|mut manifest| {
+        change(&mut manifest)?;
+        manifest.save(&dir)
+    });
+    if let Err(e) = result {
+        eprintln!("[win-audio-capture] Warning: Upload manifest: {:#}", e);
+    }
+}
+
+/// Retry delivering spooled recordings for as long as the process runs, reporting the
+/// queue whenever it isn't empty
+fn spawn_spool_drainer(rate: u64) {
+    let spawned = std::thread::Builder::new()
+        .name("spool-drain".to_string())
+        .spawn(move || {
+            let dir = spool::spool_dir();
+            loop {
+                match spool::depth(&dir) {
+                    Ok(depth) if depth.jobs > 0 => match spool::drain(&dir, rate) {
+                        Ok(Some(stats)) => events::emit("spool_drain", json!(stats)),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("[win-audio-capture] Warning: Spool drain: {:#}", e),
+                    },
+                    Ok(_) => {}
+                    Err(e) => eprintln!("[win-audio-capture] Warning: Spool: {:#}", e),
+                }
+                std::thread::sleep(spool::DRAIN_RETRY_INTERVAL);
+            }
+        });
+    if let Err(e) = spawned {
+        eprintln!(
+            "[win-audio-capture] Warning: Failed to start spool drain: {}",
+            e
+        );
+    }
+}
+
+/// Start watching the MIC endpoint's mute state unless `--mute-sync off`
+fn watch_endpoint_mute(
+    sync: MuteSync,
+    muted: &mpsc::UnboundedSender<bool>,
+    endpoint_id: &Option<String>,
+) -> Option<MuteWatcher> {
+    if sync == MuteSync::Off {
+        return None;
+    }
+    endpoint_mute::watch(muted.clone(), endpoint_id.clone())
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok()
+}
+
+/// Apply `--ducking` and start watching for duck notifications unless it is `off`
+fn watch_ducking(
+    mode: DuckingMode,
+    events: &mpsc::UnboundedSender<DuckEvent>,
+    mic_endpoint: &Option<String>,
+) -> Option<DuckWatcher> {
+    if mode == DuckingMode::Off {
+        return None;
+    }
+    ducking::watch(mode, mic_endpoint.clone(), events.clone())
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok()
+}
+
+/// The option that rules out `rotate`: the steps that rewrite or move the finished
+/// recording only know about one file
+fn rotation_blocker(args: &Args, staged: bool) -> Option<&'static str> {
+    [
+        (args.trim_silence, "--trim-silence"),
+        (args.compact_silence.is_some(), "--compact-silence"),
+        (args.rolling_minutes.is_some(), "--rolling-minutes"),
+        (args.chapters, "--chapters"),
+        (args.sync_pulse_secs.is_some(), "--sync-pulse-secs"),
+        (args.auto_redact, "--auto-redact"),
+        (args.wrap_key.is_some(), "--wrap-key"),
+        (args.output_mode != OutputMode::Stereo, "--output-mode"),
+        (staged, "output staged off slow storage"),
+    ]
+    .into_iter()
+    .find_map(|(set, option)| set.then_some(option))
+}
+
+/// The option that keeps `reconfigure` from adding a sink: the added file would be
+/// written unsealed, or hold audio the recording leaves out
+fn add_sink_blocker(args: &Args) -> Option<&'static str> {
+    [
+        (args.wrap_key.is_some(), "--wrap-key"),
+        (args.rolling_minutes.is_some(), "--rolling-minutes"),
+    ]
+    .into_iter()
+    .find_map(|(set, option)| set.then_some(option))
+}
+
+/// Log and emit how long it took from launch to the first mixed block
+fn report_startup(session: &str, launched: Option<Instant>) {
+    let Some(launched) = launched else {
+        return;
+    };
+    let startup = launched.elapsed();
+    let startup_ms = startup.as_millis() as u64;
+    if startup > STARTUP_BUDGET {
+        eprintln!(
+            "[win-audio-capture] Warning: First audio took {} ms, over the {} ms budget",
+            startup_ms,
+            STARTUP_BUDGET.as_millis()
+        );
+    } else {
+        println!(
+            "[win-audio-capture] First audio {} ms after launch",
+            startup_ms
+        );
+    }
+    let features: Vec<&str> = [
+        ("otlp", cfg!(feature = "otlp")),
+        ("self-update", cfg!(feature = "self-update")),
+        ("codecs", cfg!(feature = "codecs")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    events::emit(
+        "startup",
+        json!({
+            "session": session,
+            "first_sample_ms": startup_ms,
+            "budget_ms": STARTUP_BUDGET.as_millis() as u64,
+            "features": features,
+            "arch": std::env::consts::ARCH,
+        }),
+    );
+}
+
+/// First two channels of an interleaved block with `channels` channels
+fn stereo_of(block: &[i16], channels: usize) -> AudioBlock {
+    block
+        .chunks_exact(channels)
+        .flat_map(|frame| [frame[0], frame[1]])
+        .collect()
+}
+
+/// Silence trimming the sink writer applies once the recording is finalized
+struct TrimJob {
+    format: sink::OutputFormat,
+    path: PathBuf,
+    spec: SinkSpec,
+    tracker: SilenceTracker,
+    /// Set by a `panic_delete`: the rewrite would leave the untrimmed data unwiped
+    discarded: Arc<AtomicBool>,
+    finalize: Arc<Finalize>,
+}
+
+/// What the sink writer leaves behind
+struct Written {
+    summary: SinkSummary,
+    /// Pauses left out of the file
+    elisions: Vec<Elision>,
+    trim: Option<Trim>,
+    /// False when a `--rolling-minutes` buffer was never committed and nothing was recorded
+    committed: bool,
+}
+
+/// `--rolling-minutes`: the buffer the sink writer holds blocks in until a commit request,
+/// and where it reports what went into the recording
+struct Rolling {
+    buffer: RollingBuffer,
+    requests: std::sync::mpsc::Receiver<()>,
+    done: mpsc::UnboundedSender<Committed>,
+    pressure: Arc<Pressure>,
+}
+
+/// A file for the sink writer to carry on in, once `at` frames went into the current one
+struct Rotation {
+    at: u64,
+    path: PathBuf,
+}
+
+/// Rotation requests for the sink writer, and where it reports the file it closed
+struct Rotations {
+    format: sink::OutputFormat,
+    encoding: sink::Encoding,
+    spec: SinkSpec,
+    requests: std::sync::mpsc::Receiver<Rotation>,
+    done: mpsc::UnboundedSender<Result<SinkSummary>>,
+}
+
+impl Rotations {
+    /// Switch `recording` to the requested file; the new one is created first, so a failure
+    /// leaves the recording where it was
+    fn rotate(
+        &self,
+        recording: &mut Box<dyn RecordingSink>,
+        rotation: &Rotation,
+    ) -> Result<SinkSummary> {
+        let next = sink::create_with(self.format, &rotation.path, self.spec, self.encoding)
+            .with_context(|| format!("Failed to create {:?}", rotation.path))?;
+        std::mem::replace(recording, next).finalize()
+    }
+}
+
+/// Write blocks to the sink on a blocking worker until the channel closes, holding them in
+/// `rolling` until it is committed, leaving long pauses out with `compactor` and switching
+/// files on the requests of `rotations`, then finalize and, with `trim`, cut the silence
+/// around the audio
+fn spawn_sink_writer(
+    mut recording: Box<dyn RecordingSink>,
+    mut blocks: mpsc::Receiver<AudioBlock>,
+    mut compactor: Option<SilenceCompactor>,
+    mut trim: Option<TrimJob>,
+    rotations: Option<Rotations>,
+    mut rolling: Option<Rolling>,
+) -> JoinHandle<Result<Written>> {
+    tokio::task::spawn_blocking(move || {
+        let mut compacted = Vec::new();
+        let mut rolled_out = None;
+        let write = |recording: &mut Box<dyn RecordingSink>,
+                     trim: &mut Option<TrimJob>,
+                     samples: &[i16]|
+         -> Result<()> {
+            if let Some(job) = trim {
+                job.tracker.observe(samples);
+            }
+            let _span = trace::span("sink_write");
+            recording.write_samples(samples)
+        };
+        let mut frames = 0u64;
+        let mut rotation = None;
+        while let Some(block) = blocks.blocking_recv() {
+            if let Some(mut held) = rolling.take() {
+                if held.requests.try_recv().is_err() {
+                    if held.buffer.push(block) && held.buffer.capped() {
+                        held.pressure.hit(Pool::Rolling);
+                    }
+                    rolling = Some(held);
+                    continue;
+                }
+                let _ = held.done.send(held.buffer.committed());
+                rolled_out = held.buffer.elision();
+                for block in held.buffer.into_blocks() {
+                    write(&mut recording, &mut trim, &block)?;
+                }
+            }
+            if let Some(rotations) = &rotations {
+                rotation = rotation.or_else(|| rotations.requests.try_recv().ok());
+                if let Some(due) = rotation.take_if(|r: &mut Rotation| frames >= r.at) {
+                    let _ = rotations.done.send(rotations.rotate(&mut recording, &due));
+                }
+                frames += (block.len() / rotations.spec.channels as usize) as u64;
+            }
+            match &mut compactor {
+                Some(compactor) => {
+                    compacted.clear();
+                    compactor.compact(&block, &mut compacted);
+                    write(&mut recording, &mut trim, &compacted)?;
+                }
+                None => write(&mut recording, &mut trim, &block)?,
+            }
+        }
+        if let Some(compactor) = &mut compactor {
+            compacted.clear();
+            compactor.finish(&mut compacted);
+            write(&mut recording, &mut trim, &compacted)?;
+        }
+        let summary = {
+            let _span = trace::span("sink_finalize");
+            recording.finalize()?
+        };
+        let committed = rolling.is_none();
+        let elisions = match compactor {
+            Some(compactor) => compactor.elisions().to_vec(),
+            None => rolled_out.into_iter().collect(),
+        };
+
+        let Some(job) = trim.filter(|job| !job.discarded.load(Ordering::SeqCst)) else {
+            return Ok(Written {
+                summary,
+                elisions,
+                trim: None,
+                committed,
+            });
+        };
+        let padding_frames = job.spec.sample_rate as u64 * trim::TRIM_PADDING_MS / 1000;
+        let Some((cut, mut stage)) = job
+            .tracker
+            .trim(padding_frames)
+            .and_then(|cut| Some((cut, job.finalize.stage(Stage::Trim)?)))
+        else {
+            return Ok(Written {
+                summary,
+                elisions,
+                trim: None,
+                committed,
+            });
+        };
+        // The untrimmed recording is still complete, so a failed rewrite only loses the trim
+        let trimmed = sink::rewrite_range(
+            job.format,
+            &job.path,
+            job.spec,
+            cut.leading_frames,
+            cut.kept_frames(),
+            &mut |done, total| stage.report(done, total),
+        );
+        match trimmed {
+            Ok(trimmed) => {
+                stage.finish();
+                Ok(Written {
+                    summary: trimmed,
+                    elisions,
+                    trim: Some(cut),
+                    committed,
+                })
+            }
+            Err(e) => {
+                eprintln!(
+                    "[win-audio-capture] Warning: Silence trimming failed: {:#}",
+                    e
+                );
+                Ok(Written {
+                    summary,
+                    elisions,
+                    trim: None,
+                    committed,
+                })
+            }
+        }
+    })
+}
+
+fn join_thread<T>(handle: std::thread::JoinHandle<T>, name: &str) -> Result<T> {
+    handle
+        .join()
+        .map_err(|e| anyhow!("{} thread panicked: {:?}", name, e))
+}
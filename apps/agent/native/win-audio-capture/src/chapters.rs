@@ -0,0 +1,252 @@
+//! Coarse chapter markers
+//! With `--chapters` the session splits the call into chapters without any transcription:
+//! a new chapter starts where speech resumes after a long pause, and where the conversation
+//! changes between one side holding the floor (a demo, a pitch) and a back-and-forth
+//! (turn-taking density). Chapters are written to the timeline and as labelled cue points
+//! in the recording, so players and `extract` can jump to them.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Resolution of the analysis
+const CELL_MS: u64 = 100;
+/// Silence on both channels that ends a chapter
+const PAUSE_MS: u64 = 4_000;
+/// Chapters shorter than this are not split further
+const MIN_CHAPTER_MS: u64 = 60_000;
+/// Window the conversational mode is judged over
+const MODE_WINDOW_MS: u64 = 60_000;
+/// Speaker changes per window from which the conversation counts as a dialogue
+const DIALOGUE_TURNS: usize = 6;
+/// RMS (i16 units, about -40 dBFS) above which a channel counts as speaking
+pub(crate) const ACTIVE_RMS: f64 = 330.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speaker {
+    Rep,
+    Prospect,
+}
+
+/// How the conversation is going over the mode window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "mode", content = "speaker")]
+pub enum Mode {
+    Monologue(Speaker),
+    Dialogue,
+}
+
+/// Why a chapter starts where it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChapterReason {
+    Start,
+    Pause,
+    ModeChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Chapter {
+    /// Session sample position the chapter starts at
+    pub sample_position: u64,
+    pub reason: ChapterReason,
+    /// Mode of the conversation when the chapter started, once known
+    pub mode: Option<Mode>,
+}
+
+/// Follows the stereo mix (left = rep, right = prospect) and collects chapters
+pub struct ChapterDetector {
+    cell_frames: u64,
+    frames_in_cell: u64,
+    energy: [f64; 2],
+    position: u64,
+    silent_cells: u64,
+    /// Who spoke alone in each cell of the mode window
+    window: VecDeque<Option<Speaker>>,
+    window_cells: usize,
+    mode: Option<Mode>,
+    chapters: Vec<Chapter>,
+}
+
+impl ChapterDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        let cell_frames = (sample_rate as u64 * CELL_MS / 1000).max(1);
+        Self {
+            cell_frames,
+            frames_in_cell: 0,
+            energy: [0.0; 2],
+            position: 0,
+            silent_cells: 0,
+            window: VecDeque::new(),
+            window_cells: (MODE_WINDOW_MS / CELL_MS) as usize,
+            mode: None,
+            chapters: vec![Chapter {
+                sample_position: 0,
+                reason: ChapterReason::Start,
+                mode: None,
+            }],
+        }
+    }
+
+    /// Account for interleaved stereo samples, in recording order
+    pub fn observe(&mut self, block: &[i16]) {
+        for pair in block.chunks_exact(2) {
+            for (energy, &sample) in self.energy.iter_mut().zip(pair) {
+                *energy += sample as f64 * sample as f64;
+            }
+            self.position += 1;
+            self.frames_in_cell += 1;
+            if self.frames_in_cell == self.cell_frames {
+                self.end_cell();
+            }
+        }
+    }
+
+    /// Chapters found so far, the first one starting at the beginning
+    pub fn chapters(&self) -> &[Chapter] {
+        &self.chapters
+    }
+
+    fn end_cell(&mut self) {
+        let n = self.frames_in_cell as f64;
+        let [rep, prospect] = self.energy.map(|energy| (energy / n).sqrt() > ACTIVE_RMS);
+        let cell_start = self.position - self.frames_in_cell;
+        self.frames_in_cell = 0;
+        self.energy = [0.0; 2];
+
+        if !rep && !prospect {
+            self.silent_cells += 1;
+        } else {
+            if self.silent_cells * CELL_MS >= PAUSE_MS {
+                self.split(cell_start, ChapterReason::Pause);
+            }
+            self.silent_cells = 0;
+        }
+
+        let alone = match (rep, prospect) {
+            (true, false) => Some(Speaker::Rep),
+            (false, true) => Some(Speaker::Prospect),
+            _ => None,
+        };
+        self.window.push_back(alone);
+        if self.window.len() > self.window_cells {
+            self.window.pop_front();
+        }
+        if self.window.len() == self.window_cells {
+            let mode = self.judge_mode();
+            match self.mode {
+                None => {
+                    self.mode = Some(mode);
+                    self.chapters.iter_mut().for_each(|c| {
+                        c.mode.get_or_insert(mode);
+                    });
+                }
+                Some(current) if current != mode => {
+                    self.mode = Some(mode);
+                    // The change happened somewhere inside the window; its middle is the
+                    // best guess without looking back
+                    let half = self.cell_frames * self.window_cells as u64 / 2;
+                    self.split(
+                        self.position.saturating_sub(half),
+                        ChapterReason::ModeChange,
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn judge_mode(&self) -> Mode {
+        let speakers = self.window.iter().flatten();
+        let mut turns = 0;
+        let mut last = None;
+        let mut rep_cells = 0;
+        let mut prospect_cells = 0;
+        for &speaker in speakers {
+            if last.is_some_and(|last| last != speaker) {
+                turns += 1;
+            }
+            last = Some(speaker);
+            match speaker {
+                Speaker::Rep => rep_cells += 1,
+                Speaker::Prospect => prospect_cells += 1,
+            }
+        }
+        if turns >= DIALOGUE_TURNS {
+            Mode::Dialogue
+        } else if rep_cells >= prospect_cells {
+            Mode::Monologue(Speaker::Rep)
+        } else {
+            Mode::Monologue(Speaker::Prospect)
+        }
+    }
+
+    fn split(&mut self, sample_position: u64, reason: ChapterReason) {
+        let min_frames = self.cell_frames * (MIN_CHAPTER_MS / CELL_MS);
+        let last = self.chapters.last().map_or(0, |c| c.sample_position);
+        if sample_position >= last + min_frames {
+            self.chapters.push(Chapter {
+                sample_position,
+                reason,
+                mode: self.mode,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1_000;
+
+    /// `secs` seconds of audio with the rep and/or the prospect talking
+    fn audio(detector: &mut ChapterDetector, secs: u64, rep: bool, prospect: bool) {
+        let level = |on: bool| if on { 3_000 } else { 0 };
+        let block: Vec<i16> = (0..RATE as usize)
+            .flat_map(|i| {
+                let sign = if i % 2 == 0 { 1 } else { -1 };
+                [sign * level(rep), sign * level(prospect)]
+            })
+            .collect();
+        for _ in 0..secs {
+            detector.observe(&block);
+        }
+    }
+
+    #[test]
+    fn long_pauses_start_chapters_but_not_too_often() {
+        let mut detector = ChapterDetector::new(RATE);
+        audio(&mut detector, 90, true, false);
+        audio(&mut detector, 5, false, false);
+        audio(&mut detector, 30, true, false);
+        // Too soon after the previous chapter
+        audio(&mut detector, 5, false, false);
+        audio(&mut detector, 30, true, false);
+
+        let chapters = detector.chapters();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].reason, ChapterReason::Pause);
+        assert_eq!(chapters[1].sample_position, 95 * RATE as u64);
+        assert_eq!(chapters[1].mode, Some(Mode::Monologue(Speaker::Rep)));
+    }
+
+    #[test]
+    fn a_demo_turning_into_a_discussion_is_a_new_chapter() {
+        let mut detector = ChapterDetector::new(RATE);
+        audio(&mut detector, 180, true, false);
+        for _ in 0..60 {
+            audio(&mut detector, 2, true, false);
+            audio(&mut detector, 2, false, true);
+        }
+
+        let chapters = detector.chapters();
+        assert_eq!(chapters[0].mode, Some(Mode::Monologue(Speaker::Rep)));
+        assert_eq!(chapters.len(), 2, "{:?}", chapters);
+        assert_eq!(chapters[1].reason, ChapterReason::ModeChange);
+        assert_eq!(chapters[1].mode, Some(Mode::Dialogue));
+        // Within a mode window of where the discussion started
+        let start = 180 * RATE as u64;
+        assert!(chapters[1].sample_position.abs_diff(start) <= 60 * RATE as u64);
+    }
+}
@@ -0,0 +1,280 @@
+//! External master clock
+//! With `--clock external` the mixer's timeline follows a clock the agent reports over the
+//! control channel, e.g. the conferencing SDK's media clock:
+//! `{"cmd":"clock","media_time_ms":5231.5,"at_ms":<unix ms it was read>}`. The rate of the
+//! reports against the local clock is fitted over the last ones, and their phase error is
+//! slewed out over about ten seconds, so the timeline never jumps; the device streams are
+//! then resampled to it by the mixer's drift correction like to the local clock. A report
+//! more than a second off (the SDK restarted its clock) re-anchors instead. Without
+//! reports the timeline holds the last rate.
+
+use crate::mixer::Clock;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// `--clock`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockSource {
+    System,
+    External,
+}
+
+/// Furthest the timeline is run from the local clock's rate
+pub const MAX_PPM: f64 = 1_000.0;
+/// Time over which a phase error is slewed out
+const CONVERGE_SECS: f64 = 10.0;
+/// Reports the rate is fitted over
+const WINDOW: usize = 30;
+/// Local time the reports have to span before their rate is trusted
+const MIN_SPAN_SECS: f64 = 2.0;
+/// Phase error past which the reports are taken to have restarted
+const RESET_ERROR_MS: f64 = 1_000.0;
+
+/// The timeline as a line through an anchor, shared with the mixer thread. Written by the
+/// session loop only; the mixer reads it without locking (a sequence lock).
+pub struct ClockSync {
+    started: Instant,
+    version: AtomicU64,
+    anchor_local_ns: AtomicU64,
+    anchor_timeline_ns: AtomicU64,
+    rate_ppb: AtomicI64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            version: AtomicU64::new(0),
+            anchor_local_ns: AtomicU64::new(0),
+            anchor_timeline_ns: AtomicU64::new(0),
+            rate_ppb: AtomicI64::new(0),
+        }
+    }
+
+    /// Local time since the timeline started
+    pub fn local_elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Timeline time at local time `local`
+    pub fn timeline_at(&self, local: Duration) -> Duration {
+        let (anchor_local, anchor_timeline, rate_ppb) = self.read();
+        let since = (local.as_nanos() as u64).saturating_sub(anchor_local);
+        let scaled = since as i128 * (1_000_000_000 + rate_ppb as i128) / 1_000_000_000;
+        Duration::from_nanos(anchor_timeline + scaled.max(0) as u64)
+    }
+
+    fn read(&self) -> (u64, u64, i64) {
+        loop {
+            let before = self.version.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let line = (
+                self.anchor_local_ns.load(Ordering::Relaxed),
+                self.anchor_timeline_ns.load(Ordering::Relaxed),
+                self.rate_ppb.load(Ordering::Relaxed),
+            );
+            fence(Ordering::Acquire);
+            if self.version.load(Ordering::Relaxed) == before {
+                return line;
+            }
+        }
+    }
+
+    fn publish(&self, anchor_local: Duration, anchor_timeline: Duration, rate_ppm: f64) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+        self.anchor_local_ns
+            .store(anchor_local.as_nanos() as u64, Ordering::Relaxed);
+        self.anchor_timeline_ns
+            .store(anchor_timeline.as_nanos() as u64, Ordering::Relaxed);
+        self.rate_ppb
+            .store((rate_ppm * 1_000.0) as i64, Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// The mixer's clock with `--clock external`
+pub struct ExternalClock(pub Arc<ClockSync>);
+
+impl Clock for ExternalClock {
+    fn elapsed(&self) -> Duration {
+        self.0.timeline_at(self.0.local_elapsed())
+    }
+}
+
+/// What a report did to the timeline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    /// The first report, which only anchors the external clock to the timeline
+    Locked,
+    Tracking {
+        rate_ppm: f64,
+        error_ms: f64,
+    },
+    /// The report was too far off to slew to and the clock was anchored anew
+    Reset {
+        error_ms: f64,
+    },
+}
+
+/// State of the external clock, for the `stopped` event
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ClockStats {
+    pub reports: u64,
+    pub resets: u64,
+    pub rate_ppm: f64,
+    pub error_ms: f64,
+}
+
+/// Turns the agent's clock reports into the timeline the mixer follows
+pub struct ClockTracker {
+    sync: Arc<ClockSync>,
+    /// External time at timeline zero
+    origin_ms: Option<f64>,
+    /// Local and external seconds of the recent reports
+    points: VecDeque<(f64, f64)>,
+    stats: ClockStats,
+}
+
+impl ClockTracker {
+    pub fn new(sync: Arc<ClockSync>) -> Self {
+        Self {
+            sync,
+            origin_ms: None,
+            points: VecDeque::new(),
+            stats: ClockStats {
+                reports: 0,
+                resets: 0,
+                rate_ppm: 0.0,
+                error_ms: 0.0,
+            },
+        }
+    }
+
+    pub fn local_elapsed(&self) -> Duration {
+        self.sync.local_elapsed()
+    }
+
+    pub fn stats(&self) -> ClockStats {
+        self.stats
+    }
+
+    /// Take in a report that the external clock read `media_ms` at local time `local`
+    pub fn correct(&mut self, media_ms: f64, local: Duration) -> Correction {
+        self.stats.reports += 1;
+        let timeline = self.sync.timeline_at(local);
+        let timeline_ms = timeline.as_secs_f64() * 1_000.0;
+        let point = (local.as_secs_f64(), media_ms / 1_000.0);
+        let Some(origin_ms) = self.origin_ms else {
+            self.anchor(media_ms, timeline_ms, point);
+            return Correction::Locked;
+        };
+        let error_ms = media_ms - origin_ms - timeline_ms;
+        self.stats.error_ms = error_ms;
+        if error_ms.abs() > RESET_ERROR_MS {
+            self.stats.resets += 1;
+            self.anchor(media_ms, timeline_ms, point);
+            return Correction::Reset { error_ms };
+        }
+
+        self.points.push_back(point);
+        if self.points.len() > WINDOW {
+            self.points.pop_front();
+        }
+        let rate_ppm = (fitted_ppm(&self.points) + error_ms / CONVERGE_SECS * 1_000.0)
+            .clamp(-MAX_PPM, MAX_PPM);
+        self.stats.rate_ppm = rate_ppm;
+        self.sync.publish(local, timeline, rate_ppm);
+        Correction::Tracking { rate_ppm, error_ms }
+    }
+
+    /// Tie external time `media_ms` to the timeline where it is, keeping its current rate
+    fn anchor(&mut self, media_ms: f64, timeline_ms: f64, point: (f64, f64)) {
+        self.origin_ms = Some(media_ms - timeline_ms);
+        self.points.clear();
+        self.points.push_back(point);
+    }
+}
+
+/// How much faster than the local clock the external one ran over `points`, least squares
+fn fitted_ppm(points: &VecDeque<(f64, f64)>) -> f64 {
+    let (Some(first), Some(last)) = (points.front(), points.back()) else {
+        return 0.0;
+    };
+    if last.0 - first.0 < MIN_SPAN_SECS {
+        return 0.0;
+    }
+    let n = points.len() as f64;
+    let mean_local = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_media = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for &(local, media) in points {
+        covariance += (local - mean_local) * (media - mean_media);
+        variance += (local - mean_local) * (local - mean_local);
+    }
+    (covariance / variance - 1.0) * 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_a_faster_clock_without_jumping() {
+        let sync = Arc::new(ClockSync::new());
+        let mut tracker = ClockTracker::new(sync.clone());
+        // The external clock runs 200 ppm fast and started at 90 s
+        let media = |local: f64| 90_000.0 + local * 1_000.0 * 1.000_2;
+        assert_eq!(
+            tracker.correct(media(0.5), Duration::from_millis(500)),
+            Correction::Locked
+        );
+        let mut previous = Duration::ZERO;
+        for second in 1..=120 {
+            let local = Duration::from_secs(second);
+            let before = sync.timeline_at(local);
+            assert!(before >= previous);
+            tracker.correct(media(second as f64), local);
+            // Corrections bend the line at the current point, they don't move it
+            assert_eq!(sync.timeline_at(local), before);
+            previous = before;
+        }
+        let stats = tracker.stats();
+        assert!((stats.rate_ppm - 200.0).abs() < 5.0, "{:?}", stats);
+        assert!(stats.error_ms.abs() < 1.0, "{:?}", stats);
+    }
+
+    #[test]
+    fn re_anchors_when_the_clock_restarts() {
+        let sync = Arc::new(ClockSync::new());
+        let mut tracker = ClockTracker::new(sync.clone());
+        tracker.correct(10_000.0, Duration::from_secs(1));
+        tracker.correct(11_000.0, Duration::from_secs(2));
+        let restarted = tracker.correct(0.0, Duration::from_secs(3));
+        assert!(matches!(restarted, Correction::Reset { error_ms } if error_ms < -10_000.0));
+        assert_eq!(
+            tracker.correct(1_000.0, Duration::from_secs(4)),
+            Correction::Tracking {
+                rate_ppm: 0.0,
+                error_ms: 0.0
+            }
+        );
+        assert_eq!(
+            sync.timeline_at(Duration::from_secs(5)),
+            Duration::from_secs(5)
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Conferencing app state
+//! With `--meeting-bridge` the session looks at what the conferencing apps running on the
+//! machine show about the call, every 2 s, and reports the meeting it finds:
+//! `meeting_start`, `meeting_update` and `meeting_end` events (also on the timeline), and
+//! the last one in `stopped`, so the recording can be matched to the meeting without asking
+//! the rep.
+//!
+//! Neither app has a local API for this, so their top-level windows are read. Zoom is in a
+//! meeting while its meeting window is open; clients that put the meeting ID in its title
+//! give that, and a popped-out participants panel (`Participants (12)`) gives the head
+//! count. Teams is in a meeting while one of its windows is a meeting or call window;
+//! Teams shows no meeting ID, and a head count only in a popped-out roster.
+//!
+//! `--loopback-meeting-app` points the process loopback at the process tree of the app in
+//! the meeting when capture starts, instead of a fixed `--loopback-process-name`.
+
+use crate::process_loopback::{self, Process};
+use serde::Serialize;
+use std::time::Duration;
+
+/// How often the apps are looked at
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Zoom's meeting window
+const ZOOM_MEETING_CLASS: &str = "ZPContentViewWndClass";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum App {
+    Zoom,
+    Teams,
+}
+
+impl App {
+    const ALL: [App; 2] = [App::Zoom, App::Teams];
+
+    /// Executables of the app, new client first
+    fn executables(self) -> &'static [&'static str] {
+        match self {
+            App::Zoom => &["Zoom.exe"],
+            App::Teams => &["ms-teams.exe", "Teams.exe"],
+        }
+    }
+
+    fn is_meeting_window(self, window: &AppWindow) -> bool {
+        match self {
+            App::Zoom => {
+                window.class == ZOOM_MEETING_CLASS || window.title.starts_with("Zoom Meeting")
+            }
+            App::Teams => window
+                .title
+                .strip_suffix("| Microsoft Teams")
+                .is_some_and(|title| {
+                    title.starts_with("Meeting")
+                        || title.starts_with("Call with")
+                        || title.contains("Meeting with")
+                }),
+        }
+    }
+}
+
+/// A visible top-level window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppWindow {
+    pub pid: u32,
+    pub class: String,
+    pub title: String,
+}
+
+/// A meeting in progress
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Meeting {
+    pub app: App,
+    /// Root of the app's process tree, what the process loopback records
+    pub pid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meeting_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub participants: Option<u32>,
+}
+
+/// The meeting `windows` show, Zoom's before Teams'
+pub fn detect(windows: &[AppWindow], processes: &[Process]) -> Option<Meeting> {
+    let runs = |app: App, pid: u32| {
+        processes.iter().any(|process| {
+            process.pid == pid
+                && app
+                    .executables()
+                    .iter()
+                    .any(|exe| process.name.eq_ignore_ascii_case(exe))
+        })
+    };
+    App::ALL.into_iter().find_map(|app| {
+        let own: Vec<&AppWindow> = windows.iter().filter(|w| runs(app, w.pid)).collect();
+        let meeting_window = own.iter().find(|w| app.is_meeting_window(w))?;
+        let pid = app
+            .executables()
+            .iter()
+            .find_map(|exe| process_loopback::tree_root(processes, exe))
+            .unwrap_or(meeting_window.pid);
+        Some(Meeting {
+            app,
+            pid,
+            meeting_id: own.iter().find_map(|w| meeting_id(&w.title)),
+            participants: own.iter().find_map(|w| participants(&w.title)),
+        })
+    })
+}
+
+/// Digits of `Zoom Meeting ID: 812-3456-7890`
+fn meeting_id(title: &str) -> Option<String> {
+    let (_, id) = title.split_once("Meeting ID:")?;
+    let digits: String = id
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '-' || *c == ' ')
+        .filter(char::is_ascii_digit)
+        .collect();
+    (digits.len() >= 9).then_some(digits)
+}
+
+/// Head count of `Participants (12)` or `People (12)`
+fn participants(title: &str) -> Option<u32> {
+    let count = ["Participants (", "People ("]
+        .iter()
+        .find_map(|prefix| title.trim().strip_prefix(prefix))?;
+    count.strip_suffix(')')?.trim().parse().ok()
+}
+
+/// What a newly seen meeting state changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Started,
+    Updated,
+    Ended,
+}
+
+impl Change {
+    pub fn name(self) -> &'static str {
+        match self {
+            Change::Started => "meeting_start",
+            Change::Updated => "meeting_update",
+            Change::Ended => "meeting_end",
+        }
+    }
+}
+
+/// Follows the meeting over the session
+#[derive(Debug, Default)]
+pub struct MeetingTracker {
+    current: Option<Meeting>,
+    /// The last meeting seen, with the most participants it had
+    last: Option<Meeting>,
+}
+
+impl MeetingTracker {
+    /// Take in the latest look at the apps; `Some` when it differs from the one before
+    pub fn observe(&mut self, seen: Option<Meeting>) -> Option<Change> {
+        let change = match (&self.current, &seen) {
+            (None, None) => return None,
+            (None, Some(_)) => Change::Started,
+            (Some(_), None) => Change::Ended,
+            (Some(current), Some(seen)) if current == seen => return None,
+            (Some(current), Some(seen)) if (current.app, current.pid) != (seen.app, seen.pid) => {
+                Change::Started
+            }
+            (Some(_), Some(_)) => Change::Updated,
+        };
+        self.last = match (change, &self.last, &seen) {
+            // A closed panel or a title without the ID doesn't make them unknown
+            (Change::Updated, Some(last), Some(seen)) => Some(Meeting {
+                meeting_id: seen.meeting_id.clone().or_else(|| last.meeting_id.clone()),
+                participants: seen.participants.max(last.participants),
+                ..seen.clone()
+            }),
+            (Change::Ended, last, _) => last.clone(),
+            _ => seen.clone(),
+        };
+        self.current = seen;
+        Some(change)
+    }
+
+    pub fn current(&self) -> Option<&Meeting> {
+        self.current.as_ref()
+    }
+
+    /// The last meeting of the session, with its peak head count
+    pub fn last(&self) -> Option<&Meeting> {
+        self.last.as_ref()
+    }
+}
+
+/// The meeting in progress on this machine, if any
+#[cfg(windows)]
+pub fn current() -> Option<Meeting> {
+    let processes = process_loopback::processes()
+        .map_err(|e| eprintln!("[win-audio-capture] Warning: {:#}", e))
+        .ok()?;
+    detect(&top_level_windows(), &processes)
+}
+
+#[cfg(not(windows))]
+pub fn current() -> Option<Meeting> {
+    None
+}
+
+/// Visible top-level windows
+#[cfg(windows)]
+fn top_level_windows() -> Vec<AppWindow> {
+    use windows::Win32::Foundation::{BOOL, HWND, LPARAM};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        EnumWindows, GetClassNameW, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible,
+    };
+
+    unsafe extern "system" fn collect(hwnd: HWND, found: LPARAM) -> BOOL {
+        let found = &mut *(found.0 as *mut Vec<AppWindow>);
+        if IsWindowVisible(hwnd).as_bool() {
+            let mut pid = 0u32;
+            GetWindowThreadProcessId(hwnd, Some(&mut pid));
+            let mut class = [0u16; 256];
+            let class_len = GetClassNameW(hwnd, &mut class).max(0) as usize;
+            let mut title = [0u16; 512];
+            let title_len = GetWindowTextW(hwnd, &mut title).max(0) as usize;
+            found.push(AppWindow {
+                pid,
+                class: String::from_utf16_lossy(&class[..class_len]),
+                title: String::from_utf16_lossy(&title[..title_len]),
+            });
+        }
+        true.into()
+    }
+
+    let mut found: Vec<AppWindow> = Vec::new();
+    let _ = unsafe { EnumWindows(Some(collect), LPARAM(&mut found as *mut _ as isize)) };
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, parent: u32, name: &str) -> Process {
+        Process {
+            pid,
+            parent,
+            name: name.to_string(),
+        }
+    }
+
+    fn window(pid: u32, class: &str, title: &str) -> AppWindow {
+        AppWindow {
+            pid,
+            class: class.to_string(),
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_zoom_meeting_id_and_participants_from_its_windows() {
+        let processes = [
+            process(1, 0, "explorer.exe"),
+            process(40, 1, "Zoom.exe"),
+            process(41, 40, "Zoom.exe"),
+            process(70, 1, "ms-teams.exe"),
+        ];
+        let windows = [
+            window(40, "ZPPTMainFrmWndClassEx", "Zoom Workplace"),
+            window(41, ZOOM_MEETING_CLASS, "Zoom Meeting ID: 812-3456-7890"),
+            window(41, "zPlistWndClass", "Participants (12)"),
+            window(70, "TeamsWebView", "Chat | Microsoft Teams"),
+        ];
+        assert_eq!(
+            detect(&windows, &processes),
+            Some(Meeting {
+                app: App::Zoom,
+                pid: 40,
+                meeting_id: Some("81234567890".to_string()),
+                participants: Some(12),
+            })
+        );
+        // Zoom open without a meeting
+        assert_eq!(detect(&windows[..1], &processes), None);
+    }
+
+    #[test]
+    fn finds_teams_meetings_by_their_window() {
+        let processes = [process(70, 1, "ms-teams.exe")];
+        let windows = [
+            window(
+                70,
+                "TeamsWebView",
+                "Meeting with Dana Reyes | Microsoft Teams",
+            ),
+            // Not Teams' window, whatever its title
+            window(90, "Chrome_WidgetWin_1", "Meeting notes | Microsoft Teams"),
+        ];
+        let meeting = detect(&windows, &processes).unwrap();
+        assert_eq!((meeting.app, meeting.pid), (App::Teams, 70));
+        assert_eq!((meeting.meeting_id, meeting.participants), (None, None));
+        assert_eq!(detect(&windows[1..], &processes), None);
+    }
+
+    #[test]
+    fn reports_changes_and_keeps_the_peak_head_count() {
+        let meeting = |participants| Meeting {
+            app: App::Zoom,
+            pid: 40,
+            meeting_id: Some("81234567890".to_string()),
+            participants: Some(participants),
+        };
+        let mut tracker = MeetingTracker::default();
+        assert_eq!(tracker.observe(None), None);
+        assert_eq!(tracker.observe(Some(meeting(3))), Some(Change::Started));
+        assert_eq!(tracker.observe(Some(meeting(3))), None);
+        assert_eq!(tracker.observe(Some(meeting(7))), Some(Change::Updated));
+        // The panel was closed: the ID and the peak stay known
+        let closed = Meeting {
+            participants: None,
+            meeting_id: None,
+            ..meeting(0)
+        };
+        assert_eq!(tracker.observe(Some(closed)), Some(Change::Updated));
+        assert_eq!(tracker.observe(None), Some(Change::Ended));
+        assert_eq!(tracker.current(), None);
+        assert_eq!(tracker.last(), Some(&meeting(7)));
+
+        let teams = Meeting {
+            app: App::Teams,
+            pid: 70,
+            meeting_id: None,
+            participants: None,
+        };
+        // A new meeting starts its own count
+        assert_eq!(tracker.observe(Some(meeting(2))), Some(Change::Started));
+        assert_eq!(tracker.last(), Some(&meeting(2)));
+        assert_eq!(tracker.observe(Some(teams.clone())), Some(Change::Started));
+        assert_eq!(tracker.last(), Some(&teams));
+    }
+}
@@ -0,0 +1,353 @@
+//! Control channel
+//! Newline-delimited JSON commands read from stdin, e.g. `{"cmd":"position"}`.
+//! Lines are parsed by a task on the async runtime and handed to the session loop; replies
+//! go out as events on stderr. An attached agent sends the same commands over the
+//! instance pipe.
+//!
+//! A command may carry an `id` (string or number), e.g. `{"cmd":"dsp","agc":true,"id":"c7"}`.
+//! Then it is answered with `ack` as soon as it is queued for the session, and with `done`
+//! or `control_error` (both carrying the `id`) once it was carried out or failed, so the
+//! agent can tell a command that was lost in the pipe from one that is still in progress.
+//! With `timeout_ms` as well, a command the session only gets to later than that is dropped
+//! with `control_error` (`"expired": true`) rather than carried out late. Retrying with the
+//! same `id` is safe: a command already carried out is answered again, not run twice.
+
+use crate::dsp::DspUpdate;
+use crate::error::{self, ErrorKind};
+use crate::events;
+use crate::reconfigure::Reconfigure;
+use crate::transcript::{Fed, Word};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Report the absolute sample position, wall time and per-source drift
+    Position,
+    /// External timeline event (slide change, screen-share start, ...) to merge into the
+    /// session timeline, e.g. `{"cmd":"timeline_event","kind":"slide_change","label":"Pricing"}`
+    TimelineEvent {
+        kind: String,
+        #[serde(default)]
+        label: Option<String>,
+        /// Wall time (Unix ms) the event happened; defaults to when it is received
+        #[serde(default)]
+        at_ms: Option<u64>,
+        #[serde(default)]
+        data: Option<Value>,
+    },
+    /// Opt into optional frame types announced in the handshake, e.g.
+    /// `{"cmd":"negotiate","frames":["energy"]}`; answered with a `negotiated` event
+    Negotiate {
+        frames: Vec<String>,
+    },
+    /// Change MIC processing while recording, e.g. `{"cmd":"dsp","hpf_hz":100,"agc":true}`;
+    /// unset fields keep their value. Answered with a `dsp` event.
+    Dsp(DspUpdate),
+    /// Apply configuration that would otherwise take a new session while capture goes on,
+    /// e.g. `{"cmd":"reconfigure","frame_ms":20}`; see `reconfigure`. Answered with a
+    /// `reconfigured` event.
+    Reconfigure(Reconfigure),
+    /// Short WAV snippet (TTS of dictated notes, a prompt) to keep at the current position,
+    /// e.g. `{"cmd":"audio_note","path":"C:\\notes\\n1.wav","label":"Budget"}`; see `--notes`
+    AudioNote {
+        path: PathBuf,
+        #[serde(default)]
+        label: Option<String>,
+    },
+    /// Abort the session and securely delete everything it has written so far, e.g.
+    /// `{"cmd":"panic_delete","session":"<id>","reason":"consent_revoked"}`. The session
+    /// must match, so a command meant for another capture can't destroy this one.
+    PanicDelete {
+        session: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Record silence instead of the call until `resume`, e.g. during a break; sample
+    /// positions keep counting so the timeline stays aligned
+    Pause,
+    Resume,
+    /// Named marker at the current position, kept in the timeline file, e.g.
+    /// `{"cmd":"marker","label":"objection"}`
+    Marker {
+        label: String,
+        #[serde(default)]
+        data: Option<Value>,
+    },
+    /// Close the recording and carry on in a new file (`<stem>_0002.wav`, then `_0003`, ...);
+    /// answered with a `rotated` event once the first file is complete
+    Rotate,
+    /// Hand the recording over to another session without a gap, e.g. when the agent starts
+    /// a new logical call on the same capture: `{"cmd":"new_session","session":"<id>"}`.
+    /// Rotates like `rotate`; the next file belongs to the new session, and both files'
+    /// timelines link to each other at the exact frame of the hand-over.
+    NewSession {
+        session: String,
+    },
+    /// Keep the `--rolling-minutes` buffer: it goes into the recording, and capture carries on
+    /// into it; answered with a `committed` event
+    Commit,
+    /// Reading of the external master clock for `--clock external`, e.g.
+    /// `{"cmd":"clock","media_time_ms":5231.5,"at_ms":1760000000000}` (`at_ms`, the Unix ms
+    /// it was read at, discounts the time the command took to arrive)
+    Clock {
+        media_time_ms: f64,
+        #[serde(default)]
+        at_ms: Option<u64>,
+    },
+    /// Stop capture, the same way a signal does: the trailing window still applies
+    Stop,
+    /// Words from the ASR backend to emit on the session clock, e.g.
+    /// `{"cmd":"transcript","words":[{"text":"hi","start_ms":120,"end_ms":380}],
+    /// "fed":[{"sample_offset":96000,"frames":480000}],"final":true}`; see `transcript`
+    Transcript {
+        words: Vec<Word>,
+        fed: Vec<Fed>,
+        #[serde(default, rename = "final")]
+        is_final: bool,
+    },
+}
+
+/// Outcomes kept for answering retries
+const REMEMBERED_IDS: usize = 256;
+
+impl ControlCommand {
+    /// The command's `cmd`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Position => "position",
+            ControlCommand::TimelineEvent { .. } => "timeline_event",
+            ControlCommand::Negotiate { .. } => "negotiate",
+            ControlCommand::Dsp(_) => "dsp",
+            ControlCommand::Reconfigure(_) => "reconfigure",
+            ControlCommand::AudioNote { .. } => "audio_note",
+            ControlCommand::PanicDelete { .. } => "panic_delete",
+            ControlCommand::Pause => "pause",
+            ControlCommand::Resume => "resume",
+            ControlCommand::Marker { .. } => "marker",
+            ControlCommand::Rotate => "rotate",
+            ControlCommand::NewSession { .. } => "new_session",
+            ControlCommand::Commit => "commit",
+            ControlCommand::Clock { .. } => "clock",
+            ControlCommand::Stop => "stop",
+            ControlCommand::Transcript { .. } => "transcript",
+        }
+    }
+}
+
+/// A command as it was received
+#[derive(Debug)]
+pub struct Request {
+    pub id: Option<String>,
+    pub command: ControlCommand,
+    /// When the command is dropped instead of carried out
+    pub deadline: Option<Instant>,
+}
+
+impl Request {
+    pub fn expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Parse one command line; `id` and `timeout_ms` are taken off before the command's own
+/// fields are read. Returns the `id` with the error when the line has one.
+pub fn parse(line: &str) -> Result<Request, (Option<String>, String)> {
+    let mut value: Value = serde_json::from_str(line).map_err(|e| (None, e.to_string()))?;
+    let fields = value.as_object_mut();
+    let id = fields
+        .as_ref()
+        .and_then(|fields| fields.get("id"))
+        .map(|id| match id {
+            Value::String(id) => id.clone(),
+            other => other.to_string(),
+        });
+    let timeout_ms = match fields {
+        Some(fields) => {
+            fields.remove("id");
+            fields.remove("timeout_ms")
+        }
+        None => None,
+    };
+    let timeout_ms = match timeout_ms {
+        Some(timeout) => Some(
+            timeout
+                .as_u64()
+                .ok_or_else(|| (id.clone(), "timeout_ms must be a whole number".to_string()))?,
+        ),
+        None => None,
+    };
+    let command = serde_json::from_value(value).map_err(|e| (id.clone(), e.to_string()))?;
+    Ok(Request {
+        id,
+        command,
+        deadline: timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms)),
+    })
+}
+
+/// How a command with an `id` ended, kept to answer retries
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Done,
+    Failed(ErrorKind, String),
+}
+
+impl Outcome {
+    pub fn failed(error: &anyhow::Error) -> Self {
+        Outcome::Failed(error::kind(error), format!("{:#}", error))
+    }
+}
+
+/// Outcomes of the last commands with an `id`, oldest forgotten first
+#[derive(Default)]
+pub struct Outcomes {
+    by_id: HashMap<String, Outcome>,
+    order: VecDeque<String>,
+}
+
+impl Outcomes {
+    pub fn get(&self, id: &str) -> Option<&Outcome> {
+        self.by_id.get(id)
+    }
+
+    pub fn insert(&mut self, id: String, outcome: Outcome) {
+        if self.by_id.insert(id.clone(), outcome).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > REMEMBERED_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.by_id.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Emit the answer to the command `id` (or a command without one, which only hears
+/// about failures)
+pub fn answer(id: Option<&str>, cmd: &str, outcome: &Outcome, fields: Value) {
+    let mut event = json!({ "cmd": cmd });
+    if let Some(id) = id {
+        event["id"] = json!(id);
+    }
+    match outcome {
+        Outcome::Done if id.is_none() => return,
+        Outcome::Done => {}
+        Outcome::Failed(kind, error) => {
+            event["kind"] = json!(kind);
+            event["error"] = json!(error);
+        }
+    }
+    if let (Value::Object(event), Value::Object(fields)) = (&mut event, fields) {
+        event.extend(fields);
+    }
+    let name = match outcome {
+        Outcome::Done => "done",
+        Outcome::Failed(..) => "control_error",
+    };
+    events::emit(name, event);
+}
+
+/// Start reading commands from stdin on the runtime. The task ends when stdin closes or
+/// the receiving side is dropped.
+pub fn spawn_stdin_reader(tx: mpsc::Sender<Request>) {
+    tokio::spawn(read_commands(tokio::io::stdin(), tx));
+}
+
+/// Parse command lines from `reader` until it closes or the receiving side is dropped
+pub async fn read_commands<R: AsyncRead + Unpin>(reader: R, tx: mpsc::Sender<Request>) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse(line) {
+            Ok(request) => {
+                let ack = request
+                    .id
+                    .clone()
+                    .map(|id| json!({ "id": id, "cmd": request.command.name() }));
+                if tx.send(request).await.is_err() {
+                    break;
+                }
+                if let Some(ack) = ack {
+                    events::emit("ack", ack);
+                }
+            }
+            Err((id, error)) => {
+                let mut event =
+                    json!({ "input": line, "kind": ErrorKind::Protocol, "error": error });
+                if let Some(id) = id {
+                    event["id"] = json!(id);
+                }
+                events::emit("control_error", event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_id_and_timeout_off_the_command() {
+        let request = parse(r#"{"cmd":"dsp","agc":true,"id":"c7","timeout_ms":5000}"#).unwrap();
+        assert_eq!(request.id.as_deref(), Some("c7"));
+        assert!(matches!(request.command, ControlCommand::Dsp(_)));
+        assert!(!request.expired());
+
+        let request = parse(r#"{"cmd":"position","id":12,"timeout_ms":0}"#).unwrap();
+        assert_eq!(request.id.as_deref(), Some("12"));
+        assert!(request.expired());
+        assert!(parse(r#"{"cmd":"position"}"#).unwrap().id.is_none());
+        // The id comes back with the error so the agent can match it up
+        let (id, _) = parse(r#"{"cmd":"dsp","hpf":80,"id":"c8"}"#).unwrap_err();
+        assert_eq!(id.as_deref(), Some("c8"));
+        assert_eq!(parse("not json").unwrap_err().0, None);
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_outcomes() {
+        let mut outcomes = Outcomes::default();
+        outcomes.insert(
+            "first".to_string(),
+            Outcome::Failed(ErrorKind::Protocol, "no".to_string()),
+        );
+        for i in 0..REMEMBERED_IDS {
+            outcomes.insert(i.to_string(), Outcome::Done);
+        }
+        assert!(outcomes.get("first").is_none());
+        assert_eq!(outcomes.get("0"), Some(&Outcome::Done));
+        assert_eq!(outcomes.order.len(), REMEMBERED_IDS);
+    }
+
+    #[test]
+    fn parses_the_session_commands() {
+        for (line, name) in [
+            (r#"{"cmd":"pause"}"#, "pause"),
+            (r#"{"cmd":"resume","id":"r1"}"#, "resume"),
+            (r#"{"cmd":"rotate"}"#, "rotate"),
+            (r#"{"cmd":"commit","id":7}"#, "commit"),
+            (r#"{"cmd":"new_session","session":"s2"}"#, "new_session"),
+            (r#"{"cmd":"stop"}"#, "stop"),
+        ] {
+            assert_eq!(parse(line).unwrap().command.name(), name);
+        }
+        let request = parse(r#"{"cmd":"marker","label":"objection"}"#).unwrap();
+        assert!(matches!(
+            request.command,
+            ControlCommand::Marker { ref label, data: None } if label == "objection"
+        ));
+        // A marker needs its label
+        assert!(parse(r#"{"cmd":"marker"}"#).is_err());
+    }
+}
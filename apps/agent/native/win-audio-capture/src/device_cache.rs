@@ -0,0 +1,149 @@
+//! Device capability cache
+//! Remembers, per MIC device, the stream configuration that last opened successfully and
+//! how often it was used. Startup reuses the cached configuration instead of probing the
+//! driver again, and the most-used device is what "your usual headset is missing" refers to.
+
+use crate::paths;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Configuration a stream was opened with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedDevice {
+    /// Friendly name, which is also the key
+    pub name: String,
+    pub last_good: CachedConfig,
+    pub last_used_ms: u64,
+    pub use_count: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCache {
+    inputs: BTreeMap<String, CachedDevice>,
+}
+
+impl DeviceCache {
+    /// Default location of the cache file
+    pub fn default_path() -> PathBuf {
+        paths::state_dir().join("devices.json")
+    }
+
+    /// Load the cache; a missing or unreadable file is an empty cache
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write atomically (temp file + rename)
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create device cache directory")?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(self).context("Failed to serialize device cache")?;
+        fs::write(&tmp_path, json).context("Failed to write device cache")?;
+        fs::rename(&tmp_path, path).context("Failed to replace device cache")?;
+        Ok(())
+    }
+
+    pub fn input(&self, name: &str) -> Option<&CachedDevice> {
+        self.inputs.get(name)
+    }
+
+    /// Most-used input device, ties going to the most recently used
+    pub fn usual_input(&self) -> Option<&CachedDevice> {
+        self.inputs
+            .values()
+            .max_by_key(|device| (device.use_count, device.last_used_ms))
+    }
+
+    /// Input device used most recently
+    pub fn last_input(&self) -> Option<&CachedDevice> {
+        self.inputs
+            .values()
+            .max_by_key(|device| device.last_used_ms)
+    }
+
+    /// Record that `name` opened successfully with `config`
+    pub fn record_input(&mut self, name: &str, config: CachedConfig, now_ms: u64) {
+        let device = self
+            .inputs
+            .entry(name.to_string())
+            .or_insert_with(|| CachedDevice {
+                name: name.to_string(),
+                last_good: config,
+                last_used_ms: now_ms,
+                use_count: 0,
+            });
+        device.last_good = config;
+        device.last_used_ms = now_ms;
+        device.use_count += 1;
+    }
+
+    /// Drop a cached configuration that no longer opens
+    pub fn forget_input(&mut self, name: &str) {
+        self.inputs.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO_48K: CachedConfig = CachedConfig {
+        sample_rate: 48_000,
+        channels: 2,
+    };
+    const MONO_16K: CachedConfig = CachedConfig {
+        sample_rate: 16_000,
+        channels: 1,
+    };
+
+    #[test]
+    fn round_trips_through_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("devices.json");
+        let mut cache = DeviceCache::default();
+        cache.record_input("Jabra Evolve2 65", STEREO_48K, 10);
+        cache.save(&path).unwrap();
+
+        let loaded = DeviceCache::load(&path);
+        assert_eq!(loaded, cache);
+        assert_eq!(
+            loaded.input("Jabra Evolve2 65").unwrap().last_good,
+            STEREO_48K
+        );
+    }
+
+    #[test]
+    fn corrupt_file_is_an_empty_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        fs::write(&path, b"{not json").unwrap();
+        assert_eq!(DeviceCache::load(&path), DeviceCache::default());
+    }
+
+    #[test]
+    fn usual_input_is_the_most_used() {
+        let mut cache = DeviceCache::default();
+        cache.record_input("Headset", STEREO_48K, 1);
+        cache.record_input("Headset", STEREO_48K, 2);
+        cache.record_input("Laptop Mic", MONO_16K, 3);
+        assert_eq!(cache.usual_input().unwrap().name, "Headset");
+
+        cache.record_input("Laptop Mic", MONO_16K, 4);
+        assert_eq!(cache.usual_input().unwrap().name, "Laptop Mic");
+        assert_eq!(cache.input("Laptop Mic").unwrap().use_count, 2);
+    }
+}
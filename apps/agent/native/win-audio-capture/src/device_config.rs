@@ -0,0 +1,238 @@
+//! Per-device configuration overrides
+//! A JSON config file with a `devices` section keyed by MIC device name (cpal exposes no
+//! stable endpoint ID), e.g.
+//!
+//! ```json
+//! { "devices": {
+//!     "Jabra*": { "gain_db": 6.0 },
+//!     "Shure MV7": { "gain_db": -10.0, "agc": false, "sample_rate": 48000 },
+//!     "Focusrite USB": { "channel_map": [0] }
+//! } }
+//! ```
+//!
+//! A key ending in `*` matches by prefix, so one entry covers a whole headset family; an
+//! exact name beats any prefix and a longer prefix beats a shorter one.
+//!
+//! `setup` adds what it settled on next to them: `mic_device` and `loopback_device`
+//! (endpoint IDs) and the `latency_offset_ms` its chirp measured, which sessions use unless
+//! `--mic-device`, `--loopback-device` or `--latency-offset-ms` say otherwise, and the
+//! `output_root` it checked, for the agent to record under.
+
+use crate::error::fail;
+use crate::{latency, paths};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// MIC to record when `--mic-device` isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mic_device: Option<String>,
+    /// Render endpoint to loop back when `--loopback-device` isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loopback_device: Option<String>,
+    /// Applied when `--latency-offset-ms` isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_offset_ms: Option<f64>,
+    /// Directory the agent records under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_root: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    devices: BTreeMap<String, DeviceOverride>,
+}
+
+/// Settings for one physical device. Unset fields keep the default behavior.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceOverride {
+    /// Open the device at this rate instead of its native one
+    pub sample_rate: Option<u32>,
+    /// Gain applied to the MIC signal (negative for a pad)
+    pub gain_db: Option<f32>,
+    /// Automatic gain control on the MIC, in place of `--agc`
+    pub agc: Option<bool>,
+    /// Noise suppression on the MIC, at the `--denoise` depth (or a default one)
+    pub denoise: Option<bool>,
+    /// Input channels mixed into the MIC channel; all of them when unset
+    pub channel_map: Option<Vec<usize>>,
+}
+
+impl CaptureConfig {
+    /// Config file used when `--config` isn't given
+    pub fn default_path() -> PathBuf {
+        paths::state_dir().join("config.json")
+    }
+
+    /// Load `path`. A missing default file is an empty config; anything else that can't be
+    /// read or parsed is an error, since silently ignoring a typo'd override is worse.
+    pub fn load(path: &Path, explicit: bool) -> Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+                return Ok(Self::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read config {}", path.display()))
+            }
+        };
+        let config: Self = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+        if let Some(offset) = config.latency_offset_ms {
+            if !offset.is_finite() || offset.abs() > latency::MAX_OFFSET_MS {
+                return Err(fail!(
+                    Config,
+                    "latency_offset_ms in {} must be within ±{}",
+                    path.display(),
+                    latency::MAX_OFFSET_MS
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Write atomically (temp file + rename)
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create config directory")?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        let json = serde_json::to_vec_pretty(self).context("Failed to serialize config")?;
+        fs::write(&tmp_path, json).context("Failed to write config")?;
+        fs::rename(&tmp_path, path).context("Failed to replace config")?;
+        Ok(())
+    }
+
+    /// Override for the device called `name`, or the defaults when nothing matches
+    pub fn for_device(&self, name: &str) -> DeviceOverride {
+        if let Some(exact) = self.devices.get(name) {
+            return exact.clone();
+        }
+        self.devices
+            .iter()
+            .filter_map(|(key, value)| {
+                let prefix = key.strip_suffix('*')?;
+                name.starts_with(prefix).then_some((prefix.len(), value))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl DeviceOverride {
+    pub fn gain(&self) -> f32 {
+        10f32.powf(self.gain_db.unwrap_or(0.0) / 20.0)
+    }
+
+    /// Channels to mix for a device with `channels` inputs
+    pub fn channels_to_mix(&self, channels: u16) -> Result<Vec<usize>> {
+        match &self.channel_map {
+            None => Ok((0..channels as usize).collect()),
+            Some(map) if map.is_empty() => Err(fail!(Config, "channel_map must not be empty")),
+            Some(map) => match map.iter().find(|&&channel| channel >= channels as usize) {
+                Some(channel) => Err(fail!(
+                    Config,
+                    "channel_map refers to channel {} but the device has {}",
+                    channel,
+                    channels
+                )),
+                None => Ok(map.clone()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> CaptureConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn exact_name_beats_prefixes() {
+        let config = config(
+            r#"{ "devices": {
+                "Jabra*": { "gain_db": 6.0 },
+                "Jabra Evolve2*": { "gain_db": 4.0 },
+                "Jabra Evolve2 65": { "gain_db": 2.0 }
+            } }"#,
+        );
+        assert_eq!(config.for_device("Jabra Evolve2 65").gain_db, Some(2.0));
+        assert_eq!(config.for_device("Jabra Evolve2 85").gain_db, Some(4.0));
+        assert_eq!(config.for_device("Jabra Speak 510").gain_db, Some(6.0));
+        assert_eq!(config.for_device("Shure MV7"), DeviceOverride::default());
+    }
+
+    #[test]
+    fn gain_is_converted_from_db() {
+        let pad = DeviceOverride {
+            gain_db: Some(-20.0),
+            ..DeviceOverride::default()
+        };
+        assert!((pad.gain() - 0.1).abs() < 1e-6);
+        assert_eq!(DeviceOverride::default().gain(), 1.0);
+    }
+
+    #[test]
+    fn channel_map_is_checked_against_the_device() {
+        let first_only = DeviceOverride {
+            channel_map: Some(vec![0]),
+            ..DeviceOverride::default()
+        };
+        assert_eq!(first_only.channels_to_mix(2).unwrap(), vec![0]);
+        assert_eq!(
+            DeviceOverride::default().channels_to_mix(2).unwrap(),
+            vec![0, 1]
+        );
+        let out_of_range = DeviceOverride {
+            channel_map: Some(vec![2]),
+            ..DeviceOverride::default()
+        };
+        assert!(out_of_range.channels_to_mix(2).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(serde_json::from_str::<CaptureConfig>(
+            r#"{ "devices": { "Mic": { "gian_db": 3 } } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn missing_default_file_is_empty_but_explicit_one_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        assert!(CaptureConfig::load(&path, false)
+            .unwrap()
+            .devices
+            .is_empty());
+        assert!(CaptureConfig::load(&path, true).is_err());
+    }
+
+    #[test]
+    fn setup_choices_are_saved_next_to_the_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, r#"{ "devices": { "Jabra*": { "gain_db": 6.0 } } }"#).unwrap();
+        let mut config = CaptureConfig::load(&path, true).unwrap();
+        config.mic_device = Some("{0.0.1.00000000}.{mic}".to_string());
+        config.latency_offset_ms = Some(22.5);
+        config.save(&path).unwrap();
+
+        let saved = CaptureConfig::load(&path, true).unwrap();
+        assert_eq!(saved.mic_device.as_deref(), Some("{0.0.1.00000000}.{mic}"));
+        assert_eq!(saved.latency_offset_ms, Some(22.5));
+        assert_eq!(saved.loopback_device, None);
+        assert_eq!(saved.for_device("Jabra Evolve2 65").gain_db, Some(6.0));
+
+        fs::write(&path, r#"{ "latency_offset_ms": 900 }"#).unwrap();
+        assert!(CaptureConfig::load(&path, true).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! MIC device selection
+//! The Console default input is often the webcam's microphone while the rep talks into a
+//! headset, so by default every active capture endpoint is scored instead: being the
+//! Communications default counts most, then being a headset (by form factor, or by name
+//! when the driver doesn't report one), then being the device used last, then being the
+//! Console default. `--device-strategy` picks a fixed policy instead.
+//!
+//! The decision goes out as a `device_selected` event with every candidate's score and a
+//! confidence: how clearly the chosen device beat the runner-up.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--device-strategy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStrategy {
+    /// Highest score wins
+    Ranked,
+    /// The Communications default, else the highest score
+    Communications,
+    /// The device used last, else the highest score
+    LastUsed,
+    /// The Console default, as Windows' own "default device"
+    Default,
+}
+
+const COMMUNICATIONS_SCORE: u32 = 40;
+const HEADSET_SCORE: u32 = 30;
+const LAST_USED_SCORE: u32 = 20;
+const CONSOLE_SCORE: u32 = 10;
+
+/// Lowercase name fragments of headsets whose drivers report no form factor
+const HEADSET_NAMES: &[&str] = &[
+    "headset",
+    "headphone",
+    "earbud",
+    "airpods",
+    "jabra",
+    "plantronics",
+    "poly ",
+    "sennheiser",
+    "epos",
+    "evolve",
+    "voyager",
+];
+
+/// One active capture endpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Candidate {
+    /// Friendly name, which is also how cpal names the device
+    pub name: String,
+    /// Endpoint ID, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub communications_default: bool,
+    pub console_default: bool,
+    /// The endpoint reports itself as a headset or headphones
+    pub headset_form_factor: bool,
+    pub last_used: bool,
+}
+
+/// A candidate with its score
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Scored {
+    pub name: String,
+    pub score: u32,
+    pub reasons: Vec<&'static str>,
+}
+
+/// What was picked and why
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Decision {
+    pub strategy: DeviceStrategy,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub score: u32,
+    /// 0 when the runner-up scored the same, 1 when nothing else scored at all
+    pub confidence: f64,
+    /// Every candidate, best first
+    pub candidates: Vec<Scored>,
+}
+
+/// Whether a device name looks like a headset
+pub fn looks_like_headset(name: &str) -> bool {
+    let name = name.to_lowercase();
+    HEADSET_NAMES.iter().any(|fragment| name.contains(fragment))
+}
+
+pub fn score(candidate: &Candidate) -> Scored {
+    let mut score = 0;
+    let mut reasons = Vec::new();
+    let mut add = |applies: bool, points: u32, reason: &'static str| {
+        if applies {
+            score += points;
+            reasons.push(reason);
+        }
+    };
+    add(
+        candidate.communications_default,
+        COMMUNICATIONS_SCORE,
+        "communications_default",
+    );
+    add(
+        candidate.headset_form_factor || looks_like_headset(&candidate.name),
+        HEADSET_SCORE,
+        "headset",
+    );
+    add(candidate.last_used, LAST_USED_SCORE, "last_used");
+    add(candidate.console_default, CONSOLE_SCORE, "console_default");
+    Scored {
+        name: candidate.name.clone(),
+        score,
+        reasons,
+    }
+}
+
+/// Pick a device from `candidates` with `strategy`; `None` when there are none
+pub fn decide(strategy: DeviceStrategy, candidates: &[Candidate]) -> Option<Decision> {
+    let mut scored: Vec<(usize, Scored)> = candidates.iter().map(score).enumerate().collect();
+    // Stable, so equal scores keep the enumeration order
+    scored.sort_by_key(|(_, s)| std::cmp::Reverse(s.score));
+
+    let preferred = match strategy {
+        DeviceStrategy::Ranked => None,
+        DeviceStrategy::Communications => candidates.iter().position(|c| c.communications_default),
+        DeviceStrategy::LastUsed => candidates.iter().position(|c| c.last_used),
+        DeviceStrategy::Default => candidates.iter().position(|c| c.console_default),
+    };
+    let chosen = preferred.unwrap_or(scored.first()?.0);
+    let chosen_score = scored.iter().find(|(i, _)| *i == chosen)?.1.score;
+    let runner_up = scored
+        .iter()
+        .filter(|(i, _)| *i != chosen)
+        .map(|(_, s)| s.score)
+        .max();
+    let confidence = match runner_up {
+        None => 1.0,
+        Some(_) if chosen_score == 0 => 0.0,
+        Some(other) => chosen_score.saturating_sub(other) as f64 / chosen_score as f64,
+    };
+    Some(Decision {
+        strategy,
+        name: candidates[chosen].name.clone(),
+        id: candidates[chosen].id.clone(),
+        score: chosen_score,
+        confidence,
+        candidates: scored.into_iter().map(|(_, s)| s).collect(),
+    })
+}
+
+/// Active capture endpoints with what Windows reports about them; `last_used` is the
+/// device name to mark as used last
+#[cfg(windows)]
+pub fn candidates(last_used: Option<&str>) -> anyhow::Result<Vec<Candidate>> {
+    use anyhow::Context;
+    use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+    use windows::Win32::Media::Audio::{
+        eCapture, eCommunications, eConsole, EndpointFormFactor, Headphones, Headset, IMMDevice,
+        IMMDeviceEnumerator, MMDeviceEnumerator, PKEY_AudioEndpoint_FormFactor,
+        DEVICE_STATE_ACTIVE,
+    };
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoTaskMemFree, CLSCTX_ALL, COINIT_MULTITHREADED,
+        STGM_READ,
+    };
+
+    unsafe fn id_of(device: &IMMDevice) -> Option<String> {
+        let id = device.GetId().ok()?;
+        let value = id.to_string().ok();
+        CoTaskMemFree(Some(id.0 as *const _));
+        value
+    }
+
+    unsafe {
+        // Already initialized (as MTA) on the runtime's threads is fine too
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let default_id = |role| {
+            enumerator
+                .GetDefaultAudioEndpoint(eCapture, role)
+                .ok()
+                .and_then(|device| id_of(&device))
+        };
+        let communications = default_id(eCommunications);
+        let console = default_id(eConsole);
+
+        let devices = enumerator
+            .EnumAudioEndpoints(eCapture, DEVICE_STATE_ACTIVE)
+            .context("Failed to list capture endpoints")?;
+        let mut candidates = Vec::new();
+        for i in 0..devices.GetCount()? {
+            let Ok(device) = devices.Item(i) else {
+                continue;
+            };
+            let Ok(properties) = device.OpenPropertyStore(STGM_READ) else {
+                continue;
+            };
+            let Ok(name) = properties.GetValue(&PKEY_Device_FriendlyName) else {
+                continue;
+            };
+            let name = name.to_string();
+            let form_factor = properties
+                .GetValue(&PKEY_AudioEndpoint_FormFactor)
+                .ok()
+                .and_then(|value| u32::try_from(&value).ok())
+                .map(|value| EndpointFormFactor(value as i32));
+            let id = id_of(&device);
+            candidates.push(Candidate {
+                communications_default: id.is_some() && id == communications,
+                console_default: id.is_some() && id == console,
+                headset_form_factor: matches!(form_factor, Some(f) if f == Headset || f == Headphones),
+                last_used: last_used == Some(name.as_str()),
+                name,
+                id,
+            });
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(name: &str) -> Candidate {
+        Candidate {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn desk() -> Vec<Candidate> {
+        vec![
+            Candidate {
+                console_default: true,
+                ..candidate("HD Pro Webcam C920")
+            },
+            Candidate {
+                last_used: true,
+                ..candidate("Jabra Evolve2 65")
+            },
+            Candidate {
+                communications_default: true,
+                ..candidate("Realtek Microphone Array")
+            },
+        ]
+    }
+
+    #[test]
+    fn ranks_communications_over_headset_over_last_used_over_console() {
+        let decision = decide(DeviceStrategy::Ranked, &desk()).unwrap();
+        assert_eq!(decision.name, "Jabra Evolve2 65");
+        assert_eq!(decision.score, HEADSET_SCORE + LAST_USED_SCORE);
+        let order: Vec<&str> = decision
+            .candidates
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "Jabra Evolve2 65",
+                "Realtek Microphone Array",
+                "HD Pro Webcam C920"
+            ]
+        );
+        assert!((decision.confidence - 10.0 / 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fixed_strategies_take_their_device_and_fall_back_to_the_ranking() {
+        let candidates = desk();
+        let name = |strategy| decide(strategy, &candidates).unwrap().name;
+        assert_eq!(name(DeviceStrategy::Default), "HD Pro Webcam C920");
+        assert_eq!(
+            name(DeviceStrategy::Communications),
+            "Realtek Microphone Array"
+        );
+        assert_eq!(name(DeviceStrategy::LastUsed), "Jabra Evolve2 65");
+
+        let no_history = [candidate("Microphone"), candidate("USB headset")];
+        let decision = decide(DeviceStrategy::LastUsed, &no_history).unwrap();
+        assert_eq!(
+            (decision.name.as_str(), decision.confidence),
+            ("USB headset", 1.0)
+        );
+        assert_eq!(decide(DeviceStrategy::Ranked, &[]), None);
+    }
+
+    #[test]
+    fn ties_report_no_confidence() {
+        let decision = decide(
+            DeviceStrategy::Ranked,
+            &[candidate("Microphone A"), candidate("Microphone B")],
+        )
+        .unwrap();
+        assert_eq!(
+            (decision.name.as_str(), decision.confidence),
+            ("Microphone A", 0.0)
+        );
+        assert!(looks_like_headset("Headset Microphone (Poly BT700)"));
+        assert!(!looks_like_headset("Microphone (HD Pro Webcam C920)"));
+    }
+}
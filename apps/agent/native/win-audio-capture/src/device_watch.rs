@@ -0,0 +1,261 @@
+//! Default-device changes and hotplug
+//! An open stream doesn't move when the rep plugs in a Bluetooth headset or Windows
+//! switches the default speaker: the loopback keeps reading the old endpoint, which goes
+//! quiet, and the MIC stream errors out. An `IMMNotificationClient` registered for the
+//! session reports both. Changes arriving together (Windows announces a new default once
+//! per role, next to the state change) are settled for `SETTLE` and handled once: loopbacks
+//! that follow a default endpoint reopen it, and a MIC picked by `--device-strategy` rather
+//! than `--mic-device` is selected again and switched to when the choice changed or its
+//! endpoint went away. The mixer fills the switch with silence on its own clock, so sample
+//! positions stay continuous.
+
+use crate::devices::Flow;
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long changes are collected before they are acted on
+pub const SETTLE: Duration = Duration::from_millis(500);
+
+/// One notification from the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceChange {
+    /// The default endpoint of `flow`, for one of the roles, is now `id`
+    DefaultChanged { flow: Flow, id: Option<String> },
+    /// An endpoint was plugged in or enabled
+    Added { id: String },
+    /// An endpoint was unplugged or disabled
+    Removed { id: String },
+}
+
+/// What the session does about the changes of one settle window
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reaction {
+    /// A default render endpoint moved; loopbacks following it reopen
+    pub reopen_loopback: bool,
+    /// The MIC may have a new best or default device; it is selected again
+    pub reselect_mic: bool,
+    /// The MIC's own endpoint went away
+    pub mic_removed: bool,
+}
+
+impl Reaction {
+    /// Fold `change` in; `mic_endpoint` is the endpoint the MIC records from, when known
+    pub fn add(&mut self, change: &DeviceChange, mic_endpoint: Option<&str>) {
+        match change {
+            DeviceChange::DefaultChanged {
+                flow: Flow::Render, ..
+            } => self.reopen_loopback = true,
+            DeviceChange::DefaultChanged {
+                flow: Flow::Capture,
+                ..
+            } => self.reselect_mic = true,
+            // A new headset may well be what `--device-strategy` prefers
+            DeviceChange::Added { .. } => self.reselect_mic = true,
+            DeviceChange::Removed { id } if mic_endpoint == Some(id.as_str()) => {
+                self.mic_removed = true;
+                self.reselect_mic = true;
+            }
+            DeviceChange::Removed { .. } => {}
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[cfg(windows)]
+pub use notify::watch;
+
+#[cfg(windows)]
+mod notify {
+    use super::{DeviceChange, Flow};
+    use crate::error::fail;
+    use anyhow::{anyhow, Context, Result};
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use tokio::sync::mpsc::UnboundedSender;
+    use windows::core::{implement, PCWSTR};
+    use windows::Win32::Media::Audio::{
+        eRender, EDataFlow, ERole, IMMDeviceEnumerator, IMMNotificationClient,
+        IMMNotificationClient_Impl, MMDeviceEnumerator, DEVICE_STATE, DEVICE_STATE_ACTIVE,
+    };
+    use windows::Win32::System::Com::*;
+    use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
+
+    /// Keeps the registration alive; dropping it unregisters the callback
+    pub struct DeviceWatcher {
+        stop: Option<std_mpsc::Sender<()>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Drop for DeviceWatcher {
+        fn drop(&mut self) {
+            drop(self.stop.take());
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    #[implement(IMMNotificationClient)]
+    struct DeviceCallback {
+        changes: UnboundedSender<DeviceChange>,
+    }
+
+    fn id_of(id: &PCWSTR) -> Option<String> {
+        if id.is_null() {
+            return None;
+        }
+        unsafe { id.to_string() }.ok()
+    }
+
+    impl IMMNotificationClient_Impl for DeviceCallback_Impl {
+        fn OnDeviceStateChanged(
+            &self,
+            id: &PCWSTR,
+            state: DEVICE_STATE,
+        ) -> windows::core::Result<()> {
+            if let Some(id) = id_of(id) {
+                let change = if state == DEVICE_STATE_ACTIVE {
+                    DeviceChange::Added { id }
+                } else {
+                    DeviceChange::Removed { id }
+                };
+                let _ = self.changes.send(change);
+            }
+            Ok(())
+        }
+
+        fn OnDeviceAdded(&self, id: &PCWSTR) -> windows::core::Result<()> {
+            if let Some(id) = id_of(id) {
+                let _ = self.changes.send(DeviceChange::Added { id });
+            }
+            Ok(())
+        }
+
+        fn OnDeviceRemoved(&self, id: &PCWSTR) -> windows::core::Result<()> {
+            if let Some(id) = id_of(id) {
+                let _ = self.changes.send(DeviceChange::Removed { id });
+            }
+            Ok(())
+        }
+
+        fn OnDefaultDeviceChanged(
+            &self,
+            flow: EDataFlow,
+            _role: ERole,
+            id: &PCWSTR,
+        ) -> windows::core::Result<()> {
+            let flow = if flow == eRender {
+                Flow::Render
+            } else {
+                Flow::Capture
+            };
+            let _ = self.changes.send(DeviceChange::DefaultChanged {
+                flow,
+                id: id_of(id),
+            });
+            Ok(())
+        }
+
+        fn OnPropertyValueChanged(
+            &self,
+            _id: &PCWSTR,
+            _key: &PROPERTYKEY,
+        ) -> windows::core::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Send endpoint changes to `changes` until the watcher is dropped
+    pub fn watch(changes: UnboundedSender<DeviceChange>) -> Result<DeviceWatcher> {
+        let (ready_tx, ready_rx) = std_mpsc::channel();
+        let (stop_tx, stop_rx) = std_mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("device-watch".to_string())
+            .spawn(move || unsafe {
+                if let Err(e) = CoInitializeEx(None, COINIT_MULTITHREADED).ok() {
+                    let _ = ready_tx.send(Err(anyhow!(e).context("Failed to initialize COM")));
+                    return;
+                }
+                match register(changes) {
+                    Ok((enumerator, callback)) => {
+                        let _ = ready_tx.send(Ok(()));
+                        // Notifications arrive on COM worker threads; this one only holds
+                        // the registration until the watcher is dropped
+                        let _ = stop_rx.recv();
+                        let _ = enumerator.UnregisterEndpointNotificationCallback(&callback);
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+                CoUninitialize();
+            })
+            .context("Failed to start device watcher")?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| fail!(Device, "Device watcher exited during setup"))??;
+        Ok(DeviceWatcher {
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+
+    unsafe fn register(
+        changes: UnboundedSender<DeviceChange>,
+    ) -> Result<(IMMDeviceEnumerator, IMMNotificationClient)> {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let callback: IMMNotificationClient = DeviceCallback { changes }.into();
+        enumerator
+            .RegisterEndpointNotificationCallback(&callback)
+            .context("Failed to register for device notifications")?;
+        Ok((enumerator, callback))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_a_headset_plug_in_into_one_reaction() {
+        // Plugging in a headset: it turns active, then becomes the default of every role
+        let headset = "{0.0.1.00000000}.{headset}".to_string();
+        let mut reaction = Reaction::default();
+        for change in [
+            DeviceChange::Added {
+                id: headset.clone(),
+            },
+            DeviceChange::DefaultChanged {
+                flow: Flow::Render,
+                id: Some(headset.clone()),
+            },
+            DeviceChange::DefaultChanged {
+                flow: Flow::Render,
+                id: Some(headset),
+            },
+        ] {
+            reaction.add(&change, Some("{0.0.1.00000000}.{laptop-mic}"));
+        }
+        assert!(reaction.reopen_loopback && reaction.reselect_mic);
+        assert!(!reaction.mic_removed);
+
+        // Only the MIC's own endpoint going away counts as losing it
+        let mut reaction = Reaction::default();
+        let other = DeviceChange::Removed {
+            id: "{other}".to_string(),
+        };
+        reaction.add(&other, Some("{mic}"));
+        assert!(reaction.is_empty());
+        let mic = DeviceChange::Removed {
+            id: "{mic}".to_string(),
+        };
+        reaction.add(&mic, Some("{mic}"));
+        assert!(reaction.mic_removed && reaction.reselect_mic);
+    }
+}
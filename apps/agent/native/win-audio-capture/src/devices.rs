@@ -0,0 +1,196 @@
+//! Audio endpoints
+//! `list-devices` prints every active capture and render endpoint as NDJSON (name, endpoint
+//! ID, whether it is a default, and its shared-mode format), so the agent can offer them
+//! and pin one for a session: `--mic-device <id>` records that MIC instead of the scored
+//! pick, `--loopback-device <id>` loops back that render endpoint instead of the one its
+//! role resolves to. A pinned device that isn't there fails the session rather than
+//! quietly recording another one.
+
+use crate::error::fail;
+use anyhow::Result;
+use serde::Serialize;
+
+/// Which way an endpoint's audio flows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flow {
+    /// A MIC (`--mic-device`)
+    Capture,
+    /// Speakers or headphones (`--loopback-device`)
+    Render,
+}
+
+/// Format the endpoint runs at in shared mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NativeFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+
+/// One active endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Endpoint {
+    pub flow: Flow,
+    /// Friendly name, which is also how cpal names the device
+    pub name: String,
+    pub id: String,
+    /// The Console default ("default device" in Windows' sound settings)
+    pub default: bool,
+    pub communications_default: bool,
+    /// `None` when the endpoint couldn't be opened to ask
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<NativeFormat>,
+}
+
+/// The endpoint of `flow` with ID `id`; a friendly name is accepted too, if it names a
+/// single endpoint
+pub fn find<'a>(endpoints: &'a [Endpoint], flow: Flow, id: &str) -> Result<&'a Endpoint> {
+    let of_flow = || {
+        endpoints
+            .iter()
+            .filter(move |endpoint| endpoint.flow == flow)
+    };
+    if let Some(endpoint) = of_flow().find(|endpoint| endpoint.id.eq_ignore_ascii_case(id)) {
+        return Ok(endpoint);
+    }
+    let named: Vec<&Endpoint> = of_flow().filter(|endpoint| endpoint.name == id).collect();
+    match named.as_slice() {
+        [endpoint] => Ok(endpoint),
+        [] => Err(fail!(
+            Device,
+            "No active {:?} endpoint {:?}; `list-devices` shows the ones there are",
+            flow,
+            id
+        )),
+        _ => Err(fail!(
+            Device,
+            "{} {:?} endpoints are named {:?}; pin one by its ID",
+            named.len(),
+            flow,
+            id
+        )),
+    }
+}
+
+/// Every active capture and render endpoint
+#[cfg(windows)]
+pub fn list() -> Result<Vec<Endpoint>> {
+    use anyhow::Context;
+    use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+    use windows::Win32::Media::Audio::{
+        eCapture, eCommunications, eConsole, eRender, EDataFlow, IAudioClient, IMMDevice,
+        IMMDeviceEnumerator, MMDeviceEnumerator, DEVICE_STATE_ACTIVE,
+    };
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoTaskMemFree, CLSCTX_ALL, COINIT_MULTITHREADED,
+        STGM_READ,
+    };
+
+    unsafe fn id_of(device: &IMMDevice) -> Option<String> {
+        let id = device.GetId().ok()?;
+        let value = id.to_string().ok();
+        CoTaskMemFree(Some(id.0 as *const _));
+        value
+    }
+
+    unsafe fn format_of(device: &IMMDevice) -> Option<NativeFormat> {
+        let client: IAudioClient = device.Activate(CLSCTX_ALL, None).ok()?;
+        let mix_format = client.GetMixFormat().ok()?;
+        // WAVEFORMATEX is packed, so copy the fields out instead of borrowing them
+        let format = std::ptr::read_unaligned(mix_format);
+        CoTaskMemFree(Some(mix_format as *const _));
+        Some(NativeFormat {
+            sample_rate: format.nSamplesPerSec,
+            channels: format.nChannels,
+            bits_per_sample: format.wBitsPerSample,
+        })
+    }
+
+    unsafe {
+        // Already initialized (as MTA) on the runtime's threads is fine too
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let mut endpoints = Vec::new();
+        for (flow, data_flow) in [(Flow::Capture, eCapture), (Flow::Render, eRender)] {
+            let default_id = |data_flow: EDataFlow, role| {
+                enumerator
+                    .GetDefaultAudioEndpoint(data_flow, role)
+                    .ok()
+                    .and_then(|device| id_of(&device))
+            };
+            let console = default_id(data_flow, eConsole);
+            let communications = default_id(data_flow, eCommunications);
+            let devices = enumerator
+                .EnumAudioEndpoints(data_flow, DEVICE_STATE_ACTIVE)
+                .with_context(|| format!("Failed to list {:?} endpoints", flow))?;
+            for i in 0..devices.GetCount()? {
+                let Ok(device) = devices.Item(i) else {
+                    continue;
+                };
+                let Some(id) = id_of(&device) else {
+                    continue;
+                };
+                let name = device
+                    .OpenPropertyStore(STGM_READ)
+                    .and_then(|properties| properties.GetValue(&PKEY_Device_FriendlyName))
+                    .map(|name| name.to_string())
+                    .unwrap_or_default();
+                endpoints.push(Endpoint {
+                    flow,
+                    name,
+                    default: console.as_deref() == Some(id.as_str()),
+                    communications_default: communications.as_deref() == Some(id.as_str()),
+                    format: format_of(&device),
+                    id,
+                });
+            }
+        }
+        Ok(endpoints)
+    }
+}
+
+#[cfg(not(windows))]
+pub fn list() -> Result<Vec<Endpoint>> {
+    Err(fail!(Device, "This tool only runs on Windows"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(flow: Flow, name: &str, id: &str) -> Endpoint {
+        Endpoint {
+            flow,
+            name: name.to_string(),
+            id: id.to_string(),
+            default: false,
+            communications_default: false,
+            format: None,
+        }
+    }
+
+    #[test]
+    fn finds_pinned_endpoints_by_id_or_unique_name() {
+        let endpoints = [
+            endpoint(Flow::Capture, "Jabra Evolve2 65", "{0.0.1.00000000}.{aa}"),
+            endpoint(Flow::Render, "Jabra Evolve2 65", "{0.0.0.00000000}.{bb}"),
+            endpoint(Flow::Render, "Speakers", "{0.0.0.00000000}.{cc}"),
+            endpoint(Flow::Render, "Speakers", "{0.0.0.00000000}.{dd}"),
+        ];
+        let find = |flow, id| find(&endpoints, flow, id).map(|endpoint| endpoint.id.as_str());
+        assert_eq!(
+            find(Flow::Render, "{0.0.0.00000000}.{CC}").unwrap(),
+            "{0.0.0.00000000}.{cc}"
+        );
+        assert_eq!(
+            find(Flow::Capture, "Jabra Evolve2 65").unwrap(),
+            "{0.0.1.00000000}.{aa}"
+        );
+        // A render ID doesn't pin the MIC, and an ambiguous name pins nothing
+        assert!(find(Flow::Capture, "{0.0.0.00000000}.{bb}").is_err());
+        assert!(find(Flow::Render, "Speakers").is_err());
+    }
+}
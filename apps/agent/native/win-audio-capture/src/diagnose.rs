@@ -0,0 +1,444 @@
+//! Support bundles
+//! `diagnose --session <id> --wrap-key support.pem --out bundle.zip` packs what support needs
+//! to look into a session on a customer's machine into one zip, sealed for the support
+//! key the same way `--wrap-key` seals recordings (see `envelope`):
+//!
+//! - `manifest.json`: what is in the bundle, what was left out and why
+//! - `capabilities.json`: build, OS, power and audio endpoints, as `capabilities` reports them
+//! - `device_cache.json`: the MIC configurations that last opened
+//! - `logs/`: the event journal, audit log and timeline of each recording of the session
+//! - `crash/`: the `error.json` of a session that failed and what its workspace still holds
+//! - `sample.wav`: 10 seconds from the middle of the last recording, every channel pitch
+//!   shifted as `--anonymize` does so voices can't be told apart; ranges the session
+//!   redacted are already silent in the file
+//!
+//! Logs name files, devices and the Windows user, and the sample is audio of a call, so
+//! each is only included when the user agrees at a prompt (or with `--yes`). Without a
+//! console to ask on they are left out.
+
+use crate::anonymize::PitchShifter;
+use crate::device_cache::DeviceCache;
+use crate::error::fail;
+use crate::riff::{self, RiffAudio};
+use crate::upload_manifest::{self, UploadManifest};
+use crate::{audit, capabilities, events, i18n, journal, sync_pulse, timeline, workspace};
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use std::io::{BufRead, Cursor, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Length of the audio sample
+pub const SAMPLE_SECS: u64 = 10;
+/// Shift applied to the sample, as `--anonymize-semitones`
+const SAMPLE_SEMITONES: f64 = -4.0;
+
+/// What the user agreed to send
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Consent {
+    /// Event journal, audit log, timeline and error report
+    pub logs: bool,
+    /// The audio sample
+    pub audio: bool,
+}
+
+/// `manifest.json`
+#[derive(Debug, Clone, Serialize)]
+pub struct Contents {
+    pub session: String,
+    pub version: &'static str,
+    pub created_ms: u64,
+    pub entries: Vec<String>,
+    pub left_out: Vec<LeftOut>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LeftOut {
+    pub item: String,
+    pub reason: String,
+}
+
+/// Ask `question` on the console; no answer, or no console, is a no
+pub fn ask(question: &str) -> bool {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if stdin.lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Build the (unsealed) bundle of `session`
+pub fn collect(session: &str, consent: Consent) -> Result<(Vec<u8>, Contents)> {
+    collect_in(
+        &upload_manifest::manifest_dir(),
+        &workspace::dir(session),
+        &DeviceCache::default_path(),
+        session,
+        consent,
+    )
+}
+
+fn collect_in(
+    manifest_dir: &Path,
+    workspace: &Path,
+    device_cache: &Path,
+    session: &str,
+    consent: Consent,
+) -> Result<(Vec<u8>, Contents)> {
+    let recordings: Vec<PathBuf> = UploadManifest::load(manifest_dir, session)?
+        .segments
+        .into_iter()
+        .map(|segment| segment.path)
+        .collect();
+    if recordings.is_empty() && !workspace.exists() {
+        return Err(fail!(
+            Config,
+            "No recordings or workspace of session {:?} on this machine",
+            session
+        ));
+    }
+
+    let mut zip = Zip::default();
+    let mut left_out = Vec::new();
+    let mut leave_out = |item: &str, reason: String| {
+        left_out.push(LeftOut {
+            item: item.to_string(),
+            reason,
+        })
+    };
+
+    let report = serde_json::to_vec_pretty(&capabilities::detect(None))?;
+    zip.add("capabilities.json", &report)?;
+    match fs::read(device_cache) {
+        Ok(bytes) => zip.add("device_cache.json", &bytes)?,
+        Err(e) => leave_out("device_cache.json", e.to_string()),
+    }
+
+    if consent.logs {
+        for recording in &recordings {
+            let sidecars = [
+                journal::path(recording),
+                audit::path(recording),
+                timeline::sidecar_path(recording, "timeline.json"),
+            ];
+            for path in sidecars {
+                let name = format!("logs/{}", file_name(&path));
+                match fs::read(&path) {
+                    Ok(bytes) => zip.add(&name, &bytes)?,
+                    Err(e) => leave_out(&name, e.to_string()),
+                }
+            }
+        }
+        if let Ok(entries) = fs::read_dir(workspace) {
+            let mut files = Vec::new();
+            for entry in entries.flatten() {
+                let bytes = entry.metadata().map_or(0, |metadata| metadata.len());
+                files.push(json!({ "name": entry.file_name().to_string_lossy(), "bytes": bytes }));
+            }
+            if let Ok(report) = fs::read(workspace.join(workspace::ERROR_FILE)) {
+                zip.add(&format!("crash/{}", workspace::ERROR_FILE), &report)?;
+            }
+            zip.add("crash/files.json", &serde_json::to_vec_pretty(&files)?)?;
+        }
+    } else {
+        leave_out("logs", "Not agreed to".to_string());
+    }
+
+    let latest = recordings.iter().rev().find(|recording| recording.exists());
+    match (consent.audio, latest) {
+        (false, _) => leave_out("sample.wav", "Not agreed to".to_string()),
+        (true, None) => leave_out("sample.wav", "No recording left on disk".to_string()),
+        (true, Some(recording)) => match sample(recording) {
+            Ok(wav) => zip.add("sample.wav", &wav)?,
+            Err(e) => leave_out("sample.wav", format!("{:#}", e)),
+        },
+    }
+
+    let contents = Contents {
+        session: session.to_string(),
+        version: env!("CARGO_PKG_VERSION"),
+        created_ms: events::unix_millis(),
+        entries: zip.names.clone(),
+        left_out,
+    };
+    zip.add("manifest.json", &serde_json::to_vec_pretty(&contents)?)?;
+    Ok((zip.finish()?, contents))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// `SAMPLE_SECS` from the middle of `recording` as a WAV file, voices disguised
+fn sample(recording: &Path) -> Result<Vec<u8>> {
+    let audio = RiffAudio::open(recording)?;
+    let frames = (SAMPLE_SECS * audio.sample_rate as u64).min(audio.frames());
+    if frames == 0 {
+        return Err(fail!(Format, "{:?} holds no audio", recording));
+    }
+    let start = (audio.frames() - frames) / 2;
+    let channels = audio.channels as usize;
+    let mut shifters: Vec<PitchShifter> = (0..channels)
+        .map(|_| PitchShifter::new(SAMPLE_SEMITONES, audio.sample_rate))
+        .collect();
+
+    let spec = hound::WavSpec {
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut buffer = Cursor::new(Vec::new());
+    let mut wav = hound::WavWriter::new(&mut buffer, spec)?;
+    riff::read_frames(recording, &audio, start, frames, |samples| {
+        for (i, &sample) in samples.iter().enumerate() {
+            let shifted = shifters[i % channels].process(sample as f32 / 32_768.0);
+            wav.write_sample((shifted * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16)?;
+        }
+        Ok(())
+    })?;
+    wav.finalize()?;
+    Ok(buffer.into_inner())
+}
+
+/// Seal the bundle of `session` for `public_key` (DER) into `out`; what `diagnose` prints
+#[cfg(windows)]
+pub fn run(
+    session: &str,
+    public_key: &[u8],
+    out: &Path,
+    consent: Consent,
+) -> Result<serde_json::Value> {
+    use anyhow::Context;
+
+    // A bad key fails before anything is collected
+    let envelope = crate::envelope::Envelope::new(session, public_key)?;
+    let (bundle, contents) = collect(session, consent)?;
+    fs::write(out, bundle).with_context(|| format!("Failed to write {:?}", out))?;
+    let sealed = match envelope.seal_file(out, &mut |_, _| Ok(())) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            // Nothing unsealed is left behind
+            let _ = fs::remove_file(out);
+            return Err(e);
+        }
+    };
+    Ok(json!({
+        "path": out,
+        "bytes": sealed.bytes,
+        "wrap": sealed.wrap,
+        "key_id": sealed.key_id,
+        "entries": contents.entries,
+        "left_out": contents.left_out,
+    }))
+}
+
+/// A zip archive in memory, its entries stored as they are: logs and WAV don't shrink
+/// enough to be worth a deflate implementation, and the whole is encrypted anyway
+#[derive(Default)]
+struct Zip {
+    bytes: Vec<u8>,
+    central: Vec<u8>,
+    names: Vec<String>,
+}
+
+/// Version 2.0, the first with directories and stored entries as every reader knows them
+const ZIP_VERSION: u16 = 20;
+/// General purpose flag bit 11: names are UTF-8
+const ZIP_UTF8: u16 = 1 << 11;
+
+impl Zip {
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let end = self.bytes.len() + 30 + name.len() + data.len();
+        let offset = u32::try_from(self.bytes.len())
+            .ok()
+            .filter(|_| end <= u32::MAX as usize)
+            .ok_or_else(|| fail!(Format, "Support bundle is over 4 GiB"))?;
+        let (time, date) = dos_time(events::unix_millis());
+        let crc = crate::frame::crc32(data);
+        let mut fields = Vec::with_capacity(26);
+        fields.extend(ZIP_VERSION.to_le_bytes());
+        fields.extend(ZIP_UTF8.to_le_bytes());
+        fields.extend(0u16.to_le_bytes()); // stored
+        fields.extend(time.to_le_bytes());
+        fields.extend(date.to_le_bytes());
+        fields.extend(crc.to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((name.len() as u16).to_le_bytes());
+        fields.extend(0u16.to_le_bytes()); // extra field
+
+        self.bytes.extend(0x0403_4b50u32.to_le_bytes());
+        self.bytes.extend(&fields);
+        self.bytes.extend(name.as_bytes());
+        self.bytes.extend(data);
+
+        self.central.extend(0x0201_4b50u32.to_le_bytes());
+        self.central.extend(ZIP_VERSION.to_le_bytes()); // made by
+        self.central.extend(&fields);
+        self.central.extend([0u8; 10]); // comment, disk, attributes
+        self.central.extend(offset.to_le_bytes());
+        self.central.extend(name.as_bytes());
+        self.names.push(name.to_string());
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let count = u16::try_from(self.names.len())
+            .map_err(|_| fail!(Format, "Support bundle has too many entries"))?;
+        let offset = self.bytes.len() as u32;
+        let size = u32::try_from(self.central.len())
+            .ok()
+            .filter(|size| offset.checked_add(*size).is_some())
+            .ok_or_else(|| fail!(Format, "Support bundle is over 4 GiB"))?;
+        self.bytes.append(&mut self.central);
+        self.bytes.extend(0x0605_4b50u32.to_le_bytes());
+        self.bytes.extend([0u8; 4]); // disks
+        self.bytes.extend(count.to_le_bytes());
+        self.bytes.extend(count.to_le_bytes());
+        self.bytes.extend(size.to_le_bytes());
+        self.bytes.extend(offset.to_le_bytes());
+        self.bytes.extend(0u16.to_le_bytes()); // comment
+        Ok(self.bytes)
+    }
+}
+
+/// MS-DOS time and date of `unix_ms` in local time, as zip entries carry it
+fn dos_time(unix_ms: u64) -> (u16, u16) {
+    let offset_ms = i18n::utc_offset_minutes() as i64 * 60_000;
+    let local = sync_pulse::utc_string((unix_ms as i64 + offset_ms).max(0) as u64);
+    let field = |range: std::ops::Range<usize>| -> u16 {
+        local
+            .get(range)
+            .and_then(|text| text.parse().ok())
+            .unwrap_or(0)
+    };
+    let year = field(0..4).clamp(1980, 2107);
+    let time = (field(11..13) << 11) | (field(14..16) << 5) | (field(17..19) / 2);
+    let date = ((year - 1980) << 9) | (field(5..7) << 5) | field(8..10);
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entry names and contents, read back through the central directory
+    fn read_zip(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let u16_at = |at: usize| u16::from_le_bytes([zip[at], zip[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap()) as usize;
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50);
+        let mut at = u32_at(end + 16);
+        (0..u16_at(end + 10))
+            .map(|_| {
+                assert_eq!(u32_at(at), 0x0201_4b50);
+                let (crc, size, name_len) = (u32_at(at + 16), u32_at(at + 24), u16_at(at + 28));
+                let local = u32_at(at + 42);
+                let name = String::from_utf8(zip[at + 46..at + 46 + name_len].to_vec()).unwrap();
+                at += 46 + name_len;
+                assert_eq!(u32_at(local), 0x0403_4b50);
+                let data_at = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+                let data = zip[data_at..data_at + size].to_vec();
+                assert_eq!(crate::frame::crc32(&data) as usize, crc);
+                (name, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bundles_what_was_agreed_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifests = dir.path().join("uploads");
+        let recording = dir.path().join("call.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = hound::WavWriter::create(&recording, spec).unwrap();
+        for i in 0..8_000 * 30 {
+            wav.write_sample(((i % 80) * 200) as i16 - 8_000).unwrap();
+        }
+        wav.finalize().unwrap();
+        fs::write(journal::path(&recording), "{\"event\":\"started\"}\n").unwrap();
+        let mut manifest = UploadManifest::load(&manifests, "call-9").unwrap();
+        manifest.add_segment(&recording);
+        manifest.save(&manifests).unwrap();
+        let workspace = dir.path().join("workspace");
+        fs::create_dir(&workspace).unwrap();
+        fs::write(workspace.join(workspace::ERROR_FILE), "{\"kind\":\"io\"}").unwrap();
+        let cache = dir.path().join("devices.json");
+
+        let consent = Consent {
+            logs: true,
+            audio: true,
+        };
+        let (zip, contents) =
+            collect_in(&manifests, &workspace, &cache, "call-9", consent).unwrap();
+        let entries = read_zip(&zip);
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "capabilities.json",
+                "logs/call.events.jsonl",
+                "crash/error.json",
+                "crash/files.json",
+                "sample.wav",
+                "manifest.json",
+            ]
+        );
+        let sample = hound::WavReader::new(Cursor::new(&entries[4].1)).unwrap();
+        assert_eq!(sample.duration(), SAMPLE_SECS as u32 * 8_000);
+        let left_out: Vec<&str> = contents.left_out.iter().map(|l| l.item.as_str()).collect();
+        assert_eq!(
+            left_out,
+            [
+                "device_cache.json",
+                "logs/call.audit.jsonl",
+                "logs/call.timeline.json"
+            ]
+        );
+
+        // Nothing personal without consent
+        let (zip, contents) =
+            collect_in(&manifests, &workspace, &cache, "call-9", Consent::default()).unwrap();
+        let names: Vec<String> = read_zip(&zip).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["capabilities.json", "manifest.json"]);
+        assert_eq!(
+            contents.left_out[1..],
+            [
+                LeftOut {
+                    item: "logs".to_string(),
+                    reason: "Not agreed to".to_string()
+                },
+                LeftOut {
+                    item: "sample.wav".to_string(),
+                    reason: "Not agreed to".to_string()
+                },
+            ]
+        );
+
+        let nowhere = dir.path().join("no-workspace");
+        assert!(collect_in(&manifests, &nowhere, &cache, "call-10", consent).is_err());
+    }
+
+    #[test]
+    fn zip_times_are_dos_times() {
+        // 2026-10-15T14:03:08Z, in whatever time zone the test runs
+        let (time, date) = dos_time(1_792_072_988_000);
+        assert_eq!(date >> 9, 2026 - 1980);
+        assert_eq!(date >> 5 & 0xf, 10);
+        assert!(time >> 11 < 24 && time >> 5 & 0x3f < 60 && time & 0x1f == 4);
+    }
+}
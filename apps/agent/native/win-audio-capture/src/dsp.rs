@@ -0,0 +1,538 @@
+//! MIC processing chain
+//! Echo cancellation, high-pass filter, noise suppression and automatic gain control on the
+//! MIC channel, run by the mixer ahead of the limiter. Parameters start from the command line (and the
+//! device's `agc`/`denoise` overrides) and can be changed while recording with the `dsp`
+//! control command, so support can tune a rep's setup during a live test call.
+//!
+//! New parameters are picked up between mix rounds and every one of them is smoothed over
+//! `SMOOTHING_MS`; switching a stage on or off fades it in or out the same way, so a
+//! change never steps the signal. The chain is skipped on the low power profile.
+//!
+//! The echo canceller takes the loopback channel of the same frame as its reference: an
+//! NLMS filter of `AEC_FILTER_MS` learns how the rep's speakers reach the MIC and subtracts
+//! that estimate, so the prospect's voice bleeding into the MIC doesn't read as the rep
+//! speaking. Adaptation pauses while the rep talks over the prospect (double talk).
+//!
+//! `--dsp-compare <file>` records the MIC before and after the chain side by side (raw,
+//! processed, then the loopback channels) so its effect can be judged on real calls.
+
+use crate::error::fail;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Noise suppression depth used when a device override turns it on without `--denoise`
+pub const DEFAULT_DENOISE: f32 = 0.5;
+
+/// Time constant parameter changes are smoothed with
+pub const SMOOTHING_MS: f32 = 20.0;
+
+/// Accepted high-pass cutoffs (0 turns the filter off)
+const HPF_RANGE_HZ: (f32, f32) = (20.0, 400.0);
+
+const AGC_TARGET_RANGE_DB: (f32, f32) = (-40.0, -6.0);
+
+/// Gain the AGC may apply, either way
+const AGC_MAX_GAIN_DB: f32 = 18.0;
+
+/// Below this level the AGC holds its gain instead of boosting room noise
+const AGC_GATE_DBFS: f32 = -50.0;
+
+/// Attenuation at `denoise = 1` of what sits at the noise floor
+const DENOISE_MAX_DB: f32 = 24.0;
+
+/// How far above the tracked noise floor the signal counts as speech
+const DENOISE_OPEN_DB: f32 = 10.0;
+
+/// How fast the noise floor estimate may rise
+const NOISE_FLOOR_RISE_DB_PER_SEC: f32 = 3.0;
+
+/// Echo path the canceller models, from the loopback frame on
+const AEC_FILTER_MS: f32 = 20.0;
+
+/// NLMS step size
+const AEC_STEP: f32 = 0.3;
+
+/// MIC level, relative to the recent loopback peak, above which the rep counts as talking
+const AEC_DOUBLE_TALK_RATIO: f32 = 0.5;
+
+/// Release of the loopback peak the double talk check compares against; well past the
+/// filter, so an echo never outlasts the peak it came from
+const AEC_PEAK_RELEASE_MS: f32 = 200.0;
+
+/// How long adaptation stays paused after double talk
+const AEC_HANGOVER_MS: f32 = 50.0;
+
+/// Parameters of the chain
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DspParams {
+    /// High-pass cutoff in Hz, 0 when off
+    pub hpf_hz: f32,
+    pub agc: bool,
+    /// Level the AGC steers speech towards, in dBFS RMS
+    pub agc_target_db: f32,
+    /// Noise suppression depth, 0 (off) to 1
+    pub denoise: f32,
+    /// Echo cancellation against the loopback channel
+    #[serde(default)]
+    pub aec: bool,
+}
+
+impl Default for DspParams {
+    fn default() -> Self {
+        Self {
+            hpf_hz: 0.0,
+            agc: false,
+            agc_target_db: -20.0,
+            denoise: 0.0,
+            aec: false,
+        }
+    }
+}
+
+/// Fields of a `dsp` control command; unset ones are left as they are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DspUpdate {
+    pub hpf_hz: Option<f32>,
+    pub agc: Option<bool>,
+    pub agc_target_db: Option<f32>,
+    pub denoise: Option<f32>,
+    pub aec: Option<bool>,
+}
+
+impl DspParams {
+    /// These parameters with `update` applied, or an error naming the first bad field
+    pub fn apply(&self, update: DspUpdate) -> Result<Self> {
+        let in_range = |name: &str, value: f32, (min, max): (f32, f32)| {
+            if (min..=max).contains(&value) {
+                Ok(value)
+            } else {
+                Err(fail!(
+                    Config,
+                    "{} must be between {} and {}",
+                    name,
+                    min,
+                    max
+                ))
+            }
+        };
+        let mut params = *self;
+        if let Some(hz) = update.hpf_hz {
+            params.hpf_hz = if hz == 0.0 {
+                0.0
+            } else {
+                in_range("hpf_hz", hz, HPF_RANGE_HZ)?
+            };
+        }
+        if let Some(agc) = update.agc {
+            params.agc = agc;
+        }
+        if let Some(db) = update.agc_target_db {
+            params.agc_target_db = in_range("agc_target_db", db, AGC_TARGET_RANGE_DB)?;
+        }
+        if let Some(strength) = update.denoise {
+            params.denoise = in_range("denoise", strength, (0.0, 1.0))?;
+        }
+        if let Some(aec) = update.aec {
+            params.aec = aec;
+        }
+        Ok(params)
+    }
+}
+
+/// One-pole smoothing towards a target
+#[derive(Debug, Clone, Copy)]
+struct Smoothed {
+    value: f32,
+    target: f32,
+    coeff: f32,
+}
+
+impl Smoothed {
+    fn new(value: f32, coeff: f32) -> Self {
+        Self {
+            value,
+            target: value,
+            coeff,
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        self.value = self.target + (self.value - self.target) * self.coeff;
+        self.value
+    }
+}
+
+/// Per-sample coefficient of a one-pole filter with time constant `ms`
+fn one_pole(ms: f32, sample_rate: u32) -> f32 {
+    (-1.0 / (ms / 1000.0 * sample_rate as f32)).exp()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Second-order Butterworth high-pass, transposed direct form II so coefficients can
+/// change under a running signal
+#[derive(Debug, Clone, Copy, Default)]
+struct HighPass {
+    b: [f32; 3],
+    a: [f32; 2],
+    z: [f32; 2],
+    cutoff: f32,
+}
+
+impl HighPass {
+    fn set_cutoff(&mut self, hz: f32, sample_rate: u32) {
+        let w0 = 2.0 * PI * hz / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / std::f32::consts::SQRT_2;
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 + cos) / 2.0 / a0;
+        self.b = [b0, -2.0 * b0, b0];
+        self.a = [-2.0 * cos / a0, (1.0 - alpha) / a0];
+        self.cutoff = hz;
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// NLMS echo canceller; the reference history is stored twice over so the filter always
+/// reads one contiguous window
+struct EchoCanceller {
+    weights: Vec<f32>,
+    history: Vec<f32>,
+    /// Where the next reference sample goes, and where the newest window ends
+    next: usize,
+    energy: f32,
+    reference_peak: f32,
+    peak_decay: f32,
+    /// Samples adaptation stays paused for
+    hold: usize,
+    hangover: usize,
+}
+
+impl EchoCanceller {
+    fn new(sample_rate: u32) -> Self {
+        let taps = (AEC_FILTER_MS / 1000.0 * sample_rate as f32) as usize;
+        Self {
+            weights: vec![0.0; taps],
+            history: vec![0.0; taps * 2],
+            next: 0,
+            energy: 0.0,
+            reference_peak: 0.0,
+            peak_decay: one_pole(AEC_PEAK_RELEASE_MS, sample_rate),
+            hold: 0,
+            hangover: (AEC_HANGOVER_MS / 1000.0 * sample_rate as f32) as usize,
+        }
+    }
+
+    /// The echo of the loopback in `mic`, given the loopback sample of the same frame
+    fn estimate(&mut self, mic: f32, reference: f32) -> f32 {
+        let taps = self.weights.len();
+        let oldest = self.history[self.next];
+        self.energy = (self.energy + reference * reference - oldest * oldest).max(0.0);
+        self.history[self.next] = reference;
+        self.history[self.next + taps] = reference;
+        self.next = (self.next + 1) % taps;
+        // Oldest first, so weight `taps - 1` is the current frame
+        let window = &self.history[self.next..self.next + taps];
+        let echo: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+
+        self.reference_peak = reference.abs().max(self.reference_peak * self.peak_decay);
+        if mic.abs() > self.reference_peak * AEC_DOUBLE_TALK_RATIO && mic.abs() > echo.abs() {
+            self.hold = self.hangover;
+        }
+        if self.hold > 0 {
+            self.hold -= 1;
+        } else if self.energy > 1e-6 {
+            let step = AEC_STEP * (mic - echo) / (self.energy + 1e-3);
+            for (w, x) in self.weights.iter_mut().zip(window) {
+                *w += step * x;
+            }
+        }
+        echo
+    }
+}
+
+/// The chain for one MIC channel
+pub struct MicChain {
+    sample_rate: u32,
+
+    aec: EchoCanceller,
+    aec_mix: Smoothed,
+
+    hpf: HighPass,
+    hpf_cutoff: Smoothed,
+    hpf_mix: Smoothed,
+
+    denoise_strength: Smoothed,
+    envelope: f32,
+    noise_floor: f32,
+    denoise_gain: f32,
+    envelope_attack: f32,
+    envelope_release: f32,
+    floor_rise: f32,
+    gate_open: f32,
+    gate_close: f32,
+
+    agc_mix: Smoothed,
+    agc_target: Smoothed,
+    mean_square: f32,
+    agc_gain: f32,
+    level_coeff: f32,
+    agc_attack: f32,
+    agc_release: f32,
+}
+
+impl MicChain {
+    pub fn new(sample_rate: u32, params: DspParams) -> Self {
+        let smoothing = one_pole(SMOOTHING_MS, sample_rate);
+        let mut chain = Self {
+            sample_rate,
+            aec: EchoCanceller::new(sample_rate),
+            aec_mix: Smoothed::new(0.0, smoothing),
+            hpf: HighPass::default(),
+            hpf_cutoff: Smoothed::new(params.hpf_hz.max(HPF_RANGE_HZ.0), smoothing),
+            hpf_mix: Smoothed::new(0.0, smoothing),
+            denoise_strength: Smoothed::new(0.0, smoothing),
+            envelope: 0.0,
+            noise_floor: db_to_gain(-60.0),
+            denoise_gain: 1.0,
+            envelope_attack: one_pole(5.0, sample_rate),
+            envelope_release: one_pole(50.0, sample_rate),
+            floor_rise: db_to_gain(NOISE_FLOOR_RISE_DB_PER_SEC / sample_rate as f32),
+            gate_open: one_pole(5.0, sample_rate),
+            gate_close: one_pole(80.0, sample_rate),
+            agc_mix: Smoothed::new(0.0, smoothing),
+            agc_target: Smoothed::new(db_to_gain(params.agc_target_db), smoothing),
+            mean_square: 0.0,
+            agc_gain: 1.0,
+            level_coeff: one_pole(300.0, sample_rate),
+            agc_attack: one_pole(100.0, sample_rate),
+            agc_release: one_pole(1_500.0, sample_rate),
+        };
+        let cutoff = chain.hpf_cutoff.value;
+        chain.hpf.set_cutoff(cutoff, sample_rate);
+        // Start in the configured state rather than fading in at the first sample
+        chain.set(params);
+        chain.aec_mix.value = chain.aec_mix.target;
+        chain.hpf_mix.value = chain.hpf_mix.target;
+        chain.denoise_strength.value = chain.denoise_strength.target;
+        chain.agc_mix.value = chain.agc_mix.target;
+        chain
+    }
+
+    /// Move towards `params` over the next `SMOOTHING_MS`
+    pub fn set(&mut self, params: DspParams) {
+        // An off filter keeps its last cutoff while it fades out
+        if params.hpf_hz > 0.0 {
+            self.hpf_cutoff.target = params.hpf_hz;
+        }
+        self.aec_mix.target = if params.aec { 1.0 } else { 0.0 };
+        self.hpf_mix.target = if params.hpf_hz > 0.0 { 1.0 } else { 0.0 };
+        self.denoise_strength.target = params.denoise;
+        self.agc_mix.target = if params.agc { 1.0 } else { 0.0 };
+        self.agc_target.target = db_to_gain(params.agc_target_db);
+    }
+
+    /// Process MIC sample `x`, with `reference` the loopback sample of the same frame
+    pub fn process(&mut self, x: f32, reference: f32) -> f32 {
+        let mut y = x;
+
+        let aec_mix = self.aec_mix.next();
+        // Only runs while on or fading, it is by far the most expensive stage
+        if aec_mix > 1e-4 || self.aec_mix.target > 0.0 {
+            y -= aec_mix * self.aec.estimate(x, reference);
+        }
+
+        let hpf_mix = self.hpf_mix.next();
+        let cutoff = self.hpf_cutoff.next();
+        if (cutoff - self.hpf.cutoff).abs() > 0.01 {
+            self.hpf.set_cutoff(cutoff, self.sample_rate);
+        }
+        // Kept running while off so fading it back in doesn't start from a cold state
+        let filtered = self.hpf.process(y);
+        y += hpf_mix * (filtered - y);
+
+        let strength = self.denoise_strength.next();
+        let level = y.abs();
+        let envelope_coeff = if level > self.envelope {
+            self.envelope_attack
+        } else {
+            self.envelope_release
+        };
+        self.envelope = level + (self.envelope - level) * envelope_coeff;
+        self.noise_floor = if self.envelope < self.noise_floor {
+            self.envelope.max(1e-5)
+        } else {
+            self.noise_floor * self.floor_rise
+        };
+        let open = self.envelope > self.noise_floor * db_to_gain(DENOISE_OPEN_DB);
+        let gate_target = if open {
+            1.0
+        } else {
+            db_to_gain(-DENOISE_MAX_DB * strength)
+        };
+        let gate_coeff = if gate_target > self.denoise_gain {
+            self.gate_open
+        } else {
+            self.gate_close
+        };
+        self.denoise_gain = gate_target + (self.denoise_gain - gate_target) * gate_coeff;
+        y *= self.denoise_gain;
+
+        let agc_mix = self.agc_mix.next();
+        let target = self.agc_target.next();
+        self.mean_square = y * y + (self.mean_square - y * y) * self.level_coeff;
+        let rms = self.mean_square.sqrt();
+        if agc_mix > 0.0 && rms > db_to_gain(AGC_GATE_DBFS) {
+            let max = db_to_gain(AGC_MAX_GAIN_DB);
+            let wanted = (target / rms).clamp(1.0 / max, max);
+            let coeff = if wanted < self.agc_gain {
+                self.agc_attack
+            } else {
+                self.agc_release
+            };
+            self.agc_gain = wanted + (self.agc_gain - wanted) * coeff;
+        }
+        y * (1.0 + agc_mix * (self.agc_gain - 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    fn tone(freq: f32, amplitude: f32, samples: usize) -> impl Iterator<Item = f32> {
+        (0..samples).map(move |i| amplitude * (2.0 * PI * freq * i as f32 / RATE as f32).sin())
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn updates_are_validated_and_only_touch_given_fields() {
+        let params = DspParams::default()
+            .apply(DspUpdate {
+                hpf_hz: Some(80.0),
+                agc: Some(true),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            (params.hpf_hz, params.agc, params.denoise),
+            (80.0, true, 0.0)
+        );
+        assert!(params
+            .apply(DspUpdate {
+                denoise: Some(1.5),
+                ..Default::default()
+            })
+            .is_err());
+        assert!(serde_json::from_str::<DspUpdate>(r#"{"hpf":80}"#).is_err());
+        let command = r#"{"cmd":"dsp","denoise":0.25}"#;
+        assert!(matches!(
+            serde_json::from_str(command),
+            Ok(crate::control::ControlCommand::Dsp(DspUpdate {
+                denoise: Some(0.25),
+                hpf_hz: None,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn high_pass_removes_rumble_and_keeps_speech() {
+        let mut chain = MicChain::new(
+            RATE,
+            DspParams {
+                hpf_hz: 120.0,
+                ..Default::default()
+            },
+        );
+        let rumble: Vec<f32> = tone(30.0, 0.5, RATE as usize)
+            .map(|s| chain.process(s, 0.0))
+            .collect();
+        let voice: Vec<f32> = tone(1_000.0, 0.5, RATE as usize)
+            .map(|s| chain.process(s, 0.0))
+            .collect();
+        assert!(rms(&rumble[RATE as usize / 2..]) < 0.05);
+        assert!((rms(&voice[RATE as usize / 2..]) - 0.354).abs() < 0.02);
+    }
+
+    #[test]
+    fn live_changes_are_smoothed_instead_of_stepping() {
+        let mut chain = MicChain::new(RATE, DspParams::default());
+        let input: Vec<f32> = tone(440.0, 0.3, RATE as usize).collect();
+        let mut out = Vec::with_capacity(input.len());
+        for (i, &sample) in input.iter().enumerate() {
+            if i == RATE as usize / 4 {
+                chain.set(DspParams {
+                    hpf_hz: 400.0,
+                    agc: true,
+                    agc_target_db: -6.0,
+                    denoise: 1.0,
+                    aec: false,
+                });
+            }
+            out.push(chain.process(sample, 0.0));
+        }
+        // No sample-to-sample jump bigger than the tone's own slope allows for the gain
+        let max_step = 2.0 * PI * 440.0 / RATE as f32 * 0.3 * db_to_gain(AGC_MAX_GAIN_DB);
+        assert!(out
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs() <= max_step));
+        // and the AGC ends up steering towards its target
+        assert!(rms(&out[RATE as usize * 3 / 4..]) > rms(&input));
+    }
+
+    #[test]
+    fn cancels_the_echo_and_keeps_the_rep() {
+        const AEC_RATE: u32 = 16_000;
+        let aec = DspParams {
+            aec: true,
+            ..Default::default()
+        };
+        let mut chain = MicChain::new(AEC_RATE, aec);
+        // Far-end noise reaching the MIC 5ms later, 10dB down
+        let mut seed = 1u32;
+        let far: Vec<f32> = (0..AEC_RATE as usize * 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 16) as f32 / 32_768.0 - 1.0
+            })
+            .map(|s| s * 0.3)
+            .collect();
+        let delay = AEC_RATE as usize / 200;
+        let echo = |i: usize| {
+            if i >= delay {
+                far[i - delay] * 0.3
+            } else {
+                0.0
+            }
+        };
+        let out: Vec<f32> = (0..far.len())
+            .map(|i| chain.process(echo(i), far[i]))
+            .collect();
+        let echo_rms = rms(&(0..far.len()).map(echo).collect::<Vec<_>>());
+        let tail = &out[AEC_RATE as usize * 2..];
+        assert!(rms(tail) < echo_rms * 0.1, "{} vs {}", rms(tail), echo_rms);
+
+        // The rep talking over it comes through
+        let rep: Vec<f32> = tone(900.0, 0.2, AEC_RATE as usize).collect();
+        let talking: Vec<f32> = (0..rep.len())
+            .map(|i| chain.process(rep[i] + echo(i), far[i]) - rep[i])
+            .collect();
+        assert!(rms(&talking[AEC_RATE as usize / 2..]) < rms(&rep) * 0.25);
+    }
+}
@@ -0,0 +1,280 @@
+//! Communications ducking
+//! Windows turns other audio down ("reduce by 80%") while a communications stream is open,
+//! and our own MIC stream on the Communications device can be what sets it off, so the
+//! loopback records attenuated system audio. `--ducking opt-out` (the default) opts the
+//! MIC's audio session out of ducking; in every mode but `off` the render endpoints are
+//! watched for duck notifications, and each ducked stretch is marked in the timeline
+//! (`ducking` entries) and reported as `ducking` events, so level changes in the loopback
+//! can be explained afterwards.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// `--ducking`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuckingMode {
+    /// Keep the MIC stream from ducking other audio, and annotate ducking by other apps
+    OptOut,
+    /// Only annotate ducking
+    Detect,
+    Off,
+}
+
+/// A duck notification from one render endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuckEvent {
+    Ducked { communication_sessions: u32 },
+    Unducked,
+}
+
+/// Ducking seen during the session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DuckingStats {
+    pub episodes: u64,
+    pub ducked_ms: u64,
+}
+
+/// Turns notifications into ducked stretches; repeated duck notifications (the count of
+/// communication sessions changing) extend the current stretch
+#[derive(Debug, Default)]
+pub struct DuckTracker {
+    since: Option<Instant>,
+    stats: DuckingStats,
+}
+
+impl DuckTracker {
+    /// Account for `event`; returns whether a stretch starts (`Some(true)`) or ends
+    /// (`Some(false)`, with how long it lasted in `stats`)
+    pub fn observe(&mut self, event: DuckEvent, now: Instant) -> Option<bool> {
+        match (event, self.since) {
+            (DuckEvent::Ducked { .. }, None) => {
+                self.since = Some(now);
+                self.stats.episodes += 1;
+                Some(true)
+            }
+            (DuckEvent::Unducked, Some(since)) => {
+                self.since = None;
+                self.stats.ducked_ms += now.duration_since(since).as_millis() as u64;
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    /// How long the current stretch has lasted, if ducked
+    pub fn ducked_for(&self, now: Instant) -> Option<Duration> {
+        self.since.map(|since| now.duration_since(since))
+    }
+
+    /// Totals, counting a stretch still going on up to `now`
+    pub fn stats(&self, now: Instant) -> DuckingStats {
+        DuckingStats {
+            ducked_ms: self.stats.ducked_ms
+                + self.ducked_for(now).map_or(0, |d| d.as_millis() as u64),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use watch::{watch, DuckWatcher};
+
+#[cfg(windows)]
+mod watch {
+    use super::{DuckEvent, DuckingMode};
+    use crate::error::fail;
+    use anyhow::{anyhow, Context, Result};
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use tokio::sync::mpsc::UnboundedSender;
+    use windows::core::{implement, Interface, HSTRING, PCWSTR};
+    use windows::Win32::Media::Audio::{
+        eCapture, eCommunications, eConsole, eRender, IAudioSessionControl2, IAudioSessionManager2,
+        IAudioVolumeDuckNotification, IAudioVolumeDuckNotification_Impl, IMMDevice,
+        IMMDeviceEnumerator, MMDeviceEnumerator,
+    };
+    use windows::Win32::System::Com::*;
+
+    /// Keeps the notifications registered; dropping it unregisters them
+    pub struct DuckWatcher {
+        stop: Option<std_mpsc::Sender<()>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Drop for DuckWatcher {
+        fn drop(&mut self) {
+            drop(self.stop.take());
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    #[implement(IAudioVolumeDuckNotification)]
+    struct DuckCallback {
+        events: UnboundedSender<DuckEvent>,
+    }
+
+    impl IAudioVolumeDuckNotification_Impl for DuckCallback_Impl {
+        fn OnVolumeDuckNotification(
+            &self,
+            _session: &PCWSTR,
+            communication_sessions: u32,
+        ) -> windows::core::Result<()> {
+            let _ = self.events.send(DuckEvent::Ducked {
+                communication_sessions,
+            });
+            Ok(())
+        }
+
+        fn OnVolumeUnduckNotification(&self, _session: &PCWSTR) -> windows::core::Result<()> {
+            let _ = self.events.send(DuckEvent::Unducked);
+            Ok(())
+        }
+    }
+
+    /// Apply `mode` for this process and send duck notifications from the default render
+    /// endpoints to `events` until the watcher is dropped. Call it before the streams are
+    /// opened, so the opt-out is in place when the MIC's session starts.
+    pub fn watch(
+        mode: DuckingMode,
+        mic_endpoint: Option<String>,
+        events: UnboundedSender<DuckEvent>,
+    ) -> Result<DuckWatcher> {
+        let (ready_tx, ready_rx) = std_mpsc::channel();
+        let (stop_tx, stop_rx) = std_mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("ducking".to_string())
+            .spawn(move || unsafe {
+                if let Err(e) = CoInitializeEx(None, COINIT_MULTITHREADED).ok() {
+                    let _ = ready_tx.send(Err(anyhow!(e).context("Failed to initialize COM")));
+                    return;
+                }
+                match register(mode, mic_endpoint.as_deref(), events) {
+                    Ok(registrations) => {
+                        let _ = ready_tx.send(Ok(()));
+                        // Notifications arrive on COM worker threads
+                        let _ = stop_rx.recv();
+                        for (manager, callback) in registrations {
+                            let _ = manager.UnregisterDuckNotification(&callback);
+                        }
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+                CoUninitialize();
+            })
+            .context("Failed to start ducking watcher")?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| fail!(Device, "Ducking watcher exited during setup"))??;
+        Ok(DuckWatcher {
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+
+    unsafe fn register(
+        mode: DuckingMode,
+        mic_endpoint: Option<&str>,
+        events: UnboundedSender<DuckEvent>,
+    ) -> Result<Vec<(IAudioSessionManager2, IAudioVolumeDuckNotification)>> {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+
+        if mode == DuckingMode::OptOut {
+            let mic = match mic_endpoint {
+                Some(id) => enumerator.GetDevice(&HSTRING::from(id)),
+                None => enumerator.GetDefaultAudioEndpoint(eCapture, eConsole),
+            }
+            .context("Failed to open the MIC endpoint")?;
+            // Detection still works without it
+            if let Err(e) = opt_out(&mic) {
+                eprintln!(
+                    "[win-audio-capture] Warning: Failed to opt out of ducking: {:#}",
+                    e
+                );
+            }
+        }
+
+        // The loopback may record either default, and either can be ducked
+        let mut registrations = Vec::new();
+        let mut seen = Vec::new();
+        for role in [eConsole, eCommunications] {
+            let Ok(device) = enumerator.GetDefaultAudioEndpoint(eRender, role) else {
+                continue;
+            };
+            let id = device.GetId()?;
+            let key = id.to_string().unwrap_or_default();
+            CoTaskMemFree(Some(id.0 as *const _));
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key);
+            let manager: IAudioSessionManager2 = device
+                .Activate(CLSCTX_ALL, None)
+                .context("Failed to activate the session manager")?;
+            let callback: IAudioVolumeDuckNotification = DuckCallback {
+                events: events.clone(),
+            }
+            .into();
+            manager
+                .RegisterDuckNotification(PCWSTR::null(), &callback)
+                .context("Failed to register for duck notifications")?;
+            registrations.push((manager, callback));
+        }
+        Ok(registrations)
+    }
+
+    /// Opt this process's default session on `device` out of ducking
+    unsafe fn opt_out(device: &IMMDevice) -> Result<()> {
+        let manager: IAudioSessionManager2 = device.Activate(CLSCTX_ALL, None)?;
+        let control = manager
+            .GetAudioSessionControl(None, 0)?
+            .cast::<IAudioSessionControl2>()?;
+        control.SetDuckingPreference(true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_ducked_stretches() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut tracker = DuckTracker::default();
+        let ducked = DuckEvent::Ducked {
+            communication_sessions: 1,
+        };
+
+        assert_eq!(tracker.observe(DuckEvent::Unducked, at(0)), None);
+        assert_eq!(tracker.observe(ducked, at(100)), Some(true));
+        // Another call joining doesn't start a new stretch
+        assert_eq!(
+            tracker.observe(
+                DuckEvent::Ducked {
+                    communication_sessions: 2
+                },
+                at(200)
+            ),
+            None
+        );
+        assert_eq!(tracker.observe(DuckEvent::Unducked, at(1_100)), Some(false));
+        assert_eq!(tracker.observe(ducked, at(2_000)), Some(true));
+        assert_eq!(
+            tracker.stats(at(2_500)),
+            DuckingStats {
+                episodes: 2,
+                ducked_ms: 1_500
+            }
+        );
+    }
+}
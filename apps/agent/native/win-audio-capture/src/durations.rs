@@ -0,0 +1,171 @@
+//! Duration accounting
+//! The length of the recording is not the length of the call: clock time the mixer skipped
+//! (a suspend, a stall) is in neither the file nor the audio, pauses are recorded as
+//! silence that nobody said, compacted and trimmed silence is left out of the file, and
+//! redacted stretches are in the file but carry nothing. Billing and analytics get each
+//! of these from `Durations`, in `stopped` and in the timeline file, rather than from the
+//! file's size.
+//!
+//! Later redactions of the file (`redact`) bring `redacted_ms` in the timeline up to date.
+
+use crate::timeline;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+/// Frame counts (per channel) of a finished session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionFrames {
+    /// Frames the mixer produced, recorded silence included
+    pub mixed: u64,
+    /// Frames recorded as silence while paused
+    pub paused: u64,
+    /// Clock time skipped instead of mixed
+    pub skipped: u64,
+    /// Silence left out of the file and trimmed off its ends
+    pub removed: u64,
+    /// Frames of the file silenced by redaction
+    pub redacted: u64,
+    /// Frames in the finished file
+    pub file: u64,
+}
+
+/// Where the session's time went, in milliseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Durations {
+    /// From the start of capture to the stop
+    pub wall_ms: u64,
+    /// Audio captured from the devices
+    pub captured_ms: u64,
+    /// Silence recorded in place of audio, across the whole stream (pauses)
+    pub silence_inserted_ms: u64,
+    /// Clock time that went by without being recorded
+    pub skipped_ms: u64,
+    /// Silence compacted out of the file and trimmed off its ends
+    pub removed_ms: u64,
+    /// Audio in the file silenced by redaction
+    pub redacted_ms: u64,
+    /// Length of the file
+    pub file_ms: u64,
+}
+
+impl Durations {
+    pub fn new(wall: Duration, frames: SessionFrames, sample_rate: u32) -> Self {
+        let ms = |frames: u64| frames * 1000 / sample_rate as u64;
+        Self {
+            wall_ms: wall.as_millis() as u64,
+            captured_ms: ms(frames.mixed.saturating_sub(frames.paused)),
+            silence_inserted_ms: ms(frames.paused),
+            skipped_ms: ms(frames.skipped),
+            removed_ms: ms(frames.removed),
+            redacted_ms: ms(frames.redacted),
+            file_ms: ms(frames.file),
+        }
+    }
+}
+
+/// Frames covered by `[start, end)` spans, counting overlaps once
+pub fn covered_frames(spans: impl IntoIterator<Item = (u64, u64)>) -> u64 {
+    let mut spans: Vec<(u64, u64)> = spans.into_iter().filter(|(s, e)| s < e).collect();
+    spans.sort_unstable();
+    let mut covered = 0;
+    let mut reached = 0;
+    for (start, end) in spans {
+        let start = start.max(reached);
+        if end > start {
+            covered += end - start;
+            reached = end;
+        }
+    }
+    covered
+}
+
+/// Bring `redacted_ms` in the timeline file next to a recording up to date with every
+/// redaction logged there. Timelines from before duration accounting are left as they are.
+pub fn record_redactions(recording_path: &Path) -> Result<()> {
+    timeline::edit(recording_path, |file| {
+        let Some(sample_rate) = file.get("sample_rate").and_then(Value::as_u64) else {
+            return Ok(());
+        };
+        let spans = file
+            .get("redactions")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|redaction| {
+                Some((
+                    redaction.get("start_frame")?.as_u64()?,
+                    redaction.get("end_frame")?.as_u64()?,
+                ))
+            });
+        let redacted_ms = covered_frames(spans) * 1000 / sample_rate.max(1);
+        if let Some(durations) = file.get_mut("durations").and_then(Value::as_object_mut) {
+            durations.insert("redacted_ms".to_string(), redacted_ms.into());
+        }
+        Ok(())
+    })
+    .context("Failed to update the redacted duration")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_captured_audio_from_inserted_and_skipped_time() {
+        let frames = SessionFrames {
+            mixed: 60_000,
+            paused: 12_000,
+            skipped: 3_000,
+            removed: 6_000,
+            redacted: 1_500,
+            file: 54_000,
+        };
+        let durations = Durations::new(Duration::from_millis(63_250), frames, 1_000);
+        assert_eq!(
+            durations,
+            Durations {
+                wall_ms: 63_250,
+                captured_ms: 48_000,
+                silence_inserted_ms: 12_000,
+                skipped_ms: 3_000,
+                removed_ms: 6_000,
+                redacted_ms: 1_500,
+                file_ms: 54_000,
+            }
+        );
+    }
+
+    #[test]
+    fn overlapping_redactions_count_once() {
+        assert_eq!(covered_frames([(0, 10), (5, 15), (20, 25), (22, 23)]), 20);
+        assert_eq!(covered_frames([(7, 7), (9, 3)]), 0);
+    }
+
+    #[test]
+    fn later_redactions_update_the_timeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("call.wav");
+        let sidecar = timeline::sidecar_path(&recording, "timeline.json");
+        std::fs::write(
+            &sidecar,
+            serde_json::to_vec(&serde_json::json!({
+                "sample_rate": 1_000,
+                "durations": Durations::default(),
+                "entries": [],
+                "redactions": [
+                    { "start_frame": 1_000, "end_frame": 3_000 },
+                    { "start_frame": 2_000, "end_frame": 4_000 },
+                ],
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+
+        record_redactions(&recording).unwrap();
+        let saved = timeline::load(&recording).unwrap().unwrap();
+        assert_eq!(saved.durations.unwrap().redacted_ms, 3_000);
+    }
+}
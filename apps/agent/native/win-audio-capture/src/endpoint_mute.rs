@@ -0,0 +1,114 @@
+//! Capture endpoint mute notifications
+//! Watches the MIC's capture endpoint (the Console default when its ID isn't known)
+//! through `IAudioEndpointVolume` and forwards its mute state to the session loop: once when the watch starts, then on
+//! every change.
+
+#![cfg(windows)]
+
+use crate::error::fail;
+use anyhow::{anyhow, Context, Result};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use windows::core::{implement, HSTRING};
+use windows::Win32::Media::Audio::Endpoints::{
+    IAudioEndpointVolume, IAudioEndpointVolumeCallback, IAudioEndpointVolumeCallback_Impl,
+};
+use windows::Win32::Media::Audio::{
+    eCapture, eConsole, IMMDeviceEnumerator, MMDeviceEnumerator, AUDIO_VOLUME_NOTIFICATION_DATA,
+};
+use windows::Win32::System::Com::*;
+
+/// Keeps the registration alive; dropping it unregisters the callback
+pub struct MuteWatcher {
+    stop: Option<std_mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for MuteWatcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[implement(IAudioEndpointVolumeCallback)]
+struct MuteCallback {
+    muted: UnboundedSender<bool>,
+}
+
+impl IAudioEndpointVolumeCallback_Impl for MuteCallback_Impl {
+    fn OnNotify(&self, notify: *mut AUDIO_VOLUME_NOTIFICATION_DATA) -> windows::core::Result<()> {
+        // Volume changes arrive here too; the session loop ignores repeats
+        if let Some(notify) = unsafe { notify.as_ref() } {
+            let _ = self.muted.send(notify.bMuted.as_bool());
+        }
+        Ok(())
+    }
+}
+
+/// Send the mute state of endpoint `endpoint_id` to `muted` until the watcher is dropped
+pub fn watch(muted: UnboundedSender<bool>, endpoint_id: Option<String>) -> Result<MuteWatcher> {
+    let (ready_tx, ready_rx) = std_mpsc::channel();
+    let (stop_tx, stop_rx) = std_mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name("endpoint-mute".to_string())
+        .spawn(move || unsafe {
+            if let Err(e) = CoInitializeEx(None, COINIT_MULTITHREADED).ok() {
+                let _ = ready_tx.send(Err(anyhow!(e).context("Failed to initialize COM")));
+                return;
+            }
+            match register(muted, endpoint_id.as_deref()) {
+                Ok((volume, callback)) => {
+                    let _ = ready_tx.send(Ok(()));
+                    // Notifications arrive on COM worker threads; this one only holds the
+                    // registration until the watcher is dropped
+                    let _ = stop_rx.recv();
+                    let _ = volume.UnregisterControlChangeNotify(&callback);
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            }
+            CoUninitialize();
+        })
+        .context("Failed to start endpoint mute watcher")?;
+
+    ready_rx
+        .recv()
+        .map_err(|_| fail!(Device, "Endpoint mute watcher exited during setup"))??;
+    Ok(MuteWatcher {
+        stop: Some(stop_tx),
+        thread: Some(thread),
+    })
+}
+
+unsafe fn register(
+    muted: UnboundedSender<bool>,
+    endpoint_id: Option<&str>,
+) -> Result<(IAudioEndpointVolume, IAudioEndpointVolumeCallback)> {
+    let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+        .context("Failed to create device enumerator")?;
+    let device = match endpoint_id {
+        Some(id) => enumerator
+            .GetDevice(&HSTRING::from(id))
+            .context("Failed to open the MIC's capture endpoint")?,
+        None => enumerator
+            .GetDefaultAudioEndpoint(eCapture, eConsole)
+            .context("Failed to get default capture endpoint")?,
+    };
+    let volume: IAudioEndpointVolume = device
+        .Activate(CLSCTX_ALL, None)
+        .context("Failed to activate endpoint volume")?;
+
+    let initial = volume.GetMute().context("Failed to read mute state")?;
+    let _ = muted.send(initial.as_bool());
+
+    let callback: IAudioEndpointVolumeCallback = MuteCallback { muted }.into();
+    volume
+        .RegisterControlChangeNotify(&callback)
+        .context("Failed to register for mute notifications")?;
+    Ok((volume, callback))
+}
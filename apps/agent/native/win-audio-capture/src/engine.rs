@@ -0,0 +1,334 @@
+//! Real-time mixing engine
+//! The mixer runs on its own thread, fed by wait-free SPSC rings that the device callbacks
+//! push into, and hands interleaved i16 blocks to the async side of the pipeline. Nothing
+//! on this path waits on the runtime, the sinks or stdout.
+
+use crate::anonymize::PitchShifter;
+use crate::budget::{Pool, Pressure};
+use crate::dsp::{DspParams, MicChain};
+use crate::etw;
+use crate::health::LiveCounters;
+use crate::limiter::{Limiter, LimiterMode};
+use crate::loopback_role::LoopbackLayout;
+use crate::mixer::{Clock, FollowerSource, Mixer, MixerStats, SampleSource, Source};
+use crate::notes::{LoudnessGuard, NotePlayer};
+use crate::power::{self, PowerProfile};
+use crate::quantize::{Dither, Quantizer};
+use crate::trace;
+use rtrb::{Consumer, RingBuffer};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+
+/// Mono samples queued per source between its device callback and the mixer (1s @ 48kHz)
+pub const SOURCE_QUEUE_CAPACITY: usize = 48_000;
+
+/// Interleaved i16 samples produced by one mix round: left = MIC, right = loopback, plus
+/// the extra loopback endpoint as a third channel with `LoopbackLayout::Separate` and the
+/// audio notes as the last one with `--notes channel`
+pub type AudioBlock = Arc<[i16]>;
+
+/// How the mixer thread runs and quantizes
+#[derive(Debug, Clone, Copy)]
+pub struct EngineConfig {
+    pub sample_rate: u32,
+    /// How often the thread wakes up and at what priority it runs
+    pub profile: PowerProfile,
+    pub limiter: LimiterMode,
+    pub dither: Dither,
+    /// How the extra loopback endpoint, if any, is combined with the mix
+    pub extra_layout: LoopbackLayout,
+    /// Pitch shift in semitones applied to the loopback channels, if anonymizing
+    pub anonymize: Option<f64>,
+    /// Initial MIC processing parameters
+    pub dsp: DspParams,
+    /// Record a channel for audio notes
+    pub notes_channel: bool,
+}
+
+impl EngineConfig {
+    /// Channels per frame in the blocks the engine produces
+    pub fn channels(&self, has_extra: bool) -> usize {
+        let loopback = if has_extra && self.extra_layout == LoopbackLayout::Separate {
+            2
+        } else {
+            1
+        };
+        1 + loopback + self.notes_channel as usize
+    }
+}
+
+/// Rings the mixer thread reads from
+pub struct EngineSources {
+    pub mic: Consumer<f32>,
+    pub loopback: Consumer<f32>,
+    /// Second loopback endpoint, following the mixer's pace
+    pub extra_loopback: Option<Consumer<f32>>,
+    /// Replacement MIC rings, sent when the MIC stream is rebuilt (e.g. after resume)
+    pub mic_replacements: std::sync::mpsc::Receiver<Consumer<f32>>,
+    /// While set the MIC channel is recorded as silence
+    pub mic_muted: Arc<AtomicBool>,
+    /// New MIC processing parameters, picked up between mix rounds
+    pub dsp_updates: std::sync::mpsc::Receiver<DspParams>,
+    /// Frames to hold a source back by, for latency compensation
+    pub delays: std::sync::mpsc::Receiver<(Source, usize)>,
+    /// Audio notes for the notes channel; silence there without it
+    pub notes: Option<NotePlayer>,
+    /// Where the mixer's counters are published after every round, for the heartbeat
+    pub counters: Arc<LiveCounters>,
+    /// Where full queues are counted against the memory budget
+    pub pressure: Arc<Pressure>,
+}
+
+/// Stretch of clock time the mixer skipped instead of mixing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// Session sample position the gap sits at
+    pub sample_position: u64,
+    pub skipped_frames: u64,
+}
+
+/// Create the ring that carries one source's mono samples to the mixer
+pub fn source_queue() -> (rtrb::Producer<f32>, Consumer<f32>) {
+    RingBuffer::new(SOURCE_QUEUE_CAPACITY)
+}
+
+impl SampleSource for Consumer<f32> {
+    fn try_next(&mut self) -> Option<f32> {
+        self.pop().ok()
+    }
+
+    fn backlog(&self) -> usize {
+        self.slots()
+    }
+}
+
+/// Start the mixer thread. It runs until `running` is cleared (mixing whatever is due at
+/// that moment one last time) or the block receiver goes away, then returns its stats.
+/// Skipped clock jumps are reported on `gaps`. With `compare`, every block also goes out
+/// with the unprocessed MIC in front of its channels (see `--dsp-compare`); those blocks
+/// are dropped rather than waited for when the receiver falls behind.
+pub fn spawn<C>(
+    clock: C,
+    config: EngineConfig,
+    sources: EngineSources,
+    running: Arc<AtomicBool>,
+    blocks: mpsc::Sender<AudioBlock>,
+    gaps: mpsc::UnboundedSender<Gap>,
+    compare: Option<mpsc::Sender<AudioBlock>>,
+) -> std::io::Result<thread::JoinHandle<MixerStats>>
+where
+    C: Clock + Send + 'static,
+{
+    thread::Builder::new()
+        .name("mixer".to_string())
+        .spawn(move || {
+            let channels = config.channels(sources.extra_loopback.is_some());
+            let EngineConfig {
+                sample_rate,
+                profile,
+                limiter,
+                dither,
+                extra_layout,
+                anonymize,
+                dsp,
+                notes_channel,
+            } = config;
+            let separate_extra =
+                sources.extra_loopback.is_some() && extra_layout == LoopbackLayout::Separate;
+            if profile.lower_priority {
+                power::lower_current_thread_priority();
+            }
+
+            let EngineSources {
+                mut mic,
+                mut loopback,
+                extra_loopback,
+                mic_replacements,
+                mic_muted,
+                dsp_updates,
+                delays,
+                mut notes,
+                counters,
+                pressure,
+            } = sources;
+            let mut mixer = Mixer::new(clock, sample_rate);
+            let mut extra = extra_loopback.map(|ring| FollowerSource::new(ring, sample_rate));
+            let mut limiters: Vec<Limiter> = (0..channels)
+                .map(|_| Limiter::new(limiter, sample_rate))
+                .collect();
+            let mut quantizer = Quantizer::new(dither, channels);
+            let mut note_guard = LoudnessGuard::new(sample_rate);
+            let mut mic_chain = profile
+                .optional_dsp
+                .then(|| MicChain::new(sample_rate, dsp));
+            let mut raw_quantizer = Quantizer::new(dither, 1);
+            let mut raw_mic: Vec<i16> = Vec::new();
+            let mut compare_block: Vec<i16> = Vec::new();
+            let mut compare_drops: u64 = 0;
+            // One per loopback channel (right, and the third one if there is one)
+            let mut shifters: Vec<PitchShifter> = anonymize
+                .map(|semitones| {
+                    (0..1 + separate_extra as usize)
+                        .map(|_| PitchShifter::new(semitones, sample_rate))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let mut mixed: Vec<[f32; 2]> = Vec::with_capacity(sample_rate as usize / 10);
+            let mut extra_mixed: Vec<f32> = Vec::with_capacity(sample_rate as usize / 10);
+            let mut block: Vec<i16> = Vec::with_capacity(sample_rate as usize / 10 * channels);
+
+            loop {
+                let stopping = !running.load(Ordering::SeqCst);
+                let round = trace::span("mix_round");
+
+                if let Some(replacement) = mic_replacements.try_iter().last() {
+                    mic = replacement;
+                    mixer.reset_drift(Source::Mic);
+                }
+                for (source, frames) in delays.try_iter() {
+                    mixer.delay(source, frames);
+                    // The extra endpoint runs through the loopback path as well
+                    if let (Source::Loopback, Some(extra)) = (source, &mut extra) {
+                        extra.delay(frames);
+                    }
+                }
+                if let Some(params) = dsp_updates.try_iter().last() {
+                    if let Some(chain) = &mut mic_chain {
+                        chain.set(params);
+                    }
+                }
+
+                mixed.clear();
+                let before = mixer.stats();
+                let skipped_before = before.skipped_frames;
+                mixer.mix_due(&mut mic, &mut loopback, &mut mixed);
+                let stats = mixer.stats();
+                counters.publish(&stats);
+                if etw::enabled(etw::LEVEL_WARNING, etw::KEYWORD_GLITCH) {
+                    report_glitches(&before, &stats);
+                }
+                if stats.skipped_frames > skipped_before {
+                    if let Some(extra) = &mut extra {
+                        extra.drain();
+                    }
+                    let _ = gaps.send(Gap {
+                        sample_position: stats.frames,
+                        skipped_frames: stats.skipped_frames - skipped_before,
+                    });
+                }
+                if !mixed.is_empty() {
+                    extra_mixed.clear();
+                    if let Some(extra) = &mut extra {
+                        extra.follow(mixed.len(), &mut extra_mixed);
+                    }
+                    block.clear();
+                    raw_mic.clear();
+                    let muted = mic_muted.load(Ordering::Relaxed);
+                    for (i, &[mut mic_sample, mut loopback_sample]) in mixed.iter().enumerate() {
+                        let raw_sample = mic_sample;
+                        if let Some(chain) = &mut mic_chain {
+                            mic_sample = chain.process(mic_sample, loopback_sample);
+                        }
+                        if muted {
+                            mic_sample = 0.0;
+                        }
+                        if compare.is_some() {
+                            let raw_sample = if muted { 0.0 } else { raw_sample };
+                            raw_mic.push(raw_quantizer.quantize(0, raw_sample));
+                        }
+                        let extra_sample = extra_mixed.get(i).copied();
+                        if extra_layout == LoopbackLayout::Mix {
+                            loopback_sample += extra_sample.unwrap_or(0.0);
+                        }
+                        if let Some(shifter) = shifters.first_mut() {
+                            loopback_sample = shifter.process(loopback_sample);
+                        }
+                        block.push(quantizer.quantize(0, limiters[0].process(mic_sample)));
+                        block.push(quantizer.quantize(1, limiters[1].process(loopback_sample)));
+                        let mut separate_sample = 0.0;
+                        if separate_extra {
+                            separate_sample = extra_sample.unwrap_or(0.0);
+                            if let Some(shifter) = shifters.get_mut(1) {
+                                separate_sample = shifter.process(separate_sample);
+                            }
+                            block.push(quantizer.quantize(2, limiters[2].process(separate_sample)));
+                        }
+                        if notes_channel {
+                            let note = notes.as_mut().map_or(0.0, NotePlayer::next_sample);
+                            let voices = [mic_sample, loopback_sample, separate_sample];
+                            let note = note_guard.process(note, &voices);
+                            let last = channels - 1;
+                            block.push(quantizer.quantize(last, limiters[last].process(note)));
+                        }
+                    }
+                    if let Some(compare) = &compare {
+                        compare_block.clear();
+                        for (&raw, frame) in raw_mic.iter().zip(block.chunks_exact(channels)) {
+                            compare_block.push(raw);
+                            compare_block.extend_from_slice(frame);
+                        }
+                        if compare
+                            .try_send(AudioBlock::from(compare_block.as_slice()))
+                            .is_err()
+                        {
+                            compare_drops += 1;
+                            pressure.hit(Pool::Compare);
+                        }
+                    }
+                    if blocks.capacity() == 0 {
+                        pressure.hit(Pool::Mixer);
+                    }
+                    if blocks
+                        .blocking_send(AudioBlock::from(block.as_slice()))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+
+                drop(round);
+                if stopping {
+                    break;
+                }
+                // Sleep until more frames are due instead of spinning
+                thread::sleep(profile.mixer_tick);
+            }
+
+            if compare_drops > 0 {
+                eprintln!(
+                    "[win-audio-capture] Warning: {} blocks dropped from the DSP comparison file",
+                    compare_drops
+                );
+            }
+            mixer.stats()
+        })
+}
+
+/// Underruns and overruns of one mix round, as an ETW glitch event
+fn report_glitches(before: &MixerStats, after: &MixerStats) {
+    let underruns = [
+        after.mic_underruns - before.mic_underruns,
+        after.loopback_underruns - before.loopback_underruns,
+    ];
+    let overruns = [
+        after.mic_overruns - before.mic_overruns,
+        after.loopback_overruns - before.loopback_overruns,
+    ];
+    if underruns == [0, 0] && overruns == [0, 0] {
+        return;
+    }
+    etw::write(
+        etw::LEVEL_WARNING,
+        etw::KEYWORD_GLITCH,
+        "glitch",
+        &serde_json::json!({
+            "sample_position": after.frames,
+            "mic_underruns": underruns[0],
+            "loopback_underruns": underruns[1],
+            "mic_overruns": overruns[0],
+            "loopback_overruns": overruns[1],
+        }),
+    );
+}
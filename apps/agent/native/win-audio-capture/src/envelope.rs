@@ -0,0 +1,808 @@
+//! Session encryption envelope
+//! `--wrap-key tenant.pem` seals the finished recording for the backend. A random
+//! per-session AES-256-GCM data key encrypts the file, and only that key, wrapped with the
+//! tenant's public key (RSA-OAEP-SHA256, or ECIES on P-256), travels with it in the
+//! container header. The laptop never holds a secret the backend has too: the backend's KMS
+//! unwraps the data key with the private half.
+//!
+//! The key is wrapped when the session starts, so a bad key fails before anything is
+//! recorded, and the file is sealed in place once it is final (trimmed, cue points written),
+//! before it is published or spooled. Other sinks are sealed on their own terms with
+//! `SealedSink`, which wraps any `RecordingSink` and seals its file under a data key of its
+//! own once it is finalized, whatever container it writes. The container is
+//!
+//! ```text
+//! "SELLYENC" | header length (u32 LE) | header JSON | chunks
+//! ```
+//!
+//! where each chunk is `CHUNK_SIZE` bytes of the recording (the last one shorter) followed
+//! by its 16-byte GCM tag. Chunk `i` uses the nonce `nonce_prefix || i` (u32 BE) and the
+//! SHA-256 of the header bytes as associated data, so chunks can't be reordered or moved
+//! between files, and the header's `plaintext_bytes` exposes a truncated one.
+//!
+//! ECIES: an ephemeral P-256 key agrees a secret `Z` with the tenant key; the key-encryption
+//! key is `SHA-256(Z || ephemeral || "selly-envelope-v1")`, with `ephemeral` the uncompressed
+//! point stored in the header, and it seals the data key with AES-256-GCM under a zero nonce.
+
+use crate::error::fail;
+use crate::progress::{Reading, Report};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Start of every sealed file
+pub const MAGIC: &[u8; 8] = b"SELLYENC";
+
+/// Container version written into the header
+pub const VERSION: u32 = 1;
+
+/// Plaintext bytes per chunk
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+/// Appended to the ECDH secret when deriving the key-encryption key
+pub const KDF_LABEL: &[u8] = b"selly-envelope-v1";
+
+/// How the data key is wrapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyWrap {
+    #[serde(rename = "RSA-OAEP-256")]
+    RsaOaep256,
+    #[serde(rename = "ECIES-P256-SHA256-A256GCM")]
+    EciesP256,
+}
+
+/// The wrapped data key, as stored in the header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub wrap: KeyWrap,
+    /// SHA-256 of the tenant key's SubjectPublicKeyInfo, so the backend can pick the key
+    pub key_id: String,
+    /// Hex
+    pub wrapped: String,
+    /// Ephemeral public point (ECIES), hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<String>,
+}
+
+/// Container header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub session: String,
+    pub cipher: String,
+    pub chunk_size: usize,
+    /// Hex
+    pub nonce_prefix: String,
+    pub plaintext_bytes: u64,
+    pub key: WrappedKey,
+}
+
+/// Authenticated encryption of one chunk
+pub trait Seal {
+    /// Ciphertext followed by the tag
+    fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Nonce of chunk `index`
+pub fn chunk_nonce(prefix: &[u8; 8], index: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+/// Write `header` and the sealed chunks of `input` to `output`; returns the bytes written
+pub fn write_container<S: Seal>(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    sealer: &S,
+) -> Result<u64> {
+    let prefix: [u8; 8] = hex_decode(&header.nonce_prefix)
+        .and_then(|prefix| prefix.try_into().ok())
+        .ok_or_else(|| fail!(Format, "Invalid nonce prefix"))?;
+    let header_json = serde_json::to_vec(header)?;
+    let aad = crate::sha256::digest(&header_json);
+    output.write_all(MAGIC)?;
+    output.write_all(&(header_json.len() as u32).to_le_bytes())?;
+    output.write_all(&header_json)?;
+    let mut written = (MAGIC.len() + 4 + header_json.len()) as u64;
+
+    let mut chunk = vec![0u8; header.chunk_size];
+    let mut plaintext_bytes = 0u64;
+    for index in 0u32.. {
+        let len = read_full(input, &mut chunk)?;
+        if len == 0 {
+            break;
+        }
+        let sealed = sealer.seal(&chunk_nonce(&prefix, index), &aad, &chunk[..len])?;
+        output.write_all(&sealed)?;
+        written += sealed.len() as u64;
+        plaintext_bytes += len as u64;
+        if len < chunk.len() {
+            break;
+        }
+    }
+    if plaintext_bytes != header.plaintext_bytes {
+        bail!(fail!(
+            Format,
+            "Recording changed while it was sealed ({} bytes, expected {})",
+            plaintext_bytes,
+            header.plaintext_bytes
+        ));
+    }
+    output.flush()?;
+    Ok(written)
+}
+
+/// Replace the file at `path` with its sealed container
+pub fn seal_in_place<S: Seal>(
+    path: &Path,
+    mut header: Header,
+    sealer: &S,
+    progress: Report,
+) -> Result<u64> {
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".sealed.partial");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let input = File::open(path).context("Failed to open recording for sealing")?;
+    header.plaintext_bytes = input.metadata()?.len();
+    let written = (|| {
+        let mut output = BufWriter::new(File::create(&tmp_path)?);
+        let mut input = BufReader::new(Reading::new(input, header.plaintext_bytes, progress));
+        let written = write_container(&mut input, &mut output, &header, sealer)?;
+        output.into_inner()?.sync_all()?;
+        Ok::<_, anyhow::Error>(written)
+    })();
+    match written {
+        Ok(written) => {
+            std::fs::rename(&tmp_path, path).context("Failed to replace recording")?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            Err(e.context("Failed to seal recording"))
+        }
+    }
+}
+
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// The tenant public key at `path` as SubjectPublicKeyInfo DER, from a `PUBLIC KEY` PEM
+/// file or raw DER
+pub fn read_public_key(path: &Path) -> Result<Vec<u8>> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Failed to read wrap key {}", path.display()))?;
+    parse_public_key(&contents).with_context(|| format!("Invalid wrap key {}", path.display()))
+}
+
+fn parse_public_key(contents: &[u8]) -> Result<Vec<u8>> {
+    if contents.first() == Some(&0x30) {
+        return Ok(contents.to_vec());
+    }
+    let text = std::str::from_utf8(contents).context("Neither PEM nor DER")?;
+    if text.contains("-----BEGIN RSA PUBLIC KEY-----") {
+        bail!(fail!(
+            Format,
+            "PKCS#1 keys aren't supported, export the key as `PUBLIC KEY` (SubjectPublicKeyInfo)"
+        ));
+    }
+    let body = text
+        .split("-----BEGIN PUBLIC KEY-----")
+        .nth(1)
+        .and_then(|rest| rest.split("-----END PUBLIC KEY-----").next())
+        .ok_or_else(|| fail!(Format, "No `PUBLIC KEY` block"))?;
+    base64_decode(body).ok_or_else(|| fail!(Format, "Bad base64 in the `PUBLIC KEY` block"))
+}
+
+/// Standard base64, ignoring whitespace
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut acc, mut padding) = (0u32, 0u32, 0usize);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            _ => return None,
+        };
+        if padding > 0 {
+            return None;
+        }
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    (padding <= 2).then_some(out)
+}
+
+/// Standard base64 with padding
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |word, (i, &b)| word | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(word >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(windows)]
+pub use cng::{Envelope, SealedSink};
+
+#[cfg(windows)]
+mod cng {
+    use super::{hex, Header, KeyWrap, Seal, WrappedKey, KDF_LABEL, NONCE_LEN, TAG_LEN};
+    use crate::error::fail;
+    use crate::sink::{RecordingSink, SinkSummary};
+    use anyhow::{anyhow, bail, Context, Result};
+    use serde::Serialize;
+    use std::ffi::c_void;
+    use std::path::Path;
+    use windows::core::PCWSTR;
+    use windows::Win32::Security::Cryptography::*;
+
+    /// Per-session data key and its wrapped form
+    pub struct Envelope {
+        session: String,
+        cipher: AesGcm,
+        nonce_prefix: [u8; 8],
+        key: WrappedKey,
+    }
+
+    /// What the `stopped` event reports about the sealing
+    #[derive(Debug, Serialize)]
+    pub struct Sealed<'a> {
+        pub wrap: KeyWrap,
+        pub key_id: &'a str,
+        pub bytes: u64,
+    }
+
+    impl Envelope {
+        /// Generate the data key and wrap it with the tenant key `public_key` (DER)
+        pub fn new(session: &str, public_key: &[u8]) -> Result<Self> {
+            let mut data_key = [0u8; 32];
+            let mut nonce_prefix = [0u8; 8];
+            random(&mut data_key)?;
+            random(&mut nonce_prefix)?;
+            let cipher = AesGcm::new(&data_key);
+            let key = wrap(public_key, &data_key);
+            data_key.fill(0);
+            Ok(Self {
+                session: session.to_string(),
+                cipher: cipher.context("Failed to set up the data key")?,
+                nonce_prefix,
+                key: key?,
+            })
+        }
+
+        pub fn key_id(&self) -> &str {
+            &self.key.key_id
+        }
+
+        pub fn wrap(&self) -> KeyWrap {
+            self.key.wrap
+        }
+
+        /// Seal the finished recording at `path` in place
+        pub fn seal_file(&self, path: &Path, progress: super::Report) -> Result<Sealed<'_>> {
+            let header = Header {
+                version: super::VERSION,
+                session: self.session.clone(),
+                cipher: "AES-256-GCM".to_string(),
+                chunk_size: super::CHUNK_SIZE,
+                nonce_prefix: hex(&self.nonce_prefix),
+                plaintext_bytes: 0,
+                key: self.key.clone(),
+            };
+            let bytes = super::seal_in_place(path, header, &self.cipher, progress)?;
+            Ok(Sealed {
+                wrap: self.key.wrap,
+                key_id: &self.key.key_id,
+                bytes,
+            })
+        }
+    }
+
+    /// Sink whose file is sealed for the tenant key once the wrapped sink finalizes it
+    pub struct SealedSink {
+        inner: Box<dyn RecordingSink>,
+        session: String,
+        public_key: Vec<u8>,
+    }
+
+    impl SealedSink {
+        /// Wrap `inner`; `public_key` (DER) is only used once it finalizes, so try it with
+        /// `Envelope::new` first
+        pub fn new(inner: Box<dyn RecordingSink>, session: &str, public_key: Vec<u8>) -> Self {
+            Self {
+                inner,
+                session: session.to_string(),
+                public_key,
+            }
+        }
+    }
+
+    impl RecordingSink for SealedSink {
+        fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+            self.inner.write_samples(samples)
+        }
+
+        fn finalize(self: Box<Self>) -> Result<SinkSummary> {
+            let mut summary = self.inner.finalize()?;
+            // The envelope holds CNG handles, so it is only made on the thread sealing
+            let envelope = Envelope::new(&self.session, &self.public_key)?;
+            summary.bytes = envelope.seal_file(&summary.path, &mut |_, _| Ok(()))?.bytes;
+            Ok(summary)
+        }
+    }
+
+    struct Algorithm(BCRYPT_ALG_HANDLE);
+
+    impl Algorithm {
+        fn open(algorithm: PCWSTR) -> Result<Self> {
+            let mut handle = BCRYPT_ALG_HANDLE::default();
+            unsafe {
+                BCryptOpenAlgorithmProvider(
+                    &mut handle,
+                    algorithm,
+                    PCWSTR::null(),
+                    BCRYPT_OPEN_ALGORITHM_PROVIDER_FLAGS(0),
+                )
+                .ok()
+                .context("Failed to open CNG algorithm")?;
+            }
+            Ok(Self(handle))
+        }
+    }
+
+    impl Drop for Algorithm {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = BCryptCloseAlgorithmProvider(self.0, 0);
+            }
+        }
+    }
+
+    struct Key(BCRYPT_KEY_HANDLE);
+
+    impl Drop for Key {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = BCryptDestroyKey(self.0);
+            }
+        }
+    }
+
+    struct Secret(BCRYPT_SECRET_HANDLE);
+
+    impl Drop for Secret {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = BCryptDestroySecret(self.0);
+            }
+        }
+    }
+
+    /// AES-256-GCM key
+    struct AesGcm {
+        // Dropped after the key
+        key: Key,
+        _algorithm: Algorithm,
+    }
+
+    impl AesGcm {
+        fn new(secret: &[u8]) -> Result<Self> {
+            let algorithm = Algorithm::open(BCRYPT_AES_ALGORITHM)?;
+            let mut key = BCRYPT_KEY_HANDLE::default();
+            unsafe {
+                BCryptSetProperty(
+                    algorithm.0.into(),
+                    BCRYPT_CHAINING_MODE,
+                    &wide_bytes(BCRYPT_CHAIN_MODE_GCM),
+                    0,
+                )
+                .ok()
+                .context("Failed to select GCM")?;
+                BCryptGenerateSymmetricKey(algorithm.0, &mut key, None, secret, 0)
+                    .ok()
+                    .context("Failed to create AES key")?;
+            }
+            Ok(Self {
+                key: Key(key),
+                _algorithm: algorithm,
+            })
+        }
+    }
+
+    impl Seal for AesGcm {
+        fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+            let mut tag = [0u8; TAG_LEN];
+            let info = BCRYPT_AUTHENTICATED_CIPHER_MODE_INFO {
+                cbSize: std::mem::size_of::<BCRYPT_AUTHENTICATED_CIPHER_MODE_INFO>() as u32,
+                dwInfoVersion: BCRYPT_AUTHENTICATED_CIPHER_MODE_INFO_VERSION,
+                pbNonce: nonce.as_ptr() as *mut u8,
+                cbNonce: nonce.len() as u32,
+                pbAuthData: aad.as_ptr() as *mut u8,
+                cbAuthData: aad.len() as u32,
+                pbTag: tag.as_mut_ptr(),
+                cbTag: tag.len() as u32,
+                ..Default::default()
+            };
+            let mut sealed = vec![0u8; plaintext.len()];
+            let mut written = 0u32;
+            unsafe {
+                BCryptEncrypt(
+                    self.key.0,
+                    Some(plaintext),
+                    Some(&info as *const _ as *const c_void),
+                    None,
+                    Some(&mut sealed),
+                    &mut written,
+                    BCRYPT_FLAGS(0),
+                )
+                .ok()
+                .context("AES-GCM encryption failed")?;
+            }
+            sealed.truncate(written as usize);
+            sealed.extend_from_slice(&tag);
+            Ok(sealed)
+        }
+    }
+
+    fn random(buf: &mut [u8]) -> Result<()> {
+        unsafe {
+            BCryptGenRandom(
+                BCRYPT_ALG_HANDLE::default(),
+                buf,
+                BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+            )
+            .ok()
+            .context("Failed to generate random bytes")
+        }
+    }
+
+    /// `value` as the NUL-terminated UTF-16 bytes CNG properties take
+    fn wide_bytes(value: PCWSTR) -> Vec<u8> {
+        unsafe { value.as_wide() }
+            .iter()
+            .chain([&0u16])
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect()
+    }
+
+    /// Wrap `data_key` with the SubjectPublicKeyInfo `public_key`
+    fn wrap(public_key: &[u8], data_key: &[u8]) -> Result<WrappedKey> {
+        let key_id = crate::sha256::hex_digest(public_key);
+        unsafe {
+            // Query the decoded size, then decode into an 8-byte aligned buffer
+            let mut size = 0u32;
+            CryptDecodeObjectEx(
+                X509_ASN_ENCODING,
+                X509_PUBLIC_KEY_INFO,
+                public_key,
+                0,
+                None,
+                None,
+                &mut size,
+            )
+            .context("Not a SubjectPublicKeyInfo")?;
+            let mut info = vec![0u64; (size as usize).div_ceil(8)];
+            CryptDecodeObjectEx(
+                X509_ASN_ENCODING,
+                X509_PUBLIC_KEY_INFO,
+                public_key,
+                0,
+                None,
+                Some(info.as_mut_ptr() as *mut c_void),
+                &mut size,
+            )
+            .context("Not a SubjectPublicKeyInfo")?;
+            let info = &*(info.as_ptr() as *const CERT_PUBLIC_KEY_INFO);
+            let oid = info.Algorithm.pszObjId.to_string().unwrap_or_default();
+            let ec = oid == szOID_ECC_PUBLIC_KEY.to_string().unwrap_or_default();
+            if !ec && oid != szOID_RSA_RSA.to_string().unwrap_or_default() {
+                bail!(fail!(Format, "Unsupported wrap key algorithm {}", oid));
+            }
+
+            // EC keys are imported for key agreement rather than signing
+            let flags = if ec {
+                CRYPT_OID_INFO_PUBKEY_ENCRYPT_KEY_FLAG
+            } else {
+                CRYPT_IMPORT_PUBLIC_KEY_FLAGS(0)
+            };
+            let mut handle = BCRYPT_KEY_HANDLE::default();
+            CryptImportPublicKeyInfoEx2(X509_ASN_ENCODING, info, flags, None, &mut handle)
+                .context("Failed to import wrap key")?;
+            let recipient = Key(handle);
+
+            let (wrap, wrapped, ephemeral) = if ec {
+                let (wrapped, ephemeral) = wrap_ecies(&recipient, data_key)?;
+                (KeyWrap::EciesP256, wrapped, Some(hex(&ephemeral)))
+            } else {
+                (KeyWrap::RsaOaep256, wrap_rsa(&recipient, data_key)?, None)
+            };
+            Ok(WrappedKey {
+                wrap,
+                key_id,
+                wrapped: hex(&wrapped),
+                ephemeral,
+            })
+        }
+    }
+
+    unsafe fn wrap_rsa(recipient: &Key, data_key: &[u8]) -> Result<Vec<u8>> {
+        let padding = BCRYPT_OAEP_PADDING_INFO {
+            pszAlgId: BCRYPT_SHA256_ALGORITHM,
+            pbLabel: std::ptr::null_mut(),
+            cbLabel: 0,
+        };
+        let padding = Some(&padding as *const _ as *const c_void);
+        let mut size = 0u32;
+        BCryptEncrypt(
+            recipient.0,
+            Some(data_key),
+            padding,
+            None,
+            None,
+            &mut size,
+            BCRYPT_PAD_OAEP,
+        )
+        .ok()
+        .context("RSA-OAEP sizing failed")?;
+        let mut wrapped = vec![0u8; size as usize];
+        BCryptEncrypt(
+            recipient.0,
+            Some(data_key),
+            padding,
+            None,
+            Some(&mut wrapped),
+            &mut size,
+            BCRYPT_PAD_OAEP,
+        )
+        .ok()
+        .context("RSA-OAEP wrap failed")?;
+        wrapped.truncate(size as usize);
+        Ok(wrapped)
+    }
+
+    /// Public point of `key` as an uncompressed SEC1 point, checking it is on P-256
+    unsafe fn ecc_point(key: &Key) -> Result<Vec<u8>> {
+        let mut size = 0u32;
+        BCryptExportKey(
+            key.0,
+            BCRYPT_KEY_HANDLE::default(),
+            BCRYPT_ECCPUBLIC_BLOB,
+            None,
+            &mut size,
+            0,
+        )
+        .ok()
+        .context("Failed to export EC key")?;
+        let mut blob = vec![0u8; size as usize];
+        BCryptExportKey(
+            key.0,
+            BCRYPT_KEY_HANDLE::default(),
+            BCRYPT_ECCPUBLIC_BLOB,
+            Some(&mut blob),
+            &mut size,
+            0,
+        )
+        .ok()
+        .context("Failed to export EC key")?;
+        let header = std::mem::size_of::<BCRYPT_ECCKEY_BLOB>();
+        let magic = u32::from_le_bytes(blob[..4].try_into()?);
+        if ![
+            BCRYPT_ECDH_PUBLIC_P256_MAGIC,
+            BCRYPT_ECDSA_PUBLIC_P256_MAGIC,
+        ]
+        .contains(&magic)
+            || blob.len() != header + 64
+        {
+            bail!(fail!(Format, "Only P-256 EC wrap keys are supported"));
+        }
+        let mut point = vec![0x04];
+        point.extend_from_slice(&blob[header..]);
+        Ok(point)
+    }
+
+    /// Returns the wrapped key and the ephemeral public point
+    unsafe fn wrap_ecies(recipient: &Key, data_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        ecc_point(recipient)?;
+        let algorithm = Algorithm::open(BCRYPT_ECDH_P256_ALGORITHM)?;
+        let mut handle = BCRYPT_KEY_HANDLE::default();
+        BCryptGenerateKeyPair(algorithm.0, &mut handle, 256, 0)
+            .ok()
+            .context("Failed to generate ephemeral key")?;
+        let ephemeral = Key(handle);
+        BCryptFinalizeKeyPair(ephemeral.0, 0)
+            .ok()
+            .context("Failed to generate ephemeral key")?;
+        let point = ecc_point(&ephemeral)?;
+
+        let mut secret = BCRYPT_SECRET_HANDLE::default();
+        BCryptSecretAgreement(ephemeral.0, recipient.0, &mut secret, 0)
+            .ok()
+            .context("ECDH agreement failed")?;
+        let secret = Secret(secret);
+
+        let mut hash = wide_bytes(BCRYPT_SHA256_ALGORITHM);
+        let mut append = [point.as_slice(), KDF_LABEL].concat();
+        let mut buffers = [
+            BCryptBuffer {
+                cbBuffer: hash.len() as u32,
+                BufferType: KDF_HASH_ALGORITHM,
+                pvBuffer: hash.as_mut_ptr() as *mut c_void,
+            },
+            BCryptBuffer {
+                cbBuffer: append.len() as u32,
+                BufferType: KDF_SECRET_APPEND,
+                pvBuffer: append.as_mut_ptr() as *mut c_void,
+            },
+        ];
+        let parameters = BCryptBufferDesc {
+            ulVersion: BCRYPTBUFFER_VERSION,
+            cBuffers: buffers.len() as u32,
+            pBuffers: buffers.as_mut_ptr(),
+        };
+        let mut kek = [0u8; 32];
+        let mut size = 0u32;
+        BCryptDeriveKey(
+            secret.0,
+            BCRYPT_KDF_HASH,
+            Some(&parameters),
+            Some(&mut kek),
+            &mut size,
+            0,
+        )
+        .ok()
+        .context("Key derivation failed")?;
+        if size as usize != kek.len() {
+            return Err(anyhow!("Key derivation returned {} bytes", size));
+        }
+        let wrapped = AesGcm::new(&kek).and_then(|kek| kek.seal(&[0; NONCE_LEN], &[], data_key));
+        kek.fill(0);
+        Ok((wrapped?, point))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-in cipher: XOR with the nonce's last byte, tag = first 16 bytes of the AAD
+    struct XorSeal;
+
+    impl Seal for XorSeal {
+        fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|b| b ^ nonce[11]).collect();
+            sealed.extend_from_slice(&aad[..TAG_LEN]);
+            Ok(sealed)
+        }
+    }
+
+    fn header(plaintext_bytes: u64) -> Header {
+        Header {
+            version: VERSION,
+            session: "call-42".to_string(),
+            cipher: "AES-256-GCM".to_string(),
+            chunk_size: 4,
+            nonce_prefix: hex(&[7; 8]),
+            plaintext_bytes,
+            key: WrappedKey {
+                wrap: KeyWrap::RsaOaep256,
+                key_id: "ab".repeat(32),
+                wrapped: "00ff".to_string(),
+                ephemeral: None,
+            },
+        }
+    }
+
+    #[test]
+    fn lays_out_header_and_numbered_chunks() {
+        let header = header(10);
+        let mut out = Vec::new();
+        let written = write_container(&mut &[1u8; 10][..], &mut out, &header, &XorSeal).unwrap();
+        assert_eq!(written, out.len() as u64);
+
+        assert_eq!(&out[..8], MAGIC);
+        let header_len = u32::from_le_bytes(out[8..12].try_into().unwrap()) as usize;
+        let header_json = &out[12..12 + header_len];
+        let parsed: Header = serde_json::from_slice(header_json).unwrap();
+        assert_eq!(parsed, header);
+        assert!(!String::from_utf8_lossy(header_json).contains("ephemeral"));
+
+        // Chunks of 4, 4 and 2 bytes, each sealed under its own nonce
+        let chunks = &out[12 + header_len..];
+        assert_eq!(chunks.len(), 10 + 3 * TAG_LEN);
+        assert_eq!(&chunks[..4], &[1; 4]);
+        assert_eq!(&chunks[4 + TAG_LEN..8 + TAG_LEN], &[0; 4]);
+        assert_eq!(&chunks[8 + 2 * TAG_LEN..10 + 2 * TAG_LEN], &[3; 2]);
+        let aad = crate::sha256::digest(header_json);
+        assert_eq!(&chunks[4..4 + TAG_LEN], &aad[..TAG_LEN]);
+        assert_eq!(chunk_nonce(&[7; 8], 2)[8..], [0, 0, 0, 2]);
+
+        // A recording that doesn't match the header is refused
+        assert!(write_container(&mut &[1u8; 9][..], &mut Vec::new(), &header, &XorSeal).is_err());
+    }
+
+    #[test]
+    fn seals_files_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.wav");
+        std::fs::write(&path, b"RIFF....WAVE").unwrap();
+        let written =
+            seal_in_place(&path, header(0), &XorSeal, &mut crate::progress::ignore).unwrap();
+        let sealed = std::fs::read(&path).unwrap();
+        assert_eq!(sealed.len() as u64, written);
+        assert_eq!(&sealed[..8], MAGIC);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn reads_pem_and_der_public_keys() {
+        let pem =
+            "-----BEGIN PUBLIC KEY-----\nMBMwBwYFK4EEACIDCAAB\nAgME\n-----END PUBLIC KEY-----\n";
+        let der = parse_public_key(pem.as_bytes()).unwrap();
+        assert_eq!(der[..2], [0x30, 0x13]);
+        assert_eq!(der.len(), 18);
+        assert_eq!(parse_public_key(&der).unwrap(), der);
+        assert_eq!(base64_decode("TWE=").unwrap(), b"Ma");
+        assert!(base64_decode("TW=E").is_none());
+        for text in [&b""[..], b"M", b"Ma", b"Man", b"user:pa55"] {
+            assert_eq!(base64_decode(&base64_encode(text)).unwrap(), text);
+        }
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert!(parse_public_key(b"-----BEGIN RSA PUBLIC KEY-----\n").is_err());
+        assert!(parse_public_key(b"hello").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Error domain
+//! Failures carry a kind that integrators can program against instead of matching English
+//! messages: the `error` and `control_error` events have it as `kind`, the CLI exits with
+//! its code and the N-API bindings throw errors with it as `code`.
+//!
+//! | kind       | exit code | raised for                                           |
+//! |------------|-----------|------------------------------------------------------|
+//! | `internal` | 1         | anything else, a bug on our side                     |
+//! | `config`   | 2         | options that are invalid or don't go together        |
+//! | `device`   | 10        | an endpoint missing, held or failing                 |
+//! | `format`   | 11        | audio, a file or a key that can't be read or written |
+//! | `io`       | 12        | reading or writing files                             |
+//! | `protocol` | 13        | a malformed or out-of-place command or frame         |
+//! | `policy`   | 14        | the license, tenant policy or a `--require-*` rule   |
+//! | `network`  | 15        | a collector, an update feed or a share               |
+//!
+//! Errors are raised as a `CaptureError` where the cause is known (`fail!(Device, ...)`) and
+//! travel up as `anyhow::Error`, gathering context on the way. `kind` finds the typed error
+//! in the chain; errors from std and the libraries underneath (I/O, WAV and JSON parsing,
+//! WASAPI and cpal) are classified by their type, and anything else is `internal`.
+
+use serde::Serialize;
+use std::io;
+use thiserror::Error;
+
+/// What a failure was about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// An endpoint is missing, held or fails to open or deliver
+    Device,
+    /// Audio, a file or a key in a format that can't be read or written
+    Format,
+    /// Reading or writing files
+    Io,
+    /// A control command or frame that is malformed or doesn't fit the session's state
+    Protocol,
+    /// Refused by the license, the tenant's policy or a `--require-*` rule
+    Policy,
+    /// Reaching a collector, an update feed or a share
+    Network,
+    /// Options that are invalid or don't go together
+    Config,
+    /// Anything else, a bug on our side
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Device => "device",
+            ErrorKind::Format => "format",
+            ErrorKind::Io => "io",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Policy => "policy",
+            ErrorKind::Network => "network",
+            ErrorKind::Config => "config",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    /// Exit code of the CLI. 1 stays the catch-all and 2 is what clap exits with on usage
+    /// errors, which invalid options join.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::Device => 10,
+            ErrorKind::Format => 11,
+            ErrorKind::Io => 12,
+            ErrorKind::Protocol => 13,
+            ErrorKind::Policy => 14,
+            ErrorKind::Network => 15,
+        }
+    }
+}
+
+/// A failure whose kind is known where it is raised
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("{0}")]
+    Device(String),
+    #[error("{0}")]
+    Format(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Protocol(String),
+    #[error("{0}")]
+    Policy(String),
+    #[error("{0}")]
+    Network(String),
+    #[error("{0}")]
+    Config(String),
+}
+
+impl CaptureError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CaptureError::Device(_) => ErrorKind::Device,
+            CaptureError::Format(_) => ErrorKind::Format,
+            CaptureError::Io(e) => io_kind(e),
+            CaptureError::Protocol(_) => ErrorKind::Protocol,
+            CaptureError::Policy(_) => ErrorKind::Policy,
+            CaptureError::Network(_) => ErrorKind::Network,
+            CaptureError::Config(_) => ErrorKind::Config,
+        }
+    }
+}
+
+/// `anyhow::Error` of a `CaptureError` of the given variant, with a formatted message:
+/// `fail!(Device, "No endpoint {}", id)`
+macro_rules! fail {
+    ($variant:ident, $($message:tt)+) => {
+        anyhow::Error::from($crate::error::CaptureError::$variant(format!($($message)+)))
+    };
+}
+pub(crate) use fail;
+
+/// Kind of `error`: that of the outermost cause with a known kind
+pub fn kind(error: &anyhow::Error) -> ErrorKind {
+    error
+        .chain()
+        .find_map(cause_kind)
+        .unwrap_or(ErrorKind::Internal)
+}
+
+fn cause_kind(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    if let Some(e) = cause.downcast_ref::<CaptureError>() {
+        return Some(e.kind());
+    }
+    if let Some(e) = cause.downcast_ref::<io::Error>() {
+        return Some(io_kind(e));
+    }
+    if let Some(e) = cause.downcast_ref::<hound::Error>() {
+        return Some(match e {
+            hound::Error::IoError(e) => io_kind(e),
+            _ => ErrorKind::Format,
+        });
+    }
+    if cause.is::<serde_json::Error>() {
+        return Some(ErrorKind::Protocol);
+    }
+    if cause.is::<clap::Error>() {
+        return Some(ErrorKind::Config);
+    }
+    platform_kind(cause)
+}
+
+fn io_kind(error: &io::Error) -> ErrorKind {
+    use io::ErrorKind::*;
+    match error.kind() {
+        ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected
+        | AddrNotAvailable | HostUnreachable | NetworkUnreachable | NetworkDown => {
+            ErrorKind::Network
+        }
+        _ => ErrorKind::Io,
+    }
+}
+
+/// WASAPI (`AUDCLNT_E_*`) and cpal failures are the device's
+#[cfg(windows)]
+fn platform_kind(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    /// Facility of the audio client's HRESULTs
+    const FACILITY_AUDCLNT: i32 = 0x889;
+
+    if let Some(e) = cause.downcast_ref::<windows::core::Error>() {
+        let facility = (e.code().0 >> 16) & 0x1fff;
+        return (facility == FACILITY_AUDCLNT).then_some(ErrorKind::Device);
+    }
+    let device = cause.is::<cpal::BuildStreamError>()
+        || cause.is::<cpal::PlayStreamError>()
+        || cause.is::<cpal::DefaultStreamConfigError>()
+        || cause.is::<cpal::SupportedStreamConfigsError>()
+        || cause.is::<cpal::DevicesError>();
+    device.then_some(ErrorKind::Device)
+}
+
+#[cfg(not(windows))]
+fn platform_kind(_cause: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn finds_the_typed_error_under_context() {
+        let error = Err::<(), _>(fail!(Device, "No default input device found"))
+            .context("Failed to open the MIC")
+            .unwrap_err();
+        assert_eq!(kind(&error), ErrorKind::Device);
+        assert_eq!(
+            format!("{:#}", error),
+            "Failed to open the MIC: No default input device found"
+        );
+    }
+
+    #[test]
+    fn classifies_library_errors_by_type() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(kind(&refused.into()), ErrorKind::Network);
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(kind(&anyhow::Error::from(missing)), ErrorKind::Io);
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert_eq!(kind(&json.into()), ErrorKind::Protocol);
+        assert_eq!(kind(&anyhow::anyhow!("Something odd")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn kinds_have_distinct_exit_codes() {
+        let kinds = [
+            ErrorKind::Device,
+            ErrorKind::Format,
+            ErrorKind::Io,
+            ErrorKind::Protocol,
+            ErrorKind::Policy,
+            ErrorKind::Network,
+            ErrorKind::Config,
+            ErrorKind::Internal,
+        ];
+        let mut codes: Vec<i32> = kinds.iter().map(|kind| kind.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), kinds.len());
+        assert_eq!(
+            serde_json::to_value(ErrorKind::Network).unwrap(),
+            ErrorKind::Network.as_str()
+        );
+    }
+}
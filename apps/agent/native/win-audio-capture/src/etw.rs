@@ -0,0 +1,132 @@
+//! ETW provider
+//! The sidecar registers the `Selly-AudioCapture` provider for the whole process, so support
+//! can record its events on a customer machine with the stock tools and no extra logging,
+//! e.g. `tracelog -start selly -guid #6C1E5B8A-3F2D-4B7C-9A41-5E0D7C2B9F13 -f selly.etl`
+//! (or a WPR profile naming the provider) and `tracelog -stop selly`.
+//!
+//! Every lifecycle event is written as a string event (`<name> <json fields>`), plus
+//! device opens, mixer glitches (underruns, overruns) and dropped live frames. Keywords
+//! select the categories. Nothing is formatted unless a trace session has the provider
+//! enabled at that level and keyword, so this stays on in production.
+
+use serde_json::Value;
+
+/// `{6C1E5B8A-3F2D-4B7C-9A41-5E0D7C2B9F13}`
+pub const PROVIDER_GUID: u128 = 0x6c1e5b8a_3f2d_4b7c_9a41_5e0d7c2b9f13;
+
+/// Keyword bits
+pub const KEYWORD_LIFECYCLE: u64 = 0x1;
+pub const KEYWORD_DEVICE: u64 = 0x2;
+pub const KEYWORD_GLITCH: u64 = 0x4;
+pub const KEYWORD_DROP: u64 = 0x8;
+pub const KEYWORD_ROTATE: u64 = 0x10;
+
+/// ETW levels
+pub const LEVEL_WARNING: u8 = 3;
+pub const LEVEL_INFO: u8 = 4;
+
+/// Level and keyword an emitted lifecycle event is written with
+pub fn classify(name: &str) -> (u8, u64) {
+    match name {
+        "device_config" | "loopback_role" | "gain_staging" | "polarity_corrected" => {
+            (LEVEL_INFO, KEYWORD_DEVICE)
+        }
+        "usual_device_missing" => (LEVEL_WARNING, KEYWORD_DEVICE),
+        "gap" => (LEVEL_WARNING, KEYWORD_GLITCH),
+        "rotated" => (LEVEL_INFO, KEYWORD_ROTATE),
+        name if name.ends_with("error") => (LEVEL_WARNING, KEYWORD_LIFECYCLE),
+        _ => (LEVEL_INFO, KEYWORD_LIFECYCLE),
+    }
+}
+
+/// Write a lifecycle event as it is emitted
+pub fn lifecycle(name: &str, event: &Value) {
+    let (level, keyword) = classify(name);
+    write(level, keyword, name, event);
+}
+
+#[cfg(windows)]
+mod provider {
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use windows::Win32::System::Diagnostics::Etw::{
+        EventProviderEnabled, EventRegister, EventUnregister, EventWriteString, REGHANDLE,
+    };
+
+    static HANDLE: AtomicU64 = AtomicU64::new(0);
+
+    /// Keeps the provider registered; unregisters on drop
+    pub struct Provider(());
+
+    impl Drop for Provider {
+        fn drop(&mut self) {
+            let handle = HANDLE.swap(0, Ordering::AcqRel);
+            if handle != 0 {
+                unsafe { EventUnregister(REGHANDLE(handle as i64)) };
+            }
+        }
+    }
+
+    pub fn register() -> Option<Provider> {
+        let guid = windows_core::GUID::from_u128(super::PROVIDER_GUID);
+        let mut handle = 0u64;
+        let status = unsafe { EventRegister(&guid, None, None, &mut handle) };
+        if status != 0 {
+            eprintln!(
+                "[win-audio-capture] Warning: ETW provider registration failed ({})",
+                status
+            );
+            return None;
+        }
+        HANDLE.store(handle, Ordering::Release);
+        Some(Provider(()))
+    }
+
+    pub fn enabled(level: u8, keyword: u64) -> bool {
+        let handle = HANDLE.load(Ordering::Acquire);
+        handle != 0
+            && unsafe { EventProviderEnabled(REGHANDLE(handle as i64), level, keyword) }.as_bool()
+    }
+
+    pub fn write(level: u8, keyword: u64, name: &str, fields: &Value) {
+        crate::rt::check(crate::rt::Violation::Io);
+        if !enabled(level, keyword) {
+            return;
+        }
+        let text: Vec<u16> = format!("{} {}", name, fields)
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+        let handle = REGHANDLE(HANDLE.load(Ordering::Acquire) as i64);
+        unsafe {
+            EventWriteString(handle, level, keyword, windows_core::PCWSTR(text.as_ptr()));
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use provider::{enabled, register, write};
+
+#[cfg(not(windows))]
+pub fn enabled(_level: u8, _keyword: u64) -> bool {
+    false
+}
+
+#[cfg(not(windows))]
+pub fn write(_level: u8, _keyword: u64, _name: &str, _fields: &Value) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_events_into_keywords() {
+        assert_eq!(classify("started"), (LEVEL_INFO, KEYWORD_LIFECYCLE));
+        assert_eq!(
+            classify("control_error"),
+            (LEVEL_WARNING, KEYWORD_LIFECYCLE)
+        );
+        assert_eq!(classify("gap"), (LEVEL_WARNING, KEYWORD_GLITCH));
+        assert_eq!(classify("device_config"), (LEVEL_INFO, KEYWORD_DEVICE));
+    }
+}
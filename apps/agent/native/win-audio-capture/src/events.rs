@@ -0,0 +1,123 @@
+//! Machine-readable lifecycle events
+//! Written as newline-delimited JSON to stderr, since stdout carries the PCM frame stream.
+//! While an agent is attached over the instance pipe, every line is mirrored to it too, and
+//! to every observer connected to the read-only observe pipe that subscribed to it, and
+//! appended to the session's event journal. `--tui` turns the stderr lines off while it
+//! draws on the same console.
+
+use crate::journal::Journal;
+use crate::subscription::Subscription;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+
+static MIRROR: Mutex<Option<UnboundedSender<String>>> = Mutex::new(None);
+static OBSERVERS: Mutex<Vec<(UnboundedSender<String>, Subscription)>> = Mutex::new(Vec::new());
+#[cfg(feature = "otlp")]
+static TAP: Mutex<Option<std::sync::mpsc::Sender<Value>>> = Mutex::new(None);
+static JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+static STDERR: AtomicBool = AtomicBool::new(true);
+
+/// Also send every event line to `mirror`, or stop mirroring with `None`
+pub fn set_mirror(mirror: Option<UnboundedSender<String>>) {
+    *MIRROR.lock().unwrap_or_else(|e| e.into_inner()) = mirror;
+}
+
+/// Also send the event lines `subscription` wants to `observer` until it is dropped
+pub fn add_observer(observer: UnboundedSender<String>, subscription: Subscription) {
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((observer, subscription));
+}
+
+/// Stop sending event lines to `observer`
+pub fn remove_observer(observer: &UnboundedSender<String>) {
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(other, _)| !other.same_channel(observer));
+}
+
+#[cfg(feature = "otlp")]
+/// Also hand every event to `tap` (for export), or stop with `None`
+pub fn set_tap(tap: Option<std::sync::mpsc::Sender<Value>>) {
+    *TAP.lock().unwrap_or_else(|e| e.into_inner()) = tap;
+}
+
+/// Also append every event line to `journal`, or close it with `None`
+pub fn set_journal(journal: Option<Journal>) {
+    *JOURNAL.lock().unwrap_or_else(|e| e.into_inner()) = journal;
+}
+
+/// Write event lines to stderr (the default) or only to the mirror, observers and journal
+pub fn set_stderr(enabled: bool) {
+    STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// Emit a single event line: `{"event":"<name>","ts_ms":<unix millis>,"ts":<local time>,
+/// ...fields}`, plus the localized `message` for events shown to the rep
+pub fn emit(name: &str, fields: Value) {
+    crate::rt::check(crate::rt::Violation::Log);
+    let mut event = Map::new();
+    let now = unix_millis();
+    event.insert("event".to_string(), Value::from(name));
+    event.insert("ts_ms".to_string(), Value::from(now));
+    let ts = crate::i18n::local_timestamp(now, crate::i18n::utc_offset_minutes());
+    event.insert("ts".to_string(), Value::from(ts));
+    if let Value::Object(fields) = fields {
+        event.extend(fields);
+    }
+    if !event.contains_key("message") {
+        if let Some(message) = crate::i18n::message(name, &event) {
+            event.insert("message".to_string(), Value::from(message));
+        }
+    }
+
+    let event = Value::Object(event);
+    crate::etw::lifecycle(name, &event);
+    let line = event.to_string();
+    #[cfg(feature = "otlp")]
+    if let Some(tap) = TAP.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        let _ = tap.send(event);
+    }
+    if let Some(mirror) = MIRROR.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        let _ = mirror.send(line.clone());
+    }
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(observer, subscription)| {
+            !subscription.wants_event(name) || observer.send(line.clone()).is_ok()
+        });
+    let mut journal = JOURNAL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Err(e)) = journal.as_mut().map(|journal| journal.write(&line)) {
+        // Writing on would only repeat the warning; the stderr stream is still complete
+        eprintln!(
+            "[win-audio-capture] Warning: Event journal {:?} closed: {}",
+            journal.as_ref().map(Journal::path),
+            e
+        );
+        *journal = None;
+    }
+    drop(journal);
+
+    if !STDERR.load(Ordering::Relaxed) {
+        return;
+    }
+    let stderr = std::io::stderr();
+    let mut lock = stderr.lock();
+    let _ = writeln!(lock, "{}", line);
+    let _ = lock.flush();
+}
+
+/// Current wall time in milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,257 @@
+//! Clip extraction
+//! `extract <file> --from <pos> --to <pos> --out <clip>` copies a sample-accurate range of a
+//! finished recording into a new file, so coaching clips don't need the whole recording in
+//! an editor. A position is a time (`01:02:03.250`, `12:31.25`, `95.5`), converted to a
+//! frame without going through floating point, or the name of a marker: a labelled `cue `
+//! point in the file or an entry of its timeline (matched by label, then by kind).
+
+use crate::error::fail;
+use crate::riff::{self, RiffAudio};
+use crate::sink::{self, OutputFormat, SinkSpec};
+use crate::timeline;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// What `extract` prints once the clip is written
+#[derive(Debug, Serialize)]
+pub struct ExtractSummary {
+    pub out: PathBuf,
+    pub start_frame: u64,
+    pub frames: u64,
+    pub sample_rate: u32,
+    pub bytes: u64,
+}
+
+/// A named position in the recording, as a frame offset into the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    pub name: String,
+    pub frame: u64,
+}
+
+/// Write frames `[from, to)` of `input` to `out`; open ends mean the start and end of the file
+pub fn run(
+    input: &Path,
+    from: Option<&str>,
+    to: Option<&str>,
+    out: &Path,
+    format: OutputFormat,
+) -> Result<ExtractSummary> {
+    if out == input {
+        return Err(fail!(Config, "--out must not be the input file"));
+    }
+    let audio = RiffAudio::open(input)?;
+    let markers = markers(input, &audio)?;
+    let total = audio.frames();
+
+    let start = match from {
+        Some(from) => resolve(from, audio.sample_rate, &markers)?,
+        None => 0,
+    };
+    let end = match to {
+        Some(to) => resolve(to, audio.sample_rate, &markers)?.min(total),
+        None => total,
+    };
+    if start >= end {
+        return Err(fail!(
+            Config,
+            "Empty range: frame {} to {} of {}",
+            start,
+            end,
+            total
+        ));
+    }
+
+    write_clip(input, &audio, start, end, out, format)
+}
+
+/// Write frames `[start, end)` of the recording `input` (laid out as `audio`) to `out`
+pub fn write_clip(
+    input: &Path,
+    audio: &RiffAudio,
+    start: u64,
+    end: u64,
+    out: &Path,
+    format: OutputFormat,
+) -> Result<ExtractSummary> {
+    // Written under a temporary name so an interrupted extract never leaves a short clip
+    let mut tmp_name = OsString::from(out.as_os_str());
+    tmp_name.push(".partial");
+    let tmp_path = PathBuf::from(tmp_name);
+    let spec = SinkSpec {
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
+    };
+    let mut clip = sink::create(format, &tmp_path, spec)?;
+    let written = riff::read_frames(input, audio, start, end - start, |samples| {
+        clip.write_samples(samples)
+    })
+    .and_then(|_| clip.finalize());
+    let summary = match written {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp_path, out).context("Failed to move clip into place")?;
+
+    Ok(ExtractSummary {
+        out: out.to_path_buf(),
+        start_frame: start,
+        frames: summary.frames,
+        sample_rate: audio.sample_rate,
+        bytes: summary.bytes,
+    })
+}
+
+/// Markers of a recording in file order: its cue points first, then its timeline
+pub fn markers(input: &Path, audio: &RiffAudio) -> Result<Vec<Marker>> {
+    let mut markers: Vec<Marker> = audio
+        .cues
+        .iter()
+        .filter_map(|cue| {
+            Some(Marker {
+                name: cue.name.clone()?,
+                frame: cue.frame,
+            })
+        })
+        .collect();
+
+    if let Some(saved) = timeline::load(input)? {
+        // Labels win over kinds, so `--from objection` finds the labelled marker even if
+        // some event kind is also called that
+        let mut by_kind = Vec::new();
+        for entry in &saved.entries {
+            let Some(frame) = saved.file_frame(entry) else {
+                continue;
+            };
+            if let Some(label) = &entry.label {
+                markers.push(Marker {
+                    name: label.clone(),
+                    frame,
+                });
+            }
+            by_kind.push(Marker {
+                name: entry.kind.clone(),
+                frame,
+            });
+        }
+        markers.extend(by_kind);
+    }
+    Ok(markers)
+}
+
+/// Frame for a position: a time, or else the first marker with that name
+pub fn resolve(position: &str, sample_rate: u32, markers: &[Marker]) -> Result<u64> {
+    if let Some(frame) = parse_time(position, sample_rate) {
+        return Ok(frame);
+    }
+    markers
+        .iter()
+        .find(|marker| marker.name == position)
+        .map(|marker| marker.frame)
+        .ok_or_else(|| {
+            fail!(
+                Config,
+                "{:?} is neither a time nor a marker in the recording",
+                position
+            )
+        })
+}
+
+/// `[[HH:]MM:]SS[.fraction]` to a frame, rounded to the nearest sample
+pub fn parse_time(text: &str, sample_rate: u32) -> Option<u64> {
+    let (clock, fraction) = match text.split_once('.') {
+        Some((clock, fraction)) => (clock, fraction),
+        None => (text, ""),
+    };
+    let fields: Vec<&str> = clock.split(':').collect();
+    if fields.len() > 3 || fraction.len() > 9 {
+        return None;
+    }
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !fields.iter().all(|f| digits(f)) || !(fraction.is_empty() || digits(fraction)) {
+        return None;
+    }
+
+    let mut seconds = 0u64;
+    for (i, field) in fields.iter().enumerate() {
+        let value: u64 = field.parse().ok()?;
+        // Minutes and seconds after the first field stay below 60
+        if i > 0 && value >= 60 {
+            return None;
+        }
+        seconds = seconds.checked_mul(60)?.checked_add(value)?;
+    }
+
+    let rate = sample_rate as u64;
+    let scale = 10u64.pow(fraction.len() as u32);
+    let numerator = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u64>().ok()?
+    };
+    let sub_frame = (numerator as u128 * rate as u128 * 2 + scale as u128) / (scale as u128 * 2);
+    seconds.checked_mul(rate)?.checked_add(sub_frame as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clock_times_exactly() {
+        assert_eq!(parse_time("00:12:31.250", 48_000), Some(751_250 * 48));
+        assert_eq!(parse_time("12:31.25", 48_000), Some(751_250 * 48));
+        assert_eq!(parse_time("95.5", 8_000), Some(764_000));
+        // 1/3 ms at 48 kHz is 16 frames; rounding, not flooring
+        assert_eq!(parse_time("0.000333", 48_000), Some(16));
+        assert_eq!(parse_time("1:60", 48_000), None);
+        assert_eq!(parse_time("objection", 48_000), None);
+        assert_eq!(parse_time("1:2:3:4", 48_000), None);
+    }
+
+    #[test]
+    fn resolves_markers_by_name() {
+        let markers = [
+            Marker {
+                name: "objection".to_string(),
+                frame: 900,
+            },
+            Marker {
+                name: "objection".to_string(),
+                frame: 1_900,
+            },
+        ];
+        assert_eq!(resolve("objection", 1_000, &markers).unwrap(), 900);
+        assert_eq!(resolve("2", 1_000, &markers).unwrap(), 2_000);
+        assert!(resolve("pricing", 1_000, &markers).is_err());
+    }
+
+    #[test]
+    fn extracts_the_requested_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("call.wav");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        let mut out = sink::create(OutputFormat::Wav, &input, spec).unwrap();
+        out.write_samples(&(0..6_000).map(|i| i as i16).collect::<Vec<_>>())
+            .unwrap();
+        out.finalize().unwrap();
+
+        let clip = dir.path().join("clip.wav");
+        let summary = run(&input, Some("0.5"), Some("1.25"), &clip, OutputFormat::Wav).unwrap();
+        assert_eq!((summary.start_frame, summary.frames), (500, 750));
+
+        let mut reader = hound::WavReader::open(&clip).unwrap();
+        let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(samples.first(), Some(&1_000));
+        assert_eq!(samples.len(), 1_500);
+        assert!(run(&input, Some("2"), Some("1"), &clip, OutputFormat::Wav).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Resource footprint of a session
+//! The `stopped` event carries what the session cost the machine, so IT can quantify the
+//! recorder and resource regressions show up in the field: CPU seconds (user and kernel),
+//! bytes read and written through the OS, the peak working set, and the handle count at
+//! the start, at the end and at its highest (sampled every second).
+//!
+//! CPU and I/O are counted from the session's start, so a host running several sessions
+//! in-process gets each one's share of them; the peak working set and the handles belong to
+//! the whole process.
+
+use serde::Serialize;
+
+/// Process counters at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub user_cpu_ms: u64,
+    pub kernel_cpu_ms: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub peak_resident_bytes: u64,
+    pub handles: u32,
+}
+
+/// What the session reports in `stopped`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Footprint {
+    pub user_cpu_ms: u64,
+    pub kernel_cpu_ms: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub peak_resident_bytes: u64,
+    pub handles_at_start: u32,
+    pub handles: u32,
+    pub peak_handles: u32,
+}
+
+/// Samples the process over a session
+#[derive(Debug)]
+pub struct FootprintTracker {
+    start: Usage,
+    peak_handles: u32,
+}
+
+impl FootprintTracker {
+    pub fn new(start: Usage) -> Self {
+        Self {
+            start,
+            peak_handles: start.handles,
+        }
+    }
+
+    pub fn observe(&mut self, usage: Usage) {
+        self.peak_handles = self.peak_handles.max(usage.handles);
+    }
+
+    /// The footprint from the start to `end`
+    pub fn finish(mut self, end: Usage) -> Footprint {
+        self.observe(end);
+        let start = self.start;
+        Footprint {
+            user_cpu_ms: end.user_cpu_ms.saturating_sub(start.user_cpu_ms),
+            kernel_cpu_ms: end.kernel_cpu_ms.saturating_sub(start.kernel_cpu_ms),
+            read_bytes: end.read_bytes.saturating_sub(start.read_bytes),
+            written_bytes: end.written_bytes.saturating_sub(start.written_bytes),
+            peak_resident_bytes: end.peak_resident_bytes,
+            handles_at_start: start.handles,
+            handles: end.handles,
+            peak_handles: self.peak_handles,
+        }
+    }
+}
+
+/// Counters of this process, `None` if the OS doesn't give them
+#[cfg(windows)]
+pub fn usage() -> Option<Usage> {
+    use windows::Win32::Foundation::FILETIME;
+    use windows::Win32::System::ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+    use windows::Win32::System::Threading::{
+        GetCurrentProcess, GetProcessHandleCount, GetProcessIoCounters, GetProcessTimes,
+        IO_COUNTERS,
+    };
+
+    // FILETIME durations are in 100 ns units
+    let millis = |time: FILETIME| {
+        (((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64) / 10_000
+    };
+
+    let process = unsafe { GetCurrentProcess() };
+    let (mut created, mut exited) = (FILETIME::default(), FILETIME::default());
+    let (mut kernel, mut user) = (FILETIME::default(), FILETIME::default());
+    unsafe { GetProcessTimes(process, &mut created, &mut exited, &mut kernel, &mut user) }.ok()?;
+    let mut io = IO_COUNTERS::default();
+    unsafe { GetProcessIoCounters(process, &mut io) }.ok()?;
+    let mut memory = PROCESS_MEMORY_COUNTERS::default();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    if !unsafe { K32GetProcessMemoryInfo(process, &mut memory, size) }.as_bool() {
+        return None;
+    }
+    let mut handles = 0u32;
+    unsafe { GetProcessHandleCount(process, &mut handles) }.ok()?;
+
+    Some(Usage {
+        user_cpu_ms: millis(user),
+        kernel_cpu_ms: millis(kernel),
+        read_bytes: io.ReadTransferCount,
+        written_bytes: io.WriteTransferCount,
+        peak_resident_bytes: memory.PeakWorkingSetSize as u64,
+        handles,
+    })
+}
+
+#[cfg(not(windows))]
+pub fn usage() -> Option<Usage> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_cpu_and_io_from_the_start_and_keeps_the_handle_peak() {
+        let start = Usage {
+            user_cpu_ms: 1_000,
+            kernel_cpu_ms: 200,
+            read_bytes: 4_096,
+            written_bytes: 100,
+            peak_resident_bytes: 20 << 20,
+            handles: 180,
+        };
+        let mut tracker = FootprintTracker::new(start);
+        tracker.observe(Usage {
+            handles: 240,
+            ..start
+        });
+        let footprint = tracker.finish(Usage {
+            user_cpu_ms: 4_500,
+            kernel_cpu_ms: 700,
+            read_bytes: 8_192,
+            written_bytes: 5_760_100,
+            peak_resident_bytes: 31 << 20,
+            handles: 190,
+        });
+        assert_eq!(
+            footprint,
+            Footprint {
+                user_cpu_ms: 3_500,
+                kernel_cpu_ms: 500,
+                read_bytes: 4_096,
+                written_bytes: 5_760_000,
+                peak_resident_bytes: 31 << 20,
+                handles_at_start: 180,
+                handles: 190,
+                peak_handles: 240,
+            }
+        );
+    }
+}
@@ -0,0 +1,939 @@
+//! SELL frame protocol
+//! Framing for the PCM stream the sidecar writes to stdout. Every frame is a fixed header
+//! followed by interleaved little-endian i16 stereo samples. Version 1, the stream older
+//! agents were built against, has the short header:
+//!
+//! `[MAGIC "SELL"(4)] [SeqNum u32 LE(4)] [Size u32 LE(4)] [PCM...]`
+//!
+//! From protocol version 2 the stream opens with a handshake frame: magic `SELH`, SeqNum
+//! and SampleOffset 0 and a JSON `Handshake` payload, with the long header every frame
+//! after it has too:
+//!
+//! `[MAGIC "SELL"(4)] [SeqNum u32 LE(4)] [SampleOffset u64 LE(8)] [Size u32 LE(4)] [PCM...]`
+//!
+//! SeqNum increments once per frame, SampleOffset is the session-absolute index of the
+//! frame's first stereo pair and Size is the payload length in bytes. The handshake
+//! doesn't advance SeqNum. Decoders that predate it skip it as unknown bytes, which is
+//! why a consumer only gets long headers once it has been sent the handshake.
+//!
+//! A consumer that negotiated `energy` also gets an energy frame (magic `SELE`) after every
+//! PCM frame, with that frame's SeqNum and SampleOffset and per channel the RMS and peak
+//! magnitude as two u16 LE (0..=32768), so meters and crude VAD need no PCM decoding.
+//!
+//! With `shared_pcm` negotiated a PCM frame can instead arrive as a reference frame
+//! (magic `SELR`) whose payload points at a slot of the shared-memory ring (see `shm`).
+//!
+//! Protocol version 3 (`--protocol v3`) sends audio as typed frames with a longer header:
+//!
+//! `[MAGIC "SELT"(4)] [Type u8] [0(3)] [SeqNum u32] [SampleOffset u64] [MonotonicNs u64]
+//! [UtcMicros i64] [Size u32] [Crc32 u32] [payload...]`
+//!
+//! Type 1 is PCM, 2 a marker, 3 a stats snapshot and 4 the end of the stream, the last
+//! three with a JSON payload. MonotonicNs counts from the start of the stream and
+//! UtcMicros is the wall clock at the same moment, both taken when the frame was
+//! completed. The CRC32 (IEEE) covers the header up to it and the payload, so a decoder
+//! can tell a corrupted frame from a good one and `FrameDecoder::resync` to the next one.
+
+use crate::shm::SlotRef;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Write};
+
+/// Magic bytes for frame synchronization (0x53454C4C)
+pub const MAGIC: [u8; 4] = *b"SELL";
+
+/// Magic of the handshake frame
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"SELH";
+
+/// Magic of the energy frame following a PCM frame
+pub const ENERGY_MAGIC: [u8; 4] = *b"SELE";
+
+/// Magic of a PCM frame delivered through shared memory
+pub const SHARED_MAGIC: [u8; 4] = *b"SELR";
+
+/// Magic of a typed frame
+pub const TYPED_MAGIC: [u8; 4] = *b"SELT";
+
+/// Protocol version announced in the handshake; version 1 is the bare PCM stream
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Protocol version of a stream of typed frames
+pub const TYPED_PROTOCOL_VERSION: u32 = 3;
+
+/// Header length in bytes
+pub const HEADER_LEN: usize = 20;
+
+/// Header length of a version 1 frame, which has no SampleOffset
+pub const LEGACY_HEADER_LEN: usize = 12;
+
+/// Header length of a typed frame in bytes
+pub const TYPED_HEADER_LEN: usize = 44;
+
+/// Largest payload the decoder accepts (10 s of 48 kHz stereo); anything bigger is treated
+/// as corruption rather than allocated
+pub const MAX_PAYLOAD_LEN: u32 = 48_000 * 2 * 2 * 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Interleaved i16 stereo samples
+    #[default]
+    Pcm,
+    /// JSON `Handshake`
+    Handshake,
+    /// Per-channel `ChannelLevel`s of the preceding PCM frame
+    Energy,
+    /// `shm::SlotRef` to the samples in shared memory
+    SharedPcm,
+    /// JSON timeline entry of a `marker` command, typed frames only
+    Marker,
+    /// JSON `stats` snapshot, typed frames only
+    Stats,
+    /// JSON final counters, the last typed frame of a stream
+    EndOfStream,
+}
+
+impl FrameKind {
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            FrameKind::Pcm => MAGIC,
+            FrameKind::Handshake => HANDSHAKE_MAGIC,
+            FrameKind::Energy => ENERGY_MAGIC,
+            FrameKind::SharedPcm => SHARED_MAGIC,
+            FrameKind::Marker | FrameKind::Stats | FrameKind::EndOfStream => TYPED_MAGIC,
+        }
+    }
+
+    /// Type byte of a typed frame, `None` for the kinds only sent with the short header
+    pub fn type_code(self) -> Option<u8> {
+        match self {
+            FrameKind::Pcm => Some(1),
+            FrameKind::Marker => Some(2),
+            FrameKind::Stats => Some(3),
+            FrameKind::EndOfStream => Some(4),
+            FrameKind::Handshake | FrameKind::Energy | FrameKind::SharedPcm => None,
+        }
+    }
+
+    fn from_type_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(FrameKind::Pcm),
+            2 => Some(FrameKind::Marker),
+            3 => Some(FrameKind::Stats),
+            4 => Some(FrameKind::EndOfStream),
+            _ => None,
+        }
+    }
+
+    fn from_magic(magic: [u8; 4]) -> Option<Self> {
+        match magic {
+            MAGIC => Some(FrameKind::Pcm),
+            HANDSHAKE_MAGIC => Some(FrameKind::Handshake),
+            ENERGY_MAGIC => Some(FrameKind::Energy),
+            SHARED_MAGIC => Some(FrameKind::SharedPcm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: FrameKind,
+    pub sequence: u32,
+    pub sample_offset: u64,
+    /// Payload size in bytes
+    pub payload_len: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub header: FrameHeader,
+    /// Interleaved stereo samples of a PCM frame
+    pub samples: Vec<i16>,
+    /// Payload of any other kind of frame
+    pub data: Vec<u8>,
+    /// Timestamps of a typed frame
+    pub timestamps: Option<Timestamps>,
+}
+
+/// When a typed frame was completed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamps {
+    /// Since the start of the stream
+    pub monotonic_ns: u64,
+    /// Microseconds since the Unix epoch
+    pub utc_us: i64,
+}
+
+impl Frame {
+    /// The parsed payload of a handshake frame
+    pub fn handshake(&self) -> Option<serde_json::Result<Handshake>> {
+        (self.header.kind == FrameKind::Handshake).then(|| serde_json::from_slice(&self.data))
+    }
+
+    /// The levels of an energy frame; `None` for other kinds or a truncated payload
+    pub fn energy(&self) -> Option<Vec<ChannelLevel>> {
+        if self.header.kind != FrameKind::Energy || !self.data.len().is_multiple_of(4) {
+            return None;
+        }
+        let levels = self
+            .data
+            .chunks_exact(4)
+            .map(|b| ChannelLevel {
+                rms: u16::from_le_bytes([b[0], b[1]]),
+                peak: u16::from_le_bytes([b[2], b[3]]),
+            })
+            .collect();
+        Some(levels)
+    }
+
+    /// The JSON payload of a marker, stats or end-of-stream frame
+    pub fn json(&self) -> Option<serde_json::Result<serde_json::Value>> {
+        matches!(
+            self.header.kind,
+            FrameKind::Marker | FrameKind::Stats | FrameKind::EndOfStream
+        )
+        .then(|| serde_json::from_slice(&self.data))
+    }
+
+    /// The slot a shared PCM frame points at
+    pub fn shared(&self) -> Option<SlotRef> {
+        (self.header.kind == FrameKind::SharedPcm)
+            .then(|| SlotRef::from_bytes(&self.data))
+            .flatten()
+    }
+}
+
+/// Level of one channel over a frame, as sample magnitudes (full scale is 32768)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelLevel {
+    pub rms: u16,
+    pub peak: u16,
+}
+
+/// Per-channel levels of interleaved `samples`
+pub fn levels(samples: &[i16], channels: usize) -> Vec<ChannelLevel> {
+    let channels = channels.max(1);
+    let frames = samples.len() / channels;
+    (0..channels)
+        .map(|channel| {
+            let (mut squares, mut peak) = (0f64, 0u16);
+            for &sample in samples.iter().skip(channel).step_by(channels).take(frames) {
+                squares += f64::from(sample) * f64::from(sample);
+                peak = peak.max(sample.unsigned_abs());
+            }
+            let rms = if frames == 0 {
+                0.0
+            } else {
+                (squares / frames as f64).sqrt()
+            };
+            ChannelLevel {
+                rms: rms.round() as u16,
+                peak,
+            }
+        })
+        .collect()
+}
+
+/// What the sidecar announces at the start of the stream. A consumer that wants any of
+/// `optional_frames` asks for them with the `negotiate` control command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol: u32,
+    /// Session options in effect, e.g. `anonymize`
+    pub features: Vec<String>,
+    /// Always `s16le`
+    pub sample_format: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Always 16
+    #[serde(default = "default_bits_per_sample")]
+    pub bits_per_sample: u16,
+    /// Length of a full PCM frame
+    pub frame_duration_ms: u32,
+    /// Frame types a consumer can opt into
+    pub optional_frames: Vec<String>,
+    /// Downstream pipeline the session is meant for (`--pipeline-tag`), e.g. `demo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline_tag: Option<String>,
+    /// `--session`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// What each interleaved channel carries, e.g. `["mic", "loopback"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channel_layout: Vec<String>,
+    /// The handshake is sent again every this many PCM frames (`--handshake-every`), so a
+    /// consumer joining mid-stream can set up its decoder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_every_frames: Option<u32>,
+}
+
+fn default_bits_per_sample() -> u16 {
+    16
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The next four bytes are not a frame magic
+    BadMagic([u8; 4]),
+    /// PCM payload is not a whole number of i16 samples
+    OddPayload(u32),
+    PayloadTooLarge(u32),
+    /// Typed frame of a type this decoder doesn't know
+    UnknownType(u8),
+    /// Typed frame whose CRC32 doesn't match its contents
+    BadChecksum {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic(found) => write!(f, "bad frame magic {:02x?}", found),
+            DecodeError::OddPayload(len) => write!(f, "odd payload length {}", len),
+            DecodeError::PayloadTooLarge(len) => {
+                write!(f, "payload length {} exceeds {}", len, MAX_PAYLOAD_LEN)
+            }
+            DecodeError::UnknownType(code) => write!(f, "unknown frame type {}", code),
+            DecodeError::BadChecksum { expected, found } => {
+                write!(f, "frame CRC32 {:08x}, expected {:08x}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Encode one frame header into bytes
+pub fn encode_header(header: &FrameHeader) -> [u8; HEADER_LEN] {
+    let mut bytes = [0u8; HEADER_LEN];
+    bytes[0..4].copy_from_slice(&header.kind.magic());
+    bytes[4..8].copy_from_slice(&header.sequence.to_le_bytes());
+    bytes[8..16].copy_from_slice(&header.sample_offset.to_le_bytes());
+    bytes[16..20].copy_from_slice(&header.payload_len.to_le_bytes());
+    bytes
+}
+
+/// Encode a complete frame (header + samples)
+pub fn encode_frame(samples: &[i16], sequence: u32, sample_offset: u64) -> Vec<u8> {
+    let header = FrameHeader {
+        kind: FrameKind::Pcm,
+        sequence,
+        sample_offset,
+        payload_len: (samples.len() * 2) as u32,
+    };
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + samples.len() * 2);
+    bytes.extend_from_slice(&encode_header(&header));
+    for &sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+/// Encode a version 1 frame, for a consumer that hasn't been sent the handshake
+pub fn encode_legacy_frame(samples: &[i16], sequence: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(LEGACY_HEADER_LEN + samples.len() * 2);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&sequence.to_le_bytes());
+    bytes.extend_from_slice(&((samples.len() * 2) as u32).to_le_bytes());
+    for &sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+/// Encode a handshake frame
+pub fn encode_handshake(handshake: &Handshake) -> Vec<u8> {
+    let payload = serde_json::to_vec(handshake).expect("handshake serializes");
+    let header = FrameHeader {
+        kind: FrameKind::Handshake,
+        sequence: 0,
+        sample_offset: 0,
+        payload_len: payload.len() as u32,
+    };
+    let mut bytes = encode_header(&header).to_vec();
+    bytes.extend(payload);
+    bytes
+}
+
+/// Encode the energy frame for the PCM frame `sequence` at `sample_offset`
+pub fn encode_energy(levels: &[ChannelLevel], sequence: u32, sample_offset: u64) -> Vec<u8> {
+    let header = FrameHeader {
+        kind: FrameKind::Energy,
+        sequence,
+        sample_offset,
+        payload_len: (levels.len() * 4) as u32,
+    };
+    let mut bytes = encode_header(&header).to_vec();
+    for level in levels {
+        bytes.extend_from_slice(&level.rms.to_le_bytes());
+        bytes.extend_from_slice(&level.peak.to_le_bytes());
+    }
+    bytes
+}
+
+/// Encode the reference frame for a PCM frame published in shared memory
+pub fn encode_shared(slot_ref: SlotRef, sequence: u32, sample_offset: u64) -> Vec<u8> {
+    let header = FrameHeader {
+        kind: FrameKind::SharedPcm,
+        sequence,
+        sample_offset,
+        payload_len: SlotRef::ENCODED_LEN as u32,
+    };
+    let mut bytes = encode_header(&header).to_vec();
+    bytes.extend_from_slice(&slot_ref.to_bytes());
+    bytes
+}
+
+/// Encode a typed frame of `kind` with `payload`
+pub fn encode_typed(
+    kind: FrameKind,
+    sequence: u32,
+    sample_offset: u64,
+    timestamps: Timestamps,
+    payload: &[u8],
+) -> Vec<u8> {
+    let code = kind.type_code().expect("kind has a typed form");
+    let mut bytes = Vec::with_capacity(TYPED_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&TYPED_MAGIC);
+    bytes.extend_from_slice(&[code, 0, 0, 0]);
+    bytes.extend_from_slice(&sequence.to_le_bytes());
+    bytes.extend_from_slice(&sample_offset.to_le_bytes());
+    bytes.extend_from_slice(&timestamps.monotonic_ns.to_le_bytes());
+    bytes.extend_from_slice(&timestamps.utc_us.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    let crc = crc32_update(crc32_update(!0, &bytes), payload);
+    bytes.extend_from_slice(&(!crc).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Encode a typed PCM frame
+pub fn encode_typed_pcm(
+    samples: &[i16],
+    sequence: u32,
+    sample_offset: u64,
+    timestamps: Timestamps,
+) -> Vec<u8> {
+    let payload: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    encode_typed(
+        FrameKind::Pcm,
+        sequence,
+        sample_offset,
+        timestamps,
+        &payload,
+    )
+}
+
+/// CRC32 (IEEE, as in zlib) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |mut crc, &byte| {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+/// Write a complete frame and flush it
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    samples: &[i16],
+    sequence: u32,
+    sample_offset: u64,
+) -> io::Result<()> {
+    writer.write_all(&encode_frame(samples, sequence, sample_offset))?;
+    // Flush so the consumer sees the frame immediately
+    writer.flush()
+}
+
+/// Parse a header from the start of `bytes`, which must hold at least `HEADER_LEN` bytes
+pub fn decode_header(bytes: &[u8]) -> Result<FrameHeader, DecodeError> {
+    let magic: [u8; 4] = bytes[0..4].try_into().unwrap();
+    let kind = FrameKind::from_magic(magic).ok_or(DecodeError::BadMagic(magic))?;
+
+    let payload_len = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+    check_payload_len(kind, payload_len)?;
+
+    Ok(FrameHeader {
+        kind,
+        sequence: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        sample_offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        payload_len,
+    })
+}
+
+/// Parse a version 1 header from the start of `bytes`, which must hold at least
+/// `LEGACY_HEADER_LEN` bytes. It carries no SampleOffset, so that is 0.
+pub fn decode_legacy_header(bytes: &[u8]) -> Result<FrameHeader, DecodeError> {
+    let magic: [u8; 4] = bytes[0..4].try_into().unwrap();
+    let kind = FrameKind::from_magic(magic).ok_or(DecodeError::BadMagic(magic))?;
+
+    let payload_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    check_payload_len(kind, payload_len)?;
+
+    Ok(FrameHeader {
+        kind,
+        sequence: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        sample_offset: 0,
+        payload_len,
+    })
+}
+
+/// Parse a typed header from the start of `bytes`, which must hold at least
+/// `TYPED_HEADER_LEN` bytes, with the timestamps and the CRC32 it carries
+pub fn decode_typed_header(bytes: &[u8]) -> Result<(FrameHeader, Timestamps, u32), DecodeError> {
+    let magic: [u8; 4] = bytes[0..4].try_into().unwrap();
+    if magic != TYPED_MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+    let kind = FrameKind::from_type_code(bytes[4]).ok_or(DecodeError::UnknownType(bytes[4]))?;
+
+    let payload_len = u32::from_le_bytes(bytes[36..40].try_into().unwrap());
+    check_payload_len(kind, payload_len)?;
+
+    let header = FrameHeader {
+        kind,
+        sequence: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        sample_offset: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+        payload_len,
+    };
+    let timestamps = Timestamps {
+        monotonic_ns: u64::from_le_bytes(bytes[20..28].try_into().unwrap()),
+        utc_us: i64::from_le_bytes(bytes[28..36].try_into().unwrap()),
+    };
+    let crc = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+    Ok((header, timestamps, crc))
+}
+
+fn check_payload_len(kind: FrameKind, payload_len: u32) -> Result<(), DecodeError> {
+    if kind == FrameKind::Pcm && !payload_len.is_multiple_of(2) {
+        return Err(DecodeError::OddPayload(payload_len));
+    }
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(DecodeError::PayloadTooLarge(payload_len));
+    }
+    Ok(())
+}
+
+/// Incremental decoder for a byte stream that arrives in arbitrary chunks. It reads short
+/// version 1 headers until the stream sends a handshake and long headers from then on.
+/// Version 1 frames carry no SampleOffset; the decoder counts it from the frames before,
+/// which is exact when it reads the stream from its start.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    greeted: bool,
+    /// Stereo pairs of the version 1 frames decoded so far
+    legacy_position: u64,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decoder for a stream that has already sent its handshake, e.g. to a consumer that
+    /// read it and handed the rest over
+    pub fn greeted() -> Self {
+        Self {
+            greeted: true,
+            ..Self::default()
+        }
+    }
+
+    /// Append bytes read from the stream
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Bytes received but not yet consumed by a complete frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Take the next complete frame, `Ok(None)` if more bytes are needed.
+    /// On error the offending header byte is discarded, so calling again continues the
+    /// search for the next frame; `resync` skips the rest of the corruption at once.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, DecodeError> {
+        let short_header_len = if self.greeted {
+            HEADER_LEN
+        } else {
+            LEGACY_HEADER_LEN
+        };
+        if self.buffer.len() < short_header_len {
+            return Ok(None);
+        }
+
+        let magic: [u8; 4] = self.buffer[0..4].try_into().unwrap();
+        let typed = magic == TYPED_MAGIC;
+        let legacy = !self.greeted && !typed && magic != HANDSHAKE_MAGIC;
+        let header_len = match (typed, legacy) {
+            (true, _) => TYPED_HEADER_LEN,
+            (false, true) => LEGACY_HEADER_LEN,
+            (false, false) => HEADER_LEN,
+        };
+        if self.buffer.len() < header_len {
+            return Ok(None);
+        }
+        let decoded = if typed {
+            decode_typed_header(&self.buffer)
+                .map(|(header, timestamps, crc)| (header, Some((timestamps, crc))))
+        } else if legacy {
+            decode_legacy_header(&self.buffer).map(|header| (header, None))
+        } else {
+            decode_header(&self.buffer).map(|header| (header, None))
+        };
+        let (mut header, typed) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.buffer.drain(..1);
+                return Err(e);
+            }
+        };
+
+        let frame_len = header_len + header.payload_len as usize;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let timestamps = match typed {
+            Some((timestamps, expected)) => {
+                let crc = crc32_update(!0, &self.buffer[..header_len - 4]);
+                let found = !crc32_update(crc, &self.buffer[header_len..frame_len]);
+                if found != expected {
+                    self.buffer.drain(..1);
+                    return Err(DecodeError::BadChecksum { expected, found });
+                }
+                Some(timestamps)
+            }
+            None => None,
+        };
+        let payload = &self.buffer[header_len..frame_len];
+        let (samples, data) = match header.kind {
+            FrameKind::Pcm => (
+                payload
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect(),
+                Vec::new(),
+            ),
+            _ => (Vec::new(), payload.to_vec()),
+        };
+        self.buffer.drain(..frame_len);
+        match header.kind {
+            FrameKind::Handshake => self.greeted = true,
+            FrameKind::Pcm if legacy => {
+                header.sample_offset = self.legacy_position;
+                self.legacy_position += (samples.len() / 2) as u64;
+            }
+            _ => {}
+        }
+
+        Ok(Some(Frame {
+            header,
+            samples,
+            data,
+            timestamps,
+        }))
+    }
+
+    /// Drop bytes up to the next frame magic (or the last three bytes, which could be the
+    /// start of one) and return how many were dropped. Call after an error to skip past
+    /// corruption in one go rather than a byte per call.
+    pub fn resync(&mut self) -> usize {
+        let skipped = (0..self.buffer.len())
+            .find(|&at| {
+                let rest = &self.buffer[at..];
+                ALL_MAGICS
+                    .iter()
+                    .any(|magic| magic.starts_with(&rest[..rest.len().min(4)]))
+            })
+            .unwrap_or(self.buffer.len());
+        self.buffer.drain(..skipped);
+        skipped
+    }
+}
+
+const ALL_MAGICS: [[u8; 4]; 5] = [
+    MAGIC,
+    HANDSHAKE_MAGIC,
+    ENERGY_MAGIC,
+    SHARED_MAGIC,
+    TYPED_MAGIC,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Golden vector: frame 7 at sample offset 4800 carrying two stereo pairs
+    /// (1, -1) and (32767, -32768)
+    const GOLDEN_FRAME: [u8; 28] = [
+        0x53, 0x45, 0x4c, 0x4c, // "SELL"
+        0x07, 0x00, 0x00, 0x00, // sequence 7
+        0xc0, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // sample offset 4800
+        0x08, 0x00, 0x00, 0x00, // 8 payload bytes
+        0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x80, // samples
+    ];
+
+    /// The same frame as version 1 sends it, without the sample offset
+    const GOLDEN_LEGACY_FRAME: [u8; 20] = [
+        0x53, 0x45, 0x4c, 0x4c, // "SELL"
+        0x07, 0x00, 0x00, 0x00, // sequence 7
+        0x08, 0x00, 0x00, 0x00, // 8 payload bytes
+        0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x80, // samples
+    ];
+
+    fn golden_samples() -> Vec<i16> {
+        vec![1, -1, i16::MAX, i16::MIN]
+    }
+
+    #[test]
+    fn encodes_golden_frame() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &golden_samples(), 7, 4800).unwrap();
+        assert_eq!(bytes, GOLDEN_FRAME);
+    }
+
+    #[test]
+    fn decodes_golden_frame_byte_by_byte() {
+        let mut decoder = FrameDecoder::greeted();
+        for &byte in &GOLDEN_FRAME[..GOLDEN_FRAME.len() - 1] {
+            decoder.push(&[byte]);
+            assert_eq!(decoder.next_frame(), Ok(None));
+        }
+        decoder.push(&GOLDEN_FRAME[GOLDEN_FRAME.len() - 1..]);
+
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            frame.header,
+            FrameHeader {
+                kind: FrameKind::Pcm,
+                sequence: 7,
+                sample_offset: 4800,
+                payload_len: 8,
+            }
+        );
+        assert_eq!(frame.samples, golden_samples());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn short_headers_until_the_handshake() {
+        assert_eq!(
+            encode_legacy_frame(&golden_samples(), 7),
+            GOLDEN_LEGACY_FRAME
+        );
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&GOLDEN_LEGACY_FRAME);
+        decoder.push(&encode_legacy_frame(&[5, -5], 8));
+        decoder.push(&encode_handshake(&Handshake {
+            protocol: PROTOCOL_VERSION,
+            features: Vec::new(),
+            sample_format: "s16le".to_string(),
+            sample_rate: 48_000,
+            channels: 2,
+            bits_per_sample: 16,
+            frame_duration_ms: 100,
+            optional_frames: Vec::new(),
+            pipeline_tag: None,
+            session: None,
+            channel_layout: Vec::new(),
+            repeat_every_frames: None,
+        }));
+        decoder.push(&GOLDEN_FRAME);
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap()).collect();
+        let headers: Vec<_> = frames
+            .iter()
+            .map(|frame| {
+                (
+                    frame.header.kind,
+                    frame.header.sequence,
+                    frame.header.sample_offset,
+                )
+            })
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                (FrameKind::Pcm, 7, 0),
+                (FrameKind::Pcm, 8, 2),
+                (FrameKind::Handshake, 0, 0),
+                (FrameKind::Pcm, 7, 4800),
+            ]
+        );
+        assert_eq!(frames[0].samples, golden_samples());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn empty_frame_round_trips() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &[], u32::MAX, u64::MAX).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN);
+
+        let mut decoder = FrameDecoder::greeted();
+        decoder.push(&bytes);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.header.sequence, u32::MAX);
+        assert_eq!(frame.header.sample_offset, u64::MAX);
+        assert!(frame.samples.is_empty());
+    }
+
+    #[test]
+    fn skips_garbage_before_a_frame() {
+        let mut decoder = FrameDecoder::greeted();
+        decoder.push(b"xy");
+        decoder.push(&GOLDEN_FRAME);
+
+        let mut errors = 0;
+        let frame = loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => break frame,
+                Ok(None) => panic!("frame should be complete"),
+                Err(DecodeError::BadMagic(_)) => errors += 1,
+                Err(e) => panic!("unexpected error {}", e),
+            }
+        };
+        assert_eq!(errors, 2);
+        assert_eq!(frame.samples, golden_samples());
+    }
+
+    #[test]
+    fn rejects_odd_and_oversized_payloads() {
+        let mut odd = GOLDEN_FRAME;
+        odd[16] = 0x07;
+        assert_eq!(decode_header(&odd), Err(DecodeError::OddPayload(7)));
+
+        let mut huge = GOLDEN_FRAME;
+        huge[16..20].copy_from_slice(&(MAX_PAYLOAD_LEN + 2).to_le_bytes());
+        assert_eq!(
+            decode_header(&huge),
+            Err(DecodeError::PayloadTooLarge(MAX_PAYLOAD_LEN + 2))
+        );
+    }
+
+    #[test]
+    fn handshake_round_trips_ahead_of_pcm() {
+        let handshake = Handshake {
+            protocol: PROTOCOL_VERSION,
+            features: vec!["anonymize".to_string()],
+            sample_format: "s16le".to_string(),
+            sample_rate: 48_000,
+            channels: 2,
+            bits_per_sample: 16,
+            frame_duration_ms: 100,
+            optional_frames: Vec::new(),
+            pipeline_tag: Some("demo".to_string()),
+            session: Some("call-1".to_string()),
+            channel_layout: vec!["mic".to_string(), "loopback".to_string()],
+            repeat_every_frames: Some(50),
+        };
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode_handshake(&handshake));
+        decoder.push(&GOLDEN_FRAME);
+
+        let first = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(first.header.kind, FrameKind::Handshake);
+        assert_eq!(first.handshake().unwrap().unwrap(), handshake);
+        let second = decoder.next_frame().unwrap().unwrap();
+        assert!(second.handshake().is_none());
+        assert_eq!(second.samples, golden_samples());
+    }
+
+    #[test]
+    fn energy_frame_carries_per_channel_levels() {
+        let levels = levels(&golden_samples(), 2);
+        assert_eq!(
+            levels,
+            [
+                ChannelLevel {
+                    rms: 23_170,
+                    peak: 32_767
+                },
+                ChannelLevel {
+                    rms: 23_170,
+                    peak: 32_768
+                },
+            ]
+        );
+
+        let mut decoder = FrameDecoder::greeted();
+        decoder.push(&encode_energy(&levels, 7, 4800));
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.header.kind, FrameKind::Energy);
+        assert_eq!(
+            (frame.header.sequence, frame.header.sample_offset),
+            (7, 4800)
+        );
+        assert_eq!(frame.energy().unwrap(), levels);
+        assert!(frame.handshake().is_none());
+    }
+
+    #[test]
+    fn typed_frames_carry_timestamps_and_a_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let timestamps = Timestamps {
+            monotonic_ns: 100_000_000,
+            utc_us: 1_760_000_000_000_000,
+        };
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode_typed_pcm(&golden_samples(), 7, 4800, timestamps));
+        decoder.push(&encode_typed(
+            FrameKind::EndOfStream,
+            8,
+            4802,
+            timestamps,
+            br#"{"frames":8}"#,
+        ));
+
+        let pcm = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            (pcm.header.kind, pcm.header.sequence, pcm.timestamps),
+            (FrameKind::Pcm, 7, Some(timestamps))
+        );
+        assert_eq!(pcm.samples, golden_samples());
+        let end = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(end.header.kind, FrameKind::EndOfStream);
+        assert_eq!(end.json().unwrap().unwrap()["frames"], 8);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn resyncs_past_a_corrupted_frame() {
+        let timestamps = Timestamps::default();
+        let mut corrupted = encode_typed_pcm(&golden_samples(), 7, 4800, timestamps);
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x01;
+
+        let mut decoder = FrameDecoder::greeted();
+        decoder.push(&corrupted);
+        decoder.push(b"x");
+        decoder.push(&GOLDEN_FRAME);
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(DecodeError::BadChecksum { .. })
+        ));
+        // The rest of the frame and the junk byte after it go at once
+        assert_eq!(decoder.resync(), corrupted.len());
+        assert_eq!(decoder.resync(), 0);
+        assert_eq!(
+            decoder.next_frame().unwrap().unwrap().samples,
+            golden_samples()
+        );
+    }
+}
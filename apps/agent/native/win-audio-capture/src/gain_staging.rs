@@ -0,0 +1,247 @@
+//! Gain staging report
+//! Watches the first seconds of a session and estimates, per channel, the noise floor
+//! (quiet 50ms windows) and speech peaks (loud windows), then produces a one-time report
+//! with a level recommendation the onboarding flow can show to the rep.
+
+use serde::Serialize;
+
+/// Audio analyzed before the report is produced
+pub const ANALYSIS_SECS: u32 = 15;
+
+/// Analysis window
+const WINDOW_MS: u32 = 50;
+
+/// Speech peaks should land around here, leaving headroom for laughter and emphasis
+const TARGET_PEAK_DBFS: f64 = -12.0;
+
+/// Channel readings below this are treated as no signal
+const SILENT_DBFS: f64 = -60.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelLevels {
+    /// 10th percentile of window RMS
+    pub noise_floor_dbfs: f64,
+    /// 95th percentile of window peaks
+    pub speech_peak_dbfs: f64,
+    /// Fraction of samples at full scale
+    pub clipped_ratio: f64,
+    pub recommendation: Recommendation,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Recommendation {
+    Ok,
+    Raise {
+        db: f64,
+    },
+    Lower {
+        db: f64,
+    },
+    /// Nothing above the silence threshold, e.g. a muted or wrong device
+    NoSignal,
+    /// Levels are fine but the background is loud relative to speech
+    Noisy,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GainReport {
+    pub analyzed_secs: f64,
+    pub mic: ChannelLevels,
+    pub loopback: ChannelLevels,
+}
+
+#[derive(Default)]
+struct ChannelStats {
+    window_rms: Vec<f64>,
+    window_peaks: Vec<f64>,
+    sum_squares: f64,
+    peak: i32,
+    clipped: u64,
+}
+
+impl ChannelStats {
+    fn push(&mut self, sample: i16) {
+        self.sum_squares += (sample as f64) * (sample as f64);
+        self.peak = self.peak.max((sample as i32).abs());
+        if sample == i16::MAX || sample <= -i16::MAX {
+            self.clipped += 1;
+        }
+    }
+
+    fn close_window(&mut self, len: usize) {
+        let rms = (self.sum_squares / len as f64).sqrt();
+        self.window_rms.push(dbfs(rms));
+        self.window_peaks.push(dbfs(self.peak as f64));
+        self.sum_squares = 0.0;
+        self.peak = 0;
+    }
+
+    fn levels(&self, samples: u64, raise_hint: bool) -> ChannelLevels {
+        let noise_floor = percentile(&self.window_rms, 0.10);
+        let speech_peak = percentile(&self.window_peaks, 0.95);
+        let clipped_ratio = self.clipped as f64 / samples.max(1) as f64;
+
+        let recommendation = if speech_peak < SILENT_DBFS {
+            Recommendation::NoSignal
+        } else if clipped_ratio > 1e-4 || speech_peak > -3.0 {
+            Recommendation::Lower {
+                db: round_db(speech_peak - TARGET_PEAK_DBFS),
+            }
+        } else if raise_hint && speech_peak < TARGET_PEAK_DBFS - 12.0 {
+            Recommendation::Raise {
+                db: round_db(TARGET_PEAK_DBFS - speech_peak),
+            }
+        } else if speech_peak - noise_floor < 20.0 {
+            Recommendation::Noisy
+        } else {
+            Recommendation::Ok
+        };
+
+        ChannelLevels {
+            noise_floor_dbfs: round_db(noise_floor),
+            speech_peak_dbfs: round_db(speech_peak),
+            clipped_ratio,
+            recommendation,
+        }
+    }
+}
+
+/// Accumulates interleaved stereo blocks until the analysis period is full
+pub struct GainStaging {
+    sample_rate: u32,
+    window_len: usize,
+    in_window: usize,
+    frames: u64,
+    mic: ChannelStats,
+    loopback: ChannelStats,
+    done: bool,
+}
+
+impl GainStaging {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            window_len: (sample_rate * WINDOW_MS / 1000).max(1) as usize,
+            in_window: 0,
+            frames: 0,
+            mic: ChannelStats::default(),
+            loopback: ChannelStats::default(),
+            done: false,
+        }
+    }
+
+    /// Feed a block; returns the report once, when the analysis period completes
+    pub fn observe(&mut self, block: &[i16]) -> Option<GainReport> {
+        if self.done {
+            return None;
+        }
+
+        for pair in block.chunks_exact(2) {
+            self.mic.push(pair[0]);
+            self.loopback.push(pair[1]);
+            self.in_window += 1;
+            self.frames += 1;
+            if self.in_window == self.window_len {
+                self.mic.close_window(self.window_len);
+                self.loopback.close_window(self.window_len);
+                self.in_window = 0;
+            }
+        }
+
+        if self.frames < self.sample_rate as u64 * ANALYSIS_SECS as u64 {
+            return None;
+        }
+        self.done = true;
+        Some(GainReport {
+            analyzed_secs: self.frames as f64 / self.sample_rate as f64,
+            mic: self.mic.levels(self.frames, true),
+            // Quiet system audio usually just means nobody is talking yet, so only loud
+            // output gets a recommendation
+            loopback: self.loopback.levels(self.frames, false),
+        })
+    }
+}
+
+fn dbfs(level: f64) -> f64 {
+    if level <= 0.0 {
+        return -120.0;
+    }
+    (20.0 * (level / i16::MAX as f64).log10()).max(-120.0)
+}
+
+fn percentile(values: &[f64], fraction: f64) -> f64 {
+    if values.is_empty() {
+        return -120.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted[((sorted.len() - 1) as f64 * fraction).round() as usize]
+}
+
+fn round_db(db: f64) -> f64 {
+    (db * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16_000;
+
+    /// Alternating half-second bursts of tone at `speech_dbfs` and noise at `noise_dbfs`
+    fn signal(n: usize, speech_dbfs: f64, noise_dbfs: f64) -> i16 {
+        let amplitude = |db: f64| 10f64.powf(db / 20.0) * i16::MAX as f64;
+        let tone = (n as f64 * 0.3).sin();
+        let noise = ((n * 7919) % 200) as f64 / 100.0 - 1.0;
+        let value = if (n / (RATE as usize / 2)).is_multiple_of(2) {
+            tone * amplitude(speech_dbfs)
+        } else {
+            noise * amplitude(noise_dbfs)
+        };
+        value as i16
+    }
+
+    fn run(mic: (f64, f64), loopback: (f64, f64)) -> GainReport {
+        let mut staging = GainStaging::new(RATE);
+        let block: Vec<i16> = (0..RATE as usize * ANALYSIS_SECS as usize)
+            .flat_map(|n| [signal(n, mic.0, mic.1), signal(n, loopback.0, loopback.1)])
+            .collect();
+        let mut report = None;
+        for chunk in block.chunks(2 * 160) {
+            if let Some(r) = staging.observe(chunk) {
+                assert!(report.is_none());
+                report = Some(r);
+            }
+        }
+        report.expect("report after the analysis period")
+    }
+
+    #[test]
+    fn quiet_mic_gets_a_raise_recommendation() {
+        let report = run((-36.0, -70.0), (-14.0, -80.0));
+        assert!((report.mic.speech_peak_dbfs + 36.0).abs() < 1.0);
+        match report.mic.recommendation {
+            Recommendation::Raise { db } => assert!((db - 24.0).abs() < 1.0, "{}", db),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(report.loopback.recommendation, Recommendation::Ok);
+    }
+
+    #[test]
+    fn hot_system_audio_gets_a_lower_recommendation() {
+        let report = run((-12.0, -70.0), (-1.0, -80.0));
+        assert!(matches!(
+            report.loopback.recommendation,
+            Recommendation::Lower { .. }
+        ));
+        assert_eq!(report.mic.recommendation, Recommendation::Ok);
+    }
+
+    #[test]
+    fn noisy_room_and_dead_channel_are_flagged() {
+        let report = run((-12.0, -25.0), (-200.0, -200.0));
+        assert_eq!(report.mic.recommendation, Recommendation::Noisy);
+        assert_eq!(report.loopback.recommendation, Recommendation::NoSignal);
+    }
+}
@@ -0,0 +1,117 @@
+//! Headset call-control buttons
+//! Jabra, Poly and other call headsets report their buttons on the HID telephony usage page.
+//! This turns the button state in those input reports into press events; the Windows
+//! listener that receives the reports lives in `hid_telephony`, the session maps the presses
+//! to markers, MIC mute and stop.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// HID usage page for telephony devices
+pub const USAGE_PAGE_TELEPHONY: u16 = 0x0B;
+/// Top-level collection headsets expose their call controls in
+pub const USAGE_HEADSET: u16 = 0x05;
+const USAGE_HOOK_SWITCH: u16 = 0x20;
+const USAGE_FLASH: u16 = 0x21;
+const USAGE_PHONE_MUTE: u16 = 0x2F;
+
+/// `--headset-controls`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadsetControls {
+    /// Ignore headset buttons
+    Off,
+    /// Mark button presses in the timeline only
+    Markers,
+    /// Markers, plus the mute button mutes the MIC channel and hanging up stops capture
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadsetButton {
+    /// Call answered or placed on the headset
+    OffHook,
+    /// Call ended on the headset
+    OnHook,
+    Mute,
+    Flash,
+}
+
+/// Button state of one input report, so presses are reported once per edge
+#[derive(Debug, Default)]
+pub struct ButtonTracker {
+    off_hook: bool,
+    mute_down: bool,
+    flash_down: bool,
+}
+
+impl ButtonTracker {
+    /// Feed the telephony usages set in one input report and get the buttons that changed.
+    /// Hook switch is a level control (set while off-hook); mute and flash are momentary
+    /// and count once per press.
+    pub fn report(&mut self, usages: &[u16]) -> Vec<HeadsetButton> {
+        let mut pressed = Vec::new();
+
+        let off_hook = usages.contains(&USAGE_HOOK_SWITCH);
+        if off_hook != self.off_hook {
+            self.off_hook = off_hook;
+            pressed.push(if off_hook {
+                HeadsetButton::OffHook
+            } else {
+                HeadsetButton::OnHook
+            });
+        }
+
+        let mute_down = usages.contains(&USAGE_PHONE_MUTE);
+        if mute_down && !self.mute_down {
+            pressed.push(HeadsetButton::Mute);
+        }
+        self.mute_down = mute_down;
+
+        let flash_down = usages.contains(&USAGE_FLASH);
+        if flash_down && !self.flash_down {
+            pressed.push(HeadsetButton::Flash);
+        }
+        self.flash_down = flash_down;
+
+        pressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_switch_reports_both_edges() {
+        let mut tracker = ButtonTracker::default();
+        assert_eq!(
+            tracker.report(&[USAGE_HOOK_SWITCH]),
+            [HeadsetButton::OffHook]
+        );
+        assert!(tracker.report(&[USAGE_HOOK_SWITCH]).is_empty());
+        assert_eq!(tracker.report(&[]), [HeadsetButton::OnHook]);
+        assert!(tracker.report(&[]).is_empty());
+    }
+
+    #[test]
+    fn mute_counts_once_per_press() {
+        let mut tracker = ButtonTracker::default();
+        let held = [USAGE_HOOK_SWITCH, USAGE_PHONE_MUTE];
+        assert_eq!(
+            tracker.report(&held),
+            [HeadsetButton::OffHook, HeadsetButton::Mute]
+        );
+        assert!(tracker.report(&held).is_empty());
+        assert!(tracker.report(&[USAGE_HOOK_SWITCH]).is_empty());
+        assert_eq!(tracker.report(&held), [HeadsetButton::Mute]);
+    }
+
+    #[test]
+    fn reports_without_call_controls_change_nothing() {
+        let mut tracker = ButtonTracker::default();
+        // e.g. a volume-only report
+        assert!(tracker.report(&[0xE9]).is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! Capture health heartbeat
+//! With `--json-logs` the session emits a `stats` event every `--stats-interval-ms`: the
+//! level of the MIC and loopback channels over the interval (RMS and peak, in dBFS) and how
+//! long each has been silent, the frames written so far (`sample_position`), blocks dropped
+//! from the live stream, the
+//! mixer's underruns and overruns, and how full the block queues are. A channel that stays
+//! silent is what the agent turns into a "no audio detected" warning for the rep.
+
+use crate::mixer::MixerStats;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_INTERVAL_MS: u64 = 1_000;
+/// Level reported for digital silence, the floor of 16-bit audio
+const MIN_DBFS: f64 = -96.0;
+/// Peak below which an interval counts as silent
+const SILENCE_DBFS: f64 = -60.0;
+
+/// Mixer counters the engine thread publishes after every round
+#[derive(Debug, Default)]
+pub struct LiveCounters {
+    frames: AtomicU64,
+    mic_underruns: AtomicU64,
+    loopback_underruns: AtomicU64,
+    mic_overruns: AtomicU64,
+    loopback_overruns: AtomicU64,
+    gaps: AtomicU64,
+    skipped_frames: AtomicU64,
+}
+
+impl LiveCounters {
+    pub fn publish(&self, stats: &MixerStats) {
+        self.frames.store(stats.frames, Ordering::Relaxed);
+        self.mic_underruns
+            .store(stats.mic_underruns, Ordering::Relaxed);
+        self.loopback_underruns
+            .store(stats.loopback_underruns, Ordering::Relaxed);
+        self.mic_overruns
+            .store(stats.mic_overruns, Ordering::Relaxed);
+        self.loopback_overruns
+            .store(stats.loopback_overruns, Ordering::Relaxed);
+        self.gaps.store(stats.gaps, Ordering::Relaxed);
+        self.skipped_frames
+            .store(stats.skipped_frames, Ordering::Relaxed);
+    }
+
+    /// The counters as last published; the rest of the stats stay zero
+    pub fn load(&self) -> MixerStats {
+        MixerStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            mic_underruns: self.mic_underruns.load(Ordering::Relaxed),
+            loopback_underruns: self.loopback_underruns.load(Ordering::Relaxed),
+            mic_overruns: self.mic_overruns.load(Ordering::Relaxed),
+            loopback_overruns: self.loopback_overruns.load(Ordering::Relaxed),
+            gaps: self.gaps.load(Ordering::Relaxed),
+            skipped_frames: self.skipped_frames.load(Ordering::Relaxed),
+            ..MixerStats::default()
+        }
+    }
+}
+
+/// One channel's level over an interval
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChannelLevel {
+    pub rms_dbfs: f64,
+    pub peak_dbfs: f64,
+    /// How long the channel has been silent up to now, 0 if this interval had sound
+    pub silent_ms: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Meter {
+    sum_squares: f64,
+    peak: u32,
+    samples: u64,
+    silent_frames: u64,
+}
+
+/// Levels of the stereo mix (left = MIC, right = loopback) between heartbeats
+pub struct Heartbeat {
+    sample_rate: u32,
+    meters: [Meter; 2],
+}
+
+impl Heartbeat {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            meters: [Meter::default(); 2],
+        }
+    }
+
+    pub fn observe(&mut self, stereo: &[i16]) {
+        for frame in stereo.chunks_exact(2) {
+            for (meter, &sample) in self.meters.iter_mut().zip(frame) {
+                meter.sum_squares += sample as f64 * sample as f64;
+                meter.peak = meter.peak.max(sample.unsigned_abs() as u32);
+                meter.samples += 1;
+            }
+        }
+    }
+
+    /// Levels since the last call, starting the next interval
+    pub fn levels(&mut self) -> [ChannelLevel; 2] {
+        let sample_rate = self.sample_rate as u64;
+        let mut levels = [ChannelLevel {
+            rms_dbfs: MIN_DBFS,
+            peak_dbfs: MIN_DBFS,
+            silent_ms: 0,
+        }; 2];
+        for (level, meter) in levels.iter_mut().zip(&mut self.meters) {
+            let rms = match meter.samples {
+                0 => 0.0,
+                samples => (meter.sum_squares / samples as f64).sqrt(),
+            };
+            level.rms_dbfs = dbfs(rms);
+            level.peak_dbfs = dbfs(meter.peak as f64);
+            if level.peak_dbfs < SILENCE_DBFS {
+                meter.silent_frames += meter.samples;
+            } else {
+                meter.silent_frames = 0;
+            }
+            level.silent_ms = meter.silent_frames * 1_000 / sample_rate.max(1);
+            *meter = Meter {
+                silent_frames: meter.silent_frames,
+                ..Meter::default()
+            };
+        }
+        levels
+    }
+}
+
+/// `amplitude` (of 16-bit full scale) in dBFS, to a tenth of a dB
+fn dbfs(amplitude: f64) -> f64 {
+    if amplitude <= 0.0 {
+        return MIN_DBFS;
+    }
+    let db = 20.0 * (amplitude / 32_768.0).log10();
+    (db.max(MIN_DBFS) * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_each_channel_and_counts_silence() {
+        let mut heartbeat = Heartbeat::new(1_000);
+        // Half-scale square wave on the MIC, nothing on the loopback
+        let block: Vec<i16> = (0..1_000)
+            .flat_map(|i| [if i % 2 == 0 { 16_384 } else { -16_384 }, 0])
+            .collect();
+        heartbeat.observe(&block);
+        let [mic, loopback] = heartbeat.levels();
+        assert_eq!(
+            (mic.rms_dbfs, mic.peak_dbfs, mic.silent_ms),
+            (-6.0, -6.0, 0)
+        );
+        assert_eq!((loopback.peak_dbfs, loopback.silent_ms), (MIN_DBFS, 1_000));
+
+        heartbeat.observe(&block);
+        let [_, loopback] = heartbeat.levels();
+        assert_eq!(loopback.silent_ms, 2_000);
+        // An interval without blocks carries the silence on without adding to it
+        assert_eq!(heartbeat.levels()[1].silent_ms, 2_000);
+    }
+
+    #[test]
+    fn counters_read_back_as_published() {
+        let counters = LiveCounters::default();
+        let stats = MixerStats {
+            frames: 480,
+            mic_underruns: 2,
+            loopback_overruns: 7,
+            gaps: 1,
+            ..MixerStats::default()
+        };
+        counters.publish(&stats);
+        assert_eq!(counters.load(), stats);
+    }
+}
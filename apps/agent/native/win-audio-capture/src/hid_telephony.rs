@@ -0,0 +1,244 @@
+//! Headset button listener
+//! Registers for raw input from HID telephony headsets on a message-only window and forwards
+//! button presses to the session loop. Input reports reach every raw input reader, so this
+//! works next to a conferencing app driving the same headset. Many headsets only report the
+//! hook switch once a call is active on them (the app set the off-hook indicator).
+
+#![cfg(windows)]
+
+use crate::error::fail;
+use crate::headset::{ButtonTracker, HeadsetButton, USAGE_HEADSET, USAGE_PAGE_TELEPHONY};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use windows::core::w;
+use windows::Win32::Devices::HumanInterfaceDevice::{
+    HidP_GetSpecificButtonCaps, HidP_GetUsages, HidP_Input, HIDP_BUTTON_CAPS, HIDP_STATUS_SUCCESS,
+    PHIDP_PREPARSED_DATA,
+};
+use windows::Win32::Foundation::{HANDLE, HWND, LPARAM, WPARAM};
+use windows::Win32::System::Threading::GetCurrentThreadId;
+use windows::Win32::UI::Input::{
+    GetRawInputData, GetRawInputDeviceInfoW, RegisterRawInputDevices, HRAWINPUT, RAWINPUT,
+    RAWINPUTDEVICE, RAWINPUTHEADER, RIDEV_INPUTSINK, RIDI_PREPARSEDDATA, RID_INPUT, RIM_TYPEHID,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExW, DestroyWindow, DispatchMessageW, GetMessageW, PostThreadMessageW,
+    HWND_MESSAGE, MSG, WINDOW_EX_STYLE, WINDOW_STYLE, WM_INPUT, WM_QUIT,
+};
+
+/// Most buttons a single telephony report is read for
+const MAX_USAGES: usize = 32;
+
+/// What is needed to parse one headset's reports
+struct Device {
+    /// The report descriptor in the form `HidP_*` parses reports with
+    preparsed: Vec<u64>,
+    /// IDs of the input reports carrying telephony buttons; `[0]` when the device doesn't
+    /// number its reports
+    report_ids: Vec<u8>,
+    /// Per report ID, since a report that doesn't carry a control says nothing about it
+    trackers: HashMap<u8, ButtonTracker>,
+}
+
+/// Keeps the listener thread running; dropping it stops the thread
+pub struct HeadsetWatcher {
+    thread_id: u32,
+}
+
+impl Drop for HeadsetWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+    }
+}
+
+/// Forward headset button presses to `buttons` until the watcher is dropped
+pub fn watch(buttons: UnboundedSender<HeadsetButton>) -> Result<HeadsetWatcher> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    thread::Builder::new()
+        .name("headset".to_string())
+        .spawn(move || unsafe { listen(buttons, ready_tx) })
+        .context("Failed to start headset listener")?;
+    let thread_id = ready_rx
+        .recv()
+        .map_err(|_| fail!(Device, "Headset listener exited during setup"))??;
+    Ok(HeadsetWatcher { thread_id })
+}
+
+unsafe fn listen(
+    buttons: UnboundedSender<HeadsetButton>,
+    ready: std::sync::mpsc::Sender<Result<u32>>,
+) {
+    let window = match register() {
+        Ok(window) => window,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(GetCurrentThreadId()));
+
+    let mut devices: HashMap<isize, Option<Device>> = HashMap::new();
+    let mut msg = MSG::default();
+    while GetMessageW(&mut msg, None, 0, 0).as_bool() {
+        if msg.message == WM_INPUT {
+            handle_input(HRAWINPUT(msg.lParam.0 as *mut _), &mut devices, &buttons);
+        }
+        DispatchMessageW(&msg);
+    }
+    let _ = DestroyWindow(window);
+}
+
+unsafe fn register() -> Result<HWND> {
+    let window = CreateWindowExW(
+        WINDOW_EX_STYLE::default(),
+        w!("STATIC"),
+        w!("win-audio-capture headset"),
+        WINDOW_STYLE::default(),
+        0,
+        0,
+        0,
+        0,
+        HWND_MESSAGE,
+        None,
+        None,
+        None,
+    )
+    .context("Failed to create headset message window")?;
+
+    let device = RAWINPUTDEVICE {
+        usUsagePage: USAGE_PAGE_TELEPHONY,
+        usUsage: USAGE_HEADSET,
+        // Keep receiving input while another window has focus
+        dwFlags: RIDEV_INPUTSINK,
+        hwndTarget: window,
+    };
+    if let Err(e) = RegisterRawInputDevices(&[device], std::mem::size_of::<RAWINPUTDEVICE>() as u32)
+    {
+        let _ = DestroyWindow(window);
+        return Err(e).context("Failed to register for headset input");
+    }
+    Ok(window)
+}
+
+unsafe fn handle_input(
+    input: HRAWINPUT,
+    devices: &mut HashMap<isize, Option<Device>>,
+    buttons: &UnboundedSender<HeadsetButton>,
+) {
+    let header_size = std::mem::size_of::<RAWINPUTHEADER>() as u32;
+    let mut size = 0u32;
+    GetRawInputData(input, RID_INPUT, None, &mut size, header_size);
+    if size == 0 {
+        return;
+    }
+    // u64 storage keeps the RAWINPUT header aligned
+    let mut buf = vec![0u64; (size as usize).div_ceil(8)];
+    if GetRawInputData(
+        input,
+        RID_INPUT,
+        Some(buf.as_mut_ptr() as *mut _),
+        &mut size,
+        header_size,
+    ) != size
+    {
+        return;
+    }
+    let raw = &*(buf.as_ptr() as *const RAWINPUT);
+    if raw.header.dwType != RIM_TYPEHID.0 {
+        return;
+    }
+
+    let Some(device) = devices
+        .entry(raw.header.hDevice.0 as isize)
+        .or_insert_with(|| open_device(raw.header.hDevice))
+    else {
+        return;
+    };
+    let hid = raw.data.hid;
+    let report_len = hid.dwSizeHid as usize;
+    if report_len == 0 {
+        return;
+    }
+    // HidP_GetUsages wants the report mutable, so parse a copy
+    let mut reports = std::slice::from_raw_parts(
+        std::ptr::addr_of!(raw.data.hid.bRawData) as *const u8,
+        report_len * hid.dwCount as usize,
+    )
+    .to_vec();
+    let numbered = device.report_ids != [0];
+    for report in reports.chunks_exact_mut(report_len) {
+        let report_id = if numbered { report[0] } else { 0 };
+        if !device.report_ids.contains(&report_id) {
+            continue;
+        }
+        let mut usages = [0u16; MAX_USAGES];
+        let mut count = MAX_USAGES as u32;
+        let status = HidP_GetUsages(
+            HidP_Input,
+            USAGE_PAGE_TELEPHONY,
+            0,
+            usages.as_mut_ptr(),
+            &mut count,
+            PHIDP_PREPARSED_DATA(device.preparsed.as_ptr() as isize),
+            report,
+        );
+        if status != HIDP_STATUS_SUCCESS {
+            continue;
+        }
+        for button in device
+            .trackers
+            .entry(report_id)
+            .or_default()
+            .report(&usages[..count as usize])
+        {
+            let _ = buttons.send(button);
+        }
+    }
+}
+
+/// Read a headset's report descriptor; `None` for devices without telephony buttons
+unsafe fn open_device(handle: HANDLE) -> Option<Device> {
+    let mut size = 0u32;
+    GetRawInputDeviceInfoW(handle, RIDI_PREPARSEDDATA, None, &mut size);
+    if size == 0 {
+        return None;
+    }
+    let mut preparsed = vec![0u64; (size as usize).div_ceil(8)];
+    let read = GetRawInputDeviceInfoW(
+        handle,
+        RIDI_PREPARSEDDATA,
+        Some(preparsed.as_mut_ptr() as *mut _),
+        &mut size,
+    );
+    if read == u32::MAX {
+        return None;
+    }
+
+    let mut caps = [HIDP_BUTTON_CAPS::default(); MAX_USAGES];
+    let mut count = MAX_USAGES as u16;
+    let status = HidP_GetSpecificButtonCaps(
+        HidP_Input,
+        USAGE_PAGE_TELEPHONY,
+        0,
+        0,
+        caps.as_mut_ptr(),
+        &mut count,
+        PHIDP_PREPARSED_DATA(preparsed.as_ptr() as isize),
+    );
+    if status != HIDP_STATUS_SUCCESS || count == 0 {
+        return None;
+    }
+    let mut report_ids: Vec<u8> = caps[..count as usize].iter().map(|c| c.ReportID).collect();
+    report_ids.sort_unstable();
+    report_ids.dedup();
+
+    Some(Device {
+        preparsed,
+        report_ids,
+        trackers: HashMap::new(),
+    })
+}
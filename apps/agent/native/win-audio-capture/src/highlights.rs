@@ -0,0 +1,278 @@
+//! Highlight clips
+//! With `--highlights 3` the finished recording is scored second by second and the best
+//! stretches are cut out as `<stem>.highlight-<rank>.wav` (`--highlight-secs` long each),
+//! so managers get shareable moments without any backend processing.
+//!
+//! A second scores for speech on either side (the prospect more than the rep), more when
+//! both talk in it (interaction) and a little for loudness. Timeline markers add to the
+//! second they sit on: external markers and headset presses, keyword hits (`keyword` or
+//! `keyword_hit` entries sent by the agent) most of all. Clips never overlap.
+
+use crate::chapters::ACTIVE_RMS;
+use crate::extract;
+use crate::progress::Report;
+use crate::riff::{self, RiffAudio};
+use crate::sink::OutputFormat;
+use crate::timeline::{self, TimelineEntry};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Accepted `--highlight-secs` range
+pub const MIN_CLIP_SECS: u64 = 10;
+pub const MAX_CLIP_SECS: u64 = 300;
+
+/// Resolution of the scoring
+const CELL_MS: u64 = 1_000;
+const REP_SPEECH: f64 = 0.5;
+const PROSPECT_SPEECH: f64 = 1.0;
+const INTERACTION: f64 = 0.5;
+/// Weight of the loudest second; the others get their share of it
+const LOUDNESS: f64 = 0.5;
+const MARKER_WEIGHT: f64 = 30.0;
+const KEYWORD_WEIGHT: f64 = 45.0;
+
+/// One clip written next to the recording
+#[derive(Debug, Clone, Serialize)]
+pub struct Highlight {
+    /// 1 is the best-scored clip
+    pub rank: usize,
+    pub path: PathBuf,
+    /// Frame offset into the recording
+    pub start_frame: u64,
+    pub frames: u64,
+    pub score: f64,
+    /// Labels (or kinds) of the markers inside the clip
+    pub markers: Vec<String>,
+    pub bytes: u64,
+}
+
+/// Path of highlight `rank` of `recording`
+pub fn highlight_path(recording: &Path, rank: usize) -> PathBuf {
+    timeline::sidecar_path(recording, &format!("highlight-{}.wav", rank))
+}
+
+/// Score a finished recording and write up to `count` clips of `clip_secs` next to it.
+/// Progress counts scoring as the first half; cut short while writing clips, the clips
+/// written so far are returned.
+pub fn write(
+    recording: &Path,
+    format: OutputFormat,
+    count: usize,
+    clip_secs: u64,
+    progress: Report,
+) -> Result<Vec<Highlight>> {
+    let audio = RiffAudio::open(recording)?;
+    let cell_frames = (audio.sample_rate as u64 * CELL_MS / 1000).max(1);
+    let total = audio.frames() * 2;
+    let mut cells = speech_scores(recording, &audio, cell_frames, &mut |done, _| {
+        progress(done, total)
+    })?;
+    let mut markers = Vec::new();
+    if let Some(saved) = timeline::load(recording)? {
+        for entry in &saved.entries {
+            let (Some(weight), Some(frame)) = (marker_weight(entry), saved.file_frame(entry))
+            else {
+                continue;
+            };
+            if let Some(cell) = cells.get_mut((frame / cell_frames) as usize) {
+                *cell += weight;
+            }
+            let name = entry.label.clone().unwrap_or_else(|| entry.kind.clone());
+            markers.push((frame, name));
+        }
+    }
+
+    let window = (clip_secs * 1000 / CELL_MS) as usize;
+    let mut highlights = Vec::new();
+    let picked = pick(&cells, window, count);
+    let clips = picked.len() as u64;
+    for (i, (start_cell, score)) in picked.into_iter().enumerate() {
+        let start = start_cell as u64 * cell_frames;
+        let end = (start + window as u64 * cell_frames).min(audio.frames());
+        let path = highlight_path(recording, i + 1);
+        let clip = extract::write_clip(recording, &audio, start, end, &path, format)?;
+        highlights.push(Highlight {
+            rank: i + 1,
+            path,
+            start_frame: start,
+            frames: clip.frames,
+            score,
+            markers: markers
+                .iter()
+                .filter(|(frame, _)| (start..end).contains(frame))
+                .map(|(_, name)| name.clone())
+                .collect(),
+            bytes: clip.bytes,
+        });
+        if progress(audio.frames() * (clips + i as u64 + 1) / clips, total).is_err() {
+            break;
+        }
+    }
+    Ok(highlights)
+}
+
+fn marker_weight(entry: &TimelineEntry) -> Option<f64> {
+    match entry.kind.as_str() {
+        "keyword" | "keyword_hit" => Some(KEYWORD_WEIGHT),
+        "headset" => Some(MARKER_WEIGHT),
+        _ if entry.source == "external" => Some(MARKER_WEIGHT),
+        _ => None,
+    }
+}
+
+/// Speech and loudness score of every cell; left = rep, right = prospect
+fn speech_scores(
+    recording: &Path,
+    audio: &RiffAudio,
+    cell_frames: u64,
+    progress: Report,
+) -> Result<Vec<f64>> {
+    let channels = audio.channels as usize;
+    let mut rms = Vec::new();
+    let mut energy = [0.0f64; 2];
+    let mut frames_in_cell = 0u64;
+    let mut read = 0;
+    riff::read_frames(recording, audio, 0, audio.frames(), |samples| {
+        for frame in samples.chunks_exact(channels) {
+            for (energy, &sample) in energy.iter_mut().zip(frame) {
+                *energy += sample as f64 * sample as f64;
+            }
+            frames_in_cell += 1;
+            if frames_in_cell == cell_frames {
+                rms.push(energy.map(|e| (e / cell_frames as f64).sqrt()));
+                energy = [0.0; 2];
+                frames_in_cell = 0;
+            }
+        }
+        read += (samples.len() / channels) as u64;
+        progress(read, audio.frames())
+    })?;
+    if frames_in_cell > 0 {
+        rms.push(energy.map(|e| (e / frames_in_cell as f64).sqrt()));
+    }
+
+    let peak = rms
+        .iter()
+        .map(|[rep, prospect]| rep.max(*prospect))
+        .fold(0.0, f64::max);
+    Ok(rms
+        .iter()
+        .map(|&[rep, prospect]| {
+            let (rep_active, prospect_active) = (rep > ACTIVE_RMS, prospect > ACTIVE_RMS);
+            let mut score = 0.0;
+            if rep_active {
+                score += REP_SPEECH;
+            }
+            if prospect_active {
+                score += PROSPECT_SPEECH;
+            }
+            if rep_active && prospect_active {
+                score += INTERACTION;
+            }
+            if peak > 0.0 {
+                score += LOUDNESS * rep.max(prospect) / peak;
+            }
+            score
+        })
+        .collect())
+}
+
+/// Start cells and scores of up to `count` non-overlapping windows of `window` cells,
+/// best first; windows scoring nothing are left out
+fn pick(cells: &[f64], window: usize, count: usize) -> Vec<(usize, f64)> {
+    let window = window.clamp(1, cells.len().max(1));
+    let mut prefix = vec![0.0];
+    for cell in cells {
+        prefix.push(prefix.last().unwrap() + cell);
+    }
+    let mut picked: Vec<(usize, f64)> = Vec::new();
+    while picked.len() < count {
+        let best = (0..=cells.len().saturating_sub(window))
+            .filter(|&start| {
+                picked
+                    .iter()
+                    .all(|&(other, _)| start + window <= other || other + window <= start)
+            })
+            .map(|start| (start, prefix[start + window] - prefix[start]))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some((start, score)) if score > 0.0 => picked.push((start, score)),
+            _ => break,
+        }
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{self, SinkSpec};
+
+    #[test]
+    fn picks_the_best_non_overlapping_windows() {
+        let mut cells = vec![0.0; 20];
+        cells[3] = 5.0;
+        cells[4] = 5.0;
+        cells[15] = 4.0;
+        cells[8] = 1.0;
+        let picked = pick(&cells, 3, 4);
+        assert_eq!(picked.len(), 3);
+        assert!((2..=3).contains(&picked[0].0));
+        assert_eq!(picked[0].1, 10.0);
+        assert_eq!(picked[1].1, 4.0);
+        assert_eq!(picked[2].1, 1.0);
+        assert!((6..=8).contains(&picked[2].0));
+        // Nothing but silence: nothing to share
+        assert!(pick(&[0.0; 10], 3, 2).is_empty());
+    }
+
+    #[test]
+    fn writes_clips_around_speech_and_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.wav");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        // 60s of silence with the prospect talking over seconds 40-49
+        let mut out = sink::create(OutputFormat::Wav, &path, spec).unwrap();
+        let samples: Vec<i16> = (0..60_000u32)
+            .flat_map(|frame| {
+                let talking = (40_000..50_000).contains(&frame);
+                [0, if talking { 3_000 } else { 0 }]
+            })
+            .collect();
+        out.write_samples(&samples).unwrap();
+        out.finalize().unwrap();
+        let mut timeline = timeline::Timeline::new(&path, "call", 1_000, None);
+        timeline
+            .push(TimelineEntry {
+                kind: "keyword_hit".to_string(),
+                label: Some("pricing".to_string()),
+                sample_position: 5_500,
+                wall_time_ms: 0,
+                source: "external".to_string(),
+                data: None,
+            })
+            .unwrap();
+
+        let highlights = write(
+            &path,
+            OutputFormat::Wav,
+            3,
+            10,
+            &mut crate::progress::ignore,
+        )
+        .unwrap();
+        assert_eq!(highlights.len(), 2);
+        assert!(highlights[0].markers.contains(&"pricing".to_string()));
+        assert!((0..=5_000).contains(&highlights[0].start_frame));
+        assert_eq!(highlights[1].start_frame, 40_000);
+        assert_eq!(highlights[1].frames, 10_000);
+        assert_eq!(
+            RiffAudio::open(&highlights[1].path).unwrap().frames(),
+            10_000
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Session command hooks
+//! `--on-start-cmd` runs once recording has started and `--on-finalize-cmd` once the file
+//! is complete (published or spooled, trimmed, chaptered), e.g. to kick off a local backup
+//! script or log the call to a CRM. The command goes through the shell (`cmd /C`, `sh -c`
+//! elsewhere) with the session in `SELLY_*` environment variables, and is killed if it
+//! runs past `--hook-timeout-secs`.
+//!
+//! Hooks never fail the capture. Their exit code and the start of their output are written
+//! to the audit log.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Output kept per stream
+pub const OUTPUT_LIMIT: usize = 4 * 1024;
+
+/// How often a running hook is checked for exit
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long to wait for a killed hook's output pipes to close (a grandchild may hold them)
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a hook ran
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookOutcome {
+    /// `on_start` or `on_finalize`
+    pub hook: &'static str,
+    pub command: String,
+    /// `None` when the hook was killed or ended by a signal
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub duration_ms: u64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl HookOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Run `command` with `env` added to the environment, waiting at most `timeout`
+pub fn run(
+    hook: &'static str,
+    command: &str,
+    env: &[(&str, String)],
+    timeout: Duration,
+) -> Result<HookOutcome> {
+    let started = Instant::now();
+    let mut child = shell(command)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {} hook", hook))?;
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+
+    let status = wait(&mut child, timeout)?;
+    Ok(HookOutcome {
+        hook,
+        command: command.to_string(),
+        exit_code: status.and_then(|status| status.code()),
+        timed_out: status.is_none(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        stdout: stdout.recv_timeout(DRAIN_TIMEOUT).unwrap_or_default(),
+        stderr: stderr.recv_timeout(DRAIN_TIMEOUT).unwrap_or_default(),
+    })
+}
+
+/// Exit status, or `None` after killing a hook that ran out of time
+fn wait(child: &mut Child, timeout: Duration) -> Result<Option<std::process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for hook")? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Read a pipe to the end on its own thread (so a chatty hook can't fill it and block),
+/// keeping the first `OUTPUT_LIMIT` bytes
+fn capture<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    let Some(mut pipe) = pipe else {
+        let _ = tx.send(String::new());
+        return rx;
+    };
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = pipe.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let room = OUTPUT_LIMIT - kept.len();
+            kept.extend_from_slice(&buf[..n.min(room)]);
+        }
+        let _ = tx.send(String::from_utf8_lossy(&kept).into_owned());
+    });
+    rx
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    /// No console window flashing up for the hook
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    let mut shell = Command::new("cmd");
+    // Verbatim, so the command line is parsed by cmd exactly as the user wrote it
+    shell
+        .arg("/C")
+        .raw_arg(command)
+        .creation_flags(CREATE_NO_WINDOW);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same hooks in each platform's shell
+    #[cfg(windows)]
+    const REPORTS: &str = "echo %SELLY_SESSION%& 1>&2 echo oops& exit 3";
+    #[cfg(not(windows))]
+    const REPORTS: &str = "echo \"$SELLY_SESSION\"; echo oops >&2; exit 3";
+    #[cfg(windows)]
+    const CHATTY: &str =
+        "for /L %i in (1,1,2000) do @echo xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+    #[cfg(not(windows))]
+    const CHATTY: &str = "head -c 100000 /dev/zero | tr '\\0' x";
+    #[cfg(windows)]
+    const SLOW: &str = "ping -n 11 127.0.0.1 >nul";
+    #[cfg(not(windows))]
+    const SLOW: &str = "exec sleep 10";
+
+    #[test]
+    fn passes_the_environment_and_captures_output() {
+        let outcome = run(
+            "on_start",
+            REPORTS,
+            &[("SELLY_SESSION", "call-42".to_string())],
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(outcome.stdout.trim_end(), "call-42");
+        assert_eq!(outcome.stderr.trim_end(), "oops");
+        assert_eq!((outcome.exit_code, outcome.timed_out), (Some(3), false));
+        assert!(!outcome.succeeded());
+    }
+
+    #[test]
+    fn caps_output() {
+        let outcome = run("on_finalize", CHATTY, &[], Duration::from_secs(30)).unwrap();
+        assert!(outcome.succeeded());
+        assert_eq!(outcome.stdout.len(), OUTPUT_LIMIT);
+    }
+
+    #[test]
+    fn kills_hooks_that_run_too_long() {
+        let outcome = run("on_finalize", SLOW, &[], Duration::from_millis(300)).unwrap();
+        assert!(outcome.timed_out);
+        assert_eq!(outcome.exit_code, None);
+        assert!(outcome.duration_ms < 5_000);
+    }
+}
@@ -0,0 +1,230 @@
+//! User-facing messages
+//! The agent UI shows some events to the rep as they come (stopping, sleep, a MIC taken by
+//! another app), so those carry a `message` from this catalog in the `--lang` language next
+//! to their machine-readable fields. A message may name fields of its event as `{field}`.
+//! Events the catalog doesn't know get no `message`; the UI keeps its own wording for them.
+//!
+//! Every event also carries `ts`, the time in the machine's local time zone with its UTC
+//! offset (`2026-10-14T13:10:45.678+02:00`), next to the zone-free `ts_ms`.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// `--lang`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lang {
+    En,
+    De,
+    Fr,
+    Es,
+}
+
+static LANG: AtomicU8 = AtomicU8::new(Lang::En as u8);
+
+/// Language of the messages emitted from now on
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::De,
+        2 => Lang::Fr,
+        3 => Lang::Es,
+        _ => Lang::En,
+    }
+}
+
+/// Catalog entry for `event` in `lang`, placeholders unfilled
+fn template(event: &str, lang: Lang) -> Option<&'static str> {
+    let [en, de, fr, es] = match event {
+        "stopping" => [
+            "Stopping the recording",
+            "Aufnahme wird beendet",
+            "Arrêt de l'enregistrement",
+            "Deteniendo la grabación",
+        ],
+        "stopped" => [
+            "Recording saved",
+            "Aufnahme gespeichert",
+            "Enregistrement sauvegardé",
+            "Grabación guardada",
+        ],
+        "spooled" => [
+            "The output folder can't be reached; the recording will be delivered once it is back",
+            "Der Ausgabeordner ist nicht erreichbar; die Aufnahme wird zugestellt, sobald er wieder verfügbar ist",
+            "Le dossier de sortie est inaccessible ; l'enregistrement sera livré dès son retour",
+            "No se puede acceder a la carpeta de salida; la grabación se entregará cuando vuelva a estar disponible",
+        ],
+        "already_recording" => [
+            "Another recording is already running",
+            "Es läuft bereits eine andere Aufnahme",
+            "Un autre enregistrement est déjà en cours",
+            "Ya hay otra grabación en curso",
+        ],
+        "suspend" => [
+            "The computer is going to sleep; recording paused",
+            "Der Computer wechselt in den Energiesparmodus; Aufnahme pausiert",
+            "L'ordinateur se met en veille ; enregistrement en pause",
+            "El equipo entra en suspensión; grabación en pausa",
+        ],
+        "resume" => [
+            "Recording resumed after sleep",
+            "Aufnahme nach dem Energiesparmodus fortgesetzt",
+            "Enregistrement repris après la mise en veille",
+            "Grabación reanudada tras la suspensión",
+        ],
+        "mic_locked" => [
+            "The microphone is in use by another app; only the other side is being recorded",
+            "Das Mikrofon wird von einer anderen App verwendet; nur die Gegenseite wird aufgenommen",
+            "Le micro est utilisé par une autre application ; seul l'interlocuteur est enregistré",
+            "Otra aplicación está usando el micrófono; solo se graba al interlocutor",
+        ],
+        "mic_unlocked" => [
+            "The microphone is being recorded again",
+            "Das Mikrofon wird wieder aufgenommen",
+            "Le micro est de nouveau enregistré",
+            "El micrófono vuelve a grabarse",
+        ],
+        "mute" => [
+            "Microphone muted",
+            "Mikrofon stummgeschaltet",
+            "Micro coupé",
+            "Micrófono silenciado",
+        ],
+        "unmute" => [
+            "Microphone unmuted",
+            "Mikrofon eingeschaltet",
+            "Micro réactivé",
+            "Micrófono activado",
+        ],
+        "stream_stalled" => [
+            "The microphone stopped delivering audio",
+            "Das Mikrofon liefert keinen Ton mehr",
+            "Le micro ne transmet plus de son",
+            "El micrófono ha dejado de enviar audio",
+        ],
+        "usual_device_missing" => [
+            "Your usual microphone \"{usual}\" isn't connected; recording from \"{using}\"",
+            "Ihr übliches Mikrofon \"{usual}\" ist nicht angeschlossen; Aufnahme über \"{using}\"",
+            "Votre micro habituel « {usual} » n'est pas branché ; enregistrement via « {using} »",
+            "Su micrófono habitual \"{usual}\" no está conectado; se graba desde \"{using}\"",
+        ],
+        "panic_deleted" => [
+            "The recording was deleted",
+            "Die Aufnahme wurde gelöscht",
+            "L'enregistrement a été supprimé",
+            "La grabación se ha eliminado",
+        ],
+        "control_error" => [
+            "The command could not be carried out",
+            "Der Befehl konnte nicht ausgeführt werden",
+            "La commande n'a pas pu être exécutée",
+            "No se pudo ejecutar el comando",
+        ],
+        _ => return None,
+    };
+    Some(match lang {
+        Lang::En => en,
+        Lang::De => de,
+        Lang::Fr => fr,
+        Lang::Es => es,
+    })
+}
+
+/// Message for `event` with `fields`, in the current language
+pub fn message(event: &str, fields: &Map<String, Value>) -> Option<String> {
+    Some(fill(template(event, lang())?, fields))
+}
+
+/// Replace `{field}` with the field's value; unknown names are left as they are
+fn fill(template: &str, fields: &Map<String, Value>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let Some(close) = after.find('}') else {
+            out.push_str(&rest[open..]);
+            return out;
+        };
+        match fields.get(&after[..close]) {
+            Some(Value::String(value)) => out.push_str(value),
+            Some(value) => out.push_str(&value.to_string()),
+            None => out.push_str(&rest[open..open + close + 2]),
+        }
+        rest = &after[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// RFC 3339 local time with offset for a Unix time in milliseconds
+pub fn local_timestamp(unix_ms: u64, offset_minutes: i32) -> String {
+    let local = (unix_ms as i64 + offset_minutes as i64 * 60_000).max(0) as u64;
+    let utc = crate::sync_pulse::utc_string(local);
+    let stamp = utc.trim_end_matches('Z');
+    let (sign, offset) = if offset_minutes < 0 {
+        ('-', -offset_minutes)
+    } else {
+        ('+', offset_minutes)
+    };
+    format!("{}{}{:02}:{:02}", stamp, sign, offset / 60, offset % 60)
+}
+
+/// Minutes the local time zone is ahead of UTC right now (daylight saving included)
+#[cfg(windows)]
+pub fn utc_offset_minutes() -> i32 {
+    use windows::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+    const TIME_ZONE_ID_INVALID: u32 = u32::MAX;
+
+    let mut zone = TIME_ZONE_INFORMATION::default();
+    let id = unsafe { GetTimeZoneInformation(&mut zone) };
+    match id {
+        TIME_ZONE_ID_INVALID => 0,
+        TIME_ZONE_ID_DAYLIGHT => -(zone.Bias + zone.DaylightBias),
+        _ => -(zone.Bias + zone.StandardBias),
+    }
+}
+
+/// Outside Windows the sidecar only runs tools and tests; times are given in UTC
+#[cfg(not(windows))]
+pub fn utc_offset_minutes() -> i32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fills_fields_into_the_catalog_entry() {
+        let fields = json!({ "usual": "Jabra Evolve", "using": "Laptop MIC" });
+        let fields = fields.as_object().unwrap();
+        let german = fill(template("usual_device_missing", Lang::De).unwrap(), fields);
+        assert_eq!(
+            german,
+            "Ihr übliches Mikrofon \"Jabra Evolve\" ist nicht angeschlossen; Aufnahme über \"Laptop MIC\""
+        );
+        assert_eq!(fill("{missing} and {", fields), "{missing} and {");
+        assert!(template("negotiated", Lang::Fr).is_none());
+    }
+
+    #[test]
+    fn formats_local_time_with_its_offset() {
+        assert_eq!(
+            local_timestamp(1_791_976_245_678, 120),
+            "2026-10-14T13:10:45.678+02:00"
+        );
+        assert_eq!(
+            local_timestamp(1_791_976_245_678, -210),
+            "2026-10-14T07:40:45.678-03:30"
+        );
+        assert_eq!(local_timestamp(0, 0), "1970-01-01T00:00:00.000+00:00");
+    }
+}
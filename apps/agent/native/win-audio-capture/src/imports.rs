@@ -0,0 +1,221 @@
+//! DLL dependencies of the binary
+//! Locked-down machines often lack the Visual C++ redistributable, and a sidecar that
+//! imports `vcruntime140.dll` doesn't even start there. The `static-crt` feature links the
+//! C runtime into the binary (the UCRT stays, it is part of Windows 10), and `imports` reads
+//! a binary's PE import tables, delay-loaded ones included, so a release build can be
+//! checked for DLLs that Windows 10 1809 doesn't ship with.
+
+use crate::error::fail;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+/// A DLL a binary loads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Import {
+    pub dll: String,
+    /// Loaded on first use rather than at start
+    pub delayed: bool,
+    /// Part of Windows, rather than a runtime that has to be installed with the binary
+    pub system: bool,
+}
+
+const IMPORT_DIRECTORY: usize = 1;
+const DELAY_IMPORT_DIRECTORY: usize = 13;
+
+/// The DLLs the PE file at `path` imports
+pub fn read(path: &Path) -> Result<Vec<Import>> {
+    let image =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&image).with_context(|| format!("{} is not a valid PE file", path.display()))
+}
+
+/// Whether a DLL ships with Windows 10 1809; the compiler runtimes are the ones that don't
+pub fn is_system(dll: &str) -> bool {
+    let name = dll.to_ascii_lowercase();
+    let name = name.strip_suffix(".dll").unwrap_or(&name);
+    let versioned = [
+        "vcruntime",
+        "msvcp",
+        "msvcr",
+        "concrt",
+        "vccorlib",
+        "vcomp",
+        "mfc",
+    ];
+    let redistributable = versioned.iter().any(|prefix| {
+        name.strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+    });
+    let mingw = ["libgcc", "libstdc++", "libwinpthread"];
+    !(redistributable || name == "ucrtbased" || mingw.iter().any(|p| name.starts_with(p)))
+}
+
+fn parse(image: &[u8]) -> Result<Vec<Import>> {
+    let pe = Pe::new(image)?;
+    let mut imports = Vec::new();
+    // Import descriptors are 20 bytes with the name at 12, delay-load ones 32 with it at 4
+    for (directory, size, name_at, delayed) in [
+        (IMPORT_DIRECTORY, 20, 12, false),
+        (DELAY_IMPORT_DIRECTORY, 32, 4, true),
+    ] {
+        let Some(mut offset) = pe.directory(directory)? else {
+            continue;
+        };
+        loop {
+            let descriptor = image
+                .get(offset..offset + size)
+                .ok_or_else(|| fail!(Format, "Import table runs past the end"))?;
+            if descriptor.iter().all(|&b| b == 0) {
+                break;
+            }
+            let name = pe.offset(u32_at(descriptor, name_at)?)?;
+            let end = image[name..]
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| fail!(Format, "Unterminated DLL name"))?;
+            let dll = String::from_utf8_lossy(&image[name..name + end]).into_owned();
+            imports.push(Import {
+                system: is_system(&dll),
+                dll,
+                delayed,
+            });
+            offset += size;
+        }
+    }
+    Ok(imports)
+}
+
+/// The headers needed to find the import tables
+struct Pe<'a> {
+    image: &'a [u8],
+    /// File offset of the optional header's data directories, and how many there are
+    directories: usize,
+    directory_count: usize,
+    sections: usize,
+    section_count: usize,
+}
+
+impl<'a> Pe<'a> {
+    fn new(image: &'a [u8]) -> Result<Self> {
+        if image.get(..2) != Some(b"MZ") {
+            return Err(fail!(Format, "No MZ header"));
+        }
+        let pe = u32_at(image, 0x3c)? as usize;
+        if image.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return Err(fail!(Format, "No PE signature"));
+        }
+        let section_count = u16_at(image, pe + 6)? as usize;
+        let optional = pe + 24;
+        let optional_size = u16_at(image, pe + 20)? as usize;
+        let (count_at, directories) = match u16_at(image, optional)? {
+            0x10b => (optional + 92, optional + 96),
+            0x20b => (optional + 108, optional + 112),
+            magic => return Err(fail!(Format, "Unknown optional header magic {:#x}", magic)),
+        };
+        Ok(Self {
+            image,
+            directories,
+            directory_count: u32_at(image, count_at)? as usize,
+            sections: optional + optional_size,
+            section_count,
+        })
+    }
+
+    /// File offset of data directory `index`; `None` when the binary has none
+    fn directory(&self, index: usize) -> Result<Option<usize>> {
+        if index >= self.directory_count {
+            return Ok(None);
+        }
+        match u32_at(self.image, self.directories + index * 8)? {
+            0 => Ok(None),
+            rva => self.offset(rva).map(Some),
+        }
+    }
+
+    fn offset(&self, rva: u32) -> Result<usize> {
+        for i in 0..self.section_count {
+            let header = self.sections + i * 40;
+            let virtual_size = u32_at(self.image, header + 8)?;
+            let address = u32_at(self.image, header + 12)?;
+            let raw_size = u32_at(self.image, header + 16)?;
+            let raw = u32_at(self.image, header + 20)?;
+            if (address..address.saturating_add(virtual_size.max(raw_size))).contains(&rva) {
+                return Ok((rva - address + raw) as usize);
+            }
+        }
+        Err(fail!(Format, "Address {:#x} is in no section", rva))
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16> {
+    let field = bytes
+        .get(at..at + 2)
+        .ok_or_else(|| fail!(Format, "Truncated at {:#x}", at))?;
+    Ok(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32> {
+    let field = bytes
+        .get(at..at + 4)
+        .ok_or_else(|| fail!(Format, "Truncated at {:#x}", at))?;
+    Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PE32+ image with one section at RVA 0x1000 (file offset 0x200) holding an import
+    /// table for `dlls`
+    fn image(dlls: &[&str]) -> Vec<u8> {
+        let mut image = vec![0u8; 0x400];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x46..0x48].copy_from_slice(&1u16.to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        let optional = 0x58;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+        image[optional + 120..optional + 124].copy_from_slice(&0x1000u32.to_le_bytes());
+        let section = optional + 240;
+        for (at, value) in [(8, 0x200u32), (12, 0x1000), (16, 0x200), (20, 0x200)] {
+            image[section + at..section + at + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut name = 0x100;
+        for (i, dll) in dlls.iter().enumerate() {
+            let descriptor = 0x200 + i * 20 + 12;
+            image[descriptor..descriptor + 4]
+                .copy_from_slice(&(0x1000 + name as u32).to_le_bytes());
+            image[0x200 + name..0x200 + name + dll.len()].copy_from_slice(dll.as_bytes());
+            name += dll.len() + 1;
+        }
+        image
+    }
+
+    #[test]
+    fn flags_the_redistributable_runtimes() {
+        let imports = parse(&image(&[
+            "KERNEL32.dll",
+            "VCRUNTIME140.dll",
+            "msvcp_win.dll",
+        ]))
+        .unwrap();
+        let flagged: Vec<(&str, bool)> = imports
+            .iter()
+            .map(|import| (import.dll.as_str(), import.system))
+            .collect();
+        assert_eq!(
+            flagged,
+            [
+                ("KERNEL32.dll", true),
+                ("VCRUNTIME140.dll", false),
+                ("msvcp_win.dll", true)
+            ]
+        );
+        assert!(is_system("api-ms-win-crt-runtime-l1-1-0.dll") && is_system("msvcrt.dll"));
+        assert!(!is_system("libgcc_s_seh-1.dll") && !is_system("MSVCP140.dll"));
+        assert!(parse(b"not a binary").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! In-process capture
+//! `CaptureSession` runs a capture session on threads of its own inside the host process,
+//! which is how the Node agent's N-API bindings use it: the frames the sidecar would write
+//! to stdout are decoded and handed to a callback, every event line goes to another one
+//! (stderr still gets them), and commands are method calls rather than lines on stdin.
+//! The options are the CLI's, given as the same strings, so an embedded session behaves
+//! exactly like the sidecar. The session leaves Ctrl+C to the host.
+
+use crate::frame::{Frame, FrameDecoder};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+#[cfg(windows)]
+pub use session::CaptureSession;
+
+/// Frame output that decodes what is written to it and hands over each whole frame
+pub struct FrameSink<F> {
+    decoder: FrameDecoder,
+    on_frame: F,
+}
+
+impl<F: FnMut(Frame)> FrameSink<F> {
+    pub fn new(on_frame: F) -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            on_frame,
+        }
+    }
+}
+
+impl<F: FnMut(Frame) + Unpin> AsyncWrite for FrameSink<F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.decoder.push(buf);
+        loop {
+            match this.decoder.next_frame() {
+                Ok(Some(frame)) => (this.on_frame)(frame),
+                Ok(None) => break,
+                // The stream only writes whole frames; skip past anything that isn't one
+                Err(_) => {
+                    this.decoder.resync();
+                }
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(windows)]
+mod session {
+    use super::FrameSink;
+    use crate::capture::{self, Host};
+    use crate::control::{self, ControlCommand, Request};
+    use crate::error::{self, fail};
+    use crate::events;
+    use crate::frame::Frame;
+    use crate::subscription::Subscription;
+    use crate::{i18n, workspace, Args};
+    use anyhow::{anyhow, Context, Result};
+    use serde_json::json;
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+    use tokio::sync::mpsc::error::TrySendError;
+
+    /// Commands that can wait for the session loop
+    const COMMAND_QUEUE: usize = 64;
+
+    /// A capture session running in this process
+    pub struct CaptureSession {
+        commands: mpsc::Sender<Request>,
+        thread: Option<JoinHandle<Result<()>>>,
+    }
+
+    impl CaptureSession {
+        /// Start capturing with `args`, calling `on_frame` for every frame of the stream and
+        /// `on_event` with every event line. Both are called from the session's threads.
+        pub fn start<F, E>(mut args: Args, on_frame: F, mut on_event: E) -> Result<Self>
+        where
+            F: FnMut(Frame) + Send + Unpin + 'static,
+            E: FnMut(String) + Send + 'static,
+        {
+            let started = Instant::now();
+            i18n::set_lang(args.lang);
+            args.prepare()?;
+
+            let (commands, received) = mpsc::channel(COMMAND_QUEUE);
+            let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+            events::add_observer(events_tx.clone(), Subscription::default());
+            let forwarder = thread::Builder::new()
+                .name("capture-events".to_string())
+                .spawn(move || {
+                    while let Some(line) = events_rx.blocking_recv() {
+                        on_event(line);
+                    }
+                })
+                .context("Failed to start the event thread")?;
+            let host = Host {
+                commands: received,
+                frames: Box::new(FrameSink::new(on_frame)),
+                started,
+            };
+            let thread = thread::Builder::new()
+                .name("capture-session".to_string())
+                .spawn(move || {
+                    let session = args.session.clone();
+                    let result = capture::run_hosted(args, Some(host));
+                    if let Err(e) = &result {
+                        events::emit(
+                            "error",
+                            json!({
+                                "session": session,
+                                "kind": error::kind(e),
+                                "error": format!("{:#}", e),
+                                "workspace": workspace::keep_failed(&session, e),
+                            }),
+                        );
+                    }
+                    // The forwarder ends once the last sender is gone
+                    events::remove_observer(&events_tx);
+                    drop(events_tx);
+                    let _ = forwarder.join();
+                    result
+                })
+                .context("Failed to start the capture thread")?;
+            Ok(Self {
+                commands,
+                thread: Some(thread),
+            })
+        }
+
+        /// Send a control command line, e.g. `{"cmd":"marker","label":"Pricing"}`
+        pub fn command(&self, line: &str) -> Result<()> {
+            let request = control::parse(line).map_err(|(_, e)| fail!(Protocol, "{}", e))?;
+            self.send(request)
+        }
+
+        pub fn pause(&self) -> Result<()> {
+            self.send_command(ControlCommand::Pause)
+        }
+
+        pub fn resume(&self) -> Result<()> {
+            self.send_command(ControlCommand::Resume)
+        }
+
+        /// Ask the session to stop, the way a signal does; `wait` for it to finish
+        pub fn stop(&self) -> Result<()> {
+            self.send_command(ControlCommand::Stop)
+        }
+
+        pub fn is_finished(&self) -> bool {
+            self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+        }
+
+        /// Block until the session has finalized, returning how it ended
+        pub fn wait(mut self) -> Result<()> {
+            match self.thread.take() {
+                Some(thread) => thread
+                    .join()
+                    .map_err(|_| anyhow!("Capture thread panicked"))?,
+                None => Ok(()),
+            }
+        }
+
+        fn send_command(&self, command: ControlCommand) -> Result<()> {
+            self.send(Request {
+                id: None,
+                command,
+                deadline: None,
+            })
+        }
+
+        fn send(&self, request: Request) -> Result<()> {
+            self.commands.try_send(request).map_err(|e| match e {
+                TrySendError::Full(_) => {
+                    fail!(Protocol, "Too many commands waiting for the session")
+                }
+                TrySendError::Closed(_) => fail!(Protocol, "The session has ended"),
+            })
+        }
+    }
+
+    impl Drop for CaptureSession {
+        /// A session dropped while running is stopped and finalizes on its own
+        fn drop(&mut self) {
+            if self.thread.is_some() {
+                let _ = self.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn hands_over_whole_frames_across_writes() {
+        let mut frames = Vec::new();
+        let mut sink = FrameSink::new(|frame: Frame| frames.push(frame));
+        let mut bytes = frame::encode_legacy_frame(&[1, -1, 2, -2], 0);
+        bytes.extend(frame::encode_legacy_frame(&[3, -3], 1));
+        let (first, rest) = bytes.split_at(7);
+        sink.write_all(first).await.unwrap();
+        sink.write_all(rest).await.unwrap();
+        drop(sink);
+        let samples: Vec<_> = frames.iter().map(|f| f.samples.clone()).collect();
+        assert_eq!(samples, [vec![1, -1, 2, -2], vec![3, -3]]);
+        assert_eq!(frames[1].header.sequence, 1);
+        assert_eq!(frames[1].header.sample_offset, 2);
+    }
+}
@@ -0,0 +1,215 @@
+//! Concurrent-instance guard
+//! Each capture holds a named mutex for its session ID, its output file and its MIC device,
+//! so a second, accidentally spawned instance fails up front instead of interleaving writes
+//! into the same file or double-capturing the same microphone. The OS drops the names when
+//! the holder exits, crashes included, so there are no stale locks to clean up.
+
+use crate::error::fail;
+use crate::events;
+use anyhow::Result;
+use serde_json::json;
+use std::path::Path;
+
+/// What a lock protects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Session,
+    Output,
+    Device,
+    /// Draining the store-and-forward queue
+    Spool,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Session => "session",
+            Scope::Output => "output",
+            Scope::Device => "device",
+            Scope::Spool => "spool",
+        }
+    }
+}
+
+/// Held for the lifetime of the capture; dropping it releases the name
+pub struct InstanceLock {
+    _handle: imp::Handle,
+}
+
+/// Take the lock for `scope`/`key`, emitting `already_recording` and failing if another
+/// instance holds it
+pub fn acquire(session: &str, scope: Scope, key: &str) -> Result<InstanceLock> {
+    let name = lock_name(scope, key);
+    match imp::try_acquire(&name)? {
+        Some(handle) => Ok(InstanceLock { _handle: handle }),
+        None => {
+            events::emit(
+                "already_recording",
+                json!({ "session": session, "scope": scope.as_str(), "key": key }),
+            );
+            Err(fail!(
+                Policy,
+                "Another win-audio-capture instance is already recording {} {:?}",
+                scope.as_str(),
+                key
+            ))
+        }
+    }
+}
+
+/// Take the lock for `scope`/`key` if it is free, without reporting a conflict
+pub fn try_acquire(scope: Scope, key: &str) -> Result<Option<InstanceLock>> {
+    Ok(imp::try_acquire(&lock_name(scope, key))?.map(|handle| InstanceLock { _handle: handle }))
+}
+
+/// Whether some instance currently holds the lock for `scope`/`key`
+pub fn is_held(scope: Scope, key: &str) -> bool {
+    imp::exists(&lock_name(scope, key))
+}
+
+/// Short stable identifier for `key`, usable in file and pipe names
+pub fn fingerprint(key: &str) -> String {
+    format!("{:016x}", fnv1a(key))
+}
+
+/// Lock key for an output path. Windows paths compare case-insensitively and the same file
+/// can be spelled with or without the extended-length prefix.
+pub fn output_key(path: &Path) -> String {
+    // Lossy is fine here, the key is only hashed
+    let text = path.to_string_lossy().replace('/', "\\");
+    let text = match text.strip_prefix(r"\\?\UNC\") {
+        Some(rest) => format!(r"\\{}", rest),
+        None => text.strip_prefix(r"\\?\").unwrap_or(&text).to_string(),
+    };
+    text.to_lowercase()
+}
+
+/// Kernel object name for a lock. Names can't contain backslashes past the namespace, so
+/// the key is hashed with a hash that is stable across builds.
+fn lock_name(scope: Scope, key: &str) -> String {
+    format!(
+        r"Local\Selly.Capture.{}.{}",
+        scope.as_str(),
+        fingerprint(key)
+    )
+}
+
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(windows)]
+mod imp {
+    use anyhow::{Context, Result};
+    use windows::core::HSTRING;
+    use windows::Win32::Foundation::{CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE};
+    use windows::Win32::System::Threading::{
+        CreateMutexW, OpenMutexW, SYNCHRONIZATION_SYNCHRONIZE,
+    };
+
+    pub struct Handle(HANDLE);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = CloseHandle(self.0);
+            }
+        }
+    }
+
+    /// The mutex is never waited on; holding an open handle to the name is the lock
+    pub fn try_acquire(name: &str) -> Result<Option<Handle>> {
+        unsafe {
+            let handle = CreateMutexW(None, false, &HSTRING::from(name))
+                .context("Failed to create instance mutex")?;
+            let handle = Handle(handle);
+            if GetLastError() == ERROR_ALREADY_EXISTS {
+                return Ok(None);
+            }
+            Ok(Some(handle))
+        }
+    }
+
+    pub fn exists(name: &str) -> bool {
+        unsafe {
+            OpenMutexW(SYNCHRONIZATION_SYNCHRONIZE, false, &HSTRING::from(name))
+                .map(|handle| drop(Handle(handle)))
+                .is_ok()
+        }
+    }
+}
+
+/// Process-local stand-in so the guard logic can be exercised off Windows
+#[cfg(not(windows))]
+mod imp {
+    use anyhow::Result;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    static HELD: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+    pub struct Handle(String);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            if let Some(held) = HELD.lock().unwrap().as_mut() {
+                held.remove(&self.0);
+            }
+        }
+    }
+
+    pub fn try_acquire(name: &str) -> Result<Option<Handle>> {
+        let mut held = HELD.lock().unwrap();
+        let inserted = held
+            .get_or_insert_with(HashSet::new)
+            .insert(name.to_string());
+        Ok(inserted.then(|| Handle(name.to_string())))
+    }
+
+    pub fn exists(name: &str) -> bool {
+        HELD.lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|held| held.contains(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_key_ignores_case_and_prefix() {
+        let key = output_key(Path::new(r"C:\Calls\Out.wav"));
+        assert_eq!(key, output_key(Path::new(r"\\?\c:\calls\out.WAV")));
+        assert_eq!(key, output_key(Path::new("C:/Calls/Out.wav")));
+        assert_eq!(
+            output_key(Path::new(r"\\?\UNC\srv\share\a.wav")),
+            output_key(Path::new(r"\\srv\share\a.wav"))
+        );
+    }
+
+    #[test]
+    fn lock_names_are_stable_and_scoped() {
+        assert_eq!(
+            lock_name(Scope::Session, "abc"),
+            r"Local\Selly.Capture.session.e71fa2190541574b"
+        );
+        assert_ne!(
+            lock_name(Scope::Session, "abc"),
+            lock_name(Scope::Device, "abc")
+        );
+    }
+
+    #[test]
+    fn second_holder_is_refused_until_release() {
+        let first = acquire("s1", Scope::Output, "lock-test-key").unwrap();
+        let err = acquire("s2", Scope::Output, "lock-test-key").err().unwrap();
+        assert!(err.to_string().contains("already recording"));
+
+        drop(first);
+        assert!(acquire("s2", Scope::Output, "lock-test-key").is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+//! Attach surface roles
+//! The instance pipes come as two surfaces. The admin surface (the `data`/`events` attach
+//! pipes) carries frames and accepts control commands; the observer surface (the `observe`
+//! pipe) only mirrors events, and the live audio is read from the shared PCM ring, so a
+//! coaching overlay can follow a call without being able to change or stop it.
+//!
+//! Each surface has its own token, handed out by the parent agent: `--admin-token` (when
+//! set, attach clients must present it) and `--observer-token` (the observe pipe is only
+//! served with one). A client's first line is `{"token":"..."}`, which an observer can also
+//! subscribe to less than everything with (see `subscription`). The admin token also opens
+//! the observer surface; the observer token never opens the admin one.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How long a client has to present its token after connecting
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest token line read before giving up on the client
+pub const MAX_AUTH_LINE: usize = 1024;
+
+/// Which surface a client connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Surface {
+    Admin,
+    Observer,
+}
+
+/// What an authenticated client may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Observer,
+}
+
+/// Tokens the surfaces accept
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    pub admin: Option<String>,
+    pub observer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthLine {
+    token: String,
+}
+
+impl Tokens {
+    /// Whether clients of `surface` have to send a token line first
+    pub fn required(&self, surface: Surface) -> bool {
+        match surface {
+            Surface::Admin => self.admin.is_some(),
+            Surface::Observer => true,
+        }
+    }
+
+    /// Role granted for `line` (`None` when no token is required) on `surface`
+    pub fn authorize(&self, surface: Surface, line: Option<&str>) -> Option<Role> {
+        let presented = line
+            .and_then(|line| serde_json::from_str::<AuthLine>(line.trim()).ok())
+            .map(|auth| auth.token);
+        let matches = |token: &Option<String>| matches!((token, &presented), (Some(token), Some(presented)) if same(token, presented));
+        match surface {
+            Surface::Admin if self.admin.is_none() => Some(Role::Admin),
+            Surface::Admin => matches(&self.admin).then_some(Role::Admin),
+            Surface::Observer if matches(&self.admin) => Some(Role::Admin),
+            Surface::Observer => matches(&self.observer).then_some(Role::Observer),
+        }
+    }
+}
+
+/// Compare without stopping at the first differing byte
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(token: &str) -> String {
+        format!("{{\"token\":\"{}\"}}\n", token)
+    }
+
+    #[test]
+    fn observer_token_only_opens_the_observer_surface() {
+        let tokens = Tokens {
+            admin: Some("admin-secret".to_string()),
+            observer: Some("overlay".to_string()),
+        };
+        let observer = line("overlay");
+        let admin = line("admin-secret");
+        assert_eq!(
+            tokens.authorize(Surface::Observer, Some(&observer)),
+            Some(Role::Observer)
+        );
+        assert_eq!(tokens.authorize(Surface::Admin, Some(&observer)), None);
+        assert_eq!(
+            tokens.authorize(Surface::Admin, Some(&admin)),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            tokens.authorize(Surface::Observer, Some(&admin)),
+            Some(Role::Admin)
+        );
+        assert_eq!(tokens.authorize(Surface::Observer, Some("garbage")), None);
+        assert_eq!(tokens.authorize(Surface::Admin, None), None);
+    }
+
+    #[test]
+    fn admin_surface_is_open_without_an_admin_token() {
+        let tokens = Tokens {
+            admin: None,
+            observer: Some("overlay".to_string()),
+        };
+        assert!(!tokens.required(Surface::Admin));
+        assert_eq!(tokens.authorize(Surface::Admin, None), Some(Role::Admin));
+        // Without an observer token nobody gets in, not even an empty one
+        let closed = Tokens::default();
+        assert_eq!(closed.authorize(Surface::Observer, Some(&line(""))), None);
+    }
+}
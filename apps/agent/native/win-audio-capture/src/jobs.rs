@@ -0,0 +1,64 @@
+//! Finalize jobs
+//! The finalize steps that only read the finished recording (the preview, highlights) don't
+//! depend on each other, so they run side by side on the blocking pool rather than one after
+//! the other, at most `--finalize-jobs` at a time (default: one per core). A long call's
+//! end-of-call processing then takes about as long as its slowest step. The steps that
+//! rewrite the file (trim, cues, seal, publish) stay in order before them.
+
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+
+/// Default `--finalize-jobs`
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(2, |cores| cores.get())
+}
+
+/// Limits how many jobs run at once
+pub struct Slots(Arc<Semaphore>);
+
+impl Slots {
+    pub fn new(jobs: usize) -> Self {
+        Self(Arc::new(Semaphore::new(jobs.max(1))))
+    }
+
+    /// Run `job` on the blocking pool once a slot is free
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, JoinError> {
+        // Held until the job is done; the semaphore is never closed
+        let _slot = self.0.acquire().await;
+        tokio::task::spawn_blocking(job).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_at_most_the_slot_count_at_once() {
+        let slots = Slots::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let job = |id: usize| {
+            let (running, most) = (running.clone(), most.clone());
+            slots.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(30));
+                running.fetch_sub(1, Ordering::SeqCst);
+                id
+            })
+        };
+        let (a, b, c, d) = tokio::join!(job(1), job(2), job(3), job(4));
+        assert_eq!(
+            [a.unwrap(), b.unwrap(), c.unwrap(), d.unwrap()],
+            [1, 2, 3, 4]
+        );
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,124 @@
+//! Session event journal
+//! Every event a capture emits is also appended to `<stem>.events.jsonl` next to its
+//! recording, one line per event as it went out on stderr. Each line is written straight
+//! through to the OS as it is emitted, so the journal survives the sidecar being killed; if
+//! the agent missed events over the pipe (it restarted, the pipe broke), `journal <file>
+//! --since-ms N` replays them from there.
+//!
+//! A `panic_delete` wipes the journal with the rest of the session's files.
+
+use crate::timeline;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of the event journal of `recording`
+pub fn path(recording: &Path) -> PathBuf {
+    timeline::sidecar_path(recording, "events.jsonl")
+}
+
+/// Appends event lines to a recording's journal
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Open the journal of `recording`, continuing one left by an earlier run
+    pub fn open(recording: &Path) -> Result<Self> {
+        let path = path(recording);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open event journal {}", path.display()))?;
+        let mut journal = Self { path, file };
+        if ends_torn(&journal.path)? {
+            // Keep a line torn by a crash off the first event of this run
+            journal.file.write_all(b"\n")?;
+        }
+        Ok(journal)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append one event line
+    pub fn write(&mut self, line: &str) -> io::Result<()> {
+        // One write per line so a crash can only tear the last one
+        let mut buf = String::with_capacity(line.len() + 1);
+        buf.push_str(line);
+        buf.push('\n');
+        self.file.write_all(buf.as_bytes())
+    }
+}
+
+/// Whether the file at `path` ends in the middle of a line
+fn ends_torn(path: &Path) -> Result<bool> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Events of the journal at `path` emitted at or after `since_ms`, in order. Lines torn by
+/// a crash mid-write are skipped.
+pub fn read(path: &Path, since_ms: u64) -> Result<Vec<Value>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read event journal {}", path.display()))?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["ts_ms"].as_u64().unwrap_or(0) >= since_ms)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_across_runs_and_reads_from_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("call.wav");
+        let mut journal = Journal::open(&recording).unwrap();
+        journal.write(r#"{"event":"started","ts_ms":100}"#).unwrap();
+        drop(journal);
+        let mut journal = Journal::open(&recording).unwrap();
+        journal.write(r#"{"event":"marker","ts_ms":200}"#).unwrap();
+        assert_eq!(journal.path(), dir.path().join("call.events.jsonl"));
+
+        let all = read(journal.path(), 0).unwrap();
+        assert_eq!(all.len(), 2);
+        let later = read(journal.path(), 150).unwrap();
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0]["event"], "marker");
+    }
+
+    #[test]
+    fn skips_lines_torn_by_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("torn.wav");
+        std::fs::write(
+            path(&recording),
+            "{\"event\":\"started\",\"ts_ms\":1}\n{\"event\":\"sto",
+        )
+        .unwrap();
+        assert_eq!(read(&path(&recording), 0).unwrap().len(), 1);
+
+        // The next run starts on a line of its own
+        let mut journal = Journal::open(&recording).unwrap();
+        journal.write(r#"{"event":"started","ts_ms":2}"#).unwrap();
+        let events = read(journal.path(), 0).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["ts_ms"], 2);
+    }
+}
@@ -0,0 +1,116 @@
+//! MIC/loopback latency compensation
+//! The two paths reach the mixer with different delays: the MIC stream is event-driven
+//! while the loopback thread polls every half buffer, so prospect audio lands in the mix a
+//! few tens of milliseconds after the rep audio that was captured at the same moment, and
+//! turns look overlapped in transcripts. Both sources report how old each packet is when it
+//! is delivered (the device's capture timestamp against the clock); after the first few
+//! seconds with both delivering, the difference of the means is applied once as a fixed
+//! delay on the faster source and reported in a `latency_aligned` event.
+//!
+//! `--latency-offset-ms` applies a known offset instead of measuring one.
+
+use crate::mixer::Source;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Audio both sources must have delivered before the offset is measured
+pub const CALIBRATION: Duration = Duration::from_secs(3);
+
+/// Packets each source must have reported before its mean counts
+pub const MIN_PACKETS: u64 = 20;
+
+/// Offsets beyond this are taken as a measurement error and not applied
+pub const MAX_OFFSET_MS: f64 = 250.0;
+
+/// `--latency-compensation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyCompensation {
+    /// Measure the offset at the start of the session and apply it
+    Auto,
+    Off,
+}
+
+/// Offset applied to the session
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Alignment {
+    /// Mean age of MIC packets on delivery, when measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loopback_latency_ms: Option<f64>,
+    /// How much later the loopback path delivers than the MIC's; negative when the MIC is
+    /// the slower one
+    pub offset_ms: f64,
+    /// Source held back so the two line up
+    pub delayed: Source,
+    pub frames: u64,
+    /// Measured, rather than given with `--latency-offset-ms`
+    pub measured: bool,
+}
+
+impl Alignment {
+    /// Alignment for a known offset
+    pub fn fixed(offset_ms: f64, sample_rate: u32) -> Self {
+        let delayed = if offset_ms >= 0.0 {
+            Source::Mic
+        } else {
+            Source::Loopback
+        };
+        Self {
+            mic_latency_ms: None,
+            loopback_latency_ms: None,
+            offset_ms,
+            delayed,
+            frames: (offset_ms.abs() * sample_rate as f64 / 1000.0).round() as u64,
+            measured: false,
+        }
+    }
+
+    /// Alignment from the mean delivery latencies of both paths; `None` when the
+    /// difference is implausibly large
+    pub fn measured(mic: Duration, loopback: Duration, sample_rate: u32) -> Option<Self> {
+        let (mic_ms, loopback_ms) = (millis(mic), millis(loopback));
+        let offset_ms = loopback_ms - mic_ms;
+        (offset_ms.abs() <= MAX_OFFSET_MS).then(|| Self {
+            mic_latency_ms: Some(mic_ms),
+            loopback_latency_ms: Some(loopback_ms),
+            measured: true,
+            ..Self::fixed(offset_ms, sample_rate)
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.0 * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_the_faster_path_by_the_difference() {
+        let aligned = Alignment::measured(
+            Duration::from_micros(10_000),
+            Duration::from_micros(35_500),
+            48_000,
+        )
+        .unwrap();
+        assert_eq!(aligned.offset_ms, 25.5);
+        assert_eq!((aligned.delayed, aligned.frames), (Source::Mic, 1_224));
+        assert!(aligned.measured);
+
+        let aligned = Alignment::fixed(-5.0, 48_000);
+        assert_eq!((aligned.delayed, aligned.frames), (Source::Loopback, 240));
+    }
+
+    #[test]
+    fn rejects_implausible_measurements() {
+        assert_eq!(
+            Alignment::measured(Duration::ZERO, Duration::from_millis(400), 48_000),
+            None
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Capture engine of the Selly agent: MIC capture, WASAPI loopback, the mixer and the
+//! recording writer, with the options and lifecycle of a session. The `win-audio-capture`
+//! CLI is a thin wrapper around it; `CaptureSession` runs one inside another process (the
+//! Node agent, through its N-API bindings), as do the fuzz targets for the frame decoder.
+
+// Only the capture pipeline is Windows-specific; the rest builds (and is tested) anywhere
+#![cfg_attr(not(windows), allow(dead_code))]
+
+pub mod anonymize;
+pub mod args;
+#[cfg(windows)]
+pub mod attach;
+pub mod audit;
+pub mod auto_redact;
+pub mod budget;
+pub mod capabilities;
+#[cfg(windows)]
+pub mod capture;
+pub mod chapters;
+pub mod clock_sync;
+pub mod conferencing;
+pub mod control;
+pub mod device_cache;
+pub mod device_config;
+pub mod device_select;
+pub mod device_watch;
+pub mod devices;
+pub mod diagnose;
+pub mod dsp;
+pub mod ducking;
+pub mod durations;
+#[cfg(windows)]
+pub mod endpoint_mute;
+pub mod engine;
+pub mod envelope;
+pub mod error;
+pub mod etw;
+pub mod events;
+pub mod extract;
+pub mod footprint;
+pub mod frame;
+pub mod gain_staging;
+pub mod headset;
+pub mod health;
+#[cfg(windows)]
+pub mod hid_telephony;
+pub mod highlights;
+pub mod hooks;
+#[cfg(feature = "otlp")]
+pub mod http;
+pub mod i18n;
+pub mod imports;
+pub mod in_process;
+pub mod instance_lock;
+pub mod ipc_role;
+pub mod jobs;
+pub mod journal;
+pub mod latency;
+pub mod license;
+pub mod limiter;
+pub mod loopback_role;
+pub mod mic_lock;
+pub mod mixer;
+pub mod mute;
+pub mod notes;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod output_location;
+pub mod packet_format;
+pub mod paths;
+pub mod pipeline;
+pub mod polarity;
+pub mod power;
+pub mod preview;
+pub mod process_loopback;
+pub mod progress;
+pub mod quantize;
+pub mod rate_negotiation;
+pub mod reconfigure;
+pub mod recovery;
+pub mod redact;
+pub mod registry;
+pub mod replay;
+pub mod resample;
+pub mod riff;
+pub mod rolling;
+pub mod rt;
+pub mod self_test;
+#[cfg(feature = "self-update")]
+pub mod self_update;
+pub mod session;
+pub mod setup;
+pub mod sha256;
+#[cfg(windows)]
+pub mod shared_pcm;
+pub mod shm;
+pub mod silence;
+pub mod sink;
+pub mod soak;
+pub mod spool;
+pub mod stitch;
+pub mod stream;
+pub mod subscription;
+#[cfg(windows)]
+pub mod suspend;
+pub mod sync_pulse;
+pub mod synth;
+pub mod timeline;
+pub mod trace;
+pub mod tracks;
+pub mod transcript;
+pub mod trim;
+pub mod tui;
+pub mod upload_manifest;
+pub mod vectors;
+pub mod warnings;
+#[cfg(windows)]
+pub mod wasapi_loopback;
+#[cfg(windows)]
+pub mod wasapi_mic;
+pub mod watchdog;
+pub mod wipe;
+pub mod workspace;
+
+pub use args::Args;
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+#[global_allocator]
+static ALLOCATOR: rt::CheckedAlloc = rt::CheckedAlloc;
+
+/// When the process started, for the startup time the `startup` event reports
+pub static LAUNCHED: OnceLock<Instant> = OnceLock::new();
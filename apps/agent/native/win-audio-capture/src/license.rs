@@ -0,0 +1,235 @@
+//! Feature licensing
+//! One build ships to every customer, so the premium modules are switched on per tenant by
+//! a signed policy rather than by packaging: `--license-token <token>` names the tenant and
+//! the features it pays for, and the options that need a feature are refused without it
+//! (`--aec` needs `aec`, `--wrap-key` and `--dsp-compare-wrap-key` need `encryption`).
+//! `local_asr` gates nothing in the sidecar; the verified policy is reported in the
+//! `started` event and the agent runs its local ASR only if it is listed. Features the
+//! sidecar doesn't know are kept, so older builds accept tokens that grant newer modules.
+//!
+//! The token is a compact JWS signed with ES256 (ECDSA P-256 over SHA-256), whose claims
+//! are the `Policy`: `header.claims.signature`, each part base64url without padding, the
+//! signature the raw 64-byte `r || s`. It is verified offline against `POLICY_KEY`, whose
+//! private half only the license service holds, and refused once past `exp`.
+
+use crate::error::fail;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Public point (`x || y`) of the P-256 key policies are signed with
+pub const POLICY_KEY: [u8; 64] = [
+    0x7e, 0x6d, 0x5d, 0x08, 0x8c, 0x13, 0xea, 0xcf, 0x88, 0x81, 0x2f, 0x18, 0x54, 0xc8, 0x7b, 0x15,
+    0x71, 0x3b, 0xdf, 0x88, 0xbb, 0x84, 0xd2, 0xcb, 0xa9, 0xd3, 0xa0, 0x76, 0x70, 0x8b, 0xf7, 0xab,
+    0x05, 0xc9, 0xa4, 0x97, 0xda, 0xf0, 0xb1, 0xa7, 0x60, 0x59, 0xb4, 0xd8, 0xcc, 0x44, 0xaf, 0x2d,
+    0x06, 0x5c, 0x28, 0xaa, 0x83, 0xcf, 0x45, 0x57, 0x2c, 0xf7, 0x91, 0x2d, 0xb6, 0x6a, 0x3e, 0x83,
+];
+
+/// A premium module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Aec,
+    LocalAsr,
+    Encryption,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Aec, Feature::LocalAsr, Feature::Encryption];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Aec => "aec",
+            Feature::LocalAsr => "local_asr",
+            Feature::Encryption => "encryption",
+        }
+    }
+}
+
+/// What a tenant's token grants
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub tenant: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Unix seconds after which the token is refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+impl Policy {
+    pub fn allows(&self, feature: Feature) -> bool {
+        self.features.iter().any(|name| name == feature.name())
+    }
+}
+
+#[derive(Deserialize)]
+struct JwsHeader {
+    alg: String,
+}
+
+/// Check that `policy` (none without a token) covers every premium option in use, given
+/// as whether it is used, its flag and the feature it needs
+pub fn check(policy: Option<&Policy>, options: &[(bool, &str, Feature)]) -> Result<()> {
+    for &(used, flag, feature) in options {
+        if used && !policy.is_some_and(|policy| policy.allows(feature)) {
+            bail!(fail!(
+                Policy,
+                "{} needs a --license-token with the `{}` feature",
+                flag,
+                feature.name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Verify `token` against `POLICY_KEY` and return its policy
+pub fn verify(token: &str) -> Result<Policy> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    decode(token, now, verify_signature).context("Invalid --license-token")
+}
+
+/// Parse `token`, checking its signature with `verify` (signing input, signature) and its
+/// expiry against `now_secs`
+pub fn decode(
+    token: &str,
+    now_secs: u64,
+    verify: impl FnOnce(&[u8], &[u8]) -> Result<()>,
+) -> Result<Policy> {
+    let parts: Vec<&str> = token.trim().split('.').collect();
+    let [header, claims, signature] = parts[..] else {
+        bail!(fail!(Policy, "Not a compact JWS"));
+    };
+    let part = |text: &str, name: &str| {
+        base64url_decode(text).ok_or_else(|| fail!(Policy, "Bad base64url in the {}", name))
+    };
+    let jws_header: JwsHeader =
+        serde_json::from_slice(&part(header, "header")?).context("Bad JWS header")?;
+    if jws_header.alg != "ES256" {
+        bail!(fail!(
+            Policy,
+            "Unsupported signature algorithm {}",
+            jws_header.alg
+        ));
+    }
+    let signature = part(signature, "signature")?;
+    let signing_input = &token.trim()[..header.len() + 1 + claims.len()];
+    verify(signing_input.as_bytes(), &signature)?;
+
+    let policy: Policy =
+        serde_json::from_slice(&part(claims, "claims")?).context("Bad policy claims")?;
+    if policy.exp.is_some_and(|exp| now_secs >= exp) {
+        bail!(fail!(
+            Policy,
+            "The license for {} has expired",
+            policy.tenant
+        ));
+    }
+    Ok(policy)
+}
+
+fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    if text.contains(['+', '/', '=']) {
+        return None;
+    }
+    crate::envelope::base64_decode(&text.replace('-', "+").replace('_', "/"))
+}
+
+#[cfg(windows)]
+fn verify_signature(signing_input: &[u8], signature: &[u8]) -> Result<()> {
+    use windows::core::PCWSTR;
+    use windows::Win32::Security::Cryptography::*;
+
+    let digest = crate::sha256::digest(signing_input);
+    let mut blob = Vec::with_capacity(8 + POLICY_KEY.len());
+    blob.extend_from_slice(&BCRYPT_ECDSA_PUBLIC_P256_MAGIC.to_le_bytes());
+    blob.extend_from_slice(&32u32.to_le_bytes());
+    blob.extend_from_slice(&POLICY_KEY);
+    unsafe {
+        let mut algorithm = BCRYPT_ALG_HANDLE::default();
+        BCryptOpenAlgorithmProvider(
+            &mut algorithm,
+            BCRYPT_ECDSA_P256_ALGORITHM,
+            PCWSTR::null(),
+            BCRYPT_OPEN_ALGORITHM_PROVIDER_FLAGS(0),
+        )
+        .ok()
+        .context("Failed to open CNG algorithm")?;
+        let mut key = BCRYPT_KEY_HANDLE::default();
+        let imported =
+            BCryptImportKeyPair(algorithm, None, BCRYPT_ECCPUBLIC_BLOB, &mut key, &blob, 0)
+                .ok()
+                .context("Failed to import the policy key");
+        let verified = imported.and_then(|()| {
+            BCryptVerifySignature(key, None, &digest, signature, BCRYPT_FLAGS(0))
+                .ok()
+                .context("Signature doesn't verify")
+        });
+        if !key.is_invalid() {
+            let _ = BCryptDestroyKey(key);
+        }
+        let _ = BCryptCloseAlgorithmProvider(algorithm, 0);
+        verified
+    }
+}
+
+#[cfg(not(windows))]
+fn verify_signature(_signing_input: &[u8], _signature: &[u8]) -> Result<()> {
+    bail!("License tokens can only be verified on Windows")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{"alg":"ES256"}`
+    const HEADER: &str = "eyJhbGciOiJFUzI1NiJ9";
+    /// `{"tenant":"acme","features":["aec","transcribe_v2"],"exp":100}`
+    const CLAIMS: &str =
+        "eyJ0ZW5hbnQiOiJhY21lIiwiZmVhdHVyZXMiOlsiYWVjIiwidHJhbnNjcmliZV92MiJdLCJleHAiOjEwMH0";
+
+    #[test]
+    fn decodes_signed_policies_and_refuses_expired_ones() {
+        // Signed "sig"
+        let token = format!("{}.{}.c2ln", HEADER, CLAIMS);
+        let mut checked = None;
+        let policy = decode(&token, 99, |input, signature| {
+            checked = Some((input.to_vec(), signature.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(policy.tenant, "acme");
+        assert!(policy.allows(Feature::Aec) && !policy.allows(Feature::Encryption));
+        let (input, signature) = checked.unwrap();
+        assert_eq!(input, token.rsplit_once('.').unwrap().0.as_bytes());
+        assert_eq!(signature, b"sig");
+
+        assert!(decode(&token, 100, |_, _| Ok(())).is_err());
+        assert!(decode(&token, 0, |_, _| bail!("forged")).is_err());
+        assert!(decode("e30.e30", 0, |_, _| Ok(())).is_err());
+        // `{"alg":"none"}`
+        let none = format!("eyJhbGciOiJub25lIn0{}", &token[HEADER.len()..]);
+        assert!(decode(&none, 0, |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn refuses_premium_options_the_policy_does_not_cover() {
+        let policy = Policy {
+            tenant: "acme".to_string(),
+            features: vec!["aec".to_string()],
+            exp: None,
+        };
+        let options = [
+            (true, "--aec", Feature::Aec),
+            (false, "--wrap-key", Feature::Encryption),
+        ];
+        assert!(check(Some(&policy), &options).is_ok());
+        assert!(check(None, &options).is_err());
+        let encrypting = [(true, "--wrap-key", Feature::Encryption)];
+        let refused = check(Some(&policy), &encrypting).unwrap_err();
+        assert!(refused.to_string().contains("`encryption`"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Peak control before quantization
+//! Without it, anything past full scale is hard-clamped, which turns loud transients
+//! (door slams, laughter) into harsh digital clipping.
+//! - `soft-clip`: linear up to a knee, then a tanh curve that approaches full scale
+//! - `lookahead`: a limiter that sees peaks a few milliseconds early and turns the gain down
+//!   smoothly before they arrive. It delays its channel by the lookahead window; the
+//!   sample count is unchanged, so positions stay consistent.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Level where the soft clipper leaves the linear region
+const SOFT_KNEE: f32 = 0.8;
+
+/// Peak level the lookahead limiter holds the output under
+const CEILING: f32 = 0.98;
+
+const LOOKAHEAD_MS: u32 = 5;
+const RELEASE_MS: f32 = 150.0;
+
+/// `--limiter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimiterMode {
+    /// Hard clamp at full scale
+    Off,
+    SoftClip,
+    Lookahead,
+}
+
+/// Peak control for one channel
+pub enum Limiter {
+    Off,
+    SoftClip,
+    Lookahead(Lookahead),
+}
+
+impl Limiter {
+    pub fn new(mode: LimiterMode, sample_rate: u32) -> Self {
+        match mode {
+            LimiterMode::Off => Limiter::Off,
+            LimiterMode::SoftClip => Limiter::SoftClip,
+            LimiterMode::Lookahead => Limiter::Lookahead(Lookahead::new(sample_rate)),
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        match self {
+            Limiter::Off => sample,
+            Limiter::SoftClip => soft_clip(sample),
+            Limiter::Lookahead(lookahead) => lookahead.process(sample),
+        }
+    }
+}
+
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - SOFT_KNEE;
+    let shaped = SOFT_KNEE + headroom * ((magnitude - SOFT_KNEE) / headroom).tanh();
+    shaped.copysign(sample)
+}
+
+pub struct Lookahead {
+    /// Samples waiting to be output
+    delay: VecDeque<f32>,
+    /// Gains needed by the delayed samples, as (sample index, gain) with increasing gains,
+    /// so the front is the minimum over the window
+    needed: VecDeque<(u64, f32)>,
+    index: u64,
+    window: usize,
+    gain: f32,
+    /// Per-sample coefficients for the gain smoothing
+    attack: f32,
+    release: f32,
+}
+
+impl Lookahead {
+    fn new(sample_rate: u32) -> Self {
+        let window = (sample_rate * LOOKAHEAD_MS / 1000).max(1) as usize;
+        Self {
+            delay: VecDeque::from(vec![0.0; window]),
+            needed: VecDeque::new(),
+            index: 0,
+            window,
+            gain: 1.0,
+            // Within ~1% of the target by the time the peak leaves the delay line
+            attack: 1.0 - (-4.6 / window as f32).exp(),
+            release: 1.0 - (-1.0 / (RELEASE_MS * sample_rate as f32 / 1000.0)).exp(),
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let needed = if sample.abs() > CEILING {
+            CEILING / sample.abs()
+        } else {
+            1.0
+        };
+        while self.needed.back().is_some_and(|&(_, gain)| gain >= needed) {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((self.index, needed));
+        // Only the samples still in the delay line (or arriving now) matter
+        let oldest = (self.index + 1).saturating_sub(self.window as u64 + 1);
+        while self.needed.front().is_some_and(|&(i, _)| i < oldest) {
+            self.needed.pop_front();
+        }
+        self.index += 1;
+
+        let target = self.needed.front().map_or(1.0, |&(_, gain)| gain);
+        let coefficient = if target < self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain += (target - self.gain) * coefficient;
+
+        self.delay.push_back(sample);
+        let delayed = self.delay.pop_front().unwrap_or(0.0);
+        // The smoothing gets within 1% of the target; the clamp covers the rest
+        (delayed * self.gain).clamp(-CEILING, CEILING)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    #[test]
+    fn soft_clip_is_transparent_below_the_knee_and_bounded_above() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-0.8), -0.8);
+        let mut previous = 0.0;
+        for i in 0..1_000 {
+            let x = i as f32 / 100.0;
+            let y = soft_clip(x);
+            assert!(y >= previous && y <= 1.0, "soft_clip({}) = {}", x, y);
+            previous = y;
+        }
+        assert_eq!(soft_clip(-3.0), -soft_clip(3.0));
+    }
+
+    #[test]
+    fn lookahead_passes_quiet_audio_delayed() {
+        let mut limiter = Limiter::new(LimiterMode::Lookahead, RATE);
+        let input: Vec<f32> = (0..2_000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        let output: Vec<f32> = input.iter().map(|&x| limiter.process(x)).collect();
+        let delay = (RATE * LOOKAHEAD_MS / 1000) as usize;
+        assert!(output[..delay].iter().all(|&y| y == 0.0));
+        for (y, x) in output[delay..].iter().zip(&input) {
+            assert!((y - x).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn lookahead_holds_transients_under_the_ceiling_without_hard_clipping() {
+        let mut limiter = Limiter::new(LimiterMode::Lookahead, RATE);
+        // Quiet tone with a loud burst in the middle
+        let input: Vec<f32> = (0..20_000)
+            .map(|i| {
+                let level = if (8_000..9_000).contains(&i) {
+                    3.0
+                } else {
+                    0.3
+                };
+                (i as f32 * 0.05).sin() * level
+            })
+            .collect();
+        let output: Vec<f32> = input.iter().map(|&x| limiter.process(x)).collect();
+
+        assert!(output.iter().all(|&y| y.abs() <= CEILING));
+        // Gain was already down when the burst arrived: only the loudest peaks touch the
+        // ceiling, where a hard clamp would flatten most of the burst
+        let hard_clipped = input.iter().filter(|&&x| x.abs() >= CEILING).count();
+        let at_ceiling = output.iter().filter(|&&y| y.abs() >= CEILING).count();
+        assert!(
+            at_ceiling * 20 < hard_clipped,
+            "{} of {} samples at the ceiling",
+            at_ceiling,
+            hard_clipped
+        );
+    }
+}
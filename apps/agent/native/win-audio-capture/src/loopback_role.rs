@@ -0,0 +1,135 @@
+//! Which default render endpoint the loopback records
+//! Windows keeps separate defaults per role, and conferencing apps usually play through the
+//! Communications default (often a headset) rather than the Console default. In auto mode
+//! the audio sessions on each default endpoint are inspected and the one a known
+//! conferencing app is actively playing on wins.
+//!
+//! A second endpoint can be recorded alongside (`--loopback-extra-role`), e.g. a headset
+//! carrying the call while a shared video plays through the speakers.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--loopback-role`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopbackRole {
+    /// Follow the endpoint the conferencing app is using, else Console
+    Auto,
+    Console,
+    Communications,
+    Multimedia,
+}
+
+/// `--loopback-layout`: where a second loopback endpoint goes in the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopbackLayout {
+    /// Summed into the loopback (right) channel
+    Mix,
+    /// Written as a third channel of the recording; the stdout stream stays stereo
+    Separate,
+}
+
+/// Executables whose audio sessions mark an endpoint as the call's output
+const CONFERENCING_APPS: &[&str] = &[
+    "teams.exe",
+    "ms-teams.exe",
+    "zoom.exe",
+    "cpthost.exe",
+    "slack.exe",
+    "webex.exe",
+    "ciscocollabhost.exe",
+    "webexmta.exe",
+    "skype.exe",
+    "lync.exe",
+    "gotomeeting.exe",
+    "g2mcomm.exe",
+    "ringcentral.exe",
+    "discord.exe",
+];
+
+/// Whether the process image at `path` (full path or file name) is a conferencing app
+pub fn is_conferencing_app(path: &str) -> bool {
+    let name = path.rsplit(['\\', '/']).next().unwrap_or(path);
+    CONFERENCING_APPS
+        .iter()
+        .any(|app| name.eq_ignore_ascii_case(app))
+}
+
+/// What auto-detection found on one role's default endpoint
+#[derive(Debug, Clone)]
+pub struct EndpointUsage {
+    pub role: LoopbackRole,
+    pub device_id: String,
+    /// A conferencing app has an active session on the endpoint
+    pub conferencing_active: bool,
+}
+
+/// Pick the role to record for auto mode. An endpoint with an active conferencing session
+/// wins, Communications first since that's where calls are routed; otherwise Console.
+pub fn pick(endpoints: &[EndpointUsage]) -> LoopbackRole {
+    [
+        LoopbackRole::Communications,
+        LoopbackRole::Console,
+        LoopbackRole::Multimedia,
+    ]
+    .into_iter()
+    .find(|role| {
+        endpoints
+            .iter()
+            .any(|e| e.role == *role && e.conferencing_active)
+    })
+    .unwrap_or(LoopbackRole::Console)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(role: LoopbackRole, device_id: &str, conferencing_active: bool) -> EndpointUsage {
+        EndpointUsage {
+            role,
+            device_id: device_id.to_string(),
+            conferencing_active,
+        }
+    }
+
+    #[test]
+    fn matches_conferencing_apps_by_file_name() {
+        assert!(is_conferencing_app(
+            r"C:\Users\rep\AppData\Local\Microsoft\Teams\current\Teams.exe"
+        ));
+        assert!(is_conferencing_app("Zoom.exe"));
+        assert!(!is_conferencing_app(
+            r"C:\Program Files\Spotify\Spotify.exe"
+        ));
+        assert!(!is_conferencing_app(r"C:\zoom.exe\player.exe"));
+    }
+
+    #[test]
+    fn prefers_the_endpoint_with_the_call() {
+        let endpoints = [
+            usage(LoopbackRole::Console, "speakers", false),
+            usage(LoopbackRole::Communications, "headset", true),
+            usage(LoopbackRole::Multimedia, "speakers", false),
+        ];
+        assert_eq!(pick(&endpoints), LoopbackRole::Communications);
+
+        let endpoints = [
+            usage(LoopbackRole::Console, "speakers", true),
+            usage(LoopbackRole::Communications, "headset", false),
+        ];
+        assert_eq!(pick(&endpoints), LoopbackRole::Console);
+    }
+
+    #[test]
+    fn falls_back_to_console_without_a_call() {
+        let endpoints = [
+            usage(LoopbackRole::Console, "speakers", false),
+            usage(LoopbackRole::Communications, "headset", false),
+        ];
+        assert_eq!(pick(&endpoints), LoopbackRole::Console);
+        assert_eq!(pick(&[]), LoopbackRole::Console);
+    }
+}
@@ -4,291 +4,769 @@
 //!
 //! Usage:
 //!   win-audio-capture --session <id> --out <path.wav> --sample-rate 48000 --channels 2
+//!   [--format wav|rf64|flac|opus|mka] [--opus-bitrate 32000]
 //!
-//! Runs until SIGINT (Ctrl+C), then closes the WAV file cleanly.
-
-#[cfg(windows)]
-mod wasapi_loopback;
+//! Runs until SIGINT (Ctrl+C), then closes the WAV file cleanly. With `--trailing-secs N`
+//! capture continues for N more seconds after the stop request before finalizing
+//! (a second Ctrl+C skips the remaining window).
+//!
+//! The CLI is a thin wrapper around the `selly_audio_capture` library, which the Node agent
+//! can also load in-process through its N-API bindings (`native/selly-audio-capture-node`,
+//! `npm run build:addon`): `startCapture(options, onFrame, onEvent)` takes these same
+//! options, hands frames and events to callbacks and has `pause`/`resume`/`stop`.
+//!
+//! `{"cmd":"negotiate","frames":[...]}` on the control channel answers with the handshake
+//! frame on the stream (protocol version, features, sample format, frame duration) and opts
+//! into optional frame types. `--protocol v2` sends the handshake without waiting for it and
+//! `--protocol v1` keeps the bare frame stream older agents expect. `--protocol v3` (also
+//! `--stream-protocol`) sends typed frames instead: PCM, markers, stats snapshots and an
+//! end-of-stream frame, each with monotonic and UTC timestamps and a CRC32.
+//! `--handshake-every 50` repeats the handshake (rate, channels and channel layout) every
+//! five seconds, for consumers that attach to a running stream.
+//! `--stdout-format wav` writes a plain WAV stream to stdout instead of frames, so
+//! `win-audio-capture ... --stdout-format wav | ffplay -` works while debugging.
+//! `--pipeline-tag discovery|demo|renewal` is announced in the handshake and kept in the
+//! recording's metadata, so downstream consumers can route the session.
+//!
+//! Control commands (NDJSON) are accepted on stdin, e.g. `{"cmd":"position"}`; replies and
+//! lifecycle events are written as NDJSON to stderr, stamped with the local time and its UTC
+//! offset; events shown to the rep carry a `message` in the `--lang` language. Capture
+//! announces `started` (devices and format) and ends with `stopped`, or `error` when it
+//! fails, with the failure's `kind`, which also picks the exit code (the kinds and their
+//! codes are listed in [`error`]). `--json-logs` adds a `stats` heartbeat every
+//! `--stats-interval-ms` with the levels of both channels, stream drops, mixer xruns and
+//! queue depths. `--tui` shows the same on the console as live meters, drop counters, sinks
+//! and recent events, for debugging on a machine without the agent (with stdout sent
+//! elsewhere). External timeline events are merged into `<out>.timeline.json` next to the
+//! WAV. `{"cmd":"panic_delete","session":"<id>"}` aborts the session and overwrites and
+//! removes what it has written (the audit log is kept).
+//! `{"cmd":"audio_note","path":"note.wav"}` adds a dictated note in place: copied next to the
+//! recording, or with `--notes channel` played into an extra channel of it, normalized and
+//! ducked under the call's loudness so a full-scale snippet doesn't blast the mixdown.
+//! `{"cmd":"transcript",...}` re-emits the ASR backend's words on the session's sample clock;
+//! with `--auto-redact` card numbers and SSNs read out in them are silenced in the recording.
+//! `pause`/`resume` record silence over a break, `marker` adds a labelled timeline entry,
+//! `rotate` closes the file and carries on in `<stem>_0002.wav`, and `stop` ends capture the
+//! way a signal does, which Windows has no clean equivalent of. `--segment-seconds 900`
+//! rotates every 15 minutes on its own, reporting each part with `segment_completed`.
+//! `{"cmd":"new_session","session":"<id>"}` rotates into a file of another session, and the
+//! two timelines link to each other at the frame of the hand-over (`session_chained`).
+//! `--rolling-minutes 5` only holds the last five minutes, in memory, and records nothing
+//! until `{"cmd":"commit"}`; from then on the held minutes and the rest of the call are kept.
+//! `--max-buffer-mb 64` caps the audio buffered in memory, split over the queues and the
+//! rolling buffer; each buffer reaching its share is counted (`budget_pressure`).
+//!
+//! System suspend doesn't end the session: the skipped time is marked as a `gap` in the
+//! timeline and the devices are reopened on resume.
+//!
+//! `--loopback-pid <pid>` or `--loopback-process-name zoom.exe` records only the meeting
+//! app's audio instead of the whole system mix; `--loopback-meeting-app` picks whichever of
+//! Zoom and Teams is in a meeting. `--meeting-bridge` reports that meeting (app, meeting ID,
+//! participant count) in `meeting_*` events, the timeline and `stopped`.
+//! `win-audio-capture list-devices` lists the capture and render endpoints, which
+//! `--mic-device <id>` and `--loopback-device <id>` pin for a session.
+//! `win-audio-capture list-sessions` lists running instances and
+//! `win-audio-capture attach --session <id>` reattaches to one of them.
+//! `--admin-token` makes attaching require a token; `--observer-token` also serves a
+//! read-only surface any number of clients can follow (frames and events, no control),
+//! `win-audio-capture observe --session <id> --token <token>`, narrowed with `--events`
+//! and `--frames` (or `--no-frames`) to what a remote observer has bandwidth for.
+//! `win-audio-capture upload next|mark` track which bytes of finished recordings have been
+//! uploaded, so interrupted uploads resume where they stopped.
+//! `win-audio-capture extract <file> --from <time|marker> --to <time|marker> --out <clip>`
+//! cuts a sample-accurate clip out of a finished recording, and
+//! `win-audio-capture redact <file> --ranges ranges.json` silences intervals of one in place.
+//! `win-audio-capture stitch a.wav b.wav --out full.wav` joins the parts of one session
+//! (after a crash and restart) on the session clock, with silence for what wasn't recorded.
+//! `win-audio-capture journal <file> --since-ms N` replays the events a session emitted.
+//! `win-audio-capture recover` repairs and finalizes recordings of crashed sessions, which
+//! every capture also does on launch; `win-audio-capture repair <file>...` fixes the header
+//! of any WAV/RF64 file left unfinalized. Both keep their header current every second while
+//! recording, so a killed sidecar leaves a file that plays up to that point.
+//! `win-audio-capture replay-stream <file> --speed 1.0` replays one as the live frame stream.
+//! `win-audio-capture gen-vectors <dir>` writes the frame protocol conformance vectors.
+//! `win-audio-capture self-update --channel stable --feed <url>` installs a newer signed
+//! build, deferring the swap while a session is recording.
+//!
+//! Output on a network share, mapped drive or OneDrive folder (or a directory that writes
+//! slowly) is recorded to a local staging file and copied into place at the end; pass
+//! `--require-local` to refuse such paths instead. If the share is unreachable the recording
+//! is queued locally and delivered in order once it is back (`win-audio-capture spool`).
+//! What a session writes on the way (the staging file, rewrites of it) goes into its own
+//! `%TEMP%\selly-capture\<session>` workspace, removed when the session finishes and kept
+//! when it fails: `error` then says where (`workspace`).
+//!
+//! The loopback records the default render endpoint for `--loopback-role`; the default,
+//! `auto`, picks the Communications endpoint when a conferencing app is playing there.
+//! `--loopback-extra-role` records a second endpoint too, mixed into the right channel or,
+//! with `--loopback-layout separate`, as a third WAV channel.
+//! The latency difference between the MIC and loopback paths is measured in the first
+//! seconds and compensated with a fixed delay (`--latency-offset-ms` gives it instead).
+//! `--clock external` runs the mixer on the clock the agent reports with `{"cmd":"clock",
+//! "media_time_ms":...}` (e.g. the conferencing SDK's media clock), so the recording lines up
+//! with the platform's own; the devices are resampled to it.
+//! A MIC or loopback endpoint slower than `--sample-rate` (a Bluetooth headset in
+//! hands-free mode) is reported in `rate_negotiated`; `--rate-policy downgrade` records at
+//! its rate instead of upsampling it, `--rate-policy strict` refuses to record.
+//!
+//! `--headset-controls markers|full` follows the call buttons of HID telephony headsets:
+//! presses are marked in the timeline and, with `full`, the mute button mutes the MIC
+//! channel and hanging up stops the capture.
+//!
+//! MIC mute in Windows is reported as `mute`/`unmute` events; `--mute-sync silence` also
+//! records silence on the MIC channel while muted.
+//! A MIC held in exclusive mode by another recorder doesn't end the session: capture goes
+//! on loopback-only (`mic_locked` event) and picks the MIC up once it is free. A MIC whose
+//! driver cpal can't open is recorded through WASAPI directly (`mic_backend` event).
+//!
+//! A MIC input channel delivered with inverted polarity is flipped before the channels are
+//! averaged (`polarity_corrected` event); `--polarity-correction off` disables this.
+//!
+//! `--trim-silence` cuts leading and trailing silence from the finished file; the cut is
+//! recorded in the timeline file (entry positions stay untrimmed). `--preview-speed 1.5`
+//! also writes a pitch-preserving sped-up copy, `<stem>.preview-1.5x.wav`. `--chapters`
+//! marks chapters (long pauses, monologue/dialogue changes) as timeline entries and cues.
+//! `--sync-pulse-secs 10` emits `sync` events pairing sample positions with UTC, and marks
+//! them as cues too, so screen recordings can be aligned to the audio.
+//! `--highlights 3` cuts the best-scored moments (speech, markers, keyword hits) out of the
+//! finished recording as `<stem>.highlight-<n>.wav` clips.
+//! `--output-mode split` delivers `<stem>.mic.wav` and `<stem>.loopback.wav`, sample-aligned
+//! mono tracks, instead of the recording (`both` keeps it too); `--mixdown` adds a mono mix.
+//! The preview, highlights and tracks are cut side by side (`--finalize-jobs`, default one
+//! per core).
+//! These finalize stages report `finalize_progress` events; `--finalize-timeout 60` skips
+//! the optional ones once a minute has passed since the stop.
+//!
+//! `--format flac` records lossless FLAC, and `--format opus` Opus in Ogg at
+//! `--opus-bitrate` (builds with the `opus` feature, linking a static libopus; 48, 24, 16,
+//! 12 or 8 kHz, at most stereo). Options that rewrite the finished file are refused with
+//! them, and the preview and highlights are skipped.
+//! `--format mka` writes Matroska audio with the mic and the loopback as separate PCM
+//! tracks; `--chapters` and sync pulses become its chapters, and the session, pipeline tag
+//! and meeting its tags. Trimming, redaction, previews and per-track files need WAV.
+//!
+//! `--source sine:1000` or `--source noise` replaces the devices with a generated signal
+//! (`mic=`/`loopback=` for just one), to check the write/stream path on machines without
+//! audio hardware.
+//! `--soak hours=24 --inject device_flap,disk_stall,pipe_block` runs the pipeline like that
+//! for a day, injecting device, disk and consumer failures, and fails if memory grew or the
+//! timeline stopped accounting for the wall clock.
+//!
+//! The capture's audio sessions opt out of Windows communications ducking, so other audio
+//! isn't turned down because of us; ducking by other apps is marked in the timeline
+//! (`ducking` events). `--ducking detect` only marks it, `--ducking off` ignores it.
+//!
+//! The capture follows device changes: a loopback on the default endpoint reopens when the
+//! default speaker moves, and a MIC not pinned with `--mic-device` switches when a plugged-in
+//! headset becomes the pick or its own endpoint goes away (`device_switched` events).
+//!
+//! `--wrap-key tenant.pem` seals the finished recording (AES-256-GCM under a per-session
+//! key, wrapped with the tenant's public key in the file's header) before it is published.
+//!
+//! `--hpf-hz`, `--denoise`, `--agc` and `--aec` process the MIC before it is mixed; `--aec`
+//! cancels the echo of the loopback that the rep's speakers leave in the MIC, which
+//! diarization would otherwise take for the rep.
+//! The `--dsp-compare` file is configured on its own: `--dsp-compare-format` picks its
+//! container and `--dsp-compare-wrap-key` seals it, so e.g. a sealed Opus recording can sit
+//! next to an unsealed FLAC comparison file.
+//! `{"cmd":"reconfigure",...}` changes the denoise strength, the stream's frame length or
+//! adds a file to record into without stopping: only the stages it touches restart, and
+//! the recording and the stream go on without a gap.
+//! `--aec` and the sealing options are premium modules: they need a `--license-token`, the
+//! tenant's signed feature policy, listing `aec` or `encryption`.
+//!
+//! `--anonymize` pitch-shifts the prospect's voice (`--anonymize-semitones`) before it is
+//! written or streamed, for recordings that end up in training data.
+//!
+//! `--on-start-cmd` and `--on-finalize-cmd` run a shell command when recording starts and
+//! once the file is finalized, with the session in `SELLY_*` environment variables; their
+//! exit code and output go into the audit log.
+//!
+//! `stopped` reports the session's footprint: CPU time, bytes read and written, the peak
+//! working set and handle counts.
+//! It and the timeline file also say where the session's time went (`durations`): wall
+//! clock, captured audio, silence recorded while paused, skipped clock time, silence left
+//! out of the file, redacted audio and the file's own length.
+//!
+//! `--trace-out trace.json` records spans for device callbacks, mixer rounds, sink and frame
+//! writes and control commands, written as a Chrome/Perfetto trace when the session ends.
+//! `--otlp-endpoint http://collector:4318` exports the session span, its events and the
+//! quality counters to an OpenTelemetry collector, through the system proxy (WinHTTP, PAC)
+//! unless `--proxy direct` or `--proxy http://host:port` says otherwise; `--proxy-user` with
+//! the password in `SELLY_PROXY_PASSWORD` answers proxies that ask for credentials. Events,
+//! device opens, glitches and dropped frames are also written to the `Selly-AudioCapture`
+//! ETW provider for WPR.
+//!
+//! `cargo build --profile minimal --no-default-features` builds capture only, optimized for
+//! size into `target/minimal/`: no HTTP client, OTLP exporter or `--proxy` (the `otlp`
+//! feature), no `self-update` (`self-update`) and no FLAC or Matroska writer (`codecs`),
+//! leaving WAV and RF64. There is no ASR to leave out; it runs in the agent. `npm run
+//! build:sidecar` and `build:sidecar:minimal` fail a binary over 5 MB
+//! (`scripts/check-sidecar-size.mjs`). Every build emits a `startup` event with the time
+//! from launch to the first mixed block, held to a 300 ms budget: the headset listener and
+//! the meeting poll wait until audio flows, and the check for the usual MIC runs aside.
+//!
+//! `win-audio-capture self-test` runs generated audio through the pipeline into a scratch
+//! WAV, for checking a build on a new machine or architecture; the same code runs on x64
+//! and ARM64 (`npm run build:sidecar:arm64`).
+//!
+//! `--features static-crt` links the Visual C++ runtime in, for machines without the
+//! redistributable; `win-audio-capture imports` lists the DLLs the binary loads and fails
+//! on any Windows 10 1809 doesn't ship with.
+//! `win-audio-capture capabilities --json` reports the compiled-in features, the modules a
+//! `--license-token` permits, whether the process loopback is available and the hardware
+//! found, so the agent can adapt its UI before starting a session.
+//! `win-audio-capture diagnose --session <id> --wrap-key support.pem --out bundle.zip`
+//! packs the session's logs, the capabilities report, a failed session's error report and
+//! a 10 second sample with voices disguised into a zip sealed for support, asking before
+//! logs or audio go in.
+//! `win-audio-capture setup --output-root <dir>` is the first run: it asks for the MIC and
+//! the endpoint to loop back, measures the latency between them with a calibration chirp,
+//! checks the output root takes writes, with `--register` drains the spool at logon, and
+//! writes the choices to the config file sessions start from.
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Sample, SampleFormat, SampleRate, StreamConfig};
-use crossbeam_channel::{bounded, Receiver, Sender};
-use hound::{SampleFormat as HoundSampleFormat, WavSpec, WavWriter};
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use clap::{Parser, Subcommand};
+use selly_audio_capture::error::CaptureError;
+use selly_audio_capture::sink::OutputFormat;
+use selly_audio_capture::subscription::FrameFilter;
+use selly_audio_capture::upload_manifest::{self, ByteRange, UploadManifest};
+use selly_audio_capture::*;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::Instant;
 
 #[derive(Parser, Debug)]
 #[command(name = "win-audio-capture")]
 #[command(about = "Captures MIC + WASAPI loopback to stereo WAV")]
-struct Args {
-    /// Session identifier
-    #[arg(long)]
-    session: String,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Capture options, used when no subcommand is given
+    #[command(flatten)]
+    capture: Option<Args>,
+}
 
-    /// Output WAV file path (absolute)
-    #[arg(long)]
-    out: PathBuf,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the active capture and render endpoints as NDJSON on stdout
+    ListDevices,
+    /// Print running capture instances as NDJSON on stdout
+    ListSessions,
+    /// Take over a running capture: its frames, events and control channel are bridged
+    /// onto this process's stdio
+    Attach {
+        #[arg(long)]
+        session: String,
+        /// The capture's `--admin-token`
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Follow a running capture read-only: frames on stdout, events on stderr
+    Observe {
+        #[arg(long)]
+        session: String,
+        /// The capture's `--observer-token` (or its admin token)
+        #[arg(long)]
+        token: String,
+        /// Only these event types (a trailing `*` matches a prefix); all by default
+        #[arg(long, value_delimiter = ',')]
+        events: Option<Vec<String>>,
+        /// Only these frame kinds; all by default
+        #[arg(long, value_delimiter = ',', conflicts_with = "no_frames")]
+        frames: Option<Vec<FrameFilter>>,
+        /// Events only, no frames at all
+        #[arg(long)]
+        no_frames: bool,
+    },
+    /// Resumable upload bookkeeping for recorded sessions
+    Upload {
+        #[command(subcommand)]
+        action: UploadAction,
+    },
+    /// Copy a time or marker range of a finished recording into a new file
+    Extract {
+        input: PathBuf,
+        /// Start: `HH:MM:SS.mmm` (or shorter) or a marker name; default is the beginning
+        #[arg(long)]
+        from: Option<String>,
+        /// End (exclusive), same forms as --from; default is the end of the recording
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Wav)]
+        format: OutputFormat,
+    },
+    /// Silence intervals of a finished recording and log them in its timeline file
+    Redact {
+        input: PathBuf,
+        /// JSON array of `{"from", "to", "reason"}`; positions as for `extract`
+        #[arg(long)]
+        ranges: PathBuf,
+    },
+    /// Join the parts of one session into a single file, placed by their start times
+    Stitch {
+        #[arg(required = true)]
+        parts: Vec<PathBuf>,
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Wav)]
+        format: OutputFormat,
+        /// Longest silence inserted between two parts
+        #[arg(long, default_value_t = 600)]
+        max_gap_secs: u64,
+    },
+    /// Print the events a recording's session emitted, from its event journal, as NDJSON
+    Journal {
+        input: PathBuf,
+        /// Only events emitted at or after this Unix time in milliseconds
+        #[arg(long, default_value_t = 0)]
+        since_ms: u64,
+    },
+    /// Repair and finalize the recordings of crashed sessions, printed as NDJSON
+    Recover {
+        /// Tenant public key (DER or PEM) to seal sessions recorded with --wrap-key
+        #[arg(long)]
+        wrap_key: Option<PathBuf>,
+    },
+    /// Fix the headers of WAV/RF64 recordings whose writer never finalized them, printed as
+    /// NDJSON
+    Repair {
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Play a finished recording back as the live SELL frame stream on stdout
+    ReplayStream {
+        input: PathBuf,
+        /// Playback rate relative to real time; 0 sends frames as fast as they are read
+        #[arg(long, default_value = "1.0")]
+        speed: f64,
+    },
+    /// Check the hash chain of a recording's audit log and print the result as JSON
+    AuditVerify { input: PathBuf },
+    /// Write the frame protocol conformance vectors (`<name>.bin` and `index.json`)
+    GenVectors { out: PathBuf },
+    /// Run the capture pipeline on generated audio and print the result as JSON
+    SelfTest,
+    /// Report the compiled-in features, what the license permits, what the OS offers and
+    /// the hardware found
+    Capabilities {
+        /// One line of JSON instead of an indented report
+        #[arg(long)]
+        json: bool,
+        /// Report the features this license token permits
+        #[arg(long)]
+        license_token: Option<String>,
+    },
+    /// Install a newer signed build of this binary from the release feed
+    #[cfg(feature = "self-update")]
+    SelfUpdate {
+        #[arg(long, value_enum, default_value_t = self_update::Channel::Stable)]
+        channel: self_update::Channel,
+        /// Base URL of the release manifests (`<feed>/<channel>.json`)
+        #[arg(long)]
+        feed: String,
+    },
+    /// Print the DLLs a binary imports as NDJSON, failing when one doesn't ship with Windows
+    Imports {
+        /// Default is this binary
+        input: Option<PathBuf>,
+    },
+    /// Pack a session's logs, device report, error report and a disguised audio sample
+    /// into a zip sealed for support, asking before anything personal goes in
+    Diagnose {
+        #[arg(long)]
+        session: String,
+        /// Support's public key (DER or PEM) to seal the bundle for
+        #[arg(long)]
+        wrap_key: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+        /// Agree to include logs and the audio sample without asking
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Pick the devices, calibrate the latency between them, check the output root and
+    /// write the config file sessions start from
+    Setup {
+        /// Skip asking for the MIC
+        #[arg(long)]
+        mic_device: Option<String>,
+        /// Skip asking for the endpoint to loop back
+        #[arg(long)]
+        loopback_device: Option<String>,
+        #[arg(long)]
+        output_root: PathBuf,
+        /// Don't play the calibration chirp (e.g. on a call already)
+        #[arg(long)]
+        no_chirp: bool,
+        /// Run `spool drain` at logon
+        #[arg(long)]
+        register: bool,
+        /// Config file to write; default is the one sessions read without `--config`
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Take the default devices without asking
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Recordings waiting for their network destination to come back
+    Spool {
+        #[command(subcommand)]
+        action: SpoolAction,
+    },
+}
 
-    /// Sample rate in Hz
-    #[arg(long, default_value = "48000")]
-    sample_rate: u32,
+#[derive(Subcommand, Debug)]
+enum SpoolAction {
+    /// Print the queued recordings as NDJSON on stdout
+    Status,
+    /// Deliver what can be delivered now, in order, and print the outcome as JSON
+    Drain {
+        /// Bytes per second
+        #[arg(long, default_value_t = spool::DEFAULT_DRAIN_RATE)]
+        rate: u64,
+    },
+}
 
-    /// Number of channels (must be 2 for stereo)
-    #[arg(long, default_value = "2")]
-    channels: u16,
+#[derive(Subcommand, Debug)]
+enum UploadAction {
+    /// Print the next byte range to upload as JSON (`null` when everything is uploaded)
+    Next {
+        /// Only look at this session; default is every session, oldest first
+        #[arg(long)]
+        session: Option<String>,
+        #[arg(long, default_value_t = upload_manifest::DEFAULT_CHUNK_BYTES)]
+        chunk_bytes: u64,
+    },
+    /// Record that a byte range `[start, end)` of a segment was uploaded
+    Mark {
+        #[arg(long)]
+        session: String,
+        #[arg(long)]
+        segment: PathBuf,
+        #[arg(long)]
+        start: u64,
+        #[arg(long)]
+        end: u64,
+    },
 }
 
-/// Audio sample sent from capture threads to writer
-#[derive(Clone)]
-struct AudioFrame {
-    mic_sample: f32,
-    loopback_sample: f32,
+/// Exits with the code of the error's kind (see `error::ErrorKind::exit_code`)
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(error::kind(&e).exit_code());
+    }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
+    LAUNCHED.get_or_init(Instant::now);
+    let cli = Cli::parse();
+    let mut args = match (cli.command, cli.capture) {
+        (Some(command), _) => return run_command(command),
+        (None, Some(args)) => args,
+        // clap enforces the capture arguments when there is no subcommand
+        (None, None) => unreachable!(),
+    };
+    i18n::set_lang(args.lang);
+
+    args.prepare()?;
+
     // Fail fast on non-Windows
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(not(windows))]
     {
         eprintln!("Error: This tool only runs on Windows");
         std::process::exit(1);
     }
 
-    let args = Args::parse();
-
-    // Validate channels
-    if args.channels != 2 {
-        return Err(anyhow!("Only stereo (2 channels) is supported"));
+    #[cfg(windows)]
+    {
+        let session = args.session.clone();
+        let result = capture::run(args);
+        if let Err(e) = &result {
+            events::emit(
+                "error",
+                serde_json::json!({
+                    "session": session,
+                    "kind": error::kind(e),
+                    "error": format!("{:#}", e),
+                    "workspace": workspace::keep_failed(&session, e),
+                }),
+            );
+        }
+        result
     }
+}
 
-    println!(
-        "[win-audio-capture] Starting capture for session: {}",
-        args.session
-    );
-    println!("[win-audio-capture] Output: {:?}", args.out);
-    println!("[win-audio-capture] Sample rate: {} Hz", args.sample_rate);
-
-    // Set up graceful shutdown
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    ctrlc::set_handler(move || {
-        println!("\n[win-audio-capture] Received shutdown signal, stopping...");
-        r.store(false, Ordering::SeqCst);
-    })
-    .context("Failed to set Ctrl+C handler")?;
-
-    // Create channels for audio samples
-    let (mic_tx, mic_rx): (Sender<f32>, Receiver<f32>) = bounded(48000);
-    let (loopback_tx, loopback_rx): (Sender<f32>, Receiver<f32>) = bounded(48000);
-
-    // Get audio host
-    let host = cpal::default_host();
-
-    // Get default input device (MIC)
-    let input_device = host
-        .default_input_device()
-        .ok_or_else(|| anyhow!("No default input device found"))?;
-    println!(
-        "[win-audio-capture] MIC device: {}",
-        input_device.name().unwrap_or_else(|_| "Unknown".to_string())
-    );
-
-    // Get the device's default/supported config instead of forcing 48kHz
-    // This prevents "configuration not supported" errors on different hardware
-    let input_supported_config = input_device
-        .default_input_config()
-        .context("Failed to get default input config from MIC device")?;
-
-    println!(
-        "[win-audio-capture] MIC native config: {:?} @ {} Hz, {} channel(s)",
-        input_supported_config.sample_format(),
-        input_supported_config.sample_rate().0,
-        input_supported_config.channels()
-    );
-
-    let input_config = StreamConfig {
-        channels: input_supported_config.channels(), // Use native channel count
-        sample_rate: input_supported_config.sample_rate(),
-        buffer_size: cpal::BufferSize::Default,
-    };
-
-    // Update WAV spec to use actual sample rate
-    let actual_sample_rate = input_supported_config.sample_rate().0;
-
-    // Build input stream (MIC) - use f32 callback but handle format conversion
-    let mic_tx_clone = mic_tx.clone();
-    let num_channels = input_supported_config.channels() as usize;
-    let input_stream = input_device
-        .build_input_stream(
-            &input_config,
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                // Average all channels to mono
-                for chunk in data.chunks(num_channels) {
-                    let mono_sample = chunk.iter().sum::<f32>() / num_channels as f32;
-                    let _ = mic_tx_clone.try_send(mono_sample);
-                }
-            },
-            |err| eprintln!("[win-audio-capture] MIC stream error: {}", err),
-            None,
-        )
-        .context("Failed to build MIC input stream")?;
-
-    // Start WASAPI loopback capture in background thread
-    #[cfg(windows)]
-    let loopback_handle = {
-        use wasapi_loopback::WasapiLoopbackCapture;
-        let loopback_capture = WasapiLoopbackCapture::new(loopback_tx.clone(), running.clone());
-        match loopback_capture.start() {
-            Ok(handle) => {
-                println!("[win-audio-capture] WASAPI loopback capture started");
-                Some(handle)
+fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::ListDevices => {
+            for endpoint in devices::list()? {
+                println!("{}", serde_json::to_string(&endpoint)?);
             }
-            Err(e) => {
-                eprintln!("[win-audio-capture] Warning: Could not start WASAPI loopback: {}", e);
-                eprintln!("[win-audio-capture] Recording MIC only, loopback channel will be silent");
-                None
+            Ok(())
+        }
+        Command::ListSessions => {
+            for entry in registry::list(&registry::registry_dir())? {
+                println!("{}", serde_json::to_string(&entry)?);
             }
+            Ok(())
         }
-    };
-
-    #[cfg(not(windows))]
-    let loopback_handle: Option<std::thread::JoinHandle<Result<()>>> = None;
-
-    // Set up WAV writer using device's actual sample rate
-    let spec = WavSpec {
-        channels: 2,
-        sample_rate: actual_sample_rate,
-        bits_per_sample: 16,
-        sample_format: HoundSampleFormat::Int,
-    };
-
-    // Ensure parent directory exists
-    if let Some(parent) = args.out.parent() {
-        std::fs::create_dir_all(parent).context("Failed to create output directory")?;
-    }
-
-    let file = File::create(&args.out).context("Failed to create output WAV file")?;
-    let buf_writer = BufWriter::new(file);
-    let mut wav_writer = WavWriter::new(buf_writer, spec).context("Failed to create WAV writer")?;
-
-    // Set up stdout PCM frame writer for streaming
-    let stdout = std::io::stdout();
-    let mut stdout_lock = stdout.lock();
-    let mut frame_buffer: Vec<i16> = Vec::with_capacity(9600); // 100ms buffer @ 48kHz stereo
-    let mut sequence_number: u32 = 0;
-    const SAMPLES_PER_FRAME: usize = 4800; // 100ms @ 48kHz = 4800 stereo pairs
-
-    eprintln!("[win-audio-capture] Dual-mode output enabled: WAV file + stdout PCM frames");
-
-    // Start MIC stream (loopback is already running in background thread)
-    input_stream.play().context("Failed to start MIC stream")?;
-
-    println!("[win-audio-capture] Recording started...");
-
-    // Main loop: mix and write samples
-    let mut samples_written: u64 = 0;
-    let mut last_mic_sample: f32 = 0.0;
-    let mut last_loopback_sample: f32 = 0.0;
-
-    while running.load(Ordering::SeqCst) {
-        // Try to get samples from both channels
-        let mic_sample = mic_rx.try_recv().unwrap_or(last_mic_sample);
-        let loopback_sample = loopback_rx.try_recv().unwrap_or(last_loopback_sample);
-
-        last_mic_sample = mic_sample;
-        last_loopback_sample = loopback_sample;
-
-        // Convert to i16 and write stereo frame
-        let mic_i16 = (mic_sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
-        let loopback_i16 = (loopback_sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
-
-        wav_writer.write_sample(mic_i16)?; // Left channel
-        wav_writer.write_sample(loopback_i16)?; // Right channel
-
-        samples_written += 2;
-
-        // Accumulate stereo pair in frame buffer for stdout streaming
-        frame_buffer.push(mic_i16);
-        frame_buffer.push(loopback_i16);
-
-        // Flush frame to stdout when buffer reaches target size
-        if frame_buffer.len() >= SAMPLES_PER_FRAME * 2 {
-            match write_pcm_frame(&mut stdout_lock, &frame_buffer, sequence_number) {
-                Ok(_) => {
-                    sequence_number = sequence_number.wrapping_add(1);
-                    frame_buffer.clear();
-                }
-                Err(e) => {
-                    eprintln!("[win-audio-capture] Warning: Failed to write PCM frame: {}", e);
-                    eprintln!("[win-audio-capture] Continuing with WAV-only mode");
-                    frame_buffer.clear(); // Prevent buffer overflow
-                }
+        #[cfg(windows)]
+        Command::Attach { session, token } => attach::run_client(&session, token.as_deref()),
+        #[cfg(windows)]
+        Command::Observe {
+            session,
+            token,
+            events,
+            frames,
+            no_frames,
+        } => {
+            let subscription = subscription::Subscription {
+                events,
+                frames: if no_frames { Some(Vec::new()) } else { frames },
+            };
+            attach::run_observer(&session, &token, subscription)
+        }
+        #[cfg(windows)]
+        Command::Diagnose {
+            session,
+            wrap_key,
+            out,
+            yes,
+        } => {
+            let public_key = envelope::read_public_key(&wrap_key)?;
+            let consent = diagnose::Consent {
+                logs: yes
+                    || diagnose::ask(
+                        "Include the session's event journal, audit log and error report? \
+                         They name files, devices and the Windows user",
+                    ),
+                audio: yes
+                    || diagnose::ask(&format!(
+                        "Include {} seconds of the recording, with voices disguised?",
+                        diagnose::SAMPLE_SECS
+                    )),
+            };
+            let summary = diagnose::run(&session, &public_key, &out, consent)?;
+            println!("{}", serde_json::to_string(&summary)?);
+            Ok(())
+        }
+        #[cfg(windows)]
+        Command::Setup {
+            mic_device,
+            loopback_device,
+            output_root,
+            no_chirp,
+            register,
+            config,
+            yes,
+        } => {
+            let summary = setup::run(setup::Options {
+                mic_device,
+                loopback_device,
+                output_root,
+                chirp: !no_chirp,
+                register,
+                config: config.unwrap_or_else(device_config::CaptureConfig::default_path),
+                yes,
+            })?;
+            println!("{}", serde_json::to_string(&summary)?);
+            Ok(())
+        }
+        #[cfg(not(windows))]
+        Command::Attach { .. }
+        | Command::Observe { .. }
+        | Command::Diagnose { .. }
+        | Command::Setup { .. } => Err(anyhow!("This tool only runs on Windows")),
+        Command::Upload { action } => run_upload(action),
+        Command::Extract {
+            input,
+            from,
+            to,
+            out,
+            format,
+        } => {
+            let summary = extract::run(&input, from.as_deref(), to.as_deref(), &out, format)?;
+            println!("{}", serde_json::to_string(&summary)?);
+            Ok(())
+        }
+        Command::Stitch {
+            parts,
+            out,
+            format,
+            max_gap_secs,
+        } => {
+            let summary = stitch::run(&parts, &out, format, max_gap_secs)?;
+            println!("{}", serde_json::to_string(&summary)?);
+            Ok(())
+        }
+        Command::Journal { input, since_ms } => {
+            for event in journal::read(&journal::path(&input), since_ms)? {
+                println!("{}", event);
             }
+            Ok(())
         }
-
-        // Small sleep to prevent busy-waiting when no samples available
-        if mic_rx.is_empty() && loopback_rx.is_empty() {
-            thread::sleep(Duration::from_micros(100));
+        Command::Recover { wrap_key } => {
+            let wrap_key = wrap_key
+                .map(|path| envelope::read_public_key(&path))
+                .transpose()?;
+            let recovered =
+                recovery::scan(&upload_manifest::manifest_dir(), None, wrap_key.as_deref())?;
+            for segment in recovered {
+                println!("{}", serde_json::to_string(&segment)?);
+            }
+            Ok(())
         }
-    }
-
-    // Flush any remaining samples in frame buffer on shutdown
-    if !frame_buffer.is_empty() {
-        if let Err(e) = write_pcm_frame(&mut stdout_lock, &frame_buffer, sequence_number) {
-            eprintln!("[win-audio-capture] Warning: Failed to flush final PCM frame: {}", e);
+        Command::Repair { inputs } => {
+            let mut failed = 0;
+            for input in inputs {
+                let line = match riff::repair(&input) {
+                    Ok(repair) => serde_json::json!({ "path": input, "repair": repair }),
+                    Err(e) => {
+                        failed += 1;
+                        serde_json::json!({ "path": input, "error": format!("{:#}", e) })
+                    }
+                };
+                println!("{}", line);
+            }
+            if failed > 0 {
+                return Err(CaptureError::Format(format!(
+                    "{} recording(s) could not be repaired",
+                    failed
+                ))
+                .into());
+            }
+            Ok(())
         }
-    }
-
-    // Clean up streams
-    drop(input_stream);
-
-    // Wait for loopback thread to finish
-    if let Some(handle) = loopback_handle {
-        if let Err(e) = handle.join() {
-            eprintln!("[win-audio-capture] Warning: Loopback thread panicked: {:?}", e);
+        Command::ReplayStream { input, speed } => replay::run(&input, speed),
+        Command::SelfTest => {
+            let report = self_test::run(&std::env::temp_dir());
+            println!("{}", serde_json::to_string(&report)?);
+            match report.passed {
+                true => Ok(()),
+                false => Err(anyhow!("Self-test failed")),
+            }
+        }
+        Command::Capabilities {
+            json,
+            license_token,
+        } => {
+            let report = capabilities::detect(license_token.as_deref());
+            match json {
+                true => println!("{}", serde_json::to_string(&report)?),
+                false => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "self-update")]
+        Command::SelfUpdate { channel, feed } => {
+            let outcome = self_update::run(channel, &feed)?;
+            println!("{}", serde_json::to_string(&outcome)?);
+            Ok(())
+        }
+        Command::AuditVerify { input } => {
+            let verification = audit::verify(&input)?;
+            println!("{}", serde_json::to_string(&verification)?);
+            match verification.broken_at {
+                Some(seq) => Err(CaptureError::Format(format!(
+                    "Audit log is broken at entry {}",
+                    seq
+                ))
+                .into()),
+                None => Ok(()),
+            }
+        }
+        Command::GenVectors { out } => {
+            let vectors = vectors::write_all(&out)
+                .with_context(|| format!("Failed to write vectors to {}", out.display()))?;
+            println!(
+                "{}",
+                serde_json::json!({ "out": out, "vectors": vectors.len() })
+            );
+            Ok(())
+        }
+        Command::Redact { input, ranges } => {
+            for redaction in redact::run(&input, &redact::load_ranges(&ranges)?)? {
+                println!("{}", serde_json::to_string(&redaction)?);
+            }
+            Ok(())
+        }
+        Command::Imports { input } => {
+            let input = match input {
+                Some(input) => input,
+                None => std::env::current_exe().context("Failed to locate this binary")?,
+            };
+            let imports = imports::read(&input)?;
+            for import in &imports {
+                println!("{}", serde_json::to_string(import)?);
+            }
+            let missing: Vec<&str> = imports
+                .iter()
+                .filter(|import| !import.system)
+                .map(|import| import.dll.as_str())
+                .collect();
+            match missing.is_empty() {
+                true => Ok(()),
+                false => Err(anyhow!(
+                    "Needs DLLs Windows doesn't ship: {}",
+                    missing.join(", ")
+                )),
+            }
         }
+        Command::Spool {
+            action: SpoolAction::Status,
+        } => {
+            for job in spool::jobs(&spool::spool_dir())? {
+                println!("{}", serde_json::to_string(&job)?);
+            }
+            Ok(())
+        }
+        Command::Spool {
+            action: SpoolAction::Drain { rate },
+        } => match spool::drain(&spool::spool_dir(), rate)? {
+            Some(stats) => {
+                println!("{}", serde_json::to_string(&stats)?);
+                Ok(())
+            }
+            None => Err(anyhow!("Another process is draining the spool")),
+        },
     }
-
-    wav_writer.finalize().context("Failed to finalize WAV file")?;
-
-    let bytes_written = samples_written * 2; // 2 bytes per i16 sample
-    println!(
-        "[win-audio-capture] Recording stopped. Samples: {}, Bytes: {}",
-        samples_written, bytes_written
-    );
-
-    Ok(())
 }
 
-/// Write a PCM frame to stdout with framing header
-/// Frame format: [MAGIC(4)] [SeqNum(4)] [Size(4)] [PCM data...]
-/// Magic bytes: "SELL" (0x53454C4C)
-fn write_pcm_frame<W: Write>(
-    writer: &mut W,
-    samples: &[i16],
-    sequence_number: u32,
-) -> Result<()> {
-    let frame_size = (samples.len() * 2) as u32; // samples * 2 bytes per i16
-
-    // Write frame header
-    writer.write_all(b"SELL")?; // Magic bytes for frame synchronization
-    writer.write_all(&sequence_number.to_le_bytes())?; // Sequence number (u32 LE)
-    writer.write_all(&frame_size.to_le_bytes())?; // Frame size in bytes (u32 LE)
-
-    // Write PCM samples as little-endian i16
-    for &sample in samples {
-        writer.write_all(&sample.to_le_bytes())?;
+fn run_upload(action: UploadAction) -> Result<()> {
+    let dir = upload_manifest::manifest_dir();
+    match action {
+        UploadAction::Next {
+            session,
+            chunk_bytes,
+        } => {
+            let sessions = match session {
+                Some(session) => vec![session],
+                None => upload_manifest::sessions(&dir)?,
+            };
+            let mut next = None;
+            for session in sessions {
+                next = UploadManifest::load(&dir, &session)?.next_pending(chunk_bytes);
+                if next.is_some() {
+                    break;
+                }
+            }
+            println!("{}", serde_json::to_string(&next)?);
+            Ok(())
+        }
+        UploadAction::Mark {
+            session,
+            segment,
+            start,
+            end,
+        } => {
+            let mut manifest = UploadManifest::load(&dir, &session)?;
+            manifest.mark_uploaded(&segment, ByteRange { start, end })?;
+            manifest.save(&dir)
+        }
     }
-
-    // Flush to ensure data reaches Node.js immediately
-    writer.flush()?;
-
-    Ok(())
 }
@@ -0,0 +1,169 @@
+//! MIC held by another application
+//! A recorder or conferencing app that opens the MIC in exclusive mode (OBS or Audacity set
+//! up that way, Zoom with "original sound" exclusive mode) makes the shared-mode stream fail
+//! with `AUDCLNT_E_DEVICE_IN_USE`. Rather than ending the session on that error, capture goes
+//! on with the loopback only and a silent MIC channel, a `mic_locked` event names the
+//! processes with an active session on the endpoint, and the MIC is retried until it is
+//! free again (`mic_unlocked`).
+
+use serde::Serialize;
+
+/// `AUDCLNT_E_DEVICE_IN_USE`, as it appears in stream errors
+const DEVICE_IN_USE: &str = "0x8889000a";
+
+/// How often a locked MIC is tried again
+pub const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Executables that record audio themselves
+const RECORDER_APPS: &[&str] = &[
+    "obs64.exe",
+    "obs32.exe",
+    "audacity.exe",
+    "voicerecorder.exe",
+    "soundrecorder.exe",
+    "reaper.exe",
+    "bandicam.exe",
+    "camtasia.exe",
+    "recorder.exe",
+];
+
+/// What kind of application a holder is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HolderKind {
+    Recorder,
+    Conferencing,
+    Other,
+}
+
+/// A process with an active audio session on the MIC endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Holder {
+    pub pid: u32,
+    /// Executable file name, empty if the process couldn't be queried
+    pub app: String,
+    pub kind: HolderKind,
+}
+
+/// Whether opening the MIC failed because another application holds it exclusively
+pub fn is_exclusive_lock(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .to_string()
+            .to_ascii_lowercase()
+            .contains(DEVICE_IN_USE)
+    })
+}
+
+/// Classify a process by its image path (full path or file name)
+pub fn classify(path: &str) -> HolderKind {
+    let name = file_name(path);
+    if RECORDER_APPS
+        .iter()
+        .any(|app| name.eq_ignore_ascii_case(app))
+    {
+        HolderKind::Recorder
+    } else if crate::loopback_role::is_conferencing_app(name) {
+        HolderKind::Conferencing
+    } else {
+        HolderKind::Other
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+/// Other processes with an active session on the capture endpoint `endpoint_id` (the
+/// Console default when unknown)
+#[cfg(windows)]
+pub fn holders(endpoint_id: Option<&str>) -> anyhow::Result<Vec<Holder>> {
+    use crate::wasapi_loopback::process_image;
+    use anyhow::Context;
+    use windows::core::{Interface, HSTRING};
+    use windows::Win32::Media::Audio::{
+        eCapture, eConsole, AudioSessionStateActive, IAudioSessionControl2, IAudioSessionManager2,
+        IMMDeviceEnumerator, MMDeviceEnumerator,
+    };
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CLSCTX_ALL, COINIT_MULTITHREADED,
+    };
+
+    unsafe {
+        // Already initialized (as MTA) on the runtime's threads is fine too
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let device = match endpoint_id {
+            Some(id) => enumerator.GetDevice(&HSTRING::from(id)),
+            None => enumerator.GetDefaultAudioEndpoint(eCapture, eConsole),
+        }
+        .context("Failed to open the MIC endpoint")?;
+        let manager: IAudioSessionManager2 = device
+            .Activate(CLSCTX_ALL, None)
+            .context("Failed to list MIC audio sessions")?;
+        let sessions = manager.GetSessionEnumerator()?;
+        let own = std::process::id();
+        let mut holders = Vec::new();
+        for i in 0..sessions.GetCount()? {
+            let Ok(control) = sessions.GetSession(i) else {
+                continue;
+            };
+            if control.GetState()? != AudioSessionStateActive {
+                continue;
+            }
+            let Ok(control) = control.cast::<IAudioSessionControl2>() else {
+                continue;
+            };
+            // Process 0 is the system sounds session
+            let Ok(pid) = control.GetProcessId() else {
+                continue;
+            };
+            if pid == 0 || pid == own {
+                continue;
+            }
+            let path = process_image(pid).unwrap_or_default();
+            holders.push(Holder {
+                pid,
+                app: file_name(&path).to_string(),
+                kind: classify(&path),
+            });
+        }
+        Ok(holders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn recognises_the_device_in_use_error_anywhere_in_the_chain() {
+        let locked: anyhow::Result<()> = Err(anyhow!(
+            "A backend-specific error has occurred: The device is in use. (0x8889000A)"
+        ));
+        let locked = locked
+            .context("Failed to build MIC input stream")
+            .unwrap_err();
+        assert!(is_exclusive_lock(&locked));
+        assert!(!is_exclusive_lock(&anyhow!(
+            "The requested device is no longer available (0x88890004)"
+        )));
+    }
+
+    #[test]
+    fn classifies_holders_by_executable() {
+        assert_eq!(
+            classify(r"C:\Program Files\obs-studio\bin\64bit\obs64.exe"),
+            HolderKind::Recorder
+        );
+        assert_eq!(
+            classify(r"C:\Users\rep\AppData\Roaming\Zoom\bin\Zoom.exe"),
+            HolderKind::Conferencing
+        );
+        assert_eq!(classify("explorer.exe"), HolderKind::Other);
+        assert_eq!(classify(""), HolderKind::Other);
+    }
+}
@@ -0,0 +1,749 @@
+//! MIC/loopback mixer
+//! Paces stereo frame output from a clock and pairs samples from the two capture sources.
+//! Holds no device or OS state, so tests can drive it with a virtual clock and injected
+//! sample streams.
+//!
+//! Each device runs on its own crystal, a little fast or slow against the mixer clock, so
+//! over a long call a source's queue would slowly fill up to the backlog bound (and drop
+//! audio) or run dry (and be held). Once the queues have settled, each source is resampled
+//! by up to `MAX_CORRECTION_PPM` to keep its backlog where it settled, which keeps the two
+//! channels aligned for the whole recording.
+
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Default bound on queued audio per source before the oldest samples are dropped
+const DEFAULT_MAX_BACKLOG_MS: u64 = 500;
+
+/// Longest clock jump filled with held samples. Anything longer means the thread didn't run
+/// at all (system suspend, debugger), so the time is skipped instead of padded.
+const DEFAULT_MAX_GAP_MS: u64 = 2_000;
+
+/// Longest run of missing samples a source's last value is held for; past that the source
+/// is mixed as silence, so a stalled device doesn't leave a constant offset in the mix
+const DEFAULT_MAX_HOLD_MS: u64 = 20;
+
+/// How long the loopback may deliver nothing before it counts as idle. Some drivers send no
+/// packets at all while nothing plays; from then on the mixer fills the loopback with
+/// clocked silence instead of counting underruns, until packets arrive again.
+const DEFAULT_LOOPBACK_IDLE_MS: u64 = 100;
+
+/// Largest correction of a source's rate, far below anything audible as pitch
+const MAX_CORRECTION_PPM: f64 = 1_000.0;
+
+/// How long the backlogs get to settle before they are held where they settled
+const DRIFT_WARMUP_MS: u64 = 5_000;
+
+/// Backlog error, in milliseconds, left alone as jitter of packet delivery
+const DRIFT_DEADBAND_MS: f64 = 3.0;
+
+/// Seconds a backlog error beyond the deadband is corrected over
+const DRIFT_SETTLE_SECS: f64 = 10.0;
+
+/// Weight of each round's backlog in the smoothed backlog
+const BACKLOG_SMOOTHING: f64 = 0.02;
+
+/// Time source that decides how many frames are due
+pub trait Clock {
+    /// Time elapsed since the mixer timeline started
+    fn elapsed(&self) -> Duration;
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn elapsed(&self) -> Duration {
+        (**self).elapsed()
+    }
+}
+
+/// Wall clock used during real capture
+pub struct SystemClock {
+    started: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Queue of mono samples produced by one capture source
+pub trait SampleSource {
+    fn try_next(&mut self) -> Option<f32>;
+
+    /// Number of samples currently queued
+    fn backlog(&self) -> usize;
+}
+
+/// One of the two sources the mixer pairs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Mic,
+    Loopback,
+}
+
+/// Counters describing how well the sources kept up with the clock
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MixerStats {
+    /// Stereo frames produced
+    pub frames: u64,
+    /// Frames where a source had no sample and its last value was held
+    pub mic_underruns: u64,
+    pub loopback_underruns: u64,
+    /// Samples dropped because a source ran ahead of the backlog bound
+    pub mic_overruns: u64,
+    pub loopback_overruns: u64,
+    /// Clock jumps that were skipped rather than mixed
+    pub gaps: u64,
+    pub skipped_frames: u64,
+    /// Frames filled with silence while the loopback was idle
+    pub loopback_idle_frames: u64,
+    /// Correction each source's rate is resampled by at the moment, in ppm (positive when
+    /// the device runs fast)
+    pub mic_correction_ppm: i64,
+    pub loopback_correction_ppm: i64,
+}
+
+pub struct Mixer<C: Clock> {
+    clock: C,
+    sample_rate: u32,
+    max_backlog: usize,
+    max_gap: u64,
+    max_hold: usize,
+    loopback_idle: usize,
+    last_mic: f32,
+    last_loopback: f32,
+    /// Consecutive frames each source has been held for
+    mic_held: usize,
+    loopback_held: usize,
+    /// Frames of silence still to be inserted ahead of each source
+    mic_delay: usize,
+    loopback_delay: usize,
+    mic_drift: Drift,
+    loopback_drift: Drift,
+    stats: MixerStats,
+}
+
+impl<C: Clock> Mixer<C> {
+    pub fn new(clock: C, sample_rate: u32) -> Self {
+        Self {
+            clock,
+            sample_rate,
+            max_backlog: (sample_rate as u64 * DEFAULT_MAX_BACKLOG_MS / 1000) as usize,
+            max_gap: sample_rate as u64 * DEFAULT_MAX_GAP_MS / 1000,
+            max_hold: (sample_rate as u64 * DEFAULT_MAX_HOLD_MS / 1000) as usize,
+            loopback_idle: (sample_rate as u64 * DEFAULT_LOOPBACK_IDLE_MS / 1000) as usize,
+            last_mic: 0.0,
+            last_loopback: 0.0,
+            mic_held: 0,
+            loopback_held: 0,
+            mic_delay: 0,
+            loopback_delay: 0,
+            mic_drift: Drift::new(sample_rate),
+            loopback_drift: Drift::new(sample_rate),
+            stats: MixerStats::default(),
+        }
+    }
+
+    /// Start drift correction of `source` over, e.g. for a different device
+    pub fn reset_drift(&mut self, source: Source) {
+        match source {
+            Source::Mic => self.mic_drift = Drift::new(self.sample_rate),
+            Source::Loopback => self.loopback_drift = Drift::new(self.sample_rate),
+        }
+    }
+
+    /// Hold `source` back by `frames` from now on, mixing silence for it meanwhile
+    pub fn delay(&mut self, source: Source, frames: usize) {
+        match source {
+            Source::Mic => self.mic_delay += frames,
+            Source::Loopback => self.loopback_delay += frames,
+        }
+    }
+
+    pub fn stats(&self) -> MixerStats {
+        self.stats
+    }
+
+    /// Frames the clock says should exist by now but have not been produced yet
+    pub fn frames_due(&self) -> u64 {
+        let target = self.clock.elapsed().as_nanos() * self.sample_rate as u128 / 1_000_000_000;
+        (target as u64).saturating_sub(self.stats.frames + self.stats.skipped_frames)
+    }
+
+    /// Produce every frame that is due, appending `[mic, loopback]` pairs to `out`.
+    /// Returns the number of frames produced.
+    pub fn mix_due<M, L>(&mut self, mic: &mut M, loopback: &mut L, out: &mut Vec<[f32; 2]>) -> usize
+    where
+        M: SampleSource,
+        L: SampleSource,
+    {
+        let due = self.frames_due();
+        if due > self.max_gap {
+            // Whatever is still queued predates the gap
+            while mic.try_next().is_some() {}
+            while loopback.try_next().is_some() {}
+            self.stats.gaps += 1;
+            self.stats.skipped_frames += due;
+            self.reset_drift(Source::Mic);
+            self.reset_drift(Source::Loopback);
+            return 0;
+        }
+        let due = due as usize;
+
+        // Keep latency bounded: whatever would still be queued beyond the backlog bound
+        // after this round is dropped from the front
+        self.stats.mic_overruns += trim_backlog(mic, due + self.max_backlog);
+        self.stats.loopback_overruns += trim_backlog(loopback, due + self.max_backlog);
+        self.mic_drift.steer(mic.backlog(), due);
+        self.loopback_drift.steer(loopback.backlog(), due);
+
+        out.reserve(due);
+        for _ in 0..due {
+            let mic_sample = match delayed(&mut self.mic_delay).or_else(|| self.mic_drift.next(mic))
+            {
+                Some(sample) => {
+                    self.mic_held = 0;
+                    sample
+                }
+                None => {
+                    self.stats.mic_underruns += 1;
+                    held(&mut self.last_mic, &mut self.mic_held, self.max_hold)
+                }
+            };
+            let loopback_sample = match delayed(&mut self.loopback_delay)
+                .or_else(|| self.loopback_drift.next(loopback))
+            {
+                Some(sample) => {
+                    self.loopback_held = 0;
+                    sample
+                }
+                None if self.loopback_held >= self.loopback_idle => {
+                    // Nothing is queued on purpose; settle again once packets are back
+                    self.loopback_drift = Drift::new(self.sample_rate);
+                    self.stats.loopback_idle_frames += 1;
+                    0.0
+                }
+                None => {
+                    self.stats.loopback_underruns += 1;
+                    held(
+                        &mut self.last_loopback,
+                        &mut self.loopback_held,
+                        self.max_hold,
+                    )
+                }
+            };
+
+            self.last_mic = mic_sample;
+            self.last_loopback = loopback_sample;
+            out.push([mic_sample, loopback_sample]);
+        }
+
+        self.stats.frames += due as u64;
+        self.stats.mic_correction_ppm = self.mic_drift.correction_ppm();
+        self.stats.loopback_correction_ppm = self.loopback_drift.correction_ppm();
+        due
+    }
+}
+
+/// Drift correction of one source: reads it at a rate a little off 1:1, interpolating
+/// linearly, so that its backlog stays where it settled during the warm-up
+#[derive(Debug, Clone, Copy)]
+struct Drift {
+    sample_rate: u32,
+    /// Frames mixed so far, until the warm-up is over
+    warmup_left: usize,
+    smoothed: Option<f64>,
+    /// Backlog to hold, from the end of the warm-up
+    target: Option<f64>,
+    /// Source samples per mixed frame
+    step: f64,
+    /// Output position past `previous`, in (0, 1] once a sample has been read
+    position: f64,
+    previous: f32,
+    current: f32,
+}
+
+impl Drift {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            warmup_left: (sample_rate as u64 * DRIFT_WARMUP_MS / 1000) as usize,
+            smoothed: None,
+            target: None,
+            step: 1.0,
+            position: 2.0,
+            previous: 0.0,
+            current: 0.0,
+        }
+    }
+
+    /// Adjust the rate from the backlog at the start of a round of `due` frames
+    fn steer(&mut self, backlog: usize, due: usize) {
+        let backlog = backlog as f64;
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed + (backlog - smoothed) * BACKLOG_SMOOTHING,
+            None => backlog,
+        };
+        self.smoothed = Some(smoothed);
+        if self.warmup_left > 0 {
+            self.warmup_left = self.warmup_left.saturating_sub(due);
+            return;
+        }
+        let target = *self.target.get_or_insert(smoothed);
+        let deadband = self.sample_rate as f64 * DRIFT_DEADBAND_MS / 1000.0;
+        let error = smoothed - target;
+        let excess = error.signum() * (error.abs() - deadband).max(0.0);
+        let correction = excess / (self.sample_rate as f64 * DRIFT_SETTLE_SECS);
+        let max = MAX_CORRECTION_PPM / 1_000_000.0;
+        self.step = 1.0 + correction.clamp(-max, max);
+    }
+
+    /// Next sample of `source` at the corrected rate; `None` when it has run dry
+    fn next<S: SampleSource>(&mut self, source: &mut S) -> Option<f32> {
+        while self.position > 1.0 {
+            let sample = source.try_next()?;
+            self.previous = self.current;
+            self.current = sample;
+            self.position -= 1.0;
+        }
+        // In step (no correction yet) samples pass through untouched
+        let sample = if self.position == 1.0 {
+            self.current
+        } else {
+            self.previous + (self.current - self.previous) * self.position as f32
+        };
+        self.position += self.step;
+        Some(sample)
+    }
+
+    fn correction_ppm(&self) -> i64 {
+        ((self.step - 1.0) * 1_000_000.0).round() as i64
+    }
+}
+
+/// Silence while a source is being held back
+fn delayed(remaining: &mut usize) -> Option<f32> {
+    *remaining = remaining.checked_sub(1)?;
+    Some(0.0)
+}
+
+/// Value to mix for a source with no sample: its last one, or silence once it has been
+/// held for longer than `max_hold` frames
+fn held(last: &mut f32, held_for: &mut usize, max_hold: usize) -> f32 {
+    *held_for += 1;
+    if *held_for > max_hold {
+        *last = 0.0;
+    }
+    *last
+}
+
+/// Extra source that doesn't drive the mix but follows it: it is read for exactly as many
+/// frames as the mixer produced, with the same hold-on-underrun and backlog rules
+pub struct FollowerSource<S: SampleSource> {
+    source: S,
+    max_backlog: usize,
+    last: f32,
+    delay: usize,
+    pub underruns: u64,
+    pub overruns: u64,
+}
+
+impl<S: SampleSource> FollowerSource<S> {
+    pub fn new(source: S, sample_rate: u32) -> Self {
+        Self {
+            source,
+            max_backlog: (sample_rate as u64 * DEFAULT_MAX_BACKLOG_MS / 1000) as usize,
+            last: 0.0,
+            delay: 0,
+            underruns: 0,
+            overruns: 0,
+        }
+    }
+
+    /// Hold the source back by `frames`, like `Mixer::delay`
+    pub fn delay(&mut self, frames: usize) {
+        self.delay += frames;
+    }
+
+    /// Append `frames` samples to `out`
+    pub fn follow(&mut self, frames: usize, out: &mut Vec<f32>) {
+        self.overruns += trim_backlog(&mut self.source, frames + self.max_backlog);
+        out.reserve(frames);
+        for _ in 0..frames {
+            match delayed(&mut self.delay).or_else(|| self.source.try_next()) {
+                Some(sample) => self.last = sample,
+                None => self.underruns += 1,
+            }
+            out.push(self.last);
+        }
+    }
+
+    /// Discard everything queued, e.g. after the mixer skipped a gap
+    pub fn drain(&mut self) {
+        while self.source.try_next().is_some() {}
+    }
+}
+
+/// Drop samples until at most `keep` remain, returning how many were dropped
+fn trim_backlog<S: SampleSource>(source: &mut S, keep: usize) -> u64 {
+    let mut dropped = 0;
+    while source.backlog() > keep && source.try_next().is_some() {
+        dropped += 1;
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Clock advanced explicitly by the test
+    #[derive(Clone, Default)]
+    struct VirtualClock {
+        elapsed: Rc<Cell<Duration>>,
+    }
+
+    impl VirtualClock {
+        fn advance(&self, by: Duration) {
+            self.elapsed.set(self.elapsed.get() + by);
+        }
+    }
+
+    impl Clock for VirtualClock {
+        fn elapsed(&self) -> Duration {
+            self.elapsed.get()
+        }
+    }
+
+    /// Source fed by the test
+    #[derive(Default)]
+    struct InjectedSource {
+        queue: VecDeque<f32>,
+    }
+
+    impl InjectedSource {
+        fn push(&mut self, samples: impl IntoIterator<Item = f32>) {
+            self.queue.extend(samples);
+        }
+    }
+
+    impl SampleSource for InjectedSource {
+        fn try_next(&mut self) -> Option<f32> {
+            self.queue.pop_front()
+        }
+
+        fn backlog(&self) -> usize {
+            self.queue.len()
+        }
+    }
+
+    const RATE: u32 = 48_000;
+
+    fn ramp(start: usize, len: usize) -> impl Iterator<Item = f32> {
+        (start..start + len).map(|i| i as f32)
+    }
+
+    #[test]
+    fn no_frames_before_clock_advances() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock, RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push(ramp(0, 100));
+
+        let mut out = Vec::new();
+        assert_eq!(mixer.mix_due(&mut mic, &mut loopback, &mut out), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn gap_holds_last_sample_and_counts_underruns() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push([0.25, 0.5]);
+        loopback.push([0.1, 0.2, 0.3, 0.4]);
+
+        clock.advance(Duration::from_micros(4 * 1_000_000 / RATE as u64 + 1));
+        let mut out = Vec::new();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+
+        assert_eq!(out, vec![[0.25, 0.1], [0.5, 0.2], [0.5, 0.3], [0.5, 0.4]]);
+        assert_eq!(mixer.stats().mic_underruns, 2);
+        assert_eq!(mixer.stats().loopback_underruns, 0);
+    }
+
+    #[test]
+    fn long_underruns_fall_back_to_silence() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push([0.5]);
+        loopback.push(vec![0.1; RATE as usize / 10]);
+
+        clock.advance(Duration::from_millis(100));
+        let mut out = Vec::new();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+
+        let max_hold = (RATE as u64 * DEFAULT_MAX_HOLD_MS / 1000) as usize;
+        assert_eq!(out[max_hold][0], 0.5);
+        assert!(out[max_hold + 1..].iter().all(|&[mic, _]| mic == 0.0));
+    }
+
+    #[test]
+    fn idle_loopback_is_filled_with_clocked_silence() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push(vec![0.5; RATE as usize]);
+        loopback.push([0.1]);
+
+        clock.advance(Duration::from_millis(500));
+        let mut out = Vec::new();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+
+        let idle = (RATE as u64 * DEFAULT_LOOPBACK_IDLE_MS / 1000) as usize;
+        let stats = mixer.stats();
+        assert_eq!(out.len(), RATE as usize / 2);
+        assert_eq!(stats.loopback_underruns, idle as u64);
+        assert_eq!(stats.loopback_idle_frames, (out.len() - 1 - idle) as u64);
+        assert!(out[1 + idle..]
+            .iter()
+            .all(|&[mic, loopback]| (mic, loopback) == (0.5, 0.0)));
+
+        // Packets arriving again end the idle stretch
+        loopback.push([0.2]);
+        clock.advance(Duration::from_millis(10));
+        out.clear();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+        assert_eq!(out[0][1], 0.2);
+        assert_eq!(
+            mixer.stats().loopback_underruns,
+            idle as u64 + out.len() as u64 - 1
+        );
+    }
+
+    #[test]
+    fn delay_shifts_a_source_behind_the_other() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push(ramp(1, 4));
+        loopback.push(ramp(1, 4));
+        mixer.delay(Source::Mic, 2);
+
+        clock.advance(Duration::from_micros(4 * 1_000_000 / RATE as u64 + 1));
+        let mut out = Vec::new();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+
+        assert_eq!(out, vec![[0.0, 1.0], [0.0, 2.0], [1.0, 3.0], [2.0, 4.0]]);
+        assert_eq!(mixer.stats().mic_underruns, 0);
+        assert_eq!(mic.backlog(), 2);
+    }
+
+    #[test]
+    fn overrun_drops_oldest_samples() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        mixer.max_backlog = 10;
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push(ramp(0, 30));
+        loopback.push(ramp(0, 12));
+
+        clock.advance(Duration::from_micros(2 * 1_000_000 / RATE as u64 + 1));
+        let mut out = Vec::new();
+        mixer.mix_due(&mut mic, &mut loopback, &mut out);
+
+        assert_eq!(out, vec![[18.0, 0.0], [19.0, 1.0]]);
+        assert_eq!(mixer.stats().mic_overruns, 18);
+        assert_eq!(mixer.stats().loopback_overruns, 0);
+        assert_eq!(mic.backlog(), 10);
+    }
+
+    #[test]
+    fn long_clock_jump_is_skipped() {
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        mic.push(ramp(0, 10));
+
+        // Lid closed for a minute
+        clock.advance(Duration::from_secs(60));
+        let mut out = Vec::new();
+        assert_eq!(mixer.mix_due(&mut mic, &mut loopback, &mut out), 0);
+        assert_eq!(mixer.stats().gaps, 1);
+        assert_eq!(mixer.stats().skipped_frames, 60 * RATE as u64);
+        assert_eq!(mic.backlog(), 0);
+
+        // Mixing resumes from the new clock position
+        clock.advance(Duration::from_micros(3 * 1_000_000 / RATE as u64 + 1));
+        assert_eq!(mixer.mix_due(&mut mic, &mut loopback, &mut out), 3);
+        assert_eq!(mixer.stats().frames, 3);
+    }
+
+    #[test]
+    fn follower_tracks_mixed_frames() {
+        let mut source = InjectedSource::default();
+        source.push([0.1, 0.2, 0.3]);
+        let mut follower = FollowerSource::new(source, RATE);
+        follower.max_backlog = 2;
+
+        let mut out = Vec::new();
+        follower.follow(2, &mut out);
+        assert_eq!(out, vec![0.1, 0.2]);
+        follower.follow(3, &mut out);
+        assert_eq!(out, vec![0.1, 0.2, 0.3, 0.3, 0.3]);
+        assert_eq!(follower.underruns, 2);
+
+        follower.source.push(ramp(0, 10));
+        out.clear();
+        follower.follow(1, &mut out);
+        assert_eq!(out, vec![7.0]);
+        assert_eq!(follower.overruns, 7);
+    }
+
+    #[test]
+    fn drift_correction_keeps_a_fast_device_in_step() {
+        const SLOW_RATE: u32 = 8_000;
+        let clock = VirtualClock::default();
+        let mut mixer = Mixer::new(clock.clone(), SLOW_RATE);
+        let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+        let mut out = Vec::new();
+        // 500 ppm fast would queue up 600 ms over 20 minutes and overrun the bound
+        let mic_rate = SLOW_RATE as f64 * 1.000_5;
+        let mut delivered = 0usize;
+        for step in 1..=120_000usize {
+            let should_have = (step as f64 * 0.01 * mic_rate) as usize;
+            mic.push(std::iter::repeat_n(0.5, should_have - delivered));
+            delivered = should_have;
+            loopback.push(std::iter::repeat_n(0.25, SLOW_RATE as usize / 100));
+            clock.advance(Duration::from_millis(10));
+            out.clear();
+            mixer.mix_due(&mut mic, &mut loopback, &mut out);
+        }
+
+        let stats = mixer.stats();
+        assert_eq!(stats.mic_overruns, 0);
+        assert!(mic.backlog() < SLOW_RATE as usize / 20);
+        assert!((400..=600).contains(&stats.mic_correction_ppm));
+        // The in-step source is left alone and comes out untouched
+        assert_eq!(stats.loopback_correction_ppm, 0);
+        assert!(out
+            .iter()
+            .all(|&[mic, loopback]| mic == 0.5 && loopback == 0.25));
+    }
+
+    proptest! {
+        /// Output length follows the clock no matter how the sources behave
+        #[test]
+        fn frame_count_tracks_clock(
+            steps in prop::collection::vec((1u64..50_000, 0usize..4_000, 0usize..4_000), 1..40),
+        ) {
+            let clock = VirtualClock::default();
+            let mut mixer = Mixer::new(clock.clone(), RATE);
+            let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+            let mut out = Vec::new();
+            let mut total = Duration::ZERO;
+
+            for (step_us, mic_len, loopback_len) in steps {
+                mic.push(ramp(0, mic_len));
+                loopback.push(ramp(0, loopback_len));
+                clock.advance(Duration::from_micros(step_us));
+                total += Duration::from_micros(step_us);
+                mixer.mix_due(&mut mic, &mut loopback, &mut out);
+            }
+
+            let expected = (total.as_nanos() * RATE as u128 / 1_000_000_000) as usize;
+            prop_assert_eq!(out.len(), expected);
+            prop_assert_eq!(mixer.stats().frames, expected as u64);
+        }
+
+        /// A source running at exactly the clock rate comes out untouched, whatever the
+        /// delivery chunking
+        #[test]
+        fn in_step_source_is_lossless(chunks in prop::collection::vec(1usize..2_000, 1..40)) {
+            let clock = VirtualClock::default();
+            let mut mixer = Mixer::new(clock.clone(), RATE);
+            let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+            let mut out = Vec::new();
+            let mut delivered = 0;
+
+            for chunk in chunks {
+                mic.push(ramp(delivered, chunk));
+                loopback.push(ramp(delivered, chunk));
+                delivered += chunk;
+                // Advance to exactly the delivered sample count
+                clock.elapsed.set(Duration::from_nanos(
+                    (delivered as u64 * 1_000_000_000).div_ceil(RATE as u64),
+                ));
+                mixer.mix_due(&mut mic, &mut loopback, &mut out);
+            }
+
+            prop_assert_eq!(out.len(), delivered);
+            for (i, frame) in out.iter().enumerate() {
+                prop_assert_eq!(*frame, [i as f32, i as f32]);
+            }
+            prop_assert_eq!(mixer.stats().mic_underruns, 0);
+            prop_assert_eq!(mixer.stats().mic_overruns, 0);
+        }
+
+        /// A drifting source never queues more than the backlog bound and every due
+        /// frame is accounted for as delivered, held or dropped, from the warm-up into
+        /// drift correction, which reads the source up to `MAX_CORRECTION_PPM` off 1:1
+        #[test]
+        fn drifting_source_stays_bounded(
+            drift_ppm in -20_000i64..20_000,
+            steps in (DRIFT_WARMUP_MS / 100 + 1) as usize..80,
+        ) {
+            let clock = VirtualClock::default();
+            let max_backlog = 2_400;
+            let mut mixer = Mixer::new(clock.clone(), RATE);
+            mixer.max_backlog = max_backlog;
+            let (mut mic, mut loopback) = (InjectedSource::default(), InjectedSource::default());
+            let mut out = Vec::new();
+            let source_rate = RATE as f64 * (1.0 + drift_ppm as f64 / 1_000_000.0);
+            let mut delivered = 0usize;
+
+            for step in 1..=steps {
+                // 100 ms ticks
+                let should_have = (step as f64 * 0.1 * source_rate) as usize;
+                mic.push(ramp(delivered, should_have - delivered));
+                delivered = should_have;
+                clock.advance(Duration::from_millis(100));
+                mixer.mix_due(&mut mic, &mut loopback, &mut out);
+                out.clear();
+                prop_assert!(mic.backlog() <= max_backlog);
+            }
+            prop_assert!(mixer.mic_drift.target.is_some(), "still warming up");
+
+            let stats = mixer.stats();
+            let consumed = delivered as u64 - mic.backlog() as u64 - stats.mic_overruns;
+            let resampled = (stats.frames as f64 * MAX_CORRECTION_PPM / 1_000_000.0).ceil() as u64;
+            prop_assert!(
+                (consumed + stats.mic_underruns).abs_diff(stats.frames) <= resampled + 1,
+                "{} consumed and {} held for {} frames",
+                consumed,
+                stats.mic_underruns,
+                stats.frames
+            );
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! MIC mute state
+//! The MIC channel can be muted from two places: the Windows capture endpoint (muted in the
+//! OS or by the conferencing app) and the headset mute button. Compliance wants the
+//! recording to match what the prospect heard, so with `--mute-sync silence` an OS mute is
+//! recorded as silence.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--mute-sync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MuteSync {
+    /// Ignore the endpoint mute state
+    Off,
+    /// Emit mute/unmute events and timeline entries
+    Events,
+    /// Events, and record silence on the MIC channel while the endpoint is muted
+    Silence,
+}
+
+/// Where mutes come from, and whether the MIC channel is currently silenced
+#[derive(Debug, Clone, Copy)]
+pub struct MuteState {
+    sync: MuteSync,
+    pub endpoint: bool,
+    pub headset: bool,
+}
+
+impl MuteState {
+    pub fn new(sync: MuteSync) -> Self {
+        Self {
+            sync,
+            endpoint: false,
+            headset: false,
+        }
+    }
+
+    /// Whether the MIC channel should be recorded as silence
+    pub fn silenced(&self) -> bool {
+        self.headset || (self.endpoint && self.sync == MuteSync::Silence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_mute_only_silences_in_silence_mode() {
+        let mut events = MuteState::new(MuteSync::Events);
+        events.endpoint = true;
+        assert!(!events.silenced());
+
+        let mut silence = MuteState::new(MuteSync::Silence);
+        silence.endpoint = true;
+        assert!(silence.silenced());
+    }
+
+    #[test]
+    fn headset_mute_always_silences() {
+        let mut state = MuteState::new(MuteSync::Off);
+        assert!(!state.silenced());
+        state.headset = true;
+        assert!(state.silenced());
+    }
+}
@@ -0,0 +1,251 @@
+//! Audio notes
+//! The parent app drops short snippets into the session with
+//! `{"cmd":"audio_note","path":"note.wav","label":"Budget call-back"}`: TTS of the notes the
+//! rep dictated, a system prompt, anything a reviewer should hear in context.
+//!
+//! `--notes channel` plays each snippet into an extra channel of the recording (after the
+//! loopback channels) from the moment it arrives; snippets arriving while one plays queue up
+//! behind it. `--notes attachments` copies the snippet next to the recording as
+//! `<stem>.note-<n>.wav` instead. Either way an `audio_note` timeline entry marks the spot.
+//!
+//! Snippets come at any level, a full-scale TTS render as often as a faint phone memo, and
+//! the notes channel ends up in the mixdown and the live stream next to the call. Played
+//! snippets are therefore brought to a reference level first, then follow the call's
+//! loudness at playback: a few dB under whoever is talking, never above the reference, and
+//! no quieter than a floor when the line is silent.
+
+use crate::error::fail;
+use crate::resample::{ResampleQuality, Resampler};
+use crate::riff::{self, RiffAudio};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+/// Longest snippet accepted
+pub const MAX_NOTE_SECS: u64 = 120;
+
+/// RMS level snippets are normalized to before playing (-20 dBFS)
+const REFERENCE_RMS: f32 = 0.1;
+
+/// Peak a normalized snippet is held under
+const PEAK_CEILING: f32 = 0.9;
+
+/// Quietest a snippet plays at, relative to the reference, when the call is silent (-12 dB)
+const FLOOR_GAIN: f32 = 0.25;
+
+/// How far a snippet sits under the call's loudness (-6 dB)
+const DUCK_GAIN: f32 = 0.5;
+
+/// Time constant of the call loudness follower, about a "momentary" loudness window
+const PROGRAM_WINDOW_MS: f32 = 400.0;
+
+/// `--notes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotesMode {
+    /// Copy snippets next to the recording
+    Attachments,
+    /// Play snippets into a dedicated channel of the recording
+    Channel,
+}
+
+/// Mono samples of one snippet at the session rate
+pub type NoteSamples = Arc<[f32]>;
+
+/// Read the 16-bit WAV snippet at `path` as mono at `sample_rate`
+pub fn load(path: &Path, sample_rate: u32) -> Result<Vec<f32>> {
+    let audio = RiffAudio::open(path)?;
+    if audio.frames() > MAX_NOTE_SECS * audio.sample_rate as u64 {
+        return Err(fail!(
+            Format,
+            "Audio note {:?} is longer than {}s",
+            path,
+            MAX_NOTE_SECS
+        ));
+    }
+    let channels = audio.channels.max(1) as usize;
+    let mut mono = Vec::with_capacity(audio.frames() as usize);
+    riff::read_frames(path, &audio, 0, audio.frames(), |samples| {
+        mono.extend(
+            samples.chunks_exact(channels).map(|frame| {
+                frame.iter().map(|&s| s as f32 / 32768.0).sum::<f32>() / channels as f32
+            }),
+        );
+        Ok(())
+    })?;
+    if audio.sample_rate == sample_rate {
+        return Ok(mono);
+    }
+
+    let mut resampler = Resampler::new(audio.sample_rate, sample_rate, ResampleQuality::High);
+    let expected =
+        (mono.len() as u64 * sample_rate as u64 / audio.sample_rate.max(1) as u64) as usize;
+    let mut out = Vec::with_capacity(expected + 64);
+    resampler.process(&mono, &mut out);
+    // Flush the filter's tail, then cut what belongs to the padding
+    resampler.process(&[0.0; 64], &mut out);
+    out.truncate(expected);
+    Ok(out)
+}
+
+/// Bring a snippet to the reference level, without letting its peaks past the ceiling
+pub fn normalize(samples: &mut [f32]) {
+    let (power, peak) = samples.iter().fold((0.0f64, 0.0f32), |(power, peak), &s| {
+        (power + (s as f64) * (s as f64), peak.max(s.abs()))
+    });
+    let rms = (power / samples.len().max(1) as f64).sqrt() as f32;
+    if rms <= f32::EPSILON {
+        return;
+    }
+    let gain = (REFERENCE_RMS / rms).min(PEAK_CEILING / peak);
+    samples.iter_mut().for_each(|s| *s *= gain);
+}
+
+/// Sets the level of normalized snippets from the loudness of the call they play over
+pub struct LoudnessGuard {
+    /// Smoothed power of the call
+    program: f32,
+    coefficient: f32,
+}
+
+impl LoudnessGuard {
+    pub fn new(sample_rate: u32) -> Self {
+        let window = PROGRAM_WINDOW_MS / 1000.0 * sample_rate.max(1) as f32;
+        Self {
+            program: 0.0,
+            coefficient: 1.0 - (-1.0 / window).exp(),
+        }
+    }
+
+    /// Account for one frame of the call (`voices`, after processing) and return `note`
+    /// at the level that fits it
+    pub fn process(&mut self, note: f32, voices: &[f32]) -> f32 {
+        let power: f32 = voices.iter().map(|v| v * v).sum();
+        self.program += self.coefficient * (power - self.program);
+        note * self.gain()
+    }
+
+    /// Gain for a snippet at the reference level
+    pub fn gain(&self) -> f32 {
+        (self.program.sqrt() * DUCK_GAIN / REFERENCE_RMS).clamp(FLOOR_GAIN, 1.0)
+    }
+}
+
+/// Plays queued snippets back to back on the mixer thread, silence in between
+pub struct NotePlayer {
+    queue: Receiver<NoteSamples>,
+    current: Option<NoteSamples>,
+    position: usize,
+}
+
+impl NotePlayer {
+    pub fn new(queue: Receiver<NoteSamples>) -> Self {
+        Self {
+            queue,
+            current: None,
+            position: 0,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        loop {
+            if let Some(note) = &self.current {
+                if let Some(&sample) = note.get(self.position) {
+                    self.position += 1;
+                    return sample;
+                }
+            }
+            match self.queue.try_recv() {
+                Ok(note) => {
+                    self.current = Some(note);
+                    self.position = 0;
+                }
+                Err(_) => {
+                    self.current = None;
+                    return 0.0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{self, OutputFormat, SinkSpec};
+
+    #[test]
+    fn plays_queued_notes_back_to_back() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut player = NotePlayer::new(rx);
+        assert_eq!(player.next_sample(), 0.0);
+        tx.send(NoteSamples::from(vec![0.1, 0.2])).unwrap();
+        tx.send(NoteSamples::from(vec![0.3])).unwrap();
+        let played: Vec<f32> = (0..5).map(|_| player.next_sample()).collect();
+        assert_eq!(played, [0.1, 0.2, 0.3, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn normalizes_notes_to_the_reference_level_under_the_ceiling() {
+        let mut loud = vec![0.8f32, -0.8, 0.8, -0.8];
+        normalize(&mut loud);
+        assert!(loud.iter().all(|s| (s.abs() - REFERENCE_RMS).abs() < 1e-6));
+
+        // A click in a quiet memo can't be raised past the ceiling
+        let mut spiky = vec![0.0f32; 99];
+        spiky.push(0.5);
+        normalize(&mut spiky);
+        assert!((spiky[99] - PEAK_CEILING).abs() < 1e-6);
+
+        let mut silent = vec![0.0f32; 4];
+        normalize(&mut silent);
+        assert_eq!(silent, [0.0; 4]);
+    }
+
+    #[test]
+    fn guard_ducks_notes_under_the_call_and_keeps_a_floor() {
+        let mut guard = LoudnessGuard::new(1_000);
+        assert_eq!(
+            guard.process(REFERENCE_RMS, &[0.0, 0.0]),
+            REFERENCE_RMS * FLOOR_GAIN
+        );
+
+        // A quiet prospect: the note sits 6 dB under them
+        for _ in 0..5_000 {
+            guard.process(0.0, &[0.0, 0.1]);
+        }
+        assert!((guard.gain() - 0.5).abs() < 0.01);
+
+        // Shouting on the line never lifts the note past its reference level
+        for _ in 0..5_000 {
+            guard.process(0.0, &[0.9, 0.9]);
+        }
+        assert_eq!(guard.gain(), 1.0);
+    }
+
+    #[test]
+    fn loads_notes_as_mono_at_the_session_rate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.wav");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 24_000,
+        };
+        let mut out = sink::create(OutputFormat::Wav, &path, spec).unwrap();
+        // Left and right average to a constant 0.25
+        let frames: Vec<i16> = (0..2_400).flat_map(|_| [16_384i16, 0]).collect();
+        out.write_samples(&frames).unwrap();
+        out.finalize().unwrap();
+
+        let same_rate = load(&path, 24_000).unwrap();
+        assert_eq!(same_rate.len(), 2_400);
+        assert!(same_rate.iter().all(|&s| s == 0.25));
+
+        let resampled = load(&path, 48_000).unwrap();
+        assert_eq!(resampled.len(), 4_800);
+        assert!((resampled[2_400] - 0.25).abs() < 0.01);
+    }
+}
@@ -0,0 +1,451 @@
+//! OpenTelemetry export
+//! With `--otlp-endpoint http://collector:4318` the session is reported to an OTLP/HTTP
+//! collector (JSON encoding) when it ends: a `capture_session` span with every lifecycle
+//! event attached as a span event, child spans for suspended stretches and finalizing,
+//! an error status if the capture failed, and the quality counters of the `stopped` event
+//! as gauges. Export happens once, after the recording is closed, and failures are only
+//! logged. Requests go through the system or `--proxy` proxy (see `http`). Only plain
+//! HTTP is spoken, so TLS collectors are reached through the local collector agent.
+
+use crate::error::fail;
+use crate::http::{self, ProxyConfig};
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc;
+
+const SERVICE_NAME: &str = "win-audio-capture";
+
+/// `http://host[:port][/path]`, parsed from `--otlp-endpoint`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// Prefix the `/v1/traces` and `/v1/metrics` paths go under, without a trailing slash
+    pub path: String,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            fail!(
+                Config,
+                "OTLP endpoint must be an http:// URL (send through a local collector for TLS)"
+            )
+        })?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        // `[v6]:port` or `host:port`
+        let host_end = match authority.strip_prefix('[') {
+            Some(v6) => v6.find(']').map_or(authority.len(), |end| end + 2),
+            None => authority.find(':').unwrap_or(authority.len()),
+        };
+        let (host, port) = authority.split_at(host_end);
+        let port = match port.strip_prefix(':') {
+            Some(port) => port
+                .parse()
+                .map_err(|_| fail!(Config, "Invalid port in OTLP endpoint: {}", url))?,
+            None if port.is_empty() => 4318,
+            None => return Err(fail!(Config, "Invalid OTLP endpoint: {}", url)),
+        };
+        if host.is_empty() {
+            return Err(fail!(Config, "OTLP endpoint has no host: {}", url));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// A `--otlp-header name=value`, e.g. for collector authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for Header {
+    type Err = anyhow::Error;
+
+    fn from_str(header: &str) -> Result<Self> {
+        let (name, value) = header
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or_else(|| fail!(Config, "OTLP header must be name=value: {}", header))?;
+        if header.contains(['\r', '\n']) {
+            return Err(fail!(Config, "OTLP header must be a single line"));
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// Collects the session's events from the start and exports them at the end
+pub struct Exporter {
+    endpoint: Endpoint,
+    headers: Vec<Header>,
+    proxy: ProxyConfig,
+    session: String,
+    started_ms: u64,
+    events: mpsc::Receiver<Value>,
+}
+
+impl Exporter {
+    pub fn start(
+        endpoint: Endpoint,
+        headers: Vec<Header>,
+        proxy: ProxyConfig,
+        session: &str,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel();
+        crate::events::set_tap(Some(tx));
+        Self {
+            endpoint,
+            headers,
+            proxy,
+            session: session.to_string(),
+            started_ms: crate::events::unix_millis(),
+            events: rx,
+        }
+    }
+
+    /// Export the session; `error` is why the capture failed, if it did
+    pub fn finish(self, error: Option<&anyhow::Error>) {
+        crate::events::set_tap(None);
+        let events: Vec<Value> = self.events.try_iter().collect();
+        let ended_ms = crate::events::unix_millis();
+        let error = error.map(|e| format!("{:#}", e));
+        let resource = resource(&self.session);
+        let traces = traces(
+            &resource,
+            self.started_ms,
+            ended_ms,
+            &events,
+            error.as_deref(),
+        );
+        let metrics = metrics(&resource, ended_ms, &events);
+
+        for (signal, body) in [("traces", traces), ("metrics", metrics)] {
+            let Some(body) = body else { continue };
+            let path = format!("{}/v1/{}", self.endpoint.path, signal);
+            if let Err(e) = post(&self.endpoint, &path, &self.headers, &self.proxy, &body) {
+                eprintln!(
+                    "[win-audio-capture] Warning: OTLP {} export failed: {:#}",
+                    signal, e
+                );
+            }
+        }
+    }
+}
+
+fn resource(session: &str) -> Value {
+    json!({
+        "attributes": [
+            attribute("service.name", &json!(SERVICE_NAME)),
+            attribute("service.version", &json!(env!("CARGO_PKG_VERSION"))),
+            attribute("selly.session.id", &json!(session)),
+        ]
+    })
+}
+
+fn scope() -> Value {
+    json!({ "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") })
+}
+
+/// OTLP trace request for the session, `None` when there is nothing to report
+fn traces(
+    resource: &Value,
+    started_ms: u64,
+    ended_ms: u64,
+    events: &[Value],
+    error: Option<&str>,
+) -> Option<Value> {
+    if events.is_empty() && error.is_none() {
+        return None;
+    }
+    let trace_id = random_hex(2);
+    let root_id = random_hex(1);
+    let event_ms = |event: &Value| event["ts_ms"].as_u64().unwrap_or(ended_ms);
+    let find = |name: &str| events.iter().find(|e| e["event"] == name);
+
+    let status = match error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+    let mut spans = vec![json!({
+        "traceId": trace_id,
+        "spanId": root_id,
+        "name": "capture_session",
+        "kind": 1,
+        "startTimeUnixNano": nanos(started_ms),
+        "endTimeUnixNano": nanos(ended_ms),
+        "events": events.iter().map(|event| json!({
+            "timeUnixNano": nanos(event_ms(event)),
+            "name": event["event"].as_str().unwrap_or("event"),
+            "attributes": attributes(event),
+        })).collect::<Vec<_>>(),
+        "status": status,
+    })];
+    let mut child = |name: &str, start_ms: u64, end_ms: u64| {
+        spans.push(json!({
+            "traceId": trace_id,
+            "spanId": random_hex(1),
+            "parentSpanId": root_id,
+            "name": name,
+            "kind": 1,
+            "startTimeUnixNano": nanos(start_ms),
+            "endTimeUnixNano": nanos(end_ms.max(start_ms)),
+        }));
+    };
+
+    let mut suspended_at = None;
+    for event in events {
+        match event["event"].as_str() {
+            Some("suspend") => suspended_at = Some(event_ms(event)),
+            Some("resume") => {
+                if let Some(start) = suspended_at.take() {
+                    child("suspended", start, event_ms(event));
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(stopping) = find("stopping") {
+        let end = find("stopped").map_or(ended_ms, event_ms);
+        child("finalize", event_ms(stopping), end);
+    }
+
+    Some(json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": scope(), "spans": spans }],
+        }]
+    }))
+}
+
+/// OTLP metrics request with the numeric fields of the `stopped` event as gauges
+fn metrics(resource: &Value, ended_ms: u64, events: &[Value]) -> Option<Value> {
+    let stopped = events.iter().rev().find(|e| e["event"] == "stopped")?;
+    let mut fields = Vec::new();
+    flatten("", stopped, &mut fields);
+    let metrics: Vec<Value> = fields
+        .into_iter()
+        .filter(|(name, _)| name != "ts_ms")
+        .filter_map(|(name, value)| {
+            let point = match value {
+                Value::Number(n) if n.is_u64() || n.is_i64() => {
+                    json!({ "asInt": n.to_string() })
+                }
+                Value::Number(n) => json!({ "asDouble": n.as_f64()? }),
+                Value::Bool(b) => json!({ "asInt": (b as u8).to_string() }),
+                _ => return None,
+            };
+            let mut point = point.as_object()?.clone();
+            point.insert("timeUnixNano".to_string(), json!(nanos(ended_ms)));
+            Some(json!({
+                "name": format!("selly.capture.{}", name),
+                "gauge": { "dataPoints": [point] },
+            }))
+        })
+        .collect();
+    Some(json!({
+        "resourceMetrics": [{
+            "resource": resource,
+            "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+        }]
+    }))
+}
+
+/// OTLP attributes for an event's fields, nested objects under dotted keys
+fn attributes(event: &Value) -> Vec<Value> {
+    let mut fields = Vec::new();
+    flatten("", event, &mut fields);
+    fields
+        .into_iter()
+        .filter(|(key, value)| key != "event" && key != "ts_ms" && !value.is_null())
+        .map(|(key, value)| attribute(&key, &value))
+        .collect()
+}
+
+fn flatten(prefix: &str, value: &Value, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) => flatten_map(prefix, map, out),
+        _ => out.push((prefix.to_string(), value.clone())),
+    }
+}
+
+fn flatten_map(prefix: &str, map: &Map<String, Value>, out: &mut Vec<(String, Value)>) {
+    for (key, value) in map {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        flatten(&key, value, out);
+    }
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_u64() || n.is_i64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n.as_f64() }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn nanos(ms: u64) -> String {
+    (ms as u128 * 1_000_000).to_string()
+}
+
+/// `words` random 64-bit words as lowercase hex (OTLP/JSON ids are hex, not base64)
+fn random_hex(words: usize) -> String {
+    (0..words)
+        .map(|_| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos()),
+            );
+            hasher.write_u32(std::process::id());
+            // Ids must not be all zero
+            format!("{:016x}", hasher.finish().max(1))
+        })
+        .collect()
+}
+
+/// POST a JSON body and require a 2xx answer
+fn post(
+    endpoint: &Endpoint,
+    path: &str,
+    headers: &[Header],
+    proxy: &ProxyConfig,
+    body: &Value,
+) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let headers: Vec<(&str, &str)> = [("Content-Type", "application/json")]
+        .into_iter()
+        .chain(headers.iter().map(|h| (h.name.as_str(), h.value.as_str())))
+        .collect();
+    let status = http::post(&endpoint.host, endpoint.port, path, &headers, &body, proxy)?;
+    if !(200..300).contains(&status) {
+        return Err(fail!(Network, "Collector answered {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn parses_endpoints_and_headers() {
+        let endpoint: Endpoint = "http://collector.corp:4318/otlp/".parse().unwrap();
+        assert_eq!(
+            endpoint,
+            Endpoint {
+                host: "collector.corp".to_string(),
+                port: 4318,
+                path: "/otlp".to_string(),
+            }
+        );
+        let endpoint: Endpoint = "http://localhost".parse().unwrap();
+        assert_eq!((endpoint.port, endpoint.path.as_str()), (4318, ""));
+        assert!("https://collector".parse::<Endpoint>().is_err());
+
+        let header: Header = "Authorization=Bearer abc=".parse().unwrap();
+        assert_eq!(header.name, "Authorization");
+        assert_eq!(header.value, "Bearer abc=");
+        assert!("novalue".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn builds_session_spans_and_quality_gauges() {
+        let events = vec![
+            json!({ "event": "started", "ts_ms": 1_000, "session": "s1", "sample_rate": 48_000 }),
+            json!({ "event": "suspend", "ts_ms": 2_000 }),
+            json!({ "event": "resume", "ts_ms": 5_000 }),
+            json!({ "event": "stopping", "ts_ms": 9_000 }),
+            json!({ "event": "stopped", "ts_ms": 9_500, "bytes": 100,
+                    "mixer": { "mic_underruns": 3 }, "path": "x.wav" }),
+        ];
+        let resource = resource("s1");
+        let traces = traces(&resource, 900, 9_600, &events, Some("device lost")).unwrap();
+        let spans = traces["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let root = &spans[0];
+        assert_eq!(root["name"], "capture_session");
+        assert_eq!(root["status"]["code"], 2);
+        assert_eq!(root["events"].as_array().unwrap().len(), 5);
+        assert_eq!(root["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(
+            root["events"][0]["attributes"][0],
+            json!({ "key": "sample_rate", "value": { "intValue": "48000" } })
+        );
+        let names: Vec<&str> = spans[1..]
+            .iter()
+            .map(|s| s["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["suspended", "finalize"]);
+        assert_eq!(spans[1]["startTimeUnixNano"], "2000000000");
+        assert_eq!(spans[1]["parentSpanId"], root["spanId"]);
+
+        let metrics = metrics(&resource, 9_600, &events).unwrap();
+        let metrics = metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+        let names: Vec<&str> = metrics
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["selly.capture.bytes", "selly.capture.mixer.mic_underruns"]
+        );
+        assert_eq!(metrics[1]["gauge"]["dataPoints"][0]["asInt"], "3");
+    }
+
+    #[test]
+    fn posts_json_with_the_configured_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"{\"ok\":true}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let endpoint: Endpoint = format!("http://127.0.0.1:{}/otlp", port).parse().unwrap();
+        let headers = vec!["api-key=secret".parse().unwrap()];
+        post(
+            &endpoint,
+            "/otlp/v1/traces",
+            &headers,
+            &ProxyConfig::default(),
+            &json!({ "ok": true }),
+        )
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /otlp/v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("api-key: secret\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+    }
+}
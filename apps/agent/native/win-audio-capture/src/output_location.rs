@@ -0,0 +1,278 @@
+//! Output location checks
+//! Detects output paths that live on network storage (UNC shares, mapped network drives,
+//! cloud-synced folders) and probes how fast the target directory actually accepts writes.
+//! Recordings headed for slow storage are staged on local disk and copied over at finalize,
+//! so a stalling share can't back up into the capture path. A share that can't be reached
+//! at all is staged too; the recording waits in the spool until it comes back.
+
+use crate::error::fail;
+use crate::progress::{Reading, Report};
+use crate::workspace;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Bytes written (and synced) by the latency probe
+const PROBE_BYTES: usize = 256 * 1024;
+
+/// Probe duration above which a local-looking path is treated as slow storage
+const SLOW_WRITE_THRESHOLD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum Location {
+    Local,
+    /// `\\server\share\...` (plain or `\\?\UNC\` form)
+    UncShare(String),
+    /// Drive letter mapped to a network share
+    NetworkDrive(String),
+    /// Folder kept in sync by a cloud client (OneDrive)
+    CloudSynced(String),
+}
+
+impl Location {
+    pub fn is_network(&self) -> bool {
+        !matches!(self, Location::Local)
+    }
+}
+
+/// Result of checking where the recording will be written
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputCheck {
+    pub location: Location,
+    /// Time to write and sync the probe file
+    pub probe_ms: Option<u64>,
+    /// Record to a local staging file and copy to the output path at finalize
+    pub staged: bool,
+    /// Whether the output directory accepted the probe write
+    pub reachable: bool,
+}
+
+/// Classify `path` and probe its directory.
+/// Fails if the path is not local and `require_local` is set.
+pub fn check(path: &Path, require_local: bool) -> Result<OutputCheck> {
+    let location = classify(path);
+    if require_local && location.is_network() {
+        return Err(fail!(
+            Policy,
+            "Output path {} is on network storage ({:?}); refusing because --require-local is set",
+            path.display(),
+            location
+        ));
+    }
+
+    // Network storage that is offline right now is expected (planes, hotel Wi-Fi)
+    let mut reachable = true;
+    let probe = match path.parent().map(probe_write_latency).transpose() {
+        Ok(probe) => probe,
+        Err(_) if location.is_network() && !require_local => {
+            reachable = false;
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let slow = probe.is_some_and(|latency| latency > SLOW_WRITE_THRESHOLD);
+    if require_local && slow {
+        return Err(fail!(
+            Policy,
+            "Output directory for {} took {}ms to accept a {}KB write; refusing because --require-local is set",
+            path.display(),
+            probe.unwrap().as_millis(),
+            PROBE_BYTES / 1024
+        ));
+    }
+
+    Ok(OutputCheck {
+        staged: location.is_network() || slow,
+        reachable,
+        location,
+        probe_ms: probe.map(|latency| latency.as_millis() as u64),
+    })
+}
+
+/// Classify a path by its textual form, plus the drive type for drive-letter paths
+pub fn classify(path: &Path) -> Location {
+    let text = path.to_string_lossy().replace('/', "\\");
+
+    if let Some(share) = unc_share(&text) {
+        return Location::UncShare(share);
+    }
+
+    if let Some(drive) = drive_letter(&text) {
+        if is_remote_drive(drive) {
+            return Location::NetworkDrive(format!("{}:", drive));
+        }
+    }
+
+    let cloud_folder = text
+        .split('\\')
+        .find(|part| *part == "OneDrive" || part.starts_with("OneDrive - "));
+    if let Some(folder) = cloud_folder {
+        return Location::CloudSynced(folder.to_string());
+    }
+
+    Location::Local
+}
+
+/// `\\server\share` for UNC paths, `None` for everything else (including `\\?\C:\` and
+/// device paths like `\\.\`)
+fn unc_share(text: &str) -> Option<String> {
+    let rest = if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+        rest
+    } else if text.starts_with(r"\\?\") || text.starts_with(r"\\.\") {
+        return None;
+    } else {
+        text.strip_prefix(r"\\")?
+    };
+
+    let mut parts = rest.split('\\').filter(|p| !p.is_empty());
+    let server = parts.next()?;
+    let share = parts.next()?;
+    Some(format!(r"\\{}\{}", server, share))
+}
+
+/// Drive letter of `C:\...` or `\\?\C:\...`
+fn drive_letter(text: &str) -> Option<char> {
+    let text = text.strip_prefix(r"\\?\").unwrap_or(text);
+    let mut chars = text.chars();
+    let letter = chars.next()?;
+    (letter.is_ascii_alphabetic() && chars.next() == Some(':')).then(|| letter.to_ascii_uppercase())
+}
+
+#[cfg(windows)]
+fn is_remote_drive(letter: char) -> bool {
+    use windows::core::HSTRING;
+    use windows::Win32::Storage::FileSystem::GetDriveTypeW;
+
+    // DRIVE_REMOTE from winbase.h
+    const DRIVE_REMOTE: u32 = 4;
+    let root = HSTRING::from(format!("{}:\\", letter));
+    unsafe { GetDriveTypeW(&root) == DRIVE_REMOTE }
+}
+
+#[cfg(not(windows))]
+fn is_remote_drive(_letter: char) -> bool {
+    false
+}
+
+/// Time to create, fill and sync a small probe file next to the output
+fn probe_write_latency(dir: &Path) -> Result<Duration> {
+    fs::create_dir_all(dir).context("Failed to create output directory")?;
+    let probe_path = dir.join(format!(".selly-write-probe-{}", std::process::id()));
+
+    let started = Instant::now();
+    let result = (|| -> std::io::Result<()> {
+        let mut file = File::create(&probe_path)?;
+        file.write_all(&vec![0u8; PROBE_BYTES])?;
+        file.sync_all()
+    })();
+    let elapsed = started.elapsed();
+    let _ = fs::remove_file(&probe_path);
+
+    result.context("Output directory is not writable")?;
+    Ok(elapsed)
+}
+
+/// Local file a staged recording is written to before being copied to `out`, in the
+/// session's workspace
+pub fn staging_path(out: &Path, session: &str) -> PathBuf {
+    let file_name = out
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_else(|| OsString::from("recording"));
+    workspace::dir(session).join(file_name)
+}
+
+/// Copy a finished staged recording to its destination and remove the local copy.
+/// On failure the staged file is left in place and its path is part of the error.
+pub fn publish_staged(staged: &Path, out: &Path, progress: Report) -> Result<()> {
+    // Copy under a temporary name first so readers never see a half-copied file
+    let partial = out.with_extension("partial");
+    copy_reporting(staged, &partial, progress)
+        .and_then(|_| fs::rename(&partial, out))
+        .with_context(|| {
+            let _ = fs::remove_file(&partial);
+            format!(
+                "Failed to copy staged recording to {}; local copy kept at {}",
+                out.display(),
+                staged.display()
+            )
+        })?;
+    fs::remove_file(staged).context("Failed to remove staged recording")?;
+    Ok(())
+}
+
+/// `fs::copy`, reporting the bytes copied so far
+fn copy_reporting(from: &Path, to: &Path, progress: Report) -> std::io::Result<u64> {
+    let input = File::open(from)?;
+    let total = input.metadata()?.len();
+    let mut output = File::create(to)?;
+    let copied = std::io::copy(&mut Reading::new(input, total, progress), &mut output)?;
+    output.sync_all()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_unc_paths() {
+        assert_eq!(
+            classify(Path::new(r"\\fileserver\calls\rep1\out.wav")),
+            Location::UncShare(r"\\fileserver\calls".to_string())
+        );
+        assert_eq!(
+            classify(Path::new(r"\\?\UNC\fileserver\calls\out.wav")),
+            Location::UncShare(r"\\fileserver\calls".to_string())
+        );
+    }
+
+    #[test]
+    fn verbatim_and_device_paths_are_not_shares() {
+        assert_eq!(
+            classify(Path::new(r"\\?\C:\calls\out.wav")),
+            Location::Local
+        );
+        assert_eq!(classify(Path::new(r"\\.\pipe\selly")), Location::Local);
+        assert_eq!(drive_letter(r"\\?\d:\calls"), Some('D'));
+    }
+
+    #[test]
+    fn detects_onedrive_folders() {
+        assert_eq!(
+            classify(Path::new(
+                r"C:\Users\rep\OneDrive - ACME Corp\Calls\out.wav"
+            )),
+            Location::CloudSynced("OneDrive - ACME Corp".to_string())
+        );
+        assert_eq!(
+            classify(Path::new(r"C:\Users\rep\OneDriveBackup\out.wav")),
+            Location::Local
+        );
+    }
+
+    #[test]
+    fn staged_file_is_published_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let staged = dir.path().join("staged.wav");
+        let out = dir.path().join("final.wav");
+        fs::write(&staged, b"RIFF").unwrap();
+
+        publish_staged(&staged, &out, &mut crate::progress::ignore).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), b"RIFF");
+        assert!(!staged.exists());
+    }
+
+    #[test]
+    fn local_directory_probe_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = check(&dir.path().join("out.wav"), true).unwrap();
+        assert_eq!(check.location, Location::Local);
+        assert!(check.probe_ms.is_some());
+    }
+}
@@ -0,0 +1,332 @@
+//! Loopback packet conversion
+//! A WASAPI capture packet is raw bytes in the endpoint's mix format: 32-bit float on most
+//! drivers, 16-bit PCM on older ones, `WAVE_FORMAT_EXTENSIBLE` with a sub-format GUID and a
+//! container wider than the valid bits on others, and any number of channels. The format
+//! is read from the `WAVEFORMATEX` bytes once, when the endpoint opens, so a format that
+//! can't be converted is refused there rather than per packet; `to_mono` then converts a
+//! packet given as a byte slice and never reads past it, whatever the driver reports.
+
+use crate::error::fail;
+use anyhow::Result;
+
+pub const WAVE_FORMAT_PCM: u16 = 1;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// `WAVEFORMATEX` up to and including `cbSize`
+pub const WAVEFORMATEX_LEN: usize = 18;
+/// `WAVEFORMATEXTENSIBLE`, whose `cbSize` is at least the 22 bytes past `WAVEFORMATEX`
+const EXTENSIBLE_LEN: usize = WAVEFORMATEX_LEN + 22;
+/// `KSDATAFORMAT_SUBTYPE_*` GUIDs are the format tag followed by these bytes
+const SUBTYPE_TAIL: [u8; 12] = [
+    0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// How a sample is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    /// Signed integers (unsigned at 8 bits), left-justified in the container
+    Int,
+    Float,
+}
+
+/// A mix format the packet loop can convert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_type: SampleType,
+    /// Bits a sample takes up, padding included
+    pub container_bits: u16,
+    /// Bytes from one frame (all channels) to the next
+    pub block_align: u16,
+}
+
+impl PacketFormat {
+    /// Read a `WAVEFORMATEX`, or a `WAVEFORMATEXTENSIBLE`, from its bytes, `cbSize` extra
+    /// bytes included
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < WAVEFORMATEX_LEN {
+            return Err(fail!(
+                Format,
+                "Wave format is {} bytes, shorter than WAVEFORMATEX",
+                bytes.len()
+            ));
+        }
+        let word = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let tag = word(0);
+        let channels = word(2);
+        let sample_rate = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let block_align = word(12);
+        let container_bits = word(14);
+        let extra = word(16) as usize;
+        if bytes.len() < WAVEFORMATEX_LEN + extra {
+            return Err(fail!(
+                Format,
+                "Wave format says {} extra bytes but has {}",
+                extra,
+                bytes.len() - WAVEFORMATEX_LEN
+            ));
+        }
+
+        let (tag, valid_bits) = match tag {
+            WAVE_FORMAT_EXTENSIBLE => {
+                if WAVEFORMATEX_LEN + extra < EXTENSIBLE_LEN {
+                    return Err(fail!(
+                        Format,
+                        "WAVE_FORMAT_EXTENSIBLE with only {} extra bytes",
+                        extra
+                    ));
+                }
+                let guid = &bytes[24..40];
+                if guid[2..4] != [0, 0] || guid[4..] != SUBTYPE_TAIL {
+                    return Err(fail!(Format, "Unsupported sub-format {:02x?}", guid));
+                }
+                let valid_bits = match word(18) {
+                    0 => container_bits,
+                    bits => bits,
+                };
+                (u16::from_le_bytes([guid[0], guid[1]]), valid_bits)
+            }
+            tag => (tag, container_bits),
+        };
+        let sample_type = match (tag, container_bits) {
+            (WAVE_FORMAT_PCM, 8 | 16 | 24 | 32) => SampleType::Int,
+            (WAVE_FORMAT_IEEE_FLOAT, 32 | 64) => SampleType::Float,
+            _ => {
+                return Err(fail!(
+                    Format,
+                    "Unsupported wave format: tag {:#06x}, {} bits",
+                    tag,
+                    container_bits
+                ))
+            }
+        };
+        if channels == 0 {
+            return Err(fail!(Format, "Wave format without channels"));
+        }
+        if valid_bits > container_bits {
+            return Err(fail!(
+                Format,
+                "{} valid bits in a {}-bit sample",
+                valid_bits,
+                container_bits
+            ));
+        }
+        let frame_bytes = channels as usize * (container_bits / 8) as usize;
+        if (block_align as usize) < frame_bytes {
+            return Err(fail!(
+                Format,
+                "Block align {} is less than {} channels of {} bits",
+                block_align,
+                channels,
+                container_bits
+            ));
+        }
+        Ok(Self {
+            channels,
+            sample_rate,
+            sample_type,
+            container_bits,
+            block_align,
+        })
+    }
+
+    /// Frames a packet of `bytes` holds; a trailing partial frame doesn't count
+    pub fn frames_in(&self, bytes: usize) -> usize {
+        bytes / self.block_align as usize
+    }
+}
+
+/// Average every whole frame of `data` to one sample in -1.0..1.0 (floats as they come, a
+/// frame that doesn't add up to a finite number as silence) and append it to `out`.
+/// Returns the frames converted. Doesn't allocate while `out` has room for them.
+pub fn to_mono(format: &PacketFormat, data: &[u8], out: &mut Vec<f32>) -> usize {
+    let frames = format.frames_in(data.len());
+    let data = &data[..frames * format.block_align as usize];
+    match (format.sample_type, format.container_bits) {
+        (SampleType::Int, 8) => mix_down(format, data, out, |b| (b[0] as f32 - 128.0) / 128.0),
+        (SampleType::Int, 16) => mix_down(format, data, out, |b| {
+            i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.0
+        }),
+        (SampleType::Int, 24) => mix_down(format, data, out, |b| {
+            i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0
+        }),
+        (SampleType::Int, _) => mix_down(format, data, out, |b| {
+            i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0
+        }),
+        (SampleType::Float, 64) => mix_down(format, data, out, |b| {
+            f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+        }),
+        (SampleType::Float, _) => mix_down(format, data, out, |b| {
+            f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        }),
+    }
+    frames
+}
+
+fn mix_down(format: &PacketFormat, data: &[u8], out: &mut Vec<f32>, sample: impl Fn(&[u8]) -> f32) {
+    let channels = format.channels as usize;
+    let width = (format.container_bits / 8) as usize;
+    for frame in data.chunks_exact(format.block_align as usize) {
+        let sum: f32 = frame[..channels * width]
+            .chunks_exact(width)
+            .map(&sample)
+            .sum();
+        let mean = sum / channels as f32;
+        out.push(if mean.is_finite() { mean } else { 0.0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// `WAVEFORMATEX` bytes, with `extensible` as (valid bits, sub-format tag)
+    fn wave_format(tag: u16, channels: u16, bits: u16, extensible: Option<(u16, u16)>) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut bytes = Vec::new();
+        bytes.extend(tag.to_le_bytes());
+        bytes.extend(channels.to_le_bytes());
+        bytes.extend(48_000u32.to_le_bytes());
+        bytes.extend((48_000 * block_align as u32).to_le_bytes());
+        bytes.extend(block_align.to_le_bytes());
+        bytes.extend(bits.to_le_bytes());
+        match extensible {
+            Some((valid_bits, sub_format)) => {
+                bytes.extend(22u16.to_le_bytes());
+                bytes.extend(valid_bits.to_le_bytes());
+                bytes.extend(0x3u32.to_le_bytes());
+                bytes.extend((sub_format as u32).to_le_bytes());
+                bytes.extend(SUBTYPE_TAIL);
+            }
+            None => bytes.extend(0u16.to_le_bytes()),
+        }
+        bytes
+    }
+
+    fn any_format() -> impl Strategy<Value = PacketFormat> {
+        let layouts = prop_oneof![
+            Just((SampleType::Int, 8)),
+            Just((SampleType::Int, 16)),
+            Just((SampleType::Int, 24)),
+            Just((SampleType::Int, 32)),
+            Just((SampleType::Float, 32)),
+            Just((SampleType::Float, 64)),
+        ];
+        (layouts, 1u16..=9, 0u16..4).prop_map(|((sample_type, bits), channels, padding)| {
+            PacketFormat {
+                channels,
+                sample_rate: 48_000,
+                sample_type,
+                container_bits: bits,
+                block_align: channels * bits / 8 + padding,
+            }
+        })
+    }
+
+    #[test]
+    fn reads_extensible_formats_by_their_sub_format() {
+        let float = wave_format(WAVE_FORMAT_EXTENSIBLE, 2, 32, Some((32, 3)));
+        let format = PacketFormat::parse(&float).unwrap();
+        assert_eq!(format.sample_type, SampleType::Float);
+        assert_eq!((format.channels, format.block_align), (2, 8));
+
+        // 24 valid bits in a 32-bit container, as many USB interfaces deliver
+        let padded = wave_format(WAVE_FORMAT_EXTENSIBLE, 6, 32, Some((24, 1)));
+        let format = PacketFormat::parse(&padded).unwrap();
+        assert_eq!(
+            (format.sample_type, format.container_bits),
+            (SampleType::Int, 32)
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_cant_convert() {
+        let parse = |bytes: &[u8]| PacketFormat::parse(bytes).is_err();
+        // ADPCM, 12-bit PCM, float16, no channels
+        assert!(parse(&wave_format(2, 2, 4, None)));
+        assert!(parse(&wave_format(WAVE_FORMAT_PCM, 2, 12, None)));
+        assert!(parse(&wave_format(
+            WAVE_FORMAT_EXTENSIBLE,
+            2,
+            16,
+            Some((16, 3))
+        )));
+        assert!(parse(&wave_format(WAVE_FORMAT_PCM, 0, 16, None)));
+        // More valid bits than the container, and a truncated extension
+        assert!(parse(&wave_format(
+            WAVE_FORMAT_EXTENSIBLE,
+            2,
+            16,
+            Some((24, 1))
+        )));
+        let extensible = wave_format(WAVE_FORMAT_EXTENSIBLE, 2, 32, Some((32, 3)));
+        assert!(parse(&extensible[..30]));
+        assert!(parse(&extensible[..10]));
+    }
+
+    #[test]
+    fn averages_channels_of_sixteen_bit_pcm() {
+        let format = PacketFormat::parse(&wave_format(WAVE_FORMAT_PCM, 3, 16, None)).unwrap();
+        let samples: [i16; 6] = [16_384, 0, -16_384, i16::MIN, i16::MIN, i16::MIN];
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut mono = Vec::new();
+        // A trailing partial frame is left out
+        assert_eq!(to_mono(&format, &bytes[..bytes.len() - 1], &mut mono), 1);
+        assert_eq!(to_mono(&format, &bytes, &mut mono), 2);
+        assert_eq!(mono, [0.0, 0.0, -1.0]);
+    }
+
+    proptest! {
+        /// Parsing arbitrary bytes fails cleanly or yields a format `to_mono` can walk
+        #[test]
+        fn parse_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok(format) = PacketFormat::parse(&bytes) {
+                prop_assert!(format.channels > 0);
+                prop_assert!(
+                    format.block_align as usize
+                        >= format.channels as usize * (format.container_bits / 8) as usize
+                );
+            }
+        }
+
+        /// One sample per whole frame, finite, and within full scale for integers
+        #[test]
+        fn converts_every_whole_frame(
+            format in any_format(),
+            data in prop::collection::vec(any::<u8>(), 0..2_048),
+        ) {
+            let mut mono = vec![7.0];
+            let frames = to_mono(&format, &data, &mut mono);
+            prop_assert_eq!(frames, data.len() / format.block_align as usize);
+            prop_assert_eq!(mono.len(), frames + 1);
+            prop_assert!(mono[1..].iter().all(|sample| sample.is_finite()));
+            if format.sample_type == SampleType::Int {
+                prop_assert!(mono[1..].iter().all(|sample| (-1.0..1.0).contains(sample)));
+            }
+        }
+
+        /// The same signal on every channel comes out as that signal
+        #[test]
+        fn identical_channels_pass_through(
+            channels in 1u16..=8,
+            signal in prop::collection::vec(any::<i16>(), 0..256),
+        ) {
+            let bytes = wave_format(WAVE_FORMAT_EXTENSIBLE, channels, 16, Some((16, 1)));
+            let format = PacketFormat::parse(&bytes).unwrap();
+            let data: Vec<u8> = signal
+                .iter()
+                .flat_map(|&s| std::iter::repeat_n(s, channels as usize))
+                .flat_map(i16::to_le_bytes)
+                .collect();
+            let mut mono = Vec::new();
+            to_mono(&format, &data, &mut mono);
+            for (got, &sent) in mono.iter().zip(&signal) {
+                prop_assert!((got - sent as f32 / 32_768.0).abs() < 1e-6);
+            }
+            prop_assert_eq!(mono.len(), signal.len());
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Output path handling
+//! Windows APIs reject paths past `MAX_PATH` (260 UTF-16 units) unless they carry the `\\?\`
+//! extended-length prefix, and a verbatim path is passed to the file system untouched, so it
+//! has to be absolute and already normalized. Paths stay `OsStr` end to end; converting
+//! through `str` would mangle names that aren't valid Unicode on the way to UTF-16.
+//!
+//! Session IDs come from the caller and can hold anything, so they go through
+//! `sanitize_component` before they become part of a file name.
+
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Placeholder in `--out` replaced with the sanitized session ID
+pub const SESSION_PLACEHOLDER: &str = "{session}";
+
+/// Longest file name component generated from a session ID, in characters
+const MAX_COMPONENT_CHARS: usize = 80;
+
+/// Characters Windows refuses in file names
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Device names Windows reserves regardless of extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Longest path (in UTF-16 units) that is safe without the prefix. Directories are limited
+/// to 248 so an 8.3 file name still fits under `MAX_PATH`.
+const MAX_PLAIN_PATH: usize = 247;
+
+/// Make `path` absolute and, on Windows, switch it to the extended-length form when it is
+/// too long for the legacy APIs
+pub fn prepare_output(path: &Path) -> Result<PathBuf> {
+    let absolute = std::path::absolute(path)
+        .with_context(|| format!("Failed to resolve output path {}", path.display()))?;
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+        let wide: Vec<u16> = absolute.as_os_str().encode_wide().collect();
+        if let Some(extended) = extended_length(&wide) {
+            return Ok(PathBuf::from(OsString::from_wide(&extended)));
+        }
+    }
+
+    Ok(absolute)
+}
+
+/// Per-user directory for state the sidecar keeps between runs
+pub fn state_dir() -> PathBuf {
+    std::env::var_os("LOCALAPPDATA")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("Selly")
+        .join("capture")
+}
+
+/// Turn an arbitrary session ID into something usable as a single file name component on
+/// every Windows file system: NFC-normalized, reserved and control characters replaced,
+/// no trailing dots or spaces, no device names, bounded length
+pub fn sanitize_component(raw: &str) -> String {
+    let mut name: String = raw
+        .nfc()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .take(MAX_COMPONENT_CHARS)
+        .collect();
+
+    // Explorer and Win32 silently strip these, which breaks the round trip
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    let name = name.trim_start();
+
+    if name.is_empty() {
+        return "session".to_string();
+    }
+
+    let base = name.split('.').next().unwrap_or(name);
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| base.eq_ignore_ascii_case(reserved))
+    {
+        return format!("_{}", name);
+    }
+
+    name.to_string()
+}
+
+/// Replace `{session}` in any component of `template` with the sanitized session ID.
+/// Components that aren't valid Unicode can't hold the placeholder and are kept as is.
+pub fn expand_template(template: &Path, session: &str) -> PathBuf {
+    let sanitized = sanitize_component(session);
+    template
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => match part.to_str() {
+                Some(text) if text.contains(SESSION_PLACEHOLDER) => {
+                    OsString::from(text.replace(SESSION_PLACEHOLDER, &sanitized))
+                }
+                _ => part.to_os_string(),
+            },
+            other => other.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+/// File number `part` of a rotated recording: `<dir>/<stem>_<part>.<ext>`, zero-padded to
+/// four digits so the parts sort by name in the order they were recorded
+pub fn part_path(out: &Path, part: usize) -> PathBuf {
+    let mut name = out
+        .file_stem()
+        .map(OsString::from)
+        .unwrap_or_else(|| OsString::from("recording"));
+    name.push(format!("_{:04}", part));
+    if let Some(extension) = out.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    out.with_file_name(name)
+}
+
+/// Extended-length form of an absolute, normalized Windows path given as UTF-16, or `None`
+/// when the path is short enough or already verbatim/device
+fn extended_length(wide: &[u16]) -> Option<Vec<u16>> {
+    if wide.len() <= MAX_PLAIN_PATH || starts_with(wide, r"\\?\") || starts_with(wide, r"\\.\") {
+        return None;
+    }
+
+    // Verbatim paths skip separator normalization, so forward slashes must go
+    let wide: Vec<u16> = wide
+        .iter()
+        .map(|&unit| {
+            if unit == '/' as u16 {
+                '\\' as u16
+            } else {
+                unit
+            }
+        })
+        .collect();
+
+    let (prefix, rest) = match wide.strip_prefix(&utf16(r"\\")[..]) {
+        Some(rest) => (r"\\?\UNC\", rest),
+        None => (r"\\?\", &wide[..]),
+    };
+    Some(
+        utf16(prefix)
+            .into_iter()
+            .chain(rest.iter().copied())
+            .collect(),
+    )
+}
+
+fn utf16(text: &str) -> Vec<u16> {
+    text.encode_utf16().collect()
+}
+
+fn starts_with(wide: &[u16], prefix: &str) -> bool {
+    wide.starts_with(&utf16(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extend(path: &str) -> Option<String> {
+        extended_length(&utf16(path)).map(|wide| String::from_utf16(&wide).unwrap())
+    }
+
+    #[test]
+    fn parts_are_numbered_to_sort_by_name() {
+        let out = Path::new("calls").join("call-42.wav");
+        assert_eq!(
+            part_path(&out, 2),
+            Path::new("calls").join("call-42_0002.wav")
+        );
+        assert_eq!(part_path(Path::new("raw"), 12), Path::new("raw_0012"));
+        assert!(part_path(&out, 9) < part_path(&out, 10));
+    }
+
+    #[test]
+    fn short_paths_are_left_alone() {
+        assert_eq!(extend(r"C:\Users\rep\calls\out.wav"), None);
+    }
+
+    #[test]
+    fn long_drive_paths_get_the_prefix() {
+        let long = format!(r"C:\Users\rep\{}\out.wav", "Q".repeat(250));
+        assert_eq!(extend(&long), Some(format!(r"\\?\{}", long)));
+    }
+
+    #[test]
+    fn long_unc_paths_use_the_unc_form() {
+        let folder = "x".repeat(250);
+        assert_eq!(
+            extend(&format!(r"\\server\share\{}/out.wav", folder)),
+            Some(format!(r"\\?\UNC\server\share\{}\out.wav", folder))
+        );
+    }
+
+    #[test]
+    fn length_is_counted_in_utf16_units() {
+        // 'é' is two bytes in UTF-8 but one UTF-16 unit
+        let path = format!(r"C:\{}.wav", "é".repeat(MAX_PLAIN_PATH - 7));
+        assert_eq!(utf16(&path).len(), MAX_PLAIN_PATH);
+        assert_eq!(extend(&path), None);
+    }
+
+    #[test]
+    fn reserved_characters_are_replaced() {
+        assert_eq!(sanitize_component("ACME <Q3/Q4>"), "ACME _Q3_Q4_");
+        assert_eq!(sanitize_component("a\tb\\c:d"), "a_b_c_d");
+    }
+
+    #[test]
+    fn unicode_is_normalized_to_nfc() {
+        // "e" + combining acute accent becomes the precomposed character
+        assert_eq!(sanitize_component("Jose\u{301}"), "Jos\u{e9}");
+    }
+
+    #[test]
+    fn windows_name_rules_are_enforced() {
+        assert_eq!(sanitize_component("call.. "), "call");
+        assert_eq!(sanitize_component("con"), "_con");
+        assert_eq!(sanitize_component("LPT1.notes"), "_LPT1.notes");
+        assert_eq!(sanitize_component("console"), "console");
+        assert_eq!(sanitize_component(" ..."), "session");
+    }
+
+    #[test]
+    fn long_names_are_capped() {
+        let name = sanitize_component(&"é".repeat(500));
+        assert_eq!(name.chars().count(), MAX_COMPONENT_CHARS);
+    }
+
+    #[test]
+    fn template_substitutes_session_in_every_component() {
+        assert_eq!(
+            expand_template(
+                Path::new("calls/{session}/{session}-mic.wav"),
+                "ACME <Q3/Q4>"
+            ),
+            PathBuf::from("calls/ACME _Q3_Q4_/ACME _Q3_Q4_-mic.wav")
+        );
+        assert_eq!(
+            expand_template(Path::new("calls/out.wav"), "x/y"),
+            PathBuf::from("calls/out.wav")
+        );
+    }
+
+    #[test]
+    fn verbatim_paths_are_not_prefixed_twice() {
+        let long = format!(r"\\?\C:\{}\out.wav", "Q".repeat(250));
+        assert_eq!(extend(&long), None);
+    }
+}
@@ -0,0 +1,46 @@
+//! Pipeline tags
+//! `--pipeline-tag` says what kind of call is being recorded, so downstream consumers can
+//! route it to the matching transcription or analysis pipeline without asking the agent.
+//! The tag is announced in the handshake frame and kept with the recording's metadata: the
+//! timeline file, the upload manifest (and so `upload next`), the audit log, the hook
+//! environment and the `stopped` event.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--pipeline-tag`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineTag {
+    /// First call with a prospect
+    Discovery,
+    /// Product demo
+    Demo,
+    /// Renewal or expansion call with a customer
+    Renewal,
+}
+
+impl PipelineTag {
+    /// The tag as announced, the same as on the command line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PipelineTag::Discovery => "discovery",
+            PipelineTag::Demo => "demo",
+            PipelineTag::Renewal => "renewal",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announced_name_matches_the_flag_and_json() {
+        for tag in PipelineTag::value_variants() {
+            let flag = tag.to_possible_value().unwrap();
+            assert_eq!(tag.as_str(), flag.get_name());
+            assert_eq!(serde_json::to_value(tag).unwrap(), tag.as_str());
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! MIC channel polarity correction
+//! Some USB interfaces deliver one channel of a stereo pair inverted. Averaging such a pair
+//! down to the mono MIC channel partially cancels it and the rep sounds hollow, so the MIC
+//! callback feeds each mixed channel through a detector: once a channel is clearly
+//! anti-correlated with the first one it is flipped before averaging, and the correction is
+//! reported to the session so it is logged.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--polarity-correction`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolarityMode {
+    /// Average the channels as delivered
+    Off,
+    /// Flip channels detected as inverted
+    Auto,
+}
+
+/// A channel was found inverted and is flipped from now on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PolarityFlip {
+    /// Device channel index
+    pub channel: usize,
+    /// Normalized correlation with the reference channel over the deciding window
+    pub correlation: f32,
+}
+
+/// Correlation beyond which a window decides a channel's polarity
+const DECISION_CORRELATION: f64 = 0.7;
+
+/// Windows quieter than this (RMS, about -50 dBFS) don't decide anything
+const MIN_RMS: f64 = 0.003;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Pair {
+    cross: f64,
+    energy: f64,
+    decided: bool,
+}
+
+/// Decides, per channel, whether it is inverted relative to the first mixed channel
+pub struct PolarityDetector {
+    /// Device channel index of each mixed channel
+    channels: Vec<usize>,
+    /// +1.0 or -1.0 per mixed channel
+    signs: Vec<f32>,
+    pairs: Vec<Pair>,
+    reference_energy: f64,
+    window_frames: usize,
+    frames: usize,
+    done: bool,
+}
+
+impl PolarityDetector {
+    /// Detector for the mixed device `channels`, deciding over windows of a second
+    pub fn new(channels: &[usize], sample_rate: u32) -> Self {
+        Self {
+            channels: channels.to_vec(),
+            signs: vec![1.0; channels.len()],
+            pairs: vec![Pair::default(); channels.len()],
+            reference_energy: 0.0,
+            window_frames: sample_rate.max(1) as usize,
+            frames: 0,
+            // A single channel has nothing to be inverted against
+            done: channels.len() < 2,
+        }
+    }
+
+    /// Sign to apply to each mixed channel, in the order they were given
+    pub fn signs(&self) -> &[f32] {
+        &self.signs
+    }
+
+    /// Account for one frame of the mixed channels (as delivered, before the signs). Calls
+    /// `on_flip` for each channel the moment it is found inverted.
+    pub fn observe(&mut self, frame: &[f32], mut on_flip: impl FnMut(PolarityFlip)) {
+        if self.done {
+            return;
+        }
+        let reference = frame[0] as f64;
+        self.reference_energy += reference * reference;
+        for (pair, &sample) in self.pairs.iter_mut().zip(frame).skip(1) {
+            pair.cross += reference * sample as f64;
+            pair.energy += sample as f64 * sample as f64;
+        }
+        self.frames += 1;
+        if self.frames < self.window_frames {
+            return;
+        }
+
+        let n = self.frames as f64;
+        let reference_energy = self.reference_energy;
+        for (i, pair) in self.pairs.iter_mut().enumerate().skip(1) {
+            let loud =
+                (reference_energy / n).sqrt() > MIN_RMS && (pair.energy / n).sqrt() > MIN_RMS;
+            if !pair.decided && loud {
+                let correlation = pair.cross / (reference_energy * pair.energy).sqrt();
+                if correlation < -DECISION_CORRELATION {
+                    self.signs[i] = -1.0;
+                    pair.decided = true;
+                    on_flip(PolarityFlip {
+                        channel: self.channels[i],
+                        correlation: correlation as f32,
+                    });
+                } else if correlation > DECISION_CORRELATION {
+                    pair.decided = true;
+                }
+            }
+            pair.cross = 0.0;
+            pair.energy = 0.0;
+        }
+        self.reference_energy = 0.0;
+        self.frames = 0;
+        self.done = self.pairs.iter().skip(1).all(|pair| pair.decided);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(i: usize) -> f32 {
+        (i as f32 * 0.05).sin() * 0.3
+    }
+
+    #[test]
+    fn flips_an_inverted_channel_once() {
+        let mut detector = PolarityDetector::new(&[0, 1], 1_000);
+        let mut flips = Vec::new();
+        for i in 0..5_000 {
+            detector.observe(&[tone(i), -tone(i) * 0.9], |flip| flips.push(flip));
+        }
+        assert_eq!(flips.len(), 1);
+        assert_eq!(flips[0].channel, 1);
+        assert!(flips[0].correlation < -0.99);
+        assert_eq!(detector.signs(), [1.0, -1.0]);
+    }
+
+    #[test]
+    fn in_phase_and_quiet_channels_are_left_alone() {
+        let mut detector = PolarityDetector::new(&[2, 3, 5], 1_000);
+        for i in 0..5_000 {
+            // Channel 5 is inverted but far too quiet to judge
+            detector.observe(&[tone(i), tone(i), -tone(i) * 0.001], |flip| {
+                panic!("{:?}", flip)
+            });
+        }
+        assert_eq!(detector.signs(), [1.0, 1.0, 1.0]);
+    }
+}
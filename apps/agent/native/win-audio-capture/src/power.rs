@@ -0,0 +1,184 @@
+//! Power-aware capture profile
+//! Reads the power source at session start and picks how hard the pipeline works: on
+//! battery (or with `--battery-mode on`) the mixer and loopback poll less often and hand
+//! over larger blocks, optional processing is skipped and the capture threads drop below
+//! normal priority. Windows has no stable Win32 API for thermal pressure, so battery saver,
+//! which the OS engages under power and thermal pressure, stands in for it.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::time::Duration;
+
+/// `--battery-mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryMode {
+    /// Always use the normal profile
+    Off,
+    /// Switch to low power when running on battery or with battery saver on
+    Auto,
+    /// Always use the low power profile
+    On,
+}
+
+/// Power source as reported by the OS; fields are `None` when it doesn't know
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PowerStatus {
+    pub on_battery: Option<bool>,
+    pub battery_percent: Option<u8>,
+    pub battery_saver: bool,
+}
+
+/// How the capture pipeline is tuned for the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PowerProfile {
+    pub low_power: bool,
+    /// Pause between mixer rounds
+    #[serde(serialize_with = "serialize_ms")]
+    pub mixer_tick: Duration,
+    /// WASAPI loopback buffer; the capture thread wakes every half buffer
+    #[serde(serialize_with = "serialize_ms")]
+    pub loopback_buffer: Duration,
+    /// Whether optional processing stages run
+    pub optional_dsp: bool,
+    pub lower_priority: bool,
+}
+
+impl PowerProfile {
+    /// The mixer ticks at the 10ms WASAPI shared-mode period: the devices don't deliver
+    /// more often, so waking faster only cut the audio into more, smaller blocks
+    pub const NORMAL: Self = Self {
+        low_power: false,
+        mixer_tick: Duration::from_millis(10),
+        loopback_buffer: Duration::from_millis(100),
+        optional_dsp: true,
+        lower_priority: false,
+    };
+
+    /// Fewer wakeups at the cost of ~100ms more latency on the live stream
+    pub const LOW_POWER: Self = Self {
+        low_power: true,
+        mixer_tick: Duration::from_millis(20),
+        loopback_buffer: Duration::from_millis(200),
+        optional_dsp: false,
+        lower_priority: true,
+    };
+
+    pub fn resolve(mode: BatteryMode, status: PowerStatus) -> Self {
+        let low_power = match mode {
+            BatteryMode::Off => false,
+            BatteryMode::On => true,
+            BatteryMode::Auto => status.on_battery == Some(true) || status.battery_saver,
+        };
+        if low_power {
+            Self::LOW_POWER
+        } else {
+            Self::NORMAL
+        }
+    }
+}
+
+fn serialize_ms<S: serde::Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_millis() as u64)
+}
+
+#[cfg(windows)]
+pub fn power_status() -> PowerStatus {
+    use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status = SYSTEM_POWER_STATUS::default();
+    if unsafe { GetSystemPowerStatus(&mut status) }.is_err() {
+        return PowerStatus::default();
+    }
+
+    // 255 means unknown for both fields; BatteryFlag 128 means there is no battery
+    let no_battery = status.BatteryFlag == 128;
+    PowerStatus {
+        on_battery: match status.ACLineStatus {
+            0 => Some(true),
+            1 => Some(false),
+            _ => None,
+        },
+        battery_percent: (!no_battery && status.BatteryLifePercent <= 100)
+            .then_some(status.BatteryLifePercent),
+        battery_saver: status.SystemStatusFlag == 1,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn power_status() -> PowerStatus {
+    PowerStatus::default()
+}
+
+/// Drop the calling thread below normal priority
+#[cfg(windows)]
+pub fn lower_current_thread_priority() {
+    use windows::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_BELOW_NORMAL,
+    };
+
+    if let Err(e) = unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) } {
+        eprintln!(
+            "[win-audio-capture] Warning: Failed to lower thread priority: {}",
+            e
+        );
+    }
+}
+
+#[cfg(not(windows))]
+pub fn lower_current_thread_priority() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_follows_the_power_source() {
+        let plugged_in = PowerStatus {
+            on_battery: Some(false),
+            battery_percent: Some(80),
+            battery_saver: false,
+        };
+        let on_battery = PowerStatus {
+            on_battery: Some(true),
+            ..plugged_in
+        };
+        let saver = PowerStatus {
+            battery_saver: true,
+            ..plugged_in
+        };
+
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::Auto, plugged_in),
+            PowerProfile::NORMAL
+        );
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::Auto, on_battery),
+            PowerProfile::LOW_POWER
+        );
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::Auto, saver),
+            PowerProfile::LOW_POWER
+        );
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::Auto, PowerStatus::default()),
+            PowerProfile::NORMAL
+        );
+    }
+
+    #[test]
+    fn explicit_modes_ignore_the_power_source() {
+        let on_battery = PowerStatus {
+            on_battery: Some(true),
+            ..PowerStatus::default()
+        };
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::Off, on_battery),
+            PowerProfile::NORMAL
+        );
+        assert_eq!(
+            PowerProfile::resolve(BatteryMode::On, PowerStatus::default()),
+            PowerProfile::LOW_POWER
+        );
+    }
+}
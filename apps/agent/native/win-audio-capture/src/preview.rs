@@ -0,0 +1,326 @@
+//! Sped-up preview files
+//! With `--preview-speed 1.5` the finished recording is also written as
+//! `<stem>.preview-1.5x.wav`, time-compressed with WSOLA (waveform similarity overlap-add):
+//! windowed grains are taken from the input at `speed` times the output rate, each one
+//! shifted within a small tolerance to where it best continues the previous grain, so
+//! voices keep their pitch. Managers listen to most calls sped up, and stretching hours of
+//! audio in the browser is slow on their machines.
+
+use crate::progress::Report;
+use crate::riff::{self, RiffAudio};
+use crate::sink::{self, OutputFormat, SinkSpec, SinkSummary};
+use crate::timeline;
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Accepted `--preview-speed` range
+pub const MIN_SPEED: f64 = 1.05;
+pub const MAX_SPEED: f64 = 3.0;
+
+/// Grain length
+const WINDOW_MS: u64 = 30;
+/// How far a grain may move from its nominal position to line up with the previous one
+const TOLERANCE_MS: u64 = 10;
+/// The similarity search looks at every `SEARCH_STRIDE`th sample and lag, then refines
+const SEARCH_STRIDE: usize = 4;
+
+/// Path of the preview written for `recording` at `speed`
+pub fn preview_path(recording: &Path, speed: f64) -> PathBuf {
+    timeline::sidecar_path(recording, &format!("preview-{}x.wav", speed))
+}
+
+/// Write the preview of a finished recording next to it
+pub fn write(
+    recording: &Path,
+    format: OutputFormat,
+    speed: f64,
+    progress: Report,
+) -> Result<SinkSummary> {
+    let audio = RiffAudio::open(recording)?;
+    let path = preview_path(recording, speed);
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".partial");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let spec = SinkSpec {
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
+    };
+    let mut out = sink::create(format, &tmp_path, spec)?;
+    let mut stretcher = Wsola::new(audio.channels as usize, audio.sample_rate, speed);
+    let mut stretched = Vec::new();
+    let mut read = 0;
+    let written = riff::read_frames(recording, &audio, 0, audio.frames(), |samples| {
+        stretched.clear();
+        stretcher.push(samples, &mut stretched);
+        out.write_samples(&stretched)?;
+        read += (samples.len() / audio.channels as usize) as u64;
+        progress(read, audio.frames())
+    })
+    .and_then(|_| {
+        stretched.clear();
+        stretcher.finish(&mut stretched);
+        out.write_samples(&stretched)?;
+        out.finalize()
+    });
+    let mut summary = match written {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp_path, &path).context("Failed to move preview into place")?;
+    summary.path = path;
+    Ok(summary)
+}
+
+/// Streaming WSOLA time compressor for interleaved i16 audio
+pub struct Wsola {
+    channels: usize,
+    speed: f64,
+    /// Periodic Hann window; at half-window hops the windows sum to one
+    window: Vec<f32>,
+    hop: usize,
+    tolerance: usize,
+    /// Interleaved input from frame `input_start` on
+    input: Vec<f32>,
+    input_start: u64,
+    input_frames: u64,
+    /// Output grains produced so far
+    grains: u64,
+    /// Input position the previous grain was taken from
+    previous: Option<u64>,
+    /// Second half of the previous windowed grain, still to be overlapped
+    overlap: Vec<f32>,
+}
+
+impl Wsola {
+    pub fn new(channels: usize, sample_rate: u32, speed: f64) -> Self {
+        let rate = sample_rate as u64;
+        let hop = ((rate * WINDOW_MS / 1000 / 2) as usize).max(SEARCH_STRIDE);
+        let len = hop * 2;
+        let window = (0..len)
+            .map(|n| {
+                let phase = 2.0 * std::f64::consts::PI * n as f64 / len as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Self {
+            channels,
+            speed,
+            window,
+            hop,
+            tolerance: ((rate * TOLERANCE_MS / 1000) as usize).max(SEARCH_STRIDE),
+            input: Vec::new(),
+            input_start: 0,
+            input_frames: 0,
+            grains: 0,
+            previous: None,
+            overlap: vec![0.0; hop * channels],
+        }
+    }
+
+    /// Add interleaved input and append whatever output is complete to `out`
+    pub fn push(&mut self, samples: &[i16], out: &mut Vec<i16>) {
+        self.input.extend(samples.iter().map(|&s| s as f32));
+        self.input_frames += (samples.len() / self.channels) as u64;
+        self.run(false, out);
+    }
+
+    /// End of input: produce the remaining grains (reading silence past the end)
+    pub fn finish(&mut self, out: &mut Vec<i16>) {
+        self.run(true, out);
+        out.extend(self.overlap.iter().map(|&s| to_i16(s)));
+        self.overlap.iter_mut().for_each(|s| *s = 0.0);
+    }
+
+    fn nominal(&self, grain: u64) -> u64 {
+        (grain as f64 * self.hop as f64 * self.speed).round() as u64
+    }
+
+    fn run(&mut self, ended: bool, out: &mut Vec<i16>) {
+        let len = self.window.len() as u64;
+        loop {
+            let nominal = self.nominal(self.grains);
+            let needed = match self.previous {
+                Some(previous) => (nominal + self.tolerance as u64).max(previous + self.hop as u64),
+                None => nominal,
+            } + len;
+            if ended {
+                if nominal >= self.input_frames {
+                    break;
+                }
+            } else if needed > self.input_frames {
+                break;
+            }
+
+            let position = match self.previous {
+                Some(previous) => self.best_match(nominal, previous + self.hop as u64),
+                None => nominal,
+            };
+            self.emit_grain(position, out);
+            self.previous = Some(position);
+            self.grains += 1;
+
+            // Nothing before the next grain's search range or continuation is read again
+            let keep_from = self
+                .nominal(self.grains)
+                .saturating_sub(self.tolerance as u64)
+                .min(position + self.hop as u64);
+            if keep_from > self.input_start {
+                let drop =
+                    ((keep_from - self.input_start) as usize * self.channels).min(self.input.len());
+                self.input.drain(..drop);
+                self.input_start += (drop / self.channels) as u64;
+            }
+        }
+    }
+
+    /// Start within the tolerance of `nominal` whose opening best matches the input that
+    /// naturally follows the previous grain
+    fn best_match(&self, nominal: u64, continuation: u64) -> u64 {
+        let low = nominal
+            .saturating_sub(self.tolerance as u64)
+            .max(self.input_start);
+        let high = nominal + self.tolerance as u64;
+        let score = |lag: u64| -> f32 {
+            (0..self.hop)
+                .step_by(SEARCH_STRIDE)
+                .map(|i| self.mono(lag + i as u64) * self.mono(continuation + i as u64))
+                .sum()
+        };
+
+        let mut best = (score(low), low);
+        for lag in (low..=high).step_by(SEARCH_STRIDE).skip(1) {
+            let candidate = score(lag);
+            if candidate > best.0 {
+                best = (candidate, lag);
+            }
+        }
+        let coarse = best.1;
+        let refine_low = coarse.saturating_sub(SEARCH_STRIDE as u64 - 1).max(low);
+        let refine_high = (coarse + SEARCH_STRIDE as u64 - 1).min(high);
+        for lag in refine_low..=refine_high {
+            let candidate = score(lag);
+            if candidate > best.0 {
+                best = (candidate, lag);
+            }
+        }
+        best.1
+    }
+
+    fn emit_grain(&mut self, position: u64, out: &mut Vec<i16>) {
+        let channels = self.channels;
+        for (n, &gain) in self.window.iter().enumerate() {
+            for channel in 0..channels {
+                let sample = self.sample(position + n as u64, channel) * gain;
+                if n < self.hop {
+                    let i = n * channels + channel;
+                    out.push(to_i16(self.overlap[i] + sample));
+                } else {
+                    self.overlap[(n - self.hop) * channels + channel] = sample;
+                }
+            }
+        }
+    }
+
+    fn sample(&self, frame: u64, channel: usize) -> f32 {
+        if frame < self.input_start {
+            return 0.0;
+        }
+        let index = (frame - self.input_start) as usize * self.channels + channel;
+        self.input.get(index).copied().unwrap_or(0.0)
+    }
+
+    fn mono(&self, frame: u64) -> f32 {
+        (0..self.channels).map(|c| self.sample(frame, c)).sum()
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(frames: usize, hz: f64, rate: u32) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * std::f64::consts::PI * hz * i as f64 / rate as f64).sin();
+                let s = (s * 8_000.0) as i16;
+                [s, s / 2]
+            })
+            .collect()
+    }
+
+    fn stretch(input: &[i16], speed: f64) -> Vec<i16> {
+        let mut wsola = Wsola::new(2, 16_000, speed);
+        let mut out = Vec::new();
+        // Odd chunk sizes so grains straddle pushes
+        for chunk in input.chunks(2 * 1_234) {
+            wsola.push(chunk, &mut out);
+        }
+        wsola.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn shortens_by_the_speed_factor_and_keeps_the_pitch() {
+        let input = tone(16_000 * 3, 220.0, 16_000);
+        for speed in [1.5, 2.0] {
+            let out = stretch(&input, speed);
+            let frames = out.len() / 2;
+            let expected = 16_000.0 * 3.0 / speed;
+            assert!(
+                (frames as f64 - expected).abs() < 600.0,
+                "{} {}",
+                speed,
+                frames
+            );
+
+            let left: Vec<i16> = out.iter().step_by(2).copied().collect();
+            let crossings = left
+                .windows(2)
+                .filter(|pair| pair[0] < 0 && pair[1] >= 0)
+                .count();
+            let hz = crossings as f64 * 16_000.0 / frames as f64;
+            assert!((hz - 220.0).abs() < 15.0, "{} {}", speed, hz);
+        }
+    }
+
+    #[test]
+    fn steady_tones_keep_their_level() {
+        let out = stretch(&tone(16_000 * 2, 300.0, 16_000), 1.5);
+        // Skip the fades at either end
+        let middle = &out[2 * 2_000..out.len() - 2 * 2_000];
+        let peak = middle
+            .iter()
+            .step_by(2)
+            .map(|s| s.unsigned_abs())
+            .max()
+            .unwrap();
+        assert!((7_000..=8_400).contains(&peak), "{}", peak);
+    }
+
+    #[test]
+    fn writes_the_preview_next_to_the_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.wav");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 16_000,
+        };
+        let mut rec = sink::create(OutputFormat::Wav, &path, spec).unwrap();
+        rec.write_samples(&tone(16_000, 200.0, 16_000)).unwrap();
+        rec.finalize().unwrap();
+
+        let summary = write(&path, OutputFormat::Wav, 2.0, &mut crate::progress::ignore).unwrap();
+        assert_eq!(summary.path, dir.path().join("call.preview-2x.wav"));
+        assert!((summary.frames as i64 - 8_000).abs() < 600);
+        assert!(hound::WavReader::open(&summary.path).is_ok());
+    }
+}
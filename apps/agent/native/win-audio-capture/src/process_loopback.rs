@@ -0,0 +1,213 @@
+//! Process loopback
+//! The endpoint loopback records the whole system mix, music players and notification
+//! sounds included. With `--loopback-pid <pid>` (or `--loopback-process-name zoom.exe`,
+//! the root of that executable's process tree) only the audio that process and its
+//! children play is recorded, through the process loopback Windows 10 20H1 added. Where
+//! that isn't available the session falls back to the endpoint loopback, with a
+//! `loopback_fallback` event saying why.
+
+use serde::Serialize;
+
+/// A running process, as listed by the system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Process {
+    pub pid: u32,
+    pub parent: u32,
+    /// Executable file name, e.g. `ms-teams.exe`
+    pub name: String,
+}
+
+/// PID of the top process running `name`: one whose parent doesn't run it too, so its
+/// tree holds all the others (Teams and browsers run many processes of one name)
+pub fn tree_root(processes: &[Process], name: &str) -> Option<u32> {
+    let runs = |pid: u32| {
+        processes
+            .iter()
+            .any(|process| process.pid == pid && process.name.eq_ignore_ascii_case(name))
+    };
+    processes
+        .iter()
+        .filter(|process| process.name.eq_ignore_ascii_case(name))
+        .find(|process| process.pid == process.parent || !runs(process.parent))
+        .map(|process| process.pid)
+}
+
+#[cfg(windows)]
+pub use activate::{activate, format, processes};
+
+#[cfg(windows)]
+mod activate {
+    use super::Process;
+    use crate::error::fail;
+    use anyhow::{Context, Result};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use windows::core::{implement, Interface, HRESULT};
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::Media::Audio::{
+        ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
+        IActivateAudioInterfaceCompletionHandler, IActivateAudioInterfaceCompletionHandler_Impl,
+        IAudioClient, AUDIOCLIENT_ACTIVATION_PARAMS, AUDIOCLIENT_ACTIVATION_PARAMS_0,
+        AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK, AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS,
+        PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE, VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+        WAVEFORMATEX,
+    };
+    use windows::Win32::System::Com::{IAgileObject, IAgileObject_Impl};
+    use windows::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+        TH32CS_SNAPPROCESS,
+    };
+
+    /// How long the system gets to hand over the client
+    const ACTIVATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// `VT_BLOB`
+    const VT_BLOB: u16 = 65;
+
+    /// `WAVE_FORMAT_IEEE_FLOAT`
+    const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+    /// `PROPVARIANT` holding a blob, laid out as the system reads it
+    #[repr(C)]
+    struct BlobVariant {
+        vt: u16,
+        reserved: [u16; 3],
+        size: u32,
+        data: *const u8,
+    }
+
+    #[implement(IActivateAudioInterfaceCompletionHandler, IAgileObject)]
+    struct Completion {
+        done: mpsc::Sender<Result<IAudioClient>>,
+    }
+
+    impl IActivateAudioInterfaceCompletionHandler_Impl for Completion_Impl {
+        fn ActivateCompleted(
+            &self,
+            operation: Option<&IActivateAudioInterfaceAsyncOperation>,
+        ) -> windows::core::Result<()> {
+            let result = (|| unsafe {
+                let operation = operation.ok_or_else(|| fail!(Device, "No activation result"))?;
+                let mut hr = HRESULT(0);
+                let mut client = None;
+                operation.GetActivateResult(&mut hr, &mut client)?;
+                hr.ok().context("Process loopback activation failed")?;
+                let client =
+                    client.ok_or_else(|| fail!(Device, "Activation returned no client"))?;
+                Ok(client.cast::<IAudioClient>()?)
+            })();
+            let _ = self.done.send(result);
+            Ok(())
+        }
+    }
+
+    impl IAgileObject_Impl for Completion_Impl {}
+
+    /// An audio client recording what the process tree of `pid` plays
+    ///
+    /// # Safety
+    /// COM must be initialized on the calling thread.
+    pub unsafe fn activate(pid: u32) -> Result<IAudioClient> {
+        let params = AUDIOCLIENT_ACTIVATION_PARAMS {
+            ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+            Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+                ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                    TargetProcessId: pid,
+                    ProcessLoopbackMode: PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+                },
+            },
+        };
+        let variant = BlobVariant {
+            vt: VT_BLOB,
+            reserved: [0; 3],
+            size: std::mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32,
+            data: &params as *const _ as *const u8,
+        };
+        let (done, result) = mpsc::channel();
+        let handler: IActivateAudioInterfaceCompletionHandler = Completion { done }.into();
+        let _operation = ActivateAudioInterfaceAsync(
+            VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+            &IAudioClient::IID,
+            Some(&variant as *const BlobVariant as *const _),
+            &handler,
+        )
+        .context("Process loopback is not available")?;
+        result
+            .recv_timeout(ACTIVATE_TIMEOUT)
+            .map_err(|_| fail!(Device, "Process loopback activation timed out"))?
+    }
+
+    /// Format to ask a process loopback client for; it has no mix format of its own
+    pub fn format(sample_rate: u32) -> WAVEFORMATEX {
+        const CHANNELS: u16 = 2;
+        const BITS: u16 = 32;
+        let block_align = CHANNELS * BITS / 8;
+        WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_IEEE_FLOAT,
+            nChannels: CHANNELS,
+            nSamplesPerSec: sample_rate,
+            nAvgBytesPerSec: sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: BITS,
+            cbSize: 0,
+        }
+    }
+
+    /// Every running process
+    pub fn processes() -> Result<Vec<Process>> {
+        unsafe {
+            let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)
+                .context("Failed to list processes")?;
+            let mut entry = PROCESSENTRY32W {
+                dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+                ..Default::default()
+            };
+            let mut processes = Vec::new();
+            let mut next = Process32FirstW(snapshot, &mut entry);
+            while next.is_ok() {
+                let len = entry
+                    .szExeFile
+                    .iter()
+                    .position(|&c| c == 0)
+                    .unwrap_or(entry.szExeFile.len());
+                processes.push(Process {
+                    pid: entry.th32ProcessID,
+                    parent: entry.th32ParentProcessID,
+                    name: String::from_utf16_lossy(&entry.szExeFile[..len]),
+                });
+                next = Process32NextW(snapshot, &mut entry);
+            }
+            let _ = CloseHandle(snapshot);
+            Ok(processes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, parent: u32, name: &str) -> Process {
+        Process {
+            pid,
+            parent,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn picks_the_root_of_the_process_tree() {
+        let processes = [
+            process(4, 0, "System"),
+            process(900, 600, "explorer.exe"),
+            process(2210, 2200, "ms-teams.exe"),
+            process(2200, 900, "ms-teams.exe"),
+            process(2230, 2200, "msedgewebview2.exe"),
+        ];
+        assert_eq!(tree_root(&processes, "MS-Teams.exe"), Some(2200));
+        assert_eq!(tree_root(&processes, "zoom.exe"), None);
+        // A parent that has exited leaves its children as roots
+        let orphans = [process(10, 7, "zoom.exe"), process(11, 10, "zoom.exe")];
+        assert_eq!(tree_root(&orphans, "zoom.exe"), Some(10));
+    }
+}
@@ -0,0 +1,258 @@
+//! Finalize progress
+//! Once capture stops, the finished recording can take a while to trim, seal, copy to its
+//! destination and cut previews and highlights from, a minute or more for a long call. Each
+//! of those stages emits `finalize_progress` events (`stage`, `percent`, `elapsed_ms`) as it
+//! goes, so the agent can show a progress bar instead of a sidecar that seems hung.
+//!
+//! With `--finalize-timeout N` the optional stages (trim, cues, preview, highlights, split
+//! tracks) are skipped once N seconds have passed since the stop, and one still running is
+//! cut short where it can stop cleanly. The recording itself is always sealed and published; the
+//! `stopped` event lists what was left out (`finalize.skipped`), and `finalize_timeout`
+//! marks the moment the deadline hit.
+
+use crate::events;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Percent steps between two events of one stage
+const PERCENT_STEP: u8 = 5;
+
+/// Called with how far a long operation got and its total; an error aborts the operation
+pub type Report<'a> = &'a mut dyn FnMut(u64, u64) -> Result<()>;
+
+/// `Report` for tests that don't look at progress
+#[cfg(test)]
+pub fn ignore(_done: u64, _total: u64) -> Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Trim,
+    Cues,
+    Seal,
+    Publish,
+    Preview,
+    Highlights,
+    Tracks,
+}
+
+impl Stage {
+    /// Whether the recording is complete without this stage
+    fn optional(self) -> bool {
+        !matches!(self, Stage::Seal | Stage::Publish)
+    }
+}
+
+/// Outcome reported in the `stopped` event
+#[derive(Debug, Clone, Serialize)]
+pub struct FinalizeSummary {
+    pub elapsed_ms: u64,
+    pub timed_out: bool,
+    /// Optional stages skipped or cut short by `--finalize-timeout`
+    pub skipped: Vec<Stage>,
+}
+
+/// Progress of one session's finalize, shared with the workers running its stages
+pub struct Finalize {
+    session: String,
+    timeout: Option<Duration>,
+    started: OnceLock<Instant>,
+    skipped: Mutex<Vec<Stage>>,
+}
+
+impl Finalize {
+    pub fn new(session: &str, timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            session: session.to_string(),
+            timeout,
+            started: OnceLock::new(),
+            skipped: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Start the clock `--finalize-timeout` counts from; later calls keep the first start
+    pub fn begin(&self) {
+        self.started.get_or_init(Instant::now);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.get().map_or(Duration::ZERO, Instant::elapsed)
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.timeout
+            .is_some_and(|timeout| self.elapsed() >= timeout)
+    }
+
+    /// Record `stage` as left out, emitting `finalize_timeout` the first time
+    fn skip(&self, stage: Stage) {
+        let mut skipped = self.skipped.lock().unwrap_or_else(|e| e.into_inner());
+        if skipped.is_empty() {
+            events::emit(
+                "finalize_timeout",
+                json!({
+                    "session": self.session,
+                    "elapsed_ms": self.elapsed().as_millis() as u64,
+                    "stage": stage,
+                }),
+            );
+        }
+        if !skipped.contains(&stage) {
+            skipped.push(stage);
+        }
+    }
+
+    /// Enter an optional `stage`, or `None` if the deadline has passed
+    pub fn stage(self: &Arc<Self>, stage: Stage) -> Option<StageProgress> {
+        if stage.optional() && self.past_deadline() {
+            eprintln!(
+                "[win-audio-capture] Warning: Finalize timeout passed, skipping {:?}",
+                stage
+            );
+            self.skip(stage);
+            return None;
+        }
+        Some(self.enter(stage))
+    }
+
+    /// Enter `stage` whatever the time
+    pub fn enter(self: &Arc<Self>, stage: Stage) -> StageProgress {
+        let mut progress = StageProgress {
+            finalize: self.clone(),
+            stage,
+            last: None,
+        };
+        progress.emit(0);
+        progress
+    }
+
+    pub fn summary(&self) -> FinalizeSummary {
+        let skipped = self.skipped.lock().unwrap_or_else(|e| e.into_inner());
+        FinalizeSummary {
+            elapsed_ms: self.elapsed().as_millis() as u64,
+            timed_out: !skipped.is_empty(),
+            skipped: skipped.clone(),
+        }
+    }
+}
+
+/// The stage in progress; hand `report` to the operation running it
+pub struct StageProgress {
+    finalize: Arc<Finalize>,
+    stage: Stage,
+    last: Option<u8>,
+}
+
+impl StageProgress {
+    fn emit(&mut self, percent: u8) {
+        self.last = Some(percent);
+        events::emit(
+            "finalize_progress",
+            json!({
+                "session": self.finalize.session,
+                "stage": self.stage,
+                "percent": percent,
+                "elapsed_ms": self.finalize.elapsed().as_millis() as u64,
+            }),
+        );
+    }
+
+    /// Emit progress when it moved a step; fails for an optional stage past the deadline
+    pub fn report(&mut self, done: u64, total: u64) -> Result<()> {
+        if self.stage.optional() && self.finalize.past_deadline() {
+            self.finalize.skip(self.stage);
+            return Err(anyhow!("Finalize timeout passed during {:?}", self.stage));
+        }
+        let percent = (done.min(total) * 100).checked_div(total).unwrap_or(100) as u8;
+        let step = percent / PERCENT_STEP * PERCENT_STEP;
+        // 100 is left to `finish`, which knows the stage is really done
+        if step < 100 && self.last.is_none_or(|last| step > last) {
+            self.emit(step);
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) {
+        self.emit(100);
+    }
+}
+
+/// Reports the bytes read through it out of `total`
+pub struct Reading<'a, R> {
+    inner: R,
+    done: u64,
+    total: u64,
+    report: Report<'a>,
+}
+
+impl<'a, R: Read> Reading<'a, R> {
+    pub fn new(inner: R, total: u64, report: Report<'a>) -> Self {
+        Self {
+            inner,
+            done: 0,
+            total,
+            report,
+        }
+    }
+}
+
+impl<R: Read> Read for Reading<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.done += len as u64;
+        (self.report)(self.done, self.total).map_err(io::Error::other)?;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_in_steps_and_reads_through() {
+        let finalize = Finalize::new("s1", None);
+        finalize.begin();
+        let mut progress = finalize.enter(Stage::Publish);
+        for done in [1, 2, 3, 40, 41, 100] {
+            progress.report(done, 100).unwrap();
+        }
+        assert_eq!(progress.last, Some(40));
+        progress.finish();
+
+        let mut seen = Vec::new();
+        let mut report = |done: u64, total: u64| {
+            seen.push((done, total));
+            Ok(())
+        };
+        let mut copied = Vec::new();
+        let mut reading = Reading::new(&[7u8; 10][..], 10, &mut report);
+        io::copy(&mut reading, &mut copied).unwrap();
+        assert_eq!(copied, [7u8; 10]);
+        assert_eq!(seen.first(), Some(&(10, 10)));
+    }
+
+    #[test]
+    fn deadline_skips_optional_stages_only() {
+        let finalize = Finalize::new("s1", Some(Duration::ZERO));
+        finalize.begin();
+        assert!(finalize.stage(Stage::Preview).is_none());
+        let mut seal = finalize.enter(Stage::Seal);
+        assert!(seal.report(50, 100).is_ok());
+
+        let summary = finalize.summary();
+        assert!(summary.timed_out);
+        assert_eq!(summary.skipped, [Stage::Preview]);
+        // Without a timeout nothing is ever skipped
+        let relaxed = Finalize::new("s2", None);
+        relaxed.begin();
+        assert!(relaxed.stage(Stage::Highlights).is_some());
+        assert!(!relaxed.summary().timed_out);
+    }
+}
@@ -0,0 +1,119 @@
+//! Float to 16-bit quantization
+//! Rounds to the nearest step, optionally adding TPDF dither (two uniform values summed,
+//! +-1 LSB peak) so low-level audio decorrelates from the quantization error instead of
+//! turning into distortion. `shaped` additionally feeds the previous error back (first-order
+//! highpass), moving the noise floor up where it is least audible.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--dither`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    /// Round to nearest, no dither
+    Off,
+    /// Triangular PDF dither
+    Tpdf,
+    /// TPDF dither with first-order noise shaping
+    Shaped,
+}
+
+/// Quantizer for an interleaved stream; dither and error state are kept per channel
+pub struct Quantizer {
+    dither: Dither,
+    rng: u32,
+    last_error: Vec<f32>,
+}
+
+impl Quantizer {
+    pub fn new(dither: Dither, channels: usize) -> Self {
+        Self {
+            dither,
+            rng: 0x9e37_79b9,
+            last_error: vec![0.0; channels],
+        }
+    }
+
+    /// Quantize one sample of `channel`, with `sample` in -1.0..=1.0
+    pub fn quantize(&mut self, channel: usize, sample: f32) -> i16 {
+        let scaled = sample.clamp(-1.0, 1.0) * i16::MAX as f32;
+        let target = match self.dither {
+            Dither::Off => return scaled.round() as i16,
+            Dither::Tpdf => scaled,
+            Dither::Shaped => scaled - self.last_error[channel],
+        };
+
+        let noise = self.uniform() - self.uniform();
+        let quantized = (target + noise)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32);
+        if self.dither == Dither::Shaped {
+            self.last_error[channel] = quantized - target;
+        }
+        quantized as i16
+    }
+
+    /// Uniform value in [-0.5, 0.5) LSB (xorshift32)
+    fn uniform(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB: f32 = 1.0 / i16::MAX as f32;
+
+    #[test]
+    fn off_rounds_to_nearest() {
+        let mut quantizer = Quantizer::new(Dither::Off, 1);
+        assert_eq!(quantizer.quantize(0, 0.6 * LSB), 1);
+        assert_eq!(quantizer.quantize(0, -0.6 * LSB), -1);
+        assert_eq!(quantizer.quantize(0, 0.4 * LSB), 0);
+        assert_eq!(quantizer.quantize(0, 2.0), i16::MAX);
+        assert_eq!(quantizer.quantize(0, -2.0), -i16::MAX);
+    }
+
+    #[test]
+    fn tpdf_keeps_sub_lsb_detail_on_average() {
+        // Without dither a 0.3 LSB signal disappears entirely
+        let mut quantizer = Quantizer::new(Dither::Tpdf, 1);
+        let n = 100_000;
+        let outputs: Vec<i16> = (0..n).map(|_| quantizer.quantize(0, 0.3 * LSB)).collect();
+        let mean = outputs.iter().map(|&q| q as f64).sum::<f64>() / n as f64;
+        assert!((mean - 0.3).abs() < 0.02, "mean {}", mean);
+        assert!(outputs.iter().all(|&q| (-1..=2).contains(&q)));
+    }
+
+    #[test]
+    fn shaping_pushes_error_to_high_frequencies() {
+        let lag1_correlation = |dither| {
+            let mut quantizer = Quantizer::new(dither, 1);
+            let errors: Vec<f64> = (0..50_000)
+                .map(|i| {
+                    let x = (i as f32 * 0.001).sin() * 0.01;
+                    (quantizer.quantize(0, x) as f32 - x * i16::MAX as f32) as f64
+                })
+                .collect();
+            let power: f64 = errors.iter().map(|e| e * e).sum();
+            let lag1: f64 = errors.windows(2).map(|w| w[0] * w[1]).sum();
+            lag1 / power
+        };
+
+        // White error has no correlation, a first-order highpass has about -0.5
+        assert!(lag1_correlation(Dither::Tpdf).abs() < 0.05);
+        assert!(lag1_correlation(Dither::Shaped) < -0.3);
+    }
+
+    #[test]
+    fn channels_keep_separate_error_state() {
+        let mut quantizer = Quantizer::new(Dither::Shaped, 2);
+        quantizer.quantize(0, 0.5 * LSB);
+        assert_eq!(quantizer.last_error[1], 0.0);
+    }
+}
@@ -0,0 +1,171 @@
+//! Sample-rate negotiation
+//! `--sample-rate` is the rate the recording is meant to carry, but a source can deliver
+//! less: a Bluetooth headset in its hands-free profile captures and plays at 16 kHz (8 kHz
+//! on older ones). Resampled up to the request it still carries nothing above 8 kHz, in a
+//! file three times the size. Before capture starts the rates the MIC and the loopback
+//! endpoint run at are compared with the request, and `--rate-policy` decides what to do
+//! about a slower one:
+//!
+//! - `resample` (default): record at the request and report the source that is upsampled
+//! - `downgrade`: record at the highest rate every source delivers, snapped to a rate the
+//!   output format takes
+//! - `strict`: refuse to record
+//!
+//! `rate_negotiated` says what was asked for, what is recorded and which source set it;
+//! the recording's header, `started` and the stream all carry the negotiated rate.
+
+use crate::error::fail;
+use crate::resample;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// `--rate-policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatePolicy {
+    /// Record at --sample-rate, upsampling slower sources
+    Resample,
+    /// Record at the rate of the slowest source
+    Downgrade,
+    /// Fail when a source is slower than --sample-rate
+    Strict,
+}
+
+/// Rate a source delivers natively
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SourceRate {
+    /// `mic` or `loopback`
+    pub source: &'static str,
+    pub rate: u32,
+}
+
+/// The rate a session records at, and how it came to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Negotiated {
+    pub requested: u32,
+    pub rate: u32,
+    pub policy: RatePolicy,
+    /// The slowest source, when it is slower than the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limited_by: Option<SourceRate>,
+    pub sources: Vec<SourceRate>,
+}
+
+impl Negotiated {
+    /// A source delivers less than the request, whatever was made of it
+    pub fn is_limited(&self) -> bool {
+        self.limited_by.is_some()
+    }
+}
+
+/// The rate to record `sources` at for a request of `requested`. `format_rates` are the
+/// only rates the output format takes, when it is that picky.
+pub fn negotiate(
+    requested: u32,
+    sources: Vec<SourceRate>,
+    policy: RatePolicy,
+    format_rates: Option<&[u32]>,
+) -> Result<Negotiated> {
+    let limited_by = sources
+        .iter()
+        .filter(|source| source.rate < requested)
+        .min_by_key(|source| source.rate)
+        .copied();
+    let rate = match (limited_by, policy) {
+        (None, _) | (Some(_), RatePolicy::Resample) => requested,
+        (Some(slowest), RatePolicy::Strict) => {
+            return Err(fail!(
+                Device,
+                "The {} delivers {} Hz, less than --sample-rate {}; --rate-policy strict refuses to upsample it",
+                slowest.source,
+                slowest.rate,
+                requested
+            ))
+        }
+        (Some(slowest), RatePolicy::Downgrade) => {
+            let rate = slowest.rate.max(resample::MIN_RATE);
+            match format_rates {
+                // The highest the format takes that the source fills, else its lowest
+                Some(rates) => rates
+                    .iter()
+                    .copied()
+                    .filter(|&allowed| allowed <= rate)
+                    .max()
+                    .or_else(|| rates.iter().copied().min())
+                    .unwrap_or(requested),
+                None => rate,
+            }
+        }
+    };
+    Ok(Negotiated {
+        requested,
+        rate,
+        policy,
+        limited_by,
+        sources,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(mic: u32, loopback: u32) -> Vec<SourceRate> {
+        vec![
+            SourceRate {
+                source: "mic",
+                rate: mic,
+            },
+            SourceRate {
+                source: "loopback",
+                rate: loopback,
+            },
+        ]
+    }
+
+    #[test]
+    fn keeps_the_request_when_every_source_meets_it() {
+        for policy in [
+            RatePolicy::Resample,
+            RatePolicy::Downgrade,
+            RatePolicy::Strict,
+        ] {
+            let negotiated = negotiate(48_000, sources(48_000, 96_000), policy, None).unwrap();
+            assert_eq!(negotiated.rate, 48_000);
+            assert!(!negotiated.is_limited());
+        }
+    }
+
+    #[test]
+    fn a_hands_free_headset_limits_the_session() {
+        let hfp = sources(16_000, 16_000);
+        let resampled = negotiate(48_000, hfp.clone(), RatePolicy::Resample, None).unwrap();
+        assert_eq!(resampled.rate, 48_000);
+        assert_eq!(resampled.limited_by.unwrap().rate, 16_000);
+
+        let downgraded = negotiate(48_000, hfp.clone(), RatePolicy::Downgrade, None).unwrap();
+        assert_eq!(downgraded.rate, 16_000);
+        assert_eq!(downgraded.limited_by.unwrap().source, "mic");
+
+        let err = negotiate(48_000, hfp, RatePolicy::Strict, None).unwrap_err();
+        assert_eq!(crate::error::kind(&err), crate::error::ErrorKind::Device);
+    }
+
+    #[test]
+    fn downgrades_to_a_rate_the_format_takes() {
+        let opus = [8_000, 12_000, 16_000, 24_000, 48_000];
+        let negotiated = negotiate(
+            48_000,
+            sources(48_000, 44_100),
+            RatePolicy::Downgrade,
+            Some(&opus),
+        )
+        .unwrap();
+        assert_eq!(negotiated.rate, 24_000);
+        // Without the format's say it follows the source
+        let negotiated =
+            negotiate(48_000, sources(48_000, 44_100), RatePolicy::Downgrade, None).unwrap();
+        assert_eq!(negotiated.rate, 44_100);
+    }
+}
@@ -0,0 +1,234 @@
+//! Warm restarts
+//! `reconfigure` changes configuration that would otherwise take a new session, e.g.
+//! `{"cmd":"reconfigure","denoise":0.6,"frame_ms":20,"add_sink":{"path":"C:\\rec\\copy.flac","format":"flac"}}`.
+//! Only the stages a change touches are restarted, and capture goes on meanwhile:
+//!
+//! - `denoise`: the mixer picks up new MIC processing between two blocks
+//! - `frame_ms`: the stream sends out its partial frame, the handshake again with the new
+//!   `frame_duration_ms`, then frames of the new size
+//! - `add_sink`: another file receives the recording from the current position on
+//!
+//! Blocks mixed while a stage restarts wait in the queue in front of it, the same one that
+//! absorbs a slow disk, so neither the recording nor the stream has a gap. Answered with a
+//! `reconfigured` event naming the stages that were restarted.
+
+use crate::engine::AudioBlock;
+use crate::error::fail;
+use crate::sink::{self, Encoding, OutputFormat, SinkSpec};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Shortest and longest stream frame `frame_ms` takes
+pub const MIN_FRAME_MS: u32 = 10;
+pub const MAX_FRAME_MS: u32 = 100;
+
+/// Fields of a `reconfigure` control command; unset ones are left as they are
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reconfigure {
+    /// Noise suppression strength, as `dsp` takes it
+    pub denoise: Option<f32>,
+    /// Length of a stream frame
+    pub frame_ms: Option<u32>,
+    pub add_sink: Option<AddSink>,
+}
+
+/// Another file to record into
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddSink {
+    pub path: PathBuf,
+    /// Defaults to `--format`
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+}
+
+/// Part of the pipeline a change restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Dsp,
+    Stream,
+    Sink,
+}
+
+impl Reconfigure {
+    /// Stages to restart, in pipeline order
+    pub fn stages(&self) -> Vec<Stage> {
+        [
+            (self.denoise.is_some(), Stage::Dsp),
+            (self.frame_ms.is_some(), Stage::Stream),
+            (self.add_sink.is_some(), Stage::Sink),
+        ]
+        .into_iter()
+        .filter_map(|(changed, stage)| changed.then_some(stage))
+        .collect()
+    }
+}
+
+/// Stereo pairs per stream frame of `frame_ms` at `sample_rate`
+pub fn samples_per_frame(frame_ms: u32, sample_rate: u32) -> Result<usize> {
+    if !(MIN_FRAME_MS..=MAX_FRAME_MS).contains(&frame_ms) {
+        return Err(fail!(
+            Config,
+            "frame_ms must be between {} and {}",
+            MIN_FRAME_MS,
+            MAX_FRAME_MS
+        ));
+    }
+    let millis = sample_rate as u64 * frame_ms as u64;
+    if !millis.is_multiple_of(1000) {
+        return Err(fail!(
+            Config,
+            "frame_ms {} is not a whole number of samples at {} Hz",
+            frame_ms,
+            sample_rate
+        ));
+    }
+    Ok((millis / 1000) as usize)
+}
+
+/// What added files are created with: the recording's format, layout and encoding
+#[derive(Debug, Clone, Copy)]
+pub struct SinkSetup {
+    pub format: OutputFormat,
+    pub spec: SinkSpec,
+    pub encoding: Encoding,
+    /// Blocks queued ahead of the writer
+    pub queue_depth: usize,
+}
+
+/// A file added while recording. Blocks are queued from the moment it is added, so
+/// nothing is lost while the file is still being created.
+pub struct AddedSink {
+    path: PathBuf,
+    format: OutputFormat,
+    /// Session position of the file's first frame
+    from_frame: u64,
+    blocks: Option<mpsc::Sender<AudioBlock>>,
+    worker: JoinHandle<Result<sink::SinkSummary>>,
+}
+
+impl AddedSink {
+    /// Start recording into `request.path` at session position `from_frame`
+    pub fn open(request: AddSink, setup: &SinkSetup, from_frame: u64) -> Self {
+        let format = request.format.unwrap_or(setup.format);
+        let (tx, mut rx) = mpsc::channel::<AudioBlock>(setup.queue_depth.max(1));
+        let path = request.path.clone();
+        let SinkSetup { spec, encoding, .. } = *setup;
+        let worker = tokio::task::spawn_blocking(move || {
+            let mut sink = sink::create_with(format, &path, spec, encoding)
+                .with_context(|| format!("Failed to create {:?}", path))?;
+            while let Some(block) = rx.blocking_recv() {
+                sink.write_samples(&block)?;
+            }
+            sink.finalize()
+        });
+        Self {
+            path: request.path,
+            format,
+            from_frame,
+            blocks: Some(tx),
+            worker,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the writer still takes blocks
+    pub fn is_open(&self) -> bool {
+        self.blocks.is_some()
+    }
+
+    /// Queue `block`, waiting for room like the recording does. A writer that failed is
+    /// closed; its error comes with `finish`.
+    pub async fn write(&mut self, block: &AudioBlock) {
+        let Some(tx) = &self.blocks else {
+            return;
+        };
+        if tx.send(block.clone()).await.is_err() {
+            eprintln!(
+                "[win-audio-capture] Warning: Added sink {:?} stopped; the recording goes on without it",
+                self.path
+            );
+            self.blocks = None;
+        }
+    }
+
+    /// Finish the file; what it holds, for `stopped`
+    pub async fn finish(&mut self) -> Result<Value> {
+        self.blocks = None;
+        let summary = (&mut self.worker)
+            .await
+            .with_context(|| format!("Writer of {:?} panicked", self.path))??;
+        Ok(json!({
+            "path": summary.path,
+            "format": self.format,
+            "from_frame": self.from_frame,
+            "frames": summary.frames,
+            "bytes": summary.bytes,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{parse, ControlCommand};
+
+    #[test]
+    fn lists_the_stages_a_command_restarts() {
+        let request =
+            parse(r#"{"cmd":"reconfigure","frame_ms":20,"add_sink":{"path":"copy.wav"}}"#).unwrap();
+        let ControlCommand::Reconfigure(change) = request.command else {
+            panic!("not a reconfigure command");
+        };
+        assert_eq!(change.stages(), [Stage::Stream, Stage::Sink]);
+        assert!(Reconfigure::default().stages().is_empty());
+        assert!(parse(r#"{"cmd":"reconfigure","sample_rate":16000}"#).is_err());
+    }
+
+    #[test]
+    fn frames_hold_whole_samples() {
+        assert_eq!(samples_per_frame(20, 48_000).unwrap(), 960);
+        assert_eq!(samples_per_frame(10, 44_100).unwrap(), 441);
+        assert!(samples_per_frame(5, 48_000).is_err());
+        assert!(samples_per_frame(250, 48_000).is_err());
+        assert!(samples_per_frame(15, 22_050).is_err());
+    }
+
+    #[tokio::test]
+    async fn added_sink_gets_blocks_queued_before_it_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("copy.wav");
+        let setup = SinkSetup {
+            format: OutputFormat::Wav,
+            spec: SinkSpec {
+                channels: 2,
+                sample_rate: 8_000,
+            },
+            encoding: Encoding::default(),
+            queue_depth: 4,
+        };
+        let request = AddSink {
+            path: path.clone(),
+            format: None,
+        };
+        let mut added = AddedSink::open(request, &setup, 16_000);
+        for _ in 0..3 {
+            added.write(&vec![1i16; 1_600].into()).await;
+        }
+        assert!(added.is_open());
+        let written = added.finish().await.unwrap();
+        assert_eq!(written["frames"], 2_400);
+        assert_eq!(written["from_frame"], 16_000);
+        assert_eq!(written["format"], "wav");
+        assert!(path.exists());
+    }
+}
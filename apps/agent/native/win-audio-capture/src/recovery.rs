@@ -0,0 +1,304 @@
+//! Crash recovery
+//! A capture that dies (crash, power loss, killed by the OS) leaves a recording whose header
+//! still claims no audio, maybe a staged local copy that never reached its network
+//! destination, and half-written `.partial` files. Its upload manifest still lists the
+//! segment as not finalized, so every capture looks for such segments on launch (as does
+//! `win-audio-capture recover`) and repairs the ones whose session isn't running any more:
+//! the header is rewritten to match the audio on disk, a staged copy is published (or
+//! spooled) to its destination, leftovers are removed and the segment is finalized for the
+//! uploader. Each segment is reported as `session_recovered` or `recovery_failed`.
+//!
+//! A session recorded with `--wrap-key` is only recovered with a wrap key at hand (the
+//! launching capture's, or `recover --wrap-key`), and sealed before it is published.
+
+use crate::audit::AuditLog;
+use crate::envelope;
+use crate::error::fail;
+use crate::events;
+use crate::instance_lock::{self, Scope};
+use crate::output_location;
+use crate::riff;
+use crate::spool;
+use crate::timeline;
+use crate::upload_manifest::{self, Segment, UploadManifest};
+use crate::workspace;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// One recovered segment
+#[derive(Debug, Clone, Serialize)]
+pub struct Recovered {
+    pub session: String,
+    pub path: PathBuf,
+    pub frames: u64,
+    pub bytes: u64,
+    pub header_fixed: bool,
+    pub truncated_bytes: u64,
+    /// Recovered from the local staging copy
+    pub from_staging: bool,
+    /// Queued for delivery because the destination is unreachable
+    pub spooled: bool,
+    pub sealed: bool,
+    /// Leftover temp files that were removed
+    pub removed: Vec<PathBuf>,
+}
+
+/// Recover the unfinalized segments of sessions that aren't running, except
+/// `current_session`. `wrap_key` (DER) seals those of sessions recorded sealed.
+pub fn scan(
+    manifest_dir: &Path,
+    current_session: Option<&str>,
+    wrap_key: Option<&[u8]>,
+) -> Result<Vec<Recovered>> {
+    let mut recovered = Vec::new();
+    for session in upload_manifest::sessions(manifest_dir)? {
+        if current_session == Some(session.as_str())
+            || instance_lock::is_held(Scope::Session, &session)
+        {
+            continue;
+        }
+        let mut manifest = UploadManifest::load(manifest_dir, &session)?;
+        let orphans: Vec<Segment> = manifest
+            .segments
+            .iter()
+            .filter(|segment| !segment.finalized)
+            .filter(|segment| {
+                !instance_lock::is_held(Scope::Output, &instance_lock::output_key(&segment.path))
+            })
+            .cloned()
+            .collect();
+        for segment in orphans {
+            match recover_segment(&manifest, &segment, wrap_key) {
+                Ok(Some(segment)) => {
+                    manifest.finalize_segment(&segment.path, segment.bytes)?;
+                    manifest.save(manifest_dir)?;
+                    log_recovery(&segment);
+                    events::emit("session_recovered", json!(segment));
+                    recovered.push(segment);
+                }
+                Ok(None) => {
+                    // Nothing left to recover; don't look again
+                    manifest.remove_segment(&segment.path);
+                    manifest.save(manifest_dir)?;
+                    failed(
+                        &session,
+                        &segment.path,
+                        "Nothing of the recording is left on disk",
+                    );
+                }
+                Err(e) => failed(&session, &segment.path, &format!("{:#}", e)),
+            }
+        }
+    }
+    Ok(recovered)
+}
+
+fn failed(session: &str, path: &Path, error: &str) {
+    eprintln!(
+        "[win-audio-capture] Warning: Could not recover {:?} of session {}: {}",
+        path, session, error
+    );
+    events::emit(
+        "recovery_failed",
+        json!({ "session": session, "path": path, "error": error }),
+    );
+}
+
+fn log_recovery(segment: &Recovered) {
+    println!(
+        "[win-audio-capture] Recovered {:?} of crashed session {} ({} frames)",
+        segment.path, segment.session, segment.frames
+    );
+    let logged = AuditLog::open(&segment.path)
+        .and_then(|mut audit| audit.append("recovered", "cli", None, Some(json!(segment))));
+    if let Err(e) = logged {
+        eprintln!("[win-audio-capture] Warning: {:#}", e);
+    }
+}
+
+fn recover_segment(
+    manifest: &UploadManifest,
+    segment: &Segment,
+    wrap_key: Option<&[u8]>,
+) -> Result<Option<Recovered>> {
+    let out = &segment.path;
+    let staged = output_location::staging_path(out, &manifest.session);
+    let source = match (staged.exists(), out.exists()) {
+        (true, _) => staged.clone(),
+        (false, true) => out.clone(),
+        (false, false) => return Ok(None),
+    };
+    let removed = remove_leftovers(&source, out);
+
+    let mut recovered = Recovered {
+        session: manifest.session.clone(),
+        path: out.clone(),
+        frames: 0,
+        bytes: 0,
+        header_fixed: false,
+        truncated_bytes: 0,
+        from_staging: source == staged,
+        spooled: false,
+        sealed: false,
+        removed,
+    };
+    if is_sealed(&source)? {
+        // Sealed before the crash; only the publish is left
+        recovered.sealed = true;
+        recovered.bytes = std::fs::metadata(&source)?.len();
+    } else {
+        if plays_unrepaired(&source)? {
+            // FLAC and Ogg decode up to their last whole frame as they are, Matroska up to
+            // its last Cluster
+            recovered.bytes = std::fs::metadata(&source)?.len();
+        } else {
+            let repair = riff::repair(&source)?;
+            recovered.frames = repair.frames;
+            recovered.bytes = repair.bytes;
+            recovered.header_fixed = repair.header_fixed;
+            recovered.truncated_bytes = repair.truncated_bytes;
+        }
+        if manifest.sealed {
+            let key = wrap_key.ok_or_else(|| {
+                fail!(
+                    Config,
+                    "The session was recorded sealed; recovering it needs --wrap-key"
+                )
+            })?;
+            recovered.bytes = seal(&manifest.session, &source, key)?;
+            recovered.sealed = true;
+        }
+    }
+
+    if recovered.from_staging {
+        if let Err(e) = output_location::publish_staged(&source, out, &mut |_, _| Ok(())) {
+            eprintln!(
+                "[win-audio-capture] Warning: {:#}; spooling for later delivery",
+                e
+            );
+            spool::enqueue(&spool::spool_dir(), &manifest.session, &source, out)?;
+            recovered.spooled = true;
+        }
+        workspace::remove_if_empty(&manifest.session);
+    }
+    Ok(Some(recovered))
+}
+
+/// Remove what the crashed finalize left half-written around `source` and `out`
+fn remove_leftovers(source: &Path, out: &Path) -> Vec<PathBuf> {
+    let suffixed = |path: &Path, suffix: &str| {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let candidates = [
+        suffixed(source, ".trim.partial"),
+        suffixed(source, ".sealed.partial"),
+        out.with_extension("partial"),
+        timeline::sidecar_path(out, "timeline.json.tmp"),
+    ];
+    candidates
+        .into_iter()
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .collect()
+}
+
+fn is_sealed(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 8];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(read == magic.len() && &magic == envelope::MAGIC)
+}
+
+/// A FLAC, Opus or Matroska recording, which has no header to repair
+fn plays_unrepaired(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    Ok(read == magic.len()
+        && (&magic == b"fLaC" || &magic == b"OggS" || magic == [0x1A, 0x45, 0xDF, 0xA3]))
+}
+
+#[cfg(windows)]
+fn seal(session: &str, path: &Path, public_key: &[u8]) -> Result<u64> {
+    let envelope = envelope::Envelope::new(session, public_key)?;
+    let sealed = envelope.seal_file(path, &mut |_, _| Ok(()))?;
+    Ok(sealed.bytes)
+}
+
+#[cfg(not(windows))]
+fn seal(_session: &str, _path: &Path, _public_key: &[u8]) -> Result<u64> {
+    Err(fail!(Config, "Sealing is only available on Windows"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{self, OutputFormat, SinkSpec};
+    use std::io::{Seek, SeekFrom, Write};
+
+    fn crashed_recording(path: &Path) {
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 8_000,
+        };
+        let mut out = sink::create(OutputFormat::Wav, path, spec).unwrap();
+        out.write_samples(&[9i16; 1_600]).unwrap();
+        out.finalize().unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&36u32.to_le_bytes()).unwrap();
+    }
+
+    #[test]
+    fn finalizes_orphaned_segments_of_dead_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifests = dir.path().join("uploads");
+        let recording = dir.path().join("crashed.wav");
+        crashed_recording(&recording);
+        std::fs::write(dir.path().join("crashed.wav.trim.partial"), b"half").unwrap();
+        let gone = dir.path().join("gone.wav");
+        for (session, path) in [("recovery-crashed", &recording), ("recovery-gone", &gone)] {
+            let mut manifest = UploadManifest::load(&manifests, session).unwrap();
+            manifest.add_segment(path);
+            manifest.save(&manifests).unwrap();
+        }
+
+        let recovered = scan(&manifests, None, None).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].frames, 800);
+        assert!(recovered[0].header_fixed);
+        assert_eq!(recovered[0].removed.len(), 1);
+        let manifest = UploadManifest::load(&manifests, "recovery-crashed").unwrap();
+        assert!(manifest.segments[0].finalized);
+        assert_eq!(manifest.segments[0].bytes, Some(recovered[0].bytes));
+        // The missing one is dropped so it isn't retried on every launch
+        let manifest = UploadManifest::load(&manifests, "recovery-gone").unwrap();
+        assert!(manifest.segments.is_empty());
+        assert!(scan(&manifests, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn leaves_running_and_sealed_sessions_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifests = dir.path().join("uploads");
+        let recording = dir.path().join("sealed.wav");
+        crashed_recording(&recording);
+        let mut manifest = UploadManifest::load(&manifests, "recovery-sealed").unwrap();
+        manifest.sealed = true;
+        manifest.add_segment(&recording);
+        manifest.save(&manifests).unwrap();
+
+        // The launching capture's own session is skipped
+        assert!(scan(&manifests, Some("recovery-sealed"), None)
+            .unwrap()
+            .is_empty());
+        // Without a wrap key the plaintext stays put, unfinalized
+        assert!(scan(&manifests, None, None).unwrap().is_empty());
+        let manifest = UploadManifest::load(&manifests, "recovery-sealed").unwrap();
+        assert!(!manifest.segments[0].finalized);
+    }
+}
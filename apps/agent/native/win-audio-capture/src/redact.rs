@@ -0,0 +1,198 @@
+//! After-the-fact redaction
+//! `redact <file> --ranges ranges.json` silences intervals of a finished recording (card
+//! numbers read out, a GDPR erasure request) and appends what was done to the `redactions`
+//! log of its timeline file and to its audit log. The ranges file is a JSON array of
+//! `{"from": <pos>, "to": <pos>, "reason": "..."}` where positions take the same forms as
+//! `extract` (times or marker names).
+//!
+//! Samples are zeroed in place rather than through a copy, so no unredacted version of the
+//! audio is left behind on disk; re-running an interrupted redaction is harmless.
+
+use crate::audit::{self, AuditLog};
+use crate::durations;
+use crate::error::fail;
+use crate::events;
+use crate::extract;
+use crate::riff::RiffAudio;
+use crate::timeline;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Zero bytes written per step
+const WRITE_CHUNK_BYTES: usize = 256 * 1024;
+
+/// One entry of the ranges file
+#[derive(Debug, Clone, Deserialize)]
+pub struct RangeRequest {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// One entry of the redaction log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Redaction {
+    pub from: String,
+    pub to: String,
+    /// File frames `[start_frame, end_frame)` that were silenced
+    pub start_frame: u64,
+    pub end_frame: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub redacted_ms: u64,
+}
+
+pub fn load_ranges(path: &Path) -> Result<Vec<RangeRequest>> {
+    let bytes = std::fs::read(path).context("Failed to read ranges file")?;
+    serde_json::from_slice(&bytes).context("Failed to parse ranges file")
+}
+
+/// Silence `ranges` in `input` and log them in its timeline. Every range is resolved before
+/// anything is written, so a typo in the last range doesn't leave the file half redacted.
+pub fn run(input: &Path, ranges: &[RangeRequest]) -> Result<Vec<Redaction>> {
+    let audio = RiffAudio::open(input)?;
+    let markers = extract::markers(input, &audio)?;
+    let total = audio.frames();
+    let redacted_ms = events::unix_millis();
+
+    let mut redactions = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let start = extract::resolve(&range.from, audio.sample_rate, &markers)?;
+        let end = extract::resolve(&range.to, audio.sample_rate, &markers)?.min(total);
+        if start >= end {
+            return Err(fail!(
+                Config,
+                "Range {:?}..{:?} is empty (frame {} to {} of {})",
+                range.from,
+                range.to,
+                start,
+                end,
+                total
+            ));
+        }
+        redactions.push(Redaction {
+            from: range.from.clone(),
+            to: range.to.clone(),
+            start_frame: start,
+            end_frame: end,
+            reason: range.reason.clone(),
+            redacted_ms,
+        });
+    }
+    apply(input, &audio, &redactions, "cli")?;
+    Ok(redactions)
+}
+
+/// Silence the frames of `redactions` in `input` and log them in its timeline and its
+/// audit log, as done by `actor`
+pub fn apply(input: &Path, audio: &RiffAudio, redactions: &[Redaction], actor: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(input)
+        .context("Failed to open recording for redaction")?;
+    let zeros = vec![0u8; WRITE_CHUNK_BYTES];
+    for redaction in redactions {
+        file.seek(SeekFrom::Start(
+            audio.data_offset + redaction.start_frame * audio.block_align(),
+        ))?;
+        let mut remaining = (redaction.end_frame - redaction.start_frame) * audio.block_align();
+        while remaining > 0 {
+            let len = remaining.min(WRITE_CHUNK_BYTES as u64) as usize;
+            file.write_all(&zeros[..len])?;
+            remaining -= len as u64;
+        }
+    }
+    file.sync_all()
+        .context("Failed to flush redacted recording")?;
+
+    let log = redactions
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<Vec<_>, _>>()?;
+    timeline::append_log(input, "redactions", log)?;
+    durations::record_redactions(input)?;
+
+    let mut audit = AuditLog::open(input)?;
+    let user = audit::local_user();
+    for redaction in redactions {
+        audit.append(
+            "redact",
+            actor,
+            Some(redaction.start_frame),
+            Some(json!({ "user": user, "redaction": redaction })),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{self, OutputFormat, SinkSpec};
+
+    fn recording(dir: &Path, format: OutputFormat) -> std::path::PathBuf {
+        let path = dir.join(format!("{:?}.wav", format));
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        let mut out = sink::create(format, &path, spec).unwrap();
+        out.write_samples(&vec![100i16; 6_000]).unwrap();
+        out.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn silences_the_ranges_and_logs_them() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [OutputFormat::Wav, OutputFormat::Rf64] {
+            let path = recording(dir.path(), format);
+            let ranges: Vec<RangeRequest> = serde_json::from_str(
+                r#"[{"from": "0.5", "to": "1", "reason": "pci"}, {"from": "2.9", "to": "9"}]"#,
+            )
+            .unwrap();
+            let redactions = run(&path, &ranges).unwrap();
+            assert_eq!(
+                (redactions[1].start_frame, redactions[1].end_frame),
+                (2_900, 3_000)
+            );
+
+            let audio = RiffAudio::open(&path).unwrap();
+            let mut samples = Vec::new();
+            crate::riff::read_frames(&path, &audio, 0, audio.frames(), |s| {
+                samples.extend_from_slice(s);
+                Ok(())
+            })
+            .unwrap();
+            let silent = |frame: usize| samples[frame * 2] == 0 && samples[frame * 2 + 1] == 0;
+            assert!(!silent(499) && silent(500) && silent(999) && !silent(1_000));
+            assert!(!silent(2_899) && silent(2_999));
+
+            let log: serde_json::Value = serde_json::from_slice(
+                &std::fs::read(timeline::sidecar_path(&path, "timeline.json")).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(log["redactions"][0]["reason"], "pci");
+            assert_eq!(log["redactions"].as_array().unwrap().len(), 2);
+            assert_eq!(audit::verify(&path).unwrap().entries, 2);
+        }
+    }
+
+    #[test]
+    fn nothing_is_written_if_a_range_is_bad() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = recording(dir.path(), OutputFormat::Wav);
+        let before = std::fs::read(&path).unwrap();
+        let ranges: Vec<RangeRequest> =
+            serde_json::from_str(r#"[{"from": "0", "to": "1"}, {"from": "closing", "to": "2"}]"#)
+                .unwrap();
+        assert!(run(&path, &ranges).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert!(!timeline::sidecar_path(&path, "timeline.json").exists());
+    }
+}
@@ -0,0 +1,158 @@
+//! Running-instance registry
+//! Every capture drops a small JSON record (session, pid, output, attach pipes) into a
+//! per-user directory while it runs. A record only counts while its session lock is held,
+//! so entries left behind by a crash are recognised as stale and swept on the next listing.
+
+use crate::instance_lock::{self, Scope};
+use crate::paths;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One running capture instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceEntry {
+    pub session: String,
+    pub pid: u32,
+    pub out: PathBuf,
+    pub started_ms: u64,
+    /// Frames to the attached agent, control commands from it
+    pub data_pipe: String,
+    /// NDJSON event mirror for the attached agent
+    pub events_pipe: String,
+    /// Shared-memory PCM ring for consumers that negotiate `shared_pcm`
+    #[serde(default)]
+    pub pcm_mapping: String,
+    /// Read-only frame stream for observers (served with `--observer-token`)
+    #[serde(default)]
+    pub observe_pipe: String,
+    /// Read-only event mirror for observers
+    #[serde(default)]
+    pub observe_events_pipe: String,
+}
+
+impl InstanceEntry {
+    /// Entry for the current process, with pipe and mapping names derived from the
+    /// session ID
+    pub fn for_session(session: &str, out: &Path, started_ms: u64) -> Self {
+        let fingerprint = instance_lock::fingerprint(session);
+        let base = format!(r"\\.\pipe\Selly.Capture.{}", fingerprint);
+        Self {
+            session: session.to_string(),
+            pid: std::process::id(),
+            out: out.to_path_buf(),
+            started_ms,
+            data_pipe: format!("{}.data", base),
+            events_pipe: format!("{}.events", base),
+            pcm_mapping: format!(r"Local\Selly.Capture.{}.pcm", fingerprint),
+            observe_pipe: format!("{}.observe", base),
+            observe_events_pipe: format!("{}.observe.events", base),
+        }
+    }
+}
+
+/// Removes the record when the capture ends
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Per-user registry directory
+pub fn registry_dir() -> PathBuf {
+    paths::state_dir().join("instances")
+}
+
+/// Record `entry` in the registry for as long as the returned guard lives
+pub fn register(dir: &Path, entry: &InstanceEntry) -> Result<Registration> {
+    fs::create_dir_all(dir).context("Failed to create instance registry directory")?;
+    let path = entry_path(dir, &entry.session);
+    let tmp_path = path.with_extension("json.tmp");
+    let json = serde_json::to_vec_pretty(entry).context("Failed to serialize instance entry")?;
+    fs::write(&tmp_path, json).context("Failed to write instance entry")?;
+    fs::rename(&tmp_path, &path).context("Failed to publish instance entry")?;
+    Ok(Registration { path })
+}
+
+/// Live instances, oldest first. Stale records are deleted along the way.
+pub fn list(dir: &Path) -> Result<Vec<InstanceEntry>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read instance registry"),
+    };
+
+    let mut entries = Vec::new();
+    for item in read_dir {
+        let path = item.context("Failed to read instance registry")?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+
+        let entry = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<InstanceEntry>(&bytes).ok());
+        match entry {
+            Some(entry) if instance_lock::is_held(Scope::Session, &entry.session) => {
+                entries.push(entry)
+            }
+            // Unreadable, or the owner is gone
+            _ => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    entries.sort_by_key(|entry| entry.started_ms);
+    Ok(entries)
+}
+
+/// Live instance recording `session`, if any
+pub fn find(dir: &Path, session: &str) -> Result<Option<InstanceEntry>> {
+    Ok(list(dir)?
+        .into_iter()
+        .find(|entry| entry.session == session))
+}
+
+fn entry_path(dir: &Path, session: &str) -> PathBuf {
+    dir.join(format!("{}.json", instance_lock::fingerprint(session)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_live_instances_and_sweeps_stale_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let _lock = instance_lock::acquire("live", Scope::Session, "live").unwrap();
+        let live = InstanceEntry::for_session("live", Path::new("a.wav"), 2);
+        let stale = InstanceEntry::for_session("registry-stale", Path::new("b.wav"), 1);
+        let _live_registration = register(dir.path(), &live).unwrap();
+        let stale_registration = register(dir.path(), &stale).unwrap();
+        std::mem::forget(stale_registration);
+
+        assert_eq!(list(dir.path()).unwrap(), vec![live.clone()]);
+        assert!(!entry_path(dir.path(), "registry-stale").exists());
+        assert_eq!(find(dir.path(), "live").unwrap(), Some(live));
+    }
+
+    #[test]
+    fn registration_is_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = InstanceEntry::for_session("dropped", Path::new("c.wav"), 0);
+        drop(register(dir.path(), &entry).unwrap());
+        assert!(!entry_path(dir.path(), "dropped").exists());
+    }
+
+    #[test]
+    fn missing_registry_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(list(&dir.path().join("none")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+//! Frame stream replay
+//! `replay-stream <file> --speed 1.0` plays a finished recording back as the live SELL
+//! frame stream on stdout, so the agent and UI can be developed against the real protocol
+//! without audio hardware. Blocks go through the same `stream::stream_frames` task as a
+//! capture, so framing, sequence numbers and sample offsets are exactly what a live session
+//! produces; they are released at the recording's own rate times `--speed` (0 replays as
+//! fast as the reader takes them).
+
+use crate::engine::AudioBlock;
+use crate::error::fail;
+use crate::events;
+use crate::riff::{self, RiffAudio};
+use crate::stream::{self, FrameOutput, Protocol};
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Audio handed to the frame stream per step
+const BLOCK_MS: u64 = 10;
+
+/// Blocks queued ahead of the frame stream
+const BLOCK_QUEUE_DEPTH: usize = 64;
+
+/// Replay `input` to stdout
+pub fn run(input: &Path, speed: f64) -> Result<()> {
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(fail!(Config, "--speed must be a non-negative number"));
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")?;
+    runtime.block_on(replay(input.to_path_buf(), speed, None))
+}
+
+/// Replay `input` to `output`, or stdout when `None`
+pub async fn replay(input: PathBuf, speed: f64, output: Option<FrameOutput>) -> Result<()> {
+    let audio = RiffAudio::open(&input)?;
+    events::emit(
+        "started",
+        json!({
+            "replay": input,
+            "sample_rate": audio.sample_rate,
+            "channels": audio.channels,
+            "frames": audio.frames(),
+            "speed": speed,
+        }),
+    );
+
+    let (block_tx, block_rx) = mpsc::channel::<AudioBlock>(BLOCK_QUEUE_DEPTH);
+    let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+    if let Some(output) = output {
+        // Taken before the first block, so nothing goes to stdout
+        let _ = output_tx.try_send(output);
+    }
+    let handshake = stream::handshake(
+        audio.sample_rate,
+        vec!["replay".to_string()],
+        None,
+        None,
+        None,
+    );
+    // Nothing negotiates on a replay; it always speaks v2
+    let (_notice_tx, notice_rx) = mpsc::unbounded_channel();
+    let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+    let streamer = tokio::spawn(stream::stream_frames(
+        block_rx,
+        output_rx,
+        notice_rx,
+        Protocol::V2,
+        handshake,
+        None,
+        observer_rx,
+    ));
+
+    let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+        let channels = audio.channels as usize;
+        let block_frames = (audio.sample_rate as u64 * BLOCK_MS / 1000).max(1) as usize;
+        let started = Instant::now();
+        let mut sent_frames = 0u64;
+        riff::read_frames(&input, &audio, 0, audio.frames(), |samples| {
+            for block in samples.chunks(block_frames * channels) {
+                // The stream carries the stereo mix; a third channel stays in the file
+                let stereo: AudioBlock = block
+                    .chunks_exact(channels)
+                    .flat_map(|frame| [frame[0], frame[channels.min(2) - 1]])
+                    .collect();
+                if speed > 0.0 {
+                    let due = Duration::from_secs_f64(
+                        sent_frames as f64 / audio.sample_rate as f64 / speed,
+                    );
+                    if let Some(wait) = due.checked_sub(started.elapsed()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+                sent_frames += (block.len() / channels) as u64;
+                block_tx
+                    .blocking_send(stereo)
+                    .map_err(|_| anyhow!("Frame stream stopped"))?;
+            }
+            Ok(())
+        })
+    });
+
+    let read = reader.await.context("Replay reader panicked")?;
+    // The stream flushes its partial last frame once the reader's sender is gone
+    let counters = streamer.await.context("Frame stream task panicked")?;
+    read?;
+
+    events::emit(
+        "stopped",
+        json!({ "replay": true, "sample_position": counters.sample_position() }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameDecoder;
+    use crate::sink::{self, OutputFormat, SinkSpec};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn replays_the_recording_as_live_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.wav");
+        let spec = SinkSpec {
+            channels: 3,
+            sample_rate: 48_000,
+        };
+        let mut rec = sink::create(OutputFormat::Wav, &path, spec).unwrap();
+        let samples: Vec<i16> = (0..10_000)
+            .flat_map(|i| [i as i16, -(i as i16), 7])
+            .collect();
+        rec.write_samples(&samples).unwrap();
+        rec.finalize().unwrap();
+
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        replay(path, 0.0, Some(Box::new(writer))).await.unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let handshake = decoder.next_frame().unwrap().unwrap();
+        let handshake = handshake.handshake().unwrap().unwrap();
+        assert_eq!(handshake.sample_rate, 48_000);
+        assert_eq!(handshake.frame_duration_ms, 100);
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        // Two full 4800-pair frames and the 400-pair remainder
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames
+                .iter()
+                .map(|f| (f.header.sequence, f.header.sample_offset))
+                .collect::<Vec<_>>(),
+            [(0, 0), (1, 4_800), (2, 9_600)]
+        );
+        assert_eq!(frames[2].samples.len(), 800);
+        assert_eq!(&frames[1].samples[..2], &[4_800, -4_800]);
+    }
+}
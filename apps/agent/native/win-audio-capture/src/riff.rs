@@ -0,0 +1,536 @@
+//! Reader for finished recordings
+//! The post-processing commands work on files written by any of the sinks as well as ones
+//! that went through an editor, so this walks the RIFF/RF64 chunk list instead of assuming
+//! the sinks' fixed layout: `fmt ` for the layout, `data` for the samples, and `cue ` points
+//! with their `LIST`/`adtl` `labl` names as markers. `append_cues` adds such markers to a
+//! finished recording, and `repair` fixes up the header of one that was never finalized.
+
+use crate::error::fail;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Samples read per callback
+const READ_CHUNK_SAMPLES: usize = 64 * 1024;
+
+/// Marker stored in the file itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cue {
+    pub name: Option<String>,
+    /// Frame offset into the data chunk
+    pub frame: u64,
+}
+
+/// Layout of a 16-bit PCM recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RiffAudio {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub data_offset: u64,
+    pub data_bytes: u64,
+    pub cues: Vec<Cue>,
+}
+
+impl RiffAudio {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let file_len = file.metadata()?.len();
+        Self::parse(&mut BufReader::new(&mut file), file_len)
+            .with_context(|| format!("{:?} is not a readable recording", path))
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.data_bytes / self.block_align()
+    }
+
+    pub fn block_align(&self) -> u64 {
+        self.channels as u64 * 2
+    }
+
+    fn parse<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<Self> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        let rf64 = match &header[..4] {
+            b"RIFF" => false,
+            b"RF64" => true,
+            _ => return Err(fail!(Format, "Missing RIFF header")),
+        };
+        if &header[8..] != b"WAVE" {
+            return Err(fail!(Format, "Not a WAVE file"));
+        }
+
+        let mut ds64_data_bytes = None;
+        let mut format = None;
+        let mut data = None;
+        let mut cue_frames = Vec::new();
+        let mut labels = HashMap::new();
+        let mut offset = 12u64;
+        while offset + 8 <= file_len {
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let id = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            let body = offset + 8;
+
+            match &id {
+                b"ds64" if rf64 => {
+                    let ds64 = read_body(reader, size.min(28))?;
+                    ds64_data_bytes = Some(le_u64(&ds64, 8)?);
+                }
+                b"fmt " => {
+                    let fmt = read_body(reader, size.min(40))?;
+                    let tag = le_u16(&fmt, 0)?;
+                    let bits = le_u16(&fmt, 14)?;
+                    // WAVE_FORMAT_PCM or WAVE_FORMAT_EXTENSIBLE
+                    if !matches!(tag, 1 | 0xFFFE) || bits != 16 {
+                        return Err(fail!(Format, "Only 16-bit PCM is supported"));
+                    }
+                    format = Some((le_u16(&fmt, 2)?, le_u32(&fmt, 4)?));
+                }
+                b"data" => {
+                    let size = match (size, ds64_data_bytes) {
+                        (0xFFFF_FFFF, Some(bytes)) => bytes,
+                        _ => size,
+                    };
+                    // A recording that was never finalized claims more than it holds
+                    let size = size.min(file_len - body);
+                    data = Some((body, size));
+                    if rf64 && size == file_len - body {
+                        break;
+                    }
+                    offset = body + size + (size & 1);
+                    continue;
+                }
+                b"cue " => {
+                    let cue = read_body(reader, size)?;
+                    let count = le_u32(&cue, 0)? as usize;
+                    for point in cue
+                        .get(4..)
+                        .unwrap_or_default()
+                        .chunks_exact(24)
+                        .take(count)
+                    {
+                        // dwName, ..., dwSampleOffset
+                        cue_frames.push((le_u32(point, 0)?, le_u32(point, 20)? as u64));
+                    }
+                }
+                b"LIST" => {
+                    let list = read_body(reader, size)?;
+                    if list.get(..4) == Some(b"adtl") {
+                        read_labels(&list[4..], &mut labels);
+                    }
+                }
+                _ => {}
+            }
+            offset = body + size + (size & 1);
+        }
+
+        let (channels, sample_rate) = format.ok_or_else(|| fail!(Format, "Missing fmt chunk"))?;
+        let (data_offset, data_bytes) = data.ok_or_else(|| fail!(Format, "Missing data chunk"))?;
+        if channels == 0 {
+            return Err(fail!(Format, "No channels"));
+        }
+        let mut cues: Vec<Cue> = cue_frames
+            .into_iter()
+            .map(|(id, frame)| Cue {
+                name: labels.get(&id).cloned(),
+                frame,
+            })
+            .collect();
+        cues.sort_by_key(|cue| cue.frame);
+        Ok(Self {
+            channels,
+            sample_rate,
+            data_offset,
+            data_bytes,
+            cues,
+        })
+    }
+}
+
+/// Call `f` with the interleaved samples of `frames` frames starting at `start_frame`, in
+/// chunks of whole frames
+pub fn read_frames(
+    path: &Path,
+    audio: &RiffAudio,
+    start_frame: u64,
+    frames: u64,
+    mut f: impl FnMut(&[i16]) -> Result<()>,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path).context("Failed to reopen recording")?);
+    reader.seek(SeekFrom::Start(
+        audio.data_offset + start_frame * audio.block_align(),
+    ))?;
+
+    let channels = audio.channels as usize;
+    let chunk_samples = READ_CHUNK_SAMPLES / channels * channels;
+    let mut remaining = frames as usize * channels;
+    let mut bytes = vec![0u8; chunk_samples * 2];
+    let mut samples = Vec::with_capacity(chunk_samples);
+    while remaining > 0 {
+        let len = remaining.min(chunk_samples);
+        reader
+            .read_exact(&mut bytes[..len * 2])
+            .context("Recording ended early")?;
+        samples.clear();
+        samples.extend(
+            bytes[..len * 2]
+                .chunks_exact(2)
+                .map(|pair| i16::from_le_bytes([pair[0], pair[1]])),
+        );
+        f(&samples)?;
+        remaining -= len;
+    }
+    Ok(())
+}
+
+/// Offset of the 64-bit RIFF size in an RF64 file's ds64 chunk
+const DS64_RIFF_SIZE_OFFSET: u64 = 12 + 8;
+
+/// Append labelled cue points (`cue ` plus `LIST`/`adtl`) to a finished recording and fix up
+/// the RIFF size. Returns the new file size.
+pub fn append_cues(path: &Path, cues: &[(u64, String)]) -> Result<u64> {
+    // Cue points hold 32-bit frame offsets
+    let cues: Vec<(u32, &str)> = cues
+        .iter()
+        .filter_map(|(frame, name)| Some((u32::try_from(*frame).ok()?, name.as_str())))
+        .collect();
+
+    let mut points = (cues.len() as u32).to_le_bytes().to_vec();
+    let mut labels = b"adtl".to_vec();
+    for (i, &(frame, name)) in cues.iter().enumerate() {
+        let id = i as u32 + 1;
+        points.extend(id.to_le_bytes());
+        points.extend(frame.to_le_bytes());
+        points.extend(b"data");
+        points.extend([0u8; 8]);
+        points.extend(frame.to_le_bytes());
+
+        let mut labl = id.to_le_bytes().to_vec();
+        labl.extend(name.as_bytes());
+        labl.push(0);
+        labels.extend(chunk(b"labl", &labl));
+    }
+    let mut appended = chunk(b"cue ", &points);
+    appended.extend(chunk(b"LIST", &labels));
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("Failed to open recording for cue points")?;
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    let mut len = file.seek(SeekFrom::End(0))?;
+    // Chunks start on even offsets
+    if len % 2 == 1 {
+        file.write_all(&[0])?;
+        len += 1;
+    }
+    let new_len = len + appended.len() as u64;
+    let riff_size = new_len - 8;
+    match &header {
+        b"RIFF" => {
+            let riff_size = u32::try_from(riff_size)
+                .map_err(|_| fail!(Format, "Cue points would push the WAV past 4 GiB"))?;
+            file.write_all(&appended)?;
+            file.seek(SeekFrom::Start(4))?;
+            file.write_all(&riff_size.to_le_bytes())?;
+        }
+        b"RF64" => {
+            file.write_all(&appended)?;
+            file.seek(SeekFrom::Start(DS64_RIFF_SIZE_OFFSET))?;
+            file.write_all(&riff_size.to_le_bytes())?;
+        }
+        _ => return Err(fail!(Format, "Missing RIFF header")),
+    }
+    file.sync_all()?;
+    Ok(new_len)
+}
+
+/// What `repair` did to a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Repair {
+    pub frames: u64,
+    pub bytes: u64,
+    /// Whether the header had to be rewritten
+    pub header_fixed: bool,
+    /// Bytes of a partly written last frame that were cut
+    pub truncated_bytes: u64,
+}
+
+/// Make the header of a recording whose writer never finalized it (a crash) describe the
+/// audio on disk: everything after the data chunk's start, cut to whole frames. A header
+/// that already accounts for the whole file is left alone.
+pub fn repair(path: &Path) -> Result<Repair> {
+    let audio = RiffAudio::open(path)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?} for repair", path))?;
+    let file_len = file.metadata()?.len();
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    let rf64 = &header[..4] == b"RF64";
+    let claimed_riff_size = if rf64 {
+        file.seek(SeekFrom::Start(DS64_RIFF_SIZE_OFFSET))?;
+        let mut size = [0u8; 8];
+        file.read_exact(&mut size)?;
+        u64::from_le_bytes(size)
+    } else {
+        le_u32(&header, 4)? as u64
+    };
+    if claimed_riff_size + 8 == file_len {
+        return Ok(Repair {
+            frames: audio.frames(),
+            bytes: file_len,
+            header_fixed: false,
+            truncated_bytes: 0,
+        });
+    }
+
+    // Unfinalized files end with their data chunk
+    let available = file_len - audio.data_offset;
+    let data_bytes = available / audio.block_align() * audio.block_align();
+    let new_len = audio.data_offset + data_bytes;
+    file.set_len(new_len)?;
+    if rf64 {
+        // ds64: RIFF size, data size, sample count
+        file.seek(SeekFrom::Start(DS64_RIFF_SIZE_OFFSET))?;
+        file.write_all(&(new_len - 8).to_le_bytes())?;
+        file.write_all(&data_bytes.to_le_bytes())?;
+        file.write_all(&(data_bytes / audio.block_align()).to_le_bytes())?;
+    } else {
+        let riff_size = u32::try_from(new_len - 8).map_err(|_| {
+            fail!(
+                Format,
+                "{:?} holds more audio than a WAV header can describe",
+                path
+            )
+        })?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&riff_size.to_le_bytes())?;
+        file.seek(SeekFrom::Start(audio.data_offset - 4))?;
+        file.write_all(&(data_bytes as u32).to_le_bytes())?;
+    }
+    file.sync_all()?;
+    Ok(Repair {
+        frames: data_bytes / audio.block_align(),
+        bytes: new_len,
+        header_fixed: true,
+        truncated_bytes: available - data_bytes,
+    })
+}
+
+/// Chunk with its header and pad byte
+fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = id.to_vec();
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+fn read_labels(mut list: &[u8], labels: &mut HashMap<u32, String>) {
+    while list.len() >= 8 {
+        let size = u32::from_le_bytes([list[4], list[5], list[6], list[7]]) as usize;
+        let Some(body) = list.get(8..8 + size) else {
+            break;
+        };
+        if &list[..4] == b"labl" && body.len() >= 4 {
+            let id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let text = &body[4..];
+            let text = &text[..text.iter().position(|&b| b == 0).unwrap_or(text.len())];
+            labels.insert(id, String::from_utf8_lossy(text).into_owned());
+        }
+        list = list.get(8 + size + (size & 1)..).unwrap_or_default();
+    }
+}
+
+fn read_body<R: Read>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    reader.take(size).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Result<u16> {
+    let b = bytes
+        .get(at..at + 2)
+        .ok_or_else(|| fail!(Format, "Truncated chunk"))?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> Result<u32> {
+    let b = bytes
+        .get(at..at + 4)
+        .ok_or_else(|| fail!(Format, "Truncated chunk"))?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le_u64(bytes: &[u8], at: usize) -> Result<u64> {
+    let b = bytes
+        .get(at..at + 8)
+        .ok_or_else(|| fail!(Format, "Truncated chunk"))?;
+    Ok(u64::from_le_bytes(b.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{self, OutputFormat, SinkSpec};
+
+    #[test]
+    fn reads_both_sink_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        let samples: Vec<i16> = (0..2_000).map(|i| i as i16).collect();
+        for format in [OutputFormat::Wav, OutputFormat::Rf64] {
+            let path = dir.path().join(format!("{:?}.wav", format));
+            let mut out = sink::create(format, &path, spec).unwrap();
+            out.write_samples(&samples).unwrap();
+            out.finalize().unwrap();
+
+            let audio = RiffAudio::open(&path).unwrap();
+            assert_eq!((audio.channels, audio.sample_rate), (2, 48_000));
+            assert_eq!(audio.frames(), 1_000, "{:?}", format);
+
+            let mut read = Vec::new();
+            read_frames(&path, &audio, 10, 5, |s| {
+                read.extend_from_slice(s);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(read, (20..30).collect::<Vec<i16>>());
+        }
+    }
+
+    #[test]
+    fn repairs_headers_left_unfinalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 8_000,
+        };
+        for format in [OutputFormat::Wav, OutputFormat::Rf64] {
+            let path = dir.path().join(format!("{:?}.wav", format));
+            let mut out = sink::create(format, &path, spec).unwrap();
+            out.write_samples(&[5i16; 2_000]).unwrap();
+            out.finalize().unwrap();
+            // What a crash leaves: sizes from before the first sample, and half a frame
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            match format {
+                OutputFormat::Wav => {
+                    file.seek(SeekFrom::Start(4)).unwrap();
+                    file.write_all(&36u32.to_le_bytes()).unwrap();
+                    file.seek(SeekFrom::Start(40)).unwrap();
+                    file.write_all(&0u32.to_le_bytes()).unwrap();
+                }
+                OutputFormat::Rf64 => {
+                    file.seek(SeekFrom::Start(DS64_RIFF_SIZE_OFFSET)).unwrap();
+                    file.write_all(&[0u8; 24]).unwrap();
+                }
+                OutputFormat::Flac | OutputFormat::Opus | OutputFormat::Mka => unreachable!(),
+            }
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            drop(file);
+
+            let repair = repair(&path).unwrap();
+            assert!(repair.header_fixed, "{:?}", format);
+            assert_eq!(repair.frames, 1_000);
+            assert_eq!(repair.truncated_bytes, 3);
+            assert_eq!(RiffAudio::open(&path).unwrap().frames(), 1_000);
+            assert!(!super::repair(&path).unwrap().header_fixed);
+        }
+    }
+
+    #[test]
+    fn appended_cues_read_back_from_both_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 8_000,
+        };
+        for format in [OutputFormat::Wav, OutputFormat::Rf64] {
+            let path = dir.path().join(format!("{:?}.wav", format));
+            let mut out = sink::create(format, &path, spec).unwrap();
+            out.write_samples(&[7i16; 2_000]).unwrap();
+            out.finalize().unwrap();
+
+            let cues = [(0, "Chapter 1".to_string()), (600, "Chapter 2".to_string())];
+            let len = append_cues(&path, &cues).unwrap();
+            assert_eq!(len, std::fs::metadata(&path).unwrap().len());
+
+            let audio = RiffAudio::open(&path).unwrap();
+            assert_eq!(audio.frames(), 1_000, "{:?}", format);
+            assert_eq!(audio.cues[1].name.as_deref(), Some("Chapter 2"));
+            assert_eq!(audio.cues[1].frame, 600);
+            if format == OutputFormat::Wav {
+                let reader = hound::WavReader::open(&path).unwrap();
+                assert_eq!(reader.duration(), 1_000);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_labelled_cue_points() {
+        let mut fmt = Vec::new();
+        for v in [1u16, 1] {
+            fmt.extend(v.to_le_bytes());
+        }
+        fmt.extend(8_000u32.to_le_bytes());
+        fmt.extend(16_000u32.to_le_bytes());
+        fmt.extend(2u16.to_le_bytes());
+        fmt.extend(16u16.to_le_bytes());
+
+        let mut cue = 2u32.to_le_bytes().to_vec();
+        for (id, frame) in [(7u32, 300u32), (3, 100)] {
+            cue.extend(id.to_le_bytes());
+            cue.extend(frame.to_le_bytes());
+            cue.extend(b"data");
+            cue.extend([0u8; 8]);
+            cue.extend(frame.to_le_bytes());
+        }
+        let mut labl = 7u32.to_le_bytes().to_vec();
+        labl.extend(b"objection\0");
+        let mut adtl = b"adtl".to_vec();
+        adtl.extend(chunk(b"labl", &labl));
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"data", &[0u8; 1_000]));
+        body.extend(chunk(b"cue ", &cue));
+        body.extend(chunk(b"LIST", &adtl));
+        let file = chunk(b"RIFF", &body);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("edited.wav");
+        std::fs::write(&path, file).unwrap();
+        let audio = RiffAudio::open(&path).unwrap();
+        assert_eq!(audio.frames(), 500);
+        assert_eq!(
+            audio.cues,
+            [
+                Cue {
+                    name: None,
+                    frame: 100
+                },
+                Cue {
+                    name: Some("objection".to_string()),
+                    frame: 300
+                },
+            ]
+        );
+    }
+}
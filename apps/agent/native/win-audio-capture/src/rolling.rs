@@ -0,0 +1,157 @@
+//! Rolling buffer ("record the last N minutes")
+//! With `--rolling-minutes N` the recording writer holds the mixed blocks in memory, keeping
+//! only the last N minutes, until a `{"cmd":"commit"}` arrives: the held audio then goes
+//! into the recording and capture carries on into it as usual. What rolled out before the
+//! commit is an elision at the start of the file, so timeline positions, cues and
+//! stitching stay on the session clock. A session stopped without a commit keeps no
+//! recording. Nothing is written to disk before the commit. Under `--max-buffer-mb` the
+//! buffer holds at most its share of the budget, which can be less than N minutes.
+
+use crate::engine::AudioBlock;
+use crate::silence::Elision;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Longest buffer accepted; 15 minutes of stereo 48 kHz is about 170 MB
+pub const MAX_MINUTES: u32 = 15;
+
+/// What a commit put into the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Committed {
+    pub kept_frames: u64,
+    /// Frames that had rolled out of the buffer by then
+    pub dropped_frames: u64,
+}
+
+pub struct RollingBuffer {
+    channels: usize,
+    /// Samples held at most
+    capacity: usize,
+    blocks: VecDeque<AudioBlock>,
+    samples: usize,
+    dropped_frames: u64,
+    /// Whether the memory budget made the capacity less than the minutes asked for
+    capped: bool,
+}
+
+impl RollingBuffer {
+    /// Buffer for the last `minutes`, holding no more than `max_bytes` of samples
+    pub fn new(channels: usize, sample_rate: u32, minutes: u32, max_bytes: Option<usize>) -> Self {
+        let wanted = sample_rate as usize * 60 * minutes as usize * channels;
+        // Whole frames, so the audio that rolls out never splits one
+        let allowed = max_bytes.map_or(wanted, |bytes| {
+            bytes / std::mem::size_of::<i16>() / channels * channels
+        });
+        Self {
+            channels,
+            capacity: wanted.min(allowed),
+            blocks: VecDeque::new(),
+            samples: 0,
+            dropped_frames: 0,
+            capped: allowed < wanted,
+        }
+    }
+
+    pub fn capped(&self) -> bool {
+        self.capped
+    }
+
+    /// Seconds of audio the buffer holds when full
+    pub fn held_secs(&self, sample_rate: u32) -> u64 {
+        (self.capacity / self.channels / sample_rate.max(1) as usize) as u64
+    }
+
+    /// Hold `block`, letting the oldest audio roll out past the capacity; returns whether
+    /// any did
+    pub fn push(&mut self, block: AudioBlock) -> bool {
+        let dropped_before = self.dropped_frames;
+        self.samples += block.len();
+        self.blocks.push_back(block);
+        while self.samples > self.capacity {
+            let excess = self.samples - self.capacity;
+            let Some(front) = self.blocks.front_mut() else {
+                break;
+            };
+            let cut = excess.min(front.len());
+            if cut == front.len() {
+                self.blocks.pop_front();
+            } else {
+                *front = AudioBlock::from(&front[cut..]);
+            }
+            self.samples -= cut;
+            self.dropped_frames += (cut / self.channels) as u64;
+        }
+        self.dropped_frames > dropped_before
+    }
+
+    pub fn committed(&self) -> Committed {
+        Committed {
+            kept_frames: (self.samples / self.channels) as u64,
+            dropped_frames: self.dropped_frames,
+        }
+    }
+
+    /// What rolled out, as an elision at the start of the file
+    pub fn elision(&self) -> Option<Elision> {
+        (self.dropped_frames > 0).then_some(Elision {
+            sample_position: 0,
+            file_frame: 0,
+            frames: self.dropped_frames,
+        })
+    }
+
+    /// The held blocks, oldest first
+    pub fn into_blocks(self) -> VecDeque<AudioBlock> {
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_exactly_the_last_minutes() {
+        // 10 Hz so a minute is 600 frames
+        let mut buffer = RollingBuffer::new(2, 10, 1, None);
+        let mut next = 0i16;
+        for _ in 0..35 {
+            let block: Vec<i16> = (0..50)
+                .flat_map(|_| {
+                    next += 1;
+                    [next, -next]
+                })
+                .collect();
+            buffer.push(block.into());
+        }
+        assert_eq!(
+            buffer.committed(),
+            Committed {
+                kept_frames: 600,
+                dropped_frames: 1_150
+            }
+        );
+        assert_eq!(buffer.elision().unwrap().frames, 1_150);
+        let held: Vec<i16> = buffer
+            .into_blocks()
+            .iter()
+            .flat_map(|b| b.to_vec())
+            .collect();
+        assert_eq!(held.len(), 1_200);
+        // Starts on a whole frame, right after the last one that rolled out
+        assert_eq!(&held[..2], &[1_151, -1_151]);
+        assert_eq!(&held[held.len() - 2..], &[1_750, -1_750]);
+    }
+
+    #[test]
+    fn holds_less_when_the_budget_is_smaller() {
+        // 400 bytes is 100 stereo frames, a sixth of the minute asked for
+        let mut buffer = RollingBuffer::new(2, 10, 1, Some(400));
+        assert!(buffer.capped());
+        assert_eq!(buffer.held_secs(10), 10);
+        assert!(!buffer.push(vec![1; 200].into()));
+        assert!(buffer.push(vec![2; 2].into()));
+        assert_eq!(buffer.committed().kept_frames, 100);
+        assert!(!RollingBuffer::new(2, 10, 1, Some(1 << 20)).capped());
+    }
+}
@@ -0,0 +1,304 @@
+//! Real-time thread discipline
+//! Device callbacks (cpal's MIC callback, the WASAPI packet loop) must not allocate, lock,
+//! log or do IO: any of those can stall for longer than the device buffer. Their hot paths
+//! run inside `enter()` sections and only touch wait-free rings; log lines from those
+//! threads are formatted into fixed buffers and pushed through `LogTx` to a printer thread.
+//!
+//! With `--rt-checks` every allocation inside a section is counted by the global
+//! allocator, and the helpers that would block (`events::emit`, `etw::write`, the trace
+//! recorder's lock) count themselves too; debug builds also assert at those call sites so
+//! the backtrace shows the offender. The totals go out in the `stopped` event.
+
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Longest log line kept from a real-time thread; longer ones are cut
+pub const LINE_BYTES: usize = 240;
+
+/// Lines queued per thread before new ones are dropped
+const LOG_QUEUE_LINES: usize = 64;
+
+/// How often the printer thread empties the queues
+const PRINT_INTERVAL: Duration = Duration::from_millis(50);
+
+static CHECKS: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// What a thread did inside a section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    Alloc,
+    Lock,
+    Log,
+    Io,
+}
+
+static COUNTS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Violations counted since checks were enabled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Violations {
+    pub alloc: u64,
+    pub lock: u64,
+    pub log: u64,
+    pub io: u64,
+}
+
+impl Violations {
+    pub fn total(&self) -> u64 {
+        self.alloc + self.lock + self.log + self.io
+    }
+}
+
+/// Start counting violations
+pub fn enable_checks() {
+    CHECKS.store(true, Ordering::Release);
+}
+
+pub fn checks_enabled() -> bool {
+    CHECKS.load(Ordering::Relaxed)
+}
+
+pub fn violations() -> Violations {
+    let count = |v: Violation| COUNTS[v as usize].load(Ordering::Relaxed);
+    Violations {
+        alloc: count(Violation::Alloc),
+        lock: count(Violation::Lock),
+        log: count(Violation::Log),
+        io: count(Violation::Io),
+    }
+}
+
+/// Marks the current thread real-time until dropped; sections nest
+pub struct Section(());
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+    }
+}
+
+pub fn enter() -> Section {
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    Section(())
+}
+
+/// Whether the current thread is inside a section (false while the thread is exiting)
+pub fn in_section() -> bool {
+    DEPTH.try_with(|depth| depth.get() > 0).unwrap_or(false)
+}
+
+/// Count `violation` if the current thread shouldn't be doing it
+pub fn check(violation: Violation) {
+    if checks_enabled() && in_section() {
+        COUNTS[violation as usize].fetch_add(1, Ordering::Relaxed);
+        // Debug builds stop right at the offending call
+        if cfg!(debug_assertions) {
+            panic!("{:?} on a real-time thread", violation);
+        }
+    }
+}
+
+/// System allocator that counts allocations made inside sections while checks are on
+pub struct CheckedAlloc;
+
+unsafe impl GlobalAlloc for CheckedAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_alloc();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_alloc();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_alloc();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Only atomics and a const thread-local here: anything else could allocate again
+fn count_alloc() {
+    if checks_enabled() && in_section() {
+        COUNTS[Violation::Alloc as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A log line formatted without allocating
+#[derive(Clone, Copy)]
+pub struct LogLine {
+    stderr: bool,
+    len: usize,
+    bytes: [u8; LINE_BYTES],
+}
+
+impl LogLine {
+    pub fn format(stderr: bool, args: fmt::Arguments) -> Self {
+        let mut line = Self {
+            stderr,
+            len: 0,
+            bytes: [0; LINE_BYTES],
+        };
+        let _ = line.write_fmt(args);
+        line
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are copied in
+        std::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LogLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(LINE_BYTES - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Log queue of one real-time thread; create it before the thread starts its hot path
+pub struct LogTx {
+    lines: Producer<LogLine>,
+    dropped: u64,
+}
+
+impl LogTx {
+    /// Queue a stdout line; dropped if the printer is behind
+    pub fn println(&mut self, args: fmt::Arguments) {
+        self.push(LogLine::format(false, args));
+    }
+
+    /// Queue a stderr line
+    pub fn eprintln(&mut self, args: fmt::Arguments) {
+        self.push(LogLine::format(true, args));
+    }
+
+    fn push(&mut self, line: LogLine) {
+        if self.lines.push(line).is_err() {
+            self.dropped += 1;
+        }
+    }
+}
+
+impl Drop for LogTx {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            eprintln!(
+                "[win-audio-capture] Warning: {} real-time log lines dropped",
+                self.dropped
+            );
+        }
+    }
+}
+
+static QUEUES: OnceLock<Mutex<Vec<Consumer<LogLine>>>> = OnceLock::new();
+
+/// New log queue, printed by the shared printer thread (started on first use)
+pub fn log_queue() -> LogTx {
+    let (lines, consumer) = RingBuffer::new(LOG_QUEUE_LINES);
+    let queues = QUEUES.get_or_init(|| {
+        let spawned = std::thread::Builder::new()
+            .name("rt-log".to_string())
+            .spawn(|| loop {
+                std::thread::sleep(PRINT_INTERVAL);
+                print_queued();
+            });
+        if let Err(e) = spawned {
+            eprintln!(
+                "[win-audio-capture] Warning: Failed to start the log printer: {}",
+                e
+            );
+        }
+        Mutex::new(Vec::new())
+    });
+    queues
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(consumer);
+    LogTx { lines, dropped: 0 }
+}
+
+/// Print everything queued so far, forgetting queues whose thread is gone
+pub fn print_queued() {
+    let Some(queues) = QUEUES.get() else { return };
+    let mut queues = queues.lock().unwrap_or_else(|e| e.into_inner());
+    queues.retain_mut(|queue| {
+        while let Ok(line) = queue.pop() {
+            if line.stderr {
+                eprintln!("{}", line.as_str());
+            } else {
+                println!("{}", line.as_str());
+            }
+        }
+        !queue.is_abandoned()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_allocations_inside_sections_only() {
+        // Counters are process-wide, so only increases are compared
+        enable_checks();
+        let before = violations().alloc;
+        drop(vec![0u8; 64]);
+        let outside = violations().alloc;
+
+        let section = enter();
+        let nested = enter();
+        drop(nested);
+        drop(std::hint::black_box(vec![0u8; 64]));
+        drop(section);
+        assert!(outside >= before);
+        assert!(violations().alloc > outside);
+        assert!(!in_section());
+    }
+
+    #[test]
+    fn log_lines_format_without_allocating_and_cut_at_a_character() {
+        let line = LogLine::format(false, format_args!("[WASAPI] {} Hz, {:?}", 48_000, "x"));
+        assert_eq!(line.as_str(), "[WASAPI] 48000 Hz, \"x\"");
+
+        let long = "é".repeat(LINE_BYTES);
+        let cut = LogLine::format(true, format_args!("{}", long));
+        assert_eq!(cut.as_str().len(), LINE_BYTES);
+        assert!(cut.as_str().chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn queued_lines_are_printed_and_finished_queues_forgotten() {
+        let mut tx = log_queue();
+        tx.println(format_args!("rt test line"));
+        drop(tx);
+        print_queued();
+        let queues = QUEUES.get().unwrap().lock().unwrap();
+        assert!(queues.iter().all(|queue| !queue.is_abandoned()));
+    }
+}
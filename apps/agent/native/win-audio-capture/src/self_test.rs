@@ -0,0 +1,251 @@
+//! Self-test
+//! `self-test` runs the capture pipeline without devices: generated MIC and loopback signals
+//! through the mixer, resampler and limiter into a WAV that is read back, and the frame
+//! protocol vectors through the decoder. On Windows it also enumerates the endpoints. It
+//! reports the architecture the binary was built for next to the machine's own, so an x64
+//! build running under emulation on a Windows-on-ARM device shows up as such.
+//!
+//! The DSP is plain scalar code with no architecture-specific intrinsics, so the ARM64
+//! build (`--target aarch64-pc-windows-msvc`) runs the same code path as the x64 one.
+
+use crate::engine;
+use crate::limiter::{Limiter, LimiterMode};
+use crate::mixer::{Clock, Mixer};
+use crate::quantize::{Dither, Quantizer};
+use crate::resample::{ResampleQuality, Resampler};
+use crate::sink::{self, OutputFormat, SinkSpec};
+use crate::synth::{Generator, Signal};
+use crate::vectors;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::cell::Cell;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 48_000;
+/// Mixer rounds of 10 ms, one second in all
+const ROUNDS: u32 = 100;
+
+/// Outcome of one check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What was measured, or why the check failed
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Architecture the binary was built for
+    pub arch: &'static str,
+    /// The machine's own architecture, when the system reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_arch: Option<&'static str>,
+    pub emulated: bool,
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Run every check, writing the scratch recording into `dir`
+pub fn run(dir: &Path) -> Report {
+    let mut checks = Vec::new();
+    let mut check = |name, outcome: Result<String>| {
+        let (ok, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, format!("{:#}", e)),
+        };
+        checks.push(Check { name, ok, detail });
+    };
+    match mix() {
+        Ok(frames) => {
+            check("mixer", Ok(format!("{} frames", frames.len())));
+            check("sink", record(&frames, dir));
+        }
+        Err(e) => check("mixer", Err(e)),
+    }
+    check("resample", resample());
+    check("limiter", limit());
+    check("frames", decode_vectors());
+    #[cfg(windows)]
+    check(
+        "devices",
+        crate::devices::list().map(|endpoints| format!("{} endpoints", endpoints.len())),
+    );
+
+    let arch = std::env::consts::ARCH;
+    let os_arch = os_arch();
+    Report {
+        arch,
+        os_arch,
+        emulated: os_arch.is_some_and(|os_arch| os_arch != arch),
+        passed: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+/// Clock the test moves forward by hand
+struct SteppedClock(Rc<Cell<Duration>>);
+
+impl Clock for SteppedClock {
+    fn elapsed(&self) -> Duration {
+        self.0.get()
+    }
+}
+
+/// A second of a sine on the MIC and noise on the loopback, fed the way devices would
+fn mix() -> Result<Vec<[f32; 2]>> {
+    let now = Rc::new(Cell::new(Duration::ZERO));
+    let mut mixer = Mixer::new(SteppedClock(now.clone()), SAMPLE_RATE);
+    let (mut mic_tx, mut mic_rx) = engine::source_queue();
+    let (mut loopback_tx, mut loopback_rx) = engine::source_queue();
+    let mut mic = Generator::new(Signal::Sine { hz: 1_000.0 }, SAMPLE_RATE);
+    let mut loopback = Generator::new(Signal::Noise, SAMPLE_RATE);
+    let per_round = SAMPLE_RATE / ROUNDS;
+    let mut out = Vec::new();
+    for round in 1..=ROUNDS {
+        for _ in 0..per_round {
+            let _ = mic_tx.push(mic.next_sample());
+            let _ = loopback_tx.push(loopback.next_sample());
+        }
+        now.set(Duration::from_millis(round as u64 * 1_000 / ROUNDS as u64));
+        mixer.mix_due(&mut mic_rx, &mut loopback_rx, &mut out);
+    }
+    let stats = mixer.stats();
+    if out.len() != SAMPLE_RATE as usize {
+        return Err(anyhow!(
+            "Mixed {} frames, expected {}",
+            out.len(),
+            SAMPLE_RATE
+        ));
+    }
+    if stats.mic_underruns + stats.loopback_underruns > 0 {
+        return Err(anyhow!(
+            "{} MIC and {} loopback underruns",
+            stats.mic_underruns,
+            stats.loopback_underruns
+        ));
+    }
+    if out.iter().all(|frame| frame[0] == 0.0) || out.iter().all(|frame| frame[1] == 0.0) {
+        return Err(anyhow!("A channel came out silent"));
+    }
+    Ok(out)
+}
+
+/// A 44.1 kHz device brought to 48 kHz
+fn resample() -> Result<String> {
+    let mut generator = Generator::new(Signal::Sine { hz: 440.0 }, 44_100);
+    let input: Vec<f32> = (0..44_100).map(|_| generator.next_sample()).collect();
+    let mut resampler = Resampler::new(44_100, SAMPLE_RATE, ResampleQuality::High);
+    let mut out = Vec::new();
+    resampler.process(&input, &mut out);
+    // The filter holds back a few samples of latency
+    if out.len().abs_diff(SAMPLE_RATE as usize) > 64 {
+        return Err(anyhow!("44100 samples came out as {}", out.len()));
+    }
+    Ok(format!("44100 -> {} samples", out.len()))
+}
+
+fn limit() -> Result<String> {
+    let mut limiter = Limiter::new(LimiterMode::Lookahead, SAMPLE_RATE);
+    let mut peak = 0f32;
+    for i in 0..SAMPLE_RATE {
+        let sample = if i % 2 == 0 { 1.8 } else { -1.8 };
+        peak = peak.max(limiter.process(sample).abs());
+    }
+    if peak > 1.0 {
+        return Err(anyhow!("Peak {} past full scale", peak));
+    }
+    Ok(format!("peak {:.3}", peak))
+}
+
+/// Write `frames` as a WAV and check it reads back sample for sample
+fn record(frames: &[[f32; 2]], dir: &Path) -> Result<String> {
+    let path = dir.join(format!(
+        "win-audio-capture-self-test-{}.wav",
+        std::process::id()
+    ));
+    let mut quantizer = Quantizer::new(Dither::Off, 2);
+    let samples: Vec<i16> = frames
+        .iter()
+        .flat_map(|frame| {
+            [
+                quantizer.quantize(0, frame[0]),
+                quantizer.quantize(1, frame[1]),
+            ]
+        })
+        .collect();
+    let written = (|| {
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+        };
+        let mut sink = sink::create(OutputFormat::Wav, &path, spec)?;
+        sink.write_samples(&samples)?;
+        sink.finalize()?;
+        let read: Vec<i16> = hound::WavReader::open(&path)
+            .context("Failed to open the recording")?
+            .into_samples::<i16>()
+            .collect::<Result<_, _>>()
+            .context("Failed to read the recording")?;
+        match read == samples {
+            true => Ok(format!("{} samples", read.len())),
+            false => Err(anyhow!("The recording doesn't read back as written")),
+        }
+    })();
+    let _ = std::fs::remove_file(&path);
+    written
+}
+
+fn decode_vectors() -> Result<String> {
+    let all = vectors::all();
+    for vector in &all {
+        // A byte at a time, the hardest split for the decoder
+        let (outcomes, trailing) = vectors::decode(&vector.bytes, vector.greeted, 1);
+        if outcomes != vector.expect || trailing != vector.trailing_bytes {
+            return Err(anyhow!("Vector {} decodes differently", vector.name));
+        }
+    }
+    Ok(format!("{} vectors", all.len()))
+}
+
+/// The machine's own architecture, when the system reports it
+#[cfg(windows)]
+pub fn os_arch() -> Option<&'static str> {
+    use windows::Win32::System::SystemInformation::{
+        IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64,
+        IMAGE_FILE_MACHINE_I386,
+    };
+    use windows::Win32::System::Threading::{GetCurrentProcess, IsWow64Process2};
+
+    let mut process = IMAGE_FILE_MACHINE::default();
+    let mut native = IMAGE_FILE_MACHINE::default();
+    unsafe { IsWow64Process2(GetCurrentProcess(), &mut process, Some(&mut native)) }.ok()?;
+    match native {
+        IMAGE_FILE_MACHINE_ARM64 => Some("aarch64"),
+        IMAGE_FILE_MACHINE_AMD64 => Some("x86_64"),
+        IMAGE_FILE_MACHINE_I386 => Some("x86"),
+        _ => None,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn os_arch() -> Option<&'static str> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_pipeline_passes_on_the_build_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = run(dir.path());
+        assert!(report.passed, "{:?}", report.checks);
+        assert_eq!(report.arch, std::env::consts::ARCH);
+        assert!(!report.emulated);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
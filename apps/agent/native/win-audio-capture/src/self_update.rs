@@ -0,0 +1,376 @@
+//! Sidecar self-update
+//! `self-update --channel stable --feed <url>` reads `<feed>/stable.json`
+//! (`{"version": "0.3.0", "url": "https://…/win-audio-capture.exe"}`, with `urls` naming
+//! builds per architecture, e.g. `{"aarch64": "https://…/win-audio-capture-arm64.exe"}`),
+//! downloads a newer build for the running binary's architecture next to it and only installs it once its Authenticode signature
+//! verifies and names the same publisher as the running binary. The swap is two renames:
+//! the running binary becomes `<exe>.old` (Windows allows renaming a running image) and
+//! the download takes its place.
+//!
+//! While a capture session is running the verified build is parked as `<exe>.pending`
+//! instead, and installed by the next `self-update` or capture start that finds no session
+//! running, so an update never lands under a recording.
+
+use crate::error::fail;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Stable,
+    Beta,
+}
+
+impl Channel {
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+        }
+    }
+}
+
+/// A channel's manifest
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Release {
+    pub version: String,
+    pub url: String,
+    /// Builds for other architectures than the one `url` points at, by `std::env::consts::ARCH`
+    #[serde(default)]
+    pub urls: BTreeMap<String, String>,
+}
+
+impl Release {
+    /// The build for `arch`
+    pub fn url_for(&self, arch: &str) -> &str {
+        self.urls.get(arch).unwrap_or(&self.url)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    UpToDate,
+    Updated,
+    /// Verified and waiting for the running sessions to end
+    Staged,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    pub status: Status,
+    pub channel: Channel,
+    pub current: &'static str,
+    pub available: String,
+}
+
+/// `major.minor.patch`, ignoring a leading `v` and any pre-release/build suffix
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+/// Whether `available` is strictly newer than `current`
+pub fn is_newer(available: &str, current: &str) -> Result<bool> {
+    let available =
+        parse_version(available).ok_or_else(|| fail!(Format, "Invalid version: {}", available))?;
+    let current =
+        parse_version(current).ok_or_else(|| fail!(Format, "Invalid version: {}", current))?;
+    Ok(available > current)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn pending_path(exe: &Path) -> PathBuf {
+    with_suffix(exe, ".pending")
+}
+
+/// Put a verified `build` in place of `exe`, or park it as pending while `busy`
+pub fn install(exe: &Path, build: &Path, busy: bool) -> Result<Status> {
+    if busy {
+        std::fs::rename(build, pending_path(exe)).context("Failed to stage the update")?;
+        return Ok(Status::Staged);
+    }
+    let old = with_suffix(exe, ".old");
+    // Left over from the previous update; still locked if a session from then is running
+    if old.exists() {
+        std::fs::remove_file(&old)
+            .with_context(|| format!("Failed to remove {}", old.display()))?;
+    }
+    std::fs::rename(exe, &old).context("Failed to move the current binary aside")?;
+    if let Err(e) = std::fs::rename(build, exe) {
+        let _ = std::fs::rename(&old, exe);
+        return Err(e).context("Failed to move the update into place");
+    }
+    Ok(Status::Updated)
+}
+
+/// Install a pending update if there is one and nothing is running
+pub fn apply_pending(exe: &Path, busy: bool) -> Result<bool> {
+    let pending = pending_path(exe);
+    if busy || !pending.exists() {
+        return Ok(false);
+    }
+    install(exe, &pending, false)?;
+    Ok(true)
+}
+
+fn sessions_running() -> Result<bool> {
+    Ok(!crate::registry::list(&crate::registry::registry_dir())?.is_empty())
+}
+
+/// Install a pending update at capture start; failures only delay it
+pub fn apply_pending_at_start() {
+    let applied = std::env::current_exe()
+        .map_err(anyhow::Error::from)
+        .and_then(|exe| apply_pending(&exe, sessions_running()?));
+    match applied {
+        Ok(true) => println!("[win-audio-capture] Installed the pending update for the next start"),
+        Ok(false) => {}
+        Err(e) => eprintln!(
+            "[win-audio-capture] Warning: Pending update not installed: {:#}",
+            e
+        ),
+    }
+}
+
+/// Check `feed` for a newer build on `channel` and install it
+#[cfg(windows)]
+pub fn run(channel: Channel, feed: &str) -> Result<Outcome> {
+    let exe = std::env::current_exe().context("Failed to locate the running binary")?;
+    let current = env!("CARGO_PKG_VERSION");
+    apply_pending(&exe, sessions_running()?)?;
+
+    let manifest_path = with_suffix(&exe, ".manifest.json");
+    let url = format!("{}/{}.json", feed.trim_end_matches('/'), channel.name());
+    win::download(&url, &manifest_path)?;
+    let manifest = std::fs::read(&manifest_path);
+    let _ = std::fs::remove_file(&manifest_path);
+    let release: Release = serde_json::from_slice(&manifest?)
+        .with_context(|| format!("Invalid release manifest at {}", url))?;
+
+    let outcome = |status| Outcome {
+        status,
+        channel,
+        current,
+        available: release.version.clone(),
+    };
+    if !is_newer(&release.version, current)? {
+        return Ok(outcome(Status::UpToDate));
+    }
+    let build_url = release.url_for(std::env::consts::ARCH);
+    if !build_url.starts_with("https://") {
+        return Err(fail!(
+            Policy,
+            "Refusing to download an update over {}",
+            build_url
+        ));
+    }
+
+    let download = with_suffix(&exe, ".download");
+    let installed = win::download(build_url, &download).and_then(|_| {
+        let publisher = win::verified_publisher(&download)?;
+        let ours = win::verified_publisher(&exe)
+            .context("The running binary is not signed, so updates can't be verified")?;
+        if publisher != ours {
+            return Err(fail!(
+                Policy,
+                "Update is signed by {:?}, expected {:?}",
+                publisher,
+                ours
+            ));
+        }
+        install(&exe, &download, sessions_running()?)
+    });
+    match installed {
+        Ok(status) => Ok(outcome(status)),
+        Err(e) => {
+            let _ = std::fs::remove_file(&download);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(not(windows))]
+pub fn run(_channel: Channel, _feed: &str) -> Result<Outcome> {
+    Err(fail!(Config, "This tool only runs on Windows"))
+}
+
+#[cfg(windows)]
+mod win {
+    use crate::error::fail;
+    use anyhow::{Context, Result};
+    use std::path::Path;
+    use windows::core::{GUID, PCWSTR};
+    use windows::Win32::Foundation::{HANDLE, HWND};
+    use windows::Win32::Security::Cryptography::{
+        CertGetNameStringW, CERT_NAME_SIMPLE_DISPLAY_TYPE,
+    };
+    use windows::Win32::Security::WinTrust::{
+        WTHelperGetProvSignerFromChain, WTHelperProvDataFromStateData, WinVerifyTrust,
+        WINTRUST_ACTION_GENERIC_VERIFY_V2, WINTRUST_DATA, WINTRUST_DATA_0, WINTRUST_FILE_INFO,
+        WTD_CHOICE_FILE, WTD_REVOKE_WHOLECHAIN, WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY,
+        WTD_UI_NONE,
+    };
+    use windows::Win32::System::Com::Urlmon::URLDownloadToFileW;
+
+    fn wide(s: &std::ffi::OsStr) -> Vec<u16> {
+        use std::os::windows::ffi::OsStrExt;
+        s.encode_wide().chain(std::iter::once(0)).collect()
+    }
+
+    /// Download `url` to `path`, through the system proxy settings
+    pub fn download(url: &str, path: &Path) -> Result<()> {
+        let url_w = wide(url.as_ref());
+        let path_w = wide(path.as_os_str());
+        unsafe {
+            URLDownloadToFileW(
+                None,
+                PCWSTR(url_w.as_ptr()),
+                PCWSTR(path_w.as_ptr()),
+                0,
+                None,
+            )
+        }
+        .with_context(|| format!("Failed to download {}", url))
+    }
+
+    /// Signer name of `path` if its Authenticode signature verifies against a trusted root
+    pub fn verified_publisher(path: &Path) -> Result<String> {
+        let path_w = wide(path.as_os_str());
+        let mut file = WINTRUST_FILE_INFO {
+            cbStruct: std::mem::size_of::<WINTRUST_FILE_INFO>() as u32,
+            pcwszFilePath: PCWSTR(path_w.as_ptr()),
+            hFile: HANDLE::default(),
+            pgKnownSubject: std::ptr::null_mut(),
+        };
+        let mut data = WINTRUST_DATA {
+            cbStruct: std::mem::size_of::<WINTRUST_DATA>() as u32,
+            dwUIChoice: WTD_UI_NONE,
+            fdwRevocationChecks: WTD_REVOKE_WHOLECHAIN,
+            dwUnionChoice: WTD_CHOICE_FILE,
+            Anonymous: WINTRUST_DATA_0 { pFile: &mut file },
+            dwStateAction: WTD_STATEACTION_VERIFY,
+            ..Default::default()
+        };
+        let mut action: GUID = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+        let status = unsafe {
+            WinVerifyTrust(
+                HWND::default(),
+                &mut action,
+                &mut data as *mut WINTRUST_DATA as *mut _,
+            )
+        };
+
+        let publisher = if status == 0 {
+            unsafe { signer_name(data.hWVTStateData) }
+        } else {
+            Err(fail!(
+                Policy,
+                "{} has no valid signature (0x{:08x})",
+                path.display(),
+                status as u32
+            ))
+        };
+
+        data.dwStateAction = WTD_STATEACTION_CLOSE;
+        unsafe {
+            WinVerifyTrust(
+                HWND::default(),
+                &mut action,
+                &mut data as *mut WINTRUST_DATA as *mut _,
+            )
+        };
+        publisher
+    }
+
+    unsafe fn signer_name(state: HANDLE) -> Result<String> {
+        let provider = WTHelperProvDataFromStateData(state);
+        if provider.is_null() {
+            return Err(fail!(Policy, "No signature data"));
+        }
+        let signer = WTHelperGetProvSignerFromChain(provider, 0, false, 0);
+        if signer.is_null() || (*signer).csCertChain == 0 || (*signer).pasCertChain.is_null() {
+            return Err(fail!(Policy, "No signer certificate"));
+        }
+        let cert = (*(*signer).pasCertChain).pCert;
+        let mut name = [0u16; 256];
+        let len = CertGetNameStringW(
+            cert,
+            CERT_NAME_SIMPLE_DISPLAY_TYPE,
+            0,
+            None,
+            Some(&mut name),
+        );
+        // The length includes the terminator
+        let name = String::from_utf16_lossy(&name[..(len as usize).saturating_sub(1)]);
+        if name.is_empty() {
+            return Err(fail!(Policy, "Signer certificate has no name"));
+        }
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(parse_version("v1.2.3-beta.1"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2"), None);
+        assert!(is_newer("0.10.0", "0.9.9").unwrap());
+        assert!(!is_newer("0.1.0", "0.1.0").unwrap());
+        assert!(is_newer("nope", "0.1.0").is_err());
+    }
+
+    #[test]
+    fn picks_the_build_for_the_architecture() {
+        let release: Release = serde_json::from_str(
+            r#"{"version":"0.3.0","url":"https://x/x64.exe","urls":{"aarch64":"https://x/arm64.exe"}}"#,
+        )
+        .unwrap();
+        assert_eq!(release.url_for("aarch64"), "https://x/arm64.exe");
+        assert_eq!(release.url_for("x86_64"), "https://x/x64.exe");
+    }
+
+    #[test]
+    fn swaps_or_stages_the_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("win-audio-capture.exe");
+        let build = dir.path().join("download");
+        std::fs::write(&exe, "v1").unwrap();
+
+        std::fs::write(&build, "v2").unwrap();
+        assert_eq!(install(&exe, &build, true).unwrap(), Status::Staged);
+        assert_eq!(std::fs::read_to_string(&exe).unwrap(), "v1");
+        assert!(!apply_pending(&exe, true).unwrap());
+        assert!(apply_pending(&exe, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&exe).unwrap(), "v2");
+        assert!(!pending_path(&exe).exists());
+
+        // The previous `.old` is replaced
+        std::fs::write(&build, "v3").unwrap();
+        assert_eq!(install(&exe, &build, false).unwrap(), Status::Updated);
+        assert_eq!(std::fs::read_to_string(&exe).unwrap(), "v3");
+        assert_eq!(
+            std::fs::read_to_string(with_suffix(&exe, ".old")).unwrap(),
+            "v2"
+        );
+    }
+}
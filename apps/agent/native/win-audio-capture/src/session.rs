@@ -0,0 +1,120 @@
+//! Session-wide stream counters
+//! Owned by the capture session rather than an output file, so frame sequence numbers and
+//! sample positions keep counting across file rotations and session hot-swaps.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Position of the next PCM frame within the session
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    next_sequence: u32,
+    sample_position: u64,
+}
+
+impl StreamCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim the header fields for a frame carrying `sample_pairs` stereo pairs.
+    /// Returns `(sequence_number, absolute_sample_offset)` of that frame.
+    pub fn advance(&mut self, sample_pairs: u64) -> (u32, u64) {
+        let claimed = (self.next_sequence, self.sample_position);
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.sample_position += sample_pairs;
+        claimed
+    }
+
+    /// Sequence number the next frame will get
+    pub fn next_sequence(&self) -> u32 {
+        self.next_sequence
+    }
+
+    /// Absolute sample position (per channel) reached so far in the session
+    pub fn sample_position(&self) -> u64 {
+        self.sample_position
+    }
+}
+
+/// Delivery counter for one capture source, used to estimate its clock drift against
+/// wall time. Shared between the source's capture thread and the main loop.
+#[derive(Debug, Default)]
+pub struct SourceCounter {
+    samples: AtomicU64,
+    sample_rate: AtomicU32,
+    first_sample_at: OnceLock<Instant>,
+    /// Summed age of delivered packets, for latency compensation
+    latency_us: AtomicU64,
+    latency_packets: AtomicU64,
+}
+
+/// Drift of a source's sample clock relative to wall time
+#[derive(Debug, Serialize)]
+pub struct SourceDrift {
+    pub samples: u64,
+    pub sample_rate: u32,
+    /// Positive when the device delivered more audio than wall time elapsed
+    pub drift_ms: f64,
+    pub drift_ppm: f64,
+}
+
+impl SourceCounter {
+    /// Record mono samples delivered by the device
+    pub fn add(&self, samples: u64) {
+        self.first_sample_at.get_or_init(Instant::now);
+        self.samples.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    /// Mono samples delivered so far
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    /// Record how old a packet was when the device delivered it
+    pub fn record_latency(&self, age: Duration) {
+        self.latency_us
+            .fetch_add(age.as_micros() as u64, Ordering::Relaxed);
+        self.latency_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mean packet age on delivery, once at least `min_packets` have been reported
+    pub fn mean_latency(&self, min_packets: u64) -> Option<Duration> {
+        let packets = self.latency_packets.load(Ordering::Relaxed);
+        (packets >= min_packets.max(1))
+            .then(|| Duration::from_micros(self.latency_us.load(Ordering::Relaxed) / packets))
+    }
+
+    /// Set the nominal rate once the source knows its native format
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Drift since the first delivered sample, or `None` before any audio arrived
+    pub fn drift(&self) -> Option<SourceDrift> {
+        let started = self.first_sample_at.get()?;
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if sample_rate == 0 {
+            return None;
+        }
+
+        let samples = self.samples.load(Ordering::Relaxed);
+        let elapsed_secs = started.elapsed().as_secs_f64();
+        let captured_secs = samples as f64 / sample_rate as f64;
+        let drift_secs = captured_secs - elapsed_secs;
+        let drift_ppm = if elapsed_secs > 0.0 {
+            drift_secs / elapsed_secs * 1_000_000.0
+        } else {
+            0.0
+        };
+
+        Some(SourceDrift {
+            samples,
+            sample_rate,
+            drift_ms: drift_secs * 1000.0,
+            drift_ppm,
+        })
+    }
+}
@@ -0,0 +1,529 @@
+//! First-run setup
+//! `setup --output-root D:\Recordings` does what the onboarding doc had people do by hand,
+//! asking on the console where there is a choice:
+//!
+//! 1. Pick the MIC and the endpoint to loop back, numbered as `list-devices` finds them
+//!    (Enter keeps the Windows default, `--mic-device`/`--loopback-device` skip the question)
+//! 2. Play a half-second calibration chirp on that endpoint and listen for it on both
+//!    paths. The loopback has to hear it; the MIC should, unless a headset keeps it out.
+//!    When both do, how much later the loopback path delivered it is the session's latency
+//!    offset (the few centimetres of air to the MIC are well under a millisecond)
+//! 3. Check the output root takes writes, as a session checks its output directory
+//! 4. With `--register`, start `spool drain` at logon, so recordings queued for a share
+//!    that was unreachable are delivered after a reboot even when no call is recorded
+//! 5. Write the choices into the config file (see `device_config`), keeping its device
+//!    overrides
+//!
+//! What it settled on is printed as JSON.
+
+use crate::devices::{self, Endpoint, Flow};
+use crate::error::fail;
+use crate::latency;
+use crate::output_location::{self, OutputCheck};
+use anyhow::Result;
+use serde::Serialize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+const CHIRP_MS: u64 = 500;
+const CHIRP_FROM_HZ: f64 = 500.0;
+const CHIRP_TO_HZ: f64 = 3_000.0;
+const CHIRP_AMPLITUDE: f32 = 0.3;
+/// Silence played before the chirp, and recorded after it for late paths
+pub const LEAD_MS: u64 = 500;
+pub const TAIL_MS: u64 = 1_000;
+/// Rate recordings are searched for the chirp at; the sweep stays below its Nyquist
+const ANALYSIS_RATE: u32 = 8_000;
+/// Normalized correlation at which the chirp counts as heard
+const HEARD_CORRELATION: f64 = 0.4;
+
+/// The calibration chirp at `sample_rate`: an exponential sweep with short fades
+pub fn chirp(sample_rate: u32) -> Vec<f32> {
+    let len = (sample_rate as u64 * CHIRP_MS / 1000) as usize;
+    let secs = CHIRP_MS as f64 / 1000.0;
+    let growth = (CHIRP_TO_HZ / CHIRP_FROM_HZ).ln();
+    let fade = (len / 20).max(1);
+    (0..len)
+        .map(|i| {
+            let t = i as f64 / sample_rate as f64;
+            let phase = 2.0 * std::f64::consts::PI * CHIRP_FROM_HZ * secs / growth
+                * ((t / secs * growth).exp() - 1.0);
+            let envelope = (i.min(len - 1 - i) as f32 / fade as f32).min(1.0);
+            CHIRP_AMPLITUDE * envelope * phase.sin() as f32
+        })
+        .collect()
+}
+
+/// Block averages down to about `ANALYSIS_RATE`; returns them and the block length
+fn decimate(samples: &[f32], sample_rate: u32) -> (Vec<f64>, usize) {
+    let step = (sample_rate / ANALYSIS_RATE).max(1) as usize;
+    let averaged = samples
+        .chunks_exact(step)
+        .map(|block| block.iter().map(|&s| s as f64).sum::<f64>() / step as f64)
+        .collect();
+    (averaged, step)
+}
+
+/// Sample of `recorded` (mono, at `sample_rate`) where the chirp starts, if it was heard
+pub fn find_chirp(recorded: &[f32], sample_rate: u32) -> Option<usize> {
+    let (reference, step) = decimate(&chirp(sample_rate), sample_rate);
+    let (signal, _) = decimate(recorded, sample_rate);
+    let len = reference.len();
+    if len == 0 || signal.len() < len {
+        return None;
+    }
+    let reference_norm = reference.iter().map(|s| s * s).sum::<f64>().sqrt();
+    let mut window_energy: f64 = signal[..len].iter().map(|s| s * s).sum();
+    let mut best = (0.0, 0);
+    for offset in 0..=signal.len() - len {
+        if offset > 0 {
+            window_energy += signal[offset + len - 1].powi(2) - signal[offset - 1].powi(2);
+        }
+        let dot: f64 = reference
+            .iter()
+            .zip(&signal[offset..])
+            .map(|(a, b)| a * b)
+            .sum();
+        let score = dot / (reference_norm * window_energy.max(1e-12).sqrt());
+        if score > best.0 {
+            best = (score, offset);
+        }
+    }
+    (best.0 >= HEARD_CORRELATION).then_some(best.1 * step)
+}
+
+/// What one stream recorded while the chirp played (mixed to mono), and when each
+/// callback delivered its part
+#[derive(Debug, Default)]
+pub struct Take {
+    pub sample_rate: u32,
+    samples: Vec<f32>,
+    /// Samples recorded up to and including each callback, and when it ran (ms from the
+    /// start of the calibration)
+    deliveries: Vec<(usize, f64)>,
+}
+
+impl Take {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            ..Self::default()
+        }
+    }
+
+    /// Add a callback's interleaved `data`
+    pub fn push(&mut self, data: &[f32], channels: usize, delivered_ms: f64) {
+        let channels = channels.max(1);
+        self.samples.extend(
+            data.chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        self.deliveries.push((self.samples.len(), delivered_ms));
+    }
+
+    /// When sample `index` reached the process, had the stream delivered it on its own:
+    /// its callback's time less the audio after it in that callback
+    fn delivered_ms(&self, index: usize) -> Option<f64> {
+        let &(end, at) = self.deliveries.iter().find(|(end, _)| *end > index)?;
+        Some(at - (end - index - 1) as f64 * 1000.0 / self.sample_rate as f64)
+    }
+
+    /// When the chirp reached the process, if it is in here
+    fn chirp_ms(&self) -> Option<f64> {
+        self.delivered_ms(find_chirp(&self.samples, self.sample_rate)?)
+    }
+}
+
+/// What the chirp showed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Calibration {
+    pub loopback_heard: bool,
+    pub mic_heard: bool,
+    /// How much later the loopback path delivered the chirp than the MIC's, as
+    /// `--latency-offset-ms` takes it; only when both heard it and it is plausible
+    pub latency_offset_ms: Option<f64>,
+}
+
+pub fn calibrate(mic: &Take, loopback: &Take) -> Calibration {
+    let (mic_ms, loopback_ms) = (mic.chirp_ms(), loopback.chirp_ms());
+    let latency_offset_ms = mic_ms
+        .zip(loopback_ms)
+        .map(|(mic, loopback)| ((loopback - mic) * 10.0).round() / 10.0)
+        .filter(|offset| offset.abs() <= latency::MAX_OFFSET_MS);
+    Calibration {
+        loopback_heard: loopback_ms.is_some(),
+        mic_heard: mic_ms.is_some(),
+        latency_offset_ms,
+    }
+}
+
+/// Read an answer to `question` from the console; empty without one
+pub fn prompt(question: &str) -> String {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return String::new();
+    }
+    eprint!("{} ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    let _ = stdin.lock().read_line(&mut answer);
+    answer.trim().to_string()
+}
+
+/// The endpoint of `flow` an answer names: its number in the list, its ID or name, or
+/// nothing for the Windows default
+pub fn pick<'a>(endpoints: &'a [Endpoint], flow: Flow, answer: &str) -> Result<&'a Endpoint> {
+    let of_flow: Vec<&Endpoint> = endpoints
+        .iter()
+        .filter(|endpoint| endpoint.flow == flow)
+        .collect();
+    if answer.is_empty() {
+        return of_flow
+            .iter()
+            .find(|endpoint| endpoint.default)
+            .or(of_flow.first())
+            .copied()
+            .ok_or_else(|| fail!(Device, "No active {:?} endpoint", flow));
+    }
+    match answer.parse::<usize>() {
+        Ok(number) => number
+            .checked_sub(1)
+            .and_then(|index| of_flow.get(index))
+            .copied()
+            .ok_or_else(|| fail!(Config, "Pick a number from 1 to {}", of_flow.len())),
+        Err(_) => devices::find(endpoints, flow, answer),
+    }
+}
+
+/// Check that recordings can be written under `root`
+pub fn check_output_root(root: &Path) -> Result<OutputCheck> {
+    let check = output_location::check(&root.join("setup-probe.wav"), false)?;
+    if !check.reachable {
+        return Err(fail!(
+            Network,
+            "Output root {} can't be reached",
+            root.display()
+        ));
+    }
+    Ok(check)
+}
+
+#[cfg(windows)]
+pub use console::{run, Options};
+
+#[cfg(windows)]
+mod console {
+    use super::{calibrate, check_output_root, chirp, pick, prompt, Calibration, Take};
+    use super::{CHIRP_MS, LEAD_MS, TAIL_MS};
+    use crate::device_config::CaptureConfig;
+    use crate::devices::{self, Endpoint, Flow};
+    use crate::error::fail;
+    use anyhow::{Context, Result};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use windows::core::w;
+    use windows::Win32::Foundation::ERROR_SUCCESS;
+    use windows::Win32::System::Registry::{RegSetKeyValueW, HKEY_CURRENT_USER, REG_SZ};
+
+    #[derive(Debug)]
+    pub struct Options {
+        pub mic_device: Option<String>,
+        pub loopback_device: Option<String>,
+        pub output_root: PathBuf,
+        pub chirp: bool,
+        pub register: bool,
+        /// Config file to write
+        pub config: PathBuf,
+        /// Take the defaults instead of asking
+        pub yes: bool,
+    }
+
+    pub fn run(options: Options) -> Result<Value> {
+        let endpoints = devices::list()?;
+        let mic = choose(&endpoints, Flow::Capture, &options.mic_device, options.yes)?;
+        let loopback = choose(
+            &endpoints,
+            Flow::Render,
+            &options.loopback_device,
+            options.yes,
+        )?;
+        eprintln!("MIC: {}\nLoopback: {}", mic.name, loopback.name);
+
+        let calibration = match options.chirp {
+            true => {
+                eprintln!("Playing the calibration chirp on \"{}\"...", loopback.name);
+                let calibration = play_chirp(mic, loopback)?;
+                if !calibration.loopback_heard {
+                    return Err(fail!(
+                        Device,
+                        "The loopback of \"{}\" didn't hear the chirp; is it muted or disabled?",
+                        loopback.name
+                    ));
+                }
+                if !calibration.mic_heard {
+                    eprintln!(
+                        "The MIC didn't hear the chirp (expected with a headset); sessions will measure the latency themselves"
+                    );
+                }
+                Some(calibration)
+            }
+            false => None,
+        };
+
+        let output = check_output_root(&options.output_root)?;
+        let registered = match options.register {
+            true => Some(register_spool_drain()?),
+            false => None,
+        };
+
+        let mut config = CaptureConfig::load(&options.config, false)?;
+        config.mic_device = Some(mic.id.clone());
+        config.loopback_device = Some(loopback.id.clone());
+        if let Some(offset) = calibration.and_then(|c| c.latency_offset_ms) {
+            config.latency_offset_ms = Some(offset);
+        }
+        config.output_root = Some(options.output_root.clone());
+        config.save(&options.config)?;
+
+        Ok(json!({
+            "config": options.config,
+            "mic": mic,
+            "loopback": loopback,
+            "calibration": calibration,
+            "output_root": { "path": options.output_root, "check": output },
+            "registered": registered,
+        }))
+    }
+
+    /// The endpoint of `flow` given on the command line, or asked for
+    fn choose<'a>(
+        endpoints: &'a [Endpoint],
+        flow: Flow,
+        given: &Option<String>,
+        yes: bool,
+    ) -> Result<&'a Endpoint> {
+        if let Some(id) = given {
+            return devices::find(endpoints, flow, id);
+        }
+        if yes {
+            return pick(endpoints, flow, "");
+        }
+        let label = match flow {
+            Flow::Capture => "MIC",
+            Flow::Render => "Endpoint to loop back",
+        };
+        eprintln!("{}:", label);
+        let of_flow = endpoints.iter().filter(|endpoint| endpoint.flow == flow);
+        for (number, endpoint) in of_flow.enumerate() {
+            let default = if endpoint.default { " (default)" } else { "" };
+            eprintln!("  {}. {}{}", number + 1, endpoint.name, default);
+        }
+        loop {
+            let answer = prompt(&format!("{} [number, Enter for the default]:", label));
+            match pick(endpoints, flow, &answer) {
+                Ok(endpoint) => return Ok(endpoint),
+                Err(e) => eprintln!("{:#}", e),
+            }
+        }
+    }
+
+    /// Play the chirp on `render` while recording it on `mic` and in `render`'s loopback
+    fn play_chirp(mic: &Endpoint, render: &Endpoint) -> Result<Calibration> {
+        let host = cpal::default_host();
+        let output = host
+            .output_devices()?
+            .find(|device| device.name().ok().as_deref() == Some(render.name.as_str()))
+            .ok_or_else(|| fail!(Device, "\"{}\" is not an output device", render.name))?;
+        let input = host
+            .input_devices()?
+            .find(|device| device.name().ok().as_deref() == Some(mic.name.as_str()))
+            .ok_or_else(|| fail!(Device, "\"{}\" is not an input device", mic.name))?;
+        let output_config = output.default_output_config()?;
+        let input_config = input.default_input_config()?;
+        for (config, name) in [(&output_config, &render.name), (&input_config, &mic.name)] {
+            if config.sample_format() != cpal::SampleFormat::F32 {
+                return Err(fail!(
+                    Format,
+                    "\"{}\" runs at {:?}; the chirp needs a float mix format",
+                    name,
+                    config.sample_format()
+                ));
+            }
+        }
+
+        let started = Instant::now();
+        let record = |device: &cpal::Device, config: cpal::StreamConfig| {
+            let take = Arc::new(Mutex::new(Take::new(config.sample_rate.0)));
+            let channels = config.channels as usize;
+            let writer = take.clone();
+            let stream = device.build_input_stream(
+                &config,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                    let delivered_ms = started.elapsed().as_secs_f64() * 1000.0;
+                    if let Ok(mut take) = writer.lock() {
+                        take.push(data, channels, delivered_ms);
+                    }
+                },
+                |e| eprintln!("[win-audio-capture] Warning: {}", e),
+                None,
+            )?;
+            Ok::<_, anyhow::Error>((stream, take))
+        };
+        let (mic_stream, mic_take) = record(&input, input_config.config())?;
+        // An input stream on a render endpoint is its loopback
+        let (loopback_stream, loopback_take) = record(&output, output_config.config())?;
+
+        let config = output_config.config();
+        let lead = (config.sample_rate.0 as u64 * LEAD_MS / 1000) as usize;
+        let mut signal = vec![0.0; lead];
+        signal.extend(chirp(config.sample_rate.0));
+        let channels = config.channels as usize;
+        let mut position = 0;
+        let player = output.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                for frame in data.chunks_exact_mut(channels) {
+                    frame.fill(signal.get(position).copied().unwrap_or(0.0));
+                    position += 1;
+                }
+            },
+            |e| eprintln!("[win-audio-capture] Warning: {}", e),
+            None,
+        )?;
+
+        mic_stream.play().context("Failed to start the MIC")?;
+        loopback_stream
+            .play()
+            .context("Failed to start the loopback")?;
+        player.play().context("Failed to play the chirp")?;
+        std::thread::sleep(Duration::from_millis(LEAD_MS + CHIRP_MS + TAIL_MS));
+        drop((player, mic_stream, loopback_stream));
+
+        let mic = mic_take.lock().unwrap_or_else(|e| e.into_inner());
+        let loopback = loopback_take.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(calibrate(&mic, &loopback))
+    }
+
+    /// Start `spool drain` at logon for the signed-in user; the command registered
+    fn register_spool_drain() -> Result<String> {
+        let exe = std::env::current_exe().context("Failed to locate this binary")?;
+        let command = format!("\"{}\" spool drain", exe.display());
+        let wide: Vec<u16> = command.encode_utf16().chain([0]).collect();
+        let status = unsafe {
+            RegSetKeyValueW(
+                HKEY_CURRENT_USER,
+                w!("Software\\Microsoft\\Windows\\CurrentVersion\\Run"),
+                w!("SellySpoolDrain"),
+                REG_SZ.0,
+                Some(wide.as_ptr().cast()),
+                (wide.len() * 2) as u32,
+            )
+        };
+        if status != ERROR_SUCCESS {
+            return Err(fail!(
+                Policy,
+                "Failed to register spool drain at logon ({:?})",
+                status
+            ));
+        }
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noise, then the chirp `at` samples in, scaled by `gain`
+    fn recording(sample_rate: u32, at: usize, gain: f32) -> Vec<f32> {
+        let mut state = 0x2545_f491_u32;
+        let mut samples: Vec<f32> = (0..sample_rate as usize * 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32 - 0.5) * 0.02
+            })
+            .collect();
+        for (sample, chirp) in samples[at..].iter_mut().zip(chirp(sample_rate)) {
+            *sample += chirp * gain;
+        }
+        samples
+    }
+
+    #[test]
+    fn finds_the_chirp_in_noise() {
+        let found = find_chirp(&recording(16_000, 7_000, 0.5), 16_000).unwrap();
+        assert!(found.abs_diff(7_000) <= 2, "found at {}", found);
+        assert_eq!(find_chirp(&recording(16_000, 7_000, 0.0), 16_000), None);
+        assert_eq!(find_chirp(&[0.0; 100], 16_000), None);
+    }
+
+    #[test]
+    fn offset_is_how_much_later_the_loopback_delivered_it() {
+        // The loopback delivers 10ms callbacks, the MIC 20ms ones; the chirp reaches the
+        // process 20ms earlier on the MIC path
+        let take = |at: usize, callback: usize, head_start_ms: f64| {
+            let samples = recording(16_000, at, 0.5);
+            let mut take = Take::new(16_000);
+            for (n, chunk) in samples.chunks(callback).enumerate() {
+                let end_ms = ((n + 1) * callback) as f64 / 16.0;
+                take.push(chunk, 1, end_ms + head_start_ms);
+            }
+            take
+        };
+        let mic = take(8_000, 320, 0.0);
+        let loopback = take(8_000, 160, 20.0);
+        let calibration = calibrate(&mic, &loopback);
+        assert!(calibration.loopback_heard && calibration.mic_heard);
+        let offset = calibration.latency_offset_ms.unwrap();
+        assert!((offset - 20.0).abs() <= 0.5, "offset {}", offset);
+
+        // A headset keeps the chirp out of the MIC
+        let silent = Take::new(16_000);
+        let calibration = calibrate(&silent, &loopback);
+        assert!(calibration.loopback_heard && !calibration.mic_heard);
+        assert_eq!(calibration.latency_offset_ms, None);
+    }
+
+    #[test]
+    fn picks_endpoints_by_number_id_or_default() {
+        let endpoint = |flow, name: &str, default| Endpoint {
+            flow,
+            name: name.to_string(),
+            id: format!("{{{}}}", name),
+            default,
+            communications_default: false,
+            format: None,
+        };
+        let endpoints = [
+            endpoint(Flow::Capture, "Webcam", false),
+            endpoint(Flow::Render, "Speakers", true),
+            endpoint(Flow::Capture, "Headset", true),
+        ];
+        assert_eq!(pick(&endpoints, Flow::Capture, "").unwrap().name, "Headset");
+        assert_eq!(pick(&endpoints, Flow::Capture, "1").unwrap().name, "Webcam");
+        assert_eq!(
+            pick(&endpoints, Flow::Render, "{Speakers}").unwrap().name,
+            "Speakers"
+        );
+        assert!(pick(&endpoints, Flow::Capture, "3").is_err());
+        assert!(pick(&endpoints, Flow::Capture, "0").is_err());
+        assert!(pick(&[], Flow::Render, "").is_err());
+    }
+
+    #[test]
+    fn output_root_must_take_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("Recordings");
+        assert!(check_output_root(&root).unwrap().reachable);
+        assert!(root.is_dir());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(check_output_root(&file.join("Recordings")).is_err());
+    }
+}
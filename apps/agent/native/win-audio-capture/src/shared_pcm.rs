@@ -0,0 +1,69 @@
+//! Named mapping behind the shared-memory PCM ring
+//! Page-file backed, named after the instance (`pcm_mapping` in the registry) so the N-API
+//! addon in the agent opens it with `OpenFileMappingW` and maps the same slots. The
+//! mapping lives as long as the ring; the addon keeps its own view open for as long as it
+//! still holds buffers.
+
+use crate::shm::{Layout, Region, Ring};
+use anyhow::{anyhow, Result};
+use windows::core::HSTRING;
+use windows::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE};
+use windows::Win32::System::Memory::{
+    CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
+    MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+};
+
+struct Mapping {
+    handle: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    len: usize,
+}
+
+// Handles and views are usable from any thread
+unsafe impl Send for Mapping {}
+
+// Views start on an allocation-granularity boundary and stay mapped until drop
+unsafe impl Region for Mapping {
+    fn base(&self) -> *mut u8 {
+        self.view.Value as *mut u8
+    }
+
+    fn size(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+/// Create the mapping `name` and lay a fresh ring out in it
+pub fn create(name: &str, layout: Layout) -> Result<Ring> {
+    let len = layout.region_len();
+    let handle = unsafe {
+        CreateFileMappingW(
+            INVALID_HANDLE_VALUE,
+            None,
+            PAGE_READWRITE,
+            (len as u64 >> 32) as u32,
+            len as u32,
+            &HSTRING::from(name),
+        )
+    }
+    .map_err(|e| anyhow!("Failed to create PCM mapping {}: {}", name, e))?;
+    let view = unsafe { MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, len) };
+    if view.Value.is_null() {
+        let e = windows::core::Error::from_win32();
+        unsafe {
+            let _ = CloseHandle(handle);
+        }
+        return Err(anyhow!("Failed to map PCM mapping {}: {}", name, e));
+    }
+    let mapping = Mapping { handle, view, len };
+    Ring::create(Box::new(mapping), layout).map_err(|e| anyhow!("PCM mapping {}: {}", name, e))
+}
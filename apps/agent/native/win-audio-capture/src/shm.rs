@@ -0,0 +1,468 @@
+//! Shared-memory PCM slots
+//! Layout and recycling protocol for handing frames to the N-API consumer without copies.
+//! The sidecar owns a named mapping holding a ring of fixed-size slots; instead of the PCM
+//! payload it sends a `SELR` reference frame (slot, generation, length) on the frame
+//! stream, and the addon wraps the mapped slot as an external `ArrayBuffer`/`Int16Array`
+//! directly. The samples are copied once, into the slot, where the stdout path copies
+//! them at least three times on the way to JS.
+//!
+//! Region layout (little-endian, every offset 64-byte aligned):
+//!
+//! `[header 64] [slot 0] [slot 1] ...` with the header
+//! `[magic "SELM" u32] [version u32] [slot_count u32] [slot_bytes u32] [slot_stride u32]`
+//! and each slot `[state u32] [generation u32] [sequence u32] [payload_len u32]
+//! [sample_offset u64] (pad to 64) [payload slot_bytes]`.
+//!
+//! A slot goes `FREE -> WRITING -> READY` on the sidecar side, which only ever takes `FREE`
+//! slots; the consumer owns a `READY` slot until it stores `FREE` back, normally from the
+//! `ArrayBuffer` finalizer or an explicit release. The generation is bumped on every
+//! publish, so a release for an older reference is ignored. When the next slot is still
+//! held the sidecar falls back to an in-band PCM frame rather than waiting, so a consumer
+//! leaking buffers degrades to the copying path instead of stalling the stream.
+
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Magic at the start of the region
+pub const MAGIC: [u8; 4] = *b"SELM";
+
+/// Region layout version
+pub const VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 64;
+const SLOT_HEADER_LEN: usize = 64;
+const ALIGN: usize = 64;
+
+/// Slot states
+pub const FREE: u32 = 0;
+pub const WRITING: u32 = 1;
+pub const READY: u32 = 2;
+
+/// Size of a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub slots: u32,
+    /// Payload capacity of a slot
+    pub slot_bytes: u32,
+}
+
+impl Layout {
+    /// 32 slots of 100ms at 48kHz stereo: over three seconds of frames the consumer can
+    /// hold before the stream falls back to copying
+    pub const DEFAULT: Layout = Layout {
+        slots: 32,
+        slot_bytes: 4800 * 2 * 2,
+    };
+
+    pub fn slot_stride(&self) -> usize {
+        SLOT_HEADER_LEN + (self.slot_bytes as usize).div_ceil(ALIGN) * ALIGN
+    }
+
+    /// Bytes the mapping must have
+    pub fn region_len(&self) -> usize {
+        HEADER_LEN + self.slots as usize * self.slot_stride()
+    }
+
+    fn slot_offset(&self, slot: u32) -> usize {
+        HEADER_LEN + slot as usize * self.slot_stride()
+    }
+}
+
+/// Memory a ring lives in
+///
+/// # Safety
+/// `base` must point to `size` bytes, aligned to 64, that stay mapped and are only accessed
+/// through this protocol while the value is alive.
+pub unsafe trait Region: Send {
+    fn base(&self) -> *mut u8;
+    fn size(&self) -> usize;
+}
+
+/// What a reference frame points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotRef {
+    pub slot: u32,
+    pub generation: u32,
+    pub payload_len: u32,
+}
+
+impl SlotRef {
+    pub const ENCODED_LEN: usize = 12;
+
+    pub fn to_bytes(self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0u8; Self::ENCODED_LEN];
+        bytes[0..4].copy_from_slice(&self.slot.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.generation.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Some(Self {
+            slot: word(0),
+            generation: word(4),
+            payload_len: word(8),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    TooSmall { needed: usize, len: usize },
+    Misaligned,
+    BadMagic,
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutError::TooSmall { needed, len } => {
+                write!(f, "region is {} bytes, layout needs {}", len, needed)
+            }
+            LayoutError::Misaligned => write!(f, "region is not 64-byte aligned"),
+            LayoutError::BadMagic => write!(f, "region has no SELM header"),
+            LayoutError::UnsupportedVersion(v) => write!(f, "unsupported layout version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// Raw access shared by both sides
+struct Slots {
+    region: Box<dyn Region>,
+    base: NonNull<u8>,
+    layout: Layout,
+}
+
+impl Slots {
+    fn new(region: Box<dyn Region>, layout: Layout) -> Result<Self, LayoutError> {
+        let base = NonNull::new(region.base()).ok_or(LayoutError::Misaligned)?;
+        if !(base.as_ptr() as usize).is_multiple_of(ALIGN) {
+            return Err(LayoutError::Misaligned);
+        }
+        if region.size() < layout.region_len() {
+            return Err(LayoutError::TooSmall {
+                needed: layout.region_len(),
+                len: region.size(),
+            });
+        }
+        Ok(Self {
+            region,
+            base,
+            layout,
+        })
+    }
+
+    fn ptr(&self, offset: usize) -> *mut u8 {
+        debug_assert!(offset < self.region.size());
+        // In bounds: offsets come from the checked layout
+        unsafe { self.base.as_ptr().add(offset) }
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        // Aligned (offsets are multiples of 4 from a 64-aligned base) and shared by design
+        unsafe { &*(self.ptr(offset) as *const AtomicU32) }
+    }
+
+    fn state(&self, slot: u32) -> &AtomicU32 {
+        self.word(self.layout.slot_offset(slot))
+    }
+
+    fn generation(&self, slot: u32) -> &AtomicU32 {
+        self.word(self.layout.slot_offset(slot) + 4)
+    }
+
+    fn payload(&self, slot: u32) -> *mut u8 {
+        self.ptr(self.layout.slot_offset(slot) + SLOT_HEADER_LEN)
+    }
+}
+
+// The region is only touched through atomics and the slot protocol
+unsafe impl Send for Slots {}
+
+/// Sidecar side: publishes frames into free slots
+pub struct Ring {
+    slots: Slots,
+    next: u32,
+}
+
+impl Ring {
+    /// Write a fresh header into `region` and mark every slot free
+    pub fn create(region: Box<dyn Region>, layout: Layout) -> Result<Self, LayoutError> {
+        let slots = Slots::new(region, layout)?;
+        let header = [
+            VERSION,
+            layout.slots,
+            layout.slot_bytes,
+            layout.slot_stride() as u32,
+        ];
+        for (i, value) in header.into_iter().enumerate() {
+            slots.word(4 + i * 4).store(value, Ordering::Relaxed);
+        }
+        for slot in 0..layout.slots {
+            slots.generation(slot).store(0, Ordering::Relaxed);
+            slots.state(slot).store(FREE, Ordering::Relaxed);
+        }
+        // Magic last, so a consumer that sees it sees the rest
+        slots
+            .word(0)
+            .store(u32::from_le_bytes(MAGIC), Ordering::Release);
+        Ok(Self { slots, next: 0 })
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.slots.layout
+    }
+
+    /// Copy `samples` into the next slot and hand it to the consumer; `None` if the frame
+    /// doesn't fit or the consumer still holds that slot
+    pub fn publish(
+        &mut self,
+        samples: &[i16],
+        sequence: u32,
+        sample_offset: u64,
+    ) -> Option<SlotRef> {
+        let layout = self.slots.layout;
+        let payload_len = samples.len() * 2;
+        if payload_len > layout.slot_bytes as usize || layout.slots == 0 {
+            return None;
+        }
+        let slot = self.next;
+        self.slots
+            .state(slot)
+            .compare_exchange(FREE, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.next = (slot + 1) % layout.slots;
+
+        let offset = layout.slot_offset(slot);
+        let payload = self.slots.payload(slot);
+        for (i, sample) in samples.iter().enumerate() {
+            // Sole writer while the slot is WRITING
+            unsafe {
+                std::ptr::copy_nonoverlapping(sample.to_le_bytes().as_ptr(), payload.add(i * 2), 2)
+            };
+        }
+        let generation = self
+            .slots
+            .generation(slot)
+            .load(Ordering::Relaxed)
+            .wrapping_add(1);
+        self.slots
+            .generation(slot)
+            .store(generation, Ordering::Relaxed);
+        self.slots
+            .word(offset + 8)
+            .store(sequence, Ordering::Relaxed);
+        self.slots
+            .word(offset + 12)
+            .store(payload_len as u32, Ordering::Relaxed);
+        self.slots
+            .word(offset + 16)
+            .store(sample_offset as u32, Ordering::Relaxed);
+        self.slots
+            .word(offset + 20)
+            .store((sample_offset >> 32) as u32, Ordering::Relaxed);
+        self.slots.state(slot).store(READY, Ordering::Release);
+
+        Some(SlotRef {
+            slot,
+            generation,
+            payload_len: payload_len as u32,
+        })
+    }
+
+    /// Take back every slot handed out, when the consumer that held them is gone
+    pub fn reclaim(&mut self) {
+        for slot in 0..self.slots.layout.slots {
+            let _ = self.slots.state(slot).compare_exchange(
+                READY,
+                FREE,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+        self.next = 0;
+    }
+}
+
+/// Consumer side: reads and releases the slots reference frames point at
+pub struct View {
+    slots: Slots,
+}
+
+impl View {
+    /// Attach to a region the sidecar created, reading its layout from the header
+    pub fn attach(region: Box<dyn Region>) -> Result<Self, LayoutError> {
+        if region.size() < HEADER_LEN {
+            return Err(LayoutError::TooSmall {
+                needed: HEADER_LEN,
+                len: region.size(),
+            });
+        }
+        let header = Slots::new(
+            region,
+            Layout {
+                slots: 0,
+                slot_bytes: 0,
+            },
+        )?;
+        if header.word(0).load(Ordering::Acquire) != u32::from_le_bytes(MAGIC) {
+            return Err(LayoutError::BadMagic);
+        }
+        let version = header.word(4).load(Ordering::Relaxed);
+        if version != VERSION {
+            return Err(LayoutError::UnsupportedVersion(version));
+        }
+        let layout = Layout {
+            slots: header.word(8).load(Ordering::Relaxed),
+            slot_bytes: header.word(12).load(Ordering::Relaxed),
+        };
+        Ok(Self {
+            slots: Slots::new(header.region, layout)?,
+        })
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.slots.layout
+    }
+
+    /// The payload `slot_ref` points at, while it is still the consumer's
+    pub fn payload(&self, slot_ref: SlotRef) -> Option<&[u8]> {
+        if !self.owns(slot_ref) || slot_ref.payload_len > self.slots.layout.slot_bytes {
+            return None;
+        }
+        // READY slots are not written by the sidecar until released
+        Some(unsafe {
+            std::slice::from_raw_parts(
+                self.slots.payload(slot_ref.slot),
+                slot_ref.payload_len as usize,
+            )
+        })
+    }
+
+    /// Hand the slot back; false if it was already released or reused
+    pub fn release(&self, slot_ref: SlotRef) -> bool {
+        self.owns(slot_ref)
+            && self
+                .slots
+                .state(slot_ref.slot)
+                .compare_exchange(READY, FREE, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn owns(&self, slot_ref: SlotRef) -> bool {
+        slot_ref.slot < self.slots.layout.slots
+            && self.slots.state(slot_ref.slot).load(Ordering::Acquire) == READY
+            && self.slots.generation(slot_ref.slot).load(Ordering::Relaxed) == slot_ref.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Zeroed heap memory standing in for a mapping; leaked, so a ring and a view can share
+    /// it for the rest of the test
+    #[derive(Clone, Copy)]
+    struct Heap {
+        base: *mut u8,
+        size: usize,
+    }
+
+    unsafe impl Send for Heap {}
+
+    unsafe impl Region for Heap {
+        fn base(&self) -> *mut u8 {
+            self.base
+        }
+        fn size(&self) -> usize {
+            self.size
+        }
+    }
+
+    fn heap(layout: Layout) -> Heap {
+        let size = layout.region_len();
+        let alloc = std::alloc::Layout::from_size_align(size, ALIGN).unwrap();
+        Heap {
+            base: unsafe { std::alloc::alloc_zeroed(alloc) },
+            size,
+        }
+    }
+
+    const SMALL: Layout = Layout {
+        slots: 2,
+        slot_bytes: 8,
+    };
+
+    #[test]
+    fn hands_frames_over_and_recycles_released_slots() {
+        let region = heap(SMALL);
+        let mut ring = Ring::create(Box::new(region), SMALL).unwrap();
+        let view = View::attach(Box::new(region)).unwrap();
+        assert_eq!(view.layout(), SMALL);
+
+        let first = ring
+            .publish(&[1, -1, i16::MAX, i16::MIN], 7, 4_800)
+            .unwrap();
+        let second = ring.publish(&[2, 2], 8, 4_802).unwrap();
+        assert_eq!((first.slot, second.slot), (0, 1));
+        assert_eq!(
+            view.payload(first).unwrap(),
+            [0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x80]
+        );
+        // Both slots are held: the stream has to fall back to copying
+        assert_eq!(ring.publish(&[3, 3], 9, 4_804), None);
+
+        assert!(view.release(first));
+        assert!(!view.release(first));
+        let third = ring.publish(&[3, 3], 9, 4_804).unwrap();
+        assert_eq!((third.slot, third.generation), (0, first.generation + 1));
+        // A stale reference to the reused slot is refused
+        assert_eq!(view.payload(first), None);
+        assert!(!view.release(first));
+        assert_eq!(view.payload(third).unwrap(), [3, 0, 3, 0]);
+    }
+
+    #[test]
+    fn reclaims_slots_and_rejects_bad_regions() {
+        let region = heap(SMALL);
+        let mut ring = Ring::create(Box::new(region), SMALL).unwrap();
+        let view = View::attach(Box::new(region)).unwrap();
+        let held = ring.publish(&[1, 1], 0, 0).unwrap();
+        assert_eq!(ring.publish(&[1; 5], 1, 2), None, "larger than a slot");
+        ring.reclaim();
+        assert!(!view.release(held));
+        assert_eq!(ring.publish(&[1, 1], 1, 2).unwrap().slot, 0);
+
+        let blank = heap(SMALL);
+        assert_eq!(
+            View::attach(Box::new(blank)).err(),
+            Some(LayoutError::BadMagic)
+        );
+        let tiny = heap(Layout {
+            slots: 1,
+            slot_bytes: 8,
+        });
+        assert!(matches!(
+            Ring::create(Box::new(tiny), SMALL),
+            Err(LayoutError::TooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn slot_refs_round_trip() {
+        let slot_ref = SlotRef {
+            slot: 3,
+            generation: u32::MAX,
+            payload_len: 19_200,
+        };
+        assert_eq!(SlotRef::from_bytes(&slot_ref.to_bytes()), Some(slot_ref));
+        assert_eq!(SlotRef::from_bytes(&[0; 11]), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! Silence compaction
+//! With `--compact-silence <secs>` the sink writer leaves long stretches where every
+//! channel stays below the threshold out of the file: a pause keeps its first `secs` and,
+//! once the audio is back, its last half second, and the part in between is dropped. An
+//! all-day webinar with hours of nobody talking then doesn't write gigabytes of near-zero
+//! samples.
+//!
+//! Every span left out is recorded in the timeline file (`silence`), with the session
+//! position it started at and the file frame it would have been at, so positions can still
+//! be mapped onto the shorter file and a player can reinsert the silence if it needs to.
+
+use crate::trim::{self, TRIM_PADDING_MS};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Span of silence left out of the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Elision {
+    /// Session position of the first frame left out
+    pub sample_position: u64,
+    /// File frame the next frame written lands on
+    pub file_frame: u64,
+    pub frames: u64,
+}
+
+/// Where session position `position` lands in a file with `elisions` left out; positions
+/// inside a span land where it was cut
+pub fn file_frame(elisions: &[Elision], position: u64) -> u64 {
+    let index = elisions.partition_point(|e| e.sample_position <= position);
+    let Some(e) = index.checked_sub(1).map(|i| elisions[i]) else {
+        return position;
+    };
+    if position < e.sample_position + e.frames {
+        e.file_frame
+    } else {
+        e.file_frame + (position - e.sample_position - e.frames)
+    }
+}
+
+/// Streams samples through, leaving out the middle of long silences
+pub struct SilenceCompactor {
+    channels: usize,
+    threshold: u16,
+    /// Silent frames written at the start of a pause before frames are held back
+    keep_frames: u64,
+    /// Silent frames held back, and written if the audio comes back
+    tail: VecDeque<i16>,
+    tail_frames: usize,
+    position: u64,
+    file_frames: u64,
+    silent_run: u64,
+    elisions: Vec<Elision>,
+}
+
+impl SilenceCompactor {
+    pub fn new(channels: usize, sample_rate: u32, keep_secs: f64, threshold_db: f64) -> Self {
+        let tail_frames = (sample_rate as u64 * TRIM_PADDING_MS / 1000) as usize;
+        Self {
+            channels,
+            threshold: trim::threshold(threshold_db) as u16,
+            keep_frames: (keep_secs.max(0.0) * sample_rate as f64) as u64,
+            tail: VecDeque::with_capacity(tail_frames * channels),
+            tail_frames,
+            position: 0,
+            file_frames: 0,
+            silent_run: 0,
+            elisions: Vec::new(),
+        }
+    }
+
+    /// Append what should be written of `samples` (interleaved, in recording order) to `out`
+    pub fn compact(&mut self, samples: &[i16], out: &mut Vec<i16>) {
+        for frame in samples.chunks_exact(self.channels) {
+            let silent = frame
+                .iter()
+                .all(|sample| sample.unsigned_abs() <= self.threshold);
+            if !silent {
+                self.flush_tail(out);
+                self.silent_run = 0;
+                out.extend_from_slice(frame);
+                self.file_frames += 1;
+            } else if self.silent_run < self.keep_frames {
+                self.silent_run += 1;
+                out.extend_from_slice(frame);
+                self.file_frames += 1;
+            } else {
+                self.silent_run += 1;
+                self.hold(frame);
+            }
+            self.position += 1;
+        }
+    }
+
+    /// Write out the held-back end of a pause the recording stopped in
+    pub fn finish(&mut self, out: &mut Vec<i16>) {
+        self.flush_tail(out);
+    }
+
+    /// Spans left out so far, in order
+    pub fn elisions(&self) -> &[Elision] {
+        &self.elisions
+    }
+
+    fn hold(&mut self, frame: &[i16]) {
+        if self.tail.len() < self.tail_frames * self.channels {
+            self.tail.extend(frame);
+            return;
+        }
+        // The oldest held frame is the one left out
+        self.tail.drain(..self.channels);
+        self.tail.extend(frame);
+        let first_dropped = self.position - self.tail_frames as u64;
+        match self.elisions.last_mut() {
+            Some(e) if e.sample_position + e.frames == first_dropped => e.frames += 1,
+            _ => self.elisions.push(Elision {
+                sample_position: first_dropped,
+                file_frame: self.file_frames,
+                frames: 1,
+            }),
+        }
+    }
+
+    fn flush_tail(&mut self, out: &mut Vec<i16>) {
+        self.file_frames += (self.tail.len() / self.channels) as u64;
+        out.extend(self.tail.drain(..));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1 kHz, so the half-second tail is 500 frames
+    fn compactor(keep_secs: f64) -> SilenceCompactor {
+        SilenceCompactor::new(2, 1_000, keep_secs, -40.0)
+    }
+
+    fn stereo(frames: &[(i16, i16)]) -> Vec<i16> {
+        frames.iter().flat_map(|&(l, r)| [l, r]).collect()
+    }
+
+    #[test]
+    fn long_pauses_keep_their_start_and_end() {
+        let mut compactor = compactor(2.0);
+        let mut input = stereo(&[(5_000, 0); 10]);
+        input.extend(stereo(&[(0, 3); 10_000]));
+        input.extend(stereo(&[(0, -5_000); 10]));
+
+        let mut out = Vec::new();
+        for chunk in input.chunks(998) {
+            compactor.compact(chunk, &mut out);
+        }
+        compactor.finish(&mut out);
+
+        assert_eq!(
+            compactor.elisions(),
+            [Elision {
+                sample_position: 2_010,
+                file_frame: 2_010,
+                frames: 7_500,
+            }]
+        );
+        assert_eq!(out.len(), (10 + 2_000 + 500 + 10) * 2);
+        assert_eq!(&out[out.len() - 22..out.len() - 20], &[0, 3]);
+        assert_eq!(&out[out.len() - 20..out.len() - 18], &[0, -5_000]);
+    }
+
+    #[test]
+    fn short_pauses_and_a_pause_at_the_end_are_kept_whole_or_padded() {
+        let mut short = compactor(2.0);
+        let input = stereo(&[(0, 0); 2_400]);
+        let mut out = Vec::new();
+        short.compact(&input, &mut out);
+        short.finish(&mut out);
+        assert_eq!(out, input);
+        assert!(short.elisions().is_empty());
+
+        let mut trailing = compactor(1.0);
+        let mut out = Vec::new();
+        trailing.compact(&stereo(&[(0, 0); 5_000]), &mut out);
+        trailing.finish(&mut out);
+        assert_eq!(out.len(), 1_500 * 2);
+        assert_eq!(trailing.elisions()[0].frames, 3_500);
+    }
+
+    #[test]
+    fn maps_session_positions_onto_the_compacted_file() {
+        let elisions = [
+            Elision {
+                sample_position: 100,
+                file_frame: 100,
+                frames: 50,
+            },
+            Elision {
+                sample_position: 300,
+                file_frame: 250,
+                frames: 200,
+            },
+        ];
+        assert_eq!(file_frame(&elisions, 99), 99);
+        assert_eq!(file_frame(&elisions, 120), 100);
+        assert_eq!(file_frame(&elisions, 150), 100);
+        assert_eq!(file_frame(&elisions, 200), 150);
+        assert_eq!(file_frame(&elisions, 600), 350);
+    }
+}
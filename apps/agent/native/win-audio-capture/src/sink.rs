@@ -0,0 +1,298 @@
+//! Recording sinks
+//! A sink receives the interleaved stream (left = MIC, right = loopback, optionally a third
+//! channel for an extra loopback endpoint) and owns everything about how it is stored. The
+//! capture loop only talks to `RecordingSink`, so adding a container means adding a module
+//! here rather than editing the main loop.
+
+// Without `codecs` only the tests drive the FLAC and Matroska writers
+#[cfg_attr(not(feature = "codecs"), allow(dead_code))]
+mod flac;
+#[cfg_attr(not(feature = "codecs"), allow(dead_code))]
+pub mod mka;
+mod opus;
+mod rf64;
+mod wav;
+
+use crate::error::fail;
+use crate::progress::Report;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub use flac::FlacSink;
+pub use mka::MkaSink;
+pub use rf64::Rf64Sink;
+pub use wav::WavSink;
+
+/// Container selected with `--format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// RIFF/WAVE, limited to 4 GiB
+    Wav,
+    /// EBU RF64, a 64-bit-size WAV for recordings past 4 GiB
+    Rf64,
+    /// Lossless FLAC, about half the size of WAV
+    Flac,
+    /// Opus in Ogg at `--opus-bitrate`
+    Opus,
+    /// Matroska audio: mic and loopback as separate PCM tracks, with chapters and tags
+    Mka,
+}
+
+impl OutputFormat {
+    /// Compressed recordings can't be rewritten in place the way WAV and RF64 are
+    pub fn is_compressed(self) -> bool {
+        matches!(self, OutputFormat::Flac | OutputFormat::Opus)
+    }
+
+    /// The only rates the format takes, for the ones that don't take any
+    pub fn sample_rates(self) -> Option<&'static [u32]> {
+        match self {
+            OutputFormat::Opus => Some(&opus::SAMPLE_RATES),
+            _ => None,
+        }
+    }
+
+    /// WAV and RF64 are read back for trimming, redaction, previews and per-track files
+    pub fn is_riff(self) -> bool {
+        matches!(self, OutputFormat::Wav | OutputFormat::Rf64)
+    }
+}
+
+/// Encoder settings of the compressed formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    /// Bits per second of the Opus stream
+    pub opus_bitrate: u32,
+}
+
+pub const DEFAULT_OPUS_BITRATE: u32 = 32_000;
+/// Bitrates libopus accepts
+pub const MIN_OPUS_BITRATE: u32 = 6_000;
+pub const MAX_OPUS_BITRATE: u32 = 510_000;
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self {
+            opus_bitrate: DEFAULT_OPUS_BITRATE,
+        }
+    }
+}
+
+/// Stream layout every sink is created with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// What a sink reports once it has been finalized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkSummary {
+    pub path: PathBuf,
+    /// Sample frames (one sample per channel) written
+    pub frames: u64,
+    /// Size of the finished file in bytes
+    pub bytes: u64,
+}
+
+/// Sinks are driven from a blocking worker thread of the runtime, hence `Send`
+pub trait RecordingSink: Send {
+    /// Append interleaved 16-bit samples; the length must be a multiple of the channel count
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()>;
+
+    /// Flush buffers and fix up headers. The file is only guaranteed to be readable after
+    /// this returns.
+    fn finalize(self: Box<Self>) -> Result<SinkSummary>;
+}
+
+/// Create the sink for `format` writing to `path`
+pub fn create(format: OutputFormat, path: &Path, spec: SinkSpec) -> Result<Box<dyn RecordingSink>> {
+    create_with(format, path, spec, Encoding::default())
+}
+
+/// `create` with the compressed formats' settings
+pub fn create_with(
+    format: OutputFormat,
+    path: &Path,
+    spec: SinkSpec,
+    encoding: Encoding,
+) -> Result<Box<dyn RecordingSink>> {
+    Ok(match format {
+        OutputFormat::Wav => Box::new(WavSink::create(path, spec)?),
+        OutputFormat::Rf64 => Box::new(Rf64Sink::create(path, spec)?),
+        #[cfg(feature = "codecs")]
+        OutputFormat::Flac => Box::new(FlacSink::create(path, spec)?),
+        #[cfg(feature = "codecs")]
+        OutputFormat::Mka => Box::new(MkaSink::create(path, spec)?),
+        #[cfg(not(feature = "codecs"))]
+        OutputFormat::Flac | OutputFormat::Mka => {
+            return Err(fail!(
+                Config,
+                "This build has no {:?} writer; build with --features codecs",
+                format
+            ))
+        }
+        OutputFormat::Opus => opus::create(path, spec, encoding.opus_bitrate)?,
+    })
+}
+
+/// Audio the WAV and RF64 sinks write between header updates: a killed process leaves a
+/// file that plays up to the last update, and `repair` recovers the rest
+pub(super) const CHECKPOINT_SECS: u64 = 1;
+
+/// Samples copied per step when rewriting a recording
+const REWRITE_CHUNK_SAMPLES: usize = 64 * 1024;
+
+/// Replace a finalized recording with `frames` frames of it starting at `start_frame`,
+/// written through a temp file so a failure leaves the original in place
+pub fn rewrite_range(
+    format: OutputFormat,
+    path: &Path,
+    spec: SinkSpec,
+    start_frame: u64,
+    frames: u64,
+    progress: Report,
+) -> Result<SinkSummary> {
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".trim.partial");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let written = copy_range(format, path, &tmp_path, spec, start_frame, frames, progress);
+    let mut summary = match written {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp_path, path).context("Failed to replace trimmed recording")?;
+    summary.path = path.to_path_buf();
+    Ok(summary)
+}
+
+fn copy_range(
+    format: OutputFormat,
+    path: &Path,
+    tmp_path: &Path,
+    spec: SinkSpec,
+    start_frame: u64,
+    frames: u64,
+    progress: Report,
+) -> Result<SinkSummary> {
+    if !format.is_riff() {
+        return Err(fail!(Config, "{:?} recordings can't be rewritten", format));
+    }
+    let mut out = create(format, tmp_path, spec)?;
+    let channels = spec.channels as usize;
+    let total = frames as usize * channels;
+    let mut remaining = total;
+    let mut chunk = Vec::with_capacity(REWRITE_CHUNK_SAMPLES);
+    match format {
+        OutputFormat::Wav => {
+            let mut reader =
+                hound::WavReader::open(path).context("Failed to reopen WAV for trimming")?;
+            reader.seek(start_frame as u32)?;
+            let mut samples = reader.samples::<i16>().take(remaining);
+            while remaining > 0 {
+                chunk.clear();
+                for sample in samples.by_ref().take(REWRITE_CHUNK_SAMPLES) {
+                    chunk.push(sample?);
+                }
+                if chunk.is_empty() {
+                    break;
+                }
+                remaining -= chunk.len();
+                out.write_samples(&chunk)?;
+                progress((total - remaining) as u64, total as u64)?;
+            }
+        }
+        OutputFormat::Flac | OutputFormat::Opus | OutputFormat::Mka => {
+            unreachable!("rejected above")
+        }
+        OutputFormat::Rf64 => {
+            let mut reader =
+                BufReader::new(File::open(path).context("Failed to reopen RF64 for trimming")?);
+            reader.seek(SeekFrom::Start(
+                rf64::HEADER_LEN + start_frame * channels as u64 * 2,
+            ))?;
+            let mut bytes = vec![0u8; REWRITE_CHUNK_SAMPLES * 2];
+            while remaining > 0 {
+                let len = remaining.min(REWRITE_CHUNK_SAMPLES);
+                reader.read_exact(&mut bytes[..len * 2])?;
+                chunk.clear();
+                chunk.extend(
+                    bytes[..len * 2]
+                        .chunks_exact(2)
+                        .map(|pair| i16::from_le_bytes([pair[0], pair[1]])),
+                );
+                remaining -= len;
+                out.write_samples(&chunk)?;
+                progress((total - remaining) as u64, total as u64)?;
+            }
+        }
+    }
+    out.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_range_keeps_the_requested_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        let samples: Vec<i16> = (0..20_000).map(|i| i as i16).collect();
+
+        for format in [OutputFormat::Wav, OutputFormat::Rf64] {
+            let path = dir.path().join(format!("{:?}.wav", format));
+            let mut sink = create(format, &path, spec).unwrap();
+            sink.write_samples(&samples).unwrap();
+            sink.finalize().unwrap();
+
+            let summary = rewrite_range(
+                format,
+                &path,
+                spec,
+                1_000,
+                3_000,
+                &mut crate::progress::ignore,
+            )
+            .unwrap();
+            assert_eq!(summary.frames, 3_000, "{:?}", format);
+            assert_eq!(summary.path, path);
+            assert_eq!(summary.bytes, std::fs::metadata(&path).unwrap().len());
+
+            let bytes = std::fs::read(&path).unwrap();
+            let data = &bytes[bytes.len() - 3_000 * 4..];
+            let first = i16::from_le_bytes([data[0], data[1]]);
+            let last = i16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+            assert_eq!((first, last), (2_000, 7_999), "{:?}", format);
+        }
+    }
+
+    #[cfg(not(feature = "codecs"))]
+    #[test]
+    fn builds_without_codecs_refuse_flac_and_matroska() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        for format in [OutputFormat::Flac, OutputFormat::Mka] {
+            let path = dir.path().join(format!("{:?}", format));
+            let error = create(format, &path, spec).err().unwrap();
+            assert_eq!(crate::error::kind(&error), crate::error::ErrorKind::Config);
+            assert!(!path.exists());
+        }
+    }
+}
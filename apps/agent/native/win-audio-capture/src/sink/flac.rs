@@ -0,0 +1,544 @@
+//! FLAC sink
+//! Blocks of `BLOCK_FRAMES` frames. Each channel is coded on its own: left = MIC and
+//! right = loopback carry different audio, so stereo decorrelation buys nothing. A silent
+//! channel becomes a constant subframe, anything else the cheapest of the fixed predictors
+//! with Rice-coded residuals (or verbatim samples when nothing beats them). `finalize` fills
+//! in STREAMINFO's sample count and frame sizes; a file cut short by a crash still decodes
+//! up to its last whole frame.
+//!
+//! Layout: `fLaC` | STREAMINFO | frames
+
+use super::{RecordingSink, SinkSpec, SinkSummary};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Frames per block, 85 ms at 48 kHz
+const BLOCK_FRAMES: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 6;
+/// Largest parameter of the 4-bit Rice coding method (15 is the escape code)
+const MAX_RICE_PARAMETER: u32 = 14;
+/// Offset of STREAMINFO's minimum frame size, after the block sizes
+const STREAMINFO_FRAME_SIZES: u64 = 4 + 4 + 4;
+
+pub struct FlacSink {
+    path: PathBuf,
+    channels: usize,
+    sample_rate: u32,
+    writer: BufWriter<File>,
+    /// Interleaved samples short of a whole block
+    pending: Vec<i16>,
+    frames: u64,
+    blocks: u64,
+    frame_sizes: Option<(u32, u32)>,
+}
+
+impl FlacSink {
+    pub fn create(path: &Path, spec: SinkSpec) -> Result<Self> {
+        let file = File::create(path).context("Failed to create output FLAC file")?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(b"fLaC")?;
+        // Last metadata block, type 0 (STREAMINFO), 34 bytes
+        writer.write_all(&[0x80, 0, 0, 34])?;
+        writer.write_all(&(BLOCK_FRAMES as u16).to_be_bytes())?;
+        writer.write_all(&(BLOCK_FRAMES as u16).to_be_bytes())?;
+        writer.write_all(&streaminfo_tail(spec.sample_rate, spec.channels, 0, None))?;
+        writer.write_all(&[0; 16])?; // MD5 left unset
+        Ok(Self {
+            path: path.to_path_buf(),
+            channels: spec.channels as usize,
+            sample_rate: spec.sample_rate,
+            writer,
+            pending: Vec::with_capacity(BLOCK_FRAMES * spec.channels as usize),
+            frames: 0,
+            blocks: 0,
+            frame_sizes: None,
+        })
+    }
+
+    fn write_block(&mut self, samples: &[i16]) -> Result<()> {
+        let frame = encode_frame(samples, self.channels, self.blocks);
+        self.writer
+            .write_all(&frame)
+            .context("Failed to write FLAC frame")?;
+        let size = frame.len() as u32;
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size),
+        });
+        self.frames += (samples.len() / self.channels) as u64;
+        self.blocks += 1;
+        Ok(())
+    }
+}
+
+impl RecordingSink for FlacSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        self.pending.extend_from_slice(samples);
+        let block = BLOCK_FRAMES * self.channels;
+        if self.pending.len() >= block {
+            let pending = std::mem::take(&mut self.pending);
+            let mut blocks = pending.chunks_exact(block);
+            for samples in blocks.by_ref() {
+                self.write_block(samples)?;
+            }
+            self.pending = blocks.remainder().to_vec();
+        }
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<SinkSummary> {
+        let mut this = *self;
+        if !this.pending.is_empty() {
+            let rest = std::mem::take(&mut this.pending);
+            this.write_block(&rest)?;
+        }
+        let tail = streaminfo_tail(
+            this.sample_rate,
+            this.channels as u16,
+            this.frames,
+            this.frame_sizes,
+        );
+        this.writer
+            .seek(SeekFrom::Start(STREAMINFO_FRAME_SIZES))
+            .and_then(|_| this.writer.write_all(&tail))
+            .and_then(|_| this.writer.flush())
+            .context("Failed to finalize FLAC file")?;
+        let bytes = std::fs::metadata(&this.path)?.len();
+        Ok(SinkSummary {
+            path: this.path,
+            frames: this.frames,
+            bytes,
+        })
+    }
+}
+
+/// STREAMINFO from the frame sizes on: min/max frame size (0 when unknown), then rate,
+/// channels, sample size and total frames packed into 64 bits
+fn streaminfo_tail(
+    sample_rate: u32,
+    channels: u16,
+    frames: u64,
+    frame_sizes: Option<(u32, u32)>,
+) -> [u8; 14] {
+    let (min, max) = frame_sizes.unwrap_or((0, 0));
+    let packed = (sample_rate as u64) << 44
+        | ((channels as u64 - 1) << 41)
+        | ((BITS_PER_SAMPLE as u64 - 1) << 36)
+        | (frames & ((1 << 36) - 1));
+    let mut tail = [0u8; 14];
+    tail[..3].copy_from_slice(&min.to_be_bytes()[1..]);
+    tail[3..6].copy_from_slice(&max.to_be_bytes()[1..]);
+    tail[6..].copy_from_slice(&packed.to_be_bytes());
+    tail
+}
+
+/// One FLAC frame holding the interleaved `samples`, numbered `number`
+fn encode_frame(samples: &[i16], channels: usize, number: u64) -> Vec<u8> {
+    let block = samples.len() / channels;
+    let mut out = BitWriter::default();
+    // Sync code, fixed block size
+    out.write(0xFFF8, 16);
+    let size_code = if block == BLOCK_FRAMES {
+        0b1100
+    } else {
+        0b0111
+    };
+    // Sample rate taken from STREAMINFO
+    out.write(size_code << 4, 8);
+    // Independent channels, 16-bit samples
+    out.write(((channels as u64 - 1) << 4) | 0b1000, 8);
+    write_utf8_number(&mut out, number);
+    if size_code == 0b0111 {
+        out.write(block as u64 - 1, 16);
+    }
+    let header_crc = crc8(&out.bytes);
+    out.write(header_crc as u64, 8);
+
+    let mut channel = Vec::with_capacity(block);
+    for c in 0..channels {
+        channel.clear();
+        channel.extend(samples.iter().skip(c).step_by(channels).map(|&s| s as i32));
+        write_subframe(&mut out, &channel);
+    }
+    out.align();
+    let crc = crc16(&out.bytes);
+    out.write(crc as u64, 16);
+    out.bytes
+}
+
+/// Frame numbers are coded like UTF-8 code points, up to 36 bits
+fn write_utf8_number(out: &mut BitWriter, number: u64) {
+    if number < 0x80 {
+        out.write(number, 8);
+        return;
+    }
+    let continuation = (1..=6).find(|&n| number < 1 << (5 * n + 6)).unwrap_or(6);
+    let marker = (0xFF00u64 >> (continuation + 1)) & 0xFF;
+    out.write(marker | (number >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((number >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn write_subframe(out: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        out.write(0, 8);
+        out.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let coding = rice_coding(&residual, samples.len(), order);
+            (order, residual, coding)
+        })
+        .min_by_key(|(order, _, coding)| warmup_bits(*order).saturating_add(coding.bits));
+    match best {
+        Some((order, residual, coding))
+            if warmup_bits(order).saturating_add(coding.bits) < verbatim_bits =>
+        {
+            out.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &sample in &samples[..order] {
+                out.write_signed(sample, BITS_PER_SAMPLE);
+            }
+            write_residual(out, &residual, samples.len(), order, &coding);
+        }
+        _ => {
+            out.write(0b0000_0010, 8);
+            for &sample in samples {
+                out.write_signed(sample, BITS_PER_SAMPLE);
+            }
+        }
+    }
+}
+
+fn warmup_bits(order: usize) -> u64 {
+    order as u64 * BITS_PER_SAMPLE as u64
+}
+
+/// Residual of the fixed polynomial predictor of `order`, from sample `order` on
+fn fixed_residual(x: &[i32], order: usize) -> Vec<i32> {
+    (order..x.len())
+        .map(|n| match order {
+            0 => x[n],
+            1 => x[n] - x[n - 1],
+            2 => x[n] - 2 * x[n - 1] + x[n - 2],
+            3 => x[n] - 3 * x[n - 1] + 3 * x[n - 2] - x[n - 3],
+            _ => x[n] - 4 * x[n - 1] + 6 * x[n - 2] - 4 * x[n - 3] + x[n - 4],
+        })
+        .collect()
+}
+
+/// How a residual is partitioned and the Rice parameter of each partition
+struct RiceCoding {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    /// Size of the residual section in bits
+    bits: u64,
+}
+
+fn zigzag(r: i32) -> u32 {
+    ((r << 1) ^ (r >> 31)) as u32
+}
+
+/// Bits `values` take Rice-coded with parameter `k`
+fn rice_bits(values: &[u32], k: u32) -> u64 {
+    values.len() as u64 * (k as u64 + 1) + values.iter().map(|&u| (u >> k) as u64).sum::<u64>()
+}
+
+/// Cheapest parameter for `values`, searched around the one their mean suggests
+fn best_parameter(values: &[u32]) -> (u32, u64) {
+    let mean = values.iter().map(|&u| u as u64).sum::<u64>() / values.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+    (guess.saturating_sub(1)..=(guess + 1).min(MAX_RICE_PARAMETER))
+        .map(|k| (k, rice_bits(values, k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+fn rice_coding(residual: &[i32], block: usize, order: usize) -> RiceCoding {
+    let values: Vec<u32> = residual.iter().map(|&r| zigzag(r)).collect();
+    let mut best: Option<RiceCoding> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        if !block.is_multiple_of(partitions) || block / partitions <= order {
+            break;
+        }
+        let mut parameters = Vec::with_capacity(partitions);
+        // Coding method and partition order
+        let mut bits = 2 + 4;
+        let mut start = 0;
+        for p in 0..partitions {
+            let len = block / partitions - if p == 0 { order } else { 0 };
+            let (k, partition_bits) = best_parameter(&values[start..start + len]);
+            parameters.push(k);
+            bits += 4 + partition_bits;
+            start += len;
+        }
+        if best.as_ref().is_none_or(|best| bits < best.bits) {
+            best = Some(RiceCoding {
+                partition_order,
+                parameters,
+                bits,
+            });
+        }
+    }
+    best.unwrap_or(RiceCoding {
+        partition_order: 0,
+        parameters: vec![MAX_RICE_PARAMETER],
+        bits: u64::MAX,
+    })
+}
+
+fn write_residual(
+    out: &mut BitWriter,
+    residual: &[i32],
+    block: usize,
+    order: usize,
+    coding: &RiceCoding,
+) {
+    out.write(0, 2);
+    out.write(coding.partition_order as u64, 4);
+    let partitions = 1usize << coding.partition_order;
+    let mut values = residual.iter().map(|&r| zigzag(r));
+    for (p, &k) in coding.parameters.iter().enumerate() {
+        out.write(k as u64, 4);
+        let len = block / partitions - if p == 0 { order } else { 0 };
+        for u in values.by_ref().take(len) {
+            out.write_unary(u >> k);
+            out.write((u & ((1 << k) - 1)) as u64, k);
+        }
+    }
+}
+
+/// Big-endian bit packing
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Append the low `n` bits of `value`, `n` at most 32
+    fn write(&mut self, value: u64, n: u32) {
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, n: u32) {
+        self.write(value as u32 as u64, n);
+    }
+
+    /// `q` zeros and a one
+    fn write_unary(&mut self, mut q: u32) {
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back what `encode_frame` writes
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        bit: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, n: u32) -> u64 {
+            let mut value = 0;
+            for _ in 0..n {
+                let byte = self.bytes[self.bit / 8];
+                value = (value << 1) | ((byte >> (7 - self.bit % 8)) & 1) as u64;
+                self.bit += 1;
+            }
+            value
+        }
+
+        fn read_signed(&mut self, n: u32) -> i32 {
+            let value = self.read(n) as i64;
+            (if value >> (n - 1) != 0 {
+                value - (1 << n)
+            } else {
+                value
+            }) as i32
+        }
+    }
+
+    /// Decode a file written by `FlacSink` into interleaved samples
+    fn decode(file: &[u8]) -> (u32, usize, u64, Vec<i16>) {
+        assert_eq!(&file[..4], b"fLaC");
+        let info = u64::from_be_bytes(file[18..26].try_into().unwrap());
+        let (rate, channels, total) = (
+            (info >> 44) as u32,
+            ((info >> 41) & 7) as usize + 1,
+            info & ((1 << 36) - 1),
+        );
+        let mut reader = BitReader {
+            bytes: file,
+            bit: 42 * 8,
+        };
+        let mut samples = Vec::new();
+        while reader.bit / 8 < file.len() {
+            let start = reader.bit / 8;
+            assert_eq!(reader.read(16), 0xFFF8);
+            let size_code = reader.read(4);
+            reader.read(4);
+            assert_eq!(reader.read(4) as usize, channels - 1);
+            reader.read(4);
+            let continuation = match (reader.read(8) as u8).leading_ones() {
+                0 => 0,
+                ones => ones - 1,
+            };
+            reader.read(8 * continuation);
+            let block = match size_code {
+                0b1100 => BLOCK_FRAMES,
+                _ => reader.read(16) as usize + 1,
+            };
+            let header_end = reader.bit / 8;
+            assert_eq!(reader.read(8) as u8, crc8(&file[start..header_end]));
+            let mut decoded = vec![Vec::new(); channels];
+            for channel in decoded.iter_mut() {
+                assert_eq!(reader.read(1), 0);
+                let kind = reader.read(6);
+                reader.read(1);
+                match kind {
+                    0 => *channel = vec![reader.read_signed(16); block],
+                    1 => *channel = (0..block).map(|_| reader.read_signed(16)).collect(),
+                    _ => {
+                        let order = (kind & 7) as usize;
+                        *channel = (0..order).map(|_| reader.read_signed(16)).collect();
+                        assert_eq!(reader.read(2), 0);
+                        let partitions = 1usize << reader.read(4);
+                        for p in 0..partitions {
+                            let k = reader.read(4) as u32;
+                            let len = block / partitions - if p == 0 { order } else { 0 };
+                            for _ in 0..len {
+                                let mut q = 0;
+                                while reader.read(1) == 0 {
+                                    q += 1;
+                                }
+                                let u = (q << k) | reader.read(k) as u32;
+                                let r = ((u >> 1) as i32) ^ -((u & 1) as i32);
+                                let x = &mut *channel;
+                                let n = x.len();
+                                let predicted = match order {
+                                    0 => 0,
+                                    1 => x[n - 1],
+                                    2 => 2 * x[n - 1] - x[n - 2],
+                                    3 => 3 * x[n - 1] - 3 * x[n - 2] + x[n - 3],
+                                    _ => 4 * x[n - 1] - 6 * x[n - 2] + 4 * x[n - 3] - x[n - 4],
+                                };
+                                x.push(predicted + r);
+                            }
+                        }
+                    }
+                }
+            }
+            reader.bit = reader.bit.div_ceil(8) * 8;
+            let end = reader.bit / 8;
+            assert_eq!(reader.read(16) as u16, crc16(&file[start..end]));
+            for i in 0..block {
+                samples.extend(decoded.iter().map(|channel| channel[i] as i16));
+            }
+        }
+        (rate, channels, total, samples)
+    }
+
+    #[test]
+    fn round_trips_through_a_decoder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.flac");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        // Speech-like tone on the MIC, silence and then full-scale noise on the loopback,
+        // a block and a bit long
+        let mut rng = 7u32;
+        let samples: Vec<i16> = (0..(BLOCK_FRAMES * 2 + 123))
+            .flat_map(|i| {
+                rng = rng.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let mic = ((i as f32 * 0.05).sin() * 12_000.0) as i16;
+                let loopback = if i < BLOCK_FRAMES {
+                    0
+                } else {
+                    (rng >> 16) as i16
+                };
+                [mic, loopback]
+            })
+            .collect();
+
+        let mut sink = Box::new(FlacSink::create(&path, spec).unwrap());
+        sink.write_samples(&samples[..5_000]).unwrap();
+        sink.write_samples(&samples[5_000..]).unwrap();
+        let summary = sink.finalize().unwrap();
+        assert_eq!(summary.frames, (BLOCK_FRAMES * 2 + 123) as u64);
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(summary.bytes, file.len() as u64);
+        assert!(file.len() < samples.len() * 2);
+        let (rate, channels, total, decoded) = decode(&file);
+        assert_eq!((rate, channels, total), (48_000, 2, summary.frames));
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn checksums_and_frame_numbers_match_the_spec() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        let mut out = BitWriter::default();
+        write_utf8_number(&mut out, 0x7FF);
+        write_utf8_number(&mut out, 0x800);
+        assert_eq!(out.bytes, [0xDF, 0xBF, 0xE0, 0xA0, 0x80]);
+    }
+}
@@ -0,0 +1,603 @@
+//! Matroska audio sink
+//! Each channel of the stream becomes its own mono PCM track (`mic`, `loopback`, then
+//! `channel 3`), so players and the transcription side get the speakers apart without
+//! splitting a stereo file, and chapters and tags travel inside the recording.
+//!
+//! Layout: EBML header | Segment (size unknown until finalized) { SeekHead + Void |
+//! Info (duration patched at finalize) | Tracks | Cluster... | Chapters | Tags }
+//!
+//! Clusters are a second of audio, a SimpleBlock per track every 100 ms, and are written
+//! whole, so a file whose sidecar died plays up to the last cluster. Chapters and tags are
+//! only known once the session is over; `append` adds them after the clusters and points
+//! the SeekHead at them.
+
+use super::{RecordingSink, SinkSpec, SinkSummary, CHECKPOINT_SECS};
+use crate::error::fail;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const VOID: u32 = 0xEC;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const NAME: u32 = 0x536E;
+const CODEC_ID: u32 = 0x86;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CHAPTERS: u32 = 0x1043_A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_UID: u32 = 0x73C4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+/// Nanoseconds per Block and Cluster timestamp unit: milliseconds
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+/// Track type of audio tracks
+const TRACK_TYPE_AUDIO: u64 = 2;
+/// SimpleBlock flag of blocks every frame of which is a keyframe
+const KEYFRAME: u8 = 0x80;
+/// Blocks per Cluster, each a tenth of a second
+const BLOCKS_PER_CLUSTER: u64 = 10;
+/// Bytes kept at the start of the Segment for the SeekHead, which grows when chapters and
+/// tags are appended
+const SEEK_HEAD_SPACE: usize = 160;
+/// Segment size of a file still being written
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+/// Name of the track carrying `channel`
+pub fn track_name(channel: u16) -> String {
+    match channel {
+        0 => "mic".to_string(),
+        1 => "loopback".to_string(),
+        n => format!("channel {}", n + 1),
+    }
+}
+
+pub struct MkaSink {
+    path: PathBuf,
+    channels: usize,
+    sample_rate: u32,
+    writer: BufWriter<File>,
+    /// File offset of the Segment's contents, what SeekHead positions count from
+    segment_start: u64,
+    /// File offset of the Duration value
+    duration_offset: u64,
+    /// Interleaved samples of the Cluster being filled
+    pending: Vec<i16>,
+    cluster_frames: usize,
+    frames: u64,
+}
+
+impl MkaSink {
+    pub fn create(path: &Path, spec: SinkSpec) -> Result<Self> {
+        let file = File::create(path).context("Failed to create output MKA file")?;
+        let mut writer = BufWriter::new(file);
+
+        let mut head = ebml_header();
+        head.extend(id_bytes(SEGMENT));
+        head.extend(UNKNOWN_SIZE);
+        let segment_start = head.len() as u64;
+
+        let info = info();
+        let tracks = tracks(spec);
+        let info_position = SEEK_HEAD_SPACE as u64;
+        let tracks_position = info_position + info.len() as u64;
+        head.extend(seek_head(&[
+            (INFO, info_position),
+            (TRACKS, tracks_position),
+        ])?);
+        // Duration is the last child of Info, its float the last 8 bytes
+        let duration_offset = head.len() as u64 + info.len() as u64 - 8;
+        head.extend(info);
+        head.extend(tracks);
+        writer
+            .write_all(&head)
+            .context("Failed to write MKA header")?;
+
+        let cluster_frames = (CHECKPOINT_SECS * spec.sample_rate as u64) as usize;
+        Ok(Self {
+            path: path.to_path_buf(),
+            channels: spec.channels as usize,
+            sample_rate: spec.sample_rate,
+            writer,
+            segment_start,
+            duration_offset,
+            pending: Vec::with_capacity(cluster_frames * spec.channels as usize),
+            cluster_frames,
+            frames: 0,
+        })
+    }
+
+    /// Write the pending samples out as one Cluster
+    fn write_cluster(&mut self) -> std::io::Result<()> {
+        let frames = self.pending.len() / self.channels;
+        if frames == 0 {
+            return Ok(());
+        }
+        let start = self.frames;
+        let rate = self.sample_rate as u64;
+        let cluster_ms = start * 1_000 / rate;
+        let block_frames = (self.cluster_frames as u64 / BLOCKS_PER_CLUSTER).max(1) as usize;
+
+        let mut cluster = uint(TIMESTAMP, cluster_ms);
+        for (index, block) in self
+            .pending
+            .chunks(block_frames * self.channels)
+            .enumerate()
+        {
+            let block_ms = (start + (index * block_frames) as u64) * 1_000 / rate;
+            let relative = (block_ms - cluster_ms) as i16;
+            for channel in 0..self.channels {
+                let mut data = size_vint(channel as u64 + 1);
+                data.extend(relative.to_be_bytes());
+                data.push(KEYFRAME);
+                for sample in block.iter().skip(channel).step_by(self.channels) {
+                    data.extend(sample.to_le_bytes());
+                }
+                cluster.extend(element(SIMPLE_BLOCK, &data));
+            }
+        }
+        self.writer.write_all(&element(CLUSTER, &cluster))?;
+        self.writer.flush()?;
+        self.frames += frames as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl RecordingSink for MkaSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        let cluster_samples = self.cluster_frames * self.channels;
+        let mut rest = samples;
+        while !rest.is_empty() {
+            let take = rest.len().min(cluster_samples - self.pending.len());
+            self.pending.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.pending.len() == cluster_samples {
+                self.write_cluster()
+                    .context("Failed to write MKA cluster")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<SinkSummary> {
+        self.write_cluster()
+            .context("Failed to write MKA cluster")?;
+        let duration_ms = self.frames as f64 * 1_000.0 / self.sample_rate as f64;
+        let segment_start = self.segment_start;
+        let duration_offset = self.duration_offset;
+        let writer = &mut self.writer;
+        let end = (|| -> std::io::Result<u64> {
+            let end = writer.seek(SeekFrom::End(0))?;
+            writer.seek(SeekFrom::Start(duration_offset))?;
+            writer.write_all(&duration_ms.to_be_bytes())?;
+            writer.seek(SeekFrom::Start(segment_start - 8))?;
+            writer.write_all(&size_vint8(end - segment_start))?;
+            writer.flush()?;
+            Ok(end)
+        })()
+        .context("Failed to finalize MKA")?;
+
+        Ok(SinkSummary {
+            path: self.path.clone(),
+            frames: self.frames,
+            bytes: end,
+        })
+    }
+}
+
+/// Add chapters (frame, title) and tags (name, value) to a finalized recording, after its
+/// last Cluster. Returns the new size of the file.
+pub fn append(
+    path: &Path,
+    chapters: &[(u64, String)],
+    tags: &[(&str, String)],
+    sample_rate: u32,
+) -> Result<u64> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("Failed to reopen MKA for chapters and tags")?;
+    let mut head = Vec::new();
+    (&mut file)
+        .take(64 + SEEK_HEAD_SPACE as u64)
+        .read_to_end(&mut head)?;
+
+    let (segment_start, seeks) = parse_head(&head).ok_or_else(|| {
+        fail!(
+            Format,
+            "{} is not a Matroska file written by this sidecar",
+            path.display()
+        )
+    })?;
+    let mut seeks: Vec<(u32, u64)> = seeks
+        .into_iter()
+        .filter(|(id, _)| *id != CHAPTERS && *id != TAGS)
+        .collect();
+
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut appended = Vec::new();
+    if !chapters.is_empty() {
+        seeks.push((CHAPTERS, end - segment_start));
+        let edition = chapters
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (frame, title))| {
+                let start_ns = frame * 1_000_000_000 / sample_rate as u64;
+                let atom = [
+                    uint(CHAPTER_UID, index as u64 + 1),
+                    uint(CHAPTER_TIME_START, start_ns),
+                    element(CHAPTER_DISPLAY, &string(CHAP_STRING, title)),
+                ]
+                .concat();
+                element(CHAPTER_ATOM, &atom)
+            })
+            .collect::<Vec<u8>>();
+        let chapters = element(CHAPTERS, &element(EDITION_ENTRY, &edition));
+        end += chapters.len() as u64;
+        appended.extend(chapters);
+    }
+    if !tags.is_empty() {
+        seeks.push((TAGS, end - segment_start));
+        let mut tag = element(TARGETS, &[]);
+        for (name, value) in tags {
+            tag.extend(element(
+                SIMPLE_TAG,
+                &[string(TAG_NAME, name), string(TAG_STRING, value)].concat(),
+            ));
+        }
+        let tags = element(TAGS, &element(TAG, &tag));
+        end += tags.len() as u64;
+        appended.extend(tags);
+    }
+
+    file.write_all(&appended)?;
+    file.seek(SeekFrom::Start(segment_start))?;
+    file.write_all(&seek_head(&seeks)?)?;
+    file.seek(SeekFrom::Start(segment_start - 8))?;
+    file.write_all(&size_vint8(end - segment_start))?;
+    file.sync_all()?;
+    Ok(end)
+}
+
+fn ebml_header() -> Vec<u8> {
+    let header = [
+        uint(EBML_VERSION, 1),
+        uint(EBML_READ_VERSION, 1),
+        uint(EBML_MAX_ID_LENGTH, 4),
+        uint(EBML_MAX_SIZE_LENGTH, 8),
+        string(DOC_TYPE, "matroska"),
+        uint(DOC_TYPE_VERSION, 4),
+        uint(DOC_TYPE_READ_VERSION, 2),
+    ]
+    .concat();
+    element(EBML, &header)
+}
+
+fn info() -> Vec<u8> {
+    let app = concat!("win-audio-capture ", env!("CARGO_PKG_VERSION"));
+    let info = [
+        uint(TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS),
+        string(MUXING_APP, app),
+        string(WRITING_APP, app),
+        float(DURATION, 0.0),
+    ]
+    .concat();
+    element(INFO, &info)
+}
+
+fn tracks(spec: SinkSpec) -> Vec<u8> {
+    let entries = (0..spec.channels)
+        .flat_map(|channel| {
+            let number = channel as u64 + 1;
+            let audio = [
+                float(SAMPLING_FREQUENCY, spec.sample_rate as f64),
+                uint(CHANNELS, 1),
+                uint(BIT_DEPTH, 16),
+            ]
+            .concat();
+            let entry = [
+                uint(TRACK_NUMBER, number),
+                uint(TRACK_UID, number),
+                uint(TRACK_TYPE, TRACK_TYPE_AUDIO),
+                uint(FLAG_LACING, 0),
+                string(NAME, &track_name(channel)),
+                string(CODEC_ID, "A_PCM/INT/LIT"),
+                element(AUDIO, &audio),
+            ]
+            .concat();
+            element(TRACK_ENTRY, &entry)
+        })
+        .collect::<Vec<u8>>();
+    element(TRACKS, &entries)
+}
+
+/// SeekHead pointing at `(element ID, position in the Segment)`, padded with a Void to
+/// `SEEK_HEAD_SPACE`
+fn seek_head(seeks: &[(u32, u64)]) -> std::io::Result<Vec<u8>> {
+    let entries = seeks
+        .iter()
+        .flat_map(|(id, position)| {
+            let seek = [
+                element(SEEK_ID, &id_bytes(*id)),
+                element(SEEK_POSITION, &position.to_be_bytes()),
+            ]
+            .concat();
+            element(SEEK, &seek)
+        })
+        .collect::<Vec<u8>>();
+    let mut head = element(SEEK_HEAD, &entries);
+    // A Void with a one-byte size makes up the rest
+    let padding = SEEK_HEAD_SPACE
+        .checked_sub(head.len() + 2)
+        .filter(|padding| *padding < 127)
+        .ok_or_else(|| std::io::Error::other("MKA SeekHead doesn't fit its space"))?;
+    head.extend(element(VOID, &vec![0; padding]));
+    Ok(head)
+}
+
+/// Segment start and SeekHead entries of a file `MkaSink` wrote
+fn parse_head(bytes: &[u8]) -> Option<(u64, Vec<(u32, u64)>)> {
+    let (id, header, size) = read_element(bytes)?;
+    if id != EBML {
+        return None;
+    }
+    let rest = &bytes[header + size as usize..];
+    let (id, segment_header, _) = read_element(rest)?;
+    if id != SEGMENT || segment_header != 12 {
+        return None;
+    }
+    let segment_start = header + size as usize + segment_header;
+    let (id, seek_header, seek_size) = read_element(&bytes[segment_start..])?;
+    if id != SEEK_HEAD {
+        return None;
+    }
+    let entries_start = segment_start + seek_header;
+    let entries = bytes.get(entries_start..entries_start + seek_size as usize)?;
+    let seeks = children(entries)?
+        .into_iter()
+        .map(|(_, seek)| {
+            let fields = children(seek)?;
+            let field = |wanted| fields.iter().find(|(id, _)| *id == wanted).map(|(_, v)| *v);
+            let id = field(SEEK_ID)?
+                .iter()
+                .fold(0u32, |id, b| id << 8 | *b as u32);
+            let position = field(SEEK_POSITION)?
+                .iter()
+                .fold(0u64, |position, b| position << 8 | *b as u64);
+            Some((id, position))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((segment_start as u64, seeks))
+}
+
+/// `(ID, payload)` of the elements `bytes` is made of
+fn children(mut bytes: &[u8]) -> Option<Vec<(u32, &[u8])>> {
+    let mut found = Vec::new();
+    while !bytes.is_empty() {
+        let (id, header, size) = read_element(bytes)?;
+        let end = header.checked_add(usize::try_from(size).ok()?)?;
+        found.push((id, bytes.get(header..end)?));
+        bytes = &bytes[end..];
+    }
+    Some(found)
+}
+
+/// ID, header length and payload size of the element at the start of `bytes`
+fn read_element(bytes: &[u8]) -> Option<(u32, usize, u64)> {
+    let id_len = bytes.first()?.leading_zeros() as usize + 1;
+    if id_len > 4 {
+        return None;
+    }
+    let id = bytes
+        .get(..id_len)?
+        .iter()
+        .fold(0u32, |id, b| id << 8 | *b as u32);
+    let size_bytes = &bytes[id_len..];
+    let size_len = size_bytes.first()?.leading_zeros() as usize + 1;
+    if size_len > 8 {
+        return None;
+    }
+    let size = size_bytes
+        .get(..size_len)?
+        .iter()
+        .fold(0u64, |size, b| size << 8 | *b as u64)
+        & (u64::MAX >> (64 - 7 * size_len));
+    Some((id, id_len + size_len, size))
+}
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8) as usize;
+    bytes[skip..].to_vec()
+}
+
+/// Shortest EBML size of `size`
+fn size_vint(size: u64) -> Vec<u8> {
+    // All ones is reserved for "unknown"
+    let len = (1..=8)
+        .find(|len| size < (1u64 << (7 * len)) - 1)
+        .unwrap_or(8);
+    let marked = size | 1 << (7 * len);
+    marked.to_be_bytes()[8 - len..].to_vec()
+}
+
+/// 8-byte EBML size, for sizes patched in place
+fn size_vint8(size: u64) -> [u8; 8] {
+    let mut bytes = size.to_be_bytes();
+    bytes[0] = 0x01;
+    bytes
+}
+
+fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = id_bytes(id);
+    bytes.extend(size_vint(payload.len() as u64));
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn uint(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = ((value.leading_zeros() / 8) as usize).min(7);
+    element(id, &bytes[skip..])
+}
+
+fn float(id: u32, value: f64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+fn string(id: u32, value: &str) -> Vec<u8> {
+    element(id, value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(bytes: &[u8], id: u32) -> Vec<&[u8]> {
+        children(bytes)
+            .unwrap()
+            .into_iter()
+            .filter(|(found, _)| *found == id)
+            .map(|(_, payload)| payload)
+            .collect()
+    }
+
+    #[test]
+    fn encodes_ebml_sizes() {
+        assert_eq!(size_vint(0), [0x80]);
+        assert_eq!(size_vint(126), [0xFE]);
+        // 127 would read as unknown in one byte
+        assert_eq!(size_vint(127), [0x40, 0x7F]);
+        assert_eq!(size_vint8(5), [0x01, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(
+            read_element(&element(CLUSTER, &[0; 300])),
+            Some((CLUSTER, 6, 300))
+        );
+        assert_eq!(
+            read_element(&uint(TRACK_NUMBER, 0)),
+            Some((TRACK_NUMBER, 2, 1))
+        );
+    }
+
+    #[test]
+    fn writes_one_track_per_channel_with_chapters_and_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.mka");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        // Mic counts up, loopback counts down, 1.5 s of it
+        let samples: Vec<i16> = (0..1_500).flat_map(|i| [i as i16, -(i as i16)]).collect();
+
+        let mut sink = Box::new(MkaSink::create(&path, spec).unwrap());
+        sink.write_samples(&samples[..700]).unwrap();
+        sink.write_samples(&samples[700..]).unwrap();
+        let summary = sink.finalize().unwrap();
+        assert_eq!(summary.frames, 1_500);
+        assert_eq!(summary.bytes, std::fs::metadata(&path).unwrap().len());
+
+        let chapters = [(0, "Intro".to_string()), (1_250, "Pricing".to_string())];
+        let size = append(&path, &chapters, &[("SESSION", "s-1".to_string())], 1_000).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(size, bytes.len() as u64);
+        let top = children(&bytes).unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(child(top[0].1, DOC_TYPE), [b"matroska"]);
+        let segment = top[1].1;
+        let ids: Vec<u32> = children(segment)
+            .unwrap()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(
+            ids,
+            [SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CHAPTERS, TAGS]
+        );
+
+        // The SeekHead finds each top-level element
+        let (_, seeks) = parse_head(&bytes).unwrap();
+        let segment_start = bytes.len() - segment.len();
+        for (id, position) in &seeks {
+            let at = &bytes[segment_start + *position as usize..];
+            assert_eq!(read_element(at).unwrap().0, *id);
+        }
+        assert_eq!(seeks.len(), 4);
+
+        let info = child(segment, INFO)[0];
+        let duration = f64::from_be_bytes(child(info, DURATION)[0].try_into().unwrap());
+        assert_eq!(duration, 1_500.0);
+
+        let entries = child(child(segment, TRACKS)[0], TRACK_ENTRY);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(child(entries[1], NAME), [b"loopback"]);
+
+        // Each track gets its own channel back
+        let mut tracks = [Vec::new(), Vec::new()];
+        for cluster in child(segment, CLUSTER) {
+            for block in child(cluster, SIMPLE_BLOCK) {
+                let track = (block[0] & 0x7F) as usize - 1;
+                tracks[track].extend(
+                    block[4..]
+                        .chunks_exact(2)
+                        .map(|pair| i16::from_le_bytes([pair[0], pair[1]])),
+                );
+            }
+        }
+        assert_eq!(tracks[0], (0..1_500).map(|i| i as i16).collect::<Vec<_>>());
+        assert_eq!(
+            tracks[1],
+            (0..1_500).map(|i| -(i as i16)).collect::<Vec<_>>()
+        );
+
+        let edition = child(child(segment, CHAPTERS)[0], EDITION_ENTRY)[0];
+        let atoms = child(edition, CHAPTER_ATOM);
+        assert_eq!(
+            child(atoms[1], CHAPTER_TIME_START),
+            [&1_250_000_000u64.to_be_bytes()[4..]]
+        );
+        let tag = child(child(segment, TAGS)[0], TAG)[0];
+        let simple = child(tag, SIMPLE_TAG)[0];
+        assert_eq!(child(simple, TAG_STRING), [b"s-1"]);
+    }
+}
@@ -0,0 +1,516 @@
+//! Opus-in-Ogg sink (RFC 7845)
+//! 20 ms packets from a `PacketEncoder`, about 50 to an Ogg page. The encoder is libopus,
+//! linked statically with the `opus` feature (`OPUS_LIB_DIR` names the directory holding
+//! `opus.lib`/`libopus.a`); builds without it refuse `--format opus`. Opus takes 8, 12, 16,
+//! 24 or 48 kHz and, with the channel mapping used here, one or two channels (left = MIC,
+//! right = loopback, as in every other format).
+//!
+//! Granule positions count 48 kHz samples whatever the input rate, pre-skip included; the
+//! last page's marks where the recording ends inside the final, padded packet.
+
+// Without an encoder only the tests drive the Ogg framing
+#![cfg_attr(not(feature = "opus"), allow(dead_code))]
+
+use super::{RecordingSink, SinkSpec, SinkSummary};
+use crate::error::fail;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Input rates Opus encodes
+pub(super) const SAMPLE_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+const MAX_CHANNELS: u16 = 2;
+
+const PACKET_MS: u32 = 20;
+const GRANULE_RATE: u32 = 48_000;
+/// Packets collected before a page is written out, a second of audio
+const PACKETS_PER_PAGE: usize = 50;
+/// Largest packet libopus is asked for (its recommended bound)
+const MAX_PACKET_BYTES: usize = 4_000;
+
+/// Turns one packet's worth of interleaved samples into an Opus packet
+pub trait PacketEncoder: Send {
+    fn encode(&mut self, pcm: &[i16], packet: &mut Vec<u8>) -> Result<()>;
+
+    /// Samples (at the input rate) the encoder delays its output by
+    fn lookahead(&self) -> u32;
+}
+
+/// The Opus sink for `spec`, encoded by libopus
+pub fn create(path: &Path, spec: SinkSpec, bitrate: u32) -> Result<Box<dyn RecordingSink>> {
+    check(spec)?;
+    #[cfg(feature = "opus")]
+    {
+        let encoder = libopus::Encoder::new(spec, bitrate)?;
+        Ok(Box::new(OpusSink::create(path, spec, encoder)?))
+    }
+    #[cfg(not(feature = "opus"))]
+    {
+        let _ = (path, bitrate);
+        Err(fail!(
+            Config,
+            "This build has no Opus encoder; build with --features opus"
+        ))
+    }
+}
+
+fn check(spec: SinkSpec) -> Result<()> {
+    if !SAMPLE_RATES.contains(&spec.sample_rate) {
+        return Err(fail!(
+            Format,
+            "Opus encodes 8, 12, 16, 24 or 48 kHz, not {} Hz",
+            spec.sample_rate
+        ));
+    }
+    if spec.channels > MAX_CHANNELS {
+        return Err(fail!(
+            Format,
+            "Opus output takes at most {} channels, not {}",
+            MAX_CHANNELS,
+            spec.channels
+        ));
+    }
+    Ok(())
+}
+
+pub struct OpusSink<E: PacketEncoder> {
+    path: PathBuf,
+    channels: usize,
+    /// Input samples per channel in one packet
+    packet_frames: usize,
+    /// 48 kHz granules per input sample
+    granule_scale: u64,
+    pre_skip: u64,
+    encoder: E,
+    ogg: OggWriter<BufWriter<File>>,
+    /// Interleaved samples short of a whole packet
+    pending: Vec<i16>,
+    packet: Vec<u8>,
+    packets: u64,
+    frames: u64,
+}
+
+impl<E: PacketEncoder> OpusSink<E> {
+    pub fn create(path: &Path, spec: SinkSpec, encoder: E) -> Result<Self> {
+        check(spec)?;
+        let file = File::create(path).context("Failed to create output Opus file")?;
+        let granule_scale = (GRANULE_RATE / spec.sample_rate) as u64;
+        let pre_skip = encoder.lookahead() as u64 * granule_scale;
+        let mut ogg = OggWriter::new(BufWriter::new(file), serial(path));
+        ogg.packet(&opus_head(spec, pre_skip as u16), 0)
+            .and_then(|_| ogg.page(Flags::Bos))
+            .and_then(|_| ogg.packet(&opus_tags(), 0))
+            .and_then(|_| ogg.page(Flags::None))
+            .context("Failed to write Opus header")?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            channels: spec.channels as usize,
+            packet_frames: (spec.sample_rate * PACKET_MS / 1000) as usize,
+            granule_scale,
+            pre_skip,
+            encoder,
+            ogg,
+            pending: Vec::new(),
+            packet: Vec::with_capacity(MAX_PACKET_BYTES),
+            packets: 0,
+            frames: 0,
+        })
+    }
+
+    fn write_packet(&mut self, pcm: &[i16]) -> Result<()> {
+        self.packet.clear();
+        self.encoder.encode(pcm, &mut self.packet)?;
+        self.packets += 1;
+        let granule = self.packets * self.packet_frames as u64 * self.granule_scale;
+        self.ogg
+            .packet(&self.packet, granule)
+            .and_then(|_| match self.ogg.packets >= PACKETS_PER_PAGE {
+                true => self.ogg.page(Flags::None),
+                false => Ok(()),
+            })
+            .context("Failed to write Opus page")
+    }
+}
+
+impl<E: PacketEncoder> RecordingSink for OpusSink<E> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        self.frames += (samples.len() / self.channels) as u64;
+        self.pending.extend_from_slice(samples);
+        let packet = self.packet_frames * self.channels;
+        if self.pending.len() >= packet {
+            let pending = std::mem::take(&mut self.pending);
+            let mut packets = pending.chunks_exact(packet);
+            for pcm in packets.by_ref() {
+                self.write_packet(pcm)?;
+            }
+            self.pending = packets.remainder().to_vec();
+        }
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<SinkSummary> {
+        let mut this = *self;
+        // Silence pads the last packet and flushes what the encoder still holds back
+        let end = this.pre_skip + this.frames * this.granule_scale;
+        let packet = this.packet_frames * this.channels;
+        let mut pcm = std::mem::take(&mut this.pending);
+        while this.packets * (this.packet_frames as u64) * this.granule_scale < end {
+            pcm.resize(packet, 0);
+            this.write_packet(&pcm)?;
+            pcm.clear();
+        }
+        this.ogg.granule = end;
+        this.ogg
+            .page(Flags::Eos)
+            .and_then(|_| this.ogg.out.flush())
+            .context("Failed to finalize Opus file")?;
+        let bytes = std::fs::metadata(&this.path)?.len();
+        Ok(SinkSummary {
+            path: this.path,
+            frames: this.frames,
+            bytes,
+        })
+    }
+}
+
+/// Stream serial number, distinct per file
+fn serial(path: &Path) -> u32 {
+    let name = path.as_os_str().as_encoded_bytes();
+    crc32(name) ^ std::process::id()
+}
+
+fn opus_head(spec: SinkSpec, pre_skip: u16) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(spec.channels as u8);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&spec.sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // mono or stereo, no mapping table
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    let vendor = concat!("win-audio-capture ", env!("CARGO_PKG_VERSION"));
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    tags
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flags {
+    None,
+    /// First page of the stream
+    Bos,
+    /// Last page of the stream
+    Eos,
+}
+
+/// Packs packets into Ogg pages of one logical stream
+struct OggWriter<W: Write> {
+    out: W,
+    serial: u32,
+    sequence: u32,
+    /// Lacing values and data of the packets on the page being built
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+    packets: usize,
+    /// Granule position of the last packet completed on the page
+    granule: u64,
+}
+
+impl<W: Write> OggWriter<W> {
+    fn new(out: W, serial: u32) -> Self {
+        Self {
+            out,
+            serial,
+            sequence: 0,
+            lacing: Vec::new(),
+            body: Vec::new(),
+            packets: 0,
+            granule: 0,
+        }
+    }
+
+    /// Add a packet to the current page; packets never span pages, they are far below the
+    /// 64 KiB a page holds
+    fn packet(&mut self, packet: &[u8], granule: u64) -> std::io::Result<()> {
+        if self.lacing.len() + packet.len() / 255 + 1 > 255 {
+            self.page(Flags::None)?;
+        }
+        self.lacing
+            .extend(std::iter::repeat_n(255, packet.len() / 255));
+        self.lacing.push((packet.len() % 255) as u8);
+        self.body.extend_from_slice(packet);
+        self.packets += 1;
+        self.granule = granule;
+        Ok(())
+    }
+
+    /// Write the current page out; an empty one only when it carries `Bos` or `Eos`
+    fn page(&mut self, flags: Flags) -> std::io::Result<()> {
+        if self.packets == 0 && flags == Flags::None {
+            return Ok(());
+        }
+        let mut page = Vec::with_capacity(27 + self.lacing.len() + self.body.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(match flags {
+            Flags::None => 0,
+            Flags::Bos => 0x02,
+            Flags::Eos => 0x04,
+        });
+        page.extend_from_slice(&self.granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // CRC, filled in below
+        page.push(self.lacing.len() as u8);
+        page.extend_from_slice(&self.lacing);
+        page.extend_from_slice(&self.body);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.out.write_all(&page)?;
+        self.sequence += 1;
+        self.lacing.clear();
+        self.body.clear();
+        self.packets = 0;
+        Ok(())
+    }
+}
+
+/// The Ogg CRC: polynomial 0x04C11DB7, no reflection, zero initial value
+fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |mut crc, &byte| {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(feature = "opus")]
+mod libopus {
+    use super::{PacketEncoder, MAX_PACKET_BYTES};
+    use crate::error::fail;
+    use crate::sink::SinkSpec;
+    use anyhow::Result;
+    use std::os::raw::c_int;
+    use std::ptr::NonNull;
+
+    #[repr(C)]
+    struct OpusEncoder {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        fn opus_encoder_create(
+            fs: i32,
+            channels: c_int,
+            application: c_int,
+            error: *mut c_int,
+        ) -> *mut OpusEncoder;
+        fn opus_encode(
+            st: *mut OpusEncoder,
+            pcm: *const i16,
+            frame_size: c_int,
+            data: *mut u8,
+            max_data_bytes: i32,
+        ) -> i32;
+        fn opus_encoder_ctl(st: *mut OpusEncoder, request: c_int, ...) -> c_int;
+        fn opus_encoder_destroy(st: *mut OpusEncoder);
+    }
+
+    /// Tuned for speech, which is what both channels carry
+    const OPUS_APPLICATION_VOIP: c_int = 2048;
+    const OPUS_SET_BITRATE_REQUEST: c_int = 4002;
+    const OPUS_GET_LOOKAHEAD_REQUEST: c_int = 4027;
+
+    pub struct Encoder {
+        state: NonNull<OpusEncoder>,
+        channels: usize,
+        lookahead: u32,
+    }
+
+    // The encoder state is only touched through `&mut self`
+    unsafe impl Send for Encoder {}
+
+    impl Encoder {
+        pub fn new(spec: SinkSpec, bitrate: u32) -> Result<Self> {
+            let mut error = 0;
+            let state = unsafe {
+                opus_encoder_create(
+                    spec.sample_rate as i32,
+                    spec.channels as c_int,
+                    OPUS_APPLICATION_VOIP,
+                    &mut error,
+                )
+            };
+            let state = NonNull::new(state)
+                .filter(|_| error == 0)
+                .ok_or_else(|| fail!(Format, "Failed to create Opus encoder (error {})", error))?;
+            let mut encoder = Self {
+                state,
+                channels: spec.channels as usize,
+                lookahead: 0,
+            };
+            let mut lookahead: i32 = 0;
+            unsafe {
+                if opus_encoder_ctl(
+                    encoder.state.as_ptr(),
+                    OPUS_SET_BITRATE_REQUEST,
+                    bitrate as i32,
+                ) != 0
+                {
+                    return Err(fail!(
+                        Format,
+                        "Opus rejected a bitrate of {} bit/s",
+                        bitrate
+                    ));
+                }
+                opus_encoder_ctl(
+                    encoder.state.as_ptr(),
+                    OPUS_GET_LOOKAHEAD_REQUEST,
+                    &mut lookahead as *mut i32,
+                );
+            }
+            encoder.lookahead = lookahead.max(0) as u32;
+            Ok(encoder)
+        }
+    }
+
+    impl PacketEncoder for Encoder {
+        fn encode(&mut self, pcm: &[i16], packet: &mut Vec<u8>) -> Result<()> {
+            packet.resize(MAX_PACKET_BYTES, 0);
+            let len = unsafe {
+                opus_encode(
+                    self.state.as_ptr(),
+                    pcm.as_ptr(),
+                    (pcm.len() / self.channels) as c_int,
+                    packet.as_mut_ptr(),
+                    MAX_PACKET_BYTES as i32,
+                )
+            };
+            if len < 0 {
+                return Err(fail!(Format, "Opus encoding failed (error {})", len));
+            }
+            packet.truncate(len as usize);
+            Ok(())
+        }
+
+        fn lookahead(&self) -> u32 {
+            self.lookahead
+        }
+    }
+
+    impl Drop for Encoder {
+        fn drop(&mut self) {
+            unsafe { opus_encoder_destroy(self.state.as_ptr()) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for libopus: a packet of the first sample's bytes
+    struct FakeEncoder;
+
+    impl PacketEncoder for FakeEncoder {
+        fn encode(&mut self, pcm: &[i16], packet: &mut Vec<u8>) -> Result<()> {
+            packet.extend_from_slice(&pcm[0].to_le_bytes());
+            Ok(())
+        }
+
+        fn lookahead(&self) -> u32 {
+            312
+        }
+    }
+
+    /// (header type, granule, packets) of every page, checking each CRC
+    fn pages(file: &[u8]) -> Vec<(u8, u64, Vec<Vec<u8>>)> {
+        let mut pages = Vec::new();
+        let mut at = 0;
+        while at < file.len() {
+            assert_eq!(&file[at..at + 4], b"OggS");
+            let segments = file[at + 26] as usize;
+            let lacing = &file[at + 27..at + 27 + segments];
+            let len = 27 + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+            let mut page = file[at..at + len].to_vec();
+            let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(crc32(&page), crc);
+            let mut packets = Vec::new();
+            let mut body = at + 27 + segments;
+            let mut packet = Vec::new();
+            for &l in lacing {
+                packet.extend_from_slice(&file[body..body + l as usize]);
+                body += l as usize;
+                if l < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+            let granule = u64::from_le_bytes(file[at + 6..at + 14].try_into().unwrap());
+            pages.push((file[at + 5], granule, packets));
+            at += len;
+        }
+        pages
+    }
+
+    #[test]
+    fn frames_packets_into_ogg_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.opus");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 16_000,
+        };
+        // 2.5 s at 16 kHz: 125 packets of 320 frames
+        let samples: Vec<i16> = (0..40_000).flat_map(|i| [i as i16, 0]).collect();
+        let mut sink = Box::new(OpusSink::create(&path, spec, FakeEncoder).unwrap());
+        sink.write_samples(&samples[..1_000]).unwrap();
+        sink.write_samples(&samples[1_000..]).unwrap();
+        let summary = sink.finalize().unwrap();
+        assert_eq!(summary.frames, 40_000);
+
+        let pages = pages(&std::fs::read(&path).unwrap());
+        let head = &pages[0].2[0];
+        assert_eq!(pages[0].0, 0x02);
+        assert_eq!(&head[..8], b"OpusHead");
+        // Channels, then pre-skip in 48 kHz samples
+        assert_eq!(head[9], 2);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), 936);
+        assert!(pages[1].2[0].starts_with(b"OpusTags"));
+
+        let audio = &pages[2..];
+        let packets: usize = audio.iter().map(|page| page.2.len()).sum();
+        // The lookahead of a packet's worth of samples takes one more
+        assert_eq!(packets, 126);
+        assert_eq!(audio[0].2.len(), PACKETS_PER_PAGE);
+        assert_eq!(audio[0].1, 50 * 960);
+        let last = audio.last().unwrap();
+        assert_eq!(last.0, 0x04);
+        assert_eq!(last.1, 936 + 40_000 * 3);
+        assert_eq!(audio[1].2[0], 16_000i16.to_le_bytes());
+    }
+
+    #[test]
+    fn checks_the_layout_and_the_ogg_checksum() {
+        assert_eq!(crc32(b"123456789"), 0x89A1_897F);
+        let spec = |channels, sample_rate| SinkSpec {
+            channels,
+            sample_rate,
+        };
+        assert!(check(spec(2, 48_000)).is_ok());
+        assert!(check(spec(2, 44_100)).is_err());
+        assert!(check(spec(3, 48_000)).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! EBU RF64 sink (EBU Tech 3306)
+//! Same PCM layout as WAV, but the RIFF and data sizes live as 64-bit values in a `ds64`
+//! chunk, so multi-hour recordings are not capped at 4 GiB.
+//!
+//! Layout: `RF64` 0xFFFFFFFF `WAVE` | `ds64` (riff size, data size, sample count) |
+//! `fmt ` PCM | `data` 0xFFFFFFFF ...samples
+//!
+//! The ds64 sizes are brought up to date every `CHECKPOINT_SECS`, so the file stays
+//! readable if the sidecar dies before finalizing it.
+
+use super::{RecordingSink, SinkSpec, SinkSummary, CHECKPOINT_SECS};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BITS_PER_SAMPLE: u16 = 16;
+/// Bytes before the first sample
+pub(super) const HEADER_LEN: u64 = 12 + (8 + 28) + (8 + 16) + 8;
+/// Offset of the ds64 riff size field
+const DS64_RIFF_SIZE_OFFSET: u64 = 12 + 8;
+
+pub struct Rf64Sink {
+    path: PathBuf,
+    channels: u16,
+    writer: BufWriter<File>,
+    data_bytes: u64,
+    /// Data bytes between ds64 updates, and written since the last one
+    checkpoint_bytes: u64,
+    unpatched: u64,
+}
+
+impl Rf64Sink {
+    pub fn create(path: &Path, spec: SinkSpec) -> Result<Self> {
+        let file = File::create(path).context("Failed to create output RF64 file")?;
+        let mut writer = BufWriter::new(file);
+        write_header(&mut writer, spec, 0).context("Failed to write RF64 header")?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            channels: spec.channels,
+            writer,
+            data_bytes: 0,
+            checkpoint_bytes: CHECKPOINT_SECS
+                * spec.sample_rate as u64
+                * spec.channels as u64
+                * BITS_PER_SAMPLE as u64
+                / 8,
+            unpatched: 0,
+        })
+    }
+
+    /// Write the 64-bit sizes in ds64 for the data written so far
+    fn patch_sizes(&mut self) -> std::io::Result<()> {
+        let riff_size = HEADER_LEN - 8 + self.data_bytes;
+        let block_align = self.channels as u64 * BITS_PER_SAMPLE as u64 / 8;
+        self.writer.seek(SeekFrom::Start(DS64_RIFF_SIZE_OFFSET))?;
+        self.writer.write_all(&riff_size.to_le_bytes())?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer
+            .write_all(&(self.data_bytes / block_align).to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, spec: SinkSpec, data_bytes: u64) -> std::io::Result<()> {
+    let block_align = spec.channels * BITS_PER_SAMPLE / 8;
+    let byte_rate = spec.sample_rate * block_align as u32;
+    let sample_count = data_bytes / block_align as u64;
+
+    writer.write_all(b"RF64")?;
+    writer.write_all(&u32::MAX.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"ds64")?;
+    writer.write_all(&28u32.to_le_bytes())?;
+    writer.write_all(&(HEADER_LEN - 8 + data_bytes).to_le_bytes())?; // RIFF size
+    writer.write_all(&data_bytes.to_le_bytes())?; // data chunk size
+    writer.write_all(&sample_count.to_le_bytes())?; // sample count
+    writer.write_all(&0u32.to_le_bytes())?; // no extra chunk sizes
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // WAVE_FORMAT_PCM
+    writer.write_all(&spec.channels.to_le_bytes())?;
+    writer.write_all(&spec.sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&u32::MAX.to_le_bytes())?;
+    Ok(())
+}
+
+impl RecordingSink for Rf64Sink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for &sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u64 * 2;
+        self.unpatched += samples.len() as u64 * 2;
+        if self.unpatched >= self.checkpoint_bytes {
+            self.patch_sizes()
+                .context("Failed to update the RF64 header")?;
+            self.unpatched = 0;
+        }
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<SinkSummary> {
+        let mut this = *self;
+        this.patch_sizes().context("Failed to finalize RF64 file")?;
+        let block_align = this.channels as u64 * BITS_PER_SAMPLE as u64 / 8;
+
+        Ok(SinkSummary {
+            path: this.path,
+            frames: this.data_bytes / block_align,
+            bytes: HEADER_LEN + this.data_bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_conformant_rf64_header_and_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.rf64");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 44_100,
+        };
+        let samples: Vec<i16> = (0..1_500).map(|i| (i * 7 - 5_000) as i16).collect();
+
+        let mut sink = Box::new(Rf64Sink::create(&path, spec).unwrap());
+        sink.write_samples(&samples).unwrap();
+        let summary = sink.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(summary.bytes, bytes.len() as u64);
+        assert_eq!(summary.frames, 750);
+
+        assert_eq!(&bytes[0..4], b"RF64");
+        assert_eq!(u32_at(&bytes, 4), u32::MAX);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(&bytes[12..16], b"ds64");
+        assert_eq!(u32_at(&bytes, 16), 28);
+        assert_eq!(u64_at(&bytes, 20), bytes.len() as u64 - 8);
+        assert_eq!(u64_at(&bytes, 28), 3_000);
+        assert_eq!(u64_at(&bytes, 36), 750);
+
+        assert_eq!(&bytes[48..52], b"fmt ");
+        assert_eq!(u32_at(&bytes, 52), 16);
+        assert_eq!(u16::from_le_bytes([bytes[56], bytes[57]]), 1);
+        assert_eq!(u16::from_le_bytes([bytes[58], bytes[59]]), 2);
+        assert_eq!(u32_at(&bytes, 60), 44_100);
+        assert_eq!(u32_at(&bytes, 64), 44_100 * 4);
+
+        assert_eq!(&bytes[72..76], b"data");
+        assert_eq!(u32_at(&bytes, 76), u32::MAX);
+        let decoded: Vec<i16> = bytes[HEADER_LEN as usize..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn keeps_the_sizes_current_while_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("killed.rf64");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        let mut sink = Box::new(Rf64Sink::create(&path, spec).unwrap());
+        for _ in 0..3 {
+            sink.write_samples(&[3; 1_000]).unwrap();
+        }
+        std::mem::forget(sink);
+
+        // Updated after the second block; the third never left the write buffer
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u64_at(&bytes, 20), bytes.len() as u64 - 8);
+        assert_eq!(u64_at(&bytes, 36), 1_000);
+    }
+}
@@ -0,0 +1,425 @@
+//! Soak testing
+//! `--soak hours=24 --inject device_flap,disk_stall,pipe_block` runs the whole pipeline
+//! against synthetic sources (`--source noise` unless others are given) for that long and
+//! then stops on its own, injecting the failure modes customers hit at random meanwhile:
+//! a source that stops delivering for a while (`device_flap`), a recording disk that stops
+//! taking writes (`disk_stall`) and a frame consumer that stops reading its pipe
+//! (`pipe_block`). Every `CHECK_INTERVAL` the session checks its invariants and reports a
+//! `soak` event: resident memory stays within `MEMORY_GROWTH_MB` of where it settled after
+//! warm-up, and the frames recorded plus the gaps documented in the timeline keep up with
+//! the wall clock. The run ends with a `soak_report` and fails if an invariant broke.
+
+use crate::sink::{RecordingSink, SinkSummary};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+
+/// How often the invariants are checked
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest warm-up before the memory baseline is taken (a tenth of shorter runs)
+const MAX_WARMUP: Duration = Duration::from_secs(300);
+/// Resident memory the process may gain over its baseline
+const MEMORY_GROWTH_MB: u64 = 64;
+/// How far the recorded frames may trail the wall clock, queued blocks included
+const CLOCK_TOLERANCE_SECS: u64 = 10;
+/// Average time between two injections of the same fault
+const MEAN_FAULT_INTERVAL_SECS: u64 = 300;
+/// How often a fault being injected is checked for having ended
+const POLL: Duration = Duration::from_millis(10);
+
+/// `--soak`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoakSpec {
+    pub duration: Duration,
+}
+
+impl FromStr for SoakSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (unit, amount) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected hours=N or minutes=N, got `{}`", value))?;
+        let amount: f64 = amount
+            .parse()
+            .ok()
+            .filter(|amount: &f64| amount.is_finite() && *amount > 0.0)
+            .ok_or_else(|| format!("invalid soak length `{}`", amount))?;
+        let secs = match unit {
+            "hours" => amount * 3_600.0,
+            "minutes" => amount * 60.0,
+            _ => {
+                return Err(format!(
+                    "unknown unit `{}` (expected hours or minutes)",
+                    unit
+                ))
+            }
+        };
+        Ok(Self {
+            duration: Duration::from_secs_f64(secs),
+        })
+    }
+}
+
+/// `--inject`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    DeviceFlap,
+    DiskStall,
+    PipeBlock,
+}
+
+impl Fault {
+    const ALL: [Fault; 3] = [Fault::DeviceFlap, Fault::DiskStall, Fault::PipeBlock];
+
+    /// Shortest and longest injection; a disk stall stays within what the queues absorb
+    fn length_ms(self) -> (u64, u64) {
+        match self {
+            Fault::DeviceFlap => (200, 5_000),
+            Fault::DiskStall => (500, 4_000),
+            Fault::PipeBlock => (1_000, 15_000),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::DeviceFlap => "device_flap",
+            Fault::DiskStall => "disk_stall",
+            Fault::PipeBlock => "pipe_block",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Faults currently injected, shared with the threads that act them out
+#[derive(Debug)]
+pub struct Faults {
+    started: Instant,
+    /// Milliseconds after `started` each fault lasts until
+    until_ms: [AtomicU64; 3],
+    injected: [AtomicU64; 3],
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            until_ms: Default::default(),
+            injected: Default::default(),
+        }
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn active(&self, fault: Fault) -> bool {
+        self.until_ms[fault.index()].load(Ordering::Relaxed) > self.now_ms()
+    }
+
+    fn inject(&self, fault: Fault, length: Duration) {
+        let until = self.now_ms() + length.as_millis() as u64;
+        self.until_ms[fault.index()].store(until, Ordering::Relaxed);
+        self.injected[fault.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Block the calling thread for as long as `fault` is injected
+    pub fn hold(&self, fault: Fault) {
+        while self.active(fault) {
+            std::thread::sleep(POLL);
+        }
+    }
+
+    /// How often each fault was injected
+    pub fn counts(&self) -> BTreeMap<&'static str, u64> {
+        Fault::ALL
+            .iter()
+            .map(|&fault| {
+                let count = self.injected[fault.index()].load(Ordering::Relaxed);
+                (fault.name(), count)
+            })
+            .collect()
+    }
+}
+
+/// Decides when the enabled faults are injected; ticked once a second
+pub struct Scheduler {
+    faults: Arc<Faults>,
+    enabled: Vec<Fault>,
+    /// xorshift64 state; fixed so a failing run can be repeated
+    state: u64,
+}
+
+impl Scheduler {
+    pub fn new(faults: Arc<Faults>, enabled: &[Fault]) -> Self {
+        Self {
+            faults,
+            enabled: enabled.to_vec(),
+            state: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Roll for every enabled fault that isn't already injected; returns the ones started
+    pub fn tick(&mut self) -> Vec<(Fault, Duration)> {
+        let mut started = Vec::new();
+        for fault in self.enabled.clone() {
+            if self.faults.active(fault) || !self.next().is_multiple_of(MEAN_FAULT_INTERVAL_SECS) {
+                continue;
+            }
+            let (min, max) = fault.length_ms();
+            let length = Duration::from_millis(min + self.next() % (max - min + 1));
+            self.faults.inject(fault, length);
+            started.push((fault, length));
+        }
+        started
+    }
+}
+
+/// An invariant that didn't hold
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub invariant: &'static str,
+    pub at_secs: u64,
+    pub detail: String,
+}
+
+/// What the session looks like at a check
+#[derive(Debug, Clone, Copy)]
+pub struct Probe {
+    pub elapsed: Duration,
+    pub resident_bytes: Option<u64>,
+    /// Frames recorded plus frames skipped over documented gaps
+    pub accounted_frames: u64,
+    pub sample_rate: u32,
+}
+
+pub struct Invariants {
+    warmup: Duration,
+    baseline_bytes: Option<u64>,
+    pub peak_bytes: u64,
+    pub violations: Vec<Violation>,
+}
+
+impl Invariants {
+    pub fn new(spec: SoakSpec) -> Self {
+        Self {
+            warmup: (spec.duration / 10).min(MAX_WARMUP),
+            baseline_bytes: None,
+            peak_bytes: 0,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Check `probe`, returning what it broke
+    pub fn check(&mut self, probe: Probe) -> Vec<Violation> {
+        let at_secs = probe.elapsed.as_secs();
+        let mut broken = Vec::new();
+        if let Some(bytes) = probe.resident_bytes {
+            self.peak_bytes = self.peak_bytes.max(bytes);
+            match self.baseline_bytes {
+                None if probe.elapsed >= self.warmup => self.baseline_bytes = Some(bytes),
+                Some(baseline) if bytes > baseline + MEMORY_GROWTH_MB * 1024 * 1024 => {
+                    broken.push(Violation {
+                        invariant: "bounded_memory",
+                        at_secs,
+                        detail: format!(
+                            "resident {} MB, {} MB over the baseline",
+                            bytes / (1024 * 1024),
+                            (bytes - baseline) / (1024 * 1024)
+                        ),
+                    });
+                }
+                _ => {}
+            }
+        }
+        let expected = probe.elapsed.as_millis() as u64 * probe.sample_rate as u64 / 1_000;
+        let lag = expected.saturating_sub(probe.accounted_frames);
+        let ahead = probe.accounted_frames.saturating_sub(expected);
+        if lag.max(ahead) > CLOCK_TOLERANCE_SECS * probe.sample_rate as u64 {
+            broken.push(Violation {
+                invariant: "timeline_continuity",
+                at_secs,
+                detail: format!(
+                    "{} frames recorded or documented as gaps, the clock says {}",
+                    probe.accounted_frames, expected
+                ),
+            });
+        }
+        self.violations.extend(broken.iter().cloned());
+        broken
+    }
+}
+
+/// Recording sink whose writes hang while a `disk_stall` is injected
+pub struct StallingSink {
+    inner: Box<dyn RecordingSink>,
+    faults: Arc<Faults>,
+}
+
+impl StallingSink {
+    pub fn new(inner: Box<dyn RecordingSink>, faults: Arc<Faults>) -> Self {
+        Self { inner, faults }
+    }
+}
+
+impl RecordingSink for StallingSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        self.faults.hold(Fault::DiskStall);
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<SinkSummary> {
+        self.inner.finalize()
+    }
+}
+
+/// Frame output that stops taking writes while a `pipe_block` is injected, like a
+/// consumer that stopped reading
+pub struct BlockablePipe<W> {
+    inner: W,
+    faults: Arc<Faults>,
+    wait: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<W> BlockablePipe<W> {
+    pub fn new(inner: W, faults: Arc<Faults>) -> Self {
+        Self {
+            inner,
+            faults,
+            wait: None,
+        }
+    }
+
+    fn poll_unblocked(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while self.faults.active(Fault::PipeBlock) {
+            let wait = self
+                .wait
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(POLL)));
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for BlockablePipe<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_unblocked(cx));
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_unblocked(cx));
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Working set of this process
+#[cfg(windows)]
+pub fn resident_bytes() -> Option<u64> {
+    use windows::Win32::System::ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+    use windows::Win32::System::Threading::GetCurrentProcess;
+
+    let mut counters = PROCESS_MEMORY_COUNTERS::default();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) }
+        .as_bool()
+        .then_some(counters.WorkingSetSize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lengths_and_injects_every_enabled_fault() {
+        assert_eq!(
+            "hours=24".parse::<SoakSpec>().unwrap().duration,
+            Duration::from_secs(86_400)
+        );
+        assert_eq!(
+            "minutes=1.5".parse::<SoakSpec>().unwrap().duration,
+            Duration::from_secs(90)
+        );
+        assert!("days=1".parse::<SoakSpec>().is_err());
+        assert!("hours=0".parse::<SoakSpec>().is_err());
+
+        let faults = Arc::new(Faults::new());
+        let mut scheduler = Scheduler::new(faults.clone(), &[Fault::DiskStall, Fault::PipeBlock]);
+        let mut started = Vec::new();
+        for _ in 0..10 * MEAN_FAULT_INTERVAL_SECS {
+            for (fault, length) in scheduler.tick() {
+                let (min, max) = fault.length_ms();
+                assert!((min..=max).contains(&(length.as_millis() as u64)));
+                started.push(fault);
+            }
+            // Let the injections run out between ticks
+            for fault in Fault::ALL {
+                faults.until_ms[fault.index()].store(0, Ordering::Relaxed);
+            }
+        }
+        assert!(started.contains(&Fault::DiskStall) && started.contains(&Fault::PipeBlock));
+        assert!(!started.contains(&Fault::DeviceFlap));
+        assert!(started.len() < 60, "{} injections", started.len());
+    }
+
+    #[test]
+    fn flags_memory_growth_and_a_lagging_timeline() {
+        let spec = SoakSpec {
+            duration: Duration::from_secs(3_600),
+        };
+        let mut invariants = Invariants::new(spec);
+        let mb = 1024 * 1024;
+        let probe = |minutes: u64, resident_mb: u64, recorded_secs: u64| Probe {
+            elapsed: Duration::from_secs(minutes * 60),
+            resident_bytes: Some(resident_mb * mb),
+            accounted_frames: recorded_secs * 1_000,
+            sample_rate: 1_000,
+        };
+        // Growth during warm-up is allowed; the baseline is taken after it
+        assert!(invariants.check(probe(1, 20, 60)).is_empty());
+        assert!(invariants.check(probe(6, 200, 360)).is_empty());
+        assert!(invariants.check(probe(30, 250, 1_795)).is_empty());
+        let broken = invariants.check(probe(59, 300, 3_000));
+        let names: Vec<_> = broken.iter().map(|v| v.invariant).collect();
+        assert_eq!(names, ["bounded_memory", "timeline_continuity"]);
+        assert_eq!(invariants.peak_bytes, 300 * mb);
+    }
+}
@@ -0,0 +1,246 @@
+//! Store-and-forward queue for recordings bound for network storage
+//! When the share, mapped drive or synced folder a recording belongs on can't be reached at
+//! finalize, the staged file is moved into a local queue directory instead of failing the
+//! session. The queue is drained in the order recordings were queued, rate-limited so a
+//! returning hotel Wi-Fi isn't flooded, by any running capture and by `spool drain`.
+
+use crate::events;
+use crate::instance_lock::{self, Scope};
+use crate::paths;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often a running capture retries delivering queued recordings
+pub const DRAIN_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default drain rate (bytes per second)
+pub const DEFAULT_DRAIN_RATE: u64 = 4 * 1024 * 1024;
+
+/// Bytes copied between rate checks
+const COPY_CHUNK: usize = 256 * 1024;
+
+/// One queued recording: `<seq>.json` next to its data file `<seq>.<ext>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpoolJob {
+    pub seq: u64,
+    pub session: String,
+    /// Queued copy inside the spool directory
+    pub file: PathBuf,
+    /// Where the recording belongs
+    pub destination: PathBuf,
+    pub bytes: u64,
+    pub queued_ms: u64,
+}
+
+/// Queue depth as reported in events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueDepth {
+    pub jobs: usize,
+    pub bytes: u64,
+    pub oldest_queued_ms: Option<u64>,
+}
+
+/// Outcome of one drain pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DrainStats {
+    pub delivered: usize,
+    pub delivered_bytes: u64,
+    /// Set when a delivery failed; the pass stops there so the order is kept
+    pub blocked: bool,
+    pub remaining: QueueDepth,
+}
+
+/// Per-user queue directory
+pub fn spool_dir() -> PathBuf {
+    paths::state_dir().join("spool")
+}
+
+/// Move `staged` into the queue for later delivery to `destination`
+pub fn enqueue(dir: &Path, session: &str, staged: &Path, destination: &Path) -> Result<SpoolJob> {
+    fs::create_dir_all(dir).context("Failed to create spool directory")?;
+    let seq = jobs(dir)?.last().map_or(0, |job| job.seq + 1);
+
+    let mut name = OsString::from(format!("{:020}", seq));
+    if let Some(ext) = staged.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    let file = dir.join(name);
+    // The staging directory is usually on the same volume; fall back to copying
+    if fs::rename(staged, &file).is_err() {
+        fs::copy(staged, &file).context("Failed to copy recording into the spool")?;
+        fs::remove_file(staged).context("Failed to remove staged recording")?;
+    }
+
+    let job = SpoolJob {
+        seq,
+        session: session.to_string(),
+        bytes: fs::metadata(&file)?.len(),
+        file,
+        destination: destination.to_path_buf(),
+        queued_ms: events::unix_millis(),
+    };
+    let job_path = job_path(dir, seq);
+    let tmp_path = job_path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&job)?).context("Failed to write spool job")?;
+    fs::rename(&tmp_path, &job_path).context("Failed to publish spool job")?;
+    Ok(job)
+}
+
+/// Queued jobs in delivery order
+pub fn jobs(dir: &Path) -> Result<Vec<SpoolJob>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read spool directory"),
+    };
+
+    let mut jobs = Vec::new();
+    for item in read_dir {
+        let path = item?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Ok(bytes) = fs::read(&path) else {
+            continue;
+        };
+        if let Ok(job) = serde_json::from_slice::<SpoolJob>(&bytes) {
+            jobs.push(job);
+        }
+    }
+    jobs.sort_by_key(|job| job.seq);
+    Ok(jobs)
+}
+
+pub fn depth(dir: &Path) -> Result<QueueDepth> {
+    let jobs = jobs(dir)?;
+    Ok(QueueDepth {
+        jobs: jobs.len(),
+        bytes: jobs.iter().map(|job| job.bytes).sum(),
+        oldest_queued_ms: jobs.iter().map(|job| job.queued_ms).min(),
+    })
+}
+
+/// Deliver queued recordings in order at up to `rate` bytes per second, stopping at the
+/// first one that can't be delivered. Returns `None` if another process is draining.
+pub fn drain(dir: &Path, rate: u64) -> Result<Option<DrainStats>> {
+    let Some(_lock) = instance_lock::try_acquire(Scope::Spool, &dir.to_string_lossy())? else {
+        return Ok(None);
+    };
+
+    let mut stats = DrainStats::default();
+    for job in jobs(dir)? {
+        match deliver(&job, rate) {
+            Ok(()) => {
+                let _ = fs::remove_file(&job.file);
+                fs::remove_file(job_path(dir, job.seq)).context("Failed to remove spool job")?;
+                stats.delivered += 1;
+                stats.delivered_bytes += job.bytes;
+            }
+            Err(e) => {
+                eprintln!(
+                    "[win-audio-capture] Spooled recording {} not delivered yet: {:#}",
+                    job.destination.display(),
+                    e
+                );
+                stats.blocked = true;
+                break;
+            }
+        }
+    }
+    stats.remaining = depth(dir)?;
+    Ok(Some(stats))
+}
+
+fn job_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.json", seq))
+}
+
+/// Copy under a temporary name, then rename into place so readers never see half a file
+fn deliver(job: &SpoolJob, rate: u64) -> Result<()> {
+    let partial = job.destination.with_extension("partial");
+    let result = copy_rate_limited(&job.file, &partial, rate)
+        .and_then(|_| fs::rename(&partial, &job.destination).map_err(Into::into));
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+fn copy_rate_limited(from: &Path, to: &Path, rate: u64) -> Result<()> {
+    let mut reader = File::open(from).context("Failed to open spooled recording")?;
+    let mut writer = File::create(to).context("Failed to create destination file")?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let started = Instant::now();
+    let mut copied = 0u64;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buf[..read])?;
+        copied += read as u64;
+
+        // Sleep off whatever the copy is ahead of the rate
+        let due = Duration::from_secs_f64(copied as f64 / rate.max(1) as f64);
+        if let Some(ahead) = due.checked_sub(started.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+    writer.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drains_in_queue_order_and_stops_at_the_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let out = dir.path().join("share");
+        fs::create_dir_all(&out).unwrap();
+
+        for (name, dest) in [("a", out.join("a.wav")), ("b", out.join("missing/b.wav"))] {
+            let staged = dir.path().join(format!("{}.wav", name));
+            fs::write(&staged, name).unwrap();
+            enqueue(&spool, "s", &staged, &dest).unwrap();
+            assert!(!staged.exists());
+        }
+        let staged = dir.path().join("c.wav");
+        fs::write(&staged, "c").unwrap();
+        enqueue(&spool, "s", &staged, &out.join("c.wav")).unwrap();
+        assert_eq!(depth(&spool).unwrap().jobs, 3);
+
+        // b's directory doesn't exist (share still offline), so c has to wait behind it
+        let stats = drain(&spool, u64::MAX).unwrap().unwrap();
+        assert_eq!(stats.delivered, 1);
+        assert!(stats.blocked);
+        assert_eq!(stats.remaining.jobs, 2);
+        assert_eq!(fs::read(out.join("a.wav")).unwrap(), b"a");
+        assert!(!out.join("c.wav").exists());
+
+        fs::create_dir_all(out.join("missing")).unwrap();
+        let stats = drain(&spool, u64::MAX).unwrap().unwrap();
+        assert_eq!((stats.delivered, stats.blocked), (2, false));
+        assert_eq!(stats.remaining, QueueDepth::default());
+        assert_eq!(fs::read(out.join("c.wav")).unwrap(), b"c");
+    }
+
+    #[test]
+    fn copy_honours_the_rate() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::write(&from, vec![0u8; 100_000]).unwrap();
+
+        let started = Instant::now();
+        copy_rate_limited(&from, &dir.path().join("to"), 1_000_000).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+}
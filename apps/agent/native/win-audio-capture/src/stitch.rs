@@ -0,0 +1,466 @@
+//! Stitching session parts
+//! `stitch part1.wav part2.wav --out full.wav` joins the parts of one session (rotated
+//! files, or the recording a restarted capture began after a crash) into a single file.
+//! Each part is placed by the start time in its timeline file, and silence fills what was
+//! documented but not recorded: the time between parts, gaps where the clock jumped
+//! (suspend), and silence left out with `--compact-silence`. Parts that overlap give way to
+//! the earlier one; a part without a timeline follows the previous one directly. Parts of
+//! different sessions are only joined when their timelines chain them (`new_session`).
+
+use crate::error::fail;
+use crate::riff::{self, RiffAudio};
+use crate::silence;
+use crate::sink::{self, OutputFormat, RecordingSink, SinkSpec};
+use crate::timeline::{self, SavedTimeline};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Silent frames written per call
+const SILENCE_CHUNK_FRAMES: u64 = 4_800;
+
+/// What `stitch` prints once the file is written
+#[derive(Debug, Serialize)]
+pub struct StitchSummary {
+    pub out: PathBuf,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub frames: u64,
+    pub bytes: u64,
+    pub parts: Vec<PartSummary>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PartSummary {
+    pub path: PathBuf,
+    /// Output frame the part's first frame landed on
+    pub start_frame: u64,
+    /// Frames taken from the part
+    pub frames: u64,
+    /// Silence inserted inside the part for documented gaps
+    pub gap_frames: u64,
+    /// Frames of the part's start dropped because the previous part already covered them
+    pub overlap_frames: u64,
+    /// Whether the part was placed by its start time
+    pub placed: bool,
+}
+
+/// One input, as placed on the session's clock
+struct Part {
+    path: PathBuf,
+    audio: RiffAudio,
+    session: Option<String>,
+    /// Sessions the part's timeline names as its predecessor or successor
+    chained: Vec<String>,
+    /// Session clock frame of the part's first file frame, from its timeline
+    start: Option<u64>,
+    /// Silence to insert before file frame `.0`, in file order
+    gaps: Vec<(u64, u64)>,
+}
+
+/// Join `parts` into `out`; gaps between parts longer than `max_gap_secs` are refused
+pub fn run(
+    parts: &[PathBuf],
+    out: &Path,
+    format: OutputFormat,
+    max_gap_secs: u64,
+) -> Result<StitchSummary> {
+    if parts.iter().any(|part| part == out) {
+        return Err(fail!(Config, "--out must not be one of the parts"));
+    }
+    let mut parts = parts
+        .iter()
+        .map(|path| open_part(path))
+        .collect::<Result<Vec<_>>>()?;
+    let Some(first) = parts.first() else {
+        return Err(fail!(Config, "Nothing to stitch"));
+    };
+    let (channels, sample_rate) = (first.audio.channels, first.audio.sample_rate);
+    if let Some(odd) = parts
+        .iter()
+        .find(|part| (part.audio.channels, part.audio.sample_rate) != (channels, sample_rate))
+    {
+        return Err(fail!(
+            Format,
+            "{:?} has {} channels at {} Hz, {:?} has {} at {} Hz",
+            odd.path,
+            odd.audio.channels,
+            odd.audio.sample_rate,
+            first.path,
+            channels,
+            sample_rate
+        ));
+    }
+    if let Some((session, other)) = unchained_sessions(&parts) {
+        return Err(fail!(
+            Config,
+            "Parts come from different sessions ({} and {}) that don't chain",
+            session,
+            other
+        ));
+    }
+    if parts.iter().all(|part| part.start.is_some()) {
+        parts.sort_by_key(|part| part.start);
+    }
+
+    let mut tmp_name = OsString::from(out.as_os_str());
+    tmp_name.push(".partial");
+    let tmp_path = PathBuf::from(tmp_name);
+    let spec = SinkSpec {
+        channels,
+        sample_rate,
+    };
+    let mut writer = Writer {
+        sink: sink::create(format, &tmp_path, spec)?,
+        channels: channels as usize,
+        frames: 0,
+        skip: 0,
+    };
+    let written =
+        stitch_parts(&parts, &mut writer, sample_rate, max_gap_secs).and_then(|summaries| {
+            let summary = writer.sink.finalize()?;
+            Ok((summaries, summary))
+        });
+    let (summaries, summary) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp_path, out).context("Failed to move stitched file into place")?;
+
+    Ok(StitchSummary {
+        out: out.to_path_buf(),
+        sample_rate,
+        channels,
+        frames: summary.frames,
+        bytes: summary.bytes,
+        parts: summaries,
+    })
+}
+
+/// Two sessions among the parts that no chain of `new_session` hand-overs connects
+fn unchained_sessions(parts: &[Part]) -> Option<(&str, &str)> {
+    let mut sessions: Vec<&str> = parts
+        .iter()
+        .filter_map(|part| part.session.as_deref())
+        .collect();
+    sessions.sort();
+    sessions.dedup();
+    let first = *sessions.first()?;
+    let mut joined = vec![first];
+    loop {
+        let before = joined.len();
+        for part in parts {
+            let Some(session) = part.session.as_deref() else {
+                continue;
+            };
+            for other in part.chained.iter().map(String::as_str) {
+                for (known, new) in [(session, other), (other, session)] {
+                    if joined.contains(&known) && !joined.contains(&new) {
+                        joined.push(new);
+                    }
+                }
+            }
+        }
+        if joined.len() == before {
+            break;
+        }
+    }
+    let other = sessions.into_iter().find(|s| !joined.contains(s))?;
+    Some((first, other))
+}
+
+fn stitch_parts(
+    parts: &[Part],
+    writer: &mut Writer,
+    sample_rate: u32,
+    max_gap_secs: u64,
+) -> Result<Vec<PartSummary>> {
+    let origin = parts.first().and_then(|part| part.start).unwrap_or(0);
+    let mut summaries = Vec::new();
+    for part in parts {
+        // Where the part belongs on the output's clock
+        let at = part
+            .start
+            .map_or(writer.frames, |start| start.saturating_sub(origin));
+        let mut overlap_frames = 0;
+        if at > writer.frames {
+            let gap = at - writer.frames;
+            if gap > max_gap_secs * sample_rate as u64 {
+                return Err(fail!(
+                    Config,
+                    "{:?} starts {}s after the previous part, more than --max-gap-secs {}",
+                    part.path,
+                    gap / sample_rate as u64,
+                    max_gap_secs
+                ));
+            }
+            writer.silence(gap)?;
+        } else {
+            overlap_frames = writer.frames - at;
+            writer.skip = overlap_frames;
+        }
+        let start_frame = writer.frames;
+
+        let mut next = 0;
+        let mut gap_frames = 0;
+        for &(frame, frames) in &part.gaps {
+            writer.copy(part, next, frame - next)?;
+            writer.silence(frames)?;
+            gap_frames += frames;
+            next = frame;
+        }
+        writer.copy(part, next, part.audio.frames() - next)?;
+        // Whatever of the overlap the part didn't cover isn't skipped in the next one
+        writer.skip = 0;
+
+        summaries.push(PartSummary {
+            path: part.path.clone(),
+            start_frame,
+            frames: part.audio.frames(),
+            gap_frames,
+            overlap_frames,
+            placed: part.start.is_some(),
+        });
+    }
+    Ok(summaries)
+}
+
+fn open_part(path: &Path) -> Result<Part> {
+    let audio = RiffAudio::open(path)?;
+    let Some(saved) = timeline::load(path)? else {
+        return Ok(Part {
+            path: path.to_path_buf(),
+            audio,
+            session: None,
+            chained: Vec::new(),
+            start: None,
+            gaps: Vec::new(),
+        });
+    };
+    let (start, gaps) = placement(&saved, audio.sample_rate, audio.frames());
+    Ok(Part {
+        path: path.to_path_buf(),
+        audio,
+        session: saved.session.clone(),
+        chained: [&saved.predecessor, &saved.successor]
+            .into_iter()
+            .flatten()
+            .map(|link| link.session.clone())
+            .collect(),
+        start,
+        gaps,
+    })
+}
+
+/// Start frame and in-file gaps of a part from its timeline
+fn placement(
+    saved: &SavedTimeline,
+    sample_rate: u32,
+    frames: u64,
+) -> (Option<u64>, Vec<(u64, u64)>) {
+    // Positions in the untrimmed file: left-out silence, then skipped clock time
+    let mut documented: Vec<(u64, u64)> = saved
+        .silence
+        .iter()
+        .map(|elision| (elision.file_frame, elision.frames))
+        .collect();
+    documented.extend(
+        saved
+            .entries
+            .iter()
+            .filter(|entry| entry.kind == "gap")
+            .filter_map(|entry| {
+                let skipped = entry.data.as_ref()?.get("skipped_frames")?.as_u64()?;
+                let position = silence::file_frame(&saved.silence, entry.sample_position);
+                Some((position.checked_sub(saved.start_position)?, skipped))
+            }),
+    );
+    documented.sort();
+
+    // Trimmed silence moves the first frame later; gaps before it do too
+    let leading = saved.trim.map_or(0, |trim| trim.leading_frames);
+    let mut before = 0;
+    let mut gaps = Vec::new();
+    for (frame, skipped) in documented {
+        if frame < leading {
+            before += skipped;
+        } else if frame - leading < frames {
+            gaps.push((frame - leading, skipped));
+        }
+    }
+    let start = saved
+        .started_ms
+        .map(|ms| ms * sample_rate as u64 / 1000 + leading + before);
+    (start, gaps)
+}
+
+/// The output, dropping the first `skip` frames handed to it
+struct Writer {
+    sink: Box<dyn RecordingSink>,
+    channels: usize,
+    frames: u64,
+    skip: u64,
+}
+
+impl Writer {
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        let frames = (samples.len() / self.channels) as u64;
+        let skipped = self.skip.min(frames);
+        self.skip -= skipped;
+        let samples = &samples[skipped as usize * self.channels..];
+        if !samples.is_empty() {
+            self.sink.write_samples(samples)?;
+            self.frames += (samples.len() / self.channels) as u64;
+        }
+        Ok(())
+    }
+
+    fn silence(&mut self, mut frames: u64) -> Result<()> {
+        let zeros = vec![0i16; SILENCE_CHUNK_FRAMES as usize * self.channels];
+        while frames > 0 {
+            let len = frames.min(SILENCE_CHUNK_FRAMES);
+            self.write(&zeros[..len as usize * self.channels])?;
+            frames -= len;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, part: &Part, start: u64, frames: u64) -> Result<()> {
+        if frames == 0 {
+            return Ok(());
+        }
+        riff::read_frames(&part.path, &part.audio, start, frames, |samples| {
+            self.write(samples)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::{ChainLink, Timeline, TimelineEntry};
+    use serde_json::json;
+
+    fn part(dir: &Path, name: &str, started_ms: Option<u64>, value: i16, frames: usize) -> PathBuf {
+        let path = dir.join(name);
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 1_000,
+        };
+        let mut out = sink::create(OutputFormat::Wav, &path, spec).unwrap();
+        out.write_samples(&vec![value; frames * 2]).unwrap();
+        out.finalize().unwrap();
+        if let Some(ms) = started_ms {
+            Timeline::new(&path, "call", 1_000, None)
+                .with_start(ms)
+                .save()
+                .unwrap();
+        }
+        path
+    }
+
+    fn read(path: &Path) -> Vec<i16> {
+        let audio = RiffAudio::open(path).unwrap();
+        let mut samples = Vec::new();
+        riff::read_frames(path, &audio, 0, audio.frames(), |s| {
+            samples.extend(s.iter().step_by(2));
+            Ok(())
+        })
+        .unwrap();
+        samples
+    }
+
+    #[test]
+    fn places_parts_by_start_time_with_silence_between() {
+        let dir = tempfile::tempdir().unwrap();
+        // Given out of order; the second part starts 2s after the first ended
+        let second = part(dir.path(), "b.wav", Some(1_005_000), 2, 1_000);
+        let first = part(dir.path(), "a.wav", Some(1_000_000), 1, 3_000);
+        let out = dir.path().join("full.wav");
+
+        let summary = run(&[second, first], &out, OutputFormat::Wav, 60).unwrap();
+        assert_eq!(summary.frames, 6_000);
+        assert_eq!(summary.parts[1].start_frame, 5_000);
+        let samples = read(&out);
+        assert!(samples[..3_000].iter().all(|&s| s == 1));
+        assert!(samples[3_000..5_000].iter().all(|&s| s == 0));
+        assert!(samples[5_000..].iter().all(|&s| s == 2));
+
+        // Too far apart to be one session
+        let err = run(
+            &[dir.path().join("a.wav"), dir.path().join("b.wav")],
+            &dir.path().join("short.wav"),
+            OutputFormat::Wav,
+            1,
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn fills_documented_gaps_and_drops_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = part(dir.path(), "a.wav", Some(0), 1, 2_000);
+        // A suspend skipped 500 frames after the first second
+        let mut timeline = Timeline::new(&first, "call", 1_000, None).with_start(0);
+        timeline
+            .push(TimelineEntry {
+                kind: "gap".to_string(),
+                label: Some("suspend".to_string()),
+                sample_position: 1_000,
+                wall_time_ms: 0,
+                source: "system".to_string(),
+                data: Some(json!({ "skipped_frames": 500, "skipped_ms": 500 })),
+            })
+            .unwrap();
+        // Restarted 300 frames before the first part's end (with its gap)
+        let second = part(dir.path(), "b.wav", Some(2_200), 2, 1_000);
+        let out = dir.path().join("full.wav");
+
+        let summary = run(&[first, second], &out, OutputFormat::Wav, 60).unwrap();
+        assert_eq!(summary.parts[0].gap_frames, 500);
+        assert_eq!(summary.parts[1].overlap_frames, 300);
+        let samples = read(&out);
+        assert_eq!(samples.len(), 2_500 + 700);
+        assert!(samples[1_000..1_500].iter().all(|&s| s == 0));
+        assert!(samples[1_500..2_500].iter().all(|&s| s == 1));
+        assert!(samples[2_500..].iter().all(|&s| s == 2));
+    }
+
+    #[test]
+    fn joins_sessions_only_across_a_hand_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = part(dir.path(), "a.wav", Some(0), 1, 1_000);
+        let second = part(dir.path(), "b.wav", None, 2, 1_000);
+        let link = |session: &str, path: &Path| ChainLink {
+            session: session.to_string(),
+            path: path.to_path_buf(),
+            sample_position: 1_000,
+        };
+        Timeline::new(&second, "next", 1_000, None)
+            .with_start(1_000)
+            .save()
+            .unwrap();
+        let out = dir.path().join("full.wav");
+        assert!(run(
+            &[first.clone(), second.clone()],
+            &out,
+            OutputFormat::Wav,
+            60
+        )
+        .is_err());
+
+        Timeline::new(&second, "next", 1_000, None)
+            .with_start(1_000)
+            .starting_at(1_000)
+            .with_predecessor(link("call", &first))
+            .save()
+            .unwrap();
+        let summary = run(&[first, second], &out, OutputFormat::Wav, 60).unwrap();
+        assert_eq!(summary.frames, 2_000);
+        assert_eq!(summary.parts[1].start_frame, 1_000);
+    }
+}
@@ -0,0 +1,1134 @@
+//! stdout PCM stream
+//! Regroups mixed blocks into 100ms SELL frames (or the length `reconfigure` set with
+//! `frame_ms`) and writes them to stdout. Runs as its own task so a slow or stuck reader
+//! on the pipe never stalls the recording.
+//! An agent attaching over the instance pipe replaces stdout as the frame destination.
+//!
+//! `--protocol` picks how a consumer is greeted. `v2` opens every output with the handshake
+//! frame, `v1` never sends one (the stream older agents were built against) and `auto`
+//! sends it only once the consumer has asked with `negotiate`, so agents that don't know
+//! the handshake keep seeing a bare v1 stream. Until an output is greeted its PCM frames
+//! have the short version 1 header and it gets none of the optional frames. Each new
+//! output starts over, negotiated frame types included. `v3` greets like `v2` but sends PCM as typed frames, timestamped
+//! and checksummed; markers and stats snapshots then go out as frames of their own and the
+//! stream ends with an end-of-stream frame.
+//!
+//! With `energy` negotiated every PCM frame is followed by its energy frame. With
+//! `shared_pcm` PCM frames go into the shared-memory ring when a slot is free and are sent
+//! as reference frames, inline otherwise.
+//!
+//! Observers get a copy of the stream of their own: the handshake, then every PCM frame
+//! inline followed by its energy frame. A lagging observer loses frames instead of holding
+//! the stream up.
+//!
+//! The handshake carries the stream's rate, channel count and channel layout. With
+//! `--handshake-every N` it is sent again before every Nth PCM frame, to observers and to
+//! an output that was greeted, so a consumer that joins a running stream (a relay that
+//! reconnects, a daemon's pipe read from the middle) can resync on the next one and set up
+//! its decoder without knowing the session's options.
+//!
+//! A consumer that attaches to a running session (an output that takes over, an observer)
+//! doesn't start blind: after its handshake it gets a snapshot of the stream's state, the
+//! markers and the latest stats snapshot so far on a v3 stream, then the last second of
+//! audio again. Replayed frames keep their sequence numbers, offsets and timestamps, so
+//! the consumer can tell them from the live frames that follow. An output that isn't
+//! greeted on arrival (`v1`, `auto`) gets no snapshot, as it would take the replayed
+//! frames for new ones.
+//!
+//! `--stdout-format wav` writes plain 16-bit stereo WAV to stdout instead, for ffmpeg, sox
+//! and players while debugging: no handshake, frames or consumers taking over. Its sizes
+//! say "unknown length" so a pipe reader plays until the end; when stdout is a file they
+//! are brought up to date every second and at the end. The status lines the CLI prints
+//! then go to stderr, out of the audio.
+
+use crate::engine::AudioBlock;
+use crate::frame::{self, FrameKind, Handshake, Timestamps};
+use crate::session::StreamCounters;
+use crate::shm;
+use crate::trace;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Stereo pairs per frame until `Notice::FrameSize` says otherwise (100ms @ 48kHz)
+const SAMPLES_PER_FRAME: usize = 4800;
+
+/// What the left and right channel of the stream carry
+pub const CHANNEL_LAYOUT: [&str; 2] = ["mic", "loopback"];
+
+/// Frame types a consumer can ask for with `negotiate`
+pub const OPTIONAL_FRAMES: &[&str] = &["energy", "shared_pcm"];
+
+/// Encoded frames bound for one observer
+pub type ObserverFeed = mpsc::Sender<Vec<u8>>;
+
+/// Handshake for a stream at `sample_rate` with the session options `features`, repeated
+/// every `repeat_every` frames if set
+pub fn handshake(
+    sample_rate: u32,
+    features: Vec<String>,
+    pipeline_tag: Option<&str>,
+    session: Option<&str>,
+    repeat_every: Option<u32>,
+) -> Handshake {
+    Handshake {
+        protocol: frame::PROTOCOL_VERSION,
+        features,
+        sample_format: "s16le".to_string(),
+        sample_rate,
+        channels: 2,
+        bits_per_sample: 16,
+        frame_duration_ms: frame_duration_ms(SAMPLES_PER_FRAME, sample_rate),
+        optional_frames: OPTIONAL_FRAMES.iter().map(|s| s.to_string()).collect(),
+        pipeline_tag: pipeline_tag.map(str::to_string),
+        session: session.map(str::to_string),
+        channel_layout: CHANNEL_LAYOUT.iter().map(|s| s.to_string()).collect(),
+        repeat_every_frames: repeat_every,
+    }
+}
+
+fn frame_duration_ms(samples_per_frame: usize, sample_rate: u32) -> u32 {
+    (samples_per_frame as u64 * 1000 / sample_rate.max(1) as u64) as u32
+}
+
+/// `--protocol`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// v1 until the consumer negotiates, then the handshake and v2
+    Auto,
+    /// Bare SELL PCM frames with the 12-byte version 1 header, no handshake
+    V1,
+    /// Handshake first on every output
+    V2,
+    /// Handshake first, then typed frames
+    V3,
+}
+
+impl Protocol {
+    /// Version the handshake announces
+    pub fn version(self) -> u32 {
+        match self {
+            Protocol::V1 => 1,
+            Protocol::Auto | Protocol::V2 => frame::PROTOCOL_VERSION,
+            Protocol::V3 => frame::TYPED_PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// `--stdout-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StdoutFormat {
+    /// SELL frames, as `--protocol` says
+    Frames,
+    /// A WAV stream any audio tool can read
+    Wav,
+}
+
+/// What the session tells the stream besides the audio
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notice {
+    /// Frame types the consumer of the current output negotiated
+    Negotiated(Vec<String>),
+    /// Timeline entry of a marker, a marker frame on a v3 stream
+    Marker(serde_json::Value),
+    /// `stats` snapshot, a stats frame on a v3 stream
+    Stats(serde_json::Value),
+    /// Stereo pairs per frame from here on; the partial frame goes out short and the
+    /// handshake is sent again with the new `frame_duration_ms`
+    FrameSize(usize),
+}
+
+/// Where frames are written
+pub type FrameOutput = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Stream blocks until the sender side closes, then flush the partial last frame.
+/// Each output received on `outputs` replaces the current one. After a write fails the
+/// stream keeps counting but writes nothing until a new output arrives.
+/// Every `Notice::Negotiated` received on `notices` is what the consumer of the current
+/// output negotiated (already checked against `OPTIONAL_FRAMES`); `shared_pcm` only counts
+/// with a `shared` ring, whose slots are reclaimed whenever the output changes. Markers and
+/// stats are dropped unless the stream speaks v3. Blocks that arrive while the frame size
+/// changes wait in `blocks`, so the change costs no audio.
+/// Every feed received on `observers` gets frames until its receiver is dropped.
+/// Returns the session counters so the caller can report the final position.
+pub async fn stream_frames(
+    mut blocks: mpsc::Receiver<AudioBlock>,
+    mut outputs: mpsc::Receiver<FrameOutput>,
+    mut notices: mpsc::UnboundedReceiver<Notice>,
+    protocol: Protocol,
+    mut handshake: Handshake,
+    mut shared: Option<shm::Ring>,
+    mut observers: mpsc::UnboundedReceiver<ObserverFeed>,
+) -> StreamCounters {
+    handshake.protocol = protocol.version().max(frame::PROTOCOL_VERSION);
+    let repeat_every = handshake.repeat_every_frames.filter(|&every| every > 0);
+    let clock = (protocol == Protocol::V3).then(StreamClock::new);
+    let mut greeting = Greeting {
+        protocol,
+        handshake: frame::encode_handshake(&handshake),
+        greeted: false,
+    };
+    let mut output: Option<FrameOutput> = None;
+    let mut delivery = Delivery::default();
+    let mut samples_per_frame = SAMPLES_PER_FRAME;
+    let mut frame_buffer: Vec<i16> = Vec::with_capacity(samples_per_frame * 2);
+    let mut counters = StreamCounters::new();
+    let mut feeds: Vec<ObserverFeed> = Vec::new();
+    let mut snapshot = Snapshot::new(handshake.sample_rate, samples_per_frame);
+
+    // An output handed over before streaming starts takes the place of stdout
+    let first = match outputs.try_recv() {
+        Ok(output) => output,
+        Err(_) => Box::new(tokio::io::stdout()),
+    };
+    greeting.open(&mut output, first).await;
+
+    loop {
+        let block = tokio::select! {
+            biased;
+            Some(next) = outputs.recv() => {
+                greeting.open(&mut output, next).await;
+                // Without the handshake nothing tells replayed frames from live ones
+                if greeting.greeted {
+                    for bytes in snapshot.frames() {
+                        write_bytes(&mut output, bytes).await;
+                    }
+                }
+                delivery = Delivery::default();
+                if let Some(ring) = &mut shared {
+                    ring.reclaim();
+                }
+                continue;
+            }
+            Some(notice) = notices.recv() => {
+                let (kind, payload) = match notice {
+                    Notice::Negotiated(frames) => {
+                        greeting.negotiated(&mut output).await;
+                        delivery = Delivery {
+                            energy: frames.iter().any(|name| name == "energy"),
+                            shared_pcm: shared.is_some()
+                                && frames.iter().any(|name| name == "shared_pcm"),
+                        };
+                        continue;
+                    }
+                    Notice::Marker(entry) => (FrameKind::Marker, entry),
+                    Notice::Stats(stats) => (FrameKind::Stats, stats),
+                    Notice::FrameSize(samples) => {
+                        if !frame_buffer.is_empty() {
+                            let pcm = flush_frame(
+                                &mut output,
+                                &mut counters,
+                                &frame_buffer,
+                                greeting.greeted.then_some(delivery),
+                                &mut shared,
+                                &mut feeds,
+                                clock.as_ref(),
+                            )
+                            .await;
+                            snapshot.pcm(pcm);
+                            frame_buffer.clear();
+                        }
+                        samples_per_frame = samples.max(1);
+                        handshake.frame_duration_ms =
+                            frame_duration_ms(samples_per_frame, handshake.sample_rate);
+                        greeting.handshake = frame::encode_handshake(&handshake);
+                        greeting.repeat(&mut output, &mut feeds).await;
+                        snapshot.resize(handshake.sample_rate, samples_per_frame);
+                        continue;
+                    }
+                };
+                if let Some(clock) = &clock {
+                    let offset = counters.sample_position() + (frame_buffer.len() / 2) as u64;
+                    let bytes = frame::encode_typed(
+                        kind,
+                        counters.next_sequence(),
+                        offset,
+                        clock.now(),
+                        payload.to_string().as_bytes(),
+                    );
+                    snapshot.notice(kind, &bytes);
+                    send_typed(&mut output, &mut feeds, bytes).await;
+                }
+                continue;
+            }
+            Some(feed) = observers.recv() => {
+                let _ = feed.try_send(greeting.handshake.clone());
+                for bytes in snapshot.frames() {
+                    let _ = feed.try_send(bytes.to_vec());
+                }
+                feeds.push(feed);
+                continue;
+            }
+            block = blocks.recv() => block,
+        };
+        let Some(block) = block else { break };
+
+        for pair in block.chunks_exact(2) {
+            frame_buffer.extend_from_slice(pair);
+            if frame_buffer.len() >= samples_per_frame * 2 {
+                let sequence = counters.next_sequence();
+                if repeat_every.is_some_and(|every| sequence > 0 && sequence.is_multiple_of(every))
+                {
+                    greeting.repeat(&mut output, &mut feeds).await;
+                }
+                let pcm = flush_frame(
+                    &mut output,
+                    &mut counters,
+                    &frame_buffer,
+                    greeting.greeted.then_some(delivery),
+                    &mut shared,
+                    &mut feeds,
+                    clock.as_ref(),
+                )
+                .await;
+                snapshot.pcm(pcm);
+                frame_buffer.clear(); // Prevent buffer overflow
+            }
+        }
+    }
+
+    // Flush any remaining samples in frame buffer on shutdown
+    if !frame_buffer.is_empty() {
+        flush_frame(
+            &mut output,
+            &mut counters,
+            &frame_buffer,
+            greeting.greeted.then_some(delivery),
+            &mut shared,
+            &mut feeds,
+            clock.as_ref(),
+        )
+        .await;
+    }
+    if let Some(clock) = &clock {
+        let summary = json!({
+            "frames": counters.next_sequence(),
+            "sample_position": counters.sample_position(),
+        });
+        let bytes = frame::encode_typed(
+            FrameKind::EndOfStream,
+            counters.next_sequence(),
+            counters.sample_position(),
+            clock.now(),
+            summary.to_string().as_bytes(),
+        );
+        send_typed(&mut output, &mut feeds, bytes).await;
+    }
+
+    counters
+}
+
+/// Write the mixed blocks to `out` as one WAV stream at `sample_rate`
+pub async fn stream_wav<W>(
+    mut blocks: mpsc::Receiver<AudioBlock>,
+    mut out: W,
+    sample_rate: u32,
+) -> StreamCounters
+where
+    W: AsyncWrite + AsyncSeek + Unpin,
+{
+    let mut counters = StreamCounters::new();
+    let mut data_bytes: u64 = 0;
+    // Pipes can't seek; they keep the "unknown length" header
+    let mut seekable = true;
+    let mut unpatched: u64 = 0;
+    let mut failed = !write_wav(&mut out, &wav_header(sample_rate, None)).await;
+
+    while let Some(block) = blocks.recv().await {
+        counters.advance((block.len() / 2) as u64);
+        if failed {
+            continue;
+        }
+        let bytes: Vec<u8> = block
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        data_bytes += bytes.len() as u64;
+        unpatched += (block.len() / 2) as u64;
+        if !write_wav(&mut out, &bytes).await {
+            failed = true;
+            continue;
+        }
+        if seekable && unpatched >= sample_rate as u64 {
+            unpatched = 0;
+            seekable = patch_wav_sizes(&mut out, data_bytes).await.is_ok();
+        }
+    }
+    if seekable && !failed {
+        let _ = patch_wav_sizes(&mut out, data_bytes).await;
+    }
+    counters
+}
+
+/// Write and flush; a failure ends the WAV stream, the recording goes on
+async fn write_wav<W: AsyncWrite + Unpin>(out: &mut W, bytes: &[u8]) -> bool {
+    let result = match out.write_all(bytes).await {
+        Ok(()) => out.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        eprintln!(
+            "[win-audio-capture] Warning: Failed to write the WAV stream: {}",
+            e
+        );
+        eprintln!("[win-audio-capture] Continuing with the recording only");
+    }
+    result.is_ok()
+}
+
+/// Canonical 44-byte header of a 16-bit stereo WAV; `None` data size is "unknown length"
+fn wav_header(sample_rate: u32, data_bytes: Option<u64>) -> Vec<u8> {
+    const CHANNELS: u16 = 2;
+    const BITS: u16 = 16;
+    let block_align = CHANNELS * BITS / 8;
+    let data = data_bytes.map_or(u32::MAX, wav_size);
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&data.saturating_add(36).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&BITS.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data.to_le_bytes());
+    header
+}
+
+/// Size field for `bytes`; past 4 GB it stays at "unknown length"
+fn wav_size(bytes: u64) -> u32 {
+    u32::try_from(bytes).unwrap_or(u32::MAX)
+}
+
+/// Rewrite the RIFF and data sizes for `data_bytes` written, then go back to the end
+async fn patch_wav_sizes<W>(out: &mut W, data_bytes: u64) -> std::io::Result<()>
+where
+    W: AsyncWrite + AsyncSeek + Unpin,
+{
+    let data = wav_size(data_bytes);
+    out.seek(std::io::SeekFrom::Start(4)).await?;
+    out.write_all(&data.saturating_add(36).to_le_bytes())
+        .await?;
+    out.seek(std::io::SeekFrom::Start(40)).await?;
+    out.write_all(&data.to_le_bytes()).await?;
+    out.seek(std::io::SeekFrom::End(0)).await?;
+    out.flush().await
+}
+
+/// The process's stdout for `--stdout-format wav`. The standard output handle is pointed
+/// at stderr afterwards, so status lines printed later don't land in the audio; take it
+/// before printing anything.
+#[cfg(windows)]
+pub fn wav_stdout() -> std::io::Result<tokio::fs::File> {
+    use std::os::windows::io::AsHandle;
+    use windows::Win32::System::Console::{
+        GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE,
+    };
+
+    let stdout = std::io::stdout().as_handle().try_clone_to_owned()?;
+    unsafe { SetStdHandle(STD_OUTPUT_HANDLE, GetStdHandle(STD_ERROR_HANDLE)?)? };
+    Ok(tokio::fs::File::from_std(std::fs::File::from(stdout)))
+}
+
+#[cfg(not(windows))]
+pub fn wav_stdout() -> std::io::Result<tokio::fs::File> {
+    use std::io::Write;
+    use std::os::fd::{AsFd, AsRawFd};
+
+    extern "C" {
+        fn dup2(old: i32, new: i32) -> i32;
+    }
+
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    std::io::stdout().flush()?;
+    let redirected = unsafe { dup2(std::io::stderr().as_raw_fd(), std::io::stdout().as_raw_fd()) };
+    if redirected < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(tokio::fs::File::from_std(std::fs::File::from(stdout)))
+}
+
+/// Timestamps of typed frames
+struct StreamClock {
+    started: Instant,
+}
+
+impl StreamClock {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> Timestamps {
+        let utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamps {
+            monotonic_ns: self.started.elapsed().as_nanos() as u64,
+            utc_us: utc.as_micros() as i64,
+        }
+    }
+}
+
+/// What a consumer attaching mid-stream is sent after its handshake
+struct Snapshot {
+    /// Marker frames so far, v3 only
+    markers: Vec<Vec<u8>>,
+    /// The latest stats frame, v3 only
+    stats: Option<Vec<u8>>,
+    /// The last second of PCM frames, inline
+    recent: VecDeque<Vec<u8>>,
+    recent_frames: usize,
+}
+
+impl Snapshot {
+    fn new(sample_rate: u32, samples_per_frame: usize) -> Self {
+        let recent_frames = Self::recent_frames(sample_rate, samples_per_frame);
+        Self {
+            markers: Vec::new(),
+            stats: None,
+            recent: VecDeque::with_capacity(recent_frames),
+            recent_frames,
+        }
+    }
+
+    fn recent_frames(sample_rate: u32, samples_per_frame: usize) -> usize {
+        (sample_rate as usize)
+            .div_ceil(samples_per_frame.max(1))
+            .max(1)
+    }
+
+    /// Keep the last second at a new frame size
+    fn resize(&mut self, sample_rate: u32, samples_per_frame: usize) {
+        self.recent_frames = Self::recent_frames(sample_rate, samples_per_frame);
+        while self.recent.len() > self.recent_frames {
+            self.recent.pop_front();
+        }
+    }
+
+    fn notice(&mut self, kind: FrameKind, bytes: &[u8]) {
+        match kind {
+            FrameKind::Marker => self.markers.push(bytes.to_vec()),
+            FrameKind::Stats => self.stats = Some(bytes.to_vec()),
+            _ => {}
+        }
+    }
+
+    fn pcm(&mut self, bytes: Vec<u8>) {
+        if self.recent.len() == self.recent_frames {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(bytes);
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &[u8]> {
+        self.markers
+            .iter()
+            .chain(&self.stats)
+            .chain(&self.recent)
+            .map(Vec::as_slice)
+    }
+}
+
+/// Optional frames the current consumer negotiated
+#[derive(Debug, Clone, Copy, Default)]
+struct Delivery {
+    energy: bool,
+    shared_pcm: bool,
+}
+
+/// Whether and when the current output gets the handshake
+struct Greeting {
+    protocol: Protocol,
+    handshake: Vec<u8>,
+    greeted: bool,
+}
+
+impl Greeting {
+    /// Switch to `next`, greeting it right away with v2
+    async fn open(&mut self, output: &mut Option<FrameOutput>, next: FrameOutput) {
+        *output = Some(next);
+        self.greeted = false;
+        if matches!(self.protocol, Protocol::V2 | Protocol::V3) {
+            self.greet(output).await;
+        }
+    }
+
+    /// The consumer negotiated, so it understands the handshake
+    async fn negotiated(&mut self, output: &mut Option<FrameOutput>) {
+        if self.protocol != Protocol::V1 && !self.greeted {
+            self.greet(output).await;
+        }
+    }
+
+    async fn greet(&mut self, output: &mut Option<FrameOutput>) {
+        write_bytes(output, &self.handshake).await;
+        self.greeted = true;
+    }
+
+    /// Send the handshake again to the observers, and to the output if it was greeted
+    async fn repeat(&mut self, output: &mut Option<FrameOutput>, feeds: &mut Vec<ObserverFeed>) {
+        feeds.retain(|feed| {
+            !matches!(
+                feed.try_send(self.handshake.clone()),
+                Err(TrySendError::Closed(_))
+            )
+        });
+        if self.greeted {
+            write_bytes(output, &self.handshake).await;
+        }
+    }
+}
+
+/// Counters advance even if there is no output or the write fails, because the samples
+/// still exist in the recording and consumers rely on the offsets to place later frames.
+/// `delivery` is `None` for an output that wasn't greeted, which gets the version 1 frame.
+/// Returns the frame as inline PCM.
+async fn flush_frame(
+    output: &mut Option<FrameOutput>,
+    counters: &mut StreamCounters,
+    samples: &[i16],
+    delivery: Option<Delivery>,
+    shared: &mut Option<shm::Ring>,
+    feeds: &mut Vec<ObserverFeed>,
+    clock: Option<&StreamClock>,
+) -> Vec<u8> {
+    let (sequence_number, sample_offset) = counters.advance((samples.len() / 2) as u64);
+    let timestamps = clock.map(StreamClock::now);
+    let pcm = encode_pcm(samples, sequence_number, sample_offset, timestamps);
+    feed_observers(feeds, &pcm, samples, sequence_number, sample_offset);
+    if output.is_none() {
+        return pcm;
+    }
+
+    let _span = trace::span("frame_write");
+    let Some(delivery) = delivery else {
+        write_bytes(
+            output,
+            &frame::encode_legacy_frame(samples, sequence_number),
+        )
+        .await;
+        return pcm;
+    };
+    let slot_ref = match shared {
+        Some(ring) if delivery.shared_pcm => ring.publish(samples, sequence_number, sample_offset),
+        _ => None,
+    };
+    let mut bytes = match slot_ref {
+        Some(slot_ref) => frame::encode_shared(slot_ref, sequence_number, sample_offset),
+        None => pcm.clone(),
+    };
+    if delivery.energy {
+        let levels = frame::levels(samples, 2);
+        bytes.extend(frame::encode_energy(
+            &levels,
+            sequence_number,
+            sample_offset,
+        ));
+    }
+    write_bytes(output, &bytes).await;
+    pcm
+}
+
+/// Inline PCM frame, typed when there are `timestamps`
+fn encode_pcm(
+    samples: &[i16],
+    sequence: u32,
+    offset: u64,
+    timestamps: Option<Timestamps>,
+) -> Vec<u8> {
+    match timestamps {
+        Some(timestamps) => frame::encode_typed_pcm(samples, sequence, offset, timestamps),
+        None => frame::encode_frame(samples, sequence, offset),
+    }
+}
+
+/// Observers always get inline PCM (`pcm`, of `samples`) and energy frames
+fn feed_observers(
+    feeds: &mut Vec<ObserverFeed>,
+    pcm: &[u8],
+    samples: &[i16],
+    sequence: u32,
+    offset: u64,
+) {
+    if feeds.is_empty() {
+        return;
+    }
+    let mut bytes = pcm.to_vec();
+    bytes.extend(frame::encode_energy(
+        &frame::levels(samples, 2),
+        sequence,
+        offset,
+    ));
+    feeds.retain(|feed| !matches!(feed.try_send(bytes.clone()), Err(TrySendError::Closed(_))));
+}
+
+/// A frame outside the PCM sequence, for the output and every observer
+async fn send_typed(
+    output: &mut Option<FrameOutput>,
+    feeds: &mut Vec<ObserverFeed>,
+    bytes: Vec<u8>,
+) {
+    feeds.retain(|feed| !matches!(feed.try_send(bytes.clone()), Err(TrySendError::Closed(_))));
+    write_bytes(output, &bytes).await;
+}
+
+/// Write and flush; after a failure nothing is written until a new output arrives
+async fn write_bytes(output: &mut Option<FrameOutput>, bytes: &[u8]) {
+    let Some(writer) = output.as_mut() else {
+        return;
+    };
+    // Flush to ensure data reaches Node.js immediately
+    let result = match writer.write_all(bytes).await {
+        Ok(()) => writer.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!(
+            "[win-audio-capture] Warning: Failed to write PCM frame: {}",
+            e
+        );
+        eprintln!("[win-audio-capture] Continuing with WAV-only mode");
+        *output = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameDecoder, FrameKind};
+    use tokio::io::AsyncReadExt;
+
+    /// Stream one frame, negotiate `frames`, stream another, and list the frame kinds
+    /// received
+    async fn kinds(protocol: Protocol, frames: &[&str]) -> Vec<FrameKind> {
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.try_send(Box::new(writer)).unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            protocol,
+            handshake(48_000, Vec::new(), None, None, None),
+            None,
+            observer_rx,
+        ));
+
+        let block: AudioBlock = vec![0i16; SAMPLES_PER_FRAME * 2].into();
+        block_tx.send(block.clone()).await.unwrap();
+        // Let the first frame out before negotiating
+        tokio::task::yield_now().await;
+        while block_tx.capacity() < 4 {
+            tokio::task::yield_now().await;
+        }
+        notice_tx
+            .send(Notice::Negotiated(
+                frames.iter().map(|s| s.to_string()).collect(),
+            ))
+            .unwrap();
+        block_tx.send(block).await.unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+        drop(output_tx);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        std::iter::from_fn(|| decoder.next_frame().unwrap())
+            .map(|frame| frame.header.kind)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_a_wav_stream_that_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stdout.wav");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        let (block_tx, block_rx) = mpsc::channel(8);
+        let streamer = tokio::spawn(stream_wav(block_rx, file, 8_000));
+        // 1.5 s, so the sizes are patched once on the way and again at the end
+        let samples: Vec<i16> = (0..24_000).map(|i| (i % 1_000) as i16).collect();
+        for block in samples.chunks(4_000) {
+            block_tx.send(AudioBlock::from(block)).await.unwrap();
+        }
+        drop(block_tx);
+        let counters = streamer.await.unwrap();
+        assert_eq!(counters.sample_position(), 12_000);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 8_000);
+        assert_eq!(reader.duration(), 12_000);
+        let read: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(read, samples);
+    }
+
+    #[test]
+    fn unknown_length_header_leaves_the_sizes_open() {
+        let header = wav_header(48_000, None);
+        assert_eq!(header.len(), 44);
+        assert_eq!(header[4..8], u32::MAX.to_le_bytes());
+        assert_eq!(header[40..44], u32::MAX.to_le_bytes());
+        assert_eq!(wav_header(48_000, Some(400))[4..8], 436u32.to_le_bytes());
+        assert_eq!(wav_size(5 << 30), u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn repeats_the_handshake_for_consumers_joining_mid_stream() {
+        let (block_tx, block_rx) = mpsc::channel(8);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        let (_notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.try_send(Box::new(writer)).unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V2,
+            handshake(48_000, Vec::new(), None, None, Some(2)),
+            None,
+            observer_rx,
+        ));
+        let block: AudioBlock = vec![0i16; SAMPLES_PER_FRAME * 2 * 5].into();
+        block_tx.send(block).await.unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+        drop(output_tx);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap()).collect();
+        let kinds: Vec<_> = frames.iter().map(|frame| frame.header.kind).collect();
+        use FrameKind::{Handshake, Pcm};
+        assert_eq!(
+            kinds,
+            [Handshake, Pcm, Pcm, Handshake, Pcm, Pcm, Handshake, Pcm]
+        );
+        let repeated = frames[3].handshake().unwrap().unwrap();
+        assert_eq!(repeated.channel_layout, CHANNEL_LAYOUT);
+        assert_eq!((repeated.sample_rate, repeated.channels), (48_000, 2));
+        assert_eq!(repeated.repeat_every_frames, Some(2));
+    }
+
+    #[tokio::test]
+    async fn changes_frame_size_without_losing_samples() {
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.try_send(Box::new(writer)).unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V2,
+            handshake(48_000, Vec::new(), None, None, None),
+            None,
+            observer_rx,
+        ));
+        // A frame and a quarter, so a partial frame is pending at the change
+        let block: AudioBlock = vec![0i16; (SAMPLES_PER_FRAME + 1_200) * 2].into();
+        block_tx.send(block).await.unwrap();
+        while block_tx.capacity() < 4 {
+            tokio::task::yield_now().await;
+        }
+        notice_tx.send(Notice::FrameSize(2_400)).unwrap();
+        block_tx
+            .send(vec![0i16; 2_400 * 2 * 2].into())
+            .await
+            .unwrap();
+        drop(block_tx);
+        let counters = streamer.await.unwrap();
+        drop(output_tx);
+        assert_eq!(counters.sample_position(), SAMPLES_PER_FRAME as u64 + 6_000);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap()).collect();
+        let kinds: Vec<_> = frames.iter().map(|frame| frame.header.kind).collect();
+        use FrameKind::{Handshake, Pcm};
+        assert_eq!(kinds, [Handshake, Pcm, Pcm, Handshake, Pcm, Pcm]);
+        let offsets: Vec<_> = [1, 2, 4, 5]
+            .iter()
+            .map(|&i| frames[i].header.sample_offset)
+            .collect();
+        assert_eq!(offsets, [0, 4_800, 6_000, 8_400]);
+        assert_eq!(
+            frames[0].handshake().unwrap().unwrap().frame_duration_ms,
+            100
+        );
+        assert_eq!(
+            frames[3].handshake().unwrap().unwrap().frame_duration_ms,
+            50
+        );
+    }
+
+    #[tokio::test]
+    async fn greets_consumers_according_to_the_protocol() {
+        use FrameKind::{Handshake, Pcm};
+        assert_eq!(kinds(Protocol::V2, &[]).await, [Handshake, Pcm, Pcm]);
+        assert_eq!(kinds(Protocol::Auto, &[]).await, [Pcm, Handshake, Pcm]);
+        assert_eq!(kinds(Protocol::V1, &[]).await, [Pcm, Pcm]);
+    }
+
+    #[tokio::test]
+    async fn v1_writes_the_frames_older_agents_read() {
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.try_send(Box::new(writer)).unwrap();
+        notice_tx.send(Notice::FrameSize(2)).unwrap();
+        // Nothing a v1 consumer asks for changes what it gets
+        notice_tx
+            .send(Notice::Negotiated(vec!["energy".to_string()]))
+            .unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V1,
+            handshake(48_000, Vec::new(), None, None, Some(1)),
+            None,
+            observer_rx,
+        ));
+        block_tx
+            .send(vec![1, -1, i16::MAX, i16::MIN, 5, -5, 6, -6].into())
+            .await
+            .unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+        drop(output_tx);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        #[rustfmt::skip]
+        let expected: [u8; 40] = [
+            0x53, 0x45, 0x4c, 0x4c, // "SELL"
+            0x00, 0x00, 0x00, 0x00, // sequence 0
+            0x08, 0x00, 0x00, 0x00, // 8 payload bytes
+            0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x80,
+            0x53, 0x45, 0x4c, 0x4c,
+            0x01, 0x00, 0x00, 0x00,
+            0x08, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0xfb, 0xff, 0x06, 0x00, 0xfa, 0xff,
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[tokio::test]
+    async fn v3_streams_typed_frames_and_ends_with_end_of_stream() {
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.try_send(Box::new(writer)).unwrap();
+        notice_tx
+            .send(Notice::Marker(json!({ "label": "Pricing" })))
+            .unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V3,
+            handshake(48_000, Vec::new(), None, Some("call-1"), None),
+            None,
+            observer_rx,
+        ));
+        block_tx
+            .send(vec![1i16; SAMPLES_PER_FRAME * 2 + 4].into())
+            .await
+            .unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+        drop(output_tx);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap()).collect();
+        let kinds: Vec<_> = frames.iter().map(|frame| frame.header.kind).collect();
+        use FrameKind::{EndOfStream, Handshake, Marker, Pcm};
+        assert_eq!(kinds, [Handshake, Marker, Pcm, Pcm, EndOfStream]);
+
+        let greeting = frames[0].handshake().unwrap().unwrap();
+        assert_eq!(greeting.protocol, frame::TYPED_PROTOCOL_VERSION);
+        assert_eq!(greeting.session.as_deref(), Some("call-1"));
+        assert!(frames[1..].iter().all(|frame| frame.timestamps.is_some()));
+        let end = frames[4].json().unwrap().unwrap();
+        assert_eq!(end["frames"], 2);
+        assert_eq!(end["sample_position"], SAMPLES_PER_FRAME as u64 + 2);
+    }
+
+    #[tokio::test]
+    async fn follows_pcm_frames_with_energy_once_negotiated() {
+        use FrameKind::{Energy, Handshake, Pcm};
+        assert_eq!(
+            kinds(Protocol::Auto, &["energy"]).await,
+            [Pcm, Handshake, Pcm, Energy]
+        );
+    }
+
+    #[tokio::test]
+    async fn observers_get_every_frame_without_negotiating() {
+        use FrameKind::{Energy, Handshake, Pcm};
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        output_tx.try_send(Box::new(tokio::io::sink())).unwrap();
+        let (_notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let (feed, mut frames) = mpsc::channel(8);
+        observer_tx.send(feed).unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V1,
+            handshake(48_000, Vec::new(), None, None, None),
+            None,
+            observer_rx,
+        ));
+
+        let block: AudioBlock = vec![0i16; SAMPLES_PER_FRAME * 2].into();
+        block_tx.send(block).await.unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        while let Ok(bytes) = frames.try_recv() {
+            decoder.push(&bytes);
+        }
+        let kinds: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap())
+            .map(|frame| frame.header.kind)
+            .collect();
+        assert_eq!(kinds, [Handshake, Pcm, Energy]);
+    }
+
+    #[tokio::test]
+    async fn late_observers_get_markers_and_recent_audio_after_the_handshake() {
+        use FrameKind::{EndOfStream, Handshake, Marker, Pcm};
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        output_tx.try_send(Box::new(tokio::io::sink())).unwrap();
+        let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (observer_tx, observer_rx) = mpsc::unbounded_channel();
+        notice_tx
+            .send(Notice::Marker(json!({ "label": "Intro" })))
+            .unwrap();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            Protocol::V3,
+            handshake(48_000, Vec::new(), None, None, None),
+            None,
+            observer_rx,
+        ));
+
+        let block: AudioBlock = vec![0i16; SAMPLES_PER_FRAME * 2 * 2].into();
+        block_tx.send(block).await.unwrap();
+        while block_tx.capacity() < 4 {
+            tokio::task::yield_now().await;
+        }
+        let (feed, mut frames) = mpsc::channel(16);
+        observer_tx.send(feed).unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        while let Ok(bytes) = frames.try_recv() {
+            decoder.push(&bytes);
+        }
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame().unwrap()).collect();
+        let kinds: Vec<_> = frames.iter().map(|frame| frame.header.kind).collect();
+        assert_eq!(kinds, [Handshake, Marker, Pcm, Pcm, EndOfStream]);
+        // Replayed frames keep their place in the stream
+        assert_eq!(frames[2].header.sample_offset, 0);
+        assert_eq!(frames[3].header.sample_offset, SAMPLES_PER_FRAME as u64);
+    }
+
+    /// Frames an output taking over after two frames receives, with one more after it
+    async fn taken_over(protocol: Protocol) -> Vec<(FrameKind, u32)> {
+        let (block_tx, block_rx) = mpsc::channel(4);
+        let (output_tx, output_rx) = mpsc::channel::<FrameOutput>(1);
+        output_tx.try_send(Box::new(tokio::io::sink())).unwrap();
+        let (_notice_tx, notice_rx) = mpsc::unbounded_channel();
+        let (_observer_tx, observer_rx) = mpsc::unbounded_channel();
+        let streamer = tokio::spawn(stream_frames(
+            block_rx,
+            output_rx,
+            notice_rx,
+            protocol,
+            handshake(48_000, Vec::new(), None, None, None),
+            None,
+            observer_rx,
+        ));
+        let block: AudioBlock = vec![0i16; SAMPLES_PER_FRAME * 2].into();
+        block_tx.send(block.clone()).await.unwrap();
+        block_tx.send(block.clone()).await.unwrap();
+        while block_tx.capacity() < 4 {
+            tokio::task::yield_now().await;
+        }
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        output_tx.send(Box::new(writer)).await.unwrap();
+        block_tx.send(block).await.unwrap();
+        drop(block_tx);
+        streamer.await.unwrap();
+        drop(output_tx);
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        std::iter::from_fn(|| decoder.next_frame().unwrap())
+            .map(|frame| (frame.header.kind, frame.header.sequence))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_recent_audio_only_to_greeted_outputs() {
+        use FrameKind::{Handshake, Pcm};
+        assert_eq!(
+            taken_over(Protocol::V2).await,
+            [(Handshake, 0), (Pcm, 0), (Pcm, 1), (Pcm, 2)]
+        );
+        assert_eq!(taken_over(Protocol::Auto).await, [(Pcm, 2)]);
+        assert_eq!(taken_over(Protocol::V1).await, [(Pcm, 2)]);
+    }
+
+    #[test]
+    fn snapshot_keeps_only_the_last_second_of_audio() {
+        let mut snapshot = Snapshot::new(SAMPLES_PER_FRAME as u32 * 3, SAMPLES_PER_FRAME);
+        for n in 0..5u8 {
+            snapshot.pcm(vec![n]);
+        }
+        snapshot.notice(FrameKind::Stats, &[10]);
+        snapshot.notice(FrameKind::Stats, &[11]);
+        let frames: Vec<_> = snapshot.frames().collect();
+        assert_eq!(frames, [&[11][..], &[2], &[3], &[4]]);
+    }
+}
@@ -0,0 +1,91 @@
+//! Observer subscriptions
+//! An observer picks what it is sent in the line it opens with, next to its token:
+//! `{"token":"...","events":["transcript","finalize_*"],"frames":["energy"]}`. `events`
+//! names the event types to mirror (a trailing `*` matches a prefix) and `frames` the frame
+//! kinds (`pcm`, `energy`); either one left out means all of them. `"frames":[]` leaves the
+//! frame pipe silent, for a remote coaching view that only needs events. The handshake and
+//! the typed marker, stats and end-of-stream frames go to every observer that takes any
+//! frames.
+//!
+//! The attached agent always gets the full stream, control replies included.
+
+use crate::frame::FrameKind;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Frame kinds an observer can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameFilter {
+    Pcm,
+    Energy,
+}
+
+/// What one observer is sent
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Subscription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<Vec<FrameFilter>>,
+}
+
+impl Subscription {
+    /// The subscription in an observer's opening line; everything when it names none
+    pub fn from_line(line: &str) -> Self {
+        serde_json::from_str(line.trim()).unwrap_or_default()
+    }
+
+    pub fn wants_event(&self, name: &str) -> bool {
+        let Some(events) = &self.events else {
+            return true;
+        };
+        events
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => pattern == name,
+            })
+    }
+
+    pub fn wants_frame(&self, kind: FrameKind) -> bool {
+        let Some(frames) = &self.frames else {
+            return true;
+        };
+        match kind {
+            FrameKind::Handshake
+            | FrameKind::Marker
+            | FrameKind::Stats
+            | FrameKind::EndOfStream => !frames.is_empty(),
+            FrameKind::Pcm | FrameKind::SharedPcm => frames.contains(&FrameFilter::Pcm),
+            FrameKind::Energy => frames.contains(&FrameFilter::Energy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_events_and_frames_by_the_opening_line() {
+        let meters = Subscription::from_line(
+            r#"{"token":"t","events":["transcript","finalize_*"],"frames":["energy"]}"#,
+        );
+        assert!(meters.wants_event("transcript"));
+        assert!(meters.wants_event("finalize_progress"));
+        assert!(!meters.wants_event("position"));
+        assert!(meters.wants_frame(FrameKind::Handshake));
+        assert!(meters.wants_frame(FrameKind::Energy));
+        assert!(!meters.wants_frame(FrameKind::Pcm));
+
+        let events_only = Subscription::from_line(r#"{"token":"t","frames":[]}"#);
+        assert!(events_only.wants_event("position"));
+        assert!(!events_only.wants_frame(FrameKind::Handshake));
+
+        // A line with only the token subscribes to everything
+        let all = Subscription::from_line(r#"{"token":"t"}"#);
+        assert_eq!(all, Subscription::default());
+        assert!(all.wants_frame(FrameKind::Pcm) && all.wants_event("stopped"));
+    }
+}
@@ -0,0 +1,87 @@
+//! System suspend/resume notifications
+//! Registers a power-setting callback with the OS and forwards suspend and resume to the
+//! session loop, which marks the gap in the timeline and reopens the devices on resume.
+
+#![cfg(windows)]
+
+use anyhow::{anyhow, Result};
+use std::ffi::c_void;
+use tokio::sync::mpsc::UnboundedSender;
+use windows::Win32::Foundation::{ERROR_SUCCESS, HANDLE};
+use windows::Win32::System::Power::{
+    PowerRegisterSuspendResumeNotification, PowerUnregisterSuspendResumeNotification,
+    DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS, HPOWERNOTIFY,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    Suspend,
+    Resume,
+}
+
+/// Keeps the registration alive; dropping it unregisters the callback
+pub struct SuspendWatcher {
+    handle: *mut c_void,
+    // The OS holds pointers to both until unregistered
+    _params: Box<DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS>,
+    _events: Box<UnboundedSender<PowerEvent>>,
+}
+
+impl Drop for SuspendWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PowerUnregisterSuspendResumeNotification(HPOWERNOTIFY(self.handle as isize));
+        }
+    }
+}
+
+/// Forward suspend/resume notifications to `events` until the watcher is dropped
+pub fn watch(events: UnboundedSender<PowerEvent>) -> Result<SuspendWatcher> {
+    let mut events = Box::new(events);
+    let mut params = Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(on_power_event),
+        Context: &mut *events as *mut UnboundedSender<PowerEvent> as *mut c_void,
+    });
+
+    let mut handle: *mut c_void = std::ptr::null_mut();
+    let status = unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(&mut *params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+            &mut handle,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return Err(anyhow!(
+            "Failed to register for suspend/resume notifications: {:?}",
+            status
+        ));
+    }
+
+    Ok(SuspendWatcher {
+        handle,
+        _params: params,
+        _events: events,
+    })
+}
+
+unsafe extern "system" fn on_power_event(
+    context: *const c_void,
+    kind: u32,
+    _setting: *const c_void,
+) -> u32 {
+    let events = &*(context as *const UnboundedSender<PowerEvent>);
+    let event = match kind {
+        PBT_APMSUSPEND => Some(PowerEvent::Suspend),
+        // Sent for every resume, whether or not a user is present
+        PBT_APMRESUMEAUTOMATIC => Some(PowerEvent::Resume),
+        _ => None,
+    };
+    if let Some(event) = event {
+        let _ = events.send(event);
+    }
+    ERROR_SUCCESS.0
+}
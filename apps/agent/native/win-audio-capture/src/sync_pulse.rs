@@ -0,0 +1,131 @@
+//! External sync pulses
+//! With `--sync-pulse-secs N` the session emits a `sync` event every N seconds of audio,
+//! pairing an exact sample position with the UTC time that sample was mixed, and the same
+//! pulses end up as labelled cue points in the finished recording. A screen recorder that
+//! logs the events can line its video up with the audio to within a frame, without
+//! trusting either file's start time.
+
+use serde::Serialize;
+
+/// One pulse
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Pulse {
+    /// Counts from 0, the pulse at the start of the session
+    pub index: u64,
+    pub sample_position: u64,
+    pub utc_ms: u64,
+    /// `utc_ms` as ISO 8601
+    pub utc: String,
+}
+
+impl Pulse {
+    /// Cue point label
+    pub fn label(&self) -> String {
+        format!("Sync {} {}", self.index, self.utc)
+    }
+}
+
+/// Places pulses on the session's sample positions
+pub struct SyncPulse {
+    sample_rate: u32,
+    interval_frames: u64,
+    next_index: u64,
+    pulses: Vec<Pulse>,
+}
+
+impl SyncPulse {
+    pub fn new(interval_secs: f64, sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            interval_frames: ((interval_secs * sample_rate as f64).round() as u64).max(1),
+            next_index: 0,
+            pulses: Vec::new(),
+        }
+    }
+
+    /// Pulses falling on the frames `start..start + frames`, given that frame
+    /// `start + frames` is being mixed at `now_ms` (Unix time)
+    pub fn observe(&mut self, start: u64, frames: u64, now_ms: u64) -> &[Pulse] {
+        let first = self.pulses.len();
+        let end = start + frames;
+        loop {
+            let position = self.next_index * self.interval_frames;
+            if position >= end {
+                break;
+            }
+            let utc_ms = now_ms.saturating_sub((end - position) * 1000 / self.sample_rate as u64);
+            self.pulses.push(Pulse {
+                index: self.next_index,
+                sample_position: position,
+                utc_ms,
+                utc: utc_string(utc_ms),
+            });
+            self.next_index += 1;
+        }
+        &self.pulses[first..]
+    }
+
+    /// Every pulse so far, in order
+    pub fn pulses(&self) -> &[Pulse] {
+        &self.pulses
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.mmmZ` for a Unix time in milliseconds
+pub fn utc_string(unix_ms: u64) -> String {
+    let (days, ms_of_day) = (unix_ms / 86_400_000, unix_ms % 86_400_000);
+    // Civil date from days since 1970-01-01 (for the proleptic Gregorian calendar)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1_000 % 60,
+        ms_of_day % 1_000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_pulses_on_exact_positions_within_blocks() {
+        let mut sync = SyncPulse::new(1.0, 1_000);
+        let first: Vec<u64> = sync
+            .observe(0, 10, 50_010)
+            .iter()
+            .map(|p| p.utc_ms)
+            .collect();
+        assert_eq!(first, [50_000]);
+        assert!(sync.observe(10, 980, 51_000).is_empty());
+        let pulses = sync.observe(990, 1_020, 52_020);
+        assert_eq!(
+            pulses
+                .iter()
+                .map(|p| (p.index, p.sample_position, p.utc_ms))
+                .collect::<Vec<_>>(),
+            [(1, 1_000, 51_010), (2, 2_000, 52_010)]
+        );
+        assert_eq!(sync.pulses().len(), 3);
+    }
+
+    #[test]
+    fn formats_utc() {
+        assert_eq!(utc_string(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(utc_string(951_782_400_123), "2000-02-29T00:00:00.123Z");
+        assert_eq!(utc_string(1_791_976_245_678), "2026-10-14T11:10:45.678Z");
+    }
+}
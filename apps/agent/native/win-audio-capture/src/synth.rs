@@ -0,0 +1,257 @@
+//! Synthetic sources
+//! `--source sine:1000` or `--source noise` stands in for the capture devices, so deployment
+//! scripts can check the whole write/stream path on machines without audio hardware (VMs,
+//! build agents). A bare spec replaces both the MIC and the loopback; `mic=` or `loopback=`
+//! in front replaces only that one. The generator runs on its own thread, paced by the wall
+//! clock like a device, and fills the same source ring the device would.
+//!
+//! Noise comes from a fixed seed, so two runs produce the same samples.
+
+use crate::session::SourceCounter;
+use crate::soak::{Fault, Faults};
+use anyhow::{Context, Result};
+use rtrb::Producer;
+use serde::Serialize;
+use std::f64::consts::TAU;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Peak level of the generated signals (-12 dBFS), well clear of the limiter
+const AMPLITUDE: f64 = 0.25;
+
+/// Tone used for a bare `sine`
+const DEFAULT_HZ: f64 = 1_000.0;
+
+/// How often the generator thread tops up the ring
+const TICK: Duration = Duration::from_millis(10);
+
+/// What to generate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Sine {
+        hz: f64,
+    },
+    /// White noise
+    Noise,
+}
+
+/// Which capture source a synthetic signal replaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Mic,
+    Loopback,
+}
+
+/// One `--source` value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyntheticSource {
+    /// `None` replaces both sources
+    pub target: Option<Target>,
+    pub signal: Signal,
+}
+
+impl SyntheticSource {
+    fn replaces(&self, target: Target) -> bool {
+        self.target.is_none_or(|own| own == target)
+    }
+}
+
+impl FromStr for SyntheticSource {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (target, spec) = match value.split_once('=') {
+            Some(("mic", spec)) => (Some(Target::Mic), spec),
+            Some(("loopback", spec)) => (Some(Target::Loopback), spec),
+            Some((target, _)) => {
+                return Err(format!(
+                    "unknown source `{}` (expected mic or loopback)",
+                    target
+                ))
+            }
+            None => (None, value),
+        };
+        let signal = match spec.split_once(':') {
+            None if spec == "noise" => Signal::Noise,
+            None if spec == "sine" => Signal::Sine { hz: DEFAULT_HZ },
+            Some(("sine", hz)) => match hz.parse::<f64>() {
+                Ok(hz) if hz.is_finite() && hz > 0.0 => Signal::Sine { hz },
+                _ => return Err(format!("invalid sine frequency `{}`", hz)),
+            },
+            _ => {
+                return Err(format!(
+                    "unknown signal `{}` (expected sine[:HZ] or noise)",
+                    spec
+                ))
+            }
+        };
+        Ok(Self { target, signal })
+    }
+}
+
+impl fmt::Display for SyntheticSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            Some(Target::Mic) => write!(f, "mic=")?,
+            Some(Target::Loopback) => write!(f, "loopback=")?,
+            None => {}
+        }
+        match self.signal {
+            Signal::Sine { hz } => write!(f, "sine:{}", hz),
+            Signal::Noise => write!(f, "noise"),
+        }
+    }
+}
+
+impl Serialize for SyntheticSource {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The signal replacing `target`, if any; a later `--source` wins over an earlier one
+pub fn signal_for(sources: &[SyntheticSource], target: Target) -> Option<Signal> {
+    sources
+        .iter()
+        .rev()
+        .find(|source| source.replaces(target))
+        .map(|source| source.signal)
+}
+
+/// Sample generator for one signal
+pub struct Generator {
+    signal: Signal,
+    sample_rate: u32,
+    /// Samples produced so far, for the sine's phase
+    position: u64,
+    rng: u64,
+}
+
+impl Generator {
+    pub fn new(signal: Signal, sample_rate: u32) -> Self {
+        Self {
+            signal,
+            sample_rate,
+            position: 0,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = match self.signal {
+            Signal::Sine { hz } => {
+                let t = self.position as f64 / self.sample_rate as f64;
+                AMPLITUDE * (TAU * hz * t).sin()
+            }
+            Signal::Noise => {
+                // xorshift64*, scaled to [-1, 1)
+                self.rng ^= self.rng >> 12;
+                self.rng ^= self.rng << 25;
+                self.rng ^= self.rng >> 27;
+                let bits = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+                AMPLITUDE * (bits as f64 / (1u64 << 52) as f64 - 1.0)
+            }
+        };
+        self.position += 1;
+        sample as f32
+    }
+}
+
+/// Feed `signal` into `ring` at `sample_rate` until `running` is cleared. While `faults`
+/// has a `device_flap` injected nothing is delivered, like a device that went away.
+pub fn spawn(
+    signal: Signal,
+    sample_rate: u32,
+    mut ring: Producer<f32>,
+    counter: Arc<SourceCounter>,
+    running: Arc<AtomicBool>,
+    faults: Option<Arc<Faults>>,
+) -> Result<thread::JoinHandle<()>> {
+    counter.set_sample_rate(sample_rate);
+    thread::Builder::new()
+        .name("synthetic-source".to_string())
+        .spawn(move || {
+            let mut generator = Generator::new(signal, sample_rate);
+            let started = Instant::now();
+            let mut produced = 0u64;
+            while running.load(Ordering::SeqCst) {
+                let due = (started.elapsed().as_nanos() * sample_rate as u128 / 1_000_000_000)
+                    as u64
+                    - produced;
+                if faults.as_ref().is_some_and(|f| f.active(Fault::DeviceFlap)) {
+                    produced += due;
+                    thread::sleep(TICK);
+                    continue;
+                }
+                // Like a device callback: whatever doesn't fit the ring is dropped
+                for _ in 0..due {
+                    let sample = generator.next_sample();
+                    let _ = ring.push(sample);
+                }
+                produced += due;
+                counter.add(due);
+                thread::sleep(TICK);
+            }
+        })
+        .context("Failed to start synthetic source")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_source_specs() {
+        let parse = |value: &str| value.parse::<SyntheticSource>();
+        assert_eq!(
+            parse("sine:440"),
+            Ok(SyntheticSource {
+                target: None,
+                signal: Signal::Sine { hz: 440.0 }
+            })
+        );
+        assert_eq!(
+            parse("loopback=noise"),
+            Ok(SyntheticSource {
+                target: Some(Target::Loopback),
+                signal: Signal::Noise
+            })
+        );
+        assert_eq!(parse("mic=sine").unwrap().to_string(), "mic=sine:1000");
+        assert!(parse("sine:-5").is_err());
+        assert!(parse("square:100").is_err());
+        assert!(parse("speakers=noise").is_err());
+
+        let sources = [parse("sine:440").unwrap(), parse("mic=noise").unwrap()];
+        assert_eq!(signal_for(&sources, Target::Mic), Some(Signal::Noise));
+        assert_eq!(
+            signal_for(&sources, Target::Loopback),
+            Some(Signal::Sine { hz: 440.0 })
+        );
+        assert_eq!(signal_for(&[], Target::Mic), None);
+    }
+
+    #[test]
+    fn generates_bounded_repeatable_signals() {
+        let mut sine = Generator::new(Signal::Sine { hz: 1_000.0 }, 48_000);
+        let period: Vec<f32> = (0..48).map(|_| sine.next_sample()).collect();
+        assert_eq!(period[0], 0.0);
+        assert!((period[12] - AMPLITUDE as f32).abs() < 1e-6);
+        assert!((sine.next_sample() - period[0]).abs() < 1e-6);
+
+        let noise = |n| {
+            let mut generator = Generator::new(Signal::Noise, 48_000);
+            (0..n).map(|_| generator.next_sample()).collect::<Vec<_>>()
+        };
+        let samples = noise(10_000);
+        assert_eq!(samples, noise(10_000));
+        assert!(samples.iter().all(|s| s.abs() <= AMPLITUDE as f32));
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(mean.abs() < 0.01);
+    }
+}
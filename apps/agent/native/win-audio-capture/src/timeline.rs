@@ -0,0 +1,297 @@
+//! Session timeline
+//! Markers and external events (slide changes, screen-share start, ...) anchored to the
+//! session's absolute sample position and persisted next to the recording as
+//! `<out>.timeline.json`, so reviews can line them up with the audio directly.
+//! The file also records the wall time of the recording's first sample, which `stitch`
+//! places the parts of a session by.
+//! Files on either side of a `new_session` hand-over name each other as `predecessor` and
+//! `successor`, with the session position the one ends and the other starts at, so a call
+//! recorded across sessions can be put back together without guessing.
+//! Once the session is over it also records where its time went (`durations`).
+
+use crate::durations::Durations;
+use crate::silence::{self, Elision};
+use crate::trim::Trim;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// One event on the session timeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    /// Event type, e.g. `slide_change` or `screen_share_start`
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Absolute sample position (per channel) the event is aligned to
+    pub sample_position: u64,
+    pub wall_time_ms: u64,
+    /// Who produced the event: `sidecar` or `external`
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// The file on the other side of a `new_session` hand-over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub session: String,
+    pub path: PathBuf,
+    /// Session position of the hand-over: the successor's first frame, the one after the
+    /// predecessor's last
+    pub sample_position: u64,
+}
+
+#[derive(Serialize)]
+struct TimelineFile<'a> {
+    session: &'a str,
+    sample_rate: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline_tag: Option<&'a str>,
+    /// Unix ms of the first sample
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_ms: Option<u64>,
+    /// Session position of the first frame, for the files after a rotation
+    #[serde(skip_serializing_if = "is_zero")]
+    start_position: u64,
+    /// Silence cut from the recording; entry positions are untrimmed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trim: Option<Trim>,
+    /// Silence left out of the recording, before any trim
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    silence: &'a [Elision],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    predecessor: Option<&'a ChainLink>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    successor: Option<&'a ChainLink>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    durations: Option<&'a Durations>,
+    entries: &'a [TimelineEntry],
+}
+
+/// A timeline file read back by the post-processing commands
+#[derive(Debug, Deserialize)]
+pub struct SavedTimeline {
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub started_ms: Option<u64>,
+    #[serde(default)]
+    pub start_position: u64,
+    #[serde(default)]
+    pub trim: Option<Trim>,
+    #[serde(default)]
+    pub silence: Vec<Elision>,
+    #[serde(default)]
+    pub predecessor: Option<ChainLink>,
+    #[serde(default)]
+    pub successor: Option<ChainLink>,
+    #[serde(default)]
+    pub durations: Option<Durations>,
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl SavedTimeline {
+    /// Where an entry lands in the (possibly compacted and trimmed) file, or `None` if it
+    /// was cut
+    pub fn file_frame(&self, entry: &TimelineEntry) -> Option<u64> {
+        let frame = silence::file_frame(&self.silence, entry.sample_position)
+            .checked_sub(self.start_position)?;
+        let Some(trim) = self.trim else {
+            return Some(frame);
+        };
+        let frame = frame.checked_sub(trim.leading_frames)?;
+        (frame <= trim.kept_frames()).then_some(frame)
+    }
+}
+
+/// Load the timeline stored next to a recording, if it has one
+pub fn load(recording_path: &Path) -> Result<Option<SavedTimeline>> {
+    match std::fs::read(sidecar_path(recording_path, "timeline.json")) {
+        Ok(bytes) => Ok(Some(
+            serde_json::from_slice(&bytes).context("Failed to parse timeline file")?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read timeline file"),
+    }
+}
+
+/// Append `items` to the `field` array of the timeline stored next to a finished recording
+pub fn append_log(recording_path: &Path, field: &str, items: Vec<Value>) -> Result<()> {
+    edit(recording_path, |object| {
+        let log = object
+            .entry(field)
+            .or_insert_with(|| Value::Array(Vec::new()))
+            .as_array_mut()
+            .with_context(|| format!("Timeline field {:?} is not an array", field))?;
+        log.extend(items);
+        Ok(())
+    })
+}
+
+/// Change the timeline stored next to a finished recording with `change`, creating the
+/// file if the recording has none. Written atomically like `Timeline::save`.
+pub fn edit(
+    recording_path: &Path,
+    change: impl FnOnce(&mut serde_json::Map<String, Value>) -> Result<()>,
+) -> Result<()> {
+    let path = sidecar_path(recording_path, "timeline.json");
+    let mut file = match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).context("Failed to parse timeline file")?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => serde_json::json!({ "entries": [] }),
+        Err(e) => return Err(e).context("Failed to read timeline file"),
+    };
+    change(
+        file.as_object_mut()
+            .context("Timeline file is not a JSON object")?,
+    )?;
+
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(&file)?)
+        .context("Failed to write timeline file")?;
+    std::fs::rename(&tmp_path, &path).context("Failed to replace timeline file")?;
+    Ok(())
+}
+
+pub struct Timeline {
+    path: PathBuf,
+    session: String,
+    sample_rate: u32,
+    pipeline_tag: Option<String>,
+    started_ms: Option<u64>,
+    start_position: u64,
+    trim: Option<Trim>,
+    silence: Vec<Elision>,
+    predecessor: Option<ChainLink>,
+    successor: Option<ChainLink>,
+    durations: Option<Durations>,
+    entries: Vec<TimelineEntry>,
+}
+
+impl Timeline {
+    pub fn new(
+        recording_path: &Path,
+        session: &str,
+        sample_rate: u32,
+        pipeline_tag: Option<&str>,
+    ) -> Self {
+        Self {
+            path: sidecar_path(recording_path, "timeline.json"),
+            session: session.to_string(),
+            sample_rate,
+            pipeline_tag: pipeline_tag.map(str::to_string),
+            started_ms: None,
+            start_position: 0,
+            trim: None,
+            silence: Vec::new(),
+            predecessor: None,
+            successor: None,
+            durations: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Record when the first sample was captured
+    pub fn with_start(mut self, started_ms: u64) -> Self {
+        self.started_ms = Some(started_ms);
+        self
+    }
+
+    /// Record the session position the file starts at, for a file begun by a rotation
+    pub fn starting_at(mut self, position: u64) -> Self {
+        self.start_position = position;
+        self
+    }
+
+    /// Record the file of the previous session this one took over from
+    pub fn with_predecessor(mut self, link: ChainLink) -> Self {
+        self.predecessor = Some(link);
+        self
+    }
+
+    /// Record the file of the session that took over from this one and persist it
+    pub fn set_successor(&mut self, link: ChainLink) -> Result<()> {
+        self.successor = Some(link);
+        self.save()
+    }
+
+    /// Insert an entry keeping the timeline ordered by sample position, then persist it
+    /// so entries survive a crash
+    pub fn push(&mut self, entry: TimelineEntry) -> Result<()> {
+        let index = self
+            .entries
+            .partition_point(|e| e.sample_position <= entry.sample_position);
+        self.entries.insert(index, entry);
+        self.save()
+    }
+
+    /// Take the entries from `position` on out (they belong to the next file of a
+    /// rotation) and persist what is left
+    pub fn split_off(&mut self, position: u64) -> Result<Vec<TimelineEntry>> {
+        let index = self
+            .entries
+            .partition_point(|e| e.sample_position < position);
+        let moved = self.entries.split_off(index);
+        self.save()?;
+        Ok(moved)
+    }
+
+    /// Record the silence trimmed from the finished recording and persist it
+    pub fn set_trim(&mut self, trim: Trim) -> Result<()> {
+        self.trim = Some(trim);
+        self.save()
+    }
+
+    /// Record the silence left out of the finished recording and persist it
+    pub fn set_silence(&mut self, silence: Vec<Elision>) -> Result<()> {
+        self.silence = silence;
+        self.save()
+    }
+
+    /// Record where the session's time went and persist it
+    pub fn set_durations(&mut self, durations: Durations) -> Result<()> {
+        self.durations = Some(durations);
+        self.save()
+    }
+
+    /// Write the timeline file atomically (temp file + rename)
+    pub fn save(&self) -> Result<()> {
+        let file = TimelineFile {
+            session: &self.session,
+            sample_rate: self.sample_rate,
+            pipeline_tag: self.pipeline_tag.as_deref(),
+            started_ms: self.started_ms,
+            start_position: self.start_position,
+            trim: self.trim,
+            silence: &self.silence,
+            predecessor: self.predecessor.as_ref(),
+            successor: self.successor.as_ref(),
+            durations: self.durations.as_ref(),
+            entries: &self.entries,
+        };
+        let json = serde_json::to_vec_pretty(&file)?;
+
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, json).context("Failed to write timeline file")?;
+        std::fs::rename(&tmp_path, &self.path).context("Failed to replace timeline file")?;
+        Ok(())
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Path of a metadata file stored next to the recording: `<dir>/<stem>.<suffix>`
+pub fn sidecar_path(recording_path: &Path, suffix: &str) -> PathBuf {
+    // Built as an OsString so non-Unicode stems survive
+    let mut name = recording_path
+        .file_stem()
+        .map(OsString::from)
+        .unwrap_or_else(|| OsString::from("recording"));
+    name.push(".");
+    name.push(suffix);
+    recording_path.with_file_name(name)
+}
@@ -0,0 +1,283 @@
+//! Pipeline tracing
+//! Device callbacks, mixer rounds, sink writes, frame writes and control commands are
+//! wrapped in spans. With `--trace-out <file.json>` they are recorded and written at the
+//! end of the session in the Chrome trace event format, which `chrome://tracing` and
+//! Perfetto open directly, so latency spikes can be lined up against what every thread
+//! was doing at the time. Without it a span is a single relaxed load.
+//!
+//! Spans are queued per thread on a wait-free ring and moved into the trace by a collector
+//! thread, so spans on real-time threads never lock or allocate once the thread is
+//! registered; those threads call `register_thread` before their hot path. Spans are per
+//! callback or per block, never per sample.
+
+use crate::rt;
+use anyhow::{Context, Result};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Spans kept per session (a few hours of every span at the usual rates); later ones are
+/// counted as dropped
+const MAX_EVENTS: usize = 4_000_000;
+
+/// Spans a thread can queue before the collector picks them up
+const THREAD_QUEUE: usize = 1 << 15;
+
+const COLLECT_INTERVAL: Duration = Duration::from_millis(50);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_ID: Cell<u64> = const { Cell::new(0) };
+    static QUEUE: RefCell<Option<Producer<Event>>> = const { RefCell::new(None) };
+}
+
+struct Recorder {
+    epoch: Instant,
+    queues: Mutex<Vec<Consumer<Event>>>,
+    events: Mutex<Vec<Event>>,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Event {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u64>,
+    pid: u32,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<serde_json::Value>,
+}
+
+/// What `finish` wrote
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceSummary {
+    pub path: PathBuf,
+    pub spans: usize,
+    pub dropped: u64,
+}
+
+/// Start recording spans
+pub fn start() {
+    RECORDER.get_or_init(|| {
+        let spawned = std::thread::Builder::new()
+            .name("trace-collect".to_string())
+            .spawn(|| loop {
+                std::thread::sleep(COLLECT_INTERVAL);
+                if ENABLED.load(Ordering::Acquire) {
+                    if let Some(recorder) = RECORDER.get() {
+                        recorder.collect();
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!(
+                "[win-audio-capture] Warning: Failed to start the trace collector: {}",
+                e
+            );
+        }
+        Recorder {
+            epoch: Instant::now(),
+            queues: Mutex::new(Vec::new()),
+            events: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        }
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// A span lasting until the guard is dropped
+pub fn span(name: &'static str) -> Span {
+    let started = ENABLED
+        .load(Ordering::Relaxed)
+        .then(|| (Instant::now(), thread_id()));
+    Span { name, started }
+}
+
+pub struct Span {
+    name: &'static str,
+    started: Option<(Instant, u64)>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let (Some((started, tid)), Some(recorder)) = (self.started, RECORDER.get()) else {
+            return;
+        };
+        let ts = started
+            .saturating_duration_since(recorder.epoch)
+            .as_micros() as u64;
+        let dur = started.elapsed().as_micros() as u64;
+        recorder.record(Event {
+            name: self.name,
+            cat: "pipeline",
+            ph: "X",
+            ts,
+            dur: Some(dur),
+            pid: std::process::id(),
+            tid,
+            args: None,
+        });
+    }
+}
+
+impl Recorder {
+    /// Queue on the current thread's ring, registering the thread first if needed
+    fn record(&self, event: Event) {
+        let _ = QUEUE.try_with(|queue| {
+            let mut queue = queue.borrow_mut();
+            let producer = queue.get_or_insert_with(|| self.register_queue());
+            if producer.push(event).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    fn register_queue(&self) -> Producer<Event> {
+        let (producer, consumer) = RingBuffer::new(THREAD_QUEUE);
+        rt::check(rt::Violation::Lock);
+        self.queues
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(consumer);
+        producer
+    }
+
+    /// Move queued spans into the trace, forgetting the queues of finished threads
+    fn collect(&self) {
+        let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        queues.retain_mut(|queue| {
+            while let Ok(event) = queue.pop() {
+                if events.len() < MAX_EVENTS {
+                    events.push(event);
+                } else {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            !queue.is_abandoned()
+        });
+    }
+}
+
+/// Set up the current thread's span queue now rather than on its first span; real-time
+/// threads call this before entering their hot path
+pub fn register_thread() {
+    if ENABLED.load(Ordering::Relaxed) {
+        thread_id();
+    }
+}
+
+/// Small stable id for the current thread; the first span on a thread also records its name
+fn thread_id() -> u64 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+            if let Some(recorder) = RECORDER.get() {
+                let name = std::thread::current()
+                    .name()
+                    .unwrap_or("unnamed")
+                    .to_string();
+                recorder.record(Event {
+                    name: "thread_name",
+                    cat: "__metadata",
+                    ph: "M",
+                    ts: 0,
+                    dur: None,
+                    pid: std::process::id(),
+                    tid: id.get(),
+                    args: Some(serde_json::json!({ "name": name })),
+                });
+            }
+        }
+        id.get()
+    })
+}
+
+/// Stop recording and write the spans to `path`
+pub fn finish(path: &Path) -> Result<TraceSummary> {
+    ENABLED.store(false, Ordering::Release);
+    let (events, dropped) = match RECORDER.get() {
+        Some(recorder) => {
+            recorder.collect();
+            let mut events = recorder.events.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                std::mem::take(&mut *events),
+                recorder.dropped.swap(0, Ordering::Relaxed),
+            )
+        }
+        None => (Vec::new(), 0),
+    };
+    let spans = events.iter().filter(|e| e.ph == "X").count();
+    let trace = serde_json::json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+        "otherData": { "dropped_spans": dropped },
+    });
+
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    std::fs::write(&tmp_path, serde_json::to_vec(&trace)?)
+        .with_context(|| format!("Failed to write trace {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path).context("Failed to move trace into place")?;
+    Ok(TraceSummary {
+        path: path.to_path_buf(),
+        spans,
+        dropped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The recorder is process-wide (other tests may add spans meanwhile), so everything
+    // runs in one test
+    #[test]
+    fn records_spans_per_thread_as_chrome_trace_events() {
+        drop(span("before_start"));
+        start();
+        drop(span("main_work"));
+        std::thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(|| {
+                let _outer = span("outer");
+                drop(span("inner"));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+        let summary = finish(&path).unwrap();
+        drop(span("after_finish"));
+
+        assert!(summary.spans >= 3);
+        let trace: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let find = |name: &str| events.iter().find(|e| e["name"] == name).unwrap();
+        let (outer, inner) = (find("outer"), find("inner"));
+        assert_eq!(outer["ph"], "X");
+        assert_eq!(outer["tid"], inner["tid"]);
+        assert_ne!(outer["tid"], find("main_work")["tid"]);
+        assert!(outer["ts"].as_u64() <= inner["ts"].as_u64());
+        assert!(events
+            .iter()
+            .any(|e| e["ph"] == "M" && e["args"]["name"] == "worker"));
+        assert!(!events.iter().any(|e| e["name"] == "before_start"));
+    }
+}
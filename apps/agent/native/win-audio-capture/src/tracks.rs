@@ -0,0 +1,204 @@
+//! Per-track output
+//! `--output-mode split` delivers the call as one mono file per side instead of the stereo
+//! recording: `<stem>.mic.wav` (the rep) and `<stem>.loopback.wav` (the prospect), which
+//! diarization and per-speaker analytics want as separate inputs. `both` writes them next to
+//! the recording and keeps it, and `--mixdown` adds `<stem>.mix.wav`, every channel averaged
+//! into one for playback. The tracks are cut from the finished recording, so they share its
+//! resampling, drift correction, trim and redaction and stay sample-aligned with each other.
+//! An extra loopback channel becomes `<stem>.loopback2.wav` and the notes channel
+//! `<stem>.notes.wav`. With `split` the recording is removed only once every track is written.
+
+use crate::progress::Report;
+use crate::riff::{self, RiffAudio};
+use crate::sink::{self, OutputFormat, RecordingSink, SinkSpec};
+use crate::timeline;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// `--output-mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// One interleaved recording
+    Stereo,
+    /// One mono file per channel, without the recording
+    Split,
+    /// The recording and one mono file per channel
+    Both,
+}
+
+/// One mono file written next to the recording
+#[derive(Debug, Clone, Serialize)]
+pub struct Track {
+    /// `mic`, `loopback`, `loopback2`, `notes` or `mix`
+    pub name: String,
+    pub path: PathBuf,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// Path of the `name` track of `recording`
+pub fn track_path(recording: &Path, name: &str) -> PathBuf {
+    timeline::sidecar_path(recording, &format!("{}.wav", name))
+}
+
+/// Track names of the channels of a recording, in channel order
+pub fn channel_names(channels: u16, notes_channel: bool) -> Vec<String> {
+    let voices = channels.saturating_sub(notes_channel as u16) as usize;
+    let mut names: Vec<String> = (0..voices)
+        .map(|channel| match channel {
+            0 => "mic".to_string(),
+            1 => "loopback".to_string(),
+            n => format!("loopback{}", n),
+        })
+        .collect();
+    if notes_channel {
+        names.push("notes".to_string());
+    }
+    names
+}
+
+/// Write one mono track per channel of a finished recording, and the mixdown if asked for
+pub fn write(
+    recording: &Path,
+    format: OutputFormat,
+    notes_channel: bool,
+    mixdown: bool,
+    progress: Report,
+) -> Result<Vec<Track>> {
+    let audio = RiffAudio::open(recording)?;
+    let channels = audio.channels as usize;
+    let mut names = channel_names(audio.channels, notes_channel);
+    if mixdown {
+        names.push("mix".to_string());
+    }
+    let spec = SinkSpec {
+        channels: 1,
+        sample_rate: audio.sample_rate,
+    };
+    let mut outputs: Vec<(String, PathBuf, Box<dyn RecordingSink>)> = Vec::new();
+    for name in names {
+        let mut tmp_name = OsString::from(track_path(recording, &name).as_os_str());
+        tmp_name.push(".partial");
+        let tmp_path = PathBuf::from(tmp_name);
+        match sink::create(format, &tmp_path, spec) {
+            Ok(out) => outputs.push((name, tmp_path, out)),
+            Err(e) => {
+                remove_partials(&outputs);
+                return Err(e);
+            }
+        }
+    }
+
+    let mut mono = Vec::new();
+    let mut read = 0;
+    let written = riff::read_frames(recording, &audio, 0, audio.frames(), |samples| {
+        for (index, (_, _, out)) in outputs.iter_mut().enumerate() {
+            mono.clear();
+            if index < channels {
+                mono.extend(samples.iter().skip(index).step_by(channels));
+            } else {
+                mono.extend(samples.chunks_exact(channels).map(|frame| {
+                    let sum: i32 = frame.iter().map(|&s| s as i32).sum();
+                    (sum / channels as i32) as i16
+                }));
+            }
+            out.write_samples(&mono)?;
+        }
+        read += (samples.len() / channels) as u64;
+        progress(read, audio.frames())
+    });
+    if let Err(e) = written {
+        remove_partials(&outputs);
+        return Err(e);
+    }
+
+    let mut tracks = Vec::new();
+    let mut partials = Vec::new();
+    for (name, tmp_path, out) in outputs {
+        partials.push(tmp_path.clone());
+        let summary = match out.finalize() {
+            Ok(summary) => summary,
+            Err(e) => {
+                partials.iter().for_each(|path| {
+                    let _ = std::fs::remove_file(path);
+                });
+                return Err(e);
+            }
+        };
+        tracks.push(Track {
+            path: track_path(recording, &name),
+            name,
+            frames: summary.frames,
+            bytes: summary.bytes,
+        });
+    }
+    for (tmp_path, track) in partials.iter().zip(&tracks) {
+        std::fs::rename(tmp_path, &track.path)
+            .with_context(|| format!("Failed to move the {} track into place", track.name))?;
+    }
+    Ok(tracks)
+}
+
+fn remove_partials(outputs: &[(String, PathBuf, Box<dyn RecordingSink>)]) {
+    for (_, tmp_path, _) in outputs {
+        let _ = std::fs::remove_file(tmp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress;
+
+    #[test]
+    fn names_the_channels_of_the_layout() {
+        assert_eq!(channel_names(2, false), ["mic", "loopback"]);
+        assert_eq!(channel_names(3, false), ["mic", "loopback", "loopback2"]);
+        assert_eq!(channel_names(3, true), ["mic", "loopback", "notes"]);
+    }
+
+    #[test]
+    fn splits_the_channels_into_aligned_mono_tracks() {
+        let dir = std::env::temp_dir().join(format!("tracks-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recording = dir.join("call.wav");
+        let spec = SinkSpec {
+            channels: 2,
+            sample_rate: 16_000,
+        };
+        let mut out = sink::create(OutputFormat::Wav, &recording, spec).unwrap();
+        out.write_samples(&[100, -100, 200, 0, 300, 101]).unwrap();
+        out.finalize().unwrap();
+
+        let tracks = write(
+            &recording,
+            OutputFormat::Wav,
+            false,
+            true,
+            &mut progress::ignore,
+        )
+        .unwrap();
+        let names: Vec<_> = tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, ["mic", "loopback", "mix"]);
+        let samples = |track: &Track| {
+            let audio = RiffAudio::open(&track.path).unwrap();
+            assert_eq!(audio.channels, 1);
+            let mut all = Vec::new();
+            riff::read_frames(&track.path, &audio, 0, audio.frames(), |s| {
+                all.extend_from_slice(s);
+                Ok(())
+            })
+            .unwrap();
+            all
+        };
+        assert_eq!(samples(&tracks[0]), [100, 200, 300]);
+        assert_eq!(samples(&tracks[1]), [-100, 0, 101]);
+        assert_eq!(samples(&tracks[2]), [0, 100, 200]);
+        assert!(tracks.iter().all(|track| track.frames == 3));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,128 @@
+//! Transcript alignment
+//! ASR backends time words from the start of the audio they were given, at whatever rate
+//! the agent resampled it to, and that audio isn't the session: the agent may have started
+//! feeding late or lost frames when it lagged behind the stream. The agent passes the
+//! backend's words on with `{"cmd":"transcript"}` along with the stretches of the stream
+//! the backend got (the SampleOffset of the first frame it fed and how many frames
+//! followed, starting over after every gap), and the sidecar emits them as a `transcript`
+//! event on the session's absolute sample clock, the same one markers, highlights and
+//! redactions are positioned on.
+//!
+//! Word times are milliseconds of the backend's audio, so the rate it ran at doesn't matter.
+
+use serde::{Deserialize, Serialize};
+
+/// A word as the ASR backend timed it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Word {
+    pub text: String,
+    /// From the start of the backend's audio
+    pub start_ms: f64,
+    pub end_ms: f64,
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    #[serde(default)]
+    pub speaker: Option<String>,
+}
+
+/// A stretch of the stream the backend got without a break
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Fed {
+    /// SampleOffset of the first frame fed
+    pub sample_offset: u64,
+    pub frames: u64,
+}
+
+/// A word on the session clock
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlignedWord {
+    pub text: String,
+    /// Absolute sample position (per channel) the word starts at
+    pub sample_position: u64,
+    pub end_position: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
+
+/// Session position of `ms` into the audio made of `fed`, at `sample_rate`. A time past the
+/// end is carried on from the last stretch; with nothing fed it is taken from position 0.
+pub fn position(fed: &[Fed], sample_rate: u32, ms: f64) -> u64 {
+    let mut frame = (ms.max(0.0) * sample_rate as f64 / 1000.0).round() as u64;
+    for (i, span) in fed.iter().enumerate() {
+        if frame < span.frames || i + 1 == fed.len() {
+            return span.sample_offset + frame;
+        }
+        frame -= span.frames;
+    }
+    frame
+}
+
+/// `words` moved onto the session clock
+pub fn align(words: Vec<Word>, fed: &[Fed], sample_rate: u32) -> Vec<AlignedWord> {
+    words
+        .into_iter()
+        .map(|word| {
+            let sample_position = position(fed, sample_rate, word.start_ms);
+            AlignedWord {
+                sample_position,
+                end_position: position(fed, sample_rate, word.end_ms).max(sample_position),
+                text: word.text,
+                confidence: word.confidence,
+                speaker: word.speaker,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_backend_time_across_gaps() {
+        // Fed from 1s in; 4800 frames were lost after the first 2s
+        let fed = [
+            Fed {
+                sample_offset: 48_000,
+                frames: 96_000,
+            },
+            Fed {
+                sample_offset: 148_800,
+                frames: 48_000,
+            },
+        ];
+        assert_eq!(position(&fed, 48_000, 0.0), 48_000);
+        assert_eq!(position(&fed, 48_000, 1_500.0), 120_000);
+        assert_eq!(position(&fed, 48_000, 2_000.0), 148_800);
+        assert_eq!(position(&fed, 48_000, 2_500.0), 172_800);
+        // Past what was reported fed, carried on from the last stretch
+        assert_eq!(position(&fed, 48_000, 4_000.0), 244_800);
+        assert_eq!(position(&[], 48_000, 10.0), 480);
+    }
+
+    #[test]
+    fn a_word_straddling_a_gap_spans_it() {
+        let fed = [
+            Fed {
+                sample_offset: 0,
+                frames: 1_000,
+            },
+            Fed {
+                sample_offset: 5_000,
+                frames: 1_000,
+            },
+        ];
+        let words = vec![Word {
+            text: "pricing".to_string(),
+            start_ms: 900.0,
+            end_ms: 1_100.0,
+            confidence: Some(0.93),
+            speaker: None,
+        }];
+        let aligned = align(words, &fed, 1_000);
+        assert_eq!(aligned[0].sample_position, 900);
+        assert_eq!(aligned[0].end_position, 5_100);
+    }
+}
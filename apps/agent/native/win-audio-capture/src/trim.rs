@@ -0,0 +1,124 @@
+//! Leading and trailing silence trimming
+//! With `--trim-silence` the sink writer tracks the first and last frame where any channel
+//! rises above the threshold, and after finalizing the file is rewritten without the
+//! silence around them (lobby music before the call counts as audio, the quiet before the
+//! first word doesn't). A short pad is kept on both ends. Timeline positions stay relative
+//! to the untrimmed session; the offsets are recorded so they can be mapped.
+
+use serde::{Deserialize, Serialize};
+
+/// Audio kept before the first and after the last loud frame
+pub const TRIM_PADDING_MS: u64 = 500;
+
+/// What was cut from the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trim {
+    /// Frames removed from the start; subtract from timeline positions to get file positions
+    pub leading_frames: u64,
+    pub trailing_frames: u64,
+    /// Length of the recording before trimming
+    pub original_frames: u64,
+}
+
+impl Trim {
+    pub fn kept_frames(&self) -> u64 {
+        self.original_frames - self.leading_frames - self.trailing_frames
+    }
+}
+
+/// Tracks where the recording has audio above the threshold
+pub struct SilenceTracker {
+    channels: usize,
+    threshold: i16,
+    frames: u64,
+    first_loud: Option<u64>,
+    last_loud: u64,
+}
+
+/// Sample magnitude of `threshold_db` dBFS, at least 1
+pub fn threshold(threshold_db: f64) -> i16 {
+    let threshold = (i16::MAX as f64 * 10f64.powf(threshold_db / 20.0)).round();
+    threshold.clamp(1.0, i16::MAX as f64) as i16
+}
+
+impl SilenceTracker {
+    pub fn new(channels: usize, threshold_db: f64) -> Self {
+        Self {
+            channels,
+            threshold: threshold(threshold_db),
+            frames: 0,
+            first_loud: None,
+            last_loud: 0,
+        }
+    }
+
+    /// Account for interleaved samples, in recording order
+    pub fn observe(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(self.channels) {
+            if frame
+                .iter()
+                .any(|sample| sample.unsigned_abs() > self.threshold as u16)
+            {
+                self.first_loud.get_or_insert(self.frames);
+                self.last_loud = self.frames;
+            }
+            self.frames += 1;
+        }
+    }
+
+    /// What to cut, keeping `padding_frames` around the audio. `None` when there is nothing
+    /// to cut, or no audio at all (a silent file is kept as recorded).
+    pub fn trim(&self, padding_frames: u64) -> Option<Trim> {
+        let first = self.first_loud?;
+        let leading_frames = first.saturating_sub(padding_frames);
+        let end = (self.last_loud + 1 + padding_frames).min(self.frames);
+        let trim = Trim {
+            leading_frames,
+            trailing_frames: self.frames - end,
+            original_frames: self.frames,
+        };
+        (trim.leading_frames > 0 || trim.trailing_frames > 0).then_some(trim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo(frames: &[(i16, i16)]) -> Vec<i16> {
+        frames.iter().flat_map(|&(l, r)| [l, r]).collect()
+    }
+
+    #[test]
+    fn cuts_silence_on_both_ends_with_padding() {
+        let mut tracker = SilenceTracker::new(2, -40.0);
+        let mut frames = vec![(0, 3); 100];
+        frames.extend([(5_000, 0), (0, 0), (0, -5_000)]);
+        frames.extend(vec![(2, 0); 50]);
+        tracker.observe(&stereo(&frames));
+
+        let trim = tracker.trim(10).unwrap();
+        assert_eq!(trim.leading_frames, 90);
+        assert_eq!(trim.trailing_frames, 40);
+        assert_eq!(trim.kept_frames(), 23);
+    }
+
+    #[test]
+    fn silent_or_already_tight_recordings_are_kept() {
+        let mut silent = SilenceTracker::new(2, -40.0);
+        silent.observe(&stereo(&[(0, 0); 1_000]));
+        assert_eq!(silent.trim(10), None);
+
+        let mut tight = SilenceTracker::new(2, -40.0);
+        tight.observe(&stereo(&[(0, 0), (9_000, 9_000), (0, 0)]));
+        assert_eq!(tight.trim(10), None);
+    }
+
+    #[test]
+    fn any_channel_counts_as_audio() {
+        let mut tracker = SilenceTracker::new(3, -40.0);
+        tracker.observe(&[0, 0, 0, 0, 0, 8_000, 0, 0, 0]);
+        let trim = tracker.trim(0).unwrap();
+        assert_eq!((trim.leading_frames, trim.trailing_frames), (1, 1));
+    }
+}
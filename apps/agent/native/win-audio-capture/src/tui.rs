@@ -0,0 +1,534 @@
+//! Terminal status view
+//! `--tui` draws the session on the console it runs in, for field engineers debugging on a
+//! machine without the agent: a level meter for each channel, blocks dropped from the
+//! stream and the mixer's xruns, how full the queues are, the files being written and the
+//! latest events. It is built from the same event lines the agent gets, with the `stats`
+//! heartbeat on (every `--stats-interval-ms`), and drawn on the console's alternate screen
+//! so stray warnings are painted over on the next redraw. While it is up, event lines are
+//! not written to a stderr that is the console too; the event journal still has all of
+//! them. The audio stream still goes to stdout, which has to be sent elsewhere (`> NUL`).
+//! When the session ends the last view is left in the console's scrollback.
+
+use crate::health::ChannelLevel;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+
+/// Events listed under "Recent events"
+pub const RECENT_EVENTS: usize = 8;
+/// Bottom of the meter scale; anything quieter is drawn empty
+const FLOOR_DBFS: f64 = -60.0;
+/// Peak at which a meter turns red
+const CLIP_DBFS: f64 = -1.0;
+const METER_WIDTH: usize = 40;
+/// Too frequent to list; `stats` is shown by the meters
+const UNLISTED: [&str; 3] = ["stats", "position", "finalize_progress"];
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Starting,
+    Recording,
+    Paused,
+    Stopping,
+    Stopped,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Starting => "starting",
+            State::Recording => "recording",
+            State::Paused => "paused",
+            State::Stopping => "stopping",
+            State::Stopped => "stopped",
+        }
+    }
+}
+
+/// What the last `stats` heartbeat said
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Stats {
+    sample_position: u64,
+    levels: Levels,
+    stream_drops: u64,
+    xruns: Xruns,
+    queues: Queues,
+    paused: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Levels {
+    mic: Option<ChannelLevel>,
+    loopback: Option<ChannelLevel>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Xruns {
+    mic_underruns: u64,
+    loopback_underruns: u64,
+    mic_overruns: u64,
+    loopback_overruns: u64,
+    gaps: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Queues {
+    mixer: u64,
+    sink: u64,
+    stream: u64,
+}
+
+/// A file the session writes
+#[derive(Debug)]
+struct Sink {
+    path: String,
+    status: &'static str,
+}
+
+/// The session as the view shows it, updated from event lines
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    session: String,
+    sample_rate: u64,
+    state: State,
+    stats: Stats,
+    sinks: Vec<Sink>,
+    recent: VecDeque<String>,
+}
+
+impl Dashboard {
+    /// Take in one event line; whether the view changed
+    pub fn apply(&mut self, line: &str) -> bool {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return false;
+        };
+        let Some(name) = event["event"].as_str() else {
+            return false;
+        };
+        let text = |key: &str| event[key].as_str().map(str::to_string);
+        match name {
+            "started" => {
+                self.session = text("session").unwrap_or_default();
+                self.sample_rate = event["sample_rate"].as_u64().unwrap_or(0);
+                self.state = State::Recording;
+                if let Some(path) = text("path") {
+                    self.add_sink(path);
+                }
+            }
+            "stats" => {
+                if let Ok(stats) = Stats::deserialize(&event) {
+                    self.stats = stats;
+                }
+                if matches!(self.state, State::Recording | State::Paused) {
+                    self.state = match self.stats.paused {
+                        true => State::Paused,
+                        false => State::Recording,
+                    };
+                }
+            }
+            "rotated" => {
+                if let Some(path) = text("path") {
+                    self.set_status(&path, "closed");
+                }
+                if let Some(next) = text("next") {
+                    self.add_sink(next);
+                }
+            }
+            "reconfigured" => {
+                if let Some(path) = text("added_sink") {
+                    self.add_sink(path);
+                }
+            }
+            "spooled" => {
+                if let Some(destination) = text("destination") {
+                    self.set_status(&destination, "spooled");
+                }
+            }
+            "stopping" => self.state = State::Stopping,
+            "stopped" => {
+                self.state = State::Stopped;
+                for sink in &mut self.sinks {
+                    if sink.status == "recording" {
+                        sink.status = "finalized";
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !UNLISTED.contains(&name) {
+            // Local wall time, out of `YYYY-MM-DDTHH:MM:SS.mmm+hh:mm`
+            let time = event["ts"].as_str().and_then(|ts| ts.get(11..19));
+            let mut entry = format!("{} {}", time.unwrap_or("--:--:--"), name);
+            if let Some(message) = event["message"].as_str() {
+                entry.push_str(": ");
+                entry.push_str(message);
+            }
+            if self.recent.len() == RECENT_EVENTS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(entry);
+        }
+        true
+    }
+
+    fn add_sink(&mut self, path: String) {
+        match self.sinks.iter_mut().find(|sink| sink.path == path) {
+            Some(sink) => sink.status = "recording",
+            None => self.sinks.push(Sink {
+                path,
+                status: "recording",
+            }),
+        }
+    }
+
+    fn set_status(&mut self, path: &str, status: &'static str) {
+        if let Some(sink) = self.sinks.iter_mut().find(|sink| sink.path == path) {
+            sink.status = status;
+        }
+    }
+
+    /// The view, a line per console row (with colors)
+    pub fn lines(&self) -> Vec<String> {
+        let stats = &self.stats;
+        let elapsed_secs = match self.sample_rate {
+            0 => 0,
+            rate => stats.sample_position / rate,
+        };
+        let mut lines = vec![
+            format!(
+                "{}Selly capture{}  {}  {} Hz  {:02}:{:02}:{:02}  {}",
+                BOLD,
+                RESET,
+                self.session,
+                self.sample_rate,
+                elapsed_secs / 3600,
+                elapsed_secs / 60 % 60,
+                elapsed_secs % 60,
+                self.state.as_str()
+            ),
+            String::new(),
+            meter_line("MIC", stats.levels.mic.as_ref()),
+            meter_line("Loopback", stats.levels.loopback.as_ref()),
+            String::new(),
+            format!(
+                "Drops     stream {}  underruns mic {} loopback {}  overruns mic {} loopback {}  gaps {}",
+                stats.stream_drops,
+                stats.xruns.mic_underruns,
+                stats.xruns.loopback_underruns,
+                stats.xruns.mic_overruns,
+                stats.xruns.loopback_overruns,
+                stats.xruns.gaps
+            ),
+            format!(
+                "Queues    mixer {}  sink {}  stream {}",
+                stats.queues.mixer, stats.queues.sink, stats.queues.stream
+            ),
+            String::new(),
+            format!("{}Sinks{}", BOLD, RESET),
+        ];
+        lines.extend(
+            self.sinks
+                .iter()
+                .map(|sink| format!("  {:<10} {}", sink.status, sink.path)),
+        );
+        lines.push(String::new());
+        lines.push(format!("{}Recent events{}", BOLD, RESET));
+        lines.extend(self.recent.iter().map(|entry| format!("  {}", entry)));
+        lines
+    }
+}
+
+/// `MIC       [#####|----]  -18.0 dBFS  peak  -6.0`: the bar is the RMS level, `|` the peak
+fn meter_line(label: &str, level: Option<&ChannelLevel>) -> String {
+    let Some(level) = level else {
+        return format!("{:<9} [{}]  no signal yet", label, "-".repeat(METER_WIDTH));
+    };
+    let cells = |dbfs: f64| {
+        ((dbfs - FLOOR_DBFS) / -FLOOR_DBFS * METER_WIDTH as f64)
+            .round()
+            .clamp(0.0, METER_WIDTH as f64) as usize
+    };
+    let filled = cells(level.rms_dbfs);
+    let peak = cells(level.peak_dbfs).max(filled);
+    let color = if level.peak_dbfs >= CLIP_DBFS {
+        RED
+    } else if level.silent_ms > 0 {
+        DIM
+    } else {
+        GREEN
+    };
+    let mut bar = format!("{}{}{}", color, "#".repeat(filled), RESET);
+    if peak > filled {
+        bar.push_str(&"-".repeat(peak - filled - 1));
+        bar.push('|');
+    }
+    bar.push_str(&"-".repeat(METER_WIDTH - peak));
+    let mut line = format!(
+        "{:<9} [{}] {:>6.1} dBFS  peak {:>6.1}",
+        label, bar, level.rms_dbfs, level.peak_dbfs
+    );
+    if level.silent_ms > 0 {
+        line.push_str(&format!("  silent {:.1}s", level.silent_ms as f64 / 1000.0));
+    }
+    line
+}
+
+#[cfg(windows)]
+pub use console::{start, Tui};
+
+#[cfg(windows)]
+mod console {
+    use super::Dashboard;
+    use crate::events;
+    use crate::subscription::Subscription;
+    use anyhow::{Context, Result};
+    use std::fs::{File, OpenOptions};
+    use std::io::{IsTerminal, Write};
+    use std::os::windows::io::AsRawHandle;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, UnboundedSender};
+    use tokio::task::JoinHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Console::{
+        GetConsoleMode, SetConsoleMode, CONSOLE_MODE, ENABLE_PROCESSED_OUTPUT,
+        ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+    };
+
+    /// Event lines arriving faster than this are drawn together
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// The view while it is up; dropped without `finish` (the session failed) it still
+    /// gives the console back
+    pub struct Tui {
+        observer: UnboundedSender<String>,
+        task: Option<JoinHandle<()>>,
+    }
+
+    /// Take over the console and draw the session's events on it
+    pub fn start() -> Result<Tui> {
+        let mut screen = Screen::open()?;
+        let (observer, mut lines) = mpsc::unbounded_channel();
+        events::add_observer(observer.clone(), Subscription::default());
+        let task = tokio::spawn(async move {
+            let mut dashboard = Dashboard::default();
+            let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+            redraw.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut changed = true;
+            loop {
+                tokio::select! {
+                    line = lines.recv() => match line {
+                        Some(line) => changed |= dashboard.apply(&line),
+                        None => break,
+                    },
+                    _ = redraw.tick(), if changed => {
+                        screen.draw(dashboard.lines());
+                        changed = false;
+                    }
+                }
+            }
+            screen.draw(dashboard.lines());
+        });
+        Ok(Tui {
+            observer,
+            task: Some(task),
+        })
+    }
+
+    impl Tui {
+        /// Draw the last events and leave the view in the scrollback
+        pub async fn finish(mut self) {
+            events::remove_observer(&self.observer);
+            let task = self.task.take();
+            drop(self);
+            if let Some(task) = task {
+                let _ = task.await;
+            }
+        }
+    }
+
+    impl Drop for Tui {
+        fn drop(&mut self) {
+            // Closes the channel, which ends the drawing task
+            events::remove_observer(&self.observer);
+        }
+    }
+
+    /// The console's alternate screen, with VT sequences on
+    struct Screen {
+        console: File,
+        mode: CONSOLE_MODE,
+        last: Vec<String>,
+    }
+
+    impl Screen {
+        fn open() -> Result<Self> {
+            let console = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("CONOUT$")
+                .context("--tui needs a console to draw on")?;
+            let handle = HANDLE(console.as_raw_handle());
+            let mut mode = CONSOLE_MODE::default();
+            unsafe {
+                GetConsoleMode(handle, &mut mode).context("Failed to read the console mode")?;
+                SetConsoleMode(
+                    handle,
+                    mode | ENABLE_PROCESSED_OUTPUT | ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+                )
+                .context("The console doesn't take VT sequences (Windows 10 or later needed)")?;
+            }
+            // Event lines on the same console would scroll the view away
+            events::set_stderr(!std::io::stderr().is_terminal());
+            let mut screen = Self {
+                console,
+                mode,
+                last: Vec::new(),
+            };
+            // Alternate screen, cursor hidden
+            screen.write("\x1b[?1049h\x1b[?25l");
+            Ok(screen)
+        }
+
+        fn draw(&mut self, lines: Vec<String>) {
+            let mut frame = String::from("\x1b[H");
+            for line in &lines {
+                frame.push_str(line);
+                frame.push_str("\x1b[K\n");
+            }
+            frame.push_str("\x1b[J");
+            self.write(&frame);
+            self.last = lines;
+        }
+
+        fn write(&mut self, text: &str) {
+            let _ = self.console.write_all(text.as_bytes());
+            let _ = self.console.flush();
+        }
+    }
+
+    impl Drop for Screen {
+        fn drop(&mut self) {
+            let mut text = String::from("\x1b[?1049l\x1b[?25h");
+            for line in &self.last {
+                text.push_str(line);
+                text.push('\n');
+            }
+            self.write(&text);
+            unsafe {
+                let _ = SetConsoleMode(HANDLE(self.console.as_raw_handle()), self.mode);
+            }
+            events::set_stderr(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn line(name: &str, fields: Value) -> String {
+        let mut event = json!({ "event": name, "ts": "2026-10-15T14:03:07.250+02:00" });
+        event
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        event.to_string()
+    }
+
+    #[test]
+    fn meters_and_counters_follow_the_heartbeat() {
+        let mut dashboard = Dashboard::default();
+        assert!(dashboard.apply(&line(
+            "started",
+            json!({ "session": "call-7", "path": "C:\\rec\\call.wav", "sample_rate": 48_000 }),
+        )));
+        let view = dashboard.lines().join("\n");
+        assert!(view.contains("call-7  48000 Hz  00:00:00  recording"));
+        assert!(view.contains("MIC       [") && view.contains("no signal yet"));
+
+        dashboard.apply(&line(
+            "stats",
+            json!({
+                "sample_position": 48_000 * 75,
+                "levels": {
+                    "mic": { "rms_dbfs": -30.0, "peak_dbfs": -0.5, "silent_ms": 0 },
+                    "loopback": { "rms_dbfs": -96.0, "peak_dbfs": -96.0, "silent_ms": 4_000 },
+                },
+                "stream_drops": 3,
+                "xruns": { "mic_underruns": 2, "loopback_underruns": 0, "mic_overruns": 0,
+                           "loopback_overruns": 1, "gaps": 0 },
+                "queues": { "mixer": 1, "sink": 5, "stream": 0 },
+                "paused": true,
+            }),
+        ));
+        let lines = dashboard.lines();
+        let view = lines.join("\n");
+        assert!(view.contains("00:01:15  paused"));
+        let mic = lines.iter().find(|line| line.starts_with("MIC")).unwrap();
+        // Half the scale filled, in red for the near-clipping peak, the peak at the top
+        assert!(mic.contains(&format!("{}{}{}", RED, "#".repeat(20), RESET)));
+        assert!(mic.contains("|]  -30.0 dBFS  peak   -0.5"));
+        assert!(view.contains(&format!("[{}{}", DIM, RESET)));
+        assert!(view.contains("silent 4.0s"));
+        assert!(view.contains("stream 3  underruns mic 2 loopback 0  overruns mic 0 loopback 1"));
+        assert!(view.contains("mixer 1  sink 5  stream 0"));
+        // The heartbeat isn't listed
+        assert!(!view.contains(" stats"));
+    }
+
+    #[test]
+    fn sinks_and_recent_events_follow_the_session() {
+        let mut dashboard = Dashboard::default();
+        dashboard.apply(&line(
+            "started",
+            json!({ "session": "call-8", "path": "part1.wav", "sample_rate": 16_000 }),
+        ));
+        dashboard.apply(&line(
+            "rotated",
+            json!({ "path": "part1.wav", "next": "part2.wav" }),
+        ));
+        dashboard.apply(&line(
+            "reconfigured",
+            json!({ "stages": ["sink"], "added_sink": "copy.flac" }),
+        ));
+        dashboard.apply(&line(
+            "warning",
+            json!({ "source": "mic", "message": "MIC stalled" }),
+        ));
+        let view = dashboard.lines().join("\n");
+        assert!(view.contains("  closed     part1.wav"));
+        assert!(view.contains("  recording  part2.wav"));
+        assert!(view.contains("  recording  copy.flac"));
+        assert!(view.contains("  14:03:07 warning: MIC stalled"));
+
+        dashboard.apply(&line("stopped", json!({ "path": "part2.wav" })));
+        let view = dashboard.lines().join("\n");
+        assert!(view.contains("  finalized  part2.wav"));
+        assert!(view.contains("  finalized  copy.flac"));
+        assert!(view.contains("00:00:00  stopped"));
+
+        // Only the latest events are kept
+        for n in 0..RECENT_EVENTS {
+            dashboard.apply(&line("marker", json!({ "message": format!("m{}", n) })));
+        }
+        let view = dashboard.lines().join("\n");
+        assert!(!view.contains("warning"));
+        assert!(
+            view.contains("marker: m0")
+                && view.contains(&format!("marker: m{}", RECENT_EVENTS - 1))
+        );
+        assert!(!dashboard.apply("not json"));
+    }
+}
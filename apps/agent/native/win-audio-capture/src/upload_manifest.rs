@@ -0,0 +1,328 @@
+//! Resumable upload manifest
+//! Each session keeps a JSON manifest in the per-user state directory listing its recording
+//! segments and which byte ranges of each have been uploaded. The sidecar adds a segment
+//! when it starts recording and marks it finalized once the file is complete (headers are
+//! only valid from then on); the uploader asks `upload next` for the next range to send and
+//! confirms it with `upload mark`. The manifest is rewritten atomically after every change,
+//! so an upload interrupted by a crash or reboot resumes at exactly the first missing byte.
+
+use crate::error::fail;
+use crate::events;
+use crate::paths;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Upload chunk size when the uploader doesn't ask for one
+pub const DEFAULT_CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Half-open byte range `[start, end)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// Final size; only known once the segment is finalized
+    pub bytes: Option<u64>,
+    pub finalized: bool,
+    /// Uploaded ranges, sorted and merged
+    pub uploaded: Vec<ByteRange>,
+}
+
+impl Segment {
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// First range of at most `chunk` bytes that hasn't been uploaded yet
+    pub fn next_range(&self, chunk: u64) -> Option<ByteRange> {
+        let size = self.bytes.filter(|_| self.finalized)?;
+        let mut start = 0;
+        for range in &self.uploaded {
+            if range.start > start {
+                break;
+            }
+            start = start.max(range.end);
+        }
+        if start >= size {
+            return None;
+        }
+        let limit = self
+            .uploaded
+            .iter()
+            .map(|r| r.start)
+            .find(|&s| s > start)
+            .unwrap_or(size);
+        Some(ByteRange {
+            start,
+            end: limit.min(start + chunk.max(1)),
+        })
+    }
+
+    fn mark(&mut self, range: ByteRange) {
+        self.uploaded.push(range);
+        self.uploaded.sort_by_key(|r| r.start);
+        let mut merged: Vec<ByteRange> = Vec::with_capacity(self.uploaded.len());
+        for range in self.uploaded.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.uploaded = merged;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadManifest {
+    pub session: String,
+    /// When the manifest was first written; the upload order across sessions
+    pub created_ms: u64,
+    /// `--pipeline-tag` of the session, for routing the upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline_tag: Option<String>,
+    /// Recorded with `--wrap-key`; crash recovery seals what it repairs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sealed: bool,
+    pub segments: Vec<Segment>,
+}
+
+/// Next range the uploader should send
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PendingUpload {
+    pub session: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pipeline_tag: Option<String>,
+    pub segment: PathBuf,
+    pub bytes: u64,
+    pub uploaded_bytes: u64,
+    pub range: ByteRange,
+}
+
+/// Directory holding one manifest per session
+pub fn manifest_dir() -> PathBuf {
+    paths::state_dir().join("uploads")
+}
+
+pub fn manifest_path(dir: &Path, session: &str) -> PathBuf {
+    dir.join(format!("{}.json", paths::sanitize_component(session)))
+}
+
+impl UploadManifest {
+    /// Load the session's manifest, or an empty one if it has none yet
+    pub fn load(dir: &Path, session: &str) -> Result<Self> {
+        match fs::read(manifest_path(dir, session)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).context("Failed to parse upload manifest"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self {
+                session: session.to_string(),
+                created_ms: events::unix_millis(),
+                pipeline_tag: None,
+                sealed: false,
+                segments: Vec::new(),
+            }),
+            Err(e) => Err(e).context("Failed to read upload manifest"),
+        }
+    }
+
+    /// Write atomically (temp file + rename)
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).context("Failed to create upload manifest directory")?;
+        let path = manifest_path(dir, &self.session);
+        let tmp_path = path.with_extension("json.tmp");
+        let json =
+            serde_json::to_vec_pretty(self).context("Failed to serialize upload manifest")?;
+        fs::write(&tmp_path, json).context("Failed to write upload manifest")?;
+        fs::rename(&tmp_path, &path).context("Failed to replace upload manifest")?;
+        Ok(())
+    }
+
+    /// Add a segment that is being recorded; re-adding a known path resets it
+    pub fn add_segment(&mut self, path: &Path) {
+        self.segments.retain(|segment| segment.path != path);
+        self.segments.push(Segment {
+            path: path.to_path_buf(),
+            bytes: None,
+            finalized: false,
+            uploaded: Vec::new(),
+        });
+    }
+
+    /// Forget a segment that will never be uploaded (deleted mid-recording)
+    pub fn remove_segment(&mut self, path: &Path) {
+        self.segments.retain(|segment| segment.path != path);
+    }
+
+    /// Mark a segment complete and ready to upload
+    pub fn finalize_segment(&mut self, path: &Path, bytes: u64) -> Result<()> {
+        let segment = self.segment_mut(path)?;
+        segment.bytes = Some(bytes);
+        segment.finalized = true;
+        Ok(())
+    }
+
+    /// Record that `range` of the segment at `path` has been uploaded
+    pub fn mark_uploaded(&mut self, path: &Path, range: ByteRange) -> Result<()> {
+        let segment = self.segment_mut(path)?;
+        let size = segment
+            .bytes
+            .ok_or_else(|| fail!(Config, "Segment {:?} is not finalized", path))?;
+        if range.start >= range.end || range.end > size {
+            return Err(fail!(
+                Config,
+                "Range {}..{} is outside segment {:?} ({} bytes)",
+                range.start,
+                range.end,
+                path,
+                size
+            ));
+        }
+        segment.mark(range);
+        Ok(())
+    }
+
+    /// First missing range across the session's segments, in recording order
+    pub fn next_pending(&self, chunk: u64) -> Option<PendingUpload> {
+        self.segments.iter().find_map(|segment| {
+            segment.next_range(chunk).map(|range| PendingUpload {
+                session: self.session.clone(),
+                pipeline_tag: self.pipeline_tag.clone(),
+                segment: segment.path.clone(),
+                bytes: segment.bytes.unwrap_or(0),
+                uploaded_bytes: segment.uploaded_bytes(),
+                range,
+            })
+        })
+    }
+
+    fn segment_mut(&mut self, path: &Path) -> Result<&mut Segment> {
+        self.segments
+            .iter_mut()
+            .find(|segment| segment.path == path)
+            .ok_or_else(|| fail!(Config, "Segment {:?} is not in the manifest", path))
+    }
+}
+
+/// Sessions with a manifest in `dir`, oldest manifest first
+pub fn sessions(dir: &Path) -> Result<Vec<String>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read upload manifest directory"),
+    };
+
+    let mut manifests = Vec::new();
+    for item in read_dir {
+        let path = item?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Ok(bytes) = fs::read(&path) else {
+            continue;
+        };
+        let Ok(manifest) = serde_json::from_slice::<UploadManifest>(&bytes) else {
+            continue;
+        };
+        manifests.push((manifest.created_ms, manifest.session));
+    }
+    manifests.sort();
+    Ok(manifests.into_iter().map(|(_, session)| session).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finalized(bytes: u64) -> UploadManifest {
+        let mut manifest = UploadManifest {
+            session: "call-1".to_string(),
+            created_ms: 0,
+            pipeline_tag: Some("renewal".to_string()),
+            sealed: false,
+            segments: Vec::new(),
+        };
+        manifest.add_segment(Path::new("call.wav"));
+        manifest
+            .finalize_segment(Path::new("call.wav"), bytes)
+            .unwrap();
+        manifest
+    }
+
+    #[test]
+    fn resumes_at_the_first_missing_byte() {
+        let mut manifest = finalized(100);
+        let segment = Path::new("call.wav");
+        assert_eq!(
+            manifest.next_pending(40).unwrap().range,
+            ByteRange { start: 0, end: 40 }
+        );
+
+        manifest
+            .mark_uploaded(segment, ByteRange { start: 0, end: 40 })
+            .unwrap();
+        manifest
+            .mark_uploaded(segment, ByteRange { start: 60, end: 80 })
+            .unwrap();
+        // Stops short of the range that's already there
+        assert_eq!(
+            manifest.next_pending(40).unwrap().range,
+            ByteRange { start: 40, end: 60 }
+        );
+
+        manifest
+            .mark_uploaded(segment, ByteRange { start: 40, end: 60 })
+            .unwrap();
+        assert_eq!(
+            manifest.segments[0].uploaded,
+            [ByteRange { start: 0, end: 80 }]
+        );
+        manifest
+            .mark_uploaded(
+                segment,
+                ByteRange {
+                    start: 80,
+                    end: 100,
+                },
+            )
+            .unwrap();
+        assert_eq!(manifest.next_pending(40), None);
+        assert_eq!(manifest.segments[0].uploaded_bytes(), 100);
+    }
+
+    #[test]
+    fn unfinalized_segments_are_not_offered() {
+        let mut manifest = finalized(100);
+        manifest.add_segment(Path::new("call_0002.wav"));
+        manifest
+            .mark_uploaded(Path::new("call.wav"), ByteRange { start: 0, end: 100 })
+            .unwrap();
+        assert_eq!(manifest.next_pending(40), None);
+        assert!(manifest
+            .mark_uploaded(Path::new("call_0002.wav"), ByteRange { start: 0, end: 1 })
+            .is_err());
+        manifest.remove_segment(Path::new("call_0002.wav"));
+        assert_eq!(manifest.segments.len(), 1);
+    }
+
+    #[test]
+    fn survives_a_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = finalized(100);
+        manifest
+            .mark_uploaded(Path::new("call.wav"), ByteRange { start: 0, end: 10 })
+            .unwrap();
+        manifest.save(dir.path()).unwrap();
+
+        let loaded = UploadManifest::load(dir.path(), "call-1").unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(sessions(dir.path()).unwrap(), ["call-1"]);
+        assert!(UploadManifest::load(dir.path(), "other")
+            .unwrap()
+            .segments
+            .is_empty());
+    }
+}
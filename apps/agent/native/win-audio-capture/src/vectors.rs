@@ -0,0 +1,596 @@
+//! Frame protocol conformance vectors
+//! Canonical SELL byte streams, well-formed and malformed, each with what a conforming
+//! decoder reports for it. The Rust decoder is tested against them here and
+//! `win-audio-capture gen-vectors <dir>` writes them out (`<name>.bin` plus `index.json`)
+//! for the Node consumer's test suite, so both implementations agree byte for byte.
+//!
+//! A decoder runs a vector by pushing the bytes (in any chunking) and taking frames until
+//! it needs more input; runs of identical errors are listed once with a count, and
+//! `trailing_bytes` is what stays buffered at the end. A `greeted` vector is decoded as
+//! the rest of a stream whose handshake was already read, so with long headers from the
+//! first byte; the others start with the short version 1 headers.
+
+use crate::frame::{
+    self, ChannelLevel, DecodeError, FrameDecoder, FrameKind, Handshake, Timestamps,
+    MAX_PAYLOAD_LEN,
+};
+use crate::shm::SlotRef;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/// One decoder outcome, in stream order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Expected {
+    Frame {
+        sequence: u32,
+        sample_offset: u64,
+        /// Interleaved stereo samples; omitted for the large vectors
+        #[serde(skip_serializing_if = "Option::is_none")]
+        samples: Option<Vec<i16>>,
+        payload_len: u32,
+        /// Typed frames only
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamps: Option<Timestamps>,
+    },
+    Handshake {
+        protocol: u32,
+        payload_len: u32,
+    },
+    Energy {
+        sequence: u32,
+        sample_offset: u64,
+        levels: Vec<ChannelLevel>,
+    },
+    SharedPcm {
+        sequence: u32,
+        sample_offset: u64,
+        slot: u32,
+        generation: u32,
+        payload_len: u32,
+    },
+    /// A marker, stats or end-of-stream frame
+    Typed {
+        kind: &'static str,
+        sequence: u32,
+        sample_offset: u64,
+        timestamps: Timestamps,
+        payload: serde_json::Value,
+    },
+    Error {
+        kind: ErrorKind,
+        count: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    BadMagic,
+    OddPayload,
+    PayloadTooLarge,
+    UnknownType,
+    BadChecksum,
+}
+
+impl From<&DecodeError> for ErrorKind {
+    fn from(error: &DecodeError) -> Self {
+        match error {
+            DecodeError::BadMagic(_) => ErrorKind::BadMagic,
+            DecodeError::OddPayload(_) => ErrorKind::OddPayload,
+            DecodeError::PayloadTooLarge(_) => ErrorKind::PayloadTooLarge,
+            DecodeError::UnknownType(_) => ErrorKind::UnknownType,
+            DecodeError::BadChecksum { .. } => ErrorKind::BadChecksum,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Vector {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub greeted: bool,
+    #[serde(skip)]
+    pub bytes: Vec<u8>,
+    pub expect: Vec<Expected>,
+    pub trailing_bytes: usize,
+}
+
+/// Samples large vectors are checked by length only
+const LISTED_SAMPLES: usize = 64;
+
+fn frame(samples: &[i16], sequence: u32, sample_offset: u64) -> (Vec<u8>, Expected) {
+    let bytes = frame::encode_frame(samples, sequence, sample_offset);
+    let expected = Expected::Frame {
+        sequence,
+        sample_offset,
+        samples: (samples.len() <= LISTED_SAMPLES).then(|| samples.to_vec()),
+        payload_len: (samples.len() * 2) as u32,
+        timestamps: None,
+    };
+    (bytes, expected)
+}
+
+/// Timestamps of the typed vectors, 100ms into a stream
+const STAMP: Timestamps = Timestamps {
+    monotonic_ns: 100_000_000,
+    utc_us: 1_760_000_000_000_000,
+};
+
+fn typed_pcm(samples: &[i16], sequence: u32, sample_offset: u64) -> (Vec<u8>, Expected) {
+    let bytes = frame::encode_typed_pcm(samples, sequence, sample_offset, STAMP);
+    let expected = Expected::Frame {
+        sequence,
+        sample_offset,
+        samples: Some(samples.to_vec()),
+        payload_len: (samples.len() * 2) as u32,
+        timestamps: Some(STAMP),
+    };
+    (bytes, expected)
+}
+
+fn typed_json(kind: FrameKind, sequence: u32, payload: serde_json::Value) -> (Vec<u8>, Expected) {
+    let bytes = frame::encode_typed(kind, sequence, 0, STAMP, payload.to_string().as_bytes());
+    let expected = Expected::Typed {
+        kind: kind_name(kind),
+        sequence,
+        sample_offset: 0,
+        timestamps: STAMP,
+        payload,
+    };
+    (bytes, expected)
+}
+
+fn kind_name(kind: FrameKind) -> &'static str {
+    match kind {
+        FrameKind::Marker => "marker",
+        FrameKind::Stats => "stats",
+        FrameKind::EndOfStream => "end_of_stream",
+        _ => "other",
+    }
+}
+
+fn errors(kind: ErrorKind, count: usize) -> Expected {
+    Expected::Error { kind, count }
+}
+
+/// Header claiming `payload_len` bytes
+fn header_only(payload_len: u32) -> Vec<u8> {
+    frame::encode_header(&frame::FrameHeader {
+        kind: frame::FrameKind::Pcm,
+        sequence: 1,
+        sample_offset: 0,
+        payload_len,
+    })
+    .to_vec()
+}
+
+/// Every vector, in a stable order
+pub fn all() -> Vec<Vector> {
+    let golden = [1, -1, i16::MAX, i16::MIN];
+    let (golden_bytes, golden_frame) = frame(&golden, 7, 4_800);
+    let mut vectors = Vec::new();
+
+    vectors.push(Vector {
+        name: "single_frame",
+        description: "Frame 7 at offset 4800 with two stereo pairs",
+        greeted: true,
+        bytes: golden_bytes.clone(),
+        expect: vec![golden_frame.clone()],
+        trailing_bytes: 0,
+    });
+
+    let (bytes, expected) = frame(&[], u32::MAX, u64::MAX);
+    vectors.push(Vector {
+        name: "empty_frame",
+        description: "Header only, with the largest sequence and offset",
+        greeted: true,
+        bytes,
+        expect: vec![expected],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = Vec::new();
+    let mut expect = Vec::new();
+    for sequence in 0..3u32 {
+        let samples: Vec<i16> = (0..8).map(|i| (sequence as i16) * 100 + i).collect();
+        let (frame_bytes, expected) = frame(&samples, sequence, sequence as u64 * 4);
+        bytes.extend(frame_bytes);
+        expect.push(expected);
+    }
+    vectors.push(Vector {
+        name: "back_to_back",
+        description: "Three consecutive frames in one stream",
+        greeted: true,
+        bytes,
+        expect,
+        trailing_bytes: 0,
+    });
+
+    let samples: Vec<i16> = (0..MAX_PAYLOAD_LEN as usize / 2)
+        .map(|i| i as i16)
+        .collect();
+    let (bytes, expected) = frame(&samples, 0, 0);
+    vectors.push(Vector {
+        name: "max_payload",
+        description: "The largest payload a decoder must accept",
+        greeted: true,
+        bytes,
+        expect: vec![expected],
+        trailing_bytes: 0,
+    });
+
+    let handshake = frame::encode_handshake(&Handshake {
+        protocol: frame::PROTOCOL_VERSION,
+        features: vec!["anonymize".to_string()],
+        sample_format: "s16le".to_string(),
+        sample_rate: 48_000,
+        channels: 2,
+        bits_per_sample: 16,
+        frame_duration_ms: 100,
+        optional_frames: Vec::new(),
+        pipeline_tag: None,
+        session: None,
+        channel_layout: Vec::new(),
+        repeat_every_frames: None,
+    });
+    let mut bytes = handshake.clone();
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "handshake_then_frame",
+        description: "A stream opening with the handshake frame",
+        greeted: false,
+        expect: vec![
+            Expected::Handshake {
+                protocol: frame::PROTOCOL_VERSION,
+                payload_len: (handshake.len() - frame::HEADER_LEN) as u32,
+            },
+            golden_frame.clone(),
+        ],
+        bytes,
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = frame::encode_legacy_frame(&golden, 7);
+    bytes.extend(frame::encode_legacy_frame(&[5, -5], 8));
+    bytes.extend(&handshake);
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "v1_then_handshake",
+        description: "Two version 1 frames, then the handshake and a frame with the long header",
+        greeted: false,
+        expect: vec![
+            Expected::Frame {
+                sequence: 7,
+                sample_offset: 0,
+                samples: Some(golden.to_vec()),
+                payload_len: 8,
+                timestamps: None,
+            },
+            Expected::Frame {
+                sequence: 8,
+                sample_offset: 2,
+                samples: Some(vec![5, -5]),
+                payload_len: 4,
+                timestamps: None,
+            },
+            Expected::Handshake {
+                protocol: frame::PROTOCOL_VERSION,
+                payload_len: (handshake.len() - frame::HEADER_LEN) as u32,
+            },
+            golden_frame.clone(),
+        ],
+        bytes,
+        trailing_bytes: 0,
+    });
+
+    let levels = frame::levels(&golden, 2);
+    let mut bytes = golden_bytes.clone();
+    bytes.extend(frame::encode_energy(&levels, 7, 4_800));
+    vectors.push(Vector {
+        name: "frame_then_energy",
+        description: "A PCM frame followed by its energy frame",
+        greeted: true,
+        bytes,
+        expect: vec![
+            golden_frame.clone(),
+            Expected::Energy {
+                sequence: 7,
+                sample_offset: 4_800,
+                levels,
+            },
+        ],
+        trailing_bytes: 0,
+    });
+
+    let slot_ref = SlotRef {
+        slot: 3,
+        generation: 41,
+        payload_len: 8,
+    };
+    let mut bytes = frame::encode_shared(slot_ref, 7, 4_800);
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "shared_then_inline",
+        description: "A frame by reference to shared-memory slot 3, then one inline",
+        greeted: true,
+        bytes,
+        expect: vec![
+            Expected::SharedPcm {
+                sequence: 7,
+                sample_offset: 4_800,
+                slot: 3,
+                generation: 41,
+                payload_len: 8,
+            },
+            golden_frame.clone(),
+        ],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = b"xy".to_vec();
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "leading_garbage",
+        description: "Two junk bytes before a frame are skipped one at a time",
+        greeted: true,
+        bytes,
+        expect: vec![errors(ErrorKind::BadMagic, 2), golden_frame.clone()],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = b"SEL".to_vec();
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "partial_magic",
+        description: "A cut-off magic before a frame",
+        greeted: true,
+        bytes,
+        expect: vec![errors(ErrorKind::BadMagic, 3), golden_frame.clone()],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = header_only(7);
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "odd_payload",
+        description: "A header with an odd payload length, resynchronized on the next frame",
+        greeted: true,
+        bytes,
+        expect: vec![
+            errors(ErrorKind::OddPayload, 1),
+            errors(ErrorKind::BadMagic, frame::HEADER_LEN - 1),
+            golden_frame.clone(),
+        ],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = header_only(MAX_PAYLOAD_LEN + 2);
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "payload_too_large",
+        description: "A header claiming more than the maximum payload is not allocated",
+        greeted: true,
+        bytes,
+        expect: vec![
+            errors(ErrorKind::PayloadTooLarge, 1),
+            errors(ErrorKind::BadMagic, frame::HEADER_LEN - 1),
+            golden_frame.clone(),
+        ],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = golden_bytes.clone();
+    bytes.truncate(bytes.len() - 3);
+    vectors.push(Vector {
+        name: "truncated_payload",
+        description: "A frame missing its last three bytes waits for more input",
+        greeted: true,
+        trailing_bytes: bytes.len(),
+        bytes,
+        expect: Vec::new(),
+    });
+
+    let (pcm_bytes, pcm_frame) = typed_pcm(&golden, 7, 4_800);
+    let (marker_bytes, marker_frame) = typed_json(
+        FrameKind::Marker,
+        8,
+        serde_json::json!({ "label": "Pricing" }),
+    );
+    let (stats_bytes, stats_frame) = typed_json(
+        FrameKind::Stats,
+        8,
+        serde_json::json!({ "stream_drops": 0 }),
+    );
+    let (end_bytes, end_frame) = typed_json(
+        FrameKind::EndOfStream,
+        8,
+        serde_json::json!({ "frames": 8 }),
+    );
+    vectors.push(Vector {
+        name: "typed_frames",
+        description: "Protocol 3: a typed PCM frame, a marker, a stats snapshot and the end",
+        greeted: true,
+        bytes: [&pcm_bytes[..], &marker_bytes, &stats_bytes, &end_bytes].concat(),
+        expect: vec![pcm_frame, marker_frame, stats_frame, end_frame],
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = pcm_bytes.clone();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "bad_checksum",
+        description: "A typed frame with a flipped payload bit fails its CRC32",
+        greeted: true,
+        expect: vec![
+            errors(ErrorKind::BadChecksum, 1),
+            errors(ErrorKind::BadMagic, pcm_bytes.len() - 1),
+            golden_frame.clone(),
+        ],
+        bytes,
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = pcm_bytes;
+    bytes[4] = 9;
+    bytes.extend(&golden_bytes);
+    vectors.push(Vector {
+        name: "unknown_type",
+        description: "A typed frame of a type from a later protocol",
+        greeted: true,
+        expect: vec![
+            errors(ErrorKind::UnknownType, 1),
+            errors(ErrorKind::BadMagic, bytes.len() - golden_bytes.len() - 1),
+            golden_frame.clone(),
+        ],
+        bytes,
+        trailing_bytes: 0,
+    });
+
+    let mut bytes = golden_bytes.clone();
+    bytes.extend(&golden_bytes[..10]);
+    vectors.push(Vector {
+        name: "truncated_header",
+        description: "A frame followed by half a header",
+        greeted: true,
+        bytes,
+        expect: vec![golden_frame],
+        trailing_bytes: 10,
+    });
+
+    vectors
+}
+
+/// Run `bytes` through the decoder, pushing `chunk` bytes at a time
+pub fn decode(bytes: &[u8], greeted: bool, chunk: usize) -> (Vec<Expected>, usize) {
+    let mut decoder = if greeted {
+        FrameDecoder::greeted()
+    } else {
+        FrameDecoder::new()
+    };
+    let mut outcomes: Vec<Expected> = Vec::new();
+    for piece in bytes.chunks(chunk.max(1)) {
+        decoder.push(piece);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) if frame.header.kind == FrameKind::Handshake => {
+                    outcomes.push(Expected::Handshake {
+                        protocol: frame
+                            .handshake()
+                            .and_then(Result::ok)
+                            .map_or(0, |handshake| handshake.protocol),
+                        payload_len: frame.header.payload_len,
+                    });
+                }
+                Ok(Some(frame)) if frame.header.kind == FrameKind::Energy => {
+                    outcomes.push(Expected::Energy {
+                        sequence: frame.header.sequence,
+                        sample_offset: frame.header.sample_offset,
+                        levels: frame.energy().unwrap_or_default(),
+                    });
+                }
+                Ok(Some(frame)) if frame.header.kind == FrameKind::SharedPcm => {
+                    let slot_ref = frame.shared().unwrap_or(SlotRef {
+                        slot: u32::MAX,
+                        generation: 0,
+                        payload_len: 0,
+                    });
+                    outcomes.push(Expected::SharedPcm {
+                        sequence: frame.header.sequence,
+                        sample_offset: frame.header.sample_offset,
+                        slot: slot_ref.slot,
+                        generation: slot_ref.generation,
+                        payload_len: slot_ref.payload_len,
+                    });
+                }
+                Ok(Some(frame)) if frame.json().is_some() => {
+                    outcomes.push(Expected::Typed {
+                        kind: kind_name(frame.header.kind),
+                        sequence: frame.header.sequence,
+                        sample_offset: frame.header.sample_offset,
+                        timestamps: frame.timestamps.unwrap_or_default(),
+                        payload: frame
+                            .json()
+                            .and_then(Result::ok)
+                            .unwrap_or(serde_json::Value::Null),
+                    });
+                }
+                Ok(Some(frame)) => {
+                    let samples = frame.samples;
+                    outcomes.push(Expected::Frame {
+                        sequence: frame.header.sequence,
+                        sample_offset: frame.header.sample_offset,
+                        payload_len: frame.header.payload_len,
+                        samples: (samples.len() <= LISTED_SAMPLES).then_some(samples),
+                        timestamps: frame.timestamps,
+                    });
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let kind = ErrorKind::from(&e);
+                    match outcomes.last_mut() {
+                        Some(Expected::Error { kind: last, count }) if *last == kind => *count += 1,
+                        _ => outcomes.push(errors(kind, 1)),
+                    }
+                }
+            }
+        }
+    }
+    (outcomes, decoder.buffered())
+}
+
+/// Write every vector into `dir`: `<name>.bin` and an `index.json` describing them
+pub fn write_all(dir: &Path) -> io::Result<Vec<Vector>> {
+    std::fs::create_dir_all(dir)?;
+    let vectors = all();
+    for vector in &vectors {
+        std::fs::write(dir.join(format!("{}.bin", vector.name)), &vector.bytes)?;
+    }
+    let index = serde_json::json!({
+        "protocol": "SELL",
+        "header_len": frame::HEADER_LEN,
+        "legacy_header_len": frame::LEGACY_HEADER_LEN,
+        "typed_header_len": frame::TYPED_HEADER_LEN,
+        "max_payload_len": MAX_PAYLOAD_LEN,
+        "vectors": vectors,
+    });
+    std::fs::write(dir.join("index.json"), serde_json::to_vec_pretty(&index)?)?;
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_conforms_to_every_vector_in_any_chunking() {
+        for vector in all() {
+            for chunk in [1, 7, vector.bytes.len()] {
+                let (outcomes, trailing) = decode(&vector.bytes, vector.greeted, chunk);
+                assert_eq!(
+                    outcomes, vector.expect,
+                    "{} in {}-byte chunks",
+                    vector.name, chunk
+                );
+                assert_eq!(trailing, vector.trailing_bytes, "{}", vector.name);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_binaries_and_an_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let vectors = write_all(dir.path()).unwrap();
+        for vector in &vectors {
+            let bytes = std::fs::read(dir.path().join(format!("{}.bin", vector.name))).unwrap();
+            assert_eq!(bytes, vector.bytes);
+        }
+        let index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("index.json")).unwrap()).unwrap();
+        assert_eq!(index["vectors"].as_array().unwrap().len(), vectors.len());
+        assert_eq!(index["vectors"][0]["expect"][0]["type"], "frame");
+    }
+}
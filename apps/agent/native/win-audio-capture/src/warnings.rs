@@ -0,0 +1,170 @@
+//! Repeated warnings
+//! A flapping device fails every callback, hundreds of times a second. A warning goes out
+//! (log line and `warning` event) the first time it happens in a window of `WINDOW`;
+//! repeats within the window are only counted and go out as one summary when the window
+//! ends: `MIC stream error: ... (x412 in last 10s)`, `warning` with `repeats` and
+//! `window_secs`. Warnings are told apart by their source, so a new message from the same
+//! source counts as a repeat and the summary carries the latest one.
+
+use crate::events;
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window repeats are counted over
+pub const WINDOW: Duration = Duration::from_secs(10);
+
+/// How often ended windows are summarized
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts the repeats of one warning. Allocation-free, so real-time threads keep their own.
+#[derive(Debug, Clone, Copy)]
+pub struct Repeats {
+    window: Duration,
+    started: Option<Instant>,
+    counted: u64,
+}
+
+impl Repeats {
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: None,
+            counted: 0,
+        }
+    }
+
+    /// Count an occurrence at `now`. `Some(repeats)` when it opens a window and should be
+    /// reported, `repeats` being what the previous window counted and nobody reported yet.
+    pub fn hit(&mut self, now: Instant) -> Option<u64> {
+        match self.started {
+            Some(started) if now.duration_since(started) < self.window => {
+                self.counted += 1;
+                None
+            }
+            _ => {
+                self.started = Some(now);
+                Some(std::mem::take(&mut self.counted))
+            }
+        }
+    }
+
+    /// Repeats of a window that has ended by `now`, if it had any; reported once
+    pub fn expire(&mut self, now: Instant) -> Option<u64> {
+        let started = self.started?;
+        if now.duration_since(started) < self.window || self.counted == 0 {
+            return None;
+        }
+        self.started = None;
+        Some(std::mem::take(&mut self.counted))
+    }
+}
+
+struct Entry {
+    source: String,
+    message: String,
+    repeats: Repeats,
+}
+
+static WARNINGS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Report `message` from `source`, or count it if `source` already warned this window
+pub fn warn(source: &str, message: &str) {
+    let now = Instant::now();
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = match warnings.iter().position(|entry| entry.source == source) {
+        Some(index) => index,
+        None => {
+            warnings.push(Entry {
+                source: source.to_string(),
+                message: String::new(),
+                repeats: Repeats::new(WINDOW),
+            });
+            warnings.len() - 1
+        }
+    };
+    let entry = &mut warnings[index];
+    let previous = std::mem::replace(&mut entry.message, message.to_string());
+    if let Some(repeats) = entry.repeats.hit(now) {
+        if repeats > 0 {
+            summarize(source, &previous, repeats);
+        }
+        report(source, message);
+    }
+}
+
+/// Summarize the windows that have ended
+pub fn flush() {
+    let now = Instant::now();
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    for entry in warnings.iter_mut() {
+        if let Some(repeats) = entry.repeats.expire(now) {
+            summarize(&entry.source, &entry.message, repeats);
+        }
+    }
+}
+
+/// Flush on the runtime for as long as the process runs
+pub fn spawn_flusher() {
+    tokio::spawn(async {
+        let mut tick = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tick.tick().await;
+            flush();
+        }
+    });
+}
+
+fn report(source: &str, message: &str) {
+    eprintln!("[win-audio-capture] Warning: {}", message);
+    events::emit("warning", json!({ "source": source, "message": message }));
+}
+
+fn summarize(source: &str, message: &str, repeats: u64) {
+    eprintln!(
+        "[win-audio-capture] Warning: {} (x{} in last {}s)",
+        message,
+        repeats,
+        WINDOW.as_secs()
+    );
+    events::emit(
+        "warning",
+        json!({
+            "source": source,
+            "message": message,
+            "repeats": repeats,
+            "window_secs": WINDOW.as_secs(),
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_once_per_window_and_counts_the_rest() {
+        let mut repeats = Repeats::new(WINDOW);
+        let start = Instant::now();
+        assert_eq!(repeats.hit(start), Some(0));
+        for ms in 1..=411 {
+            assert_eq!(repeats.hit(start + Duration::from_millis(ms)), None);
+        }
+        assert_eq!(repeats.expire(start + Duration::from_secs(5)), None);
+        assert_eq!(repeats.expire(start + WINDOW), Some(411));
+        assert_eq!(repeats.expire(start + WINDOW * 2), None);
+        // After a quiet window the next one is reported straight away
+        assert_eq!(repeats.hit(start + WINDOW * 3), Some(0));
+    }
+
+    #[test]
+    fn a_new_window_carries_the_unreported_repeats() {
+        let mut repeats = Repeats::new(WINDOW);
+        let start = Instant::now();
+        repeats.hit(start);
+        repeats.hit(start + Duration::from_secs(1));
+        repeats.hit(start + Duration::from_secs(2));
+        assert_eq!(repeats.hit(start + WINDOW), Some(2));
+        assert_eq!(repeats.expire(start + WINDOW * 3), None);
+    }
+}
@@ -1,95 +1,343 @@
 //! WASAPI Loopback Audio Capture for Windows
 //! Captures system audio output using WASAPI loopback mode
+//!
+//! The packet loop is a real-time section: buffers are sized when the endpoint opens,
+//! samples go to the mixer in one ring chunk per packet and the thread's log lines go
+//! through its `rt` log queue. Opening the endpoint (COM, role detection) is not on that
+//! path and may allocate.
 
 #![cfg(windows)]
 
-use anyhow::{anyhow, Context, Result};
-use crossbeam_channel::Sender;
+use crate::etw;
+use crate::events;
+use crate::loopback_role::{self, EndpointUsage, LoopbackRole};
+use crate::packet_format::{self, PacketFormat};
+use crate::power::{self, PowerProfile};
+use crate::process_loopback;
+use crate::resample::{ResampleQuality, ResampleStats, Resampler};
+use crate::rt::{self, LogTx};
+use crate::session::SourceCounter;
+use crate::trace;
+use crate::warnings::{self, Repeats};
+use anyhow::{Context, Result};
+use rtrb::Producer;
+use serde_json::json;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use windows::core::*;
-use windows::Win32::Foundation::*;
+use std::time::Instant;
+use windows::core::{Interface, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::CloseHandle;
 use windows::Win32::Media::Audio::*;
-use windows::Win32::Media::KernelStreaming::*;
 use windows::Win32::System::Com::*;
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
 use windows::Win32::System::Threading::*;
 
-const REFTIMES_PER_SEC: i64 = 10_000_000;
 const REFTIMES_PER_MILLISEC: i64 = 10_000;
 
+/// Pause before reopening the loopback endpoint after a failure
+const REOPEN_DELAY_MS: u32 = 1_000;
+
+/// Why a capture pass ended without an error
+enum Closed {
+    Stopped,
+    /// The default endpoint it followed moved
+    DefaultChanged,
+}
+
 pub struct WasapiLoopbackCapture {
     running: Arc<AtomicBool>,
-    sample_tx: Sender<f32>,
+    sample_tx: Producer<f32>,
+    counter: Arc<SourceCounter>,
+    profile: PowerProfile,
+    role: LoopbackRole,
+    /// `--loopback-device`: this endpoint rather than the one `role` resolves to
+    device_id: Option<String>,
+    /// `--loopback-pid`: only this process tree's audio
+    process: Option<u32>,
+    /// Role of the primary loopback when this one records an extra endpoint; while both
+    /// resolve to the same device this capture stays idle instead of doubling the audio
+    alongside: Option<LoopbackRole>,
+    /// Set by the session when a default render endpoint changed
+    default_changed: Option<Arc<AtomicBool>>,
+    /// Rate the mixer runs at; loopback audio is resampled to it when the device differs
+    target_rate: u32,
+    quality: ResampleQuality,
+    resampler: Option<Resampler>,
+    mono: Vec<f32>,
+    resampled: Vec<f32>,
+    log: LogTx,
 }
 
 impl WasapiLoopbackCapture {
-    pub fn new(sample_tx: Sender<f32>, running: Arc<AtomicBool>) -> Self {
-        Self { running, sample_tx }
+    pub fn new(
+        sample_tx: Producer<f32>,
+        running: Arc<AtomicBool>,
+        counter: Arc<SourceCounter>,
+        profile: PowerProfile,
+        role: LoopbackRole,
+        target_rate: u32,
+        quality: ResampleQuality,
+    ) -> Self {
+        Self {
+            running,
+            sample_tx,
+            counter,
+            profile,
+            role,
+            device_id: None,
+            process: None,
+            alongside: None,
+            default_changed: None,
+            target_rate,
+            quality,
+            resampler: None,
+            mono: Vec::new(),
+            resampled: Vec::new(),
+            log: rt::log_queue(),
+        }
+    }
+
+    /// Loop back the endpoint with this ID instead of a default one
+    pub fn device(mut self, id: Option<String>) -> Self {
+        self.device_id = id;
+        self
+    }
+
+    /// Record only what the process tree of `pid` plays, if the system supports it
+    pub fn process(mut self, pid: Option<u32>) -> Self {
+        self.process = pid;
+        self
+    }
+
+    /// A process loopback client for `self.process`; `None` records the endpoint instead,
+    /// for the rest of the session if process loopback isn't available
+    unsafe fn open_process_loopback(&mut self) -> Option<IAudioClient> {
+        let pid = self.process?;
+        match process_loopback::activate(pid) {
+            Ok(client) => {
+                self.log.println(format_args!(
+                    "[WASAPI] Recording the audio of process {} and its children",
+                    pid
+                ));
+                Some(client)
+            }
+            Err(e) => {
+                self.log.eprintln(format_args!(
+                    "[WASAPI] Process loopback unavailable, recording the endpoint: {:#}",
+                    e
+                ));
+                events::emit(
+                    "loopback_fallback",
+                    json!({ "pid": pid, "error": format!("{:#}", e) }),
+                );
+                self.process = None;
+                None
+            }
+        }
+    }
+
+    /// Record as an extra endpoint next to the primary loopback using `primary`
+    pub fn alongside(mut self, primary: LoopbackRole) -> Self {
+        self.alongside = Some(primary);
+        self
     }
 
-    /// Start WASAPI loopback capture in a background thread
-    pub fn start(self) -> Result<thread::JoinHandle<Result<()>>> {
-        let handle = thread::spawn(move || {
-            self.run_capture_loop()
-        });
+    /// Reopen the endpoint as soon as `changed` is set, when it follows a default one
+    pub fn follow_default(mut self, changed: Arc<AtomicBool>) -> Self {
+        self.default_changed = Some(changed);
+        self
+    }
+
+    /// Start WASAPI loopback capture in a background thread. The thread returns the
+    /// resampler's stats if the loopback device needed resampling.
+    pub fn start(mut self) -> Result<thread::JoinHandle<Result<Option<ResampleStats>>>> {
+        let handle = thread::spawn(move || self.run_capture_loop());
         Ok(handle)
     }
 
-    fn run_capture_loop(&self) -> Result<()> {
+    fn run_capture_loop(&mut self) -> Result<Option<ResampleStats>> {
+        if self.profile.lower_priority {
+            power::lower_current_thread_priority();
+        }
+        trace::register_thread();
+
         unsafe {
             // Initialize COM for this thread
             CoInitializeEx(None, COINIT_MULTITHREADED)
+                .ok()
                 .context("Failed to initialize COM")?;
 
-            let result = self.capture_audio();
+            // The endpoint is invalidated by suspend/resume and default device changes;
+            // reopen it for as long as the session runs
+            let mut failures = Repeats::new(warnings::WINDOW);
+            let result = loop {
+                let e = match self.capture_audio() {
+                    Ok(Closed::Stopped) => break Ok(()),
+                    Ok(Closed::DefaultChanged) => {
+                        self.log.println(format_args!(
+                            "[WASAPI] Default endpoint changed, reopening loopback"
+                        ));
+                        continue;
+                    }
+                    Err(e) if !self.running.load(Ordering::SeqCst) => break Err(e),
+                    Err(e) => e,
+                };
+                match failures.hit(Instant::now()) {
+                    Some(0) => self.log.eprintln(format_args!(
+                        "[WASAPI] Loopback capture failed, reopening in {}ms: {:#}",
+                        REOPEN_DELAY_MS, e
+                    )),
+                    Some(repeats) => self.log.eprintln(format_args!(
+                        "[WASAPI] Loopback capture failed, reopening in {}ms: {:#} (x{} in last {}s)",
+                        REOPEN_DELAY_MS,
+                        e,
+                        repeats + 1,
+                        warnings::WINDOW.as_secs()
+                    )),
+                    None => {}
+                }
+                Sleep(REOPEN_DELAY_MS);
+            };
 
             // Clean up COM
             CoUninitialize();
 
-            result
+            result?;
+            let stats = self.resampler.as_ref().map(Resampler::stats);
+            if let Some(stats) = &stats {
+                self.log.println(format_args!(
+                    "[WASAPI] Resampled {} -> {} Hz ({:?}): {:.2}ms CPU per second of audio",
+                    stats.input_rate, stats.output_rate, stats.quality, stats.cpu_ms_per_sec
+                ));
+            }
+            Ok(stats)
         }
     }
 
-    unsafe fn capture_audio(&self) -> Result<()> {
+    unsafe fn capture_audio(&mut self) -> Result<Closed> {
         // Create device enumerator
-        let enumerator: IMMDeviceEnumerator = CoCreateInstance(
-            &MMDeviceEnumerator,
-            None,
-            CLSCTX_ALL,
-        )
-        .context("Failed to create device enumerator")?;
-
-        // Get default audio endpoint for rendering (speakers/headphones)
-        let device = enumerator
-            .GetDefaultAudioEndpoint(eRender, eConsole)
-            .context("Failed to get default audio endpoint")?;
-
-        // Activate audio client
-        let audio_client: IAudioClient = device
-            .Activate(CLSCTX_ALL, None)
-            .context("Failed to activate audio client")?;
-
-        // Get the mix format
-        let mix_format = audio_client
-            .GetMixFormat()
-            .context("Failed to get mix format")?;
-
-        let wave_format = &*mix_format;
-        println!(
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+
+        let process_format = process_loopback::format(self.target_rate);
+        let process_client = self.open_process_loopback();
+        let process_mode = process_client.is_some();
+        let (audio_client, mix_format, role) = match process_client {
+            Some(client) => (client, &process_format as *const WAVEFORMATEX, self.role),
+            None => {
+                // Get default audio endpoint for rendering (speakers/headphones); auto mode is
+                // resolved on every open so a call that moves endpoints is followed after a reopen
+                let pinned = match &self.device_id {
+                    Some(id) => Some(
+                        enumerator
+                            .GetDevice(&HSTRING::from(id.as_str()))
+                            .with_context(|| {
+                                format!("Pinned loopback endpoint {} is not available", id)
+                            })?,
+                    ),
+                    None => None,
+                };
+                let role = match self.role {
+                    _ if pinned.is_some() => self.role,
+                    LoopbackRole::Auto => detect_role(&enumerator, Some(&mut self.log)),
+                    role => role,
+                };
+                let mut idle = false;
+                let device = loop {
+                    if let Some(device) = pinned {
+                        break device;
+                    }
+                    let device = enumerator
+                        .GetDefaultAudioEndpoint(eRender, erole(role))
+                        .context("Failed to get default audio endpoint")?;
+                    let Some(primary) = self.alongside else {
+                        break device;
+                    };
+                    let primary = match primary {
+                        LoopbackRole::Auto => detect_role(&enumerator, None),
+                        primary => primary,
+                    };
+                    let primary_id = enumerator
+                        .GetDefaultAudioEndpoint(eRender, erole(primary))
+                        .and_then(|primary| primary.GetId())
+                        .map(|id| take_string(id));
+                    if primary_id.ok() != Some(take_string(device.GetId()?)) {
+                        break device;
+                    }
+                    if !idle {
+                        self.log.println(format_args!(
+                    "[WASAPI] {:?} endpoint is the same device as the primary loopback, not recording it twice",
+                    role
+                ));
+                        idle = true;
+                    }
+                    Sleep(REOPEN_DELAY_MS);
+                    if !self.running.load(Ordering::SeqCst) {
+                        return Ok(Closed::Stopped);
+                    }
+                };
+
+                // Activate audio client
+                let audio_client: IAudioClient = device
+                    .Activate(CLSCTX_ALL, None)
+                    .context("Failed to activate audio client")?;
+
+                // Get the mix format
+                let mix_format = audio_client
+                    .GetMixFormat()
+                    .context("Failed to get mix format")?;
+                (audio_client, mix_format as *const WAVEFORMATEX, role)
+            }
+        };
+
+        // WAVEFORMATEX is packed, so read it (and the extensible part `cbSize` says follows)
+        // as bytes instead of borrowing its fields; formats that can't be converted stop here
+        let extra = std::ptr::read_unaligned(mix_format).cbSize as usize;
+        let format = PacketFormat::parse(std::slice::from_raw_parts(
+            mix_format as *const u8,
+            packet_format::WAVEFORMATEX_LEN + extra,
+        ))?;
+        let num_channels = format.channels;
+        let sample_rate = format.sample_rate;
+        let bits_per_sample = format.container_bits;
+        self.log.println(format_args!(
             "[WASAPI] Loopback format: {} channels @ {} Hz, {} bits",
-            wave_format.nChannels,
-            wave_format.nSamplesPerSec,
-            wave_format.wBitsPerSample
-        );
+            num_channels, sample_rate, bits_per_sample
+        ));
+        self.counter.set_sample_rate(sample_rate);
+
+        // Keep the resampler (and its stats) across reopens of a device at the same rate
+        if sample_rate == self.target_rate {
+            self.resampler = None;
+        } else if self
+            .resampler
+            .as_ref()
+            .is_none_or(|resampler| resampler.stats().input_rate != sample_rate)
+        {
+            self.log.println(format_args!(
+                "[WASAPI] Resampling loopback {} -> {} Hz ({:?})",
+                sample_rate, self.target_rate, self.quality
+            ));
+            self.resampler = Some(Resampler::new(sample_rate, self.target_rate, self.quality));
+        }
 
-        // Initialize audio client in loopback mode
-        let buffer_duration = REFTIMES_PER_SEC / 10; // 100ms buffer
+        // Initialize audio client in loopback mode; a process loopback client only runs
+        // event-driven and converts to the format asked for
+        let buffer_duration =
+            self.profile.loopback_buffer.as_millis() as i64 * REFTIMES_PER_MILLISEC;
+        let flags = if process_mode {
+            AUDCLNT_STREAMFLAGS_LOOPBACK
+                | AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM
+        } else {
+            AUDCLNT_STREAMFLAGS_LOOPBACK
+        };
         audio_client
             .Initialize(
                 AUDCLNT_SHAREMODE_SHARED,
-                AUDCLNT_STREAMFLAGS_LOOPBACK,
+                flags,
                 buffer_duration,
                 0,
                 mix_format,
@@ -97,25 +345,79 @@ impl WasapiLoopbackCapture {
             )
             .context("Failed to initialize audio client")?;
 
-        // Get buffer size
-        let buffer_frame_count = audio_client
-            .GetBufferSize()
-            .context("Failed to get buffer size")?;
-
         // Get capture client
         let capture_client: IAudioCaptureClient = audio_client
             .GetService()
             .context("Failed to get capture client")?;
 
+        // Packets are still polled below; the event only has to exist
+        let packet_event = if process_mode {
+            let event = CreateEventW(None, false, false, PCWSTR::null())
+                .context("Failed to create event")?;
+            audio_client
+                .SetEventHandle(event)
+                .context("Failed to set event handle")?;
+            Some(event)
+        } else {
+            None
+        };
+
+        // Size the packet buffers for the whole endpoint buffer up front (a process
+        // loopback client doesn't say, so a buffer's duration at the asked rate)
+        let buffer_frames = match audio_client.GetBufferSize() {
+            Ok(frames) => frames as usize,
+            Err(_) if process_mode => {
+                (self.profile.loopback_buffer.as_millis() as usize * sample_rate as usize) / 1000
+            }
+            Err(e) => return Err(e).context("Failed to get buffer size"),
+        };
+        self.mono.reserve(buffer_frames);
+        if let Some(resampler) = &mut self.resampler {
+            let output = resampler.reserve(buffer_frames);
+            self.resampled.reserve(output);
+        }
+
         // Start audio client
-        audio_client.Start().context("Failed to start audio client")?;
+        audio_client
+            .Start()
+            .context("Failed to start audio client")?;
+
+        self.log
+            .println(format_args!("[WASAPI] Loopback capture started"));
+        etw::write(
+            etw::LEVEL_INFO,
+            etw::KEYWORD_DEVICE,
+            "device_open",
+            &serde_json::json!({
+                "source": "loopback",
+                "role": role,
+                "process": self.process,
+                "channels": num_channels,
+                "sample_rate": sample_rate,
+                "bits": bits_per_sample,
+            }),
+        );
 
-        println!("[WASAPI] Loopback capture started");
+        // Packet ages are reported against the performance counter, in 100ns units
+        let mut qpc_frequency = 0i64;
+        let _ = QueryPerformanceFrequency(&mut qpc_frequency);
+
+        // A pinned endpoint or a process loopback doesn't move with the defaults
+        let follows_default = self.device_id.is_none() && !process_mode;
+        let mut closed = Closed::Stopped;
 
         // Capture loop
         while self.running.load(Ordering::SeqCst) {
+            let changed = self.default_changed.as_ref();
+            if follows_default
+                && changed.is_some_and(|changed| changed.swap(false, Ordering::SeqCst))
+            {
+                closed = Closed::DefaultChanged;
+                break;
+            }
             // Sleep for half the buffer duration
             Sleep(buffer_duration as u32 / REFTIMES_PER_MILLISEC as u32 / 2);
+            let _section = rt::enter();
 
             // Get next packet
             loop {
@@ -131,6 +433,7 @@ impl WasapiLoopbackCapture {
                 let mut data: *mut u8 = std::ptr::null_mut();
                 let mut num_frames_available: u32 = 0;
                 let mut flags: u32 = 0;
+                let mut qpc_position: u64 = 0;
 
                 capture_client
                     .GetBuffer(
@@ -138,9 +441,13 @@ impl WasapiLoopbackCapture {
                         &mut num_frames_available,
                         &mut flags,
                         None,
-                        None,
+                        Some(&mut qpc_position),
                     )
                     .context("Failed to get buffer")?;
+                if let Some(age) = packet_age(qpc_position, qpc_frequency) {
+                    self.counter.record_latency(age);
+                }
+                let _span = trace::span("loopback_packet");
 
                 // Process audio data
                 if data.is_null() || num_frames_available == 0 {
@@ -151,20 +458,21 @@ impl WasapiLoopbackCapture {
                 }
 
                 // Check for silence flag
-                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 != 0 {
+                self.mono.clear();
+                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
                     // Send silence
-                    for _ in 0..num_frames_available {
-                        let _ = self.sample_tx.try_send(0.0);
-                    }
+                    self.mono.resize(num_frames_available as usize, 0.0);
                 } else {
                     // Convert and send samples
-                    self.process_buffer(
+                    let packet = std::slice::from_raw_parts(
                         data,
-                        num_frames_available,
-                        wave_format.nChannels,
-                        wave_format.wBitsPerSample,
-                    )?;
+                        num_frames_available as usize * format.block_align as usize,
+                    );
+                    packet_format::to_mono(&format, packet, &mut self.mono);
                 }
+                self.push_mono();
+
+                self.counter.add(num_frames_available as u64);
 
                 // Release the buffer
                 capture_client
@@ -175,53 +483,192 @@ impl WasapiLoopbackCapture {
 
         // Stop audio client
         audio_client.Stop().context("Failed to stop audio client")?;
+        if let Some(event) = packet_event {
+            let _ = CloseHandle(event);
+        }
 
-        println!("[WASAPI] Loopback capture stopped");
+        self.log
+            .println(format_args!("[WASAPI] Loopback capture stopped"));
 
-        Ok(())
+        Ok(closed)
     }
 
-    unsafe fn process_buffer(
-        &self,
-        data: *const u8,
-        num_frames: u32,
-        num_channels: u16,
-        bits_per_sample: u16,
-    ) -> Result<()> {
-        match bits_per_sample {
-            16 => {
-                // 16-bit PCM
-                let samples = std::slice::from_raw_parts(
-                    data as *const i16,
-                    (num_frames * num_channels as u32) as usize,
-                );
-                for chunk in samples.chunks(num_channels as usize) {
-                    // Average channels to mono
-                    let mono_sample: f32 = chunk.iter()
-                        .map(|&s| s as f32 / i16::MAX as f32)
-                        .sum::<f32>() / num_channels as f32;
-                    let _ = self.sample_tx.try_send(mono_sample);
-                }
+    /// Hand the converted packet to the mixer, at the mixer's rate, as one ring chunk;
+    /// whatever doesn't fit a full ring is dropped
+    fn push_mono(&mut self) {
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(&self.mono, &mut self.resampled);
+                &self.resampled
             }
-            32 => {
-                // 32-bit float
-                let samples = std::slice::from_raw_parts(
-                    data as *const f32,
-                    (num_frames * num_channels as u32) as usize,
-                );
-                for chunk in samples.chunks(num_channels as usize) {
-                    // Average channels to mono
-                    let mono_sample: f32 = chunk.iter().sum::<f32>() / num_channels as f32;
-                    let _ = self.sample_tx.try_send(mono_sample);
+            None => &self.mono,
+        };
+        let len = samples.len().min(self.sample_tx.slots());
+        if let Ok(chunk) = self.sample_tx.write_chunk_uninit(len) {
+            chunk.fill_from_iter(samples.iter().copied());
+        }
+    }
+}
+
+/// Shared-mode rate of the default endpoint a loopback of `role` records, with `auto`
+/// resolved the way the capture thread resolves it
+pub fn endpoint_rate(role: LoopbackRole) -> Result<u32> {
+    unsafe {
+        // Already initialized (as MTA) on the runtime's threads is fine too
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let role = match role {
+            LoopbackRole::Auto => detect_role(&enumerator, None),
+            role => role,
+        };
+        let device = enumerator
+            .GetDefaultAudioEndpoint(eRender, erole(role))
+            .context("Failed to get default audio endpoint")?;
+        let audio_client: IAudioClient = device
+            .Activate(CLSCTX_ALL, None)
+            .context("Failed to activate audio client")?;
+        let mix_format = audio_client
+            .GetMixFormat()
+            .context("Failed to get mix format")?;
+        // WAVEFORMATEX is packed, so copy the fields out instead of borrowing them
+        let format = std::ptr::read_unaligned(mix_format);
+        CoTaskMemFree(Some(mix_format as *const _));
+        Ok(format.nSamplesPerSec)
+    }
+}
+
+fn erole(role: LoopbackRole) -> ERole {
+    match role {
+        LoopbackRole::Communications => eCommunications,
+        LoopbackRole::Multimedia => eMultimedia,
+        LoopbackRole::Console | LoopbackRole::Auto => eConsole,
+    }
+}
+
+/// Resolve `--loopback-role auto` to the role whose default endpoint carries the call,
+/// logging to `report` and emitting `loopback_role` with what was found when given
+unsafe fn detect_role(
+    enumerator: &IMMDeviceEnumerator,
+    mut report: Option<&mut LogTx>,
+) -> LoopbackRole {
+    let mut endpoints = Vec::new();
+    for role in [
+        LoopbackRole::Console,
+        LoopbackRole::Communications,
+        LoopbackRole::Multimedia,
+    ] {
+        match endpoint_usage(enumerator, role) {
+            Ok(usage) => endpoints.push(usage),
+            Err(e) => {
+                if let Some(log) = report.as_deref_mut() {
+                    log.eprintln(format_args!(
+                        "[WASAPI] Could not inspect {:?} endpoint: {:#}",
+                        role, e
+                    ));
                 }
             }
-            _ => {
-                return Err(anyhow!(
-                    "Unsupported bit depth: {} bits",
-                    bits_per_sample
-                ));
-            }
         }
-        Ok(())
     }
+    let picked = loopback_role::pick(&endpoints);
+    let Some(log) = report else {
+        return picked;
+    };
+    log.println(format_args!(
+        "[WASAPI] Auto-detected loopback role: {:?}",
+        picked
+    ));
+    events::emit(
+        "loopback_role",
+        json!({
+            "role": picked,
+            "endpoints": endpoints
+                .iter()
+                .map(|e| json!({
+                    "role": e.role,
+                    "device_id": e.device_id,
+                    "conferencing_active": e.conferencing_active,
+                }))
+                .collect::<Vec<_>>(),
+        }),
+    );
+    picked
+}
+
+unsafe fn endpoint_usage(
+    enumerator: &IMMDeviceEnumerator,
+    role: LoopbackRole,
+) -> Result<EndpointUsage> {
+    let device = enumerator.GetDefaultAudioEndpoint(eRender, erole(role))?;
+    let device_id = take_string(device.GetId()?);
+
+    let manager: IAudioSessionManager2 = device.Activate(CLSCTX_ALL, None)?;
+    let sessions = manager.GetSessionEnumerator()?;
+    let mut conferencing_active = false;
+    for i in 0..sessions.GetCount()? {
+        let Ok(control) = sessions.GetSession(i) else {
+            continue;
+        };
+        if control.GetState()? != AudioSessionStateActive {
+            continue;
+        }
+        let Ok(control) = control.cast::<IAudioSessionControl2>() else {
+            continue;
+        };
+        let Ok(pid) = control.GetProcessId() else {
+            continue;
+        };
+        if process_image(pid).is_some_and(|path| loopback_role::is_conferencing_app(&path)) {
+            conferencing_active = true;
+            break;
+        }
+    }
+
+    Ok(EndpointUsage {
+        role,
+        device_id,
+        conferencing_active,
+    })
+}
+
+/// How long ago the packet captured at `qpc_position` (100ns units) was captured
+pub(crate) unsafe fn packet_age(
+    qpc_position: u64,
+    qpc_frequency: i64,
+) -> Option<std::time::Duration> {
+    if qpc_position == 0 || qpc_frequency <= 0 {
+        return None;
+    }
+    let mut now = 0i64;
+    QueryPerformanceCounter(&mut now).ok()?;
+    let now = (now as u128 * 10_000_000 / qpc_frequency as u128) as u64;
+    let age = now.checked_sub(qpc_position)?;
+    Some(std::time::Duration::from_nanos(age * 100))
+}
+
+/// Full image path of a process, if it can be queried
+pub fn process_image(pid: u32) -> Option<String> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let mut buf = [0u16; 1024];
+        let mut len = buf.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buf.as_mut_ptr()),
+            &mut len,
+        );
+        let _ = CloseHandle(process);
+        result.ok()?;
+        Some(String::from_utf16_lossy(&buf[..len as usize]))
+    }
+}
+
+/// Copy a COM-allocated string and free it
+unsafe fn take_string(s: PWSTR) -> String {
+    let value = s.to_string().unwrap_or_default();
+    CoTaskMemFree(Some(s.0 as *const _));
+    value
 }
@@ -0,0 +1,287 @@
+//! Raw WASAPI MIC capture
+//! Some virtual audio drivers (voice changers, virtual cables, vendor "studio" effects)
+//! make cpal fail or panic while it builds the MIC's input stream. When that happens the
+//! MIC is opened directly with an `IAudioClient` in shared mode instead, the way the
+//! loopback is, and its packets are polled on a thread of its own and handed to the same
+//! processing as cpal's callback. The fallback runs at the endpoint's mix format; once a
+//! MIC has fallen back, reopening it (stalls, resume, lock retries) stays on WASAPI. The
+//! backend in use is reported as `mic.backend` in the `started` event and, when it changes,
+//! in a `mic_backend` event.
+
+#![cfg(windows)]
+
+use crate::error::fail;
+use crate::warnings;
+use crate::wasapi_loopback::packet_age;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use windows::core::HSTRING;
+use windows::Win32::Media::Audio::*;
+use windows::Win32::System::Com::*;
+use windows::Win32::System::Performance::QueryPerformanceFrequency;
+use windows::Win32::System::Threading::Sleep;
+
+const REFTIMES_PER_MILLISEC: i64 = 10_000;
+/// Endpoint buffer; packets are polled every half of it
+const BUFFER_MS: u32 = 40;
+
+/// What records the MIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MicBackend {
+    Cpal,
+    Wasapi,
+}
+
+/// Shared-mode format of a MIC endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MicFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+/// The format the endpoint `id` (the default capture endpoint without one) records at
+pub fn mix_format(id: Option<&str>) -> Result<MicFormat> {
+    unsafe {
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let client = activate(id)?;
+        read_mix_format(&client)
+    }
+}
+
+/// A MIC stream polled on its own thread; it starts paused
+pub struct WasapiMicStream {
+    playing: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl WasapiMicStream {
+    /// Open the endpoint `id` at `format`, calling `on_data` with each packet as
+    /// interleaved frames and how long ago it was captured, when known
+    pub fn open<F>(id: Option<String>, format: MicFormat, on_data: F) -> Result<Self>
+    where
+        F: FnMut(&[f32], Option<Duration>) + Send + 'static,
+    {
+        let playing = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let (thread_playing, thread_running) = (playing.clone(), running.clone());
+        let thread = thread::Builder::new()
+            .name("wasapi-mic".to_string())
+            .spawn(move || {
+                let mut capture = Capture {
+                    format,
+                    playing: thread_playing,
+                    running: thread_running,
+                    on_data,
+                    samples: Vec::new(),
+                };
+                unsafe { capture.run(id.as_deref(), opened_tx) }
+            })
+            .context("Failed to start the WASAPI MIC thread")?;
+        let stream = Self {
+            playing,
+            running,
+            thread: Some(thread),
+        };
+        match opened_rx.recv() {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(fail!(Device, "WASAPI MIC thread ended while opening")),
+        }
+    }
+
+    pub fn play(&self) -> Result<()> {
+        self.playing.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.playing.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for WasapiMicStream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Capture<F> {
+    format: MicFormat,
+    playing: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    on_data: F,
+    samples: Vec<f32>,
+}
+
+impl<F: FnMut(&[f32], Option<Duration>)> Capture<F> {
+    unsafe fn run(&mut self, id: Option<&str>, opened: mpsc::Sender<Result<()>>) {
+        if let Err(e) = CoInitializeEx(None, COINIT_MULTITHREADED).ok() {
+            let _ = opened.send(Err(e).context("Failed to initialize COM"));
+            return;
+        }
+        match self.open(id) {
+            Ok((client, capture_client)) => {
+                let _ = opened.send(Ok(()));
+                if let Err(e) = self.poll(&client, &capture_client) {
+                    warnings::warn("mic_stream", &format!("MIC stream error: {:#}", e));
+                }
+                let _ = client.Stop();
+            }
+            Err(e) => {
+                let _ = opened.send(Err(e));
+            }
+        }
+        CoUninitialize();
+    }
+
+    unsafe fn open(&mut self, id: Option<&str>) -> Result<(IAudioClient, IAudioCaptureClient)> {
+        let client = activate(id)?;
+        let format = read_mix_format(&client)?;
+        if format != self.format {
+            return Err(fail!(
+                Device,
+                "MIC format changed to {} channel(s) at {} Hz",
+                format.channels,
+                format.sample_rate
+            ));
+        }
+        let mix_format = client
+            .GetMixFormat()
+            .context("Failed to get MIC mix format")?;
+        let initialized = client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            0,
+            BUFFER_MS as i64 * REFTIMES_PER_MILLISEC,
+            0,
+            mix_format,
+            None,
+        );
+        CoTaskMemFree(Some(mix_format as *const _));
+        initialized.context("Failed to initialize MIC audio client")?;
+        let capture_client: IAudioCaptureClient = client
+            .GetService()
+            .context("Failed to get MIC capture client")?;
+        let buffer_frames = client
+            .GetBufferSize()
+            .context("Failed to get MIC buffer size")?;
+        self.samples
+            .reserve(buffer_frames as usize * format.channels as usize);
+        Ok((client, capture_client))
+    }
+
+    unsafe fn poll(
+        &mut self,
+        client: &IAudioClient,
+        capture_client: &IAudioCaptureClient,
+    ) -> Result<()> {
+        let mut qpc_frequency = 0i64;
+        let _ = QueryPerformanceFrequency(&mut qpc_frequency);
+        let channels = self.format.channels as usize;
+        let mut started = false;
+        while self.running.load(Ordering::SeqCst) {
+            let playing = self.playing.load(Ordering::SeqCst);
+            if playing != started {
+                match playing {
+                    true => client.Start().context("Failed to start MIC audio client")?,
+                    false => client.Stop().context("Failed to stop MIC audio client")?,
+                }
+                started = playing;
+            }
+            Sleep(BUFFER_MS / 2);
+            if !started {
+                continue;
+            }
+            loop {
+                let packet_length = capture_client
+                    .GetNextPacketSize()
+                    .context("Failed to get MIC packet size")?;
+                if packet_length == 0 {
+                    break;
+                }
+                let mut data: *mut u8 = std::ptr::null_mut();
+                let mut frames: u32 = 0;
+                let mut flags: u32 = 0;
+                let mut qpc_position: u64 = 0;
+                capture_client
+                    .GetBuffer(
+                        &mut data,
+                        &mut frames,
+                        &mut flags,
+                        None,
+                        Some(&mut qpc_position),
+                    )
+                    .context("Failed to get MIC buffer")?;
+                let len = frames as usize * channels;
+                self.samples.clear();
+                if data.is_null() || flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                    self.samples.resize(len, 0.0);
+                } else if self.format.bits_per_sample == 16 {
+                    let packet = std::slice::from_raw_parts(data as *const i16, len);
+                    self.samples
+                        .extend(packet.iter().map(|&s| s as f32 / i16::MAX as f32));
+                } else {
+                    let packet = std::slice::from_raw_parts(data as *const f32, len);
+                    self.samples.extend_from_slice(packet);
+                }
+                capture_client
+                    .ReleaseBuffer(frames)
+                    .context("Failed to release MIC buffer")?;
+                if !self.samples.is_empty() {
+                    (self.on_data)(&self.samples, packet_age(qpc_position, qpc_frequency));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe fn activate(id: Option<&str>) -> Result<IAudioClient> {
+    let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+        .context("Failed to create device enumerator")?;
+    let device = match id {
+        Some(id) => enumerator
+            .GetDevice(&HSTRING::from(id))
+            .with_context(|| format!("MIC endpoint {} is not available", id))?,
+        None => enumerator
+            .GetDefaultAudioEndpoint(eCapture, eConsole)
+            .context("Failed to get the default MIC endpoint")?,
+    };
+    device
+        .Activate(CLSCTX_ALL, None)
+        .context("Failed to activate MIC audio client")
+}
+
+unsafe fn read_mix_format(client: &IAudioClient) -> Result<MicFormat> {
+    let mix_format = client
+        .GetMixFormat()
+        .context("Failed to get MIC mix format")?;
+    // WAVEFORMATEX is packed, so copy the fields out instead of borrowing them
+    let wave_format = std::ptr::read_unaligned(mix_format);
+    CoTaskMemFree(Some(mix_format as *const _));
+    let format = MicFormat {
+        channels: wave_format.nChannels,
+        sample_rate: wave_format.nSamplesPerSec,
+        bits_per_sample: wave_format.wBitsPerSample,
+    };
+    if !matches!(format.bits_per_sample, 16 | 32) || format.channels == 0 {
+        return Err(fail!(
+            Format,
+            "Unsupported MIC format: {} channel(s), {} bits",
+            format.channels,
+            format.bits_per_sample
+        ));
+    }
+    Ok(format)
+}
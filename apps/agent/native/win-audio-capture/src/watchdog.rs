@@ -0,0 +1,123 @@
+//! Stalled stream watchdog
+//! Some USB headsets stop delivering MIC data every so often without the stream reporting
+//! an error. The session loop checks the MIC's delivery counter a few times a second; when
+//! it hasn't moved for `--mic-stall-ms` the stream is reopened like after a resume, and the
+//! mixer fills the stretch with silence in the meantime. Stalls go out as `stream_stalled`
+//! events and are counted in the `stopped` event.
+//!
+//! The loopback isn't watched: WASAPI delivers no packets while nothing plays, and its
+//! thread reopens the device on errors by itself.
+
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// How often the session loop checks the counters
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What the watchdog has seen so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StallStats {
+    pub stalls: u64,
+    /// Stalls after which the stream was reopened
+    pub restarts: u64,
+}
+
+/// Watches one source's delivery counter
+pub struct StallWatchdog {
+    timeout: Duration,
+    samples: u64,
+    progressed_at: Instant,
+    stats: StallStats,
+}
+
+impl StallWatchdog {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            samples: 0,
+            progressed_at: now,
+            stats: StallStats::default(),
+        }
+    }
+
+    /// Account for the counter reading `samples`; returns how long the source has been
+    /// silent if that counts as a stall. The stall is counted once, and the clock restarts.
+    pub fn check(&mut self, samples: u64, now: Instant) -> Option<Duration> {
+        if samples != self.samples {
+            self.samples = samples;
+            self.progressed_at = now;
+            return None;
+        }
+        let silent = now.duration_since(self.progressed_at);
+        if silent < self.timeout {
+            return None;
+        }
+        self.stats.stalls += 1;
+        self.progressed_at = now;
+        Some(silent)
+    }
+
+    /// Record that the stream was reopened after a stall
+    pub fn restarted(&mut self) {
+        self.stats.restarts += 1;
+    }
+
+    /// Start over, e.g. after the stream was reopened for another reason
+    pub fn reset(&mut self, now: Instant) {
+        self.progressed_at = now;
+    }
+
+    pub fn stats(&self) -> StallStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(750);
+
+    #[test]
+    fn flags_a_counter_that_stops_moving() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut watchdog = StallWatchdog::new(TIMEOUT, start);
+
+        assert_eq!(watchdog.check(480, at(250)), None);
+        assert_eq!(watchdog.check(960, at(500)), None);
+        assert_eq!(watchdog.check(960, at(1_000)), None);
+        assert_eq!(
+            watchdog.check(960, at(1_250)),
+            Some(Duration::from_millis(750))
+        );
+        // Counted once, then it takes another full timeout
+        assert_eq!(watchdog.check(960, at(1_500)), None);
+        assert_eq!(
+            watchdog.check(960, at(2_000)),
+            Some(Duration::from_millis(750))
+        );
+        watchdog.restarted();
+        assert_eq!(
+            watchdog.stats(),
+            StallStats {
+                stalls: 2,
+                restarts: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reset_restarts_the_clock() {
+        let start = Instant::now();
+        let mut watchdog = StallWatchdog::new(TIMEOUT, start);
+        watchdog.reset(start + Duration::from_millis(700));
+        assert_eq!(
+            watchdog.check(0, start + Duration::from_millis(1_000)),
+            None
+        );
+        assert!(watchdog
+            .check(0, start + Duration::from_millis(1_450))
+            .is_some());
+    }
+}
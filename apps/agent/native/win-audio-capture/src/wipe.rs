@@ -0,0 +1,84 @@
+//! Secure deletion
+//! For a `panic_delete` (the prospect revoked consent mid-call) the session's files are
+//! overwritten in place, once with ones and once with zeros, flushed to disk after each
+//! pass, and only then removed, so the audio can't be undeleted from the free space.
+//!
+//! On SSDs and copy-on-write volumes an overwrite doesn't reach every physical copy of the
+//! blocks; recordings sealed with `--wrap-key` are the stronger guarantee there, since
+//! their key never touches the disk.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Fill byte of each overwrite pass
+const PASSES: [u8; 2] = [0xFF, 0x00];
+
+const WRITE_CHUNK_BYTES: usize = 1 << 20;
+
+/// A file that was overwritten and removed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Wiped {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Overwrite and remove `path`; `None` if there was no such file
+pub fn wipe(path: &Path) -> Result<Option<Wiped>> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {:?} to wipe", path)),
+    };
+    let bytes = file.metadata()?.len();
+    let mut chunk = vec![0u8; WRITE_CHUNK_BYTES];
+    for fill in PASSES {
+        chunk.fill(fill);
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = bytes;
+        while remaining > 0 {
+            let len = remaining.min(WRITE_CHUNK_BYTES as u64) as usize;
+            file.write_all(&chunk[..len])
+                .with_context(|| format!("Failed to overwrite {:?}", path))?;
+            remaining -= len as u64;
+        }
+        file.sync_all()
+            .with_context(|| format!("Failed to flush {:?}", path))?;
+    }
+    drop(file);
+    fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))?;
+    Ok(Some(Wiped {
+        path: path.to_path_buf(),
+        bytes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_before_removing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call.wav");
+        fs::write(&path, vec![0x5Au8; WRITE_CHUNK_BYTES + 17]).unwrap();
+        // A second link to the same data shows what is left on disk
+        let witness = dir.path().join("witness");
+        fs::hard_link(&path, &witness).unwrap();
+
+        let wiped = wipe(&path).unwrap().unwrap();
+        assert_eq!(wiped.bytes, WRITE_CHUNK_BYTES as u64 + 17);
+        assert!(!path.exists());
+        let left = fs::read(&witness).unwrap();
+        assert_eq!(left.len(), WRITE_CHUNK_BYTES + 17);
+        assert!(left.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn missing_files_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(wipe(&dir.path().join("never-written.wav")).unwrap(), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Session workspace
+//! Each session gets a temp directory of its own, `%TEMP%\selly-capture\<session>`, for
+//! what it writes on the way to the recording rather than next to it: the local copy of a
+//! recording staged off slow storage, and with it the temp files trimming and sealing
+//! rewrite that copy through. A session that finishes removes the directory. One that
+//! fails keeps it with an `error.json` saying what went wrong, and its `error` event
+//! points there (`workspace`), so the audio can be recovered and the directory sent in
+//! with a bug report. Crash recovery finds the staged copy of a session that got to
+//! neither, and removes the directory once it is empty.
+
+use crate::paths;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What a failed session's workspace says about the failure
+pub const ERROR_FILE: &str = "error.json";
+
+/// Where every session's workspace is
+pub fn root() -> PathBuf {
+    std::env::temp_dir().join("selly-capture")
+}
+
+/// Workspace directory of `session`, whether or not it exists
+pub fn dir(session: &str) -> PathBuf {
+    root().join(paths::sanitize_component(session))
+}
+
+/// A session's workspace while it runs. Dropped without `finish` (the session failed) it
+/// is kept, unless nothing was written to it.
+#[derive(Debug)]
+pub struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    pub fn create(session: &str) -> Result<Self> {
+        Self::create_in(dir(session))
+    }
+
+    fn create_in(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create session workspace {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The session finished: remove the workspace and everything left in it
+    pub fn finish(mut self) -> Result<()> {
+        // Leaves `drop` an empty path to try
+        let dir = std::mem::take(&mut self.dir);
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove session workspace {:?}", dir))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        // Only succeeds when empty
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+/// Record `error` in the workspace `session` failed with and return the workspace, if
+/// the session left anything in it
+pub fn keep_failed(session: &str, error: &anyhow::Error) -> Option<PathBuf> {
+    keep_failed_in(dir(session), session, error)
+}
+
+fn keep_failed_in(dir: PathBuf, session: &str, error: &anyhow::Error) -> Option<PathBuf> {
+    // Nothing written, nothing to keep
+    fs::read_dir(&dir).ok()?.next()?.ok()?;
+    let report = json!({
+        "session": session,
+        "kind": crate::error::kind(error),
+        "error": format!("{:#}", error),
+        "failed_ms": crate::events::unix_millis(),
+    });
+    if let Ok(bytes) = serde_json::to_vec_pretty(&report) {
+        let _ = fs::write(dir.join(ERROR_FILE), bytes);
+    }
+    Some(dir)
+}
+
+/// Remove the workspace of `session` if nothing but its `error.json` is left in it, e.g.
+/// once crash recovery has published its staged copy
+pub fn remove_if_empty(session: &str) {
+    remove_if_empty_in(&dir(session));
+}
+
+fn remove_if_empty_in(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let only_report = entries
+        .flatten()
+        .all(|entry| entry.file_name() == ERROR_FILE);
+    if only_report {
+        let _ = fs::remove_file(dir.join(ERROR_FILE));
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::fail;
+
+    #[test]
+    fn finishing_removes_what_the_session_left() {
+        let root = tempfile::tempdir().unwrap();
+        let workspace = Workspace::create_in(root.path().join("call-1")).unwrap();
+        fs::write(workspace.path().join("call.wav.trim.partial"), b"half").unwrap();
+        let dir = workspace.path().to_path_buf();
+        workspace.finish().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn a_failed_session_keeps_its_files_and_says_why() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("call-2");
+        {
+            let workspace = Workspace::create_in(dir.clone()).unwrap();
+            fs::write(workspace.path().join("call.wav"), b"RIFF").unwrap();
+        }
+        let error = fail!(Network, "Failed to copy staged recording");
+        assert_eq!(
+            keep_failed_in(dir.clone(), "call-2", &error),
+            Some(dir.clone())
+        );
+        assert!(dir.join("call.wav").exists());
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.join(ERROR_FILE)).unwrap()).unwrap();
+        assert_eq!(report["kind"], "network");
+
+        // Once its audio is recovered the report goes with it
+        fs::remove_file(dir.join("call.wav")).unwrap();
+        remove_if_empty_in(&dir);
+        assert!(!dir.exists());
+
+        // Nothing written, nothing kept
+        let empty = root.path().join("call-3");
+        drop(Workspace::create_in(empty.clone()).unwrap());
+        assert!(!empty.exists());
+        assert_eq!(keep_failed_in(empty, "call-3", &error), None);
+    }
+}
@@ -0,0 +1,22 @@
+// Fail a sidecar build that is over its size budget: the binary ships inside the Electron
+// installer, so every megabyte adds to the download and to the cold start.
+//
+//   node scripts/check-sidecar-size.mjs <binary> [limit-mb]
+//
+// The limit defaults to 5 MB.
+
+import { statSync } from "node:fs";
+
+const [binary, limitMb = "5"] = process.argv.slice(2);
+if (!binary) {
+    console.error("usage: check-sidecar-size.mjs <binary> [limit-mb]");
+    process.exit(2);
+}
+
+const megabytes = (bytes) => (bytes / (1024 * 1024)).toFixed(2);
+const { size } = statSync(binary);
+if (size > Number(limitMb) * 1024 * 1024) {
+    console.error(`${binary} is ${megabytes(size)} MB, over the ${limitMb} MB budget`);
+    process.exit(1);
+}
+console.log(`${binary}: ${megabytes(size)} MB of ${limitMb} MB`);
@@ -0,0 +1,26 @@
+// Issue a `--license-token` for a tenant: a compact JWS (ES256) over its feature policy,
+// signed with the license service's P-256 private key.
+//
+//   node scripts/sign-license.mjs <private-key.pem> <tenant> <feature,...> [days]
+//
+// Features: aec, local_asr, encryption. Without `days` the token doesn't expire.
+
+import { createPrivateKey, sign } from "node:crypto";
+import { readFileSync } from "node:fs";
+
+const [keyPath, tenant, features, days] = process.argv.slice(2);
+if (!keyPath || !tenant || features === undefined) {
+    console.error("usage: sign-license.mjs <private-key.pem> <tenant> <feature,...> [days]");
+    process.exit(2);
+}
+
+const base64url = (data) => Buffer.from(data).toString("base64url");
+const claims = {
+    tenant,
+    features: features.split(",").filter(Boolean),
+    ...(days ? { exp: Math.floor(Date.now() / 1000) + Number(days) * 86_400 } : {}),
+};
+const input = `${base64url(JSON.stringify({ alg: "ES256" }))}.${base64url(JSON.stringify(claims))}`;
+const key = createPrivateKey(readFileSync(keyPath));
+const signature = sign("sha256", Buffer.from(input), { key, dsaEncoding: "ieee-p1363" });
+console.log(`${input}.${base64url(signature)}`);